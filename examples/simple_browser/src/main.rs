@@ -1,12 +1,10 @@
-#![feature(proc_macro)]
-
 extern crate kay;
 extern crate kay_simple_example_common;
 
 #[macro_use]
 extern crate stdweb;
 
-use kay::{Actor, ActorSystem, Networking};
+use kay::{ActorSystem, Networking, PeerAddress, Tuning, TypedID};
 use kay_simple_example_common::counter;
 
 use std::cell::RefCell;
@@ -21,7 +19,9 @@ fn main() {
         console.log("Starting actor system...");
     }
 
-    let mut system = ActorSystem::new(Networking::new(1, vec!["localhost:9999","wsclient"], 50_000, 30, 10));
+    let network = vec!["localhost:9999".parse().unwrap(), PeerAddress::ClientOnly];
+    let networking = Networking::new(1, network, 50_000, 30, 10).unwrap();
+    let mut system = ActorSystem::new(networking, Tuning::default());
     counter::setup(&mut system);
 
     js! {
@@ -32,7 +32,7 @@ fn main() {
 
     let world = &mut system.world();
 
-    counter::BrowserLoggerID::spawn(counter::Counter::global_broadcast(world), world);
+    counter::BrowserLoggerID::spawn(counter::CounterID::global_broadcast(world), world);
 
     system.process_all_messages();
 
@@ -52,7 +52,7 @@ impl Looper {
 
         system.networking_send_and_receive();
 
-        counter::Counter::global_broadcast(world).increment_by(13, world);
+        counter::CounterID::global_broadcast(world).increment_by(13, world);
 
         system.process_all_messages();
 
@@ -1,12 +1,15 @@
 extern crate kay;
 extern crate kay_simple_example_common;
 
-use kay::{ActorSystem, Networking};
+use kay::{ActorSystem, Networking, PeerAddress, Tuning};
 use kay_simple_example_common::counter;
 
 fn main() {
     println!("Creating actor system...");
-    let mut system = ActorSystem::new(Networking::new(0, vec!["localhost:9999","wsclient"], 50_000, 30, 10));
+    let network = vec!["localhost:9999".parse().unwrap(), PeerAddress::ClientOnly];
+    let networking =
+        Networking::new(0, network, 50_000, 30, 10).expect("Couldn't listen on localhost:9999");
+    let mut system = ActorSystem::new(networking, Tuning::default());
     counter::setup(&mut system);
 
     println!("Connecting to network...");
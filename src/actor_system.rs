@@ -1,17 +1,83 @@
 use crate::actor::{Actor, ActorOrActorTrait};
-use crate::class::{Class, ActorVTable};
-use crate::id::{MachineID, RawID};
-use crate::messaging::{Fate, Message, Packet};
-use crate::networking::Networking;
-use crate::type_registry::{ShortTypeId, TypeRegistry};
+#[cfg(feature = "admin")]
+use crate::admin::{AdminData, AdminEndpoint};
+use crate::columns::{ColumnLayout, Columns};
+#[cfg(feature = "serde-serialization")]
+use crate::console::{parse_command, ConsoleCommand};
+#[cfg(feature = "serde-serialization")]
+use crate::json::{to_json, JsonMessages};
+use crate::class::{Class, ActorVTable, ExternalState};
+use crate::class::inbox::Inbox;
+#[cfg(feature = "server")]
+use crate::class::{ActorStateVTable, InstanceStore};
+use crate::dead_letters::{DeadLetter, DeadLetters};
+use crate::diagnosis::{
+    ChunkHashesReply, ChunkHashesRequest, DesyncDiagnosis, Diagnosis, DiagnosisStep, DivergedChunk, FinishedDiagnosis,
+    InstanceHash, InstanceHashesReply, InstanceHashesRequest, INSTANCES_PER_CHUNK, INSTANCE_HASHES_TURNS_AHEAD,
+};
+use crate::determinism::{DeterminismAudit, NondeterminismReport, NondeterminismSource};
+use crate::debugger::{BreakpointID, DebugStop, Debugger, PacketDecoders, PacketHeader};
+use crate::gather::{Gather, GatherExpect, GatherID, GatherReply, GatherRequest, GatherResolved, Gathers};
+use crate::id_allocation::{IdAllocator, IdBlock};
+use crate::id::{parse_named, MachineID, NamedRawID, ParseRawIDError, RawID, TypedID};
+use crate::random::DeterministicRng;
+use crate::history::{InstanceHistory, RecordedHistory};
+use crate::interceptors::{Handled, Intercept, InterceptorID, Interceptors};
+use crate::interest::InterestDeclaration;
+use crate::inspector::{ClassInspection, ClassOccupancy, MemoryReport, NetworkingInspection, SystemInspection};
+use crate::journal::{JournalReplay, MessageJournal};
+use crate::messaging::{Correlation, Fate, Message, Packet};
+#[cfg(feature = "server")]
+use crate::messaging::HandlerFnRef;
+use crate::migration::{Forwarding, InstanceMigrated, MigrateInstance, Migrations};
+#[cfg(feature = "server")]
+use crate::parallel::ParallelProcessing;
+use crate::parallel::{defer, in_worker};
+#[cfg(feature = "server")]
+use crate::parallel::group_class;
+use crate::names::{NameRegistration, Names};
+use crate::networking::{
+    BufferPoolStats, DesyncDetected, LockstepWait, MessageTraffic, NetworkPaused, NetworkResumed, NetworkTraffic, Networking, NetworkingEvent,
+    PeerConnected, PeerDisconnected, PeerLagging, RemoteMessageRejected,
+};
+use crate::plugin::Plugin;
+use crate::placement::{LoadReport, Placement, PlacementPolicy, LOAD_REPORT_INTERVAL_TURNS};
+use crate::profiling::{ClassProfile, ProfilingReport};
+use crate::rate_limits::{RateLimitAction, RateLimitCounters, RateLimits};
+use crate::query::{handled_correlation, Asker, Queries, QueryHandle};
+use crate::scheduler::{ScheduledMessage, Scheduler};
+use crate::scratch::{Scratch, ScratchScope};
+use crate::shutdown::ShutdownReport;
+use crate::tasks::Tasks;
+use crate::trace::TraceRecorder;
+use crate::metrics::{MetricKind, MetricsWriter};
+use crate::scheduling::{Scheduling, SchedulingPolicy};
+use crate::snapshot::{ClassStorage, RestoredNames, SnapshotStorage};
+use crate::spatial::{Positioned, SpatialArea, SpatialGrid, SpatialIndices, SpatialQuery, SpatialQueryID, SpatialQueryResults};
+use crate::supervision::{HandlerPanicked, SupervisionPolicy};
+use crate::system_events::{SystemEvent, SystemEvents};
+#[cfg(feature = "server")]
+use crate::supervision::call_supervised;
+use crate::time::{duration_ms, now_ms};
+use crate::topics::{Topic, TopicSubscription, Topics};
+use crate::topology::{MessageTopology, TopologySampler};
+use crate::phases::{FlowEnforcement, FlowProblem, FlowViolation, MessageFlow};
+use crate::client_protocol::ClientProtocol;
+use crate::type_manifest::{TypeKind, TypeManifest};
+use crate::type_registry::{ShortTypeId, TypeIdUsage, TypeRegistration, TypeRegistry};
+use crate::watches::{NotifyTerminated, Terminated, Unwatch, Watch, Watches};
 use crate::tuning::Tuning;
 
+use compact::Compact;
 use std::collections::HashMap;
+use std::ops::Range;
 use std::panic::{catch_unwind, AssertUnwindSafe};
 use std::rc::Rc;
+use std::sync::mpsc::Receiver;
 
-const MAX_RECIPIENT_TYPES: usize = 64;
-pub const MAX_MESSAGE_TYPES: usize = 256;
+/// The class without instances that handles messages for the actor system itself,
+/// like replies to queries. Registered first, so it has the same type ID on all machines.
+struct SystemServices;
 
 /// Contains the state of a whole actor system
 /// and can be used for managing and progressing the actor system
@@ -22,12 +88,82 @@ pub struct ActorSystem {
     pub panic_happened: bool,
     actor_registry: TypeRegistry,
     message_registry: TypeRegistry,
-    classes: [Option<Class>; MAX_RECIPIENT_TYPES],
-    trait_implementors: [Option<Vec<ShortTypeId>>; MAX_RECIPIENT_TYPES],
-    message_statistics: [usize; MAX_MESSAGE_TYPES],
+    /// The number of registered actor and message types once setup was done (when messages
+    /// were first processed or exchanged), types registered later are replicated to peers
+    type_ids_after_setup: Option<(usize, usize)>,
+    /// The number of registered actor and message types when new types were last replicated
+    announced_type_ids: (usize, usize),
+    plugins: Vec<String>,
+    /// Indexed by type ID, with room for `Tuning::max_actor_types`
+    classes: Vec<Option<Class>>,
+    trait_implementors: Vec<Option<Vec<ShortTypeId>>>,
+    /// Indexed by type ID, with room for `Tuning::max_message_types`
+    message_statistics: Vec<usize>,
+    coalesced_messages: Vec<ShortTypeId>,
     networking: Networking,
-    storage: Rc<dyn chunky::ChunkStorage>,
-    tuning: Tuning
+    pub(crate) snapshots: Rc<SnapshotStorage>,
+    /// Storages given to classes with `use_storage_for`, taken when they are registered
+    class_storages: HashMap<ShortTypeId, (Rc<dyn chunky::ChunkStorage>, bool)>,
+    tuning: Tuning,
+    networking_event_recipient: Option<RawID>,
+    networking_pause_event_recipient: Option<RawID>,
+    networking_desync_event_recipient: Option<RawID>,
+    security_event_recipient: Option<RawID>,
+    supervisor: Option<RawID>,
+    queries: Queries,
+    gathers: Gathers,
+    scheduler: Scheduler,
+    tasks: Tasks,
+    /// Records the next turns as a Chrome trace, see `record_trace`
+    trace: Option<TraceRecorder>,
+    topics: Topics,
+    migrations: Migrations,
+    watches: Watches,
+    names: Names,
+    dead_letters: DeadLetters,
+    placement: Placement,
+    /// Instances spawned in this turn that are moved to the machine they were placed on
+    /// once the messages of the turn were handled
+    placed_spawns: Vec<(RawID, MachineID)>,
+    spatial: SpatialIndices,
+    profiling: bool,
+    profiled_turns: usize,
+    topology: Option<TopologySampler>,
+    flow: MessageFlow,
+    /// The names of the phases of each turn, in order
+    turn_phases: Vec<String>,
+    debugger: Option<Debugger>,
+    packet_decoders: PacketDecoders,
+    interceptors: Interceptors,
+    /// Instances whose history is recorded, with the interceptor recording their messages
+    histories: HashMap<RawID, (RecordedHistory, InterceptorID)>,
+    diagnosis: Diagnosis,
+    scheduling: Scheduling,
+    random_seed: u64,
+    #[cfg(feature = "server")]
+    parallel: Option<ParallelProcessing>,
+    /// The class whose messages are currently being handled
+    handling_class: Option<ShortTypeId>,
+    determinism_audit: Option<DeterminismAudit>,
+    /// Counts calls of `process_all_messages`, to reset `Scratch` arenas after each of them
+    scratch_turn: usize,
+    system_events: SystemEvents,
+    /// Did `shutdown` start? Messages from outside are dropped from then on
+    shut_down: bool,
+    /// The simulated time each turn advances, see `set_simulated_turn_duration`
+    simulated_turn_duration: ::std::time::Duration,
+    /// The turn the simulated turn duration was last changed in, and the simulated time before it
+    simulated_time_base: (usize, ::std::time::Duration),
+    #[cfg(feature = "admin")]
+    admin: Option<AdminEndpoint>,
+    /// Time spent handling messages since the last load report
+    busy_ms_since_load_report: f64,
+    /// Is `process_all_messages` running? Otherwise, sent messages are injected from outside
+    handling_messages: bool,
+    journal: Option<MessageJournal>,
+    journal_replay: Option<JournalReplay>,
+    #[cfg(feature = "serde-serialization")]
+    json_messages: JsonMessages,
 }
 
 impl ActorSystem {
@@ -36,36 +172,740 @@ impl ActorSystem {
         Self::new_with_storage(networking, Rc::new(chunky::HeapStorage), tuning)
     }
 
-    /// Create a new actor system that lives in memory and is persisted to disk using Mmapping
+    /// Create a new actor system that lives in memory and is persisted to disk using Mmapping.
+    /// Creating it again with the same directory (and registering the same types in the same order)
+    /// picks up the persisted state of all instances and inboxes without reading a snapshot.
     #[cfg(feature = "server")]
     pub fn new_mmap_persisted<P: AsRef<::std::path::Path>>(networking: Networking, directory: &P, tuning: Tuning) -> ActorSystem {
-        Self::new_with_storage(networking, Rc::new(chunky::MmapStorage::new(directory.as_ref().to_owned())), tuning)
+        let storage = Rc::new(chunky::MmapStorage::new(directory.as_ref().to_owned()));
+        let restored_names = RestoredNames { actors: Vec::new(), messages: Vec::new() };
+        Self::new_with_snapshot_storage(networking, SnapshotStorage::mapped(storage), restored_names, tuning)
+    }
+
+    /// Make sure all changes to the state of an actor system created with `new_mmap_persisted`,
+    /// and of classes given to `use_mmap_storage_for`, are written to their files, so it can be picked up from this point after a crash.
+    /// Should be called between calls to `process_all_messages`, blocks until the files are written.
+    #[cfg(all(feature = "server", unix))]
+    pub fn sync(&self) -> ::std::io::Result<()> {
+        assert!(!self.handling_messages, "Can't sync while handling messages");
+        self.snapshots.sync_mapped()
     }
 
-    /// Create a new actor system backed by any `chunky::ChunkStorage`
+    /// Create a new actor system backed by any `chunky::ChunkStorage`,
+    /// which allocates and frees the chunks all instances and inboxes are stored in
     pub fn new_with_storage(networking: Networking, storage: Rc<dyn chunky::ChunkStorage>, tuning: Tuning) -> ActorSystem {
-        ActorSystem {
+        let restored_names = RestoredNames { actors: Vec::new(), messages: Vec::new() };
+        Self::new_with_snapshot_storage(networking, SnapshotStorage::new(storage), restored_names, tuning)
+    }
+
+    /// Load an actor system that lives in memory only from a snapshot written with `save`.
+    /// The state of each actor class is restored when it is registered, so all actor
+    /// and message types need to be registered in the same order as in the saved system.
+    /// Scheduled messages, pending queries and topic subscriptions are not part of snapshots.
+    pub fn load<R: ::std::io::Read>(networking: Networking, reader: R, tuning: Tuning) -> ::std::io::Result<ActorSystem> {
+        Self::load_incremental(networking, reader, Vec::<R>::new(), tuning)
+    }
+
+    /// Like `load`, but applies incremental snapshots written with `save_incremental`
+    /// on top of the full snapshot `base`, in the order they were saved in
+    pub fn load_incremental<R: ::std::io::Read, I: IntoIterator<Item = R>>(networking: Networking, base: R, increments: I, tuning: Tuning) -> ::std::io::Result<ActorSystem> {
+        let (snapshots, mut restored_names) = SnapshotStorage::from_snapshot(Rc::new(chunky::HeapStorage), base)?;
+        for increment in increments {
+            restored_names = snapshots.apply_increment(increment)?;
+        }
+        Ok(Self::new_with_snapshot_storage(networking, snapshots, restored_names, tuning))
+    }
+
+    fn new_with_snapshot_storage(networking: Networking, snapshots: SnapshotStorage, restored_names: RestoredNames, tuning: Tuning) -> ActorSystem {
+        let snapshots = Rc::new(snapshots);
+        assert!(
+            tuning.max_actor_types > 1 && tuning.max_actor_types <= 1 << 16,
+            "Actor type IDs need to fit into a u16"
+        );
+        assert!(
+            tuning.max_message_types > 1 && tuning.max_message_types <= 1 << 16,
+            "Message type IDs need to fit into a u16"
+        );
+        let mut actor_registry = TypeRegistry::expecting(restored_names.actors);
+        actor_registry.set_max_id((tuning.max_actor_types - 1) as u16);
+        let mut message_registry = TypeRegistry::expecting(restored_names.messages);
+        message_registry.set_max_id((tuning.max_message_types - 1) as u16);
+        let mut system = ActorSystem {
             panic_happened: false,
-            trait_implementors: unsafe { make_array!(MAX_RECIPIENT_TYPES, |_| None) },
-            actor_registry: TypeRegistry::new(),
-            message_registry: TypeRegistry::new(),
-            classes: unsafe { make_array!(MAX_RECIPIENT_TYPES, |_| None) },
-            message_statistics: [0; MAX_MESSAGE_TYPES],
+            trait_implementors: (0..tuning.max_actor_types).map(|_| None).collect(),
+            actor_registry,
+            message_registry,
+            type_ids_after_setup: None,
+            announced_type_ids: (0, 0),
+            plugins: Vec::new(),
+            classes: (0..tuning.max_actor_types).map(|_| None).collect(),
+            message_statistics: vec![0; tuning.max_message_types],
+            scheduling: Scheduling::new(tuning.max_actor_types),
+            coalesced_messages: Vec::new(),
             networking,
-            storage,
-            tuning
+            snapshots,
+            class_storages: HashMap::new(),
+            tuning,
+            networking_event_recipient: None,
+            networking_pause_event_recipient: None,
+            networking_desync_event_recipient: None,
+            security_event_recipient: None,
+            supervisor: None,
+            queries: Queries::new(),
+            gathers: Gathers::new(),
+            scheduler: Scheduler::new(),
+            tasks: Tasks::new(),
+            trace: None,
+            topics: Topics::new(),
+            migrations: Migrations::new(),
+            watches: Watches::new(),
+            names: Names::new(),
+            dead_letters: DeadLetters::new(),
+            placement: Placement::new(),
+            placed_spawns: Vec::new(),
+            spatial: SpatialIndices::new(),
+            profiling: false,
+            profiled_turns: 0,
+            topology: None,
+            flow: MessageFlow::new(),
+            turn_phases: Vec::new(),
+            debugger: None,
+            packet_decoders: PacketDecoders::new(),
+            interceptors: Interceptors::new(),
+            histories: HashMap::new(),
+            diagnosis: Diagnosis::new(),
+            random_seed: 0,
+            #[cfg(feature = "server")]
+            parallel: None,
+            handling_class: None,
+            determinism_audit: None,
+            scratch_turn: 0,
+            system_events: SystemEvents::new(),
+            shut_down: false,
+            simulated_turn_duration: ::std::time::Duration::from_millis(0),
+            simulated_time_base: (0, ::std::time::Duration::from_millis(0)),
+            #[cfg(feature = "admin")]
+            admin: None,
+            busy_ms_since_load_report: 0.0,
+            handling_messages: false,
+            journal: None,
+            journal_replay: None,
+            #[cfg(feature = "serde-serialization")]
+            json_messages: JsonMessages::new(),
+        };
+
+        let services_id = system.actor_registry.get_or_register::<SystemServices>();
+        let services = Class::new(
+            ActorVTable::new_without_instances("SystemServices", system.message_statistics.len()),
+            Rc::new(ClassStorage::of_system(&system.snapshots)),
+            &system.tuning,
+            1,
+        );
+        system.classes[services_id.as_usize()] = Some(services);
+
+        system.add_service_handler(|subscription: &TopicSubscription, world: &mut World| {
+            let system: &mut ActorSystem = unsafe { &mut *world.0 };
+            system.topics.update(subscription);
+        });
+
+        system.add_service_handler(|migrate: &MigrateInstance, world: &mut World| {
+            let system: &mut ActorSystem = unsafe { &mut *world.0 };
+            let machine = system.networking.machine_id;
+            let n_turns = system.networking.n_turns;
+            let class = system.classes[migrate.old_id.type_id.as_usize()].as_mut().expect("Class of migrated instance should exist");
+            let new_id = class.add_migrated_instance(&migrate.state, migrate.old_id.type_id, machine, n_turns);
+            let all_services = system.services_id(machine).global_broadcast();
+            system.send(all_services, InstanceMigrated { old_id: migrate.old_id, new_id });
+        });
+
+        system.add_service_handler(|migrated: &InstanceMigrated, world: &mut World| {
+            let system: &mut ActorSystem = unsafe { &mut *world.0 };
+            let n_turns = system.networking.n_turns;
+            for forwarding in system.migrations.moved(migrated.old_id, migrated.new_id, n_turns) {
+                forwarding(migrated.new_id, world);
+            }
+            if !system.watches.is_empty() {
+                system.watches.moved_remote(migrated.old_id, migrated.new_id);
+                if migrated.old_id.machine == system.networking.machine_id {
+                    let new_services = system.services_id(migrated.new_id.machine);
+                    for observer in system.watches.take_observers(migrated.old_id) {
+                        system.send(new_services, Watch { target: migrated.new_id, observer });
+                    }
+                }
+            }
+        });
+
+        system.add_service_handler(|registration: &NameRegistration, world: &mut World| {
+            let system: &mut ActorSystem = unsafe { &mut *world.0 };
+            system.names.update(registration);
+        });
+
+        system.add_service_handler(|declaration: &InterestDeclaration, world: &mut World| {
+            let system: &mut ActorSystem = unsafe { &mut *world.0 };
+            system.networking.interests().update(declaration);
+        });
+
+        system.add_service_handler(|registration: &TypeRegistration, world: &mut World| {
+            let system: &mut ActorSystem = unsafe { &mut *world.0 };
+            let registry = if registration.actor {
+                &mut system.actor_registry
+            } else {
+                &mut system.message_registry
+            };
+            let short_id = ShortTypeId::new(registration.id).expect("Type IDs are never 0");
+            if let Err(problem) = registry.reserve(&registration.name, short_id) {
+                let event = SystemEvent::TypeConflict {
+                    from: registration.from,
+                    problem,
+                };
+                if system.system_events.is_empty() {
+                    eprintln!("{}", event);
+                } else {
+                    system.emit_system_event(event);
+                }
+            }
+        });
+
+        system.add_service_handler(|watch: &Watch, world: &mut World| {
+            let system: &mut ActorSystem = unsafe { &mut *world.0 };
+            system.watch_here(watch.target, watch.observer);
+        });
+
+        system.add_service_handler(|unwatch: &Unwatch, world: &mut World| {
+            let system: &mut ActorSystem = unsafe { &mut *world.0 };
+            system.watches.remove(unwatch.target, unwatch.observer);
+        });
+
+        system.add_service_handler(|notify: &NotifyTerminated, world: &mut World| {
+            let system: &mut ActorSystem = unsafe { &mut *world.0 };
+            system.watches.remove_remote(notify.target, notify.observer);
+            system.send(notify.observer, Terminated(notify.target));
+        });
+
+        system.add_service_handler(|query: &SpatialQuery, world: &mut World| {
+            let system: &mut ActorSystem = unsafe { &mut *world.0 };
+            let results = SpatialQueryResults {
+                query: query.query,
+                from: system.networking.machine_id,
+                ids: system.spatial.query(query.class, &query.area).into(),
+            };
+            let origin_services = system.services_id(query.origin);
+            system.send(origin_services, results);
+        });
+
+        system.add_service_handler(|results: &SpatialQueryResults, world: &mut World| {
+            let system: &mut ActorSystem = unsafe { &mut *world.0 };
+            if let Some((requester, found)) = system.spatial.add_results(results) {
+                system.send(requester, found);
+            }
+        });
+
+        system.add_service_handler(|request: &ChunkHashesRequest, world: &mut World| {
+            let system: &mut ActorSystem = unsafe { &mut *world.0 };
+            let own_hashes = system.diagnosis.chunk_hashes(request.n_turns as usize);
+            let reply = ChunkHashesReply {
+                machine: system.networking.machine_id,
+                n_turns: request.n_turns,
+                kept: own_hashes.is_some(),
+                hashes: own_hashes.map(|hashes| hashes.to_vec()).unwrap_or_default().into(),
+            };
+            let requester_services = system.services_id(request.requester);
+            system.send(requester_services, reply);
+        });
+
+        system.add_service_handler(|reply: &ChunkHashesReply, world: &mut World| {
+            let system: &mut ActorSystem = unsafe { &mut *world.0 };
+            let instances_turn = system.networking.n_turns + INSTANCE_HASHES_TURNS_AHEAD;
+            match system.diagnosis.chunk_hashes_received(reply, instances_turn) {
+                Some(DiagnosisStep::CompareInstances(n_turns, chunks)) => {
+                    let request = InstanceHashesRequest {
+                        requester: system.networking.machine_id,
+                        n_turns: n_turns as u64,
+                        chunks: chunks.into(),
+                    };
+                    let peer_services = system.services_id(reply.machine);
+                    system.send(peer_services, request);
+                }
+                Some(DiagnosisStep::Finished(finished)) => system.send_desync_diagnosis(finished),
+                None => {}
+            }
+        });
+
+        system.add_service_handler(|request: &InstanceHashesRequest, world: &mut World| {
+            let system: &mut ActorSystem = unsafe { &mut *world.0 };
+            // the instances are hashed at the end of turn `n_turns`, before it is counted
+            let n_turns = request.n_turns as usize;
+            if n_turns > system.networking.n_turns {
+                system
+                    .diagnosis
+                    .reply_after(n_turns, request.chunks.to_vec(), request.requester);
+            } else {
+                let kept = system.diagnosis.instance_hashes(n_turns, &request.chunks);
+                let reply = InstanceHashesReply {
+                    machine: system.networking.machine_id,
+                    n_turns: request.n_turns,
+                    in_time: kept.is_some(),
+                    hashes: kept.unwrap_or_default().into(),
+                };
+                let requester_services = system.services_id(request.requester);
+                system.send(requester_services, reply);
+            }
+        });
+
+        system.add_service_handler(|reply: &InstanceHashesReply, world: &mut World| {
+            let system: &mut ActorSystem = unsafe { &mut *world.0 };
+            for finished in system.diagnosis.peer_instances_hashed(reply) {
+                system.send_desync_diagnosis(finished);
+            }
+        });
+
+        system.add_service_handler(|expect: &GatherExpect, world: &mut World| {
+            let system: &mut ActorSystem = unsafe { &mut *world.0 };
+            if let Some(pending) = system.gathers.expect(expect) {
+                pending.finish(false, world);
+            }
+        });
+
+        system.add_service_handler(|resolved: &GatherResolved, world: &mut World| {
+            let system: &mut ActorSystem = unsafe { &mut *world.0 };
+            if let Some(pending) = system.gathers.resolved(resolved) {
+                pending.finish(false, world);
+            }
+        });
+
+        system.add_service_handler(|report: &LoadReport, world: &mut World| {
+            let system: &mut ActorSystem = unsafe { &mut *world.0 };
+            system.placement.update(report);
+        });
+
+        system
+    }
+
+    /// Write the state of all actor instances and their inboxes to a snapshot,
+    /// which can be turned into an identical system with `ActorSystem::load`.
+    /// Messages scheduled with `send_in_turns` or `send_after` aren't saved.
+    /// Should be called between calls to `process_all_messages`.
+    pub fn save<W: ::std::io::Write>(&self, writer: W) -> ::std::io::Result<()> {
+        self.save_external_states();
+        self.save_id_allocators();
+        self.save_spilled_messages()?;
+        self.snapshots.save(writer, &self.actor_registry.names(), &self.message_registry.names())
+    }
+
+    /// Write only the memory chunks that changed since the last snapshot (full or incremental)
+    /// was written, which is much faster for big systems that only change in parts.
+    /// Load it by applying it on top of its base with `ActorSystem::load_incremental`.
+    pub fn save_incremental<W: ::std::io::Write>(&self, writer: W) -> ::std::io::Result<()> {
+        self.save_external_states();
+        self.save_id_allocators();
+        self.save_spilled_messages()?;
+        self.snapshots.save_incremental(writer, &self.actor_registry.names(), &self.message_registry.names())
+    }
+
+    /// Fork the current state into an isolated actor system, for example to simulate a few
+    /// turns speculatively and then throw the fork away. The fork keeps the machine ID, turn
+    /// and random seed, but never connects to peers and drops all messages sent to other machines.
+    /// `setup` needs to register all actor and message types in the same order as for this system.
+    /// The memory of each class is copied from this system only when `setup` registers it,
+    /// without writing a snapshot first, and the classes `setup` doesn't register aren't copied.
+    /// Like snapshots, the fork doesn't contain the scheduled messages of this system.
+    /// Doesn't affect incremental snapshots of this system.
+    /// Should be called between calls to `process_all_messages`.
+    pub fn fork<F: FnOnce(&mut ActorSystem)>(&self, setup: F) -> ::std::io::Result<ActorSystem> {
+        assert!(!self.handling_messages, "Can't fork while handling messages");
+        self.save_external_states();
+        self.save_id_allocators();
+        self.save_spilled_messages()?;
+        let snapshots = SnapshotStorage::forked(&self.snapshots, Rc::new(chunky::HeapStorage));
+        let restored_names = RestoredNames {
+            actors: self.actor_registry.names().into_iter().map(str::to_owned).collect(),
+            messages: self.message_registry.names().into_iter().map(str::to_owned).collect(),
+        };
+        let mut fork = Self::new_with_snapshot_storage(
+            Networking::isolated(&self.networking),
+            snapshots,
+            restored_names,
+            self.tuning.clone(),
+        );
+        fork.random_seed = self.random_seed;
+        // this system can't change while `setup` runs, since it is borrowed
+        setup(&mut fork);
+        fork.snapshots.detach();
+        Ok(fork)
+    }
+
+    /// Write the saved states of all instances of synced external classes to following snapshots
+    fn save_external_states(&self) {
+        for class in self.classes.iter().filter_map(Option::as_ref) {
+            if let Some(states) = class.save_external_states() {
+                self.snapshots.set_extra(class.ident.sub("xtrn").0, states);
+            }
+        }
+    }
+
+    /// Write the state of the ID allocators of all classes to following snapshots
+    fn save_id_allocators(&self) {
+        for class in self.classes.iter().filter_map(Option::as_ref) {
+            if let Some(saved) = class.instance_store.saved_id_allocator() {
+                self.snapshots.set_extra(class.ident.sub("idal").0, saved);
+            }
+        }
+    }
+
+    /// Write the messages that inboxes spilled to disk to following snapshots,
+    /// since they aren't in any chunk
+    fn save_spilled_messages(&self) -> ::std::io::Result<()> {
+        for class in self.classes.iter().filter_map(Option::as_ref) {
+            if let Some(ExternalState::Excluded) = class.external {
+                continue;
+            }
+            for (ident, messages) in class.inbox.spilled_messages()? {
+                self.snapshots.set_extra(ident, messages);
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle a message for the actor system itself, once per message
+    fn add_service_handler<M: Message, F: Fn(&M, &mut World) + 'static>(&mut self, handler: F) {
+        let services_id = self.actor_registry.get::<SystemServices>();
+        let message_id = self.message_registry.get_or_register::<M>();
+        let class = self.classes[services_id.as_usize()].as_mut().expect("System services should exist");
+        class.add_class_handler(message_id, handler, true);
+    }
+
+    /// The system services of a machine
+    pub(crate) fn services_id(&mut self, machine: MachineID) -> RawID {
+        RawID::new(self.actor_registry.get::<SystemServices>(), 0, machine, 0)
+    }
+
+    /// Register a type that is used as a reply to queries (see `World::query`).
+    /// Needs to be done on all machines, in the same order as other registrations.
+    pub fn register_query_reply<R: Message>(&mut self) {
+        self.add_service_handler(|reply: &R, world: &mut World| {
+            let system: &mut ActorSystem = unsafe { &mut *world.0 };
+            system.queries.reply(handled_correlation().id, reply);
+        });
+    }
+
+    /// Send the reply to a query to the system services of the machine that asked it
+    pub(crate) fn send_reply<R: Message>(&mut self, correlation: Correlation, reply: R) {
+        let asker_services = self.services_id(correlation.machine);
+        self.send_correlated(asker_services, reply, correlation);
+    }
+
+    /// Register a request type for `World::gather`, together with the type of its replies.
+    /// Needs to be done on all machines, in the same order as other registrations.
+    pub fn register_gather<Req: Message, R: Message>(&mut self) {
+        let gather_type = self.message_registry.get_or_register::<Gather<Req>>();
+        self.gathers.register::<Req>(gather_type);
+        self.add_service_handler(|request: &GatherRequest<Req>, world: &mut World| {
+            let system: &mut ActorSystem = unsafe { &mut *world.0 };
+            let machine = system.networking.machine_id;
+            // the instances are only counted once each class handles the broadcast
+            let n_classes = system.local_classes_of(request.class);
+            system.send(
+                request.gatherer.services(),
+                GatherExpect { gather: request.gatherer.gather(), from: machine, n_classes: n_classes as u32 },
+            );
+            if n_classes > 0 {
+                let recipients = RawID::new(request.class, 0, machine, 0).local_broadcast();
+                system.send(recipients, Gather { request: request.request.clone(), gatherer: request.gatherer });
+            }
+        });
+        self.add_service_handler(|reply: &GatherReply<R>, world: &mut World| {
+            let system: &mut ActorSystem = unsafe { &mut *world.0 };
+            if let Some(pending) = system.gathers.reply(reply) {
+                pending.finish(false, world);
+            }
+        });
+    }
+
+    /// The number of classes a local broadcast to a class or trait reaches
+    fn local_classes_of(&self, type_id: ShortTypeId) -> usize {
+        if self.classes[type_id.as_usize()].is_some() {
+            1
+        } else {
+            self.trait_implementors[type_id.as_usize()]
+                .iter()
+                .flatten()
+                .filter(|implementor| self.classes[implementor.as_usize()].is_some())
+                .count()
         }
     }
 
-    /// Register a new actor class with the system (assigning it a type ID)
+    /// Store the instances and inbox of `A` in chunks of `storage` instead of the storage
+    /// of the whole system. To keep a big class in memory-mapped files, use `use_mmap_storage_for`,
+    /// so `sync` writes them too. Needs to be called before `A` is registered.
+    pub fn use_storage_for<A: Actor>(&mut self, storage: Rc<dyn chunky::ChunkStorage>) {
+        self.set_class_storage::<A>(storage, false);
+    }
+
+    /// Store the instances and inbox of `A` in memory-mapped files of `storage`,
+    /// which `sync` writes along with those of the system.
+    /// Needs to be called before `A` is registered.
+    #[cfg(feature = "server")]
+    pub fn use_mmap_storage_for<A: Actor>(&mut self, storage: Rc<chunky::MmapStorage>) {
+        self.set_class_storage::<A>(storage, true);
+    }
+
+    fn set_class_storage<A: Actor>(&mut self, storage: Rc<dyn chunky::ChunkStorage>, mapped: bool) {
+        let actor_id = self.actor_registry.get_or_register::<A>();
+        assert!(
+            self.classes[actor_id.as_usize()].is_none(),
+            "The storage of {} needs to be set before it is registered",
+            self.actor_registry.get_name(actor_id)
+        );
+        self.class_storages.insert(actor_id, (storage, mapped));
+    }
+
+    /// Register a new actor class with the system (assigning it a type ID).
+    /// Classes, traits and messages registered after setup (once messages were processed
+    /// or exchanged with peers) get their type IDs replicated to all machines,
+    /// which then need to register the same types before they receive messages for them.
     pub fn register<A: Actor>(&mut self) {
         // allow use of actor id before it is added
         let actor_id = self.actor_registry.get_or_register::<A>();
         // ...but still make sure it is only added once
         assert!(self.classes[actor_id.as_usize()].is_none());
         // Store pointer to the actor
-        let class = Class::new(ActorVTable::new_for_actor_type::<A>(), Rc::clone(&self.storage), &self.tuning);
+        let storage = match self.class_storages.remove(&actor_id) {
+            Some((inner, mapped)) => {
+                Rc::new(ClassStorage::new(Rc::clone(&self.snapshots), inner, mapped)) as Rc<dyn chunky::ChunkStorage>
+            }
+            None => Rc::new(ClassStorage::of_system(&self.snapshots)) as Rc<dyn chunky::ChunkStorage>,
+        };
+        let n_turn_phases = self.turn_phases.len().max(1);
+        let vtable = ActorVTable::new_for_actor_type::<A>(self.message_statistics.len());
+        let mut class = Class::new(vtable, storage, &self.tuning, n_turn_phases);
+        for message_type in &self.coalesced_messages {
+            class.inbox.coalesce(*message_type);
+        }
+        let snapshots = &self.snapshots;
+        if let Err(e) = class.inbox.restore_spilled(&|ident: &str| snapshots.take_restored(ident)) {
+            println!("Couldn't restore the spilled inbox of {}: {}", class.v_table.type_name, e);
+        }
         self.classes[actor_id.as_usize()] = Some(class);
+        if !self.system_events.is_empty() {
+            let name = self.actor_registry.get_name(actor_id).to_owned();
+            self.emit_system_event(SystemEvent::ClassRegistered {
+                type_id: actor_id.as_u16(),
+                name,
+            });
+        }
+    }
+
+    /// Register an actor class whose state holds `External`s (OS resources like files
+    /// or GPU handles, which can't be `Compact`). Its instances and inbox are left out of
+    /// snapshots, so the class starts out empty in loaded and forked systems,
+    /// and its instances can't be migrated (see `register_external_synced` for both).
+    pub fn register_external<A: Actor>(&mut self) {
+        self.register::<A>();
+        let class = self.class_mut::<A>();
+        class.external = Some(ExternalState::Excluded);
+        let prefix = format!("{}_", class.ident.0);
+        self.snapshots.exclude(prefix);
+    }
+
+    /// Register an actor class whose state holds `External`s, whose instances are written to
+    /// snapshots and migrated by saving each one to bytes with `save` and rebuilding it
+    /// with `load`, which needs to reacquire its OS resources. Rebuilt instances keep their ID
+    /// when loading a snapshot and get a new one when migrating, migrated instances are
+    /// dropped on their old machine.
+    pub fn register_external_synced<A: Actor, S: Fn(&A) -> Vec<u8> + 'static, L: Fn(&[u8]) -> A + 'static>(
+        &mut self,
+        save: S,
+        load: L,
+    ) {
+        self.register::<A>();
+        let ident = self.class_ref::<A>().ident.sub("xtrn").0;
+        let restored_states = self.snapshots.take_restored(&ident);
+        let class = self.class_mut::<A>();
+        class.sync_external(save, load);
+        if let Some(states) = restored_states {
+            class.restore_external_states(&states);
+        }
+    }
+
+    /// Handle at most `max_messages` messages of type `M` per instance of `A` within each
+    /// window of `window_turns` turns, protecting instances from being flooded (for example
+    /// by a buggy or malicious peer). `action` is taken for messages beyond the limit.
+    /// Broadcasts aren't limited.
+    pub fn rate_limit<A: Actor, M: Message>(&mut self, max_messages: usize, window_turns: usize, action: RateLimitAction) {
+        let message_type = self.message_registry.get_or_register::<M>();
+        self.class_mut::<A>()
+            .rate_limits
+            .get_or_insert_with(RateLimits::new)
+            .set(message_type, max_messages, window_turns, action);
+    }
+
+    /// How often the rate limits of each actor class and message type were exceeded
+    /// (see `rate_limit`), sorted by class and message type
+    pub fn rate_limit_counters(&self) -> Vec<RateLimitCounters> {
+        let mut counters = self
+            .classes
+            .iter()
+            .filter_map(Option::as_ref)
+            .filter_map(|class| {
+                let rate_limits = class.rate_limits.as_ref()?;
+                Some(rate_limits.counters(class.v_table.type_name, |message_type| {
+                    self.message_registry.get_name(message_type).clone()
+                }))
+            })
+            .flatten()
+            .collect::<Vec<_>>();
+        counters.sort_by(|a, b| (&a.class, &a.message_type).cmp(&(&b.class, &b.message_type)));
+        counters
+    }
+
+    /// Only handle the newest of the queued messages of type `M` for each recipient
+    /// (in all inboxes), for messages where only the latest one matters, like updates.
+    /// Older ones are skipped when the inbox is handled.
+    pub fn coalesce<M: Message>(&mut self) {
+        let message_type = self.message_registry.get_or_register::<M>();
+        if self.coalesced_messages.contains(&message_type) {
+            return;
+        }
+        self.coalesced_messages.push(message_type);
+        for class in self.classes.iter_mut().filter_map(Option::as_mut) {
+            class.inbox.coalesce(message_type);
+        }
+    }
+
+    /// Let a plugin register its classes, traits and handlers, namespacing all types
+    /// it registers for the first time with its name. Should be called between calls
+    /// to `process_all_messages`, can be called after setup (see `register`).
+    pub fn load_plugin(&mut self, plugin: &dyn Plugin) {
+        assert!(!self.handling_messages, "Plugins can't be loaded while handling messages");
+        let name = plugin.name().to_owned();
+        if self.plugins.contains(&name) {
+            println!("Plugin {} is already loaded", name);
+            return;
+        }
+        self.actor_registry.set_namespace(Some(name.clone()));
+        self.message_registry.set_namespace(Some(name.clone()));
+        plugin.setup(self);
+        self.actor_registry.set_namespace(None);
+        self.message_registry.set_namespace(None);
+        self.plugins.push(name);
+    }
+
+    /// Load a plugin from a dynamic library that exports it with `declare_plugin!`
+    #[cfg(all(feature = "server", unix))]
+    pub fn load_plugin_library(&mut self, path: &str) -> Result<(), String> {
+        let plugin = crate::plugin::load_library(path)?;
+        self.load_plugin(&*plugin);
+        Ok(())
+    }
+
+    /// The names of all loaded plugins, in the order they were loaded in
+    pub fn plugins(&self) -> &[String] {
+        &self.plugins
+    }
+
+    /// Reserve type IDs for the actor classes and traits (`actor_ids`) and the message types
+    /// (`message_ids`) that are registered under `namespace` (by the plugin with that name,
+    /// see `load_plugin`), so they get the same IDs no matter which other plugins are loaded.
+    /// No other types get IDs in these ranges. Needs to be done in the same way on all
+    /// machines, before registering types of the namespace.
+    pub fn reserve_type_ids(&mut self, namespace: &str, actor_ids: Range<u16>, message_ids: Range<u16>) -> Result<(), String> {
+        self.actor_registry.reserve_range(namespace, actor_ids)?;
+        self.message_registry.reserve_range(namespace, message_ids)
+    }
+
+    /// Give the actor class or trait `A` the type ID `id` once it is registered.
+    /// Fails if the ID is already used or reserved (see `reserve_type_ids`).
+    pub fn pin_actor_type_id<A: ActorOrActorTrait>(&mut self, id: u16) -> Result<(), String> {
+        self.actor_registry.pin::<A>(id)
+    }
+
+    /// Give the message type `M` the type ID `id` once it is registered.
+    /// Fails if the ID is already used or reserved (see `reserve_type_ids`).
+    pub fn pin_message_type_id<M: Message>(&mut self, id: u16) -> Result<(), String> {
+        self.message_registry.pin::<M>(id)
+    }
+
+    /// How many of the actor type IDs there is room for (see `Tuning::max_actor_types`) are used.
+    /// Registering a type when none are left panics with this report.
+    pub fn actor_type_id_usage(&self) -> TypeIdUsage {
+        self.actor_registry.usage()
+    }
+
+    /// How many of the message type IDs there is room for (see `Tuning::max_message_types`) are used.
+    /// Registering a type when none are left panics with this report.
+    pub fn message_type_id_usage(&self) -> TypeIdUsage {
+        self.message_registry.usage()
+    }
+
+    /// A manifest of the type IDs and layouts of all actor and message types registered here,
+    /// so external tools can interpret messages, IDs and snapshots of this system
+    /// (see `TypeManifest::to_text` and `TypeManifest::to_json`)
+    pub fn export_type_manifest(&self) -> TypeManifest {
+        let mut entries = self.actor_registry.export(TypeKind::Actor);
+        entries.extend(self.message_registry.export(TypeKind::Message));
+        TypeManifest { entries }
+    }
+
+    /// Describe the wire protocol with the types registered here, for clients that aren't written
+    /// in Rust (see `ClientProtocol::describe` and `ClientProtocol::typescript`)
+    pub fn client_protocol(&self) -> ClientProtocol {
+        let classes = &self.classes;
+        let messages = self
+            .message_registry
+            .export(TypeKind::Message)
+            .into_iter()
+            .map(|entry| {
+                let short_id = ShortTypeId::new(entry.id).expect("Type IDs are never 0");
+                let packet_size = classes
+                    .iter()
+                    .filter_map(Option::as_ref)
+                    .find_map(|class| class.packet_size(short_id));
+                (entry, packet_size)
+            })
+            .collect();
+        ClientProtocol::new(self.actor_registry.export(TypeKind::Actor), messages)
+    }
+
+    /// Check that all types of `manifest` are registered here with the same type IDs and layouts,
+    /// for example before handing wire data or snapshots of this system to a tool built against it.
+    /// The error lists all differences.
+    pub fn validate_type_manifest(&self, manifest: &TypeManifest) -> Result<(), String> {
+        let differences = manifest.differences(&self.export_type_manifest());
+        if differences.is_empty() {
+            Ok(())
+        } else {
+            Err(differences.join("\n"))
+        }
+    }
+
+    /// Use the type IDs of `manifest` (exported by another build or tool) for its types that aren't
+    /// registered yet, so they get the same IDs once registered, like with `pin_actor_type_id`.
+    /// Types that are already registered are validated like with `validate_type_manifest`.
+    pub fn import_type_manifest(&mut self, manifest: &TypeManifest) -> Result<(), String> {
+        let local = self.export_type_manifest();
+        let (registered, unregistered): (Vec<_>, Vec<_>) = manifest
+            .entries
+            .iter()
+            .cloned()
+            .partition(|entry| local.get(entry.kind, &entry.name).is_some());
+        let mut problems = TypeManifest { entries: registered }.differences(&local);
+        for entry in unregistered {
+            let registry = match entry.kind {
+                TypeKind::Actor => &mut self.actor_registry,
+                TypeKind::Message => &mut self.message_registry,
+            };
+            let reserved = ShortTypeId::new(entry.id)
+                .ok_or_else(|| format!("{} has the invalid type ID 0", entry.name))
+                .and_then(|short_id| registry.reserve(&entry.name, short_id));
+            if let Err(problem) = reserved {
+                problems.push(problem);
+            }
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems.join("\n"))
+        }
     }
 
     /// Register a dummy actor class without allocating any resources or dispatchers.
@@ -108,6 +948,44 @@ impl ActorSystem {
         class.add_handler(message_id, handler, critical);
     }
 
+    /// Add a handler to a registered actor class that gets all messages of type `M` that were
+    /// queued for an instance when its inbox was handled, in one slice, to process them in a tight loop.
+    /// Broadcasts are batched separately, each instance gets all of them in one slice.
+    /// Messages that are part of a query (see `World::query`) are batched separately per query,
+    /// so the handler can answer them with `World::asker`.
+    /// Batches are handled after all other messages in the inbox.
+    pub fn add_batch_handler<A: Actor, M: Message, F: Fn(&[M], &mut A, &mut World) -> Fate + 'static>(
+        &mut self,
+        handler: F,
+        critical: bool,
+    ) {
+        let actor_id = self.actor_registry.get::<A>();
+        let message_id = self.message_registry.get_or_register::<M>();
+        let class = self.classes[actor_id.as_usize()].as_mut().expect("Actor not added yet");
+        class.add_batch_handler(message_id, handler, critical);
+    }
+
+    /// Keep the fields of `layout` of all instances of a registered actor class in parallel arrays
+    /// (a structure of arrays) for column handlers, see `add_column_handler`
+    pub fn set_column_layout<A: Actor>(&mut self, layout: ColumnLayout<A>) {
+        self.class_mut::<A>().set_column_layout(layout);
+    }
+
+    /// Add a handler to a registered actor class with a column layout that works on the
+    /// columns of the recipient, or of all instances at once for broadcasts, so bulk updates
+    /// like per-agent physics can loop over whole arrays of fields (and be autovectorized).
+    /// The columns are loaded from the instances by the first broadcast in a message cycle,
+    /// and written back once the instances are needed otherwise, or the inbox was handled.
+    /// Messages to single instances copy the fields of the recipient before and after the handler.
+    pub fn add_column_handler<A: Actor, M: Message, F: Fn(&M, &mut Columns, &mut World) + 'static>(
+        &mut self,
+        handler: F,
+        critical: bool,
+    ) {
+        let message_id = self.message_registry.get_or_register::<M>();
+        self.class_mut::<A>().add_column_handler(message_id, handler, critical);
+    }
+
     /// Add an actor spawner to a registered actor class
     pub fn add_spawner<A: Actor, M: Message, F: Fn(&M, &mut World) -> A + 'static>(
         &mut self,
@@ -120,127 +998,1713 @@ impl ActorSystem {
         class.add_spawner(message_id, constructor, critical);
     }
 
-    /// Manually send a message
-    pub fn send<M: Message>(&mut self, recipient: RawID, message: M) {
-        let packet = Packet {
-            recipient_id: recipient,
-            message,
-        };
+    /// Call `hook` with every new instance of a registered actor class,
+    /// right after it was constructed by a spawner
+    pub fn on_spawn<A: Actor, F: Fn(&mut A, &mut World) + 'static>(&mut self, hook: F) {
+        self.class_mut::<A>().set_on_spawn(hook);
+    }
 
-        let to_here = recipient.machine == self.networking.machine_id;
-        let global = recipient.is_global_broadcast();
+    /// Call `hook` with every instance of a registered actor class that dies by returning
+    /// `Fate::Die` (or is restarted by its supervision policy), right before its state is dropped
+    pub fn on_destroy<A: Actor, F: Fn(&mut A, &mut World) + 'static>(&mut self, hook: F) {
+        self.class_mut::<A>().set_on_destroy(hook);
+    }
 
-        if !to_here || global {
-            self.networking
-                .enqueue(self.message_registry.get::<M>(), packet.clone());
-        }
+    /// Call `hook` with every instance of a registered actor class that is about to be moved
+    /// to another machine (see `migrate`), with the machine it moves to
+    pub fn on_migrate<A: Actor, F: Fn(&A, MachineID, &mut World) + 'static>(&mut self, hook: F) {
+        self.class_mut::<A>().set_on_migrate(hook);
+    }
 
-        if to_here || global {
-            if let Some(class) = self.classes[recipient.type_id.as_usize()].as_mut() {
-                class.inbox.put(packet, &self.message_registry);
-            } else if let Some(implementors) = self.trait_implementors[recipient.type_id.as_usize()].as_ref() {
-                for implementor_type_id in implementors {
-                    let class = self.classes[implementor_type_id.as_usize()].as_mut().expect("Implementor should exist");
-                    class.inbox.put(packet.clone(), &self.message_registry);
-                }
-            } else {
-                panic!(
-                    "Recipient {} doesn't exist, or Trait has no implementors",
-                    self.actor_registry.get_name(recipient.type_id),
-                );
-            }
-        }
+    fn class_mut<A: Actor>(&mut self) -> &mut Class {
+        let actor_id = self.actor_registry.get::<A>();
+        self.classes[actor_id.as_usize()].as_mut().expect("Actor not added yet")
     }
 
-    /// Get a base RawID for an actor or actor trait
-    pub fn id<A: ActorOrActorTrait>(&mut self) -> RawID {
-        RawID::new(self.short_id::<A>(), 0, self.networking.machine_id, 0)
+    fn class_ref<A: Actor>(&self) -> &Class {
+        let actor_id = self.actor_registry.get::<A>();
+        self.classes[actor_id.as_usize()].as_ref().expect("Actor not added yet")
     }
 
-    fn short_id<A: ActorOrActorTrait>(&mut self) -> ShortTypeId {
-        self.actor_registry.get_or_register::<A>()
+    /// The number of instances of `A` on this machine
+    pub fn instance_count<A: Actor>(&self) -> usize {
+        *self.class_ref::<A>().instance_store.n_instances
     }
 
-    fn single_message_cycle(&mut self) {
-        let mut world = World(self as *const Self as *mut Self);
+    /// A read-only view of the state of an instance of `A`, if it lives on this machine.
+    /// Should be used between calls to `process_all_messages`,
+    /// for example to extract data for rendering without asking each instance for it.
+    pub fn instance<A: Actor>(&self, id: <A as Actor>::ID) -> Option<&A> {
+        self.class_ref::<A>()
+            .instance_store
+            .get(id.as_raw())
+            .map(|actor| unsafe { &*(actor as *const A) })
+    }
 
-        for maybe_class in self.classes.iter_mut() {
-            if let Some(class) = maybe_class.as_mut() {
-                class.handle_messages(&mut self.message_statistics, &mut world);
+    /// The state of an instance of `A` on this machine as JSON, for external tools
+    #[cfg(feature = "serde-serialization")]
+    pub fn instance_json<A: Actor + ::serde::Serialize>(&self, id: <A as Actor>::ID) -> Option<String> {
+        self.instance::<A>(id).map(to_json)
+    }
+
+    /// Allow sending messages of type `M` as JSON with `send_json`,
+    /// for example from REST APIs, scripts or save-game editors
+    #[cfg(feature = "serde-serialization")]
+    pub fn register_json<M: Message + ::serde::de::DeserializeOwned>(&mut self) {
+        let message_id = self.message_registry.get_or_register::<M>();
+        let type_name = self.message_registry.get_name(message_id).clone();
+        self.json_messages.register::<M>(type_name);
+    }
+
+    /// The type names of all message types registered with `register_json`, sorted
+    #[cfg(feature = "serde-serialization")]
+    pub fn json_message_types(&self) -> Vec<&str> {
+        self.json_messages.type_names()
+    }
+
+    /// Send a message given as JSON, with the full type name it was registered with
+    /// in `register_json`. Fails if the type isn't registered or the JSON doesn't match it.
+    #[cfg(feature = "serde-serialization")]
+    pub fn send_json(&mut self, recipient: RawID, type_name: &str, json: &str) -> Result<(), String> {
+        let mut world = World(self as *mut Self);
+        self.json_messages.send(recipient, type_name, json, &mut world)
+    }
+
+    /// Run a command of the actor console, for in-game debug consoles and remote admin.
+    /// Commands are
+    ///
+    /// - `send <recipient> <message type> [<message>]`, like
+    ///   `send Car.local#42@0 SetDestination {x: 10, y: 20}`, to send a message registered
+    ///   with `register_json` to a recipient in the format of `parse_id`.
+    ///   Message types can be named without their module path, keys of the message
+    ///   given as JSON don't need to be quoted, and a missing message is `null` (for unit structs).
+    /// - `types`, to list the message types that can be sent
+    ///
+    /// Returns the output of the command or why it failed.
+    #[cfg(feature = "serde-serialization")]
+    pub fn run_console_command(&mut self, command: &str) -> Result<String, String> {
+        match parse_command(command)? {
+            ConsoleCommand::Send {
+                recipient,
+                message_type,
+                json,
+            } => {
+                let recipient = self
+                    .parse_id(recipient)
+                    .map_err(|e| format!("Invalid recipient {}: {}", recipient, e))?;
+                let message_id = self
+                    .message_registry
+                    .get_by_display_name(message_type)
+                    .ok_or_else(|| format!("Unknown message type {}", message_type))?;
+                let type_name = self.message_registry.get_name(message_id).clone();
+                self.send_json(recipient, &type_name, &json)?;
+                Ok(format!("Sent {} to {}", message_type, self.display_id(recipient)))
             }
+            ConsoleCommand::Types => Ok(self
+                .json_messages
+                .type_names()
+                .into_iter()
+                .filter_map(|type_name| self.message_registry.get_by_name(type_name))
+                .map(|message_id| self.message_registry.display_name(message_id))
+                .collect::<Vec<_>>()
+                .join("\n")),
         }
     }
 
-    /// Process and handle all enqueued messages in the system
-    /// and the resulting messages, up to a recursion depth of 1000
-    pub fn process_all_messages(&mut self) {
-        let result = catch_unwind(AssertUnwindSafe(|| {
-            for _i in 0..1000 {
-                self.single_message_cycle();
-            }
-        }));
+    /// Call `f` with a read-only view of the state of each instance of `A` on this machine,
+    /// in storage order (see `instance`)
+    pub fn for_each_instance<A: Actor, F: FnMut(&A)>(&self, mut f: F) {
+        self.class_ref::<A>()
+            .instance_store
+            .for_each(|actor| f(unsafe { &*(actor as *const A) }));
+    }
 
-        if result.is_err() {
-            self.panic_happened = true;
+    /// Keep an index of where the instances of `A` are, to find them with `instances_in`
+    /// and `World::find_in`. It is a uniform grid with cells of `cell_size`
+    /// (ideally about the size of typical queries), rebuilt after each `process_all_messages`.
+    pub fn enable_spatial_index<A: Actor + Positioned>(&mut self, cell_size: f32) {
+        let class = self.actor_registry.get::<A>();
+        let position = Box::new(|actor: *const ()| unsafe { &*(actor as *const A) }.position());
+        self.spatial.grids.insert(class, SpatialGrid::new(cell_size, position));
+    }
+
+    fn rebuild_spatial_indices(&mut self) {
+        let classes = &self.classes;
+        for (class_id, grid) in self.spatial.grids.iter_mut() {
+            grid.clear();
+            if let Some(class) = classes[class_id.as_usize()].as_ref() {
+                let get_raw_id = &class.v_table.state_v_table.get_raw_id;
+                class.instance_store.for_each(|actor| {
+                    let position = (grid.position)(actor);
+                    grid.insert(get_raw_id(actor), position);
+                });
+            }
         }
     }
 
-    /// Get a `World` handle for the system.
-    pub fn world(&mut self) -> World {
-        World(self as *mut Self)
+    /// The instances of `A` on this machine that were in `area` at the end of the last
+    /// `process_all_messages`, needs a spatial index (see `enable_spatial_index`)
+    pub fn instances_in<A: Actor>(&self, area: SpatialArea) -> Vec<RawID> {
+        let class = self.actor_registry.get::<A>();
+        assert!(self.spatial.grids.contains_key(&class), "Spatial index should be enabled with enable_spatial_index");
+        self.spatial.query(class, &area)
     }
 
-    /// Connect to peers in the networking topology.
-    pub fn networking_connect(&mut self) {
-        self.networking.connect();
+    /// Add many instances of `A` on this machine at once, without sending a spawn message for each,
+    /// for example when creating a world. The instances get consecutive IDs, which are set
+    /// in their states (so the IDs in `states` don't matter). Returns the IDs in the order of `states`.
+    /// Like instances spawned by a local broadcast, instances that belong on another machine
+    /// (see `set_placement` and `set_sharding`) migrate there at the end of the turn.
+    pub fn spawn_many<A: Actor, I: IntoIterator<Item = A>>(&mut self, states: I) -> Vec<<A as Actor>::ID> {
+        let mut world = World(self as *const Self as *mut Self);
+        let base_id = self.id::<A>();
+        let n_turns = self.networking.n_turns;
+        self.class_mut::<A>()
+            .spawn_many(states, base_id, n_turns, &mut world)
+            .into_iter()
+            .map(TypedID::from_raw)
+            .collect()
     }
 
-    /// Send and receive messages from peers in the networking topology.
-    pub fn networking_send_and_receive(&mut self) {
-        self.networking
-            .send_and_receive(&mut self.classes, &mut self.trait_implementors);
+    /// How well the storage of the local instances of `A` is used,
+    /// to decide when to `compact` it
+    pub fn occupancy<A: Actor>(&self) -> ClassOccupancy {
+        let class = self.class_ref::<A>();
+        class.instance_store.occupancy(&class.v_table.state_v_table)
     }
 
-    /// Mark the local "networking turn" as finished. Networking turns are
-    /// used to track and manage time drift between peers in the networking topology.
-    pub fn networking_finish_turn(&mut self) -> Option<usize> {
-        self.networking.finish_turn()
+    /// Move the local instances of `A` that shrunk into storage slots that fit them,
+    /// keeping their IDs. Should be called between calls to `process_all_messages`.
+    /// Returns how many instances were moved.
+    pub fn compact<A: Actor>(&mut self) -> usize {
+        assert!(!self.handling_messages, "Instances can't be compacted while handling messages");
+        let class = self.class_mut::<A>();
+        class.instance_store.compact(&class.v_table.state_v_table)
     }
 
-    /// Get the machine ID of this system in the network
-    pub fn networking_machine_id(&self) -> MachineID {
-        self.networking.machine_id
+    /// Decide what happens when a message handler of a registered actor class panics.
+    /// Panics inside spawners always escalate.
+    pub fn supervise<A: Actor>(&mut self, policy: SupervisionPolicy) {
+        let actor_id = self.actor_registry.get::<A>();
+        let class = self.classes[actor_id.as_usize()].as_mut().expect("Actor not added yet");
+        class.supervision = policy;
     }
 
-    /// Get the local number of networking turns
-    pub fn networking_n_turns(&self) -> usize {
-        self.networking.n_turns
+    /// Send a `HandlerPanicked` message to `supervisor` whenever a message handler panics
+    pub fn set_supervisor(&mut self, supervisor: RawID) {
+        self.supervisor = Some(supervisor);
     }
 
-    /// Get a summary of the **local view** of the networking turn state of all connected peers.
-    pub fn networking_debug_all_n_turns(&self) -> HashMap<MachineID, isize> {
-        self.networking.debug_all_n_turns()
+    /// Journal every message sent from outside of message handlers (with the turn
+    /// it was sent in) to `writer`, to reproduce a run later with `replay_journal`
+    pub fn start_journal<W: ::std::io::Write + 'static>(&mut self, writer: W) -> ::std::io::Result<()> {
+        self.journal = Some(MessageJournal::new(Box::new(writer))?);
+        Ok(())
     }
 
-    /// Get local instance counts of each actor class
-    pub fn get_instance_counts(&self) -> HashMap<String, usize> {
-        self.classes
-            .iter()
-            .filter_map(|maybe_class| maybe_class.as_ref())
-            .map(|class| {
-                (
-                    class.v_table.type_name.split("::").last().unwrap().replace(">", ""),
-                    *class.instance_store.n_instances,
-                )
-            }).collect()
+    /// Feed the messages of a journal written with `start_journal` into this (fresh) system,
+    /// each in the turn it was originally sent in, to reproduce a run deterministically.
+    /// All types need to be registered in the same order as in the journaled system.
+    /// While replaying, messages sent from outside of message handlers are ignored.
+    pub fn replay_journal<R: ::std::io::Read>(&mut self, reader: R) -> ::std::io::Result<()> {
+        self.journal_replay = Some(JournalReplay::new(reader)?);
+        Ok(())
     }
 
-    /// Get statistics of sent messages per type
-    pub fn get_message_statistics(&self) -> HashMap<String, usize> {
-        self.message_statistics
-            .iter()
-            .enumerate()
-            .filter_map(|(i, n_sent)| {
+    /// The number of journaled messages that weren't replayed yet
+    pub fn journal_messages_remaining(&self) -> usize {
+        self.journal_replay.as_ref().map(JournalReplay::remaining).unwrap_or(0)
+    }
+
+    /// Manually send a message
+    pub fn send<M: Message>(&mut self, recipient: RawID, message: M) {
+        self.send_with_key(recipient, message, None, Correlation::none())
+    }
+
+    /// Send a message that can be cancelled with `cancel_messages(key)` until it is handled
+    /// (or, if it goes to peers, until it is sent at the end of the turn, unless its type is
+    /// delta encoded), for example for commands that are made obsolete by newer ones.
+    /// Several messages can share a key.
+    pub fn send_cancellable<M: Message>(&mut self, recipient: RawID, message: M, key: u64) {
+        self.send_with_key(recipient, message, Some(key), Correlation::none())
+    }
+
+    /// Cancel all messages sent with `send_cancellable` and `key` that are still queued
+    /// in local inboxes or not yet sent to peers. Returns how many were cancelled
+    /// (counting a message once for each inbox and peer it was queued for).
+    pub fn cancel_messages(&mut self, key: u64) -> usize {
+        let cancelled_here = self
+            .classes
+            .iter_mut()
+            .filter_map(Option::as_mut)
+            .map(|class| class.inbox.cancel(key))
+            .sum::<usize>();
+        cancelled_here + self.networking.cancel(key)
+    }
+
+    /// Send a message as the request of or the reply to a query, see `World::query`
+    pub(crate) fn send_correlated<M: Message>(&mut self, recipient: RawID, message: M, correlation: Correlation) {
+        self.send_with_key(recipient, message, None, correlation)
+    }
+
+    fn send_with_key<M: Message>(&mut self, recipient: RawID, message: M, key: Option<u64>, correlation: Correlation) {
+        if !self.handling_messages && (self.journal_replay.is_some() || self.shut_down) {
+            return;
+        }
+
+        let mut recipient = if self.migrations.is_empty() {
+            recipient
+        } else {
+            self.migrations.resolve(recipient)
+        };
+
+        if recipient.is_global_broadcast() {
+            let message_id = self.message_registry.get::<M>();
+            let message_ptr = &message as *const M as *const ();
+            if let Some(owner) = self.placement.owner_of_broadcast(recipient.type_id, message_id, message_ptr) {
+                recipient.machine = owner;
+            }
+        }
+
+        if let Some(ref mut topology) = self.topology {
+            topology.record(self.handling_class, self.message_registry.get::<M>(), recipient.type_id);
+        }
+
+        if let (Some(_), Some(sender)) = (self.flow.enforcement(), self.handling_class) {
+            self.check_flow_of_send(sender, self.message_registry.get::<M>(), recipient.type_id);
+        }
+
+        let packet = Packet {
+            recipient_id: recipient,
+            correlation,
+            message,
+        };
+
+        let global = recipient.is_global_broadcast();
+        let multicast = recipient.machine.is_multicast_group();
+        let to_here = if multicast {
+            self.networking.is_multicast_group_member(recipient.machine)
+        } else {
+            global || recipient.machine == self.networking.machine_id
+        };
+
+        if !to_here || global || multicast {
+            self.networking
+                .enqueue(self.message_registry.get::<M>(), packet.clone(), key, self.handling_class);
+        }
+
+        if to_here {
+            if !self.handling_messages {
+                if let Some(journal) = self.journal.as_mut() {
+                    journal.record(self.networking.n_turns, &packet, &self.message_registry);
+                }
+            }
+
+            let message_registry = &self.message_registry;
+            let put = |inbox: &mut Inbox, packet: Packet<M>| match key {
+                Some(key) => inbox.put_cancellable(packet, message_registry, key),
+                None => inbox.put(packet, message_registry),
+            };
+            if let Some(class) = self.classes[recipient.type_id.as_usize()].as_mut() {
+                put(&mut class.inbox, packet);
+            } else if let Some(implementors) = self.trait_implementors[recipient.type_id.as_usize()].as_ref() {
+                for implementor_type_id in implementors {
+                    let class = self.classes[implementor_type_id.as_usize()].as_mut().expect("Implementor should exist");
+                    put(&mut class.inbox, packet.clone());
+                }
+            } else {
+                panic!(
+                    "Recipient {} doesn't exist, or Trait has no implementors",
+                    self.actor_registry.get_name(recipient.type_id),
+                );
+            }
+        }
+    }
+
+    /// Set the seed all random streams from `World::rng` are derived from.
+    /// Needs to be the same on all machines, before the first turn.
+    pub fn set_random_seed(&mut self, seed: u64) {
+        self.random_seed = seed;
+    }
+
+    /// Send a message once `n_turns` more networking turns have started
+    /// (deterministic across machines)
+    pub fn send_in_turns<M: Message>(&mut self, recipient: RawID, message: M, n_turns: usize) -> ScheduledMessage {
+        let due_turn = self.networking.n_turns + n_turns;
+        self.scheduler.in_turn(due_turn, Box::new(move |system: &mut ActorSystem| system.send(recipient, message)))
+    }
+
+    /// Send a message once `delay` has passed (as measured by the local clock)
+    pub fn send_after<M: Message>(&mut self, recipient: RawID, message: M, delay: ::std::time::Duration) -> ScheduledMessage {
+        self.report_nondeterminism(NondeterminismSource::WallClock, "send_after");
+        let due_ms = now_ms() + duration_ms(delay);
+        self.scheduler.at_time(due_ms, Box::new(move |system: &mut ActorSystem| system.send(recipient, message)))
+    }
+
+    /// Cancel a scheduled message, returns false if it was already sent or cancelled
+    pub fn cancel_scheduled(&mut self, scheduled: ScheduledMessage) -> bool {
+        self.scheduler.cancel(scheduled)
+    }
+
+    /// Run `task` (for example blocking I/O) on a background thread, and send its result
+    /// to `recipient` as a message at the start of the first turn after it finished.
+    /// Results only arrive on this machine and at a time that depends on it,
+    /// so they should only affect other machines through messages the recipient sends.
+    /// In the browser, which has no threads, `task` runs on this thread between turns instead.
+    pub fn spawn_task<M: Message + Send, F: FnOnce() -> M + Send + 'static>(&mut self, recipient: RawID, task: F) {
+        self.report_nondeterminism(NondeterminismSource::WallClock, "spawn_task");
+        self.tasks.spawn(recipient, task);
+    }
+
+    /// Poll `future` between turns (whenever it was woken) on this thread, and send its output
+    /// to `recipient` as a message at the start of the first turn after it completed.
+    /// Works in the browser, where futures are woken by the event loop, like `spawn_task` otherwise.
+    pub fn spawn_future<M: Message, F: ::std::future::Future<Output = M> + 'static>(&mut self, recipient: RawID, future: F) {
+        self.report_nondeterminism(NondeterminismSource::WallClock, "spawn_future");
+        self.tasks.spawn_future(recipient, future);
+    }
+
+    /// Subscribe `subscriber` to all messages published to `topic`, on all machines.
+    /// Machines only learn about subscriptions made while they are connected.
+    pub fn subscribe<M: Message>(&mut self, topic: Topic<M>, subscriber: RawID) {
+        let key = self.topic_key(topic);
+        self.replicate_subscription(key, subscriber, true);
+    }
+
+    /// Stop sending messages published to `topic` to `subscriber`
+    pub fn unsubscribe<M: Message>(&mut self, topic: Topic<M>, subscriber: RawID) {
+        let key = self.topic_key(topic);
+        self.replicate_subscription(key, subscriber, false);
+    }
+
+    /// Topics of different message types are different, even if they have the same name
+    fn topic_key<M: Message>(&mut self, topic: Topic<M>) -> u64 {
+        let message_type_id = self.message_registry.get_or_register::<M>();
+        topic.key(message_type_id)
+    }
+
+    fn replicate_subscription(&mut self, topic: u64, subscriber: RawID, subscribed: bool) {
+        let subscription = TopicSubscription { topic, subscriber, subscribed };
+        // apply locally right away, so messages published until
+        // the broadcast is handled already reach the subscriber
+        self.topics.update(&subscription);
+        let all_services = self.services_id(self.networking.machine_id).global_broadcast();
+        self.send(all_services, subscription);
+    }
+
+    /// Register `id` under `name` on all machines, so it can be found with `resolve_name`.
+    /// Replaces an actor registered under the same name before.
+    /// Names of actors on a machine are forgotten when it disconnects.
+    pub fn register_name(&mut self, name: &str, id: RawID) {
+        self.replicate_name(name, id, true);
+    }
+
+    /// Remove `name` on all machines, if `id` is still registered under it
+    pub fn unregister_name(&mut self, name: &str, id: RawID) {
+        self.replicate_name(name, id, false);
+    }
+
+    fn replicate_name(&mut self, name: &str, id: RawID, registered: bool) {
+        let registration = NameRegistration {
+            name: name.to_owned().into(),
+            id,
+            registered,
+            n_turns: self.networking.n_turns as u32,
+            from: self.networking.machine_id,
+        };
+        // apply locally right away, like subscriptions
+        self.names.update(&registration);
+        let all_services = self.services_id(self.networking.machine_id).global_broadcast();
+        self.send(all_services, registration);
+    }
+
+    /// The actor registered under `name`, as currently known on this machine
+    pub fn resolve_name(&self, name: &str) -> Option<RawID> {
+        self.names.resolve(name)
+    }
+
+    /// Only send broadcasts of `M` to peers that are interested in the position of the message,
+    /// see `set_interest_areas`. Needs to be done on the sending machines.
+    pub fn set_interest_position<M: Message + Positioned>(&mut self) {
+        let message_id = self.message_registry.get_or_register::<M>();
+        let position = Box::new(|message: *const ()| unsafe { &*(message as *const M) }.position());
+        self.networking.interests().set_position(message_id, position);
+    }
+
+    /// Receive broadcasts and multicasts of positioned message types (see `set_interest_position`)
+    /// from peers only if they are in one of `areas`, or from everywhere with `None` (the default).
+    /// Can be changed anytime, like when the area a machine simulates or shows moves.
+    /// Peers keep sending everything until they learn about a change.
+    pub fn set_interest_areas(&mut self, areas: Option<Vec<SpatialArea>>) {
+        let local = &mut self.networking.interests().local;
+        local.everywhere = areas.is_none();
+        local.areas = areas.unwrap_or_else(Vec::new).into();
+        self.replicate_interest();
+    }
+
+    /// Receive broadcasts and multicasts to `A` (a class or trait) from peers or not,
+    /// for machines that don't need the instances of `A` elsewhere to be updated
+    pub fn set_class_interest<A: ActorOrActorTrait>(&mut self, interested: bool) {
+        let class = self.short_id::<A>();
+        let local = &mut self.networking.interests().local;
+        let mut ignored_classes = local
+            .ignored_classes
+            .iter()
+            .cloned()
+            .filter(|&ignored| ignored != class)
+            .collect::<Vec<_>>();
+        if !interested {
+            ignored_classes.push(class);
+        }
+        local.ignored_classes = ignored_classes.into();
+        self.replicate_interest();
+    }
+
+    fn replicate_interest(&mut self) {
+        let interest = self.networking.interests().local.clone();
+        let all_services = self.services_id(self.networking.machine_id).global_broadcast();
+        self.send(all_services, interest);
+    }
+
+    /// Send a message to all subscribers of `topic`, wherever they are
+    pub fn publish<M: Message>(&mut self, topic: Topic<M>, message: M) {
+        let key = self.topic_key(topic);
+        for subscriber in self.topics.subscribers(key) {
+            self.send(subscriber, message.clone());
+        }
+    }
+
+    /// Decide how the machine for new instances of a registered actor class is chosen.
+    /// Instances spawned by a local broadcast (like `spawn` functions send) migrate to
+    /// the chosen machine at the end of the turn (see `migrate`, their ID changes), while
+    /// instances spawned by a message to the class on a certain machine (like the ID
+    /// `World::place` returns) stay there. Policies other than `PlacementPolicy::Local`
+    /// make the machine broadcast its load regularly, so they need to be set on all machines.
+    pub fn set_placement<A: Actor>(&mut self, policy: PlacementPolicy) {
+        let actor_id = self.actor_registry.get::<A>();
+        self.placement.set_policy(actor_id, policy);
+    }
+
+    /// Let `allocator` decide the instance IDs of a registered actor class, instead of each
+    /// machine allocating the IDs of its own instances (see `IdAllocator`), before any are allocated.
+    /// Has to be set on all machines. After loading a snapshot, the allocator continues
+    /// from the state it was saved in.
+    pub fn set_id_allocator<A: Actor, I: IdAllocator + 'static>(&mut self, mut allocator: I) {
+        let ident = self.class_ref::<A>().ident.sub("idal").0;
+        let restored = self.snapshots.take_restored(&ident);
+        let instance_store = &mut self.class_mut::<A>().instance_store;
+        match restored {
+            Some(saved) => allocator.restore(&saved),
+            None => assert!(!instance_store.has_ids(), "The ID allocator has to be set before any IDs are allocated"),
+        }
+        instance_store.set_id_allocator(Box::new(allocator));
+    }
+
+    /// Split the instances of a registered actor class into shards (like spatial cells),
+    /// with `key_of` giving the shard key of an instance and `shard_of` deciding which machine
+    /// owns the shard of a key. Instances spawned on another machine than the one owning their
+    /// shard migrate there at the end of the turn (see `migrate`, their ID changes), and
+    /// `shard_broadcasts_by` sends global broadcasts only to the owner of their shard.
+    /// `World::place_in_shard` and `World::shard_broadcast` do the same by hand.
+    /// Both have to be the same pure functions on all machines for all of them
+    /// to decompose the world in the same way.
+    pub fn set_sharding<A: Actor, K: Fn(&A) -> u64 + 'static, F: Fn(u64) -> MachineID + 'static>(
+        &mut self,
+        key_of: K,
+        shard_of: F,
+    ) {
+        let actor_id = self.actor_registry.get::<A>();
+        let key_of = move |state: *const ()| key_of(unsafe { &*(state as *const A) });
+        self.placement.set_sharding(actor_id, Box::new(key_of), Box::new(shard_of));
+    }
+
+    /// Send global broadcasts of `M` to the sharded actor class `A` (see `set_sharding`)
+    /// only to the machine owning the shard `key_of` gives for the message
+    pub fn shard_broadcasts_by<A: Actor, M: Message, K: Fn(&M) -> u64 + 'static>(&mut self, key_of: K) {
+        let actor_id = self.actor_registry.get::<A>();
+        let message_id = self.message_registry.get::<M>();
+        let key_of = move |message: *const ()| key_of(unsafe { &*(message as *const M) });
+        self.placement.set_broadcast_key(actor_id, message_id, Box::new(key_of));
+    }
+
+    fn report_load(&mut self) {
+        let instances = self
+            .classes
+            .iter()
+            .map(|maybe_class| {
+                maybe_class
+                    .as_ref()
+                    .map(|class| *class.instance_store.n_instances as u32)
+                    .unwrap_or(0)
+            })
+            .collect::<Vec<_>>();
+        let report = LoadReport {
+            machine: self.networking.machine_id,
+            instances: instances.into(),
+            busy_ms: self.busy_ms_since_load_report,
+        };
+        self.busy_ms_since_load_report = 0.0;
+        let all_services = self.services_id(self.networking.machine_id).global_broadcast();
+        self.send(all_services, report);
+    }
+
+    fn after_turn(&mut self, n_turns_before: usize) {
+        let n_turns = self.networking.n_turns;
+        if n_turns != n_turns_before && self.profiling {
+            self.profiled_turns += 1;
+            for class in self.classes.iter_mut().filter_map(Option::as_mut) {
+                let instances = *class.instance_store.n_instances;
+                class.profile.count_instances(instances);
+            }
+        }
+        if n_turns != n_turns_before
+            && n_turns % LOAD_REPORT_INTERVAL_TURNS == 0
+            && self.placement.needs_load_reports()
+        {
+            self.report_load();
+        }
+        self.deliver_networking_events();
+    }
+
+    /// Move a local actor instance (with its state) to machine `to`, where it gets a new ID.
+    /// Messages sent to its old ID are held back until it arrived, after which all machines
+    /// send them to its new ID, and its old machine forwards messages still addressed to
+    /// its old ID for a grace period. Returns false if the instance doesn't exist here,
+    /// or its class can't be migrated (see `register_external`).
+    pub fn migrate(&mut self, id: RawID, to: MachineID) -> bool {
+        if to == self.networking.machine_id {
+            return true;
+        }
+        let mut world = World(self as *const Self as *mut Self);
+        let state = match self.classes[id.type_id.as_usize()]
+            .as_mut()
+            .and_then(|class| class.take_instance(id, to, &mut world))
+        {
+            Some(state) => state,
+            None => return false,
+        };
+        self.migrations.start(id, self.networking.n_turns);
+        let services = self.services_id(to);
+        self.send(services, MigrateInstance { old_id: id, state: state.into() });
+        true
+    }
+
+    /// Send `Terminated(target)` to `observer` once the instance `target` dies, or the machine
+    /// it lives on disconnects (right away, if it doesn't exist anymore). Works across machines
+    /// and follows the instance when it migrates. The observer needs to handle `Terminated`.
+    pub fn watch(&mut self, target: RawID, observer: RawID) {
+        if target.machine == self.networking.machine_id {
+            self.watch_here(target, observer);
+        } else {
+            if observer.machine == self.networking.machine_id {
+                self.watches.add_remote(target, observer);
+            }
+            let services = self.services_id(target.machine);
+            self.send(services, Watch { target, observer });
+        }
+    }
+
+    /// Stop watching `target` for `observer`, see `watch`
+    pub fn unwatch(&mut self, target: RawID, observer: RawID) {
+        if target.machine == self.networking.machine_id {
+            self.watches.remove(target, observer);
+        } else {
+            self.watches.remove_remote(target, observer);
+            let services = self.services_id(target.machine);
+            self.send(services, Unwatch { target, observer });
+        }
+    }
+
+    /// Start watching a local instance
+    fn watch_here(&mut self, target: RawID, observer: RawID) {
+        let exists = self.classes[target.type_id.as_usize()]
+            .as_ref()
+            .map_or(false, |class| class.has_instance(target));
+        if exists {
+            self.watches.add(target, observer);
+        } else {
+            self.notify_terminated(target, observer);
+        }
+    }
+
+    fn dead_letter(&mut self, recipient: RawID, message_type: ShortTypeId, message: Option<String>) {
+        let letter = DeadLetter {
+            recipient,
+            message_type: self.message_registry.get_name(message_type).clone(),
+            message,
+            n_turns: self.networking.n_turns,
+        };
+        self.dead_letters.push(letter);
+    }
+
+    fn instance_died(&mut self, id: RawID) {
+        for observer in self.watches.take_observers(id) {
+            self.notify_terminated(id, observer);
+        }
+        self.emit_system_event(SystemEvent::InstanceDied(id));
+    }
+
+    fn notify_terminated(&mut self, target: RawID, observer: RawID) {
+        if observer.machine == self.networking.machine_id {
+            self.send(observer, Terminated(target));
+        } else {
+            let services = self.services_id(observer.machine);
+            self.send(services, NotifyTerminated { target, observer });
+        }
+    }
+
+    /// Get a base RawID for an actor or actor trait
+    pub fn id<A: ActorOrActorTrait>(&mut self) -> RawID {
+        RawID::new(self.short_id::<A>(), 0, self.networking.machine_id, 0)
+    }
+
+    /// Format `id` using the name of its actor class, like `Car.local#42@machine3`
+    /// (see `NamedRawID` for the format)
+    pub fn display_id(&self, id: RawID) -> NamedRawID<'_> {
+        NamedRawID::new(id, &self.actor_registry)
+    }
+
+    /// Parse an ID in the format of `display_id`, for admin commands and debug consoles
+    pub fn parse_id(&self, id: &str) -> Result<RawID, ParseRawIDError> {
+        parse_named(id, &self.actor_registry)
+    }
+
+    fn short_id<A: ActorOrActorTrait>(&mut self) -> ShortTypeId {
+        self.actor_registry.get_or_register::<A>()
+    }
+
+    fn single_message_cycle(&mut self) {
+        let mut world = World(self as *const Self as *mut Self);
+
+        for i in 0..self.classes.len() {
+            let type_id = self.scheduling.order()[i];
+            if let Some(class) = self.classes[type_id].as_mut() {
+                self.handling_class = ShortTypeId::new(type_id as u16);
+                let traced = if self.trace.is_some() && class.inbox.len() > 0 {
+                    Some((now_ms(), class.inbox.len()))
+                } else {
+                    None
+                };
+                let remaining = self.scheduling.remaining(type_id);
+                if remaining.is_none() && self.interceptors.is_empty() {
+                    class.handle_messages(&mut self.message_statistics, &mut world, self.profiling);
+                } else {
+                    let (max_messages, max_ms) = remaining.unwrap_or((usize::max_value(), ::std::f64::INFINITY));
+                    let interceptors = if self.interceptors.is_empty() {
+                        None
+                    } else {
+                        Some((&self.interceptors, &self.message_registry, &self.packet_decoders))
+                    };
+                    let (n_handled, ms) = class.handle_messages_limited(
+                        &mut self.message_statistics,
+                        &mut world,
+                        interceptors,
+                        self.profiling,
+                        max_messages,
+                        max_ms,
+                    );
+                    self.scheduling.count(type_id, n_handled, ms);
+                }
+                if let (Some((started_ms, n_queued)), Some(trace)) = (traced, self.trace.as_mut()) {
+                    let class = self.classes[type_id].as_ref().expect("Class should still exist");
+                    trace.span(class.v_table.type_name, "inbox", started_ms, now_ms(), &[("queued", n_queued)]);
+                }
+            }
+        }
+        self.handling_class = None;
+    }
+
+    #[cfg(feature = "server")]
+    fn is_parallel(&self) -> bool {
+        self.parallel.is_some()
+    }
+
+    #[cfg(not(feature = "server"))]
+    fn is_parallel(&self) -> bool {
+        false
+    }
+
+    /// Like `single_message_cycle`, but handling independent groups of classes on worker threads.
+    /// Everything handlers do to the system from a worker (mostly sending messages)
+    /// happens after all groups were handled, in the order of the groups.
+    #[cfg(feature = "server")]
+    fn parallel_message_cycle(&mut self) {
+        let started_ms = now_ms();
+        let system = self as *mut Self;
+        let profiling = self.profiling;
+        let parallel = self.parallel.as_ref().expect("Parallel processing should be enabled");
+        let order = self.scheduling.order();
+        let deferred = parallel.cycle(system, &mut self.classes, order, &mut self.message_statistics, |class, message_statistics| {
+            class.handle_messages(message_statistics, &mut World(system), profiling)
+        });
+        for (sender_class, action) in deferred {
+            self.handling_class = Some(sender_class);
+            action(self);
+        }
+        self.handling_class = None;
+        if let Some(ref mut trace) = self.trace {
+            trace.span("Parallel message cycle", "inbox", started_ms, now_ms(), &[]);
+        }
+    }
+
+    #[cfg(not(feature = "server"))]
+    fn parallel_message_cycle(&mut self) {}
+
+    /// Handle the inboxes of actor classes on `n_threads` worker threads. Each class is handled
+    /// by one thread at a time, so every instance still handles its messages one after the other.
+    ///
+    /// Messages sent by handlers in a worker are only delivered after all inboxes were handled
+    /// in the current message cycle (which doesn't change the turn they are handled in).
+    /// Handlers running in a worker can't schedule messages, ask queries, place or migrate
+    /// instances, and may only allocate instance IDs (or count instances) of classes
+    /// they were grouped with using `declare_dependency`.
+    ///
+    /// Scheduling priorities (see `set_scheduling_policy`) order the classes of a group
+    /// and the delivery of what the groups deferred, but groups run at the same time.
+    /// While there are interceptors or observers, or any class has a budget,
+    /// inboxes are handled one after the other instead.
+    #[cfg(feature = "server")]
+    pub fn enable_parallel_processing(&mut self, n_threads: usize) {
+        self.parallel = Some(ParallelProcessing::new(n_threads, self.classes.len()));
+    }
+
+    /// Always handle the inboxes of `A` and `B` on the same thread (in parallel processing),
+    /// because they depend on each other beyond messages, for example because
+    /// `A` spawns instances of `B`
+    #[cfg(feature = "server")]
+    pub fn declare_dependency<A: Actor, B: Actor>(&mut self) {
+        let (a, b) = (self.short_id::<A>(), self.short_id::<B>());
+        self.parallel
+            .as_mut()
+            .expect("Parallel processing should be enabled with enable_parallel_processing")
+            .declare_dependency(a, b);
+    }
+
+    /// Split up broadcasts to `A` between the worker threads of parallel processing,
+    /// if it has many instances. Handlers of broadcasts then have the same restrictions
+    /// as handlers on workers (see `enable_parallel_processing`), and can't allocate instance IDs.
+    /// Messages they send are delivered after the broadcast was handled by all instances,
+    /// in the order of the instances.
+    #[cfg(feature = "server")]
+    pub fn enable_parallel_broadcasts<A: Actor>(&mut self) {
+        let class = self.short_id::<A>();
+        self.parallel
+            .as_mut()
+            .expect("Parallel processing should be enabled with enable_parallel_processing")
+            .enable_parallel_broadcasts(class);
+    }
+
+    /// Like up to 1000 calls of `single_message_cycle`, but stopping where the debugger says so
+    fn debugged_message_cycles(&mut self) {
+        let mut world = World(self as *const Self as *mut Self);
+        let debugger = self.debugger.as_mut().expect("Should be debugging");
+
+        if let Some((class_id, packet, header)) = debugger.take_held() {
+            let class = self.classes[class_id.as_usize()].as_mut().expect("Class of held message should exist");
+            self.handling_class = Some(class_id);
+            class.handle_held_message(packet, &mut self.message_statistics, &mut world);
+            self.handling_class = None;
+            if debugger.after_message(&header) {
+                return;
+            }
+        }
+
+        for _i in 0..1000 {
+            for (type_id, maybe_class) in self.classes.iter_mut().enumerate() {
+                if !debugger.may_continue() {
+                    return;
+                }
+                if let Some(class) = maybe_class.as_mut() {
+                    let class_id = ShortTypeId::new(type_id as u16).expect("Class should have a type ID");
+                    if debugger.only_class().map_or(false, |only| only != class_id) {
+                        continue;
+                    }
+                    self.handling_class = Some(class_id);
+                    let stop = class.handle_messages_debugged(
+                        class_id,
+                        &mut self.message_statistics,
+                        &mut world,
+                        debugger,
+                        &self.message_registry,
+                        &self.packet_decoders,
+                    );
+                    self.handling_class = None;
+                    if stop {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Enter debug mode, in which message processing can be paused, stepped and
+    /// stopped at breakpoints. Slower than normal processing, since each message is checked.
+    pub fn enable_debugging(&mut self) {
+        self.debugger.get_or_insert_with(Debugger::new);
+    }
+
+    fn debugger(&mut self) -> &mut Debugger {
+        self.debugger.as_mut().expect("Debug mode should be enabled with enable_debugging")
+    }
+
+    /// Stop handling messages (in debug mode), the next message is handled once stepping or resuming
+    pub fn debug_pause(&mut self) {
+        self.debugger().pause();
+    }
+
+    /// Continue handling messages normally (in debug mode), until the next breakpoint is hit
+    pub fn debug_resume(&mut self) {
+        self.debugger().resume();
+    }
+
+    /// Is message processing paused (in debug mode)?
+    pub fn debug_is_paused(&self) -> bool {
+        self.debugger.as_ref().map_or(false, Debugger::is_paused)
+    }
+
+    /// Handle only the next message during the next `process_all_messages`, then pause again
+    pub fn debug_step_message(&mut self) {
+        self.debugger().step_message();
+    }
+
+    /// Handle the messages queued in the next non-empty inbox (or the inbox of the message
+    /// that was stopped at) during the next `process_all_messages`, then pause again
+    pub fn debug_step_inbox(&mut self) {
+        self.debugger().step_inbox();
+    }
+
+    /// Pause before handling any message of type `M` sent to actors of class (or trait) `A`,
+    /// for which `condition` is true
+    pub fn add_breakpoint<A: ActorOrActorTrait, M: Message, F: Fn(&PacketHeader) -> bool + 'static>(
+        &mut self,
+        condition: F,
+    ) -> BreakpointID {
+        let recipient_type = self.short_id::<A>();
+        let message_type = self.message_registry.get_or_register::<M>();
+        self.debugger().add_breakpoint(recipient_type, message_type, Box::new(condition))
+    }
+
+    /// Remove a breakpoint, returns false if it didn't exist
+    pub fn remove_breakpoint(&mut self, breakpoint: BreakpointID) -> bool {
+        self.debugger().remove_breakpoint(breakpoint)
+    }
+
+    /// Call `callback` whenever a breakpoint is hit or a message was handled as part of a step,
+    /// with the header of the message
+    pub fn on_debug_stop<F: FnMut(&PacketHeader, DebugStop) + 'static>(&mut self, callback: F) {
+        self.debugger().on_stop(Box::new(callback));
+    }
+
+    /// Change how the inbox of `A` is scheduled: its priority among all classes and
+    /// its budget per call of `process_all_messages`. While any class has a budget,
+    /// inboxes are handled one after the other even if parallel processing is enabled,
+    /// otherwise priorities apply as described in `enable_parallel_processing`.
+    /// Budgets don't apply in debug mode.
+    pub fn set_scheduling_policy<A: Actor>(&mut self, policy: SchedulingPolicy) {
+        let class = self.short_id::<A>();
+        self.scheduling.set_policy(class.as_usize(), policy);
+    }
+
+    /// Call `interceptor` before handling any message (from here or from the network),
+    /// in the order interceptors were added in. It can look at the header of the message
+    /// (to log or validate it) and drop it, which skips all later interceptors and the handler.
+    ///
+    /// While there are interceptors, inboxes are handled one after the other even if
+    /// parallel processing is enabled, and they are skipped in debug mode.
+    pub fn intercept_all<F: Fn(&PacketHeader, &mut World) -> Intercept + 'static>(&mut self, interceptor: F) -> InterceptorID {
+        self.interceptors
+            .add_before(None, Box::new(move |_packet, header, world| interceptor(header, world)))
+    }
+
+    /// Like `intercept_all`, but only for messages of type `M`, which `interceptor` may also modify.
+    /// Changes need to keep the message compact: dynamically sized parts can only be shrunk.
+    pub fn intercept<M: Message, F: Fn(&mut M, &PacketHeader, &mut World) -> Intercept + 'static>(
+        &mut self,
+        interceptor: F,
+    ) -> InterceptorID {
+        let message_type = self.message_registry.get_or_register::<M>();
+        self.interceptors.add_before(
+            Some(message_type),
+            Box::new(move |packet_ptr, header, world| {
+                let packet = unsafe { &mut *(packet_ptr as *mut Packet<M>) };
+                interceptor(&mut packet.message, header, world)
+            }),
+        )
+    }
+
+    /// Call `observer` after any message was handled, with how long handling took
+    /// and the fate the recipient chose. Like interceptors, observers keep inboxes from
+    /// being handled in parallel and are skipped in debug mode.
+    pub fn observe_handled<F: Fn(&PacketHeader, &Handled, &mut World) + 'static>(&mut self, observer: F) -> InterceptorID {
+        self.interceptors.add_after(Box::new(observer))
+    }
+
+    /// Remove an interceptor or observer, returns false if it didn't exist
+    pub fn remove_interceptor(&mut self, interceptor: InterceptorID) -> bool {
+        self.interceptors.remove(interceptor)
+    }
+
+    /// Keep the compact state of the instance `id` at the start of each of the last `n_turns`
+    /// turns, together with the messages it received in them (with their text for types
+    /// added with `decode_packets`), to find out how it got into its current state with
+    /// `instance_history`. `describe` renders the state to make the history readable.
+    /// Messages are recorded by an interceptor, so parallel processing is paused while recording.
+    pub fn record_history<A: Actor, F: Fn(&A) -> String + 'static>(&mut self, id: <A as Actor>::ID, n_turns: usize, describe: F) {
+        let raw_id = id.as_raw();
+        let recorded = RecordedHistory::new(
+            raw_id,
+            n_turns,
+            Box::new(move |system: &ActorSystem| {
+                let actor = system.instance::<A>(id)?;
+                let size = Compact::total_size_bytes(actor);
+                let state = unsafe { ::std::slice::from_raw_parts(actor as *const A as *const u8, size) }.to_vec();
+                Some((state, describe(actor)))
+            }),
+        );
+        let receive = recorded.receiver(self.class_ref::<A>().v_table.type_name);
+        let interceptor = self.intercept_all(move |header, _world| {
+            receive(header);
+            Intercept::Deliver
+        });
+        if let Some((_, replaced_interceptor)) = self.histories.insert(raw_id, (recorded, interceptor)) {
+            self.remove_interceptor(replaced_interceptor);
+        }
+    }
+
+    /// The recorded history of an instance, see `record_history`
+    pub fn instance_history(&self, id: RawID) -> Option<InstanceHistory> {
+        self.histories.get(&id).map(|(recorded, _)| recorded.history())
+    }
+
+    /// Stop recording the history of an instance, returning what was recorded
+    pub fn stop_history(&mut self, id: RawID) -> Option<InstanceHistory> {
+        let (recorded, interceptor) = self.histories.remove(&id)?;
+        self.remove_interceptor(interceptor);
+        Some(recorded.history())
+    }
+
+    /// Process and handle all enqueued messages in the system
+    /// and the resulting messages, up to a recursion depth of 1000
+    pub fn process_all_messages(&mut self) {
+        let turn_started_ms = now_ms();
+        if self.trace.as_mut().map_or(false, |trace| !trace.start_turn()) {
+            self.finish_trace();
+        }
+        self.replicate_new_types();
+        let classes = &self.classes;
+        self.scheduling
+            .start_turn(|class| classes[class].as_ref().map_or(false, |class| class.inbox.len() > 0));
+        self.handling_messages = true;
+
+        if let Some(mut replay) = self.journal_replay.take() {
+            for data in replay.take_until(self.networking.n_turns) {
+                self.put_raw_here(&data);
+            }
+            self.journal_replay = Some(replay);
+        }
+
+        for sending in self.scheduler.take_due(self.networking.n_turns, now_ms()) {
+            sending(self);
+        }
+
+        for deliver in self.tasks.take_finished() {
+            deliver(self);
+        }
+
+        let n_turns = self.networking.n_turns;
+        for (recorded, _) in self.histories.values() {
+            recorded.start_turn(n_turns, self);
+        }
+
+        let deferred = self
+            .classes
+            .iter_mut()
+            .filter_map(Option::as_mut)
+            .filter_map(|class| class.rate_limits.as_mut())
+            .flat_map(|rate_limits| rate_limits.start_turn(n_turns))
+            .collect::<Vec<_>>();
+        let mut world = World(self as *mut Self);
+        for (recipient_id, forwarding) in deferred {
+            forwarding(recipient_id, &mut world);
+        }
+
+        let started_ms = now_ms();
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            for phase in 0..self.turn_phases.len().max(1) {
+                self.start_turn_phase(phase);
+                if self.debugger.is_some() {
+                    self.debugged_message_cycles();
+                } else if self.is_parallel() && self.interceptors.is_empty() && !self.scheduling.has_budgets() {
+                    for _i in 0..1000 {
+                        self.parallel_message_cycle();
+                    }
+                } else {
+                    for _i in 0..1000 {
+                        self.single_message_cycle();
+                    }
+                }
+            }
+        }));
+        self.start_turn_phase(0);
+        self.busy_ms_since_load_report += now_ms() - started_ms;
+        if let Some(ref mut trace) = self.trace {
+            trace.span("Handle messages", "turn", started_ms, now_ms(), &[]);
+        }
+
+        if result.is_err() {
+            self.panic_happened = true;
+            self.handling_class = None;
+        }
+
+        if self.queries.expire(now_ms()) > 0 {
+            self.report_nondeterminism(NondeterminismSource::WallClock, "query timeout");
+        }
+        let mut world = World(self as *const Self as *mut Self);
+        for pending in self.gathers.expire(now_ms()) {
+            self.report_nondeterminism(NondeterminismSource::WallClock, "gather timeout");
+            pending.finish(true, &mut world);
+        }
+        for (requester, found) in self.spatial.expire(now_ms()) {
+            self.report_nondeterminism(NondeterminismSource::WallClock, "spatial query timeout");
+            self.send(requester, found);
+        }
+        for (id, machine) in ::std::mem::replace(&mut self.placed_spawns, Vec::new()) {
+            self.migrate(id, machine);
+        }
+        for (old_id, held_back) in self.migrations.expire(self.networking.n_turns) {
+            eprintln!("Migration of {} timed out, messages to it become dead letters", old_id.format(&mut world));
+            // the route is gone, so they end up with the instance that doesn't exist anymore
+            for forwarding in held_back {
+                forwarding(old_id, &mut world);
+            }
+        }
+        self.rebuild_spatial_indices();
+        self.handling_messages = false;
+        self.scratch_turn += 1;
+        self.emit_inbox_overflows();
+
+        #[cfg(feature = "admin")]
+        {
+            if let Some(mut admin) = self.admin.take() {
+                admin.poll(|| AdminData {
+                    inspection: self.inspect(),
+                    metrics: self.metrics(),
+                    message_counts: self.get_message_statistics(),
+                });
+                self.admin = Some(admin);
+            }
+        }
+
+        let n_turns = self.networking.n_turns;
+        if let Some(ref mut trace) = self.trace {
+            trace.span(&format!("Turn {}", n_turns), "turn", turn_started_ms, now_ms(), &[("n_turns", n_turns)]);
+        }
+    }
+
+    /// Record what the system does in the next `n_turns` calls of `process_all_messages`
+    /// as a Chrome trace, with spans for each turn, the handling of each inbox,
+    /// sending and receiving from peers and finishing turns, to be viewed in chrome://tracing
+    /// or Perfetto. The trace is written to `writer` when the turn after the last recorded one
+    /// starts, or with `finish_trace`. Traces of all machines can be concatenated, each machine
+    /// shows up as the process with its machine ID. With parallel processing,
+    /// only whole message cycles are recorded.
+    pub fn record_trace<W: ::std::io::Write + 'static>(&mut self, writer: W, n_turns: usize) {
+        self.finish_trace();
+        self.trace = Some(TraceRecorder::new(Box::new(writer), n_turns, self.networking.machine_id.0));
+    }
+
+    /// Stop recording a trace (see `record_trace`) and write it
+    pub fn finish_trace(&mut self) {
+        if let Some(trace) = self.trace.take() {
+            if let Err(e) = trace.finish() {
+                println!("Error while writing trace: {}", e);
+            }
+        }
+    }
+
+    /// Split each turn into named phases (for example "plan", "act" and "apply"), which
+    /// `process_all_messages` handles one after the other. Messages assigned to a phase with
+    /// `handle_in_phase` are only handled in that phase: ones sent earlier in the turn wait for it,
+    /// ones sent later wait for the next turn. Since messages from peers arrive between turns,
+    /// all machines handle the same messages in the same phase.
+    /// Needs to be called before any actor classes are registered.
+    pub fn define_turn_phases(&mut self, phases: &[&str]) {
+        assert!(
+            self.classes.iter().filter(|class| class.is_some()).count() <= 1,
+            "Turn phases need to be defined before registering actor classes"
+        );
+        self.turn_phases = phases.iter().map(|phase| (*phase).to_owned()).collect();
+    }
+
+    /// Only handle messages of type `M` sent to `A` in the turn phase `phase`
+    /// (see `define_turn_phases`). Messages of types without a phase are handled
+    /// in the phase they are sent in (or the first one, if they arrive between turns).
+    pub fn handle_in_phase<A: Actor, M: Message>(&mut self, phase: &str) {
+        let phase_index = self
+            .turn_phases
+            .iter()
+            .position(|existing| existing == phase)
+            .unwrap_or_else(|| panic!("Turn phase {} wasn't defined", phase));
+        let message_id = self.message_registry.get_or_register::<M>();
+        self.class_mut::<A>().inbox.assign_phase(message_id, phase_index);
+    }
+
+    fn start_turn_phase(&mut self, phase: usize) {
+        for class in self.classes.iter_mut().filter_map(Option::as_mut) {
+            class.inbox.set_phase(phase);
+        }
+    }
+
+    /// Serve the inspection of the system (see `inspect`) as JSON at `/inspect`
+    /// and metrics in the Prometheus text format at `/metrics` over HTTP on `address`.
+    /// Requests are answered at the end of each `process_all_messages`.
+    #[cfg(feature = "admin")]
+    pub fn serve_admin_endpoint<A: ::std::net::ToSocketAddrs>(&mut self, address: A) -> ::std::io::Result<()> {
+        self.admin = Some(AdminEndpoint::bind(address)?);
+        Ok(())
+    }
+
+    /// Put a message, stored like in an inbox, into the inboxes of its local recipients
+    fn put_raw_here(&mut self, data: &[u8]) {
+        #[allow(clippy::cast_ptr_alignment)]
+        let recipient_type =
+            unsafe { (*(&data[::std::mem::size_of::<ShortTypeId>()] as *const u8 as *const RawID)).type_id };
+
+        if let Some(class) = self.classes[recipient_type.as_usize()].as_mut() {
+            class.inbox.put_raw(data);
+        } else if let Some(implementors) = self.trait_implementors[recipient_type.as_usize()].as_ref() {
+            for implementor_type_id in implementors {
+                let class = self.classes[implementor_type_id.as_usize()].as_mut().expect("Implementor should exist");
+                class.inbox.put_raw(data);
+            }
+        } else {
+            panic!(
+                "Recipient {} doesn't exist, or Trait has no implementors (replaying journal)",
+                self.actor_registry.get_name(recipient_type),
+            );
+        }
+    }
+
+    /// Get a `World` handle for the system.
+    pub fn world(&mut self) -> World {
+        World(self as *mut Self)
+    }
+
+    /// Connect to peers in the networking topology.
+    pub fn networking_connect(&mut self) {
+        self.networking.connect();
+    }
+
+    /// Send and receive messages from peers in the networking topology.
+    pub fn networking_send_and_receive(&mut self) {
+        let started_ms = now_ms();
+        self.replicate_new_types();
+        self.networking
+            .send_and_receive(&mut self.classes, &mut self.trait_implementors);
+        self.deliver_networking_events();
+        if let Some(ref mut trace) = self.trace {
+            trace.span("Send and receive", "networking", started_ms, now_ms(), &[]);
+        }
+    }
+
+    /// Mark the local "networking turn" as finished. Networking turns are
+    /// used to track and manage time drift between peers in the networking topology.
+    ///
+    /// In strict lockstep mode, the turn isn't finished while waiting for peers,
+    /// which is only reported as a `SystemEvent::LockstepTimedOut` once it takes too long.
+    /// Use `networking_try_finish_turn` to see which peers the turn is waiting for.
+    pub fn networking_finish_turn(&mut self) -> Option<usize> {
+        match self.networking_try_finish_turn() {
+            Ok(skip_turns) => skip_turns,
+            Err(_waiting) => None,
+        }
+    }
+
+    /// Like `networking_finish_turn`, but in strict lockstep mode
+    /// reports which peers the local turn is waiting for, instead of finishing it.
+    pub fn networking_try_finish_turn(&mut self) -> Result<Option<usize>, LockstepWait> {
+        let started_ms = now_ms();
+        self.hash_state_for_networking();
+        let n_turns_before = self.networking.n_turns;
+        let result = self.networking.try_finish_turn();
+        self.after_turn(n_turns_before);
+        if let Some(ref mut trace) = self.trace {
+            match result {
+                Ok(skip_turns) => trace.span(
+                    "Finish turn",
+                    "barrier",
+                    started_ms,
+                    now_ms(),
+                    &[("skip_turns", skip_turns.unwrap_or(0))],
+                ),
+                Err(_) => trace.instant("Waiting for peers", "barrier", started_ms),
+            }
+        }
+        result
+    }
+
+    /// Send `PeerConnected`, `PeerDisconnected` and `PeerLagging` messages to `recipient`
+    /// whenever the state of a connection to a peer changes.
+    /// The recipient needs to handle all three message types.
+    pub fn networking_notify_connection_events(&mut self, recipient: RawID) {
+        self.networking_event_recipient = Some(recipient);
+    }
+
+    /// Ask all machines to stop advancing turns at the same turn number,
+    /// see `Networking::request_pause`
+    pub fn networking_request_pause(&mut self) {
+        self.networking.request_pause();
+    }
+
+    /// Ask all machines to continue advancing turns after a pause
+    pub fn networking_request_resume(&mut self) {
+        self.networking.request_resume();
+    }
+
+    /// Did all machines stop advancing turns because of a requested pause?
+    pub fn networking_is_paused(&self) -> bool {
+        self.networking.is_paused()
+    }
+
+    /// Send `NetworkPaused` and `NetworkResumed` messages to `recipient`
+    /// whenever turns stop or continue advancing because of a requested pause.
+    /// The recipient needs to handle both message types.
+    pub fn networking_notify_pause_events(&mut self, recipient: RawID) {
+        self.networking_pause_event_recipient = Some(recipient);
+    }
+
+    /// Send a `DesyncDetected` message to `recipient` whenever the actor state of a peer
+    /// differs from ours after the same turn (reported once per peer),
+    /// by hashing the state of all actor instances after each turn (see `state_hash`).
+    /// Needs to be enabled on all machines of a network.
+    pub fn networking_notify_desyncs(&mut self, recipient: RawID) {
+        self.networking_desync_event_recipient = Some(recipient);
+        self.networking.enable_desync_detection();
+    }
+
+    /// Hash all actor instances, in the order of their type IDs.
+    /// Identical systems (on different machines) have identical hashes.
+    /// Actor states that contain padding need to implement `Hash`, which is used instead of their bytes.
+    pub fn state_hash(&self) -> u64 {
+        self.classes
+            .iter()
+            .filter_map(Option::as_ref)
+            .fold(0, |hash, class| class.state_hash(hash))
+    }
+
+    fn hash_state_for_networking(&mut self) {
+        if self.networking.wants_state_hash() {
+            // the turn is counted by the networking after hashing
+            let n_turns = self.networking.n_turns + 1;
+            let mut class_hashes = Vec::new();
+            let mut instance_hashes = Vec::new();
+            for (type_id, class) in self.classes.iter().enumerate() {
+                if let (Some(class), Some(type_id)) = (class.as_ref(), ShortTypeId::new(type_id as u16)) {
+                    let (chunks, instances) = class.chunk_and_instance_hashes(INSTANCES_PER_CHUNK);
+                    class_hashes.push((type_id, chunks));
+                    instance_hashes.extend(instances.into_iter().map(|(id, hash)| InstanceHash { id, hash }));
+                }
+            }
+            let state_hash = self.diagnosis.record(n_turns, class_hashes, instance_hashes);
+            self.networking.set_state_hash(state_hash);
+            self.reply_with_instance_hashes(n_turns);
+        }
+    }
+
+    fn reply_with_instance_hashes(&mut self, n_turns: usize) {
+        let (replies, finished) = self.diagnosis.instances_hashed(n_turns);
+        for (requester, hashes) in replies {
+            let reply = InstanceHashesReply {
+                machine: self.networking.machine_id,
+                n_turns: n_turns as u64,
+                in_time: true,
+                hashes: hashes.into(),
+            };
+            let requester_services = self.services_id(requester);
+            self.send(requester_services, reply);
+        }
+        for finished in finished {
+            self.send_desync_diagnosis(finished);
+        }
+    }
+
+    /// Find out which actor instances diverged in a desync with `machine` after `turn`
+    /// (as reported by a `DesyncDetected`). The hashes of chunks of instances after `turn`
+    /// are compared with the peer, then the hashes of each instance in the chunks that differed,
+    /// also after `turn` if it is one of the last 8 turns (otherwise a few turns later).
+    /// A `DesyncDiagnosis` is sent to `recipient` once done. Needs desync detection
+    /// (see `networking_notify_desyncs`) on both machines, and `turn` to be one of the last 64 turns.
+    pub fn networking_diagnose_desync(&mut self, machine: MachineID, turn: usize, recipient: RawID) {
+        self.diagnosis.start(machine, turn, recipient);
+        let request = ChunkHashesRequest {
+            requester: self.networking.machine_id,
+            n_turns: turn as u64,
+        };
+        let peer_services = self.services_id(machine);
+        self.send(peer_services, request);
+    }
+
+    fn send_desync_diagnosis(&mut self, finished: FinishedDiagnosis) {
+        let chunks = finished
+            .chunks
+            .iter()
+            .map(|chunk| {
+                let first_instance_id = chunk.chunk * INSTANCES_PER_CHUNK as u32;
+                DivergedChunk {
+                    class: self.actor_registry.display_name(chunk.class).to_owned().into(),
+                    first_instance_id,
+                    last_instance_id: first_instance_id + INSTANCES_PER_CHUNK as u32 - 1,
+                }
+            })
+            .collect::<Vec<_>>();
+        let diagnosis = DesyncDiagnosis {
+            machine: finished.machine,
+            turn: finished.turn as u64,
+            chunks: chunks.into(),
+            instances_turn: finished.instances_turn as u64,
+            instances: finished.instances.into(),
+            complete: finished.complete,
+        };
+        self.send(finished.recipient, diagnosis);
+    }
+
+    /// Tell all machines about types registered since setup or since the last call
+    fn replicate_new_types(&mut self) {
+        let next_ids = (self.actor_registry.n_registrations(), self.message_registry.n_registrations());
+        if self.type_ids_after_setup.is_none() {
+            self.type_ids_after_setup = Some(next_ids);
+            self.announced_type_ids = next_ids;
+            return;
+        }
+        if next_ids == self.announced_type_ids {
+            return;
+        }
+        let all_services = self.services_id(self.networking.machine_id).global_broadcast();
+        for registration in self.type_registrations_since(self.announced_type_ids) {
+            self.send(all_services, registration);
+        }
+        self.announced_type_ids = next_ids;
+    }
+
+    fn type_registrations_since(&self, (n_actor_types, n_message_types): (usize, usize)) -> Vec<TypeRegistration> {
+        let registrations = |registry: &TypeRegistry, n_registrations, actor| {
+            registry
+                .registrations_since(n_registrations)
+                .into_iter()
+                .map(move |(short_id, name)| TypeRegistration {
+                    actor,
+                    id: short_id.as_u16(),
+                    name: name.to_owned().into(),
+                    from: self.networking.machine_id,
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut all = registrations(&self.actor_registry, n_actor_types, true);
+        all.extend(registrations(&self.message_registry, n_message_types, false));
+        all
+    }
+
+    fn deliver_networking_events(&mut self) {
+        for event in self.networking.take_events() {
+            if let NetworkingEvent::Connected(machine_id) = event {
+                let services = self.services_id(machine_id);
+                for registration in self.names.registered_on(self.networking.machine_id) {
+                    self.send(services, registration);
+                }
+                let interest = self.networking.interests().local.clone();
+                self.send(services, interest);
+                if let Some(type_ids_after_setup) = self.type_ids_after_setup {
+                    for registration in self.type_registrations_since(type_ids_after_setup) {
+                        self.send(services, registration);
+                    }
+                }
+            }
+            if let NetworkingEvent::Rejected(from, message_type_id, recipient) = event {
+                if let Some(security_event_recipient) = self.security_event_recipient {
+                    let message_type = self.message_registry.get_name(message_type_id).clone().into();
+                    self.send(
+                        security_event_recipient,
+                        RemoteMessageRejected {
+                            from,
+                            message_type,
+                            recipient,
+                        },
+                    );
+                }
+            }
+            if let NetworkingEvent::Connected(machine_id) = event {
+                self.report_peer_event(SystemEvent::PeerConnected(machine_id));
+            }
+            if let NetworkingEvent::Left(machine_id) = event {
+                self.report_peer_event(SystemEvent::PeerLeft(machine_id));
+            }
+            if let NetworkingEvent::ConnectionLost(peer, ref reason) = event {
+                self.report_peer_event(SystemEvent::PeerConnectionLost { peer, reason: reason.clone() });
+            }
+            if let NetworkingEvent::LockstepTimedOut(ref stragglers) = event {
+                self.emit_system_event(SystemEvent::LockstepTimedOut { stragglers: stragglers.clone() });
+            }
+            if let NetworkingEvent::Invalid(from, ref problem) = event {
+                self.emit_system_event(SystemEvent::InvalidFromPeer { from, problem: problem.clone() });
+            }
+            if let NetworkingEvent::Disconnected(machine_id) = event {
+                self.report_peer_event(SystemEvent::PeerDisconnected(machine_id));
+                self.placement.forget(machine_id);
+                for finished in self.diagnosis.disconnected(machine_id) {
+                    self.send_desync_diagnosis(finished);
+                }
+                for (requester, found) in self.spatial.disconnected(machine_id) {
+                    self.send(requester, found);
+                }
+                let mut world = World(self as *const Self as *mut Self);
+                for pending in self.gathers.disconnected(machine_id) {
+                    pending.finish(false, &mut world);
+                }
+                self.names.forget(machine_id);
+                self.networking.interests().forget(machine_id);
+                for (target, observer) in self.watches.disconnected(machine_id) {
+                    self.send(observer, Terminated(target));
+                }
+            }
+            match (
+                event,
+                self.networking_event_recipient,
+                self.networking_pause_event_recipient,
+                self.networking_desync_event_recipient,
+            ) {
+                (NetworkingEvent::Connected(machine_id), Some(recipient), _, _) => {
+                    self.send(recipient, PeerConnected { machine_id })
+                }
+                (NetworkingEvent::Disconnected(machine_id), Some(recipient), _, _) => {
+                    self.send(recipient, PeerDisconnected { machine_id })
+                }
+                (NetworkingEvent::Lagging(machine_id, turns_behind), Some(recipient), _, _) => self
+                    .send(
+                        recipient,
+                        PeerLagging {
+                            machine_id,
+                            turns_behind,
+                        },
+                    ),
+                (NetworkingEvent::Paused(n_turns), _, Some(recipient), _) => {
+                    self.send(recipient, NetworkPaused { n_turns })
+                }
+                (NetworkingEvent::Resumed(n_turns), _, Some(recipient), _) => {
+                    self.send(recipient, NetworkResumed { n_turns })
+                }
+                (NetworkingEvent::Desync(turn, machine), _, _, Some(recipient)) => {
+                    self.send(recipient, DesyncDetected { turn, machine })
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Get the duration a local turn should take, if adaptive pacing is enabled
+    pub fn networking_target_tick_duration(&self) -> Option<::std::time::Duration> {
+        self.networking.target_tick_duration()
+    }
+
+    /// Get the estimated current time of the host's clock,
+    /// in milliseconds since the Unix epoch
+    pub fn networking_estimated_shared_time(&self) -> f64 {
+        self.networking.estimated_shared_time()
+    }
+
+    /// Get the machine ID of this system in the network
+    pub fn networking_machine_id(&self) -> MachineID {
+        self.networking.machine_id
+    }
+
+    /// Define (or redefine) which machines are members of a multicast group.
+    /// Messages to `RawID::multicast(group)` are then only sent to these machines.
+    /// All machines need to define groups identically.
+    pub fn networking_define_multicast_group(&mut self, group: u8, members: Vec<MachineID>) {
+        self.networking.define_multicast_group(group, members)
+    }
+
+    /// Allow spectator machines to send messages of this type to peers.
+    /// Messages of other types are neither sent by spectators nor accepted from them.
+    /// Needs to be done before connecting. Fails if `M` is new and no type IDs are left.
+    pub fn allow_from_spectators<M: Message>(&mut self) -> Result<(), String> {
+        let message_id = self.message_registry.try_get_or_register::<M>()?;
+        self.networking.allow_from_spectators(message_id);
+        Ok(())
+    }
+
+    /// Send messages of this type to peers delta encoded against the last one
+    /// from the same actor class to the same recipient, see `Networking::enable_delta_encoding`.
+    /// Only needs to be done on the sending machines. Fails if `M` is new and no type IDs are left.
+    pub fn enable_delta_encoding<M: Message>(&mut self) -> Result<(), String> {
+        let message_id = self.message_registry.try_get_or_register::<M>()?;
+        self.networking.enable_delta_encoding(message_id);
+        Ok(())
+    }
+
+    /// Send messages of this type to peers connected over WebTransport as unreliable datagrams,
+    /// see `Networking::send_as_datagrams`. Only needs to be done on the sending machines.
+    /// Fails if `M` is new and no type IDs are left.
+    #[cfg(feature = "webtransport")]
+    pub fn networking_send_as_datagrams<M: Message>(&mut self) -> Result<(), String> {
+        let message_id = self.message_registry.try_get_or_register::<M>()?;
+        self.networking.send_as_datagrams(message_id);
+        Ok(())
+    }
+
+    /// Accept messages of this type to this actor class (or actor trait) from peers.
+    /// Once any pair is allowed, messages from peers that weren't allowed are dropped
+    /// instead of being dispatched (see `networking_notify_rejected_messages`).
+    /// Messages to the system services of this machine are always accepted.
+    /// Only affects what this machine accepts, so a server can restrict what clients send to it.
+    /// Fails if `M` or `A` are new and no type IDs are left.
+    pub fn networking_allow_remote<M: Message, A: ActorOrActorTrait>(&mut self) -> Result<(), String> {
+        let services_id = self.actor_registry.get::<SystemServices>();
+        let message_id = self.message_registry.try_get_or_register::<M>()?;
+        let recipient_id = self.actor_registry.try_get_or_register::<A>()?;
+        self.networking.allow_remote(services_id, message_id, recipient_id);
+        Ok(())
+    }
+
+    /// Send a `RemoteMessageRejected` message to `recipient` whenever a peer sent
+    /// a message that isn't allowed (see `networking_allow_remote`)
+    pub fn networking_notify_rejected_messages(&mut self, recipient: RawID) {
+        self.security_event_recipient = Some(recipient);
+    }
+
+    /// Get the machine currently acting as host in the network
+    pub fn networking_host(&self) -> MachineID {
+        self.networking.host()
+    }
+
+    /// Render messages of type `M` with their `Debug` representation in the packet headers
+    /// passed to interceptors and the debugger, in dead letters and in inspections
+    pub fn decode_packets<M: Message + ::std::fmt::Debug>(&mut self) {
+        let message_id = self.message_registry.get_or_register::<M>();
+        self.packet_decoders.add::<M, _>(message_id, |message| format!("{:?}", message));
+    }
+
+    /// Like `decode_packets`, but rendering messages of type `M` as JSON
+    #[cfg(feature = "serde-serialization")]
+    pub fn decode_packets_as_json<M: Message + ::serde::Serialize>(&mut self) {
+        let message_id = self.message_registry.get_or_register::<M>();
+        self.packet_decoders.add::<M, _>(message_id, to_json);
+    }
+
+    /// Take the most recent messages (up to 1000) that couldn't be delivered, because their
+    /// recipient didn't exist (anymore). Messages sent to a stale ID of an instance that died
+    /// end up here, never at a new instance reusing its slot.
+    /// Messages beyond a rate limit with `RateLimitAction::DeadLetter` end up here, too.
+    pub fn take_dead_letters(&mut self) -> Vec<DeadLetter> {
+        self.dead_letters.take()
+    }
+
+    /// How many messages couldn't be delivered in total
+    pub fn dead_letter_count(&self) -> usize {
+        self.dead_letters.total()
+    }
+
+    /// Get the size batches to each peer are currently filled up to,
+    /// see `Networking::enable_adaptive_batching`
+    pub fn networking_batch_sizes(&self) -> HashMap<MachineID, usize> {
+        self.networking.batch_sizes()
+    }
+
+    /// Get how well the buffers of outgoing message batches are recycled
+    pub fn networking_buffer_pool_stats(&self) -> BufferPoolStats {
+        self.networking.buffer_pool_stats()
+    }
+
+    /// Get the local number of networking turns
+    pub fn networking_n_turns(&self) -> usize {
+        self.networking.n_turns
+    }
+
+    /// Let each finished networking turn advance the simulated time by `duration` from now on
+    /// (it doesn't advance by default). Turns finished before keep their duration.
+    /// Needs to be set in the same turn on all machines to give them the same simulated time.
+    pub fn set_simulated_turn_duration(&mut self, duration: ::std::time::Duration) {
+        self.simulated_time_base = (self.networking.n_turns, self.simulated_time());
+        self.simulated_turn_duration = duration;
+    }
+
+    /// Get the simulated time of all finished networking turns, see `set_simulated_turn_duration`
+    pub fn simulated_time(&self) -> ::std::time::Duration {
+        let (base_turn, base_time) = self.simulated_time_base;
+        let n_turns_since = self.networking.n_turns.saturating_sub(base_turn);
+        base_time + self.simulated_turn_duration * n_turns_since as u32
+    }
+
+    /// Get a summary of the **local view** of the networking turn state of all connected peers.
+    pub fn networking_debug_all_n_turns(&self) -> HashMap<MachineID, isize> {
+        self.networking.debug_all_n_turns()
+    }
+
+    /// Get the memory allocated and used by the instances and inbox of each actor class,
+    /// to find out what takes up memory
+    pub fn memory_report(&self) -> MemoryReport {
+        MemoryReport {
+            classes: self
+                .classes
+                .iter()
+                .enumerate()
+                .filter_map(|(i, maybe_class)| maybe_class.as_ref().map(|class| class.memory(i as u16, &self.tuning)))
+                .collect(),
+        }
+    }
+
+    /// Get a structured view of all actor classes, their instances and inboxes,
+    /// and of the networking turn state
+    pub fn inspect(&self) -> SystemInspection {
+        SystemInspection {
+            classes: self
+                .classes
+                .iter()
+                .enumerate()
+                .filter_map(|(i, maybe_class)| {
+                    maybe_class.as_ref().map(|class| ClassInspection {
+                        type_id: i as u16,
+                        type_name: class.v_table.type_name.to_owned(),
+                        instances: *class.instance_store.n_instances,
+                        state_bytes: class.state_bytes(),
+                        queued_messages: class.inbox.len(),
+                        queued_bytes: class.inbox.queued_bytes(),
+                    })
+                })
+                .collect(),
+            networking: NetworkingInspection {
+                machine_id: self.networking.machine_id,
+                n_turns: self.networking.n_turns,
+                peer_n_turns: self.networking.peer_n_turns(),
+                host: self.networking.host(),
+                paused: self.networking.is_paused(),
+            },
+            panic_happened: self.panic_happened,
+            held_packet: self
+                .debugger
+                .as_ref()
+                .and_then(|debugger| debugger.held_header().cloned()),
+        }
+    }
+
+    /// Get local instance counts of each actor class
+    pub fn get_instance_counts(&self) -> HashMap<String, usize> {
+        self.classes
+            .iter()
+            .filter_map(|maybe_class| maybe_class.as_ref())
+            .map(|class| {
+                (
+                    class.v_table.type_name.split("::").last().unwrap().replace(">", ""),
+                    *class.instance_store.n_instances,
+                )
+            }).collect()
+    }
+
+    /// Get statistics of sent messages per type
+    pub fn get_message_statistics(&self) -> HashMap<String, usize> {
+        self.message_statistics
+            .iter()
+            .enumerate()
+            .filter_map(|(i, n_sent)| {
                 if *n_sent > 0 {
                     let name = self
                         .message_registry
@@ -252,9 +2716,423 @@ impl ActorSystem {
             }).collect()
     }
 
-    /// Reset the counter for message statistics
-    pub fn reset_message_statistics(&mut self) {
-        self.message_statistics = [0; MAX_MESSAGE_TYPES]
+    /// Reset the counter for message statistics
+    pub fn reset_message_statistics(&mut self) {
+        for count in self.message_statistics.iter_mut() {
+            *count = 0;
+        }
+    }
+
+    /// Tear down the system gracefully: stop accepting messages sent from outside,
+    /// handle the messages that are still queued for at most `max_turns` turns
+    /// (without finishing networking turns), let all local instances die (running
+    /// their `on_destroy` hooks), handle one final turn of the messages the hooks sent
+    /// (which end up as dead letters if their recipients died), tell peers that we leave and
+    /// send them everything still in their outboxes (waiting at most `flush_timeout`),
+    /// then close all connections.
+    ///
+    /// The system shouldn't be used anymore afterwards, except to inspect it.
+    pub fn shutdown(&mut self, max_turns: usize, flush_timeout: ::std::time::Duration) -> ShutdownReport {
+        self.shut_down = true;
+        let mut report = ShutdownReport::default();
+
+        while report.drained_turns < max_turns && self.n_queued_messages() > 0 {
+            self.process_all_messages();
+            self.networking_send_and_receive();
+            report.drained_turns += 1;
+        }
+
+        report.destroyed_instances += self.destroy_all_instances();
+        self.process_all_messages();
+        // instances spawned in the final turn don't outlive the system either
+        report.destroyed_instances += self.destroy_all_instances();
+
+        report.undelivered_messages = self.n_queued_messages();
+        self.replicate_new_types();
+        report.unflushed_peers = self
+            .networking
+            .shutdown(&mut self.classes, &mut self.trait_implementors, flush_timeout);
+        self.deliver_networking_events();
+        report
+    }
+
+    /// Let all local instances die, letting their `on_destroy` hooks send messages
+    /// (to peers for example), returns how many there were
+    fn destroy_all_instances(&mut self) -> usize {
+        self.handling_messages = true;
+        let mut world = World(self as *mut Self);
+        let mut n_destroyed = 0;
+        for class in self.classes.iter_mut().filter_map(Option::as_mut) {
+            n_destroyed += class
+                .instance_store
+                .destroy_all(&mut world, &class.v_table.state_v_table);
+        }
+        self.handling_messages = false;
+        n_destroyed
+    }
+
+    fn n_queued_messages(&self) -> usize {
+        self.classes
+            .iter()
+            .filter_map(Option::as_ref)
+            .map(|class| class.inbox.len())
+            .sum()
+    }
+
+    /// Subscribe to kay's own lifecycle events: registered classes, spawned and dead instances,
+    /// connected, leaving and disconnected peers, overflowing inboxes, panicked handlers
+    /// and types registered at runtime that conflict with ones of peers.
+    /// Events are sent to all subscribers as they happen, until their receiver is dropped.
+    /// While anybody is subscribed, panicked handlers, type conflicts and peers connecting
+    /// and disconnecting are reported only as events.
+    pub fn subscribe_system_events(&mut self) -> Receiver<SystemEvent> {
+        self.system_events.subscribe()
+    }
+
+    fn emit_system_event(&mut self, event: SystemEvent) {
+        self.system_events.emit(event);
+    }
+
+    /// Emit a peer connecting or disconnecting, or print it while nobody is subscribed
+    fn report_peer_event(&mut self, event: SystemEvent) {
+        if self.system_events.is_empty() {
+            println!("{}", event);
+        } else {
+            self.emit_system_event(event);
+        }
+    }
+
+    fn emit_inbox_overflows(&mut self) {
+        let mut overflows = Vec::new();
+        for (type_id, class) in self.classes.iter_mut().enumerate() {
+            if let Some(ref mut class) = *class {
+                if class.inbox.take_overflowed() {
+                    let type_id = ShortTypeId::new(type_id as u16).expect("Type IDs are never 0");
+                    let name = self.actor_registry.get_name(type_id).clone();
+                    overflows.push((name, class.inbox.queued_bytes()));
+                }
+            }
+        }
+        for (class, queued_bytes) in overflows {
+            self.emit_system_event(SystemEvent::InboxOverflow { class, queued_bytes });
+        }
+    }
+
+    /// Record each use of a source of nondeterminism, like messages sent after a duration,
+    /// query, gather and spatial query timeouts, background tasks or uses reported with
+    /// `World::audit_nondeterminism`, to find what makes machines diverge in test runs.
+    /// If `panic_on_report`, panic at the first use instead, see `take_nondeterminism_reports`
+    pub fn enable_determinism_audit(&mut self, panic_on_report: bool) {
+        self.determinism_audit = Some(DeterminismAudit::new(panic_on_report));
+    }
+
+    /// Take the distinct uses of sources of nondeterminism recorded since the last call
+    /// (empty if the audit mode isn't enabled)
+    pub fn take_nondeterminism_reports(&mut self) -> Vec<NondeterminismReport> {
+        self.determinism_audit.as_mut().map(DeterminismAudit::take_reports).unwrap_or_default()
+    }
+
+    fn report_nondeterminism(&mut self, source: NondeterminismSource, context: &str) {
+        let actor_registry = &self.actor_registry;
+        let class = self.handling_class.map(|class| actor_registry.get_name(class).clone());
+        if let Some(ref mut audit) = self.determinism_audit {
+            audit.report(NondeterminismReport {
+                n_turns: self.networking.n_turns,
+                class,
+                source,
+                context: context.to_owned(),
+            });
+        }
+    }
+
+    /// Start measuring, per actor class, how many messages are handled, how long handling them
+    /// takes and how many instances there are each turn, see `profiling_report`
+    pub fn enable_profiling(&mut self) {
+        self.profiling = true;
+    }
+
+    /// Get the statistics measured per actor class since profiling was enabled or last reset
+    pub fn profiling_report(&self) -> ProfilingReport {
+        ProfilingReport {
+            per_class: self
+                .classes
+                .iter()
+                .filter_map(Option::as_ref)
+                .map(|class| {
+                    let name = class.v_table.type_name.split("::").last().unwrap().replace(">", "");
+                    (name, class.profile)
+                })
+                .collect(),
+            turns: self.profiled_turns,
+        }
+    }
+
+    /// Reset the statistics measured per actor class
+    pub fn reset_profiling(&mut self) {
+        self.profiled_turns = 0;
+        for class in self.classes.iter_mut().filter_map(Option::as_mut) {
+            class.profile = ClassProfile::default();
+        }
+    }
+
+    /// Start recording which actor classes send which message types to which classes,
+    /// discarding anything recorded before
+    pub fn start_topology_sampling(&mut self) {
+        self.topology = Some(TopologySampler::new());
+    }
+
+    /// Stop recording and get the graph of all messages sent since `start_topology_sampling`,
+    /// which can be exported as DOT or JSON
+    pub fn stop_topology_sampling(&mut self) -> MessageTopology {
+        let actor_registry = &self.actor_registry;
+        let message_registry = &self.message_registry;
+        self.topology
+            .take()
+            .map(|topology| {
+                topology.into_topology(
+                    |type_id| actor_registry.get_name(ShortTypeId::new(type_id).unwrap()).clone(),
+                    |type_id| message_registry.get_name(ShortTypeId::new(type_id).unwrap()).clone(),
+                )
+            })
+            .unwrap_or_default()
+    }
+
+    /// Put the actor class (or trait) `A` into a phase of each turn: messages should only be sent
+    /// to classes in the same or a later phase, see `enforce_flow`. Can be combined with
+    /// the priorities of `SchedulingPolicy` to also handle earlier phases first in each message cycle.
+    pub fn set_phase<A: ActorOrActorTrait>(&mut self, phase: u32) {
+        let class_id = self.actor_registry.get_or_register::<A>();
+        self.flow.set_phase(class_id, phase);
+    }
+
+    /// Declare that handlers of `A` send messages of type `M` to `R`. Once any sends are
+    /// declared, all sends that weren't declared violate the message flow (see `enforce_flow`).
+    pub fn declare_sends<A: Actor, M: Message, R: ActorOrActorTrait>(&mut self) {
+        let sender = self.actor_registry.get_or_register::<A>();
+        let message_type = self.message_registry.get_or_register::<M>();
+        let recipient = self.actor_registry.get_or_register::<R>();
+        self.flow.declare(sender, message_type, recipient);
+    }
+
+    /// Declare all sends between message handlers of a sampled topology
+    /// (see `start_topology_sampling`), for example sampled during setup or a test run
+    pub fn declare_sends_from(&mut self, topology: &MessageTopology) {
+        for edge in &topology.edges {
+            let sender = edge.sender.as_ref().and_then(|sender| self.actor_registry.get_by_name(sender));
+            let message_type = self.message_registry.get_by_name(&edge.message_type);
+            let recipient = self.actor_registry.get_by_name(&edge.recipient);
+            if let (Some(sender), Some(message_type), Some(recipient)) = (sender, message_type, recipient) {
+                self.flow.declare(sender, message_type, recipient);
+            }
+        }
+    }
+
+    /// Check the declared message flow: classes that send to each other in a cycle
+    /// and declared sends to earlier phases. Empty if there are no problems.
+    pub fn check_flow(&self) -> Vec<FlowProblem> {
+        let name = |class: ShortTypeId| self.actor_registry.get_name(class).clone();
+        let cycles = self
+            .flow
+            .cycles()
+            .into_iter()
+            .map(|cycle| FlowProblem::Cycle(cycle.into_iter().map(name).collect()));
+        let backward = self
+            .flow
+            .backward_sends()
+            .into_iter()
+            .map(|(sender, message_type, recipient)| FlowProblem::Backward {
+                sender: name(sender),
+                message_type: self.message_registry.get_name(message_type).clone(),
+                recipient: name(recipient),
+            });
+        cycles.chain(backward).collect()
+    }
+
+    /// Check each message sent from a message handler against the declared phases and sends,
+    /// reporting or panicking on violations (`None` stops checking)
+    pub fn enforce_flow(&mut self, enforcement: Option<FlowEnforcement>) {
+        self.flow.enforce(enforcement);
+    }
+
+    /// Take all sends that violated the declared message flow since the last call
+    pub fn take_flow_violations(&mut self) -> Vec<FlowViolation> {
+        self.flow.take_violations()
+    }
+
+    fn check_flow_of_send(&mut self, sender: ShortTypeId, message_type: ShortTypeId, recipient: ShortTypeId) {
+        if let Some(reason) = self.flow.check(sender, message_type, recipient) {
+            let violation = FlowViolation {
+                sender: self.actor_registry.get_name(sender).clone(),
+                message_type: self.message_registry.get_name(message_type).clone(),
+                recipient: self.actor_registry.get_name(recipient).clone(),
+                n_turns: self.networking.n_turns,
+                reason,
+            };
+            if self.flow.enforcement() == Some(FlowEnforcement::Panic) {
+                panic!(
+                    "{} sent {} to {} against the declared message flow: {}",
+                    violation.sender, violation.message_type, violation.recipient, violation.reason
+                );
+            }
+            self.flow.report(violation);
+        }
+    }
+
+    /// Get bytes and message counts sent to and received from peers per message type,
+    /// for each connected peer and aggregated
+    pub fn get_network_traffic(&self) -> NetworkTraffic {
+        let message_registry = &self.message_registry;
+        self.networking.traffic(|message_type_id| {
+            message_registry
+                .short_ids_to_names
+                .get(&message_type_id)
+                .cloned()
+                .unwrap_or_else(|| format!("Unknown message type {}", message_type_id.as_u16()))
+        })
+    }
+
+    /// Dispatch a batch of messages in the wire format as if it was received from the peer
+    /// `from`, checking it like all batches received from peers: lengths, type IDs and
+    /// recipients are checked, messages need to have at least the fixed size of their type,
+    /// and their dynamic parts (like the contents of a `CVec`) need to lie within them
+    /// (see `CompactBounds`), so invalid batches and messages return an error instead of
+    /// breaking the system. Meant as a fuzz target for the wire format, and to inject recorded traffic.
+    pub fn dispatch_untrusted(&mut self, from: MachineID, data: &[u8]) -> Result<(), String> {
+        self.networking
+            .dispatch_untrusted(from, data, &mut self.classes, &mut self.trait_implementors)
+    }
+
+    /// Reset the counters for network traffic
+    pub fn reset_network_traffic(&mut self) {
+        self.networking.reset_traffic()
+    }
+
+    /// Render counters and gauges of this machine in the Prometheus text format,
+    /// for example to serve to a scraper on dedicated servers. Handler times are only
+    /// measured with `enable_profiling`. Counters restart when they are reset
+    /// (see `reset_message_statistics`, `reset_profiling` and `reset_network_traffic`).
+    pub fn metrics(&self) -> String {
+        let mut metrics = MetricsWriter::new();
+        let classes = self.classes.iter().filter_map(Option::as_ref).collect::<Vec<_>>();
+        let class_samples = |value: &dyn Fn(&Class) -> f64| {
+            classes
+                .iter()
+                .map(|class| (vec![("class", class.v_table.type_name.to_owned())], value(class)))
+                .collect::<Vec<_>>()
+        };
+
+        metrics.metric(
+            "kay_turn",
+            MetricKind::Gauge,
+            "The current networking turn",
+            vec![(vec![], self.networking.n_turns as f64)],
+        );
+        metrics.metric(
+            "kay_messages_handled_total",
+            MetricKind::Counter,
+            "Messages handled per message type",
+            self.get_message_statistics()
+                .into_iter()
+                .map(|(message_type, n_handled)| (vec![("message_type", message_type)], n_handled as f64))
+                .collect(),
+        );
+        metrics.metric(
+            "kay_instances",
+            MetricKind::Gauge,
+            "Local instances per actor class",
+            class_samples(&|class| *class.instance_store.n_instances as f64),
+        );
+        metrics.metric(
+            "kay_swarm_memory_bytes",
+            MetricKind::Gauge,
+            "Bytes taken by the state of all local instances per actor class",
+            class_samples(&|class| class.state_bytes() as f64),
+        );
+        metrics.metric(
+            "kay_inbox_depth",
+            MetricKind::Gauge,
+            "Messages queued in the inbox per actor class",
+            class_samples(&|class| class.inbox.len() as f64),
+        );
+        metrics.metric(
+            "kay_inbox_bytes",
+            MetricKind::Gauge,
+            "Bytes of messages queued in the inbox per actor class",
+            class_samples(&|class| class.inbox.queued_bytes() as f64),
+        );
+        if self.profiling {
+            metrics.metric(
+                "kay_class_messages_total",
+                MetricKind::Counter,
+                "Messages handled per actor class (a broadcast counts once)",
+                class_samples(&|class| class.profile.messages as f64),
+            );
+            metrics.metric(
+                "kay_handler_seconds_total",
+                MetricKind::Counter,
+                "Time spent in message handlers per actor class",
+                class_samples(&|class| class.profile.handler_ms / 1000.0),
+            );
+        }
+
+        let n_turns = self.networking.n_turns as f64;
+        metrics.metric(
+            "kay_turn_lag",
+            MetricKind::Gauge,
+            "How many turns each connected peer is behind this machine (negative if ahead)",
+            self.networking
+                .peer_n_turns()
+                .into_iter()
+                .map(|(machine, peer_n_turns)| (vec![("peer", machine.0.to_string())], n_turns - peer_n_turns as f64))
+                .collect(),
+        );
+        let traffic = self.get_network_traffic();
+        let peer_bytes = |bytes: &dyn Fn(&MessageTraffic) -> usize| {
+            traffic
+                .per_peer
+                .iter()
+                .map(|(machine, per_type)| {
+                    (vec![("peer", machine.0.to_string())], per_type.values().map(bytes).sum::<usize>() as f64)
+                })
+                .collect::<Vec<_>>()
+        };
+        metrics.metric(
+            "kay_network_received_bytes_total",
+            MetricKind::Counter,
+            "Bytes of messages received from each connected peer",
+            peer_bytes(&|traffic| traffic.bytes_in),
+        );
+        metrics.metric(
+            "kay_network_sent_bytes_total",
+            MetricKind::Counter,
+            "Bytes of messages sent to each connected peer",
+            peer_bytes(&|traffic| traffic.bytes_out),
+        );
+        let rate_limited = self
+            .rate_limit_counters()
+            .into_iter()
+            .flat_map(|counters| {
+                let labels = |action: &str| {
+                    vec![
+                        ("class", counters.class.clone()),
+                        ("message_type", counters.message_type.clone()),
+                        ("action", action.to_owned()),
+                    ]
+                };
+                vec![
+                    (labels("drop"), counters.dropped as f64),
+                    (labels("defer"), counters.deferred as f64),
+                    (labels("dead_letter"), counters.dead_lettered as f64),
+                ]
+            })
+            .collect();
+        metrics.metric(
+            "kay_rate_limited_messages_total",
+            MetricKind::Counter,
+            "Messages beyond the rate limit of their recipient, by what happened to them",
+            rate_limited,
+        );
+        metrics.finish()
     }
 
     /// Get the current length of all actor message queues
@@ -301,57 +3179,881 @@ unsafe impl Send for World {}
 impl World {
     /// Send a message to a RawID
     pub fn send<M: Message>(&mut self, receiver: RawID, message: M) {
-        unsafe { &mut *self.0 }.send(receiver, message);
+        if let Some(send) = defer(self.0, move |system: &mut ActorSystem| system.send(receiver, message)) {
+            send(unsafe { &mut *self.0 });
+        }
+    }
+
+    /// Send a message that can be cancelled, see `ActorSystem::send_cancellable`
+    pub fn send_cancellable<M: Message>(&mut self, receiver: RawID, message: M, key: u64) {
+        if let Some(send) = defer(self.0, move |system: &mut ActorSystem| system.send_cancellable(receiver, message, key)) {
+            send(unsafe { &mut *self.0 });
+        }
+    }
+
+    /// Cancel messages sent with `send_cancellable` and `key` that weren't handled
+    /// or sent to peers yet, see `ActorSystem::cancel_messages`
+    pub fn cancel_messages(&mut self, key: u64) {
+        if let Some(cancel) = defer(self.0, move |system: &mut ActorSystem| {
+            system.cancel_messages(key);
+        }) {
+            cancel(unsafe { &mut *self.0 });
+        }
+    }
+
+    /// Send a message once `n_turns` more networking turns have started
+    pub fn send_in_turns<M: Message>(&mut self, recipient: RawID, message: M, n_turns: usize) -> ScheduledMessage {
+        not_in_worker("Scheduling messages");
+        unsafe { &mut *self.0 }.send_in_turns(recipient, message, n_turns)
+    }
+
+    /// Send a message once `delay` has passed
+    pub fn send_after<M: Message>(&mut self, recipient: RawID, message: M, delay: ::std::time::Duration) -> ScheduledMessage {
+        not_in_worker("Scheduling messages");
+        unsafe { &mut *self.0 }.send_after(recipient, message, delay)
+    }
+
+    /// Cancel a scheduled message, returns false if it was already sent or cancelled
+    pub fn cancel_scheduled(&mut self, scheduled: ScheduledMessage) -> bool {
+        not_in_worker("Cancelling scheduled messages");
+        unsafe { &mut *self.0 }.cancel_scheduled(scheduled)
+    }
+
+    /// Run `task` on a background thread and send its result to `recipient`
+    /// once it finished, see `ActorSystem::spawn_task`
+    pub fn spawn_task<M: Message + Send, F: FnOnce() -> M + Send + 'static>(&mut self, recipient: RawID, task: F) {
+        if let Some(spawn) = defer(self.0, move |system: &mut ActorSystem| system.spawn_task(recipient, task)) {
+            spawn(unsafe { &mut *self.0 });
+        }
+    }
+
+    /// Poll `future` between turns and send its output to `recipient`
+    /// once it completed, see `ActorSystem::spawn_future`
+    pub fn spawn_future<M: Message, F: ::std::future::Future<Output = M> + 'static>(&mut self, recipient: RawID, future: F) {
+        if let Some(spawn) = defer(self.0, move |system: &mut ActorSystem| system.spawn_future(recipient, future)) {
+            spawn(unsafe { &mut *self.0 });
+        }
+    }
+
+    /// Subscribe `subscriber` to all messages published to `topic`, on all machines
+    pub fn subscribe<M: Message>(&mut self, topic: Topic<M>, subscriber: RawID) {
+        if let Some(subscribe) = defer(self.0, move |system: &mut ActorSystem| system.subscribe(topic, subscriber)) {
+            subscribe(unsafe { &mut *self.0 });
+        }
+    }
+
+    /// Stop sending messages published to `topic` to `subscriber`
+    pub fn unsubscribe<M: Message>(&mut self, topic: Topic<M>, subscriber: RawID) {
+        if let Some(unsubscribe) = defer(self.0, move |system: &mut ActorSystem| system.unsubscribe(topic, subscriber)) {
+            unsubscribe(unsafe { &mut *self.0 });
+        }
+    }
+
+    /// Send a message to all subscribers of `topic`, wherever they are
+    pub fn publish<M: Message>(&mut self, topic: Topic<M>, message: M) {
+        if let Some(publish) = defer(self.0, move |system: &mut ActorSystem| system.publish(topic, message)) {
+            publish(unsafe { &mut *self.0 });
+        }
+    }
+
+    /// Send `request` to `recipient` as a query: its handler answers it using
+    /// `world.asker()`, with a reply of type `R`, as in `world.query::<Count>(counter, GetCount, timeout)`.
+    /// Works both locally and across machines, the correlation of request and reply is carried
+    /// in their packet headers. `R` needs to be registered with `ActorSystem::register_query_reply`
+    /// on all machines. The returned handle times out if no reply arrived within `timeout`.
+    pub fn query<R: Message>(
+        &mut self,
+        recipient: RawID,
+        request: impl Message,
+        timeout: ::std::time::Duration,
+    ) -> QueryHandle<R> {
+        not_in_worker("Asking queries");
+        let system: &mut ActorSystem = unsafe { &mut *self.0 };
+        let machine = system.networking.machine_id;
+        let (correlation, handle) = system.queries.ask::<R>(machine, duration_ms(timeout));
+        system.send_correlated(recipient, request, correlation);
+        handle
+    }
+
+    /// Who asked the query whose request is being handled, to answer it with `Asker::reply`.
+    /// `None` if the message being handled wasn't sent with `World::query`.
+    pub fn asker(&self) -> Option<Asker> {
+        let correlation = handled_correlation();
+        if correlation.is_query() {
+            Some(Asker::new(correlation))
+        } else {
+            None
+        }
+    }
+
+    /// Send the reply to a query, see `Asker::reply`
+    pub(crate) fn send_reply<R: Message>(&mut self, correlation: Correlation, reply: R) {
+        if let Some(send) = defer(self.0, move |system: &mut ActorSystem| system.send_reply(correlation, reply)) {
+            send(unsafe { &mut *self.0 });
+        }
+    }
+
+    /// Send a message on with the correlation of the packet it came in, see `ActorSystem::send_correlated`
+    pub(crate) fn send_correlated<M: Message>(&mut self, receiver: RawID, message: M, correlation: Correlation) {
+        if let Some(send) = defer(self.0, move |system: &mut ActorSystem| system.send_correlated(receiver, message, correlation)) {
+            send(unsafe { &mut *self.0 });
+        }
+    }
+
+    /// The base ID of `A`, see `ActorSystem::id`. Handlers on workers only read the registry
+    /// (other workers read it at the same time), so there `A` needs to be registered already.
+    fn base_id<A: ActorOrActorTrait>(&mut self) -> RawID {
+        if in_worker() {
+            let (actor_registry, machine) = unsafe { (&(*self.0).actor_registry, (*self.0).networking.machine_id) };
+            RawID::new(actor_registry.get::<A>(), 0, machine, 0)
+        } else {
+            unsafe { &mut *self.0 }.id::<A>()
+        }
     }
 
     /// Get the RawID of the first local actor of a certain type
     /// (Note: no such actor might exist)
     pub fn local_first<A: ActorOrActorTrait>(&mut self) -> RawID {
-        unsafe { &mut *self.0 }.id::<A>()
+        self.base_id::<A>()
     }
 
     /// Get the RawID of the first global actor (among all network peers)
     /// of a certain type (Note: no such actor might exist)
     pub fn global_first<A: ActorOrActorTrait>(&mut self) -> RawID {
-        let mut id = unsafe { &mut *self.0 }.id::<A>();
+        let mut id = self.base_id::<A>();
         id.machine = MachineID(0);
         id
     }
 
     /// Get a RawID for a broadcast to all local actors of a certain type
     pub fn local_broadcast<A: ActorOrActorTrait>(&mut self) -> RawID {
-        unsafe { &mut *self.0 }.id::<A>().local_broadcast()
+        self.base_id::<A>().local_broadcast()
     }
 
     /// Get a RawID for a broadcast to all global actors
     /// (across all network peers) of a certain type
     pub fn global_broadcast<A: ActorOrActorTrait>(&mut self) -> RawID {
-        unsafe { &mut *self.0 }.id::<A>().global_broadcast()
+        self.base_id::<A>().global_broadcast()
+    }
+
+    /// Get a RawID for a broadcast to all actors of a certain type
+    /// on the members of a multicast group
+    pub fn multicast<A: ActorOrActorTrait>(&mut self, group: u8) -> RawID {
+        self.base_id::<A>().multicast(group)
     }
 
     /// Allocate a new instance id to be used by a to-be-spawned actor
     pub fn allocate_instance_id<A: 'static + Actor>(&mut self) -> RawID {
+        let machine = self.local_machine_id();
+        self.allocate_instance_id_on::<A>(machine)
+    }
+
+    /// Allocate the ID of an instance to be spawned on `machine` (by sending the to-be-spawned
+    /// state there), which needs an `IdAllocator` for other machines (see `ActorSystem::set_id_allocator`)
+    pub fn allocate_instance_id_on<A: 'static + Actor>(&mut self, machine: MachineID) -> RawID {
+        self.reserve_instance_ids::<A>(machine, 1).next().expect("Should have reserved one ID")
+    }
+
+    /// Allocate `n` consecutive IDs of instances to be spawned on `machine` at once,
+    /// like `allocate_instance_id_on`
+    pub fn reserve_instance_ids<A: 'static + Actor>(&mut self, machine: MachineID, n: usize) -> IdBlock {
+        let mut base_id = self.local_broadcast::<A>();
+        base_id.machine = machine;
+        let (local, n_turns) = unsafe { ((*self.0).networking.machine_id, (*self.0).networking.n_turns) };
+        let class = self.class_for_ids(base_id.type_id, "Allocating IDs");
+        let first_id = if n == 1 {
+            unsafe { class.instance_store.allocate_id(base_id, local, n_turns) }
+        } else {
+            class.instance_store.allocate_contiguous_ids(n, base_id, local, n_turns)
+        };
+        IdBlock::new(first_id, n as u32)
+    }
+
+    /// How many more IDs of instances on `machine` this machine can allocate in the current turn
+    pub fn remaining_instance_ids<A: 'static + Actor>(&mut self, machine: MachineID) -> usize {
+        let type_id = self.local_broadcast::<A>().type_id;
+        let (local, n_turns) = unsafe { ((*self.0).networking.machine_id, (*self.0).networking.n_turns) };
+        self.class_for_ids(type_id, "Allocating IDs").instance_store.remaining_ids(machine, local, n_turns)
+    }
+
+    /// The class whose instance IDs are allocated. Handlers on a worker only reach
+    /// the classes of their own group, which no other thread touches meanwhile.
+    fn class_for_ids(&mut self, type_id: ShortTypeId, what: &str) -> &mut Class {
+        #[cfg(feature = "server")]
+        {
+            if in_worker() {
+                let class = group_class(type_id).unwrap_or_else(|| {
+                    panic!(
+                        "{} of {} from a worker requires declare_dependency",
+                        what,
+                        unsafe { &(*self.0).actor_registry }.get_name(type_id)
+                    )
+                });
+                return unsafe { &mut *class };
+            }
+        }
+        let _ = what;
         let system: &mut ActorSystem = unsafe { &mut *self.0 };
-        let class = system.classes[system.actor_registry.get::<A>().as_usize()].as_mut()
-                .expect("Subactor type not found.");
-        unsafe { class.instance_store.allocate_id(self.local_broadcast::<A>()) }
+        system.classes[type_id.as_usize()].as_mut().expect("Subactor type not found.")
     }
 
     /// Get the machine ID of this system in the network
     pub fn local_machine_id(&mut self) -> MachineID {
+        unsafe { (*self.0).networking.machine_id }
+    }
+
+    /// Get the current networking turn, the same on all machines while the messages of a turn
+    /// are handled (see `ActorSystem::networking_n_turns`)
+    pub fn n_turns(&self) -> usize {
+        unsafe { (*self.0).networking.n_turns }
+    }
+
+    /// Get the simulated time of all turns before the current one,
+    /// see `ActorSystem::set_simulated_turn_duration`
+    pub fn simulated_time(&self) -> ::std::time::Duration {
+        unsafe { &*self.0 }.simulated_time()
+    }
+
+    /// The machine owning the shard `key` of `A`, see `ActorSystem::set_sharding`
+    pub fn shard_owner<A: Actor>(&mut self, key: u64) -> MachineID {
+        let class = self.base_id::<A>().type_id;
+        let (placement, actor_registry) = unsafe { (&(*self.0).placement, &(*self.0).actor_registry) };
+        placement.shard_owner(class, key).unwrap_or_else(|| {
+            panic!("{} has no sharding key function", actor_registry.get_name(class))
+        })
+    }
+
+    /// Get the ID of the class `A` on the machine owning the shard `key`,
+    /// to send the spawning message of an instance in that shard to
+    pub fn place_in_shard<A: Actor>(&mut self, key: u64) -> RawID {
+        let mut id = self.base_id::<A>();
+        id.machine = self.shard_owner::<A>(key);
+        id
+    }
+
+    /// Get a RawID for a broadcast to the instances of `A` on the machine owning
+    /// the shard `key`, instead of to the instances on all machines
+    pub fn shard_broadcast<A: Actor>(&mut self, key: u64) -> RawID {
+        self.place_in_shard::<A>(key).local_broadcast()
+    }
+
+    /// Choose the machine to spawn a new instance of `A` on, according to the placement
+    /// policy of the class (see `ActorSystem::set_placement`) and the load of all machines.
+    /// If `near` is given, the instance is placed on the same machine as that actor.
+    /// Returns the ID of the class on the chosen machine, to send the spawning message to,
+    /// which avoids migrating the instance after it was spawned here.
+    pub fn place<A: Actor>(&mut self, near: Option<RawID>) -> RawID {
+        not_in_worker("Placing instances");
+        let system: &mut ActorSystem = unsafe { &mut *self.0 };
+        let mut id = system.id::<A>();
+        id.machine = system.placement.place(id.type_id, near, system.networking.machine_id);
+        id
+    }
+
+    /// A random number generator for the actor `id` in the current networking turn,
+    /// which produces the same numbers on all machines (unlike `thread_rng`).
+    /// Calling this again during the same turn starts the same stream again,
+    /// use `rng_stream` to get several independent streams.
+    pub fn rng(&mut self, id: RawID) -> DeterministicRng {
+        self.rng_stream(id, 0)
+    }
+
+    /// Like `rng`, but one of many independent streams for the actor `id` in the current turn
+    pub fn rng_stream(&mut self, id: RawID, stream: u64) -> DeterministicRng {
+        let (random_seed, n_turns) = unsafe { ((*self.0).random_seed, (*self.0).networking.n_turns) };
+        DeterministicRng::for_actor(random_seed, n_turns, id, stream)
+    }
+
+    /// A bump arena for temporaries of the current handler (allocating from it is nearly free),
+    /// which is reset at the end of the turn. What is allocated from it can't outlive the returned
+    /// scope, which keeps the arena from being reset while it is alive, but it still shouldn't
+    /// end up in actor state or messages.
+    pub fn scratch(&mut self) -> ScratchScope {
+        Scratch::for_turn(self.0 as usize, unsafe { (*self.0).scratch_turn })
+    }
+
+    /// Report that the current handler depends on a source of nondeterminism (like branching
+    /// on the local time), which the audit mode records, see `ActorSystem::enable_determinism_audit`
+    pub fn audit_nondeterminism(&mut self, source: NondeterminismSource, context: &str) {
+        let context = context.to_owned();
+        if let Some(report) = defer(self.0, move |system: &mut ActorSystem| system.report_nondeterminism(source, &context)) {
+            report(unsafe { &mut *self.0 });
+        }
+    }
+
+    /// Register `id` under `name` on all machines, see `ActorSystem::register_name`
+    pub fn register_name(&mut self, name: &str, id: RawID) {
+        let name = name.to_owned();
+        if let Some(register) = defer(self.0, move |system: &mut ActorSystem| system.register_name(&name, id)) {
+            register(unsafe { &mut *self.0 });
+        }
+    }
+
+    /// Remove `name` on all machines, if `id` is still registered under it
+    pub fn unregister_name(&mut self, name: &str, id: RawID) {
+        let name = name.to_owned();
+        if let Some(unregister) = defer(self.0, move |system: &mut ActorSystem| system.unregister_name(&name, id)) {
+            unregister(unsafe { &mut *self.0 });
+        }
+    }
+
+    /// The actor registered under `name`, as currently known on this machine
+    pub fn resolve_name(&mut self, name: &str) -> Option<RawID> {
+        unsafe { &(*self.0).names }.resolve(name)
+    }
+
+    /// Change which positioned broadcasts this machine receives, see `ActorSystem::set_interest_areas`
+    pub fn set_interest_areas(&mut self, areas: Option<Vec<SpatialArea>>) {
+        if let Some(set) = defer(self.0, move |system: &mut ActorSystem| system.set_interest_areas(areas)) {
+            set(unsafe { &mut *self.0 });
+        }
+    }
+
+    /// Send `Terminated(target)` to `observer` once `target` dies, see `ActorSystem::watch`
+    pub fn watch(&mut self, target: RawID, observer: RawID) {
+        if let Some(watch) = defer(self.0, move |system: &mut ActorSystem| system.watch(target, observer)) {
+            watch(unsafe { &mut *self.0 });
+        }
+    }
+
+    /// Stop watching `target` for `observer`
+    pub fn unwatch(&mut self, target: RawID, observer: RawID) {
+        if let Some(unwatch) = defer(self.0, move |system: &mut ActorSystem| system.unwatch(target, observer)) {
+            unwatch(unsafe { &mut *self.0 });
+        }
+    }
+
+    /// Remember a message whose recipient doesn't exist
+    pub(crate) fn dead_letter(&mut self, recipient: RawID, message_type: ShortTypeId, packet_ptr: *const ()) {
+        // the packet is only valid until the next packet is dequeued
+        let message = unsafe { &(*self.0).packet_decoders }.decode(message_type, packet_ptr);
+        if let Some(record) = defer(self.0, move |system: &mut ActorSystem| system.dead_letter(recipient, message_type, message)) {
+            record(unsafe { &mut *self.0 });
+        }
+    }
+
+    /// Notify the observers of a local instance that just died
+    pub(crate) fn instance_died(&mut self, id: RawID) {
+        if unsafe { (*self.0).watches.is_empty() && (*self.0).system_events.is_empty() } {
+            return;
+        }
+        if let Some(notify) = defer(self.0, move |system: &mut ActorSystem| system.instance_died(id)) {
+            notify(unsafe { &mut *self.0 });
+        }
+    }
+
+    /// Tell subscribers to system events about a local instance that was just spawned
+    pub(crate) fn instance_spawned(&mut self, id: RawID) {
+        if unsafe { (*self.0).system_events.is_empty() } {
+            return;
+        }
+        let emit = defer(self.0, move |system: &mut ActorSystem| {
+            system.emit_system_event(SystemEvent::InstanceSpawned(id))
+        });
+        if let Some(emit) = emit {
+            emit(unsafe { &mut *self.0 });
+        }
+    }
+
+    /// Move an instance that was just spawned here to the machine owning its shard
+    /// at the end of the turn, if its class is sharded (see `ActorSystem::set_sharding`),
+    /// or else to the machine its placement policy chooses, if it was spawned by a
+    /// local broadcast (see `ActorSystem::set_placement`)
+    pub(crate) fn place_spawned(&mut self, id: RawID, state: *const (), by_broadcast: bool) {
+        let (placement, local) = unsafe { (&(*self.0).placement, (*self.0).networking.machine_id) };
+        let place: Box<dyn FnOnce(&mut ActorSystem)> = match placement.owner_of_instance(id.type_id, state) {
+            Some(machine) if machine != local => {
+                Box::new(move |system: &mut ActorSystem| system.placed_spawns.push((id, machine)))
+            }
+            Some(_) => return,
+            None if by_broadcast && placement.has_policy(id.type_id) => Box::new(move |system: &mut ActorSystem| {
+                let machine = system.placement.place(id.type_id, None, local);
+                if machine != local {
+                    system.placed_spawns.push((id, machine));
+                }
+            }),
+            None => return,
+        };
+        if let Some(place) = defer(self.0, place) {
+            place(unsafe { &mut *self.0 });
+        }
+    }
+
+    /// Move a local actor instance to another machine, see `ActorSystem::migrate`
+    pub fn migrate(&mut self, id: RawID, to: MachineID) -> bool {
+        not_in_worker("Migrating instances");
+        unsafe { &mut *self.0 }.migrate(id, to)
+    }
+
+    /// The instances of `A` on this machine in `area`, see `ActorSystem::instances_in`
+    pub fn instances_in<A: Actor>(&self, area: SpatialArea) -> Vec<RawID> {
+        unsafe { &*self.0 }.instances_in::<A>(area)
+    }
+
+    /// Find the instances of `A` in `area` on all machines. Once all connected machines answered,
+    /// or after `timeout`, `requester` receives all of them in a `FoundInstances` message
+    /// (which it needs to handle). Machines without a spatial index for `A` report no instances.
+    pub fn find_in<A: Actor>(
+        &mut self,
+        area: SpatialArea,
+        requester: RawID,
+        timeout: ::std::time::Duration,
+    ) -> SpatialQueryID {
+        not_in_worker("Finding instances");
+        let system: &mut ActorSystem = unsafe { &mut *self.0 };
+        let class = system.actor_registry.get::<A>();
+        let mut machines = system.networking.connected_machines();
+        machines.push(system.networking.machine_id);
+        let query = system.spatial.start(requester, machines, now_ms() + duration_ms(timeout));
+        let origin = system.networking.machine_id;
+        let all_services = system.services_id(origin).global_broadcast();
+        system.send(all_services, SpatialQuery { class, area, query, origin });
+        query
+    }
+
+    /// Broadcast `request` to all instances of `A` (a class or trait) on all machines,
+    /// wrapped in a `Gather` message that they answer with `gatherer.reply`.
+    /// Once all instances that received it replied, or after `timeout`, `requester` receives
+    /// all replies in one `Gathered` message (which it needs to handle).
+    /// The request and reply types need to be registered with `ActorSystem::register_gather`.
+    pub fn gather<A: ActorOrActorTrait, Req: Message, R: Message>(
+        &mut self,
+        request: Req,
+        requester: RawID,
+        timeout: ::std::time::Duration,
+    ) -> GatherID {
+        not_in_worker("Gathering replies");
+        let system: &mut ActorSystem = unsafe { &mut *self.0 };
+        let class = system.short_id::<A>();
+        let origin = system.networking.machine_id;
+        let services = system.services_id(origin);
+        let mut machines = system.networking.connected_machines();
+        machines.push(origin);
+        let gatherer = system
+            .gathers
+            .start::<R>(services, requester, machines, now_ms() + duration_ms(timeout));
+        system.send(services.global_broadcast(), GatherRequest { class, request, gatherer });
+        gatherer.gather()
+    }
+
+    /// Panics if instances of `A` might be in use while we access them
+    fn check_not_handling<A: Actor>(&self, what: &str) {
+        not_in_worker(what);
+        let system: &ActorSystem = unsafe { &*self.0 };
+        if system.handling_class == Some(system.actor_registry.get::<A>()) {
+            panic!(
+                "{} isn't possible from handlers of {}",
+                what,
+                system.actor_registry.get_name(system.actor_registry.get::<A>())
+            );
+        }
+    }
+
+    /// Add many instances of `A` at once, see `ActorSystem::spawn_many`.
+    /// Not possible from handlers of `A` itself or during parallel processing.
+    pub fn spawn_many<A: Actor, I: IntoIterator<Item = A>>(&mut self, states: I) -> Vec<<A as Actor>::ID> {
+        self.check_not_handling::<A>("Spawning many instances");
+        unsafe { &mut *self.0 }.spawn_many::<A, I>(states)
+    }
+
+    /// The number of instances of `A` on this machine
+    pub fn instance_count<A: Actor>(&self) -> usize {
+        #[cfg(feature = "server")]
+        {
+            if in_worker() {
+                let type_id = unsafe { &(*self.0).actor_registry }.get::<A>();
+                let class = group_class(type_id).unwrap_or_else(|| {
+                    panic!("Counting instances of other groups isn't possible in handlers during parallel processing")
+                });
+                return *unsafe { &*class }.instance_store.n_instances;
+            }
+        }
+        unsafe { &*self.0 }.instance_count::<A>()
+    }
+
+    /// A read-only view of a local instance of `A`, see `ActorSystem::instance`.
+    /// Not possible from handlers of `A` itself or during parallel processing.
+    pub fn instance<A: Actor>(&self, id: <A as Actor>::ID) -> Option<&A> {
+        self.check_not_handling::<A>("Looking at instances");
+        unsafe { &*self.0 }.instance::<A>(id)
+    }
+
+    /// Send a message given as JSON, see `ActorSystem::send_json`
+    #[cfg(feature = "serde-serialization")]
+    pub fn send_json(&mut self, recipient: RawID, type_name: &str, json: &str) -> Result<(), String> {
+        let system = unsafe { &*self.0 };
+        system.json_messages.send(recipient, type_name, json, self)
+    }
+
+    /// Run a command of the actor console, see `ActorSystem::run_console_command`
+    #[cfg(feature = "serde-serialization")]
+    pub fn run_console_command(&mut self, command: &str) -> Result<String, String> {
+        let system: &mut ActorSystem = unsafe { &mut *self.0 };
+        system.run_console_command(command)
+    }
+
+    /// Look at all local instances of `A`, see `ActorSystem::for_each_instance`.
+    /// Not possible from handlers of `A` itself or during parallel processing.
+    pub fn for_each_instance<A: Actor, F: FnMut(&A)>(&self, f: F) {
+        self.check_not_handling::<A>("Looking at instances");
+        unsafe { &*self.0 }.for_each_instance::<A, F>(f)
+    }
+
+    /// A broadcast reached `n_recipients` instances of a class, tell the gathering machine
+    /// how many replies to expect if it is a `Gather` request
+    pub(crate) fn broadcast_resolved(&mut self, message_type: ShortTypeId, packet_ptr: *const (), n_recipients: usize) {
+        let resolved_fn = unsafe { (*self.0).gathers.resolved_fn(message_type) };
+        if let Some(resolved_fn) = resolved_fn {
+            resolved_fn(packet_ptr, n_recipients, self);
+        }
+    }
+
+    /// Is this instance migrating away or did it migrate away from this machine?
+    pub(crate) fn has_migrated(&mut self, id: RawID) -> bool {
+        let migrations = unsafe { &(*self.0).migrations };
+        !migrations.is_empty() && migrations.has_route(id)
+    }
+
+    /// Send a message that was addressed to a migrated instance to its new ID
+    pub(crate) fn forward_to_migrated(&mut self, id: RawID, forwarding: Forwarding) {
+        let forward = move |system: &mut ActorSystem| {
+            if let Some((new_id, forwarding)) = system.migrations.forward(id, forwarding) {
+                forwarding(new_id, &mut World(system));
+            }
+        };
+        if let Some(forward) = defer(self.0, forward) {
+            forward(unsafe { &mut *self.0 });
+        }
+    }
+
+    /// Handle a broadcast to `class` on the worker threads of parallel processing,
+    /// if they are enabled for it. Returns false if the broadcast still needs to be handled.
+    #[cfg(feature = "server")]
+    pub(crate) fn broadcast_in_parallel(
+        &mut self,
+        class: ShortTypeId,
+        instance_store: &mut InstanceStore,
+        packet_ptr: *const (),
+        handler: &HandlerFnRef,
+        state_v_table: &ActorStateVTable,
+        supervision: SupervisionPolicy,
+        message_type: ShortTypeId,
+    ) -> bool {
         let system: &mut ActorSystem = unsafe { &mut *self.0 };
-        system.networking.machine_id
+        let parallel = match system.parallel {
+            Some(ref parallel) if parallel.should_broadcast_in_parallel(class, *instance_store.n_instances) => parallel,
+            _ => return false,
+        };
+
+        let system_ptr = self.0;
+        let mut deferred = Vec::new();
+        instance_store.receive_broadcast_batched(&mut World(system_ptr), state_v_table, |actors, _| {
+            let (fates, broadcast_deferred) = parallel.broadcast(system_ptr, class, actors, |actor| {
+                call_supervised(handler, actor, packet_ptr, &mut World(system_ptr), supervision, message_type, state_v_table)
+            });
+            deferred = broadcast_deferred;
+            fates
+        });
+
+        for (_, action) in deferred {
+            action(system);
+        }
+        true
+    }
+
+    /// Report a panic inside a message handler that was caught,
+    /// notifying the supervisor if there is one
+    pub(crate) fn handler_panicked(
+        &mut self,
+        actor: RawID,
+        message_type: ShortTypeId,
+        reason: String,
+        policy: SupervisionPolicy,
+    ) {
+        let (message_registry, actor_registry) = unsafe { (&(*self.0).message_registry, &(*self.0).actor_registry) };
+        let message_type = message_registry.get_name(message_type).clone();
+        if unsafe { (*self.0).system_events.is_empty() } {
+            println!(
+                "Handler for {} in {} panicked ({:?}): {}",
+                message_type,
+                actor_registry.get_name(actor.type_id),
+                policy,
+                reason
+            );
+        } else {
+            let event = SystemEvent::HandlerPanicked {
+                actor,
+                message_type: message_type.clone(),
+                reason: reason.clone(),
+            };
+            if let Some(emit) = defer(self.0, move |system: &mut ActorSystem| system.emit_system_event(event)) {
+                emit(unsafe { &mut *self.0 });
+            }
+        }
+        if let Some(supervisor) = unsafe { (*self.0).supervisor } {
+            self.send(
+                supervisor,
+                HandlerPanicked {
+                    actor,
+                    message_type: message_type.into(),
+                    reason: reason.into(),
+                    policy,
+                },
+            );
+        }
     }
 
     /// Returns whether the system is in a panicked state
     pub fn panic_happened(&self) -> bool {
-        let system: &mut ActorSystem = unsafe { &mut *self.0 };
-        system.panic_happened
+        unsafe { (*self.0).panic_happened }
     }
 
     /// Get the name of an actor class by type ID
     pub fn get_actor_name(&mut self, type_id: ShortTypeId) -> &str {
-        let system: &mut ActorSystem = unsafe { &mut *self.0 };
-        system.actor_registry.get_name(type_id)
+        unsafe { &(*self.0).actor_registry }.get_name(type_id)
+    }
+
+    /// Format `id` using the name of its actor class, see `ActorSystem::display_id`
+    pub fn display_id(&mut self, id: RawID) -> NamedRawID<'_> {
+        NamedRawID::new(id, unsafe { &(*self.0).actor_registry })
+    }
+
+    /// Parse an ID in the format of `display_id`, see `ActorSystem::parse_id`
+    pub fn parse_id(&mut self, id: &str) -> Result<RawID, ParseRawIDError> {
+        parse_named(id, unsafe { &(*self.0).actor_registry })
+    }
+}
+
+/// Panic if called by a handler on a worker thread (see `ActorSystem::enable_parallel_processing`)
+fn not_in_worker(what: &str) {
+    if in_worker() {
+        panic!("{} isn't possible in handlers during parallel processing", what);
+    }
+}
+
+#[test]
+fn test_on_spawn_and_on_migrate_hooks() {
+    use crate::test_support::{local_system, Add, Counter, CounterID};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Compact, Clone)]
+    struct SpawnCounter(u32);
+
+    let spawned = Rc::new(RefCell::new(Vec::new()));
+    let migrated = Rc::new(RefCell::new(Vec::new()));
+    let mut system = local_system();
+    system.register::<Counter>();
+    system.add_handler::<Counter, _, _>(
+        |&Add(n), counter, _| {
+            counter.count += n;
+            Fate::Live
+        },
+        false,
+    );
+    system.add_spawner::<Counter, _, _>(
+        |&SpawnCounter(n), world| {
+            let mut counter = Counter::new(n);
+            counter.id = CounterID::from_raw(world.allocate_instance_id::<Counter>());
+            counter
+        },
+        false,
+    );
+    let spawned_ids = Rc::clone(&spawned);
+    system.on_spawn::<Counter, _>(move |counter, world| {
+        spawned_ids.borrow_mut().push(counter.id);
+        counter.count += 100;
+        world.send(counter.id.as_raw(), Add(1));
+    });
+    let migrated_ids = Rc::clone(&migrated);
+    system.on_migrate::<Counter, _>(move |counter, to, _| {
+        migrated_ids.borrow_mut().push((counter.id, to, counter.count));
+    });
+
+    let many = system.spawn_many(vec![Counter::new(0), Counter::new(10)]);
+    let counter_class = system.world().local_first::<Counter>();
+    system.send(counter_class, SpawnCounter(20));
+    system.process_all_messages();
+
+    let spawned = spawned.borrow().clone();
+    assert_eq!(spawned.len(), 3);
+    assert_eq!(&spawned[..2], &many[..]);
+    // the hook sees the ID and state the instance lives with
+    assert_eq!(system.instance::<Counter>(many[1]).unwrap().count, 111);
+    assert_eq!(system.instance::<Counter>(spawned[2]).unwrap().count, 121);
+
+    // staying on this machine isn't a migration
+    assert!(system.migrate(many[0].as_raw(), MachineID(0)));
+    assert!(migrated.borrow().is_empty());
+    assert!(system.migrate(many[0].as_raw(), MachineID(1)));
+    assert!(system.instance::<Counter>(many[0]).is_none());
+    // neither is trying to move an instance that isn't here anymore
+    assert!(!system.migrate(many[0].as_raw(), MachineID(1)));
+    assert_eq!(&migrated.borrow()[..], &[(many[0], MachineID(1), 101)]);
+}
+
+/// An actor whose state grows and shrinks, for tests of instance storage
+#[cfg(test)]
+#[derive(Compact, Clone)]
+struct Bag {
+    id: BagID,
+    items: ::compact::CVec<u32>,
+}
+
+#[cfg(test)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+struct BagID {
+    _raw_id: RawID,
+}
+
+#[cfg(test)]
+impl TypedID for BagID {
+    type Target = Bag;
+
+    fn from_raw(id: RawID) -> Self {
+        BagID { _raw_id: id }
+    }
+    fn as_raw(&self) -> RawID {
+        self._raw_id
+    }
+}
+
+#[cfg(test)]
+impl Actor for Bag {
+    type ID = BagID;
+    fn id(&self) -> Self::ID {
+        self.id
+    }
+    unsafe fn set_id(&mut self, id: RawID) {
+        self.id = Self::ID::from_raw(id);
+    }
+}
+
+/// Makes a bag hold this many items, or die
+#[cfg(test)]
+#[derive(Compact, Clone)]
+struct Fill(Option<u32>);
+
+#[cfg(test)]
+fn bag_system() -> ActorSystem {
+    let mut system = crate::test_support::local_system();
+    system.register::<Bag>();
+    system.add_handler::<Bag, _, _>(
+        |&Fill(n_items), bag, _| match n_items {
+            None => Fate::Die,
+            // an empty vector stays compact, so the instance isn't moved to a smaller slot
+            Some(0) => {
+                bag.items = ::compact::CVec::new();
+                Fate::Live
+            }
+            Some(n_items) => {
+                bag.items = (0..n_items).collect();
+                Fate::Live
+            }
+        },
+        false,
+    );
+    system
+}
+
+#[test]
+fn test_compact_keeps_ids() {
+    let mut system = bag_system();
+    let bags = system.spawn_many((0..100).map(|_| Bag {
+        id: BagID::from_raw(crate::test_support::test_id(0, 0)),
+        items: ::compact::CVec::new(),
+    }));
+    for &bag in &bags {
+        system.send(bag.as_raw(), Fill(Some(64)));
     }
-}
\ No newline at end of file
+    system.process_all_messages();
+    let (kept, killed): (Vec<_>, Vec<_>) = bags.iter().enumerate().partition(|&(i, _)| i % 10 == 0);
+    for &(_, &bag) in &killed {
+        system.send(bag.as_raw(), Fill(None));
+    }
+    // shrinking leaves an instance in its bigger slot
+    for &(_, &bag) in &kept {
+        system.send(bag.as_raw(), Fill(Some(0)));
+    }
+    system.process_all_messages();
+
+    let occupancy = system.occupancy::<Bag>();
+    assert_eq!(occupancy.instances, 10);
+    assert_eq!(occupancy.ids, 100);
+    assert_eq!(occupancy.free_ids, 90);
+    assert_eq!(occupancy.oversized_instances, 10);
+    assert!(occupancy.slot_bytes > occupancy.state_bytes);
+
+    assert_eq!(system.compact::<Bag>(), 10);
+    let compacted = system.occupancy::<Bag>();
+    assert_eq!(compacted.instances, 10);
+    assert_eq!(compacted.oversized_instances, 0);
+    assert_eq!(compacted.state_bytes, occupancy.state_bytes);
+    assert!(compacted.fragmentation() < occupancy.fragmentation());
+    assert_eq!(system.compact::<Bag>(), 0);
+
+    for &(_, &bag) in &kept {
+        let instance = system.instance::<Bag>(bag).unwrap();
+        assert_eq!(instance.id, bag);
+        assert!(instance.items.is_empty());
+        system.send(bag.as_raw(), Fill(Some(3)));
+    }
+    for &(_, &bag) in &killed {
+        assert!(system.instance::<Bag>(bag).is_none());
+    }
+    system.process_all_messages();
+    for &(_, &bag) in &kept {
+        assert_eq!(system.instance::<Bag>(bag).unwrap().items.len(), 3);
+    }
+}
+
+#[test]
+fn test_memory_report_grows_with_instances_and_messages() {
+    use crate::test_support::{local_system, Add, Counter, OtherCounter};
+
+    let mut system = local_system();
+    system.register::<Counter>();
+    system.add_handler::<Counter, _, _>(
+        |&Add(n), counter, _| {
+            counter.count += n;
+            Fate::Live
+        },
+        false,
+    );
+    system.register::<OtherCounter>();
+    let counter_type = system.actor_registry.get::<Counter>().as_usize() as u16;
+    let other_type = system.actor_registry.get::<OtherCounter>().as_usize() as u16;
+    // the report also contains the system services
+    let of = |report: &MemoryReport, type_id: u16| report.classes.iter().find(|memory| memory.type_id == type_id).unwrap().clone();
+    let empty = system.memory_report();
+    assert_eq!(of(&empty, counter_type).instances, 0);
+    assert_eq!(of(&empty, counter_type).used_bytes(), 0);
+
+    let counters = system.spawn_many((0..1000).map(Counter::new));
+    system.spawn_many((0..10).map(OtherCounter::new));
+    let spawned = system.memory_report();
+    let counter_memory = of(&spawned, counter_type);
+    assert_eq!(counter_memory.instances, 1000);
+    assert_eq!(counter_memory.instance_bytes_used, 1000 * ::std::mem::size_of::<Counter>());
+    assert!(counter_memory.instance_bytes_allocated >= counter_memory.instance_bytes_used);
+    assert!(counter_memory.id_bytes_allocated > of(&empty, counter_type).id_bytes_allocated);
+    assert_eq!(of(&spawned, other_type).instances, 10);
+    assert!(spawned.allocated_bytes() > empty.allocated_bytes());
+    assert_eq!(spawned.largest_first()[0].type_id, counter_memory.type_id);
+
+    for &counter in &counters[..500] {
+        system.send(counter.as_raw(), Add(1));
+    }
+    let queued = system.memory_report();
+    assert_eq!(of(&queued, counter_type).inbox_messages, 500);
+    assert!(of(&queued, counter_type).inbox_bytes_used >= 500 * ::std::mem::size_of::<Add>());
+    assert!(of(&queued, counter_type).inbox_bytes_allocated >= of(&queued, counter_type).inbox_bytes_used);
+    assert!(queued.used_bytes() > spawned.used_bytes());
+    assert_eq!(of(&queued, other_type).inbox_messages, 0);
+
+    system.process_all_messages();
+    let handled = system.memory_report();
+    assert_eq!(of(&handled, counter_type).inbox_messages, 0);
+    assert_eq!(of(&handled, counter_type).inbox_bytes_used, 0);
+    assert_eq!(handled.used_bytes(), spawned.used_bytes());
+}
@@ -0,0 +1,285 @@
+use crate::inspector::SystemInspection;
+use crate::metrics::{MetricKind, MetricsWriter};
+use crate::time::now_ms;
+use crate::topology::json_string;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+/// How long we wait for a request and for writing the response before dropping a connection
+const REQUEST_TIMEOUT_MS: f64 = 200.0;
+/// Requests whose first line is longer are rejected
+const MAX_REQUEST_LINE_BYTES: usize = 8 * 1024;
+
+/// A tiny HTTP endpoint for operators, polled from the actor system's turn loop.
+/// Serves the inspection of the system as JSON at `/inspect`
+/// and metrics in the Prometheus text format at `/metrics`.
+/// Never blocks the turn: requests and responses are read and written a bit with every poll.
+pub(crate) struct AdminEndpoint {
+    listener: TcpListener,
+    connections: Vec<Connection>,
+    /// Message counts per type and when they were taken, at the last scrape
+    last_scrape: Option<(f64, HashMap<String, usize>)>,
+}
+
+/// A connection whose request wasn't answered completely yet
+struct Connection {
+    stream: TcpStream,
+    accepted_ms: f64,
+    /// What was read of the request so far
+    request: Vec<u8>,
+    /// What is left to write of the response, once the request line was read
+    response: Option<Vec<u8>>,
+}
+
+/// Everything the endpoint serves, collected by the `ActorSystem` for each request
+pub(crate) struct AdminData {
+    pub inspection: SystemInspection,
+    /// See `ActorSystem::metrics`
+    pub metrics: String,
+    pub message_counts: HashMap<String, usize>,
+}
+
+impl AdminEndpoint {
+    pub fn bind<A: ToSocketAddrs>(address: A) -> ::std::io::Result<AdminEndpoint> {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        Ok(AdminEndpoint {
+            listener,
+            connections: Vec::new(),
+            last_scrape: None,
+        })
+    }
+
+    /// Accept new connections and continue answering all pending requests,
+    /// collecting the data to serve only if a request is ready to be answered
+    pub fn poll<F: FnMut() -> AdminData>(&mut self, mut collect: F) {
+        self.accept();
+        let now = now_ms();
+        let mut data = None;
+        for mut connection in ::std::mem::replace(&mut self.connections, Vec::new()) {
+            match self.advance(&mut connection, &mut data, &mut collect) {
+                Ok(true) => {}
+                Ok(false) => {
+                    if now - connection.accepted_ms < REQUEST_TIMEOUT_MS {
+                        self.connections.push(connection);
+                    }
+                }
+                Err(e) => println!("Error while answering admin request: {}", e),
+            }
+        }
+    }
+
+    fn accept(&mut self) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(e) = stream.set_nonblocking(true) {
+                        println!("Error while accepting admin connection: {}", e);
+                        continue;
+                    }
+                    self.connections.push(Connection {
+                        stream,
+                        accepted_ms: now_ms(),
+                        request: Vec::new(),
+                        response: None,
+                    });
+                }
+                Err(ref e) if e.kind() == ::std::io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    println!("Error while accepting admin connection: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Read what arrived of the request and write what fits of the response,
+    /// returns whether the whole response was written
+    fn advance<F: FnMut() -> AdminData>(
+        &mut self,
+        connection: &mut Connection,
+        data: &mut Option<AdminData>,
+        collect: &mut F,
+    ) -> ::std::io::Result<bool> {
+        if connection.response.is_none() {
+            let mut buffer = [0u8; 1024];
+            while !connection.request.contains(&b'\n') {
+                match connection.stream.read(&mut buffer) {
+                    Ok(0) => {
+                        return Err(::std::io::Error::new(
+                            ::std::io::ErrorKind::UnexpectedEof,
+                            "Connection closed before the request was complete",
+                        ))
+                    }
+                    Ok(n_read) => connection.request.extend_from_slice(&buffer[..n_read]),
+                    Err(ref e) if e.kind() == ::std::io::ErrorKind::WouldBlock => break,
+                    Err(e) => return Err(e),
+                }
+                if connection.request.len() > MAX_REQUEST_LINE_BYTES {
+                    return Err(::std::io::Error::new(
+                        ::std::io::ErrorKind::InvalidData,
+                        "Request line too long",
+                    ));
+                }
+            }
+            let line_end = match connection.request.iter().position(|&byte| byte == b'\n') {
+                Some(line_end) => line_end,
+                None => return Ok(false),
+            };
+            if data.is_none() {
+                *data = Some(collect());
+            }
+            let request_line = String::from_utf8_lossy(&connection.request[..line_end]).into_owned();
+            connection.response = Some(self.respond(&request_line, data.as_ref().unwrap()));
+        }
+
+        let response = connection.response.as_mut().unwrap();
+        while !response.is_empty() {
+            match connection.stream.write(response) {
+                Ok(0) => {
+                    return Err(::std::io::Error::new(
+                        ::std::io::ErrorKind::WriteZero,
+                        "Connection closed before the response was written",
+                    ))
+                }
+                Ok(n_written) => {
+                    response.drain(..n_written);
+                }
+                Err(ref e) if e.kind() == ::std::io::ErrorKind::WouldBlock => return Ok(false),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(true)
+    }
+
+    fn respond(&mut self, request_line: &str, data: &AdminData) -> Vec<u8> {
+        let path = request_line.split_whitespace().nth(1).unwrap_or("");
+
+        let (status, content_type, body) = match path {
+            "/inspect" => ("200 OK", "application/json", inspection_json(&data.inspection)),
+            "/metrics" => (
+                "200 OK",
+                "text/plain; version=0.0.4",
+                self.metrics(data),
+            ),
+            _ => ("404 Not Found", "text/plain", "Try /inspect or /metrics\n".to_owned()),
+        };
+
+        format!(
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            content_type,
+            body.len(),
+            body
+        )
+        .into_bytes()
+    }
+
+    /// The metrics of the system, plus message rates since the last scrape
+    fn metrics(&mut self, data: &AdminData) -> String {
+        let mut rates = MetricsWriter::new();
+        let now = now_ms();
+        if let Some((last_ms, ref last_counts)) = self.last_scrape {
+            let elapsed_s = ((now - last_ms) / 1000.0).max(::std::f64::EPSILON);
+            rates.metric(
+                "kay_messages_per_second",
+                MetricKind::Gauge,
+                "Messages handled per second per message type, since the last scrape",
+                data.message_counts
+                    .iter()
+                    .map(|(message_type, &count)| {
+                        let previous = last_counts.get(message_type).cloned().unwrap_or(0);
+                        let rate = count.saturating_sub(previous) as f64 / elapsed_s;
+                        (vec![("type", message_type.clone())], rate)
+                    })
+                    .collect(),
+            );
+        }
+        self.last_scrape = Some((now, data.message_counts.clone()));
+        data.metrics.clone() + &rates.finish()
+    }
+}
+
+fn inspection_json(inspection: &SystemInspection) -> String {
+    let classes = inspection
+        .classes
+        .iter()
+        .map(|class| {
+            format!(
+                "{{\"type_id\":{},\"type_name\":{},\"instances\":{},\"state_bytes\":{},\"queued_messages\":{},\"queued_bytes\":{}}}",
+                class.type_id,
+                json_string(&class.type_name),
+                class.instances,
+                class.state_bytes,
+                class.queued_messages,
+                class.queued_bytes
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let networking = &inspection.networking;
+    let peer_n_turns = networking
+        .peer_n_turns
+        .iter()
+        .map(|(machine_id, n_turns)| format!("\"{}\":{}", machine_id.0, n_turns))
+        .collect::<Vec<_>>();
+
+    format!(
+        "{{\"classes\":[{}],\"networking\":{{\"machine_id\":{},\"n_turns\":{},\"peer_n_turns\":{{{}}},\"host\":{},\"paused\":{}}},\"panic_happened\":{}}}",
+        classes.join(","),
+        networking.machine_id.0,
+        networking.n_turns,
+        peer_n_turns.join(","),
+        networking.host.0,
+        networking.paused,
+        inspection.panic_happened
+    )
+}
+
+#[test]
+fn test_requests_are_answered_without_blocking() {
+    use crate::id::MachineID;
+    use crate::inspector::NetworkingInspection;
+
+    let mut endpoint = AdminEndpoint::bind("127.0.0.1:0").unwrap();
+    let mut client = TcpStream::connect(endpoint.listener.local_addr().unwrap()).unwrap();
+    let mut n_collected = 0;
+    let mut collect = || {
+        n_collected += 1;
+        AdminData {
+            inspection: SystemInspection {
+                classes: Vec::new(),
+                networking: NetworkingInspection {
+                    machine_id: MachineID(0),
+                    n_turns: 0,
+                    peer_n_turns: HashMap::new(),
+                    host: MachineID(0),
+                    paused: false,
+                },
+                panic_happened: false,
+                held_packet: None,
+            },
+            metrics: String::new(),
+            message_counts: HashMap::new(),
+        }
+    };
+
+    // half a request line doesn't make the poll wait for the rest
+    client.write_all(b"GET /insp").unwrap();
+    ::std::thread::sleep(::std::time::Duration::from_millis(20));
+    endpoint.poll(&mut collect);
+    assert_eq!(endpoint.connections.len(), 1);
+
+    client.write_all(b"ect HTTP/1.1\r\n\r\n").unwrap();
+    ::std::thread::sleep(::std::time::Duration::from_millis(20));
+    endpoint.poll(&mut collect);
+    assert!(endpoint.connections.is_empty());
+    drop(collect);
+    assert_eq!(n_collected, 1);
+
+    let mut response = String::new();
+    client.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.ends_with("\"panic_happened\":false}"));
+}
@@ -0,0 +1,146 @@
+use crate::id::RawID;
+use crate::messaging::{Correlation, Message, Packet};
+use std::collections::HashMap;
+
+/// Messages of one type collected while draining an inbox, for a batched handler
+/// (see `ActorSystem::add_batch_handler`)
+pub trait MessageBatch {
+    /// Copy the message out of its packet, which is only valid until the next packet is dequeued
+    fn collect(&mut self, packet_ptr: *const ());
+    fn is_empty(&self) -> bool;
+    /// All recipients in the order they first got a message, each with a pointer
+    /// to the `Vec` of its messages, valid until the next `clear`.
+    /// Messages that are part of different queries are separate batches with their correlation.
+    fn recipients(&self) -> Vec<(RawID, Correlation, *const ())>;
+    fn clear(&mut self);
+}
+
+pub struct TypedBatch<M: Message> {
+    recipients: Vec<(RawID, Correlation)>,
+    messages: HashMap<(RawID, Correlation), Vec<M>>,
+}
+
+impl<M: Message> TypedBatch<M> {
+    pub fn new() -> Self {
+        TypedBatch {
+            recipients: Vec::new(),
+            messages: HashMap::new(),
+        }
+    }
+}
+
+impl<M: Message> MessageBatch for TypedBatch<M> {
+    fn collect(&mut self, packet_ptr: *const ()) {
+        let packet = unsafe { &*(packet_ptr as *const Packet<M>) };
+        let recipients = &mut self.recipients;
+        let key = (packet.recipient_id, packet.correlation);
+        self.messages
+            .entry(key)
+            .or_insert_with(|| {
+                recipients.push(key);
+                Vec::new()
+            })
+            .push(packet.message.clone());
+    }
+
+    fn is_empty(&self) -> bool {
+        self.recipients.is_empty()
+    }
+
+    fn recipients(&self) -> Vec<(RawID, Correlation, *const ())> {
+        self.recipients
+            .iter()
+            .map(|key| (key.0, key.1, &self.messages[key] as *const Vec<M> as *const ()))
+            .collect()
+    }
+
+    fn clear(&mut self) {
+        self.recipients.clear();
+        self.messages.clear();
+    }
+}
+
+#[test]
+fn test_batches_are_handled_per_correlation() {
+    use crate::id::TypedID;
+    use crate::messaging::Fate;
+    use crate::query::QueryStatus;
+    use crate::test_support::{local_system, Add, Counter};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    let batch_sizes = Rc::new(RefCell::new(Vec::new()));
+    let handled_sizes = Rc::clone(&batch_sizes);
+    let mut system = local_system();
+    system.register::<Counter>();
+    system.add_batch_handler::<Counter, _, _>(
+        move |adds: &[Add], counter, world| {
+            handled_sizes.borrow_mut().push(adds.len());
+            counter.count += adds.iter().map(|add| add.0).sum::<u32>();
+            if let Some(asker) = world.asker() {
+                asker.reply(counter.count, world);
+            }
+            Fate::Live
+        },
+        false,
+    );
+    system.register_query_reply::<u32>();
+    let counter = system.spawn_many(vec![Counter::new(0)])[0].as_raw();
+
+    let mut world = system.world();
+    world.send(counter, Add(1));
+    let first = world.query::<u32>(counter, Add(3), Duration::from_secs(10));
+    world.send(counter, Add(2));
+    let second = world.query::<u32>(counter, Add(4), Duration::from_secs(10));
+    system.process_all_messages();
+
+    assert_eq!(*batch_sizes.borrow(), vec![2, 1, 1]);
+    for (handle, expected) in vec![(first, 6), (second, 10)] {
+        match handle.status() {
+            QueryStatus::Replied(count) => assert_eq!(count, expected),
+            _ => panic!("Query should have been answered"),
+        }
+    }
+}
+
+#[test]
+fn test_batched_messages_follow_migrated_instances() {
+    use crate::id::{MachineID, TypedID};
+    use crate::messaging::Fate;
+    use crate::migration::InstanceMigrated;
+    use crate::query::QueryStatus;
+    use crate::test_support::{local_system, Add, Counter};
+    use std::time::Duration;
+
+    let mut system = local_system();
+    system.register::<Counter>();
+    system.add_batch_handler::<Counter, _, _>(
+        |adds: &[Add], counter, world| {
+            counter.count += adds.iter().map(|add| add.0).sum::<u32>();
+            if let Some(asker) = world.asker() {
+                asker.reply(counter.count, world);
+            }
+            Fate::Live
+        },
+        false,
+    );
+    system.register_query_reply::<u32>();
+    let counters = system.spawn_many(vec![Counter::new(0), Counter::new(7)]);
+    let (old_id, new_id) = (counters[0].as_raw(), counters[1].as_raw());
+
+    // messages to a migrating instance are held back, then forwarded once it arrived
+    assert!(system.migrate(old_id, MachineID(1)));
+    system.send(old_id, Add(1));
+    let query = system.world().query::<u32>(old_id, Add(2), Duration::from_secs(10));
+    system.process_all_messages();
+    let all_services = system.services_id(MachineID(0)).global_broadcast();
+    system.send(all_services, InstanceMigrated { old_id, new_id });
+    system.process_all_messages();
+
+    assert_eq!(system.instance::<Counter>(counters[1]).unwrap().count, 10);
+    match query.status() {
+        QueryStatus::Replied(count) => assert_eq!(count, 10),
+        _ => panic!("Forwarded query should have been answered"),
+    }
+}
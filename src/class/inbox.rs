@@ -1,24 +1,257 @@
 use chunky;
 use compact::Compact;
+use super::spill::Spill;
+use crate::id::RawID;
 use crate::messaging::{Message, Packet};
 use crate::type_registry::{ShortTypeId, TypeRegistry};
 use crate::tuning::Tuning;
+use ::std::collections::{HashMap, HashSet};
 use ::std::rc::Rc;
 
 pub struct Inbox {
     queue: chunky::Queue,
+    /// Bytes of all queued messages (only counting messages put since the inbox was created)
+    queued_bytes: usize,
+    coalescing: Option<Coalescing>,
+    /// Separate inboxes for the messages of each turn phase after the first
+    /// (see `ActorSystem::define_turn_phases`), this inbox holds those of the first
+    phase_lanes: Vec<Inbox>,
+    /// The turn phases of message types that are only handled in one phase
+    message_phases: HashMap<ShortTypeId, usize>,
+    /// The current turn phase, whose messages are drained
+    phase: usize,
+    cancellation: Cancellation,
+    /// Messages put while the inbox held `spill_bytes` or more, see `Tuning::inbox_spill_bytes`
+    spill: Option<Spill>,
+    spill_bytes: Option<usize>,
+    /// See `Tuning::inbox_overflow_bytes`
+    overflow_bytes: Option<usize>,
+    /// Did the queued messages grow beyond `overflow_bytes` or start being spilled
+    /// since the last `take_overflowed`?
+    overflowed: bool,
+    /// Names the spill file
+    name: String,
+}
+
+/// Remembers the newest queued packet for each recipient of coalesced message types,
+/// so older ones are skipped when draining
+struct Coalescing {
+    message_types: HashSet<ShortTypeId>,
+    /// The number (in the order they were put) of the newest packet per recipient and type
+    newest: HashMap<(RawID, ShortTypeId), usize>,
+    n_put: usize,
+    n_taken: usize,
+}
+
+impl Coalescing {
+    fn put(&mut self, message_type: ShortTypeId, recipient_id: RawID) {
+        if self.message_types.contains(&message_type) {
+            self.newest.insert((recipient_id, message_type), self.n_put);
+        }
+        self.n_put += 1;
+    }
+
+    /// Is the packet that was just taken out superseded by a newer one?
+    fn take_is_outdated(&mut self, message_type: ShortTypeId, recipient_id: RawID) -> bool {
+        let number = self.n_taken;
+        self.n_taken += 1;
+        if !self.message_types.contains(&message_type) {
+            return false;
+        }
+        match self.newest.get(&(recipient_id, message_type)) {
+            Some(&newest) if newest != number => true,
+            Some(_) => {
+                self.newest.remove(&(recipient_id, message_type));
+                false
+            }
+            // queued before coalescing was enabled
+            None => false,
+        }
+    }
+}
+
+/// Remembers the cancellation keys of queued packets sent with `ActorSystem::send_cancellable`,
+/// so cancelled ones are skipped when draining
+struct Cancellation {
+    /// The key of each keyed packet by its number (in the order they were put)
+    keys: HashMap<usize, u64>,
+    /// The numbers of cancelled packets
+    cancelled: HashSet<usize>,
+    n_put: usize,
+    n_taken: usize,
+}
+
+impl Cancellation {
+    fn key_last(&mut self, key: u64) {
+        self.keys.insert(self.n_put - 1, key);
+    }
+
+    /// Returns how many queued packets were cancelled
+    fn cancel(&mut self, key: u64) -> usize {
+        let numbers = self
+            .keys
+            .iter()
+            .filter(|&(_, &packet_key)| packet_key == key)
+            .map(|(&number, _)| number)
+            .collect::<Vec<_>>();
+        for number in &numbers {
+            self.keys.remove(number);
+            self.cancelled.insert(*number);
+        }
+        numbers.len()
+    }
+
+    /// Was the packet that was just taken out cancelled?
+    fn take_is_cancelled(&mut self) -> bool {
+        let number = self.n_taken;
+        self.n_taken += 1;
+        if self.keys.is_empty() && self.cancelled.is_empty() {
+            return false;
+        }
+        self.keys.remove(&number);
+        self.cancelled.remove(&number)
+    }
 }
 
 impl Inbox {
-    pub fn new(ident: &chunky::Ident, storage: Rc<dyn chunky::ChunkStorage>, tuning: &Tuning) -> Self {
+    pub fn new(ident: &chunky::Ident, storage: Rc<dyn chunky::ChunkStorage>, tuning: &Tuning, n_phases: usize) -> Self {
+        let queue = chunky::Queue::new(ident, tuning.inbox_queue_chunk_size, Rc::clone(&storage));
+        // a queue restored from a snapshot already holds messages, cancellable
+        // ones put from now on are numbered after them (spilled ones follow in `restore_spilled`)
+        let n_restored = queue.len();
         Inbox {
-            queue: chunky::Queue::new(ident, tuning.inbox_queue_chunk_size, storage),
+            phase_lanes: (1..n_phases)
+                .map(|phase| Inbox::new(&ident.sub(format!("p{}", phase)), Rc::clone(&storage), tuning, 1))
+                .collect(),
+            queue,
+            queued_bytes: 0,
+            coalescing: None,
+            message_phases: HashMap::new(),
+            phase: 0,
+            cancellation: Cancellation {
+                keys: HashMap::new(),
+                cancelled: HashSet::new(),
+                n_put: n_restored,
+                n_taken: 0,
+            },
+            spill: None,
+            spill_bytes: tuning.inbox_spill_bytes,
+            overflow_bytes: tuning.inbox_overflow_bytes,
+            overflowed: false,
+            name: ident.0.clone(),
+        }
+    }
+
+    /// Should a message of `size` bytes be spilled to disk? Once messages were spilled,
+    /// all later ones are as well until they were read back, to keep them in order.
+    fn spills(&mut self, size: usize) -> bool {
+        if self.spill.as_ref().map_or(false, |spill| spill.len() > 0) {
+            return true;
+        }
+        match self.spill_bytes {
+            Some(spill_bytes) if self.queued_bytes + size > spill_bytes => {
+                if self.spill.is_none() {
+                    match Spill::create(&self.name) {
+                        Ok(spill) => self.spill = Some(spill),
+                        Err(e) => {
+                            println!("Couldn't spill inbox {} to disk, keeping it in memory: {}", self.name, e);
+                            self.spill_bytes = None;
+                        }
+                    }
+                }
+                self.overflowed |= self.spill.is_some();
+                self.spill.is_some()
+            }
+            _ => false,
+        }
+    }
+
+    /// Count `size` more bytes of messages queued in memory
+    fn add_queued_bytes(&mut self, size: usize) {
+        if let Some(overflow_bytes) = self.overflow_bytes {
+            self.overflowed |= self.queued_bytes <= overflow_bytes && self.queued_bytes + size > overflow_bytes;
+        }
+        self.queued_bytes += size;
+    }
+
+    /// Did the messages of any turn phase grow beyond `Tuning::inbox_overflow_bytes`
+    /// or start being spilled to disk since this was last called?
+    pub fn take_overflowed(&mut self) -> bool {
+        let overflowed = self.phase_lanes.iter_mut().fold(self.overflowed, |overflowed, lane| lane.take_overflowed() || overflowed);
+        self.overflowed = false;
+        overflowed
+    }
+
+    /// Only handle messages of this type in the given turn phase. Messages of other types
+    /// are handled in the phase they arrive in (during the first phase if they arrive between turns).
+    pub fn assign_phase(&mut self, message_type: ShortTypeId, phase: usize) {
+        assert!(phase <= self.phase_lanes.len(), "Turn phase doesn't exist");
+        self.message_phases.insert(message_type, phase);
+    }
+
+    /// Only drain the messages of this turn phase from now on. Inboxes with fewer turn phases
+    /// (like those of classes only handling system messages) treat later phases like their last.
+    pub fn set_phase(&mut self, phase: usize) {
+        self.phase = phase.min(self.phase_lanes.len());
+    }
+
+    /// The lane of a message put into the inbox now, `None` for this inbox itself
+    fn lane_for(&mut self, message_type: ShortTypeId) -> Option<&mut Inbox> {
+        let phase = self.message_phases.get(&message_type).cloned().unwrap_or(self.phase);
+        if phase == 0 {
+            None
+        } else {
+            Some(&mut self.phase_lanes[phase - 1])
         }
     }
 
+    /// Only handle the newest queued message of this type for each recipient
+    pub fn coalesce(&mut self, message_type: ShortTypeId) {
+        for lane in &mut self.phase_lanes {
+            lane.coalesce(message_type);
+        }
+        let n_queued = self.queue.len() + self.spill.as_ref().map_or(0, Spill::len);
+        self.coalescing
+            .get_or_insert_with(|| Coalescing {
+                message_types: HashSet::new(),
+                newest: HashMap::new(),
+                n_put: n_queued,
+                n_taken: 0,
+            })
+            .message_types
+            .insert(message_type);
+    }
+
     pub fn put<M: Message>(&mut self, mut packet: Packet<M>, message_registry: &TypeRegistry) {
+        if let Some(lane) = self.lane_for(message_registry.get::<M>()) {
+            return lane.put(packet, message_registry);
+        }
         let packet_size = packet.total_size_bytes();
         let total_size = ::std::mem::size_of::<ShortTypeId>() + packet_size;
+        self.cancellation.n_put += 1;
+
+        if self.spills(total_size) {
+            let message_type = message_registry.get::<M>();
+            if let Some(ref mut coalescing) = self.coalescing {
+                coalescing.put(message_type, packet.recipient_id);
+            }
+            // 8-byte aligned like chunks
+            let mut buffer = vec![0u64; (total_size + 7) / 8];
+            #[allow(clippy::cast_ptr_alignment)]
+            unsafe {
+                let buffer_ptr = buffer.as_mut_ptr() as *mut u8;
+                *(buffer_ptr as *mut ShortTypeId) = message_type;
+                Compact::compact_behind(
+                    &mut packet,
+                    buffer_ptr.add(::std::mem::size_of::<ShortTypeId>()) as *mut Packet<M>,
+                );
+                ::std::mem::forget(packet);
+                let bytes = ::std::slice::from_raw_parts(buffer_ptr, total_size);
+                self.spill.as_mut().expect("Should be spilling").write(bytes);
+            }
+            return;
+        }
+        self.add_queued_bytes(total_size);
 
         #[allow(clippy::cast_ptr_alignment)]
         unsafe {
@@ -26,7 +259,11 @@ impl Inbox {
             let queue_ptr = self.queue.enqueue(total_size);
 
             // Write message type
-            *(queue_ptr as *mut ShortTypeId) = message_registry.get::<M>();
+            let message_type = message_registry.get::<M>();
+            *(queue_ptr as *mut ShortTypeId) = message_type;
+            if let Some(ref mut coalescing) = self.coalescing {
+                coalescing.put(message_type, packet.recipient_id);
+            }
             let payload_ptr = (queue_ptr as *mut u8).offset(::std::mem::size_of::<ShortTypeId>() as isize);
 
             // Write the packet into the queue
@@ -35,11 +272,68 @@ impl Inbox {
         }
     }
 
+    /// Like `put`, but the packet can be cancelled with `cancel` until it is drained
+    pub fn put_cancellable<M: Message>(&mut self, packet: Packet<M>, message_registry: &TypeRegistry, key: u64) {
+        if let Some(lane) = self.lane_for(message_registry.get::<M>()) {
+            return lane.put_cancellable(packet, message_registry, key);
+        }
+        self.put(packet, message_registry);
+        self.cancellation.key_last(key);
+    }
+
+    /// Skip all queued packets put with `key` (in all turn phases) when draining,
+    /// returns how many were cancelled
+    pub fn cancel(&mut self, key: u64) -> usize {
+        self.cancellation.cancel(key) + self.phase_lanes.iter_mut().map(|lane| lane.cancel(key)).sum::<usize>()
+    }
+
+    /// The number of queued messages of all turn phases
     pub fn len(&self) -> usize {
         self.queue.len()
+            + self.spill.as_ref().map_or(0, Spill::len)
+            + self.phase_lanes.iter().map(Inbox::len).sum::<usize>()
+    }
+
+    /// The bytes of all messages queued in memory
+    pub fn queued_bytes(&self) -> usize {
+        self.queued_bytes + self.phase_lanes.iter().map(Inbox::queued_bytes).sum::<usize>()
+    }
+
+    /// The bytes of all messages spilled to disk that weren't handled yet
+    pub fn spilled_bytes(&self) -> usize {
+        self.spill.as_ref().map_or(0, Spill::bytes) + self.phase_lanes.iter().map(Inbox::spilled_bytes).sum::<usize>()
+    }
+
+    /// The bytes of the chunks needed for all queued messages
+    pub fn allocated_bytes(&self, tuning: &Tuning) -> usize {
+        let chunk_size = tuning.inbox_queue_chunk_size;
+        (self.queued_bytes + chunk_size - 1) / chunk_size * chunk_size
+            + self.phase_lanes.iter().map(|lane| lane.allocated_bytes(tuning)).sum::<usize>()
     }
 
     pub fn put_raw(&mut self, buf: &[u8]) {
+        #[allow(clippy::cast_ptr_alignment)]
+        let message_type = unsafe { *(buf.as_ptr() as *const ShortTypeId) };
+        if let Some(lane) = self.lane_for(message_type) {
+            return lane.put_raw(buf);
+        }
+        self.cancellation.n_put += 1;
+        let spills = self.spills(buf.len());
+        if !spills {
+            self.add_queued_bytes(buf.len());
+        }
+        if let Some(ref mut coalescing) = self.coalescing {
+            #[allow(clippy::cast_ptr_alignment)]
+            unsafe {
+                let message_type = *(buf.as_ptr() as *const ShortTypeId);
+                let recipient_id = *(buf.as_ptr().add(::std::mem::size_of::<ShortTypeId>()) as *const RawID);
+                coalescing.put(message_type, recipient_id);
+            }
+        }
+        if spills {
+            self.spill.as_mut().expect("Should be spilling").write(buf);
+            return;
+        }
         unsafe {
             let queue_ptr = self.queue.enqueue(buf.len());
 
@@ -47,17 +341,85 @@ impl Inbox {
         }
     }
 
+    /// The messages of all turn phases that are spilled to disk, by the ident of their inbox
+    /// (see `spilled_ident`), to be written to snapshots
+    pub fn spilled_messages(&self) -> ::std::io::Result<Vec<(String, Vec<u8>)>> {
+        let mut spilled = Vec::new();
+        if let Some(ref spill) = self.spill {
+            spilled.push((spilled_ident(&self.name), spill.unread()?));
+        }
+        for lane in &self.phase_lanes {
+            spilled.extend(lane.spilled_messages()?);
+        }
+        Ok(spilled)
+    }
+
+    /// Spill the messages of all turn phases that were spilled in a loaded snapshot again,
+    /// behind those in the queue. `take_restored` gives the data saved by `spilled_messages`.
+    pub fn restore_spilled<F: Fn(&str) -> Option<Vec<u8>>>(&mut self, take_restored: &F) -> ::std::io::Result<()> {
+        if let Some(stored) = take_restored(&spilled_ident(&self.name)) {
+            let messages = Spill::split_stored(&stored)?;
+            if !messages.is_empty() {
+                let mut spill = match self.spill.take() {
+                    Some(spill) => spill,
+                    None => Spill::create(&self.name)?,
+                };
+                for message in messages {
+                    spill.write(message);
+                    self.cancellation.n_put += 1;
+                    if let Some(ref mut coalescing) = self.coalescing {
+                        coalescing.n_put += 1;
+                    }
+                }
+                self.spill = Some(spill);
+            }
+        }
+        for lane in &mut self.phase_lanes {
+            lane.restore_spilled(take_restored)?;
+        }
+        Ok(())
+    }
+
+    /// Take out all messages of the current turn phase that were queued so far
     pub fn drain(&mut self) -> InboxIterator {
+        if self.phase > 0 {
+            return self.phase_lanes[self.phase - 1].drain();
+        }
         InboxIterator {
             n_messages_to_read: self.queue.len(),
+            n_messages: self.queue.len(),
+            n_spilled_to_read: self.spill.as_ref().map_or(0, Spill::len),
+            spill: self.spill.as_mut(),
+            spilled_packet: Vec::new(),
             queue: &mut self.queue,
+            bytes_to_read: self.queued_bytes,
+            queued_bytes: &mut self.queued_bytes,
+            coalescing: self.coalescing.as_mut(),
+            cancellation: &mut self.cancellation,
         }
     }
 }
 
+/// The ident under which the spilled messages of the inbox `name` are part of snapshots
+fn spilled_ident(name: &str) -> String {
+    format!("{}_spill", name)
+}
+
 pub struct InboxIterator<'a> {
     queue: &'a mut chunky::Queue,
     n_messages_to_read: usize,
+    n_messages: usize,
+    /// Spilled messages are newer than those in the queue, so they are read after them
+    spill: Option<&'a mut Spill>,
+    n_spilled_to_read: usize,
+    /// The spilled packet taken out last, which its pointer points into until the next one
+    /// is taken (those who keep a packet for longer need to copy it)
+    spilled_packet: Vec<u64>,
+    queued_bytes: &'a mut usize,
+    /// Bytes of the messages that were queued when draining started
+    bytes_to_read: usize,
+    coalescing: Option<&'a mut Coalescing>,
+    cancellation: &'a mut Cancellation,
 }
 
 pub struct DispatchablePacket {
@@ -69,29 +431,187 @@ impl<'a> Iterator for InboxIterator<'a> {
     type Item = DispatchablePacket;
 
     fn next(&mut self) -> Option<DispatchablePacket> {
-        if self.n_messages_to_read == 0 {
-            None
-        } else {
+        while self.n_messages_to_read > 0 || self.n_spilled_to_read > 0 {
             #[allow(clippy::cast_ptr_alignment)]
             unsafe {
-                let ptr = self
-                    .queue
-                    .dequeue()
-                    .expect("should have something left for sure");
+                let ptr = if self.n_messages_to_read > 0 {
+                    self.n_messages_to_read -= 1;
+                    self.queue
+                        .dequeue()
+                        .expect("should have something left for sure")
+                } else {
+                    self.n_spilled_to_read -= 1;
+                    self.spilled_packet = self.spill.as_mut().expect("should have spilled messages").read();
+                    self.spilled_packet.as_ptr() as *const u8
+                };
                 let message_type = *(ptr as *mut ShortTypeId);
                 let payload_ptr = (ptr as *mut u8).offset(::std::mem::size_of::<ShortTypeId>() as isize);
-                self.n_messages_to_read -= 1;
-                Some(DispatchablePacket {
+                let cancelled = self.cancellation.take_is_cancelled();
+                if let Some(ref mut coalescing) = self.coalescing {
+                    let recipient_id = (*(payload_ptr as *const Packet<()>)).recipient_id;
+                    if coalescing.take_is_outdated(message_type, recipient_id) {
+                        continue;
+                    }
+                }
+                if cancelled {
+                    continue;
+                }
+                return Some(DispatchablePacket {
                     message_type,
                     packet_ptr: payload_ptr as *const (),
-                })
+                });
             }
         }
+        None
     }
 }
 
 impl<'a> Drop for InboxIterator<'a> {
     fn drop(&mut self) {
         unsafe { self.queue.drop_old_chunks() };
+        // when stopping early (while debugging), the read bytes are only estimated
+        let n_read = self.n_messages - self.n_messages_to_read;
+        let bytes_read = if n_read == self.n_messages {
+            self.bytes_to_read
+        } else {
+            self.bytes_to_read * n_read / self.n_messages
+        };
+        *self.queued_bytes = self.queued_bytes.saturating_sub(bytes_read);
     }
 }
+
+#[test]
+fn test_inbox_coalescing_and_cancellation() {
+    use crate::messaging::Correlation;
+    let mut registry = TypeRegistry::new();
+    let message_type = registry.register_new::<u32>();
+    let mut inbox = Inbox::new(&chunky::Ident::from("test_inbox"), Rc::new(chunky::HeapStorage), &Tuning::default(), 1);
+    inbox.coalesce(message_type);
+    let recipient = |instance_id: u32| RawID::new(ShortTypeId::new(1).unwrap(), instance_id, crate::id::MachineID(0), 0);
+    let packet = |instance_id: u32, message: u32| Packet {
+        recipient_id: recipient(instance_id),
+        correlation: Correlation::none(),
+        message,
+    };
+
+    inbox.put(packet(0, 1), &registry);
+    inbox.put_cancellable(packet(1, 2), &registry, 7);
+    inbox.put(packet(0, 3), &registry);
+    inbox.put_cancellable(packet(1, 4), &registry, 8);
+    inbox.put_cancellable(packet(2, 5), &registry, 7);
+    assert_eq!(inbox.cancel(7), 2);
+
+    let handled = inbox
+        .drain()
+        .map(|packet| unsafe { (*(packet.packet_ptr as *const Packet<u32>)).message })
+        .collect::<Vec<_>>();
+    // 1 is superseded by 3, 2 and 5 are cancelled
+    assert_eq!(handled, vec![3, 4]);
+    assert_eq!(inbox.len(), 0);
+
+    inbox.put_cancellable(packet(0, 6), &registry, 7);
+    assert_eq!(inbox.cancel(7), 1);
+    inbox.put(packet(0, 7), &registry);
+    let handled = inbox
+        .drain()
+        .map(|packet| unsafe { (*(packet.packet_ptr as *const Packet<u32>)).message })
+        .collect::<Vec<_>>();
+    assert_eq!(handled, vec![7]);
+}
+
+#[test]
+fn test_inbox_spill_and_drain() {
+    use crate::messaging::Correlation;
+    let mut registry = TypeRegistry::new();
+    registry.register_new::<u32>();
+    let tuning = Tuning {
+        inbox_spill_bytes: Some(60),
+        ..Tuning::default()
+    };
+    let ident = chunky::Ident::from("test_spilling_inbox");
+    let mut inbox = Inbox::new(&ident, Rc::new(chunky::HeapStorage), &tuning, 2);
+    let packet = |message: u32| Packet {
+        recipient_id: RawID::new(ShortTypeId::new(1).unwrap(), 0, crate::id::MachineID(0), 0),
+        correlation: Correlation::none(),
+        message,
+    };
+    let messages = |inbox: &mut Inbox| {
+        inbox
+            .drain()
+            .map(|packet| unsafe { (*(packet.packet_ptr as *const Packet<u32>)).message })
+            .collect::<Vec<_>>()
+    };
+
+    for message in 0..10 {
+        inbox.put(packet(message), &registry);
+    }
+    inbox.set_phase(1);
+    inbox.put(packet(10), &registry);
+    inbox.put(packet(11), &registry);
+    inbox.set_phase(0);
+    assert!(inbox.take_overflowed());
+    assert!(inbox.spilled_bytes() > 0);
+    assert_eq!(inbox.len(), 12);
+
+    // spilled messages of all phases end up in snapshots, and are spilled again when restored
+    let spilled = inbox.spilled_messages().unwrap().into_iter().collect::<HashMap<_, _>>();
+    assert_eq!(spilled.len(), 1);
+    let mut restored = Inbox::new(&ident, Rc::new(chunky::HeapStorage), &tuning, 2);
+    restored.restore_spilled(&|ident: &str| spilled.get(ident).cloned()).unwrap();
+    let n_spilled = restored.len();
+    assert!(n_spilled > 0 && n_spilled < 10);
+    assert_eq!(messages(&mut restored), (10 - n_spilled as u32..10).collect::<Vec<_>>());
+
+    // the queue first, then the spill, in the order they were put
+    assert_eq!(messages(&mut inbox), (0..10).collect::<Vec<_>>());
+    assert_eq!(inbox.spilled_bytes(), 0);
+    inbox.set_phase(1);
+    assert_eq!(messages(&mut inbox), vec![10, 11]);
+    assert_eq!(inbox.len(), 0);
+
+    inbox.set_phase(0);
+    inbox.put(packet(12), &registry);
+    assert_eq!(messages(&mut inbox), vec![12]);
+}
+
+#[test]
+fn test_turn_phases() {
+    use crate::id::TypedID;
+    use crate::messaging::Fate;
+    use crate::test_support::{local_system, Add, Counter, OtherCounter};
+
+    let mut system = local_system();
+    system.define_turn_phases(&["input", "simulation", "output"]);
+    system.register::<Counter>();
+    system.register::<OtherCounter>();
+    // counters pass what they got on to other counters, which only handle it in the last phase
+    system.add_handler::<Counter, _, _>(
+        |&Add(n), counter, world| {
+            counter.count += n;
+            let others = world.local_broadcast::<OtherCounter>();
+            world.send(others, Add(n));
+            Fate::Live
+        },
+        false,
+    );
+    system.add_handler::<OtherCounter, _, _>(
+        |&Add(n), counter, _| {
+            counter.count = counter.count * 10 + n;
+            Fate::Live
+        },
+        false,
+    );
+    system.handle_in_phase::<Counter, Add>("simulation");
+    system.handle_in_phase::<OtherCounter, Add>("output");
+
+    let counter = system.spawn_many(vec![Counter::new(0)])[0];
+    let other = system.spawn_many(vec![OtherCounter::new(0)])[0];
+    system.send(counter.as_raw(), Add(1));
+    system.send(other.as_raw(), Add(2));
+    // system services only have one phase, but are handled in all of them
+    system.process_all_messages();
+
+    assert!(!system.world().panic_happened());
+    assert_eq!(system.instance::<Counter>(counter).unwrap().count, 1);
+    assert_eq!(system.instance::<OtherCounter>(other).unwrap().count, 21);
+}
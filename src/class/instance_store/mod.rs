@@ -1,20 +1,38 @@
 use crate::messaging::HandlerFnRef;
 use crate::actor_system::{World};
+use crate::columns::ColumnStorage;
 use crate::tuning::Tuning;
 use chunky;
-use crate::id::RawID;
+use crate::id::{MachineID, RawID};
+use crate::id_allocation::IdAllocator;
+use crate::inspector::ClassOccupancy;
 use crate::messaging::Fate;
+use crate::state_hash::{xxh64, StateHasher};
+use crate::supervision::{call_supervised, SupervisionPolicy};
+use crate::type_registry::ShortTypeId;
 use super::ActorStateVTable;
+use byteorder::{ByteOrder, LittleEndian};
 use compact::Compact;
 use ::std::rc::Rc;
 
 mod slot_map;
 use self::slot_map::{SlotMap, SlotIndices};
 
+/// The bytes of the chunks a chunky collection needs for `n_items` items of `item_size`
+pub fn chunked_bytes(n_items: usize, item_size: usize, chunk_size: usize) -> usize {
+    let chunk_size = ::std::cmp::max(chunk_size, item_size);
+    let items_per_chunk = chunk_size / item_size;
+    (n_items + items_per_chunk - 1) / items_per_chunk * chunk_size
+}
+
 pub struct InstanceStore {
     instances: chunky::MultiArena,
     slot_map: SlotMap,
     pub n_instances: chunky::Value<usize>,
+    /// Decides new IDs instead of the slot map, if set (see `ActorSystem::set_id_allocator`)
+    id_allocator: Option<Box<dyn IdAllocator>>,
+    /// The columns of all instances, if the class has a column layout (see `ColumnStorage`)
+    columns: Option<Rc<ColumnStorage>>,
 }
 
 impl InstanceStore {
@@ -28,7 +46,47 @@ impl InstanceStore {
                 ),
                 n_instances: chunky::Value::load_or_default(ident.sub("n"), 0, Rc::clone(&storage)),
                 slot_map: SlotMap::new(&ident.sub("slts"), storage, tuning),
+                id_allocator: None,
+                columns: None,
+            }
+    }
+
+    pub fn set_columns(&mut self, columns: Rc<ColumnStorage>) {
+        self.columns = Some(columns);
+    }
+
+    /// Write the columns loaded by column handlers back into the instances, if they are loaded.
+    /// Needs to happen before the instances are used or changed in any other way.
+    pub fn write_back_columns(&mut self) {
+        if let Some(columns) = self.columns.clone() {
+            if columns.is_loaded() {
+                let (_, actors) = self.all_instances();
+                columns.write_back(&actors);
+            }
+        }
+    }
+
+    /// The indices of all instances and the instances, in storage order
+    fn all_instances(&mut self) -> (Vec<SlotIndices>, Vec<*mut ()>) {
+        let mut indices = Vec::with_capacity(*self.n_instances);
+        let mut actors = Vec::with_capacity(*self.n_instances);
+        for (bin_index, len) in self.instances.populated_bin_indices_and_lens().collect::<Vec<_>>() {
+            for slot in 0..len {
+                let index = SlotIndices::new(bin_index, slot);
+                indices.push(index);
+                actors.push(self.at_index_mut(index));
             }
+        }
+        (indices, actors)
+    }
+
+    pub fn set_id_allocator(&mut self, allocator: Box<dyn IdAllocator>) {
+        self.id_allocator = Some(allocator);
+    }
+
+    /// The state of the ID allocator, if set, to be written to snapshots
+    pub fn saved_id_allocator(&self) -> Option<Vec<u8>> {
+        self.id_allocator.as_ref().map(|allocator| allocator.save())
     }
 
     fn allocate_instance_id(&mut self) -> (usize, usize) {
@@ -45,7 +103,151 @@ impl InstanceStore {
             .map(move |index| self.at_index_mut(index))
     }
 
-    pub unsafe fn allocate_id(&mut self, base_id: RawID) -> RawID {
+    pub fn contains(&self, id: RawID) -> bool {
+        self.slot_map.indices_of(id.instance_id as usize, id.version).is_some()
+    }
+
+    /// The instance with this ID (in this version), if it exists
+    pub fn get(&self, id: RawID) -> Option<*const ()> {
+        self.slot_map
+            .indices_of(id.instance_id as usize, id.version)
+            .map(|index| self.instances.at(index.into()) as *const ())
+    }
+
+    /// Call `f` with all instances, in storage order
+    pub fn for_each<F: FnMut(*const ())>(&self, mut f: F) {
+        for (bin_index, len) in self.instances.populated_bin_indices_and_lens() {
+            for slot in 0..len {
+                f(self.instances.at(SlotIndices::new(bin_index, slot).into()) as *const ());
+            }
+        }
+    }
+
+    /// Hash all instances in storage order, starting from `seed`, see `state_hash::hash_state`
+    pub fn state_hash(&self, seed: u64, state_v_table: &ActorStateVTable) -> u64 {
+        let mut hash = seed;
+        self.for_each(|actor| hash = Self::hash_instance(actor, hash, state_v_table));
+        hash
+    }
+
+    /// Hash each instance, and all instances grouped into chunks of `instances_per_chunk`
+    /// consecutive instance IDs, combining the hashes of their instances in storage order.
+    /// Empty chunks hash to 0.
+    pub fn chunk_and_instance_hashes(
+        &self,
+        instances_per_chunk: usize,
+        state_v_table: &ActorStateVTable,
+    ) -> (Vec<u64>, Vec<(RawID, u64)>) {
+        let mut chunk_hashes = Vec::new();
+        let mut instance_hashes = Vec::new();
+        self.for_each(|actor| {
+            let id = (state_v_table.get_raw_id)(actor);
+            let chunk = id.instance_id as usize / instances_per_chunk;
+            if chunk >= chunk_hashes.len() {
+                chunk_hashes.resize(chunk + 1, 0);
+            }
+            let hash = Self::hash_instance(actor, 0, state_v_table);
+            let mut bytes = [0; 8];
+            LittleEndian::write_u64(&mut bytes, hash);
+            chunk_hashes[chunk] = xxh64(&bytes, chunk_hashes[chunk]);
+            instance_hashes.push((id, hash));
+        });
+        (chunk_hashes, instance_hashes)
+    }
+
+    fn hash_instance(actor: *const (), seed: u64, state_v_table: &ActorStateVTable) -> u64 {
+        let mut hasher = StateHasher::new(seed);
+        (state_v_table.hash_state)(actor, &mut hasher);
+        ::std::hash::Hasher::finish(&hasher)
+    }
+
+    pub fn state_bytes(&self, state_v_table: &ActorStateVTable) -> usize {
+        let mut bytes = 0;
+        for (bin_index, len) in self.instances.populated_bin_indices_and_lens() {
+            for slot in 0..len {
+                let actor = self.instances.at(SlotIndices::new(bin_index, slot).into()) as *const ();
+                bytes += (state_v_table.total_size_bytes)(actor);
+            }
+        }
+        bytes
+    }
+
+    /// The bytes of all chunks allocated for instances
+    pub fn allocated_bytes(&self, state_v_table: &ActorStateVTable, tuning: &Tuning) -> usize {
+        self.instances
+            .populated_bin_indices_and_lens()
+            .map(|(bin_index, len)| chunked_bytes(len, state_v_table.typical_size << bin_index, tuning.instance_chunk_size))
+            .sum()
+    }
+
+    /// The bytes of all chunks allocated to look up instances by ID
+    pub fn id_bytes(&self, tuning: &Tuning) -> usize {
+        self.slot_map.allocated_bytes(tuning)
+    }
+
+    /// How well the storage of instances is used
+    pub fn occupancy(&self, state_v_table: &ActorStateVTable) -> ClassOccupancy {
+        let mut occupancy = ClassOccupancy {
+            instances: *self.n_instances,
+            ids: self.slot_map.n_ids(),
+            free_ids: self.slot_map.n_free_ids(),
+            ..ClassOccupancy::default()
+        };
+        for (bin_index, len) in self.instances.populated_bin_indices_and_lens() {
+            for slot in 0..len {
+                let actor = self.instances.at(SlotIndices::new(bin_index, slot).into()) as *const ();
+                let size = (state_v_table.total_size_bytes)(actor);
+                occupancy.state_bytes += size;
+                occupancy.slot_bytes += state_v_table.typical_size << bin_index;
+                if self.instances.size_to_index(size) < bin_index {
+                    occupancy.oversized_instances += 1;
+                }
+            }
+        }
+        occupancy
+    }
+
+    /// Move all instances that are in slots bigger than they need into fitting ones.
+    /// Their IDs stay the same, only the slot map is updated. Returns how many were moved.
+    pub fn compact(&mut self, state_v_table: &ActorStateVTable) -> usize {
+        self.write_back_columns();
+        let mut oversized = Vec::new();
+        for (bin_index, len) in self.instances.populated_bin_indices_and_lens() {
+            for slot in 0..len {
+                let actor = self.instances.at(SlotIndices::new(bin_index, slot).into()) as *const ();
+                if self.instances.size_to_index((state_v_table.total_size_bytes)(actor)) < bin_index {
+                    oversized.push((state_v_table.get_raw_id)(actor));
+                }
+            }
+        }
+        for id in &oversized {
+            self.resize(id.instance_id as usize, state_v_table);
+        }
+        oversized.len()
+    }
+
+    /// Copy the compact state of an instance out of the store to migrate it to `to`,
+    /// and remove it without dropping it, since its state lives on in the copy
+    pub fn take(&mut self, id: RawID, to: MachineID, world: &mut World, state_v_table: &ActorStateVTable) -> Option<Vec<u8>> {
+        self.write_back_columns();
+        let index = self.slot_map.indices_of(id.instance_id as usize, id.version)?;
+        let actor = self.at_index_mut(index);
+        state_v_table.lifecycle.migrating(actor, to, world);
+        let size = (state_v_table.total_size_bytes)(actor);
+        let state = unsafe { ::std::slice::from_raw_parts(actor as *const u8, size) }.to_vec();
+        self.swap_remove(index, state_v_table);
+        self.slot_map
+            .free(id.instance_id as usize, id.version as usize, self.id_allocator.is_none());
+        *self.n_instances -= 1;
+        Some(state)
+    }
+
+    /// Allocate an ID of an instance on `base_id.machine`, by the machine `local` in turn `n_turns`
+    pub unsafe fn allocate_id(&mut self, base_id: RawID, local: MachineID, n_turns: usize) -> RawID {
+        if self.id_allocator.is_some() {
+            return self.allocate_contiguous_ids(1, base_id, local, n_turns);
+        }
+        assert!(base_id.machine == local, "Allocating IDs of instances on other machines needs an IdAllocator");
         let (instance_id, version) = self.allocate_instance_id();
         RawID::new(
             base_id.type_id,
@@ -55,11 +257,44 @@ impl InstanceStore {
         )
     }
 
+    /// Allocate `n` IDs with consecutive instance IDs (all in version 0) of instances on
+    /// `base_id.machine`, by the machine `local` in turn `n_turns`, starting from the returned one
+    pub fn allocate_contiguous_ids(&mut self, n: usize, base_id: RawID, local: MachineID, n_turns: usize) -> RawID {
+        let first_instance_id = match self.id_allocator {
+            Some(ref mut allocator) => allocator
+                .allocate(local, base_id.machine, n_turns, n as u32)
+                .unwrap_or_else(|| panic!("No instance IDs left to allocate {} more", n)) as usize,
+            None => {
+                assert!(base_id.machine == local, "Allocating IDs of instances on other machines needs an IdAllocator");
+                self.slot_map.allocate_contiguous_ids(n)
+            }
+        };
+        RawID::new(base_id.type_id, first_instance_id as u32, base_id.machine, 0)
+    }
+
+    /// How many more IDs of instances on `owner` the machine `local` can allocate in turn `n_turns`
+    pub fn remaining_ids(&self, owner: MachineID, local: MachineID, n_turns: usize) -> usize {
+        match self.id_allocator {
+            Some(ref allocator) => allocator.remaining(local, owner, n_turns),
+            None if owner == local => u32::max_value() as usize - self.slot_map.n_ids() + self.slot_map.n_free_ids(),
+            None => 0,
+        }
+    }
+
+    /// Have any IDs been allocated so far?
+    pub fn has_ids(&self) -> bool {
+        self.slot_map.n_ids() > 0
+    }
+
     pub unsafe fn add(&mut self, initial_state: *mut (), state_v_table: &ActorStateVTable, increment_n_instances: bool) {
+        self.write_back_columns();
         let id = (state_v_table.get_raw_id)(initial_state);
         let size = (state_v_table.total_size_bytes)(initial_state);
         let (slot_ptr, index) = self.instances.push(size);
 
+        if self.id_allocator.is_some() {
+            self.slot_map.ensure_id(id.instance_id as usize);
+        }
         self.slot_map
             .associate(id.instance_id as usize, index.into());
 
@@ -68,6 +303,21 @@ impl InstanceStore {
         (state_v_table.compact_behind)(initial_state, slot_ptr as *mut ());
     }
 
+    /// Move `new_state` into the place of an instance whose state is stale, without dropping
+    /// the stale state (for instances holding `External`s restored from a snapshot).
+    /// The new state gets the ID of the instance, returns false if it doesn't exist.
+    pub unsafe fn replace_stale(&mut self, instance_id: u32, version: u8, new_state: *mut (), state_v_table: &ActorStateVTable) -> bool {
+        let index = match self.slot_map.indices_of(instance_id as usize, version) {
+            Some(index) => index,
+            None => return false,
+        };
+        let id = (state_v_table.get_raw_id)(self.at_index_mut(index));
+        (state_v_table.set_raw_id)(new_state, id);
+        self.add(new_state, state_v_table, false);
+        self.swap_remove(index, state_v_table);
+        true
+    }
+
     fn swap_remove(&mut self, indices: SlotIndices, state_v_table: &ActorStateVTable) -> bool {
         match self.instances.swap_remove_within_bin(indices.into()) {
             Some(swapped_actor) => {
@@ -93,7 +343,7 @@ impl InstanceStore {
         (state_v_table.drop)(old_actor_ptr);
         self.swap_remove(i, state_v_table);
         self.slot_map
-            .free(id.instance_id as usize, id.version as usize);
+            .free(id.instance_id as usize, id.version as usize, self.id_allocator.is_none());
         *self.n_instances -= 1;
     }
 
@@ -111,12 +361,14 @@ impl InstanceStore {
         self.swap_remove(old_i, state_v_table)
     }
 
-    pub fn receive_instance(&mut self, recipient_id: RawID, packet_ptr: *const (), world: &mut World, handler: &Box<HandlerFnRef>, state_v_table: &ActorStateVTable) {
+    /// Let the instance `recipient_id` handle a message, returns its fate (`None` if it doesn't exist)
+    pub fn receive_instance(&mut self, recipient_id: RawID, packet_ptr: *const (), world: &mut World, handler: &Box<HandlerFnRef>, state_v_table: &ActorStateVTable, supervision: SupervisionPolicy, message_type: ShortTypeId) -> Option<Fate> {
+        self.write_back_columns();
         if let Some(actor) = self.at_mut(
             recipient_id.instance_id as usize,
             recipient_id.version,
         ) {
-            let fate = handler(actor, packet_ptr, world);
+            let fate = call_supervised(&**handler, actor, packet_ptr, world, supervision, message_type, state_v_table);
             let is_still_compact = (state_v_table.is_still_compact)(actor);
 
             match fate {
@@ -125,14 +377,68 @@ impl InstanceStore {
                         self.resize(recipient_id.instance_id as usize, &state_v_table);
                     }
                 }
-                Fate::Die => self.remove(recipient_id, &state_v_table),
+                Fate::Die => {
+                    state_v_table.lifecycle.destroyed(actor, world);
+                    world.instance_died(recipient_id);
+                    self.remove(recipient_id, &state_v_table)
+                }
             }
+            Some(fate)
         } else {
             eprintln!("Could not find actor {}", recipient_id.format(world));
+            world.dead_letter(recipient_id, message_type, packet_ptr);
+            None
+        }
+    }
+
+    /// Like `receive_broadcast`, but letting `receive_all` handle the broadcast for all instances
+    /// at once (on worker threads or column by column). Instances are only resized or removed afterwards,
+    /// from the last one to the first, so the indices of the others stay valid.
+    pub fn receive_broadcast_batched<F: FnOnce(&[*mut ()], &mut World) -> Vec<Fate>>(&mut self, world: &mut World, state_v_table: &ActorStateVTable, receive_all: F) {
+        self.write_back_columns();
+        let (indices, actors) = self.all_instances();
+        let fates = receive_all(&actors, world);
+        self.apply_fates(indices, actors, fates, world, state_v_table);
+    }
+
+    /// Like `receive_broadcast_batched`, but for column handlers, which leave the columns loaded
+    /// (see `ColumnStorage`) and don't change the instances themselves
+    pub fn receive_column_broadcast<F: FnOnce(&[*mut ()], &mut World) -> Vec<Fate>>(&mut self, world: &mut World, state_v_table: &ActorStateVTable, receive_all: F) {
+        let (indices, actors) = self.all_instances();
+        let fates = receive_all(&actors, world);
+        if fates.iter().any(|fate| if let Fate::Die = fate { true } else { false }) {
+            self.write_back_columns();
+            self.apply_fates(indices, actors, fates, world, state_v_table);
+        }
+    }
+
+    fn apply_fates(&mut self, indices: Vec<SlotIndices>, actors: Vec<*mut ()>, fates: Vec<Fate>, world: &mut World, state_v_table: &ActorStateVTable) {
+        for ((index, actor), fate) in indices.into_iter().zip(actors).zip(fates).rev() {
+            match fate {
+                Fate::Live => {
+                    if !(state_v_table.is_still_compact)(actor) {
+                        self.resize_at_index(index, state_v_table);
+                    }
+                }
+                Fate::Die => {
+                    state_v_table.lifecycle.destroyed(actor, world);
+                    let id = (state_v_table.get_raw_id)(actor);
+                    world.instance_died(id);
+                    self.remove_at_index(index, id, state_v_table);
+                }
+            }
         }
     }
 
-    pub fn receive_broadcast(&mut self, packet_ptr: *const (), world: &mut World, handler: &Box<HandlerFnRef>, state_v_table: &ActorStateVTable) {
+    /// Let all instances die, running their `on_destroy` hooks, returns how many there were
+    pub fn destroy_all(&mut self, world: &mut World, state_v_table: &ActorStateVTable) -> usize {
+        let n_instances = *self.n_instances;
+        self.receive_broadcast_batched(world, state_v_table, |actors, _| actors.iter().map(|_| Fate::Die).collect());
+        n_instances
+    }
+
+    pub fn receive_broadcast(&mut self, packet_ptr: *const (), world: &mut World, handler: &Box<HandlerFnRef>, state_v_table: &ActorStateVTable, supervision: SupervisionPolicy, message_type: ShortTypeId) {
+    self.write_back_columns();
     // this function has to deal with the fact that during the iteration,
     // receivers of the broadcast can be resized
     // and thus removed from a bin, swapping in either
@@ -152,7 +458,11 @@ impl InstanceStore {
             let index = SlotIndices::new(bin_index, slot);
             let (fate, is_still_compact, id) = {
                 let actor = self.at_index_mut(index);
-                let fate = handler(actor, packet_ptr, world);
+                let fate = call_supervised(&**handler, actor, packet_ptr, world, supervision, message_type, state_v_table);
+                if let Fate::Die = fate {
+                    state_v_table.lifecycle.destroyed(actor, world);
+                    world.instance_died((state_v_table.get_raw_id)(actor));
+                }
                 (fate, actor.is_still_compact(), (state_v_table.get_raw_id)(actor))
             };
 
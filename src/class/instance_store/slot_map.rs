@@ -1,6 +1,7 @@
 use chunky;
 use std::rc::Rc;
 use crate::tuning::Tuning;
+use super::chunked_bytes;
 
 #[derive(Clone, Copy)]
 pub struct SlotIndices {
@@ -23,6 +24,10 @@ impl SlotIndices {
         }
     }
 
+    pub fn is_valid(&self) -> bool {
+        self.bin != u8::max_value()
+    }
+
     pub fn bin(&self) -> usize {
         self.bin as usize
     }
@@ -70,6 +75,42 @@ impl SlotMap {
         }
     }
 
+    pub fn n_ids(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn n_free_ids(&self) -> usize {
+        self.free_ids_with_versions.len()
+    }
+
+    pub fn allocated_bytes(&self, tuning: &Tuning) -> usize {
+        chunked_bytes(self.entries.len(), ::std::mem::size_of::<SlotIndices>(), tuning.instance_entry_chunk_size)
+            + chunked_bytes(self.last_known_version.len(), 1, tuning.instance_versions_chunk_size)
+            + chunked_bytes(
+                self.free_ids_with_versions.len(),
+                ::std::mem::size_of::<(usize, usize)>(),
+                tuning.instance_free_chunk_size,
+            )
+    }
+
+    /// Allocate `n` new IDs with consecutive numbers (not reusing free ones), returns the first
+    pub fn allocate_contiguous_ids(&mut self, n: usize) -> usize {
+        let first_id = self.entries.len();
+        for _ in 0..n {
+            self.entries.push(SlotIndices::invalid());
+            self.last_known_version.push(0);
+        }
+        first_id
+    }
+
+    /// Make room for an ID that was allocated by an `IdAllocator`, possibly on another machine
+    pub fn ensure_id(&mut self, id: usize) {
+        while self.entries.len() <= id {
+            self.entries.push(SlotIndices::invalid());
+            self.last_known_version.push(0);
+        }
+    }
+
     pub fn associate(&mut self, id: usize, new_entry: SlotIndices) {
         let entry = self
             .entries
@@ -81,7 +122,7 @@ impl SlotMap {
     pub fn indices_of(&self, id: usize, version: u8) -> Option<SlotIndices> {
         if let Some(last_known_version) = self.last_known_version.at(id) {
             if *last_known_version == version {
-                self.indices_of_no_version_check(id)
+                self.indices_of_no_version_check(id).filter(SlotIndices::is_valid)
             } else {
                 None
             }
@@ -94,7 +135,14 @@ impl SlotMap {
         self.entries.at(id).cloned()
     }
 
-    pub fn free(&mut self, id: usize, version: usize) {
+    /// Free an ID to be reused in its next version, or retire it for good if `reuse` is false
+    pub fn free(&mut self, id: usize, version: usize, reuse: bool) {
+        if version >= u8::max_value() as usize || !reuse {
+            // all versions of this ID were used, so retire it instead of
+            // letting the next version wrap around and be addressed by stale IDs
+            self.associate(id, SlotIndices::invalid());
+            return;
+        }
         *self
             .last_known_version
             .at_mut(id)
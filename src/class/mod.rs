@@ -1,27 +1,99 @@
 use crate::messaging::HandlerFnRef;
 use crate::messaging::Message;
 use crate::actor::Actor;
-use crate::type_registry::ShortTypeId;
-use crate::actor_system::{World, MAX_MESSAGE_TYPES};
-use crate::id::{broadcast_instance_id, RawID, TypedID};
-use crate::messaging::{Fate, Packet};
+use crate::columns::{ColumnLayout, ColumnStorage, Columns};
+use crate::type_registry::{ShortTypeId, TypeRegistry};
+use crate::debugger::{Debugger, HeldPacket, PacketDecoders, PacketHeader};
+use crate::interceptors::{Handled, Intercept, Interceptors};
+use crate::inspector::ClassMemory;
+use crate::actor_system::World;
+use crate::id::{broadcast_instance_id, MachineID, RawID, TypedID};
+use crate::messaging::{dynamic_parts_within, Fate, Packet};
+use crate::migration::{ForwardFn, Forwarding};
+use crate::profiling::ClassProfile;
+use crate::query::handling;
+use crate::rate_limits::RateLimits;
+use crate::state_hash::{hash_state, StateHasher};
+use crate::supervision::SupervisionPolicy;
+use crate::time::now_ms;
 use crate::tuning::Tuning;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use compact::Compact;
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::rc::Rc;
 
 mod instance_store;
-use self::instance_store::InstanceStore;
+pub(crate) use self::instance_store::InstanceStore;
 pub mod inbox;
 use self::inbox::{Inbox, DispatchablePacket};
+mod batch;
+use self::batch::{MessageBatch, TypedBatch};
+mod spill;
 
 pub struct Class {
     pub instance_store: InstanceStore,
     pub v_table: ActorVTable,
-    pub inbox: Inbox
+    pub inbox: Inbox,
+    pub supervision: SupervisionPolicy,
+    pub profile: ClassProfile,
+    /// The ident of all chunks of the class
+    pub ident: chunky::Ident,
+    /// Set for classes whose state holds `External`s, see `ActorSystem::register_external`
+    pub external: Option<ExternalState>,
+    /// Set if messages to single instances are limited, see `ActorSystem::rate_limit`
+    pub rate_limits: Option<RateLimits>,
+    /// How to check messages of each handled message type from peers
+    packet_checks: HashMap<ShortTypeId, PacketCheck>,
+    /// Whether any message type is handled in batches, which are handled after draining the inbox
+    has_batch_handlers: bool,
+    /// Set if column handlers can be added, see `ActorSystem::set_column_layout`
+    columns: Option<Rc<ColumnStorage>>,
+}
+
+/// How packets of a message type received from peers are checked before they are dispatched
+pub struct PacketCheck {
+    /// The fixed size of the packets
+    pub size: usize,
+    /// Do the dynamic parts of the packet (at the pointer) lie within its bytes?
+    pub dynamic_parts_within: fn(*const (), &[u8]) -> bool,
+    /// The size of the packet (at the pointer) including its dynamic parts
+    pub total_size_bytes: fn(*const ()) -> usize,
+}
+
+impl PacketCheck {
+    fn of<M: Message>() -> PacketCheck {
+        fn dynamic_parts_of<M: Message>(packet_ptr: *const (), bounds: &[u8]) -> bool {
+            let packet = unsafe { &*(packet_ptr as *const Packet<M>) };
+            dynamic_parts_within(&packet.message, bounds)
+        }
+        fn total_size_of<M: Message>(packet_ptr: *const ()) -> usize {
+            Compact::total_size_bytes(unsafe { &*(packet_ptr as *const Packet<M>) })
+        }
+        PacketCheck {
+            size: ::std::mem::size_of::<Packet<M>>(),
+            dynamic_parts_within: dynamic_parts_of::<M>,
+            total_size_bytes: total_size_of::<M>,
+        }
+    }
+}
+
+/// How the state of a class holding `External`s is saved,
+/// since bytes of its instances only stay valid on the same machine and while running
+pub enum ExternalState {
+    /// Left out of snapshots and never migrated
+    Excluded,
+    /// Saved to bytes and rebuilt from them, for snapshots and migration
+    Synced {
+        save: Box<dyn Fn(*const ()) -> Vec<u8>>,
+        /// Rebuilds a state from saved bytes and passes it on to be moved into an instance store
+        load: Box<dyn Fn(&[u8], &mut dyn FnMut(*mut ()))>,
+    },
 }
 
 pub struct ActorVTable {
-    pub message_handlers: [MessageHandler; MAX_MESSAGE_TYPES],
+    /// Indexed by message type ID
+    pub message_handlers: Vec<MessageHandler>,
     pub state_v_table: ActorStateVTable,
     pub type_name: &'static str,
 }
@@ -32,15 +104,47 @@ pub struct ActorStateVTable {
     pub compact_behind: Box<dyn Fn(*mut (), *mut ())>,
     pub drop: Box<dyn Fn(*mut ())>,
     pub get_raw_id: Box<dyn Fn(*const ()) -> RawID>,
+    pub hash_state: Box<dyn Fn(*const (), &mut StateHasher)>,
     pub set_raw_id: Box<dyn Fn(*mut (), RawID)>,
-    pub typical_size: usize
+    pub typical_size: usize,
+    pub align: usize,
+    pub lifecycle: LifecycleHooks,
+}
+
+/// Optional callbacks for the lifecycle of instances,
+/// see `ActorSystem::on_spawn`, `on_destroy` and `on_migrate`
+#[derive(Default)]
+pub struct LifecycleHooks {
+    pub on_spawn: Option<Box<dyn Fn(*mut (), &mut World)>>,
+    pub on_destroy: Option<Box<dyn Fn(*mut (), &mut World)>>,
+    pub on_migrate: Option<Box<dyn Fn(*const (), MachineID, &mut World)>>,
+}
+
+impl LifecycleHooks {
+    pub fn spawned(&self, actor: *mut (), world: &mut World) {
+        if let Some(ref on_spawn) = self.on_spawn {
+            on_spawn(actor, world);
+        }
+    }
+
+    pub fn destroyed(&self, actor: *mut (), world: &mut World) {
+        if let Some(ref on_destroy) = self.on_destroy {
+            on_destroy(actor, world);
+        }
+    }
+
+    pub fn migrating(&self, actor: *const (), to: MachineID, world: &mut World) {
+        if let Some(ref on_migrate) = self.on_migrate {
+            on_migrate(actor, to, world);
+        }
+    }
 }
 
 impl ActorVTable {
-    pub fn new_for_actor_type<A: Actor>() -> ActorVTable {
+    pub fn new_for_actor_type<A: Actor>(n_message_types: usize) -> ActorVTable {
         let actor_name = unsafe { ::std::intrinsics::type_name::<A>() };
         ActorVTable {
-            message_handlers: unsafe { make_array!(MAX_MESSAGE_TYPES, |_| MessageHandler::Unassigned) },
+            message_handlers: (0..n_message_types).map(|_| MessageHandler::Unassigned).collect(),
             type_name: actor_name,
             state_v_table: ActorStateVTable {
                 is_still_compact: Box::new(|act: *const ()| unsafe {(*(act as *const A)).is_still_compact()}),
@@ -48,8 +152,31 @@ impl ActorVTable {
                 compact_behind: Box::new(|source: *mut (), dest: *mut ()| unsafe{Compact::compact_behind(source as *mut A, dest as *mut A)}),
                 drop: Box::new(|act: *mut ()| unsafe{::std::ptr::drop_in_place(act as *mut A)}),
                 get_raw_id: Box::new(|act: *const ()| unsafe{(*(act as *const A)).id().as_raw()}),
+                hash_state: Box::new(|act: *const (), hasher: &mut StateHasher| unsafe{hash_state(&*(act as *const A), hasher)}),
                 set_raw_id: Box::new(|act: *mut (), id: RawID| unsafe{(*(act as *mut A)).set_id(id)}),
-                typical_size: A::typical_size()
+                typical_size: A::typical_size(),
+                align: ::std::mem::align_of::<A>(),
+                lifecycle: LifecycleHooks::default(),
+            }
+        }
+    }
+
+    /// A v-table for a class that never has instances and only handles class messages
+    pub fn new_without_instances(type_name: &'static str, n_message_types: usize) -> ActorVTable {
+        ActorVTable {
+            message_handlers: (0..n_message_types).map(|_| MessageHandler::Unassigned).collect(),
+            type_name,
+            state_v_table: ActorStateVTable {
+                is_still_compact: Box::new(|_| unreachable!("Class without instances")),
+                total_size_bytes: Box::new(|_| unreachable!("Class without instances")),
+                compact_behind: Box::new(|_, _| unreachable!("Class without instances")),
+                drop: Box::new(|_| unreachable!("Class without instances")),
+                get_raw_id: Box::new(|_| unreachable!("Class without instances")),
+                hash_state: Box::new(|_, _| unreachable!("Class without instances")),
+                set_raw_id: Box::new(|_, _| unreachable!("Class without instances")),
+                typical_size: 1,
+                align: 1,
+                lifecycle: LifecycleHooks::default(),
             }
         }
     }
@@ -57,19 +184,33 @@ impl ActorVTable {
 
 pub enum MessageHandler {
     Unassigned,
-    OnMessage{handler: Box<HandlerFnRef>, critical: bool},
-    OnSpawn{spawner: Box<dyn Fn(*const (), &mut World, &mut InstanceStore, &ActorStateVTable)>, critical: bool}
+    OnMessage{handler: Box<HandlerFnRef>, forward: Box<ForwardFn>, critical: bool},
+    /// Collected while draining the inbox, then handled for each recipient at once
+    OnBatch{handler: Box<HandlerFnRef>, batch: RefCell<Box<dyn MessageBatch>>, forward: Box<ForwardFn>, critical: bool},
+    OnSpawn{spawner: Box<dyn Fn(*const (), &mut World, &mut InstanceStore, &ActorStateVTable)>, critical: bool},
+    /// Handled once per message by the class itself, regardless of instances
+    OnClassMessage{handler: Box<dyn Fn(*const (), &mut World)>, critical: bool},
+    /// Handled on the columns of the recipients, all instances at once for broadcasts
+    OnColumns{handler: Box<HandlerFnRef>, handle_all: Box<dyn Fn(*const (), &[*mut ()], &mut World) -> Vec<Fate>>, forward: Box<ForwardFn>, critical: bool}
 }
 
 impl Class {
-    pub fn new(v_table: ActorVTable, storage: Rc<dyn chunky::ChunkStorage>, tuning: &Tuning) -> Self {
+    pub fn new(v_table: ActorVTable, storage: Rc<dyn chunky::ChunkStorage>, tuning: &Tuning, n_turn_phases: usize) -> Self {
         let ident: chunky::Ident = v_table.type_name.split("<").map(|piece|
             piece.split("::").last().unwrap_or("")
         ).collect::<Vec<_>>().join("<").replace("<", "(").replace(">", ")").into();
         Class {
             instance_store: InstanceStore::new(&ident, v_table.state_v_table.typical_size, Rc::clone(&storage), tuning),
-            inbox: Inbox::new(&ident.sub("inbx"), storage, tuning),
+            inbox: Inbox::new(&ident.sub("inbx"), storage, tuning, n_turn_phases),
             v_table,
+            supervision: SupervisionPolicy::default(),
+            profile: ClassProfile::default(),
+            ident,
+            external: None,
+            rate_limits: None,
+            packet_checks: HashMap::new(),
+            has_batch_handlers: false,
+            columns: None,
         }
     }
 
@@ -79,14 +220,83 @@ impl Class {
         handler: F,
         critical: bool,
     ) {
+        self.packet_checks.insert(message_id, PacketCheck::of::<M>());
         self.v_table.message_handlers[message_id.as_usize()] = MessageHandler::OnMessage {
                 handler: Box::new(move |actor_ptr: *mut (), packet_ptr: *const (), world: &mut World| -> Fate {
                     unsafe {
                         let actor = &mut *(actor_ptr as *mut A);
                         let packet = & *(packet_ptr as *const Packet<M>);
-                        handler(&packet.message, actor, world)
+                        handling(packet.correlation, || handler(&packet.message, actor, world))
+                    }
+                }),
+                forward: Box::new(|packet_ptr: *const ()| -> Forwarding {
+                    let packet = unsafe { &*(packet_ptr as *const Packet<M>) };
+                    let (message, correlation) = (packet.message.clone(), packet.correlation);
+                    Box::new(move |new_id: RawID, world: &mut World| world.send_correlated(new_id, message, correlation))
+                }),
+                critical
+        };
+    }
+
+    pub fn add_batch_handler<A: Actor, M: Message, F: Fn(&[M], &mut A, &mut World) -> Fate + 'static>(
+        &mut self,
+        message_id: ShortTypeId,
+        handler: F,
+        critical: bool,
+    ) {
+        self.packet_checks.insert(message_id, PacketCheck::of::<M>());
+        self.v_table.message_handlers[message_id.as_usize()] = MessageHandler::OnBatch {
+                handler: Box::new(move |actor_ptr: *mut (), messages_ptr: *const (), world: &mut World| -> Fate {
+                    unsafe {
+                        let actor = &mut *(actor_ptr as *mut A);
+                        let messages = &*(messages_ptr as *const Vec<M>);
+                        handler(messages, actor, world)
                     }
                 }),
+                batch: RefCell::new(Box::new(TypedBatch::<M>::new())),
+                forward: Box::new(|packet_ptr: *const ()| -> Forwarding {
+                    let packet = unsafe { &*(packet_ptr as *const Packet<M>) };
+                    let (message, correlation) = (packet.message.clone(), packet.correlation);
+                    Box::new(move |new_id: RawID, world: &mut World| world.send_correlated(new_id, message, correlation))
+                }),
+                critical
+        };
+        self.has_batch_handlers = true;
+    }
+
+    pub fn set_column_layout<A: Actor>(&mut self, layout: ColumnLayout<A>) {
+        let columns = Rc::new(ColumnStorage::new(layout));
+        self.instance_store.set_columns(Rc::clone(&columns));
+        self.columns = Some(columns);
+    }
+
+    pub fn add_column_handler<M: Message, F: Fn(&M, &mut Columns, &mut World) + 'static>(
+        &mut self,
+        message_id: ShortTypeId,
+        handler: F,
+        critical: bool,
+    ) {
+        let storage = self.columns.clone().expect("Column layout not set yet, see `ActorSystem::set_column_layout`");
+        let storage_all = Rc::clone(&storage);
+        let handler = Rc::new(handler);
+        let handler_all = Rc::clone(&handler);
+        self.packet_checks.insert(message_id, PacketCheck::of::<M>());
+        self.v_table.message_handlers[message_id.as_usize()] = MessageHandler::OnColumns {
+                handler: Box::new(move |actor_ptr: *mut (), packet_ptr: *const (), world: &mut World| -> Fate {
+                    let packet = unsafe { &*(packet_ptr as *const Packet<M>) };
+                    handling(packet.correlation, || {
+                        storage.handle_one(actor_ptr, |columns| handler(&packet.message, columns, world))
+                    })
+                }),
+                handle_all: Box::new(move |packet_ptr: *const (), actors: &[*mut ()], world: &mut World| -> Vec<Fate> {
+                    let packet = unsafe { &*(packet_ptr as *const Packet<M>) };
+                    storage_all.handle_all(actors, |columns| handler_all(&packet.message, columns, world))
+                }),
+                forward: Box::new(|packet_ptr: *const ()| -> Forwarding {
+                    let packet = unsafe { &*(packet_ptr as *const Packet<M>) };
+                    let (message, correlation) = (packet.message.clone(), packet.correlation);
+                    Box::new(move |new_id: RawID, world: &mut World| world.send_correlated(new_id, message, correlation))
+                }),
                 critical
         };
     }
@@ -97,12 +307,16 @@ impl Class {
         constructor: F,
         critical: bool,
     ) {
+        self.packet_checks.insert(message_id, PacketCheck::of::<M>());
         self.v_table.message_handlers[message_id.as_usize()] = MessageHandler::OnSpawn {
             spawner: Box::new(move |packet_ptr: *const (), world: &mut World, store: &mut InstanceStore, intrinsics: &ActorStateVTable| {
                 unsafe {
                     let packet = &*(packet_ptr as *const Packet<M>);
                     let mut instance = constructor(&packet.message, world);
+                    intrinsics.lifecycle.spawned(&mut instance as *mut A as *mut (), world);
                     store.add(&mut instance as *mut A as *mut (), intrinsics, true);
+                    world.instance_spawned(instance.id().as_raw());
+                    world.place_spawned(instance.id().as_raw(), &instance as *const A as *const (), packet.recipient_id.is_broadcast());
                     ::std::mem::forget(instance);
                 }
             }),
@@ -110,40 +324,443 @@ impl Class {
         };
     }
 
-    pub fn handle_messages(&mut self, message_statistics: &mut [usize], world: &mut World) {
+    /// Add many instances at once, giving them consecutive IDs based on `base_id`
+    /// (allocated in turn `n_turns`), returns their IDs
+    pub fn spawn_many<A: Actor, I: IntoIterator<Item = A>>(&mut self, states: I, base_id: RawID, n_turns: usize, world: &mut World) -> Vec<RawID> {
+        let states = states.into_iter().collect::<Vec<_>>();
+        let first_id = self.instance_store.allocate_contiguous_ids(states.len(), base_id, base_id.machine, n_turns);
+        let mut ids = Vec::with_capacity(states.len());
+        for (i, mut instance) in states.into_iter().enumerate() {
+            let id = RawID::new(first_id.type_id, first_id.instance_id + i as u32, first_id.machine, 0);
+            unsafe {
+                instance.set_id(id);
+                self.v_table.state_v_table.lifecycle.spawned(&mut instance as *mut A as *mut (), world);
+                self.instance_store.add(&mut instance as *mut A as *mut (), &self.v_table.state_v_table, true);
+            }
+            world.instance_spawned(id);
+            world.place_spawned(id, &instance as *const A as *const (), true);
+            ::std::mem::forget(instance);
+            ids.push(id);
+        }
+        ids
+    }
+
+    pub fn add_class_handler<M: Message, F: Fn(&M, &mut World) + 'static>(
+        &mut self,
+        message_id: ShortTypeId,
+        handler: F,
+        critical: bool,
+    ) {
+        self.packet_checks.insert(message_id, PacketCheck::of::<M>());
+        self.v_table.message_handlers[message_id.as_usize()] = MessageHandler::OnClassMessage {
+            handler: Box::new(move |packet_ptr: *const (), world: &mut World| {
+                unsafe {
+                    let packet = &*(packet_ptr as *const Packet<M>);
+                    handling(packet.correlation, || handler(&packet.message, world))
+                }
+            }),
+            critical
+        };
+    }
+
+    /// The fixed size of packets of a message type, if the class handles it
+    pub fn packet_size(&self, message_type: ShortTypeId) -> Option<usize> {
+        self.packet_checks.get(&message_type).map(|check| check.size)
+    }
+
+    /// How to check packets of a message type from peers, if the class handles it
+    pub fn packet_check(&self, message_type: ShortTypeId) -> Option<&PacketCheck> {
+        self.packet_checks.get(&message_type)
+    }
+
+    pub fn set_on_spawn<A: Actor, F: Fn(&mut A, &mut World) + 'static>(&mut self, hook: F) {
+        self.v_table.state_v_table.lifecycle.on_spawn = Some(Box::new(move |actor_ptr: *mut (), world: &mut World| {
+            hook(unsafe { &mut *(actor_ptr as *mut A) }, world)
+        }));
+    }
+
+    pub fn set_on_destroy<A: Actor, F: Fn(&mut A, &mut World) + 'static>(&mut self, hook: F) {
+        self.v_table.state_v_table.lifecycle.on_destroy = Some(Box::new(move |actor_ptr: *mut (), world: &mut World| {
+            hook(unsafe { &mut *(actor_ptr as *mut A) }, world)
+        }));
+    }
+
+    pub fn set_on_migrate<A: Actor, F: Fn(&A, MachineID, &mut World) + 'static>(&mut self, hook: F) {
+        self.v_table.state_v_table.lifecycle.on_migrate = Some(Box::new(move |actor_ptr: *const (), to: MachineID, world: &mut World| {
+            hook(unsafe { &*(actor_ptr as *const A) }, to, world)
+        }));
+    }
+
+    pub fn sync_external<A: Actor, S: Fn(&A) -> Vec<u8> + 'static, L: Fn(&[u8]) -> A + 'static>(&mut self, save: S, load: L) {
+        self.external = Some(ExternalState::Synced {
+            save: Box::new(move |actor_ptr: *const ()| save(unsafe { &*(actor_ptr as *const A) })),
+            load: Box::new(move |saved: &[u8], add: &mut dyn FnMut(*mut ())| {
+                let mut state = load(saved);
+                add(&mut state as *mut A as *mut ());
+                // now owned by the instance store
+                ::std::mem::forget(state);
+            }),
+        });
+    }
+
+    /// The saved states of all instances of a synced external class, as
+    /// `[n_instances: u32]` and then `[instance_id: u32][version: u8][length: u32][bytes]` for each
+    pub fn save_external_states(&self) -> Option<Vec<u8>> {
+        if let Some(ExternalState::Synced { ref save, .. }) = self.external {
+            let mut states = Vec::new();
+            states.write_u32::<LittleEndian>(*self.instance_store.n_instances as u32).expect("Writing to memory");
+            self.instance_store.for_each(|actor| {
+                let id = (self.v_table.state_v_table.get_raw_id)(actor);
+                let saved = save(actor);
+                states.write_u32::<LittleEndian>(id.instance_id).expect("Writing to memory");
+                states.write_u8(id.version).expect("Writing to memory");
+                states.write_u32::<LittleEndian>(saved.len() as u32).expect("Writing to memory");
+                states.extend_from_slice(&saved);
+            });
+            Some(states)
+        } else {
+            None
+        }
+    }
+
+    /// Rebuild the instances of a synced external class restored from a snapshot,
+    /// whose restored state is stale, from their saved states (see `save_external_states`)
+    pub fn restore_external_states(&mut self, mut states: &[u8]) {
+        let load = match self.external {
+            Some(ExternalState::Synced { ref load, .. }) => load,
+            _ => panic!("Class isn't synced external"),
+        };
+        let n_instances = states.read_u32::<LittleEndian>().expect("Saved external states should be complete");
+        for _ in 0..n_instances {
+            let instance_id = states.read_u32::<LittleEndian>().expect("Saved external states should be complete");
+            let version = states.read_u8().expect("Saved external states should be complete");
+            let len = states.read_u32::<LittleEndian>().expect("Saved external states should be complete") as usize;
+            let (saved, rest) = states.split_at(len);
+            states = rest;
+            let instance_store = &mut self.instance_store;
+            let state_v_table = &self.v_table.state_v_table;
+            load(saved, &mut |state_ptr| unsafe {
+                instance_store.replace_stale(instance_id, version, state_ptr, state_v_table);
+            });
+        }
+    }
+
+    /// Hash the state of all instances, starting from `seed`
+    pub fn state_hash(&self, seed: u64) -> u64 {
+        self.instance_store.state_hash(seed, &self.v_table.state_v_table)
+    }
+
+    /// Hash the state of each instance, and of all instances in chunks of consecutive instance IDs
+    pub fn chunk_and_instance_hashes(&self, instances_per_chunk: usize) -> (Vec<u64>, Vec<(RawID, u64)>) {
+        self.instance_store.chunk_and_instance_hashes(instances_per_chunk, &self.v_table.state_v_table)
+    }
+
+    /// The memory taken by the state of all instances
+    pub fn state_bytes(&self) -> usize {
+        self.instance_store.state_bytes(&self.v_table.state_v_table)
+    }
+
+    /// The memory allocated and used by instances and the inbox
+    pub fn memory(&self, type_id: u16, tuning: &Tuning) -> ClassMemory {
+        ClassMemory {
+            type_id,
+            type_name: self.v_table.type_name.to_owned(),
+            instances: *self.instance_store.n_instances,
+            instance_bytes_allocated: self.instance_store.allocated_bytes(&self.v_table.state_v_table, tuning),
+            instance_bytes_used: self.state_bytes(),
+            id_bytes_allocated: self.instance_store.id_bytes(tuning),
+            inbox_messages: self.inbox.len(),
+            inbox_bytes_allocated: self.inbox.allocated_bytes(tuning),
+            inbox_bytes_used: self.inbox.queued_bytes(),
+            inbox_bytes_spilled: self.inbox.spilled_bytes(),
+        }
+    }
+
+    /// Does the instance exist (in this version)?
+    pub fn has_instance(&self, id: RawID) -> bool {
+        self.instance_store.contains(id)
+    }
+
+    /// Copy the state of an instance out of the class to migrate it to `to`, removing the instance.
+    /// The state of synced external classes is saved instead, and the local instance is dropped.
+    pub fn take_instance(&mut self, id: RawID, to: MachineID, world: &mut World) -> Option<Vec<u8>> {
+        if let Some(ExternalState::Excluded) = self.external {
+            return None;
+        }
+        let state = self.instance_store.take(id, to, world, &self.v_table.state_v_table)?;
+        if let Some(ExternalState::Synced { ref save, .. }) = self.external {
+            let mut aligned = AlignedState::copy_of(&state, self.v_table.state_v_table.align);
+            let state_ptr = aligned.as_mut_ptr();
+            let saved = save(state_ptr);
+            (self.v_table.state_v_table.drop)(state_ptr);
+            Some(saved)
+        } else {
+            Some(state)
+        }
+    }
+
+    /// Add an instance whose state was taken out of this class (of type `type_id`)
+    /// on another machine, giving it a new ID on this machine (allocated in turn `n_turns`).
+    /// A compact state is copied to an allocation aligned for the actor type first, since it arrives at any offset of a message.
+    pub fn add_migrated_instance(&mut self, state: &[u8], type_id: ShortTypeId, machine: MachineID, n_turns: usize) -> RawID {
+        let instance_store = &mut self.instance_store;
+        let state_v_table = &self.v_table.state_v_table;
+        let mut add = |state_ptr: *mut ()| unsafe {
+            let new_id = instance_store.allocate_id(RawID::new(type_id, 0, machine, 0), machine, n_turns);
+            (state_v_table.set_raw_id)(state_ptr, new_id);
+            instance_store.add(state_ptr, state_v_table, true);
+            new_id
+        };
+        if let Some(ExternalState::Synced { ref load, .. }) = self.external {
+            let mut new_id = None;
+            load(state, &mut |state_ptr| new_id = Some(add(state_ptr)));
+            new_id.expect("Should have added the rebuilt instance")
+        } else {
+            add(AlignedState::copy_of(state, state_v_table.align).as_mut_ptr())
+        }
+    }
+
+    /// Like `handle_messages`, but asking the debugger before and after each message.
+    /// Returns true if processing should stop.
+    pub fn handle_messages_debugged(
+        &mut self,
+        class_id: ShortTypeId,
+        message_statistics: &mut [usize],
+        world: &mut World,
+        debugger: &mut Debugger,
+        message_registry: &TypeRegistry,
+        decoders: &PacketDecoders,
+    ) -> bool {
+        for packet in self.inbox.drain() {
+            let header = PacketHeader::of(&packet, self.v_table.type_name, message_registry, decoders);
+            if !debugger.before_message(class_id, &header) {
+                debugger.hold(class_id, Self::copy_packet(&self.packet_checks, &packet), header);
+                self.instance_store.write_back_columns();
+                return true;
+            }
+            message_statistics[packet.message_type.as_usize()] += 1;
+            Self::dispatch_packet(&mut self.instance_store, &self.v_table, self.supervision, &mut self.rate_limits, packet.message_type, packet.packet_ptr, world);
+            if debugger.after_message(&header) {
+                self.instance_store.write_back_columns();
+                return true;
+            }
+        }
+        self.handle_batches(world);
+        self.instance_store.write_back_columns();
+        debugger.after_inbox(class_id)
+    }
+
+    /// Copy a packet taken from the inbox, to keep it for longer than until the next one is taken
+    fn copy_packet(packet_checks: &HashMap<ShortTypeId, PacketCheck>, packet: &DispatchablePacket) -> HeldPacket {
+        // unknown message types are only dispatched to fail, without reading the message
+        let size = packet_checks
+            .get(&packet.message_type)
+            .map_or(::std::mem::size_of::<Packet<()>>(), |check| (check.total_size_bytes)(packet.packet_ptr));
+        let mut bytes = vec![0u64; (size + 7) / 8];
+        unsafe {
+            ::std::ptr::copy_nonoverlapping(packet.packet_ptr as *const u8, bytes.as_mut_ptr() as *mut u8, size);
+        }
+        HeldPacket {
+            message_type: packet.message_type,
+            bytes,
+        }
+    }
+
+    /// Handle a message that was held back by the debugger
+    pub fn handle_held_message(&mut self, held: HeldPacket, message_statistics: &mut [usize], world: &mut World) {
+        let packet = held.dispatchable();
+        message_statistics[packet.message_type.as_usize()] += 1;
+        Self::dispatch_packet(&mut self.instance_store, &self.v_table, self.supervision, &mut self.rate_limits, packet.message_type, packet.packet_ptr, world);
+        self.instance_store.write_back_columns();
+    }
+
+    pub fn handle_messages(&mut self, message_statistics: &mut [usize], world: &mut World, profiling: bool) {
         for DispatchablePacket { message_type, packet_ptr} in self.inbox.drain() {
-            Self::dispatch_packet(&mut self.instance_store, &self.v_table, message_type, packet_ptr, world);
+            if profiling {
+                let started_ms = now_ms();
+                Self::dispatch_packet(&mut self.instance_store, &self.v_table, self.supervision, &mut self.rate_limits, message_type, packet_ptr, world);
+                self.profile.count_message(now_ms() - started_ms);
+            } else {
+                Self::dispatch_packet(&mut self.instance_store, &self.v_table, self.supervision, &mut self.rate_limits, message_type, packet_ptr, world);
+            }
             message_statistics[message_type.as_usize()] += 1;
         }
+        self.handle_batches(world);
+        self.instance_store.write_back_columns();
+    }
+
+    /// Like `handle_messages`, but passing each packet through the interceptors (if any),
+    /// and stopping after `max_messages` or once `max_ms` passed.
+    /// Returns how many messages were handled and how long that took.
+    pub fn handle_messages_limited(
+        &mut self,
+        message_statistics: &mut [usize],
+        world: &mut World,
+        interceptors: Option<(&Interceptors, &TypeRegistry, &PacketDecoders)>,
+        profiling: bool,
+        max_messages: usize,
+        max_ms: f64,
+    ) -> (usize, f64) {
+        let wants_after = interceptors.map_or(false, |(interceptors, _, _)| interceptors.wants_after());
+        if max_messages == 0 {
+            return (0, 0.0);
+        }
+        let started_ms = now_ms();
+        let mut n_handled = 0;
+        let type_name = self.v_table.type_name;
+        for packet in self.inbox.drain() {
+            let header = interceptors.map(|(_, message_registry, decoders)| {
+                PacketHeader::of(&packet, type_name, message_registry, decoders)
+            });
+            if let (Some((interceptors, _, _)), Some(header)) = (interceptors, header.as_ref()) {
+                if interceptors.before(&packet, header, world) == Intercept::Drop {
+                    continue;
+                }
+            }
+            let message_started_ms = now_ms();
+            let fate = Self::dispatch_packet(&mut self.instance_store, &self.v_table, self.supervision, &mut self.rate_limits, packet.message_type, packet.packet_ptr, world);
+            let duration_ms = now_ms() - message_started_ms;
+            if profiling {
+                self.profile.count_message(duration_ms);
+            }
+            if let (true, Some((interceptors, _, _)), Some(header)) = (wants_after, interceptors, header.as_ref()) {
+                interceptors.after(header, &Handled { duration_ms, fate }, world);
+            }
+            message_statistics[packet.message_type.as_usize()] += 1;
+            n_handled += 1;
+            if n_handled >= max_messages || now_ms() - started_ms >= max_ms {
+                break;
+            }
+        }
+        self.handle_batches(world);
+        self.instance_store.write_back_columns();
+        (n_handled, now_ms() - started_ms)
+    }
+
+    /// Handle the messages collected for batched handlers, for each recipient at once
+    fn handle_batches(&mut self, world: &mut World) {
+        if !self.has_batch_handlers {
+            return;
+        }
+        for (message_type, handler_kind) in self.v_table.message_handlers.iter().enumerate() {
+            if let MessageHandler::OnBatch{ref handler, ref batch, ..} = handler_kind {
+                if batch.borrow().is_empty() {
+                    continue;
+                }
+                let message_type = ShortTypeId::new(message_type as u16).expect("Message types have IDs");
+                for (recipient_id, correlation, messages_ptr) in batch.borrow().recipients() {
+                    let (instance_store, state_v_table, supervision) = (&mut self.instance_store, &self.v_table.state_v_table, self.supervision);
+                    handling(correlation, || {
+                        if recipient_id.instance_id == broadcast_instance_id() {
+                            instance_store.receive_broadcast(messages_ptr, world, handler, state_v_table, supervision, message_type);
+                        } else {
+                            instance_store.receive_instance(recipient_id, messages_ptr, world, handler, state_v_table, supervision, message_type);
+                        }
+                    });
+                }
+                batch.borrow_mut().clear();
+            }
+        }
     }
 
     fn dispatch_packet(
         instance_store: &mut InstanceStore,
         v_table: &ActorVTable,
+        supervision: SupervisionPolicy,
+        rate_limits: &mut Option<RateLimits>,
         message_type: ShortTypeId,
         packet_ptr: *const (),
         world: &mut World,
-    )
+    ) -> Option<Fate>
     {
         let handler_kind = &v_table.message_handlers[message_type.as_usize()];
+        let mut fate = None;
 
-        if let MessageHandler::OnMessage{ref handler, critical} = handler_kind {
+        if let MessageHandler::OnMessage{ref handler, ref forward, critical} = handler_kind {
             if *critical || !world.panic_happened() {
                 let recipient_id = unsafe {(*(packet_ptr as *const Packet<()>)).recipient_id};
                 if recipient_id.instance_id == broadcast_instance_id() {
-                    instance_store.receive_broadcast(packet_ptr, world, handler, &v_table.state_v_table);
-                } else {
-                    instance_store.receive_instance(recipient_id, packet_ptr, world, handler,  &v_table.state_v_table);
+                    world.broadcast_resolved(message_type, packet_ptr, *instance_store.n_instances);
+                    #[cfg(feature = "server")]
+                    {
+                        if world.broadcast_in_parallel(recipient_id.type_id, instance_store, packet_ptr, handler, &v_table.state_v_table, supervision, message_type) {
+                            return None;
+                        }
+                    }
+                    instance_store.receive_broadcast(packet_ptr, world, handler, &v_table.state_v_table, supervision, message_type);
+                } else if world.has_migrated(recipient_id) {
+                    world.forward_to_migrated(recipient_id, forward(packet_ptr));
+                } else if rate_limits.as_mut().map_or(true, |limits| limits.admit(recipient_id, message_type, packet_ptr, &**forward, world)) {
+                    fate = instance_store.receive_instance(recipient_id, packet_ptr, world, handler,  &v_table.state_v_table, supervision, message_type);
+                }
+            }
+        } else if let MessageHandler::OnBatch{ref batch, ref forward, critical, ..} = handler_kind {
+            if *critical || !world.panic_happened() {
+                let recipient_id = unsafe {(*(packet_ptr as *const Packet<()>)).recipient_id};
+                if world.has_migrated(recipient_id) {
+                    world.forward_to_migrated(recipient_id, forward(packet_ptr));
+                } else if recipient_id.instance_id == broadcast_instance_id() {
+                    world.broadcast_resolved(message_type, packet_ptr, *instance_store.n_instances);
+                    batch.borrow_mut().collect(packet_ptr);
+                } else if rate_limits.as_mut().map_or(true, |limits| limits.admit(recipient_id, message_type, packet_ptr, &**forward, world)) {
+                    batch.borrow_mut().collect(packet_ptr);
+                }
+            }
+        } else if let MessageHandler::OnColumns{ref handler, ref handle_all, ref forward, critical} = handler_kind {
+            if *critical || !world.panic_happened() {
+                let recipient_id = unsafe {(*(packet_ptr as *const Packet<()>)).recipient_id};
+                if recipient_id.instance_id == broadcast_instance_id() {
+                    world.broadcast_resolved(message_type, packet_ptr, *instance_store.n_instances);
+                    instance_store.receive_column_broadcast(world, &v_table.state_v_table, |actors, world| handle_all(packet_ptr, actors, world));
+                } else if world.has_migrated(recipient_id) {
+                    world.forward_to_migrated(recipient_id, forward(packet_ptr));
+                } else if rate_limits.as_mut().map_or(true, |limits| limits.admit(recipient_id, message_type, packet_ptr, &**forward, world)) {
+                    fate = instance_store.receive_instance(recipient_id, packet_ptr, world, handler,  &v_table.state_v_table, supervision, message_type);
                 }
             }
         } else if let MessageHandler::OnSpawn{spawner, critical} = handler_kind {
             if *critical || !world.panic_happened() {
                 spawner(packet_ptr, world, instance_store, &v_table.state_v_table);
             }
+        } else if let MessageHandler::OnClassMessage{handler, critical} = handler_kind {
+            if *critical || !world.panic_happened() {
+                handler(packet_ptr, world);
+            }
         } else {
             if !world.panic_happened() {
                 panic!("Handler for message {} not found in {}", message_type.as_usize(), v_table.type_name);
             }
         }
+        fate
     }
-}
\ No newline at end of file
+}
+
+/// A copy of the bytes of an actor state, in an allocation aligned for the actor type
+pub(crate) struct AlignedState {
+    ptr: *mut u8,
+    layout: ::std::alloc::Layout,
+}
+
+impl AlignedState {
+    pub fn copy_of(bytes: &[u8], align: usize) -> AlignedState {
+        let layout = ::std::alloc::Layout::from_size_align(bytes.len().max(1), align).expect("Actor states have a valid layout");
+        unsafe {
+            let ptr = ::std::alloc::alloc(layout);
+            if ptr.is_null() {
+                ::std::alloc::handle_alloc_error(layout);
+            }
+            ::std::ptr::copy_nonoverlapping(bytes.as_ptr(), ptr, bytes.len());
+            AlignedState { ptr, layout }
+        }
+    }
+
+    pub fn as_mut_ptr(&mut self) -> *mut () {
+        self.ptr as *mut ()
+    }
+}
+
+impl Drop for AlignedState {
+    fn drop(&mut self) {
+        unsafe { ::std::alloc::dealloc(self.ptr, self.layout) }
+    }
+}
@@ -0,0 +1,145 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+/// The messages an inbox wrote to a temporary file after growing beyond
+/// `Tuning::inbox_spill_bytes`, read back one at a time in the order they were written.
+///
+/// Each message is stored as `[length: u32][message type][packet]`.
+/// The file is emptied whenever all messages were read back and deleted when dropped.
+/// Messages are written right away (without buffering), so the unread ones
+/// can be copied into snapshots at any time (see `unread`).
+pub struct Spill {
+    path: PathBuf,
+    writer: File,
+    reader: BufReader<File>,
+    /// Where the oldest unread message starts in the file
+    read_offset: u64,
+    n_unread: usize,
+    unread_bytes: usize,
+}
+
+impl Spill {
+    /// Create a temporary file for the messages of the inbox `name`, numbered
+    /// so inboxes of the same name (in several systems of this process) get their own
+    pub fn create(name: &str) -> ::std::io::Result<Spill> {
+        let mut number = 0;
+        let (path, writer) = loop {
+            let path = ::std::env::temp_dir().join(format!("kay-{}-{}-{}.spill", ::std::process::id(), name, number));
+            match OpenOptions::new().create_new(true).write(true).open(&path) {
+                Ok(writer) => break (path, writer),
+                Err(ref e) if e.kind() == ErrorKind::AlreadyExists => number += 1,
+                Err(e) => return Err(e),
+            }
+        };
+        let reader = File::open(&path)?;
+        Ok(Spill {
+            path,
+            writer,
+            reader: BufReader::new(reader),
+            read_offset: 0,
+            n_unread: 0,
+            unread_bytes: 0,
+        })
+    }
+
+    /// The number of messages that weren't read back yet
+    pub fn len(&self) -> usize {
+        self.n_unread
+    }
+
+    /// The bytes of the messages that weren't read back yet
+    pub fn bytes(&self) -> usize {
+        self.unread_bytes
+    }
+
+    pub fn write(&mut self, message: &[u8]) {
+        let mut entry = Vec::with_capacity(4 + message.len());
+        entry.write_u32::<LittleEndian>(message.len() as u32).expect("Writing to a Vec can't fail");
+        entry.extend_from_slice(message);
+        self.writer.write_all(&entry).expect("Couldn't spill inbox messages to disk");
+        self.n_unread += 1;
+        self.unread_bytes += message.len();
+    }
+
+    /// Read the oldest unread message into a buffer of its own (8-byte aligned like chunks)
+    pub fn read(&mut self) -> Vec<u64> {
+        assert!(self.n_unread > 0, "No spilled messages left");
+        self.read_next().expect("Couldn't read spilled inbox messages")
+    }
+
+    fn read_next(&mut self) -> ::std::io::Result<Vec<u64>> {
+        let len = self.reader.read_u32::<LittleEndian>()? as usize;
+        let mut message = vec![0u64; (len + 7) / 8];
+        let bytes = unsafe { ::std::slice::from_raw_parts_mut(message.as_mut_ptr() as *mut u8, len) };
+        self.reader.read_exact(bytes)?;
+        self.read_offset += 4 + len as u64;
+        self.n_unread -= 1;
+        self.unread_bytes -= len;
+
+        if self.n_unread == 0 {
+            self.writer.set_len(0)?;
+            self.writer.seek(SeekFrom::Start(0))?;
+            self.reader.seek(SeekFrom::Start(0))?;
+            self.read_offset = 0;
+        }
+        Ok(message)
+    }
+
+    /// The messages that weren't read back yet, stored like in the file
+    pub fn unread(&self) -> ::std::io::Result<Vec<u8>> {
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(self.read_offset))?;
+        let mut unread = Vec::new();
+        file.read_to_end(&mut unread)?;
+        Ok(unread)
+    }
+
+    /// Split messages stored like in the file (see `unread`) up again
+    pub fn split_stored(mut stored: &[u8]) -> ::std::io::Result<Vec<&[u8]>> {
+        let mut messages = Vec::new();
+        while !stored.is_empty() {
+            let len = stored.read_u32::<LittleEndian>()? as usize;
+            if len > stored.len() {
+                return Err(::std::io::Error::new(ErrorKind::InvalidData, "Truncated spilled message"));
+            }
+            let (message, rest) = stored.split_at(len);
+            messages.push(message);
+            stored = rest;
+        }
+        Ok(messages)
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        let _ = ::std::fs::remove_file(&self.path);
+    }
+}
+
+#[test]
+fn test_spill_roundtrip() {
+    let mut spill = Spill::create("test").unwrap();
+    spill.write(&[1, 2, 3]);
+    spill.write(&[4; 20]);
+    assert_eq!((spill.len(), spill.bytes()), (2, 23));
+    let bytes = |message: &Vec<u64>, len: usize| unsafe { ::std::slice::from_raw_parts(message.as_ptr() as *const u8, len).to_vec() };
+    let first = spill.read();
+    spill.write(&[5]);
+    let unread = spill.unread().unwrap();
+    assert_eq!(Spill::split_stored(&unread).unwrap(), vec![&[4; 20][..], &[5][..]]);
+    let second = spill.read();
+    // earlier messages stay readable
+    assert_eq!((bytes(&first, 3), bytes(&second, 20)), (vec![1, 2, 3], vec![4; 20]));
+    assert_eq!(bytes(&spill.read(), 1), vec![5]);
+    assert_eq!((spill.len(), spill.bytes()), (0, 0));
+    assert_eq!(::std::fs::metadata(&spill.path).unwrap().len(), 0);
+    assert!(spill.unread().unwrap().is_empty());
+
+    spill.write(&[6, 7]);
+    assert_eq!(bytes(&spill.read(), 2), vec![6, 7]);
+
+    let other = Spill::create("test").unwrap();
+    assert!(other.path != spill.path);
+}
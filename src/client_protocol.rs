@@ -0,0 +1,370 @@
+use crate::id::RawID;
+use crate::messaging::Packet;
+use crate::topology::json_string;
+use crate::type_manifest::TypeManifestEntry;
+use crate::type_registry::short_name;
+use std::fmt::Write;
+
+/// The parts of the generated TypeScript module that don't depend on the registered types
+const TYPESCRIPT_RUNTIME: &str = r#"export interface RawID {
+    instanceId: number;
+    typeId: number;
+    machine: number;
+    version: number;
+}
+
+export const RAW_ID_BYTES = 12;
+/** The recipient and the correlation of queries, before the message of a packet */
+export const PACKET_HEADER_BYTES = 20;
+export const BROADCAST_INSTANCE_ID = 0xFFFFFFFF;
+export const BROADCAST_MACHINE_ID = 0xFFFF;
+
+export function decodeRawID(view: DataView, offset: number): RawID {
+    return {
+        instanceId: view.getUint32(offset, true),
+        typeId: view.getUint16(offset + 4, true),
+        machine: view.getUint16(offset + 6, true),
+        version: view.getUint8(offset + 8),
+    };
+}
+
+export function encodeRawID(view: DataView, offset: number, id: RawID): void {
+    view.setUint32(offset, id.instanceId, true);
+    view.setUint16(offset + 4, id.typeId, true);
+    view.setUint16(offset + 6, id.machine, true);
+    view.setUint8(offset + 8, id.version);
+}
+
+/** The first websocket message to send: a thin client handshake, with the auth token if the network needs one */
+export function encodeHandshake(authToken?: Uint8Array): Uint8Array {
+    const fields: [number, Uint8Array][] = [];
+    if (authToken) fields.push([1, authToken]);
+    fields.push([7, new Uint8Array(0)]);
+    const length = fields.reduce((sum, [, field]) => sum + 3 + field.length, 3);
+    const data = new Uint8Array(length);
+    const view = new DataView(data.buffer);
+    data.set([0xFF, 0xFF, 0xFF]);
+    let pos = 3;
+    for (const [tag, field] of fields) {
+        view.setUint8(pos, tag);
+        view.setUint16(pos + 1, field.length, true);
+        data.set(field, pos + 3);
+        pos += 3 + field.length;
+    }
+    return data;
+}
+
+/** The first websocket message received: the handshake of the machine, with the machine ID assigned to us */
+export function decodeHandshakeReply(data: ArrayBuffer): { machineId: number; assignedMachineId?: number } {
+    const view = new DataView(data);
+    let machineId = view.getUint8(0);
+    let pos = 1;
+    if (machineId === 0xFF) {
+        machineId = view.getUint16(1, true);
+        pos = 3;
+    }
+    let assignedMachineId: number | undefined;
+    while (pos + 3 <= data.byteLength) {
+        const tag = view.getUint8(pos);
+        const length = view.getUint16(pos + 1, true);
+        if (tag === 3 && length === 2) assignedMachineId = view.getUint16(pos + 3, true);
+        pos += 3 + length;
+    }
+    return { machineId, assignedMachineId };
+}
+
+/** A control frame, 0: turn marker, 1: ping, 2: pong, 3: sequence, 4: ack, 5: pause, 6: resume, 7: turn marker with state hash */
+export interface ControlFrame {
+    kind: number;
+    payload: DataView;
+}
+
+/** Ties the request of a query and its reply together, `id` is zero for packets that aren't part of one */
+export interface Correlation {
+    id: number;
+    machine: number;
+}
+
+export interface Packet {
+    messageType: number;
+    recipient: RawID;
+    correlation: Correlation;
+    /** The message, starting at the offset of its layout (including dynamic parts behind it) */
+    message: DataView;
+}
+
+/** Split a received websocket message into control frames and packets */
+export function decodeBatch(data: ArrayBuffer): (ControlFrame | Packet)[] {
+    const view = new DataView(data);
+    const decoded: (ControlFrame | Packet)[] = [];
+    let pos = 0;
+    while (pos + 4 <= data.byteLength) {
+        const length = view.getUint32(pos, true);
+        const start = pos + 4;
+        const messageType = view.getUint16(start, true);
+        if (messageType === 0) {
+            decoded.push({ kind: view.getUint8(start + 2), payload: new DataView(data, start + 3, length - 3) });
+        } else {
+            const layout = MESSAGE_TYPES_BY_ID[messageType];
+            const offset = layout ? layout.offset : PACKET_HEADER_BYTES;
+            decoded.push({
+                messageType,
+                recipient: decodeRawID(view, start + 2),
+                correlation: {
+                    id: view.getUint32(start + 2 + RAW_ID_BYTES, true),
+                    machine: view.getUint16(start + 2 + RAW_ID_BYTES + 4, true),
+                },
+                message: new DataView(data, start + 2 + offset, length - 2 - offset),
+            });
+        }
+        pos = start + length;
+    }
+    return decoded;
+}
+
+/** Encode a packet to `recipient`, with `message` in the Compact layout of its type, ready to be put in a batch.
+ * To reply to a query, pass the correlation of its request. */
+export function encodePacket(layout: MessageLayout, recipient: RawID, message: Uint8Array, correlation?: Correlation): Uint8Array {
+    const length = 2 + Math.max(layout.packetSize, layout.offset + message.length);
+    const data = new Uint8Array(4 + length);
+    const view = new DataView(data.buffer);
+    view.setUint32(0, length, true);
+    view.setUint16(4, layout.id, true);
+    encodeRawID(view, 6, recipient);
+    if (correlation) {
+        view.setUint32(6 + RAW_ID_BYTES, correlation.id, true);
+        view.setUint16(6 + RAW_ID_BYTES + 4, correlation.machine, true);
+    }
+    data.set(message, 6 + layout.offset);
+    return data;
+}
+
+/** Concatenate encoded packets into one websocket message */
+export function encodeBatch(packets: Uint8Array[]): Uint8Array {
+    const data = new Uint8Array(packets.reduce((sum, packet) => sum + packet.length, 0));
+    let pos = 0;
+    for (const packet of packets) {
+        data.set(packet, pos);
+        pos += packet.length;
+    }
+    return data;
+}
+"#;
+
+/// A message type with the layout of its packets, as seen by a client
+#[derive(Clone, Debug)]
+pub struct ClientMessageType {
+    /// The type ID, name and layout of the message itself
+    pub entry: TypeManifestEntry,
+    /// The offset of the message within its packets (behind the recipient ID and correlation)
+    pub offset: usize,
+    /// The fixed size of packets, `None` if no actor class here handles the message type
+    pub packet_size: Option<usize>,
+}
+
+/// Describes the wire protocol of a system for clients that aren't written in Rust,
+/// like lightweight bots connecting as thin clients (see `Networking::accept_thin_clients`),
+/// see `ActorSystem::client_protocol`.
+///
+/// Only the fixed parts of messages are described, since their fields aren't known to kay.
+/// Dynamic parts (like `CVec` and `CString`) follow the fixed part of a packet and are
+/// referenced from it by relative pointers, as laid out by the compact crate.
+#[derive(Clone, Debug)]
+pub struct ClientProtocol {
+    /// All actor classes and traits
+    pub actors: Vec<TypeManifestEntry>,
+    /// All message types
+    pub messages: Vec<ClientMessageType>,
+}
+
+impl ClientProtocol {
+    pub(crate) fn new(actors: Vec<TypeManifestEntry>, messages: Vec<(TypeManifestEntry, Option<usize>)>) -> ClientProtocol {
+        let header_bytes = ::std::mem::size_of::<Packet<()>>();
+        ClientProtocol {
+            actors,
+            messages: messages
+                .into_iter()
+                .map(|(entry, packet_size)| ClientMessageType {
+                    offset: (header_bytes + entry.align - 1) / entry.align * entry.align,
+                    entry,
+                    packet_size,
+                })
+                .collect(),
+        }
+    }
+
+    /// A human-readable description of the handshake, batches, packets and all types (as Markdown)
+    pub fn describe(&self) -> String {
+        let mut text = String::new();
+        let mut line = |line: String| writeln!(text, "{}", line).expect("Writing to a string");
+        line(format!(
+            "# Wire protocol ({} actor types, {} message types)\n",
+            self.actors.len(),
+            self.messages.len()
+        ));
+        line("All numbers are little endian. Connect with a binary websocket.\n".to_owned());
+        line("## Handshake\n".to_owned());
+        line(
+            "Send `[0xFF][0xFFFF: u16]` (no machine ID yet) followed by fields `[tag: u8][length: u16][bytes]`: \
+             the auth token (tag 1) if the network needs one and an empty thin client field (tag 7). \
+             The reply is the handshake of the machine, with the machine ID assigned to the client \
+             in field 3 (`u16`). Unknown fields can be skipped.\n"
+                .to_owned(),
+        );
+        line("## Batches\n".to_owned());
+        line(
+            "Every other websocket message is a batch of messages, each `[length: u32][message]`. \
+             Messages starting with a zero `u16` are control frames `[0: u16][kind: u8][payload]` and can be \
+             skipped by thin clients. All others are packets \
+             `[message type: u16][recipient: RawID][correlation ID: u32][asking machine ID: u16][padding: u16][message]`, \
+             with the message at the offset given below and at least as long as the packet size. \
+             The correlation ID is zero, unless the packet is the request of a query or the reply to one \
+             (which is sent with the correlation of the request).\n"
+                .to_owned(),
+        );
+        line(format!(
+            "A `RawID` has {} bytes: `[instance ID: u32][type ID: u16][machine ID: u16][version: u8]` and padding. \
+             The instance ID `0xFFFFFFFF` broadcasts to all instances of a type, \
+             the machine ID `0xFFFF` to all machines.\n",
+            ::std::mem::size_of::<RawID>()
+        ));
+        line("## Actor types\n".to_owned());
+        line("| ID | Name |".to_owned());
+        line("|---|---|".to_owned());
+        for actor in &self.actors {
+            line(format!("| {} | `{}` |", actor.id, actor.name));
+        }
+        line("\n## Message types\n".to_owned());
+        line("| ID | Name | Size | Alignment | Offset | Packet size | Schema hash |".to_owned());
+        line("|---|---|---|---|---|---|---|".to_owned());
+        for message in &self.messages {
+            line(format!(
+                "| {} | `{}` | {} | {} | {} | {} | `{:016x}` |",
+                message.entry.id,
+                message.entry.name,
+                message.entry.size,
+                message.entry.align,
+                message.offset,
+                message.packet_size.map_or("not handled".to_owned(), |size| size.to_string()),
+                message.entry.schema_hash
+            ));
+        }
+        text
+    }
+
+    /// A TypeScript module with the type IDs and layouts, functions for the handshake,
+    /// decoding batches and encoding packets, and decode/encode stubs for each message type,
+    /// to be filled in with the fields of its fixed part
+    pub fn typescript(&self) -> String {
+        let mut ts = String::new();
+        writeln!(ts, "// Generated by kay from the types registered in one build, don't edit.\n").expect("Writing to a string");
+        ts.push_str(TYPESCRIPT_RUNTIME);
+
+        writeln!(ts, "\nexport const ACTOR_TYPES: {{ [name: string]: number }} = {{").expect("Writing to a string");
+        for actor in &self.actors {
+            writeln!(ts, "    {}: {},", json_string(&actor.name), actor.id).expect("Writing to a string");
+        }
+        writeln!(ts, "}};").expect("Writing to a string");
+
+        writeln!(
+            ts,
+            "\nexport interface MessageLayout {{\n    id: number;\n    name: string;\n    size: number;\n    align: number;\n    \
+             offset: number;\n    packetSize: number;\n    schemaHash: string;\n}}"
+        )
+        .expect("Writing to a string");
+        writeln!(ts, "\nexport const MESSAGE_TYPES: {{ [name: string]: MessageLayout }} = {{").expect("Writing to a string");
+        for message in &self.messages {
+            writeln!(
+                ts,
+                "    {name}: {{ id: {id}, name: {name}, size: {size}, align: {align}, offset: {offset}, \
+                 packetSize: {packet_size}, schemaHash: \"{hash:016x}\" }},",
+                name = json_string(&message.entry.name),
+                id = message.entry.id,
+                size = message.entry.size,
+                align = message.entry.align,
+                offset = message.offset,
+                packet_size = message.packet_size.unwrap_or(message.offset + message.entry.size),
+                hash = message.entry.schema_hash
+            )
+            .expect("Writing to a string");
+        }
+        writeln!(ts, "}};").expect("Writing to a string");
+        writeln!(
+            ts,
+            "\nexport const MESSAGE_TYPES_BY_ID: {{ [id: number]: MessageLayout }} = {{}};\n\
+             for (const name in MESSAGE_TYPES) MESSAGE_TYPES_BY_ID[MESSAGE_TYPES[name].id] = MESSAGE_TYPES[name];"
+        )
+        .expect("Writing to a string");
+
+        for message in &self.messages {
+            let identifier = self.identifier(&message.entry);
+            writeln!(
+                ts,
+                "\n/** Decode the fixed part of `{name}` ({size} bytes) from `message` (TODO: fill in its fields) */\n\
+                 export function decode{identifier}(message: DataView): DataView {{\n    return message;\n}}\n\n\
+                 /** Encode `{name}` in its Compact layout, to be sent with `encodePacket` (TODO: fill in its fields) */\n\
+                 export function encode{identifier}(message: Uint8Array): Uint8Array {{\n    return message;\n}}",
+                name = message.entry.name,
+                size = message.entry.size,
+                identifier = identifier
+            )
+            .expect("Writing to a string");
+        }
+        ts
+    }
+
+    /// A TypeScript identifier for a message type: its short name, or with its ID
+    /// if other types have the same short name
+    fn identifier(&self, entry: &TypeManifestEntry) -> String {
+        let sanitize = |name: &str| {
+            name.split(|c: char| !c.is_ascii_alphanumeric())
+                .filter(|part| !part.is_empty())
+                .map(|part| {
+                    let mut chars = part.chars();
+                    chars
+                        .next()
+                        .map(|first| first.to_ascii_uppercase().to_string() + chars.as_str())
+                        .unwrap_or_default()
+                })
+                .collect::<String>()
+        };
+        let identifier = sanitize(short_name(&entry.name));
+        let n_same = self
+            .messages
+            .iter()
+            .filter(|other| sanitize(short_name(&other.entry.name)) == identifier)
+            .count();
+        if n_same == 1 {
+            identifier
+        } else {
+            format!("{}{}", identifier, entry.id)
+        }
+    }
+}
+
+#[test]
+fn test_client_protocol() {
+    use crate::type_manifest::TypeKind;
+
+    let protocol = ClientProtocol::new(
+        vec![TypeManifestEntry::new(TypeKind::Actor, 1, "game::Car".to_owned(), 48, 8, &[])],
+        vec![
+            (TypeManifestEntry::new(TypeKind::Message, 3, "game::Honk".to_owned(), 2, 2, &[]), Some(24)),
+            (TypeManifestEntry::new(TypeKind::Message, 4, "game::Move<u8>".to_owned(), 16, 8, &[]), None),
+            (TypeManifestEntry::new(TypeKind::Message, 5, "radio/game::Honk".to_owned(), 1, 1, &[]), Some(24)),
+        ],
+    );
+    assert_eq!(protocol.messages[0].offset, 20);
+    assert_eq!(protocol.messages[1].offset, 24);
+
+    let description = protocol.describe();
+    assert!(description.contains("| 1 | `game::Car` |"));
+    assert!(description.contains("| 4 | `game::Move<u8>` | 16 | 8 | 24 | not handled |"));
+
+    let ts = protocol.typescript();
+    assert!(ts.contains("    \"game::Car\": 1,"));
+    assert!(ts.contains("export function decodeHonk3(message: DataView)"));
+    assert!(ts.contains("export function encodeHonk5(message: Uint8Array)"));
+    assert!(ts.contains("export function decodeMoveU8(message: DataView)"));
+    assert!(ts.contains("packetSize: 40, schemaHash"));
+}
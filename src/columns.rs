@@ -0,0 +1,349 @@
+use crate::actor::Actor;
+use crate::id::{RawID, TypedID};
+use crate::messaging::Fate;
+use std::any::TypeId;
+use std::cell::{Cell, RefCell};
+use std::marker::PhantomData;
+use std::rc::Rc;
+
+/// Refers to one column of a `ColumnLayout`, to access its array in `Columns`
+pub struct Column<T> {
+    index: usize,
+    marker: PhantomData<T>,
+}
+
+impl<T> Clone for Column<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Column<T> {}
+
+/// Copies one field between an instance and the array of its column
+struct ColumnField {
+    type_id: TypeId,
+    item_size: usize,
+    read: Box<dyn Fn(*mut (), *mut u8)>,
+    write: Box<dyn Fn(*mut (), *const u8)>,
+}
+
+/// The fields of an actor class that column handlers work on, each kept in an array of its own
+/// (a structure of arrays), see `ActorSystem::set_column_layout`.
+/// Fields need to be `Copy` and aligned to at most 8 bytes.
+pub struct ColumnLayout<A: Actor> {
+    fields: Vec<ColumnField>,
+    marker: PhantomData<A>,
+}
+
+impl<A: Actor> ColumnLayout<A> {
+    /// A layout without columns yet
+    pub fn new() -> Self {
+        ColumnLayout {
+            fields: Vec::new(),
+            marker: PhantomData,
+        }
+    }
+
+    /// Add a column for the field that `field` points to in an instance
+    pub fn column<T: Copy + 'static, F: Fn(&mut A) -> &mut T + 'static>(&mut self, field: F) -> Column<T> {
+        assert!(
+            ::std::mem::align_of::<T>() <= 8,
+            "Columns can only hold fields aligned to at most 8 bytes"
+        );
+        let field = Rc::new(field);
+        let read_field = Rc::clone(&field);
+        self.fields.push(ColumnField {
+            type_id: TypeId::of::<T>(),
+            item_size: ::std::mem::size_of::<T>(),
+            read: Box::new(move |actor: *mut (), to: *mut u8| unsafe {
+                (to as *mut T).write(*read_field(&mut *(actor as *mut A)))
+            }),
+            write: Box::new(move |actor: *mut (), from: *const u8| unsafe {
+                *field(&mut *(actor as *mut A)) = *(from as *const T)
+            }),
+        });
+        Column {
+            index: self.fields.len() - 1,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<A: Actor> Default for ColumnLayout<A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The columns of all instances a column handler works on, in parallel arrays:
+/// the values at index `i` of each column belong to the instance `ids()[i]`.
+/// Loops over whole columns can be autovectorized, unlike loops over the instances.
+pub struct Columns {
+    ids: Vec<RawID>,
+    /// Each column as 8-byte words, to align all fields
+    data: Vec<Vec<u64>>,
+    type_ids: Vec<TypeId>,
+    dying: Vec<bool>,
+}
+
+impl Columns {
+    fn new(fields: &[ColumnField]) -> Columns {
+        Columns {
+            ids: Vec::new(),
+            data: fields.iter().map(|_| Vec::new()).collect(),
+            type_ids: fields.iter().map(|field| field.type_id).collect(),
+            dying: Vec::new(),
+        }
+    }
+
+    /// The number of instances
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    /// Are there no instances?
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// The IDs of the instances, in the order of their values in the columns
+    pub fn ids(&self) -> &[RawID] {
+        &self.ids
+    }
+
+    fn check<T: 'static>(&self, column: Column<T>) {
+        assert_eq!(
+            self.type_ids[column.index],
+            TypeId::of::<T>(),
+            "Column is from another layout"
+        );
+    }
+
+    /// The values of `column` for all instances
+    pub fn get<T: Copy + 'static>(&self, column: Column<T>) -> &[T] {
+        self.check(column);
+        unsafe { ::std::slice::from_raw_parts(self.data[column.index].as_ptr() as *const T, self.len()) }
+    }
+
+    /// The values of `column` for all instances, to change them
+    pub fn get_mut<T: Copy + 'static>(&mut self, column: Column<T>) -> &mut [T] {
+        self.check(column);
+        let len = self.len();
+        unsafe { ::std::slice::from_raw_parts_mut(self.data[column.index].as_mut_ptr() as *mut T, len) }
+    }
+
+    /// The values of two different columns, to change one based on the other
+    pub fn get_pair_mut<T: Copy + 'static, U: Copy + 'static>(
+        &mut self,
+        a: Column<T>,
+        b: Column<U>,
+    ) -> (&mut [T], &mut [U]) {
+        assert_ne!(a.index, b.index, "Can't borrow the same column twice");
+        self.check(a);
+        self.check(b);
+        let len = self.len();
+        let a = self.data[a.index].as_mut_ptr() as *mut T;
+        let b = self.data[b.index].as_mut_ptr() as *mut U;
+        unsafe {
+            (
+                ::std::slice::from_raw_parts_mut(a, len),
+                ::std::slice::from_raw_parts_mut(b, len),
+            )
+        }
+    }
+
+    /// Let the instance at `index` die after the handler
+    pub fn kill(&mut self, index: usize) {
+        self.dying[index] = true;
+    }
+}
+
+/// The column layout of a class, with the arrays of all its instances, kept by its instance store.
+/// Broadcasts to column handlers load the columns from the instances and leave them loaded,
+/// so following broadcasts work on them without copying. The instance store writes them back
+/// before the instances are used or changed in any other way, and once the inbox of the class
+/// was handled. Messages to single instances copy the fields of their recipient.
+pub(crate) struct ColumnStorage {
+    fields: Vec<ColumnField>,
+    get_raw_id: fn(*const ()) -> RawID,
+    columns: RefCell<Columns>,
+    /// Are the columns of all instances loaded (and newer than the fields in the instances)?
+    loaded: Cell<bool>,
+}
+
+impl ColumnStorage {
+    pub fn new<A: Actor>(layout: ColumnLayout<A>) -> ColumnStorage {
+        fn get_raw_id<A: Actor>(actor: *const ()) -> RawID {
+            unsafe { (*(actor as *const A)).id().as_raw() }
+        }
+        ColumnStorage {
+            columns: RefCell::new(Columns::new(&layout.fields)),
+            fields: layout.fields,
+            get_raw_id: get_raw_id::<A>,
+            loaded: Cell::new(false),
+        }
+    }
+
+    pub fn is_loaded(&self) -> bool {
+        self.loaded.get()
+    }
+
+    fn read(&self, columns: &mut Columns, actors: &[*mut ()]) {
+        columns.ids.clear();
+        columns.ids.extend(actors.iter().map(|&actor| (self.get_raw_id)(actor)));
+        for (field, data) in self.fields.iter().zip(columns.data.iter_mut()) {
+            data.clear();
+            data.resize((actors.len() * field.item_size + 7) / 8, 0);
+            let base = data.as_mut_ptr() as *mut u8;
+            for (i, &actor) in actors.iter().enumerate() {
+                (field.read)(actor, unsafe { base.add(i * field.item_size) });
+            }
+        }
+    }
+
+    fn write(&self, columns: &Columns, actors: &[*mut ()]) {
+        for (field, data) in self.fields.iter().zip(columns.data.iter()) {
+            let base = data.as_ptr() as *const u8;
+            for (i, &actor) in actors.iter().enumerate() {
+                (field.write)(actor, unsafe { base.add(i * field.item_size) });
+            }
+        }
+    }
+
+    fn fates(columns: &mut Columns, handle: impl FnOnce(&mut Columns)) -> Vec<Fate> {
+        columns.dying.clear();
+        columns.dying.resize(columns.ids.len(), false);
+        handle(columns);
+        columns
+            .dying
+            .iter()
+            .map(|&dying| if dying { Fate::Die } else { Fate::Live })
+            .collect()
+    }
+
+    /// Let `handle` work on the columns of all instances (`actors`, in storage order),
+    /// loading them first unless they are loaded already, and return the fates of the actors.
+    /// The columns stay loaded.
+    pub fn handle_all<F: FnOnce(&mut Columns)>(&self, actors: &[*mut ()], handle: F) -> Vec<Fate> {
+        let mut columns = self.columns.borrow_mut();
+        if !self.loaded.get() {
+            self.read(&mut columns, actors);
+            self.loaded.set(true);
+        }
+        Self::fates(&mut columns, handle)
+    }
+
+    /// Copy the columns of one instance into the arrays, let `handle` work on them
+    /// and copy them back, returning its fate. The columns can't be loaded.
+    pub fn handle_one<F: FnOnce(&mut Columns)>(&self, actor: *mut (), handle: F) -> Fate {
+        assert!(!self.loaded.get(), "Columns should be written back before handling single instances");
+        let mut columns = self.columns.borrow_mut();
+        self.read(&mut columns, &[actor]);
+        let fate = Self::fates(&mut columns, handle).pop().expect("Should have the fate of the one instance");
+        self.write(&columns, &[actor]);
+        fate
+    }
+
+    /// Write the loaded columns back into all instances (`actors`, in the storage order
+    /// they were loaded in), if they are loaded
+    pub fn write_back(&self, actors: &[*mut ()]) {
+        if self.loaded.replace(false) {
+            self.write(&self.columns.borrow(), actors);
+        }
+    }
+}
+
+#[test]
+fn test_columns_stay_loaded_between_broadcasts() {
+    use crate::actor_system::ActorSystem;
+    use crate::test_support::{local_system, Add, Counter};
+
+    /// Makes counters multiply their count by this
+    #[derive(Compact, Clone)]
+    struct Multiply(u32);
+
+    let accesses = Rc::new(Cell::new(0));
+    let mut system = local_system();
+    system.register::<Counter>();
+    let mut layout = ColumnLayout::<Counter>::new();
+    let counted_accesses = Rc::clone(&accesses);
+    let count = layout.column(move |counter: &mut Counter| {
+        counted_accesses.set(counted_accesses.get() + 1);
+        &mut counter.count
+    });
+    system.set_column_layout(layout);
+    system.add_column_handler::<Counter, Add, _>(
+        move |&Add(n), columns, _| {
+            for value in columns.get_mut(count) {
+                *value += n;
+            }
+        },
+        false,
+    );
+    system.add_column_handler::<Counter, Multiply, _>(
+        move |&Multiply(n), columns, _| {
+            for value in columns.get_mut(count) {
+                *value *= n;
+            }
+        },
+        false,
+    );
+    let counters = system.spawn_many(vec![Counter::new(1), Counter::new(2), Counter::new(3)]);
+    let counts = |system: &mut ActorSystem| {
+        let mut counts = Vec::new();
+        system.world().for_each_instance::<Counter, _>(|counter| counts.push(counter.count));
+        counts.sort();
+        counts
+    };
+
+    // the broadcasts share one load and one write back of the column
+    let all_counters = system.world().local_broadcast::<Counter>();
+    system.send(all_counters, Add(1));
+    system.send(all_counters, Multiply(2));
+    system.send(all_counters, Add(1));
+    system.process_all_messages();
+    assert_eq!(counts(&mut system), vec![5, 7, 9]);
+    assert_eq!(accesses.get(), 2 * 3);
+
+    // a message to one instance writes the loaded column back first
+    accesses.set(0);
+    system.send(all_counters, Add(1));
+    system.send(counters[0].as_raw(), Multiply(2));
+    system.send(all_counters, Add(1));
+    system.process_all_messages();
+    assert_eq!(counts(&mut system), vec![9, 11, 13]);
+    assert_eq!(accesses.get(), 2 * 3 + 2 + 2 * 3);
+}
+
+#[test]
+fn test_instances_killed_from_columns() {
+    use crate::test_support::{local_system, Add, Counter};
+
+    let mut system = local_system();
+    system.register::<Counter>();
+    let mut layout = ColumnLayout::<Counter>::new();
+    let count = layout.column(|counter: &mut Counter| &mut counter.count);
+    system.set_column_layout(layout);
+    system.add_column_handler::<Counter, Add, _>(
+        move |&Add(n), columns, _| {
+            for index in 0..columns.len() {
+                columns.get_mut(count)[index] += n;
+                if columns.get(count)[index] % 2 == 0 {
+                    columns.kill(index);
+                }
+            }
+        },
+        false,
+    );
+    system.spawn_many((0..6).map(Counter::new));
+
+    let all_counters = system.world().local_broadcast::<Counter>();
+    system.send(all_counters, Add(1));
+    system.send(all_counters, Add(2));
+    system.process_all_messages();
+    let mut counts = Vec::new();
+    system.world().for_each_instance::<Counter, _>(|counter| counts.push(counter.count));
+    counts.sort();
+    assert_eq!(counts, vec![3, 5, 7]);
+}
@@ -0,0 +1,115 @@
+/// A command of the actor console, see `ActorSystem::run_console_command`
+#[derive(PartialEq, Debug)]
+pub(crate) enum ConsoleCommand<'a> {
+    /// `send <recipient> <message type> [<message>]`
+    Send {
+        recipient: &'a str,
+        message_type: &'a str,
+        /// The message as JSON
+        json: String,
+    },
+    /// `types`, listing all message types that can be sent
+    Types,
+}
+
+pub(crate) fn parse_command(command: &str) -> Result<ConsoleCommand<'_>, String> {
+    let command = command.trim();
+    let (verb, rest) = split_word(command);
+    match verb {
+        "send" => {
+            let (recipient, rest) = split_word(rest);
+            let (message_type, message) = split_word(rest);
+            if recipient.is_empty() || message_type.is_empty() {
+                return Err("Usage: send <recipient> <message type> [<message>]".to_owned());
+            }
+            Ok(ConsoleCommand::Send {
+                recipient,
+                message_type,
+                json: if message.is_empty() {
+                    "null".to_owned()
+                } else {
+                    quote_keys(message)
+                },
+            })
+        }
+        "types" => Ok(ConsoleCommand::Types),
+        "" => Err("Empty command".to_owned()),
+        _ => Err(format!("Unknown command {}, commands are send and types", verb)),
+    }
+}
+
+fn split_word(s: &str) -> (&str, &str) {
+    let s = s.trim_start();
+    match s.find(char::is_whitespace) {
+        Some(end) => (&s[..end], s[end..].trim_start()),
+        None => (s, ""),
+    }
+}
+
+/// Make messages easier to type by allowing unquoted keys like `{x: 10, y: 20}`,
+/// quoting identifiers followed by `:` outside of strings
+fn quote_keys(message: &str) -> String {
+    let mut quoted = String::with_capacity(message.len());
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut chars = message.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        if in_string {
+            quoted.push(c);
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+            }
+        } else if c == '"' {
+            in_string = true;
+            quoted.push(c);
+        } else if c.is_alphabetic() || c == '_' {
+            let mut end = i + c.len_utf8();
+            while let Some(&(j, next)) = chars.peek() {
+                if next.is_alphanumeric() || next == '_' {
+                    end = j + next.len_utf8();
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            let word = &message[i..end];
+            if message[end..].trim_start().starts_with(':') {
+                quoted.push('"');
+                quoted.push_str(word);
+                quoted.push('"');
+            } else {
+                quoted.push_str(word);
+            }
+        } else {
+            quoted.push(c);
+        }
+    }
+    quoted
+}
+
+#[test]
+fn test_console_commands() {
+    assert_eq!(
+        parse_command("send Car.local#42@0 SetDestination {x: 10, y: 20, \"name\": \"a: b\"}"),
+        Ok(ConsoleCommand::Send {
+            recipient: "Car.local#42@0",
+            message_type: "SetDestination",
+            json: "{\"x\": 10, \"y\": 20, \"name\": \"a: b\"}".to_owned(),
+        })
+    );
+    assert_eq!(
+        parse_command("  send Car.broadcast@all Honk "),
+        Ok(ConsoleCommand::Send {
+            recipient: "Car.broadcast@all",
+            message_type: "Honk",
+            json: "null".to_owned(),
+        })
+    );
+    assert_eq!(parse_command("types"), Ok(ConsoleCommand::Types));
+    assert!(parse_command("send Car.local#42@0").is_err());
+    assert!(parse_command("drive").is_err());
+}
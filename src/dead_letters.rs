@@ -0,0 +1,50 @@
+use crate::id::RawID;
+use std::collections::VecDeque;
+
+/// How many undelivered messages are kept until they are taken
+const MAX_DEAD_LETTERS: usize = 1000;
+
+/// A message that couldn't be delivered because its recipient doesn't exist,
+/// for example because it died and the message was sent using its stale ID
+#[derive(Clone, Debug)]
+pub struct DeadLetter {
+    /// The ID the message was addressed to
+    pub recipient: RawID,
+    /// The name of the message type
+    pub message_type: String,
+    /// The message rendered as text, if its type opted in with `ActorSystem::decode_packets`
+    pub message: Option<String>,
+    /// The networking turn the message should have been handled in
+    pub n_turns: usize,
+}
+
+/// The most recent undelivered messages, see `ActorSystem::take_dead_letters`
+pub(crate) struct DeadLetters {
+    letters: VecDeque<DeadLetter>,
+    total: usize,
+}
+
+impl DeadLetters {
+    pub fn new() -> DeadLetters {
+        DeadLetters {
+            letters: VecDeque::new(),
+            total: 0,
+        }
+    }
+
+    pub fn push(&mut self, letter: DeadLetter) {
+        if self.letters.len() == MAX_DEAD_LETTERS {
+            self.letters.pop_front();
+        }
+        self.letters.push_back(letter);
+        self.total += 1;
+    }
+
+    pub fn take(&mut self) -> Vec<DeadLetter> {
+        self.letters.drain(..).collect()
+    }
+
+    pub fn total(&self) -> usize {
+        self.total
+    }
+}
@@ -0,0 +1,331 @@
+use crate::class::inbox::DispatchablePacket;
+use crate::id::RawID;
+use crate::messaging::{Message, Packet};
+use crate::type_registry::{ShortTypeId, TypeRegistry};
+use std::collections::HashMap;
+
+/// The decoded header of a packet that is about to be, or was just handled,
+/// passed to the callback set with `ActorSystem::on_debug_stop`
+#[derive(Clone, Debug)]
+pub struct PacketHeader {
+    /// The recipient of the packet
+    pub recipient: RawID,
+    /// The name of the class whose inbox the packet is in
+    pub recipient_class: String,
+    /// The type ID of the message
+    pub message_type_id: u16,
+    /// The name of the message type
+    pub message_type: String,
+    /// The message rendered as text, if its type opted in with `ActorSystem::decode_packets`
+    pub message: Option<String>,
+}
+
+impl PacketHeader {
+    pub(crate) fn of(
+        packet: &DispatchablePacket,
+        recipient_class: &str,
+        message_registry: &TypeRegistry,
+        decoders: &PacketDecoders,
+    ) -> PacketHeader {
+        PacketHeader {
+            recipient: unsafe { (*(packet.packet_ptr as *const Packet<()>)).recipient_id },
+            recipient_class: recipient_class.to_owned(),
+            message_type_id: packet.message_type.as_u16(),
+            message_type: message_registry.get_name(packet.message_type).clone(),
+            message: decoders.decode(packet.message_type, packet.packet_ptr),
+        }
+    }
+}
+
+type DecodeFn = Box<dyn Fn(*const ()) -> String>;
+
+/// Renders the messages of packets as text for debugging, for the message types
+/// that opted in with `ActorSystem::decode_packets`
+pub(crate) struct PacketDecoders {
+    decoders: HashMap<ShortTypeId, DecodeFn>,
+}
+
+impl PacketDecoders {
+    pub fn new() -> PacketDecoders {
+        PacketDecoders {
+            decoders: HashMap::new(),
+        }
+    }
+
+    pub fn add<M: Message, F: Fn(&M) -> String + 'static>(&mut self, message_type: ShortTypeId, render: F) {
+        self.decoders.insert(
+            message_type,
+            Box::new(move |packet_ptr| render(unsafe { &(*(packet_ptr as *const Packet<M>)).message })),
+        );
+    }
+
+    pub fn decode(&self, message_type: ShortTypeId, packet_ptr: *const ()) -> Option<String> {
+        self.decoders.get(&message_type).map(|decode| decode(packet_ptr))
+    }
+}
+
+/// Refers to a breakpoint set with `ActorSystem::add_breakpoint`
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct BreakpointID(u32);
+
+/// Why message processing stopped in debug mode
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum DebugStop {
+    /// A breakpoint was hit before the message was handled
+    Breakpoint(BreakpointID),
+    /// The message was handled as part of a step
+    Stepped,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Step {
+    Message,
+    Inbox,
+}
+
+struct Breakpoint {
+    id: BreakpointID,
+    recipient_type: ShortTypeId,
+    message_type: ShortTypeId,
+    condition: Box<dyn Fn(&PacketHeader) -> bool>,
+}
+
+type StopCallback = Box<dyn FnMut(&PacketHeader, DebugStop)>;
+
+/// A copy of a packet that is held back by the debugger, since the dequeued packet itself
+/// is only valid until the next packet is taken from the inbox (spilled packets even less)
+pub(crate) struct HeldPacket {
+    pub message_type: ShortTypeId,
+    pub bytes: Vec<u64>,
+}
+
+impl HeldPacket {
+    /// The copy, to be dispatched while it is alive
+    pub fn dispatchable(&self) -> DispatchablePacket {
+        DispatchablePacket {
+            message_type: self.message_type,
+            packet_ptr: self.bytes.as_ptr() as *const (),
+        }
+    }
+}
+
+/// The state of debug mode: whether message processing is paused, pending steps and breakpoints.
+/// A message that hit a breakpoint (or was next when pausing) is held back as a copy.
+pub(crate) struct Debugger {
+    paused: bool,
+    step: Option<Step>,
+    /// The class an inbox step is limited to, once it started
+    step_class: Option<ShortTypeId>,
+    breakpoints: Vec<Breakpoint>,
+    next_breakpoint_id: u32,
+    on_stop: Option<StopCallback>,
+    held: Option<(ShortTypeId, HeldPacket, PacketHeader)>,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger {
+            paused: false,
+            step: None,
+            step_class: None,
+            breakpoints: Vec::new(),
+            next_breakpoint_id: 0,
+            on_stop: None,
+            held: None,
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn pause(&mut self) {
+        self.paused = true;
+        self.step = None;
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+        self.step = None;
+    }
+
+    pub fn step_message(&mut self) {
+        self.paused = true;
+        self.step = Some(Step::Message);
+        self.step_class = None;
+    }
+
+    pub fn step_inbox(&mut self) {
+        self.paused = true;
+        self.step = Some(Step::Inbox);
+        self.step_class = None;
+    }
+
+    pub fn on_stop(&mut self, callback: StopCallback) {
+        self.on_stop = Some(callback);
+    }
+
+    pub fn add_breakpoint(
+        &mut self,
+        recipient_type: ShortTypeId,
+        message_type: ShortTypeId,
+        condition: Box<dyn Fn(&PacketHeader) -> bool>,
+    ) -> BreakpointID {
+        let id = BreakpointID(self.next_breakpoint_id);
+        self.next_breakpoint_id += 1;
+        self.breakpoints.push(Breakpoint {
+            id,
+            recipient_type,
+            message_type,
+            condition,
+        });
+        id
+    }
+
+    pub fn remove_breakpoint(&mut self, id: BreakpointID) -> bool {
+        let n_breakpoints = self.breakpoints.len();
+        self.breakpoints.retain(|breakpoint| breakpoint.id != id);
+        self.breakpoints.len() < n_breakpoints
+    }
+
+    /// Processing only continues for the class of an inbox step, once it started
+    pub fn only_class(&self) -> Option<ShortTypeId> {
+        match self.step {
+            Some(Step::Inbox) => self.step_class,
+            _ => None,
+        }
+    }
+
+    fn stopped(&mut self, header: &PacketHeader, reason: DebugStop) {
+        if let Some(ref mut on_stop) = self.on_stop {
+            on_stop(header, reason);
+        }
+    }
+
+    /// Decide whether a message may be handled now, returns false if it needs to be held back
+    pub fn before_message(&mut self, class_id: ShortTypeId, header: &PacketHeader) -> bool {
+        if self.paused {
+            if let Some(step) = self.step {
+                if step == Step::Inbox {
+                    self.step_class = Some(class_id);
+                }
+                return true;
+            }
+            return false;
+        }
+
+        let hit = self
+            .breakpoints
+            .iter()
+            .find(|breakpoint| {
+                breakpoint.recipient_type == header.recipient.type_id
+                    && breakpoint.message_type.as_u16() == header.message_type_id
+                    && (breakpoint.condition)(header)
+            })
+            .map(|breakpoint| breakpoint.id);
+
+        if let Some(id) = hit {
+            self.paused = true;
+            self.stopped(header, DebugStop::Breakpoint(id));
+            false
+        } else {
+            true
+        }
+    }
+
+    /// Returns true if processing should stop after this message
+    pub fn after_message(&mut self, header: &PacketHeader) -> bool {
+        match self.step {
+            Some(Step::Message) => {
+                self.step = None;
+                self.stopped(header, DebugStop::Stepped);
+                true
+            }
+            Some(Step::Inbox) => {
+                self.stopped(header, DebugStop::Stepped);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Returns true if processing should stop after the inbox of this class was handled
+    pub fn after_inbox(&mut self, class_id: ShortTypeId) -> bool {
+        if self.step == Some(Step::Inbox) && self.step_class == Some(class_id) {
+            self.step = None;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Can anything be handled right now?
+    pub fn may_continue(&self) -> bool {
+        !self.paused || self.step.is_some()
+    }
+
+    /// The header of the message that is held back, if any
+    pub fn held_header(&self) -> Option<&PacketHeader> {
+        self.held.as_ref().map(|(_, _, header)| header)
+    }
+
+    pub fn hold(&mut self, class_id: ShortTypeId, packet: HeldPacket, header: PacketHeader) {
+        self.held = Some((class_id, packet, header));
+    }
+
+    /// Take the message that was held back, if it may be handled now
+    pub fn take_held(&mut self) -> Option<(ShortTypeId, HeldPacket, PacketHeader)> {
+        if !self.may_continue() {
+            return None;
+        }
+        let (class_id, packet, header) = self.held.take()?;
+        if self.step == Some(Step::Inbox) {
+            self.step_class = Some(class_id);
+        }
+        Some((class_id, packet, header))
+    }
+}
+
+#[test]
+fn test_held_spilled_packets_are_copied() {
+    use crate::actor_system::ActorSystem;
+    use crate::compact::CVec;
+    use crate::id::TypedID;
+    use crate::messaging::Fate;
+    use crate::test_support::{local_networking, Counter};
+    use crate::tuning::Tuning;
+
+    #[derive(Compact, Clone)]
+    struct AddAll {
+        amounts: CVec<u32>,
+    }
+
+    // every packet is spilled, so the inbox overwrites the taken packet with the next one
+    let tuning = Tuning {
+        inbox_spill_bytes: Some(0),
+        ..Tuning::default()
+    };
+    let mut system = ActorSystem::new(local_networking(), tuning);
+    system.register::<Counter>();
+    system.add_handler::<Counter, _, _>(
+        |add: &AddAll, counter, _| {
+            counter.count += add.amounts.iter().sum::<u32>();
+            Fate::Live
+        },
+        false,
+    );
+    let counter = system.spawn_many(vec![Counter::new(0)])[0];
+    system.enable_debugging();
+    let breakpoint = system.add_breakpoint::<Counter, AddAll, _>(|_| true);
+
+    system.send(counter.as_raw(), AddAll { amounts: vec![1, 2, 3].into() });
+    system.send(counter.as_raw(), AddAll { amounts: vec![10, 20].into() });
+    system.process_all_messages();
+    assert_eq!(system.instance::<Counter>(counter).unwrap().count, 0);
+
+    system.remove_breakpoint(breakpoint);
+    system.debug_resume();
+    system.send(counter.as_raw(), AddAll { amounts: vec![100, 200, 300, 400].into() });
+    system.process_all_messages();
+    assert_eq!(system.instance::<Counter>(counter).unwrap().count, 1036);
+}
@@ -0,0 +1,128 @@
+use std::collections::{HashMap, HashSet};
+use std::hash::{BuildHasherDefault, Hasher};
+
+/// A source of behaviour that can differ between machines running the same turns.
+/// Iterating `std` hash maps isn't detected, use `DeterministicHashMap` instead.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub enum NondeterminismSource {
+    /// Depending on the local clock, like messages sent after a duration or timeouts
+    WallClock,
+    /// Any other source, named by the reporter
+    Other(String),
+}
+
+/// A use of a source of nondeterminism, recorded by the audit mode
+/// (see `ActorSystem::enable_determinism_audit`)
+#[derive(Clone, Debug)]
+pub struct NondeterminismReport {
+    /// The networking turn it happened in
+    pub n_turns: usize,
+    /// The actor class whose handler caused it, `None` if outside of handlers
+    pub class: Option<String>,
+    /// What kind of source it was
+    pub source: NondeterminismSource,
+    /// Where it happened, like the kay feature or the location given to `World::audit_nondeterminism`
+    pub context: String,
+}
+
+impl ::std::fmt::Display for NondeterminismReport {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(
+            f,
+            "Turn {}: {:?} in {} ({})",
+            self.n_turns,
+            self.source,
+            self.class.as_ref().map_or("no handler", String::as_str),
+            self.context
+        )
+    }
+}
+
+/// Collects the reported uses of sources of nondeterminism, each distinct use only once
+pub(crate) struct DeterminismAudit {
+    reports: Vec<NondeterminismReport>,
+    seen: HashSet<(Option<String>, NondeterminismSource, String)>,
+    panic_on_report: bool,
+}
+
+impl DeterminismAudit {
+    pub fn new(panic_on_report: bool) -> DeterminismAudit {
+        DeterminismAudit {
+            reports: Vec::new(),
+            seen: HashSet::new(),
+            panic_on_report,
+        }
+    }
+
+    pub fn report(&mut self, report: NondeterminismReport) {
+        if self.panic_on_report {
+            panic!("Nondeterminism detected: {}", report);
+        }
+        if self
+            .seen
+            .insert((report.class.clone(), report.source.clone(), report.context.clone()))
+        {
+            self.reports.push(report);
+        }
+    }
+
+    pub fn take_reports(&mut self) -> Vec<NondeterminismReport> {
+        self.reports.drain(..).collect()
+    }
+}
+
+/// A hasher without a random seed (64 bit FNV-1a), so hash maps using it iterate
+/// in the same order on all machines, given the same insertions and removals
+#[derive(Clone, Copy)]
+pub struct DeterministicHasher(u64);
+
+impl Default for DeterministicHasher {
+    fn default() -> Self {
+        DeterministicHasher(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for DeterministicHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// A `HashMap` that iterates in the same order on all machines, see `DeterministicHasher`
+pub type DeterministicHashMap<K, V> = HashMap<K, V, BuildHasherDefault<DeterministicHasher>>;
+
+/// A `HashSet` that iterates in the same order on all machines, see `DeterministicHasher`
+pub type DeterministicHashSet<K> = HashSet<K, BuildHasherDefault<DeterministicHasher>>;
+
+#[test]
+fn test_deterministic_hash_map_order() {
+    let build = || {
+        let mut map = DeterministicHashMap::default();
+        for i in 0..100u32 {
+            map.insert(i.wrapping_mul(2_654_435_761), i);
+        }
+        map.into_iter().map(|(_, i)| i).collect::<Vec<_>>()
+    };
+    assert_eq!(build(), build());
+
+    let mut audit = DeterminismAudit::new(false);
+    let report = |context: &str| NondeterminismReport {
+        n_turns: 3,
+        class: Some("Car".to_owned()),
+        source: NondeterminismSource::WallClock,
+        context: context.to_owned(),
+    };
+    audit.report(report("send_after"));
+    audit.report(report("send_after"));
+    audit.report(report("query timeout"));
+    let reports = audit.take_reports();
+    assert_eq!(reports.len(), 2);
+    assert_eq!(reports[0].to_string(), "Turn 3: WallClock in Car (send_after)");
+}
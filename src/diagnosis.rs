@@ -0,0 +1,483 @@
+use crate::id::{MachineID, RawID};
+use crate::type_registry::ShortTypeId;
+use byteorder::{ByteOrder, LittleEndian};
+use compact::{CString, CVec};
+use crate::state_hash::xxh64;
+use std::collections::{HashMap, VecDeque};
+
+/// How many consecutive instance IDs of a class are hashed together into one chunk hash
+pub(crate) const INSTANCES_PER_CHUNK: usize = 256;
+/// How many turns of chunk hashes are kept to compare with peers after a desync
+const KEPT_TURNS: usize = 64;
+/// How many turns of instance hashes are kept, so the instances of diverged chunks
+/// can be compared after the first turn that differed, if the desync is diagnosed quickly
+const KEPT_INSTANCE_TURNS: usize = 8;
+/// How many turns after asking a peer the instances of diverged chunks are compared
+/// if their hashes after the first turn that differed aren't kept anymore,
+/// so the peer is very likely not past that turn yet when it gets the request
+pub(crate) const INSTANCE_HASHES_TURNS_AHEAD: usize = 16;
+
+/// A chunk of the instances of a class, see `INSTANCES_PER_CHUNK`
+#[derive(Compact, Clone, Copy, PartialEq)]
+pub(crate) struct ChunkRef {
+    pub class: ShortTypeId,
+    pub chunk: u32,
+}
+
+impl ChunkRef {
+    fn of(id: RawID) -> ChunkRef {
+        ChunkRef {
+            class: id.type_id,
+            chunk: id.instance_id / INSTANCES_PER_CHUNK as u32,
+        }
+    }
+}
+
+#[derive(Compact, Clone, Copy)]
+pub(crate) struct ChunkHash {
+    pub chunk: ChunkRef,
+    pub hash: u64,
+}
+
+#[derive(Compact, Clone, Copy)]
+pub(crate) struct InstanceHash {
+    pub id: RawID,
+    pub hash: u64,
+}
+
+/// Sent to the system services of a desynced peer to get its chunk hashes after a turn
+#[derive(Compact, Clone)]
+pub(crate) struct ChunkHashesRequest {
+    pub requester: MachineID,
+    pub n_turns: u64,
+}
+
+/// Sent back by the peer, `kept` is false if the turn was too long ago
+#[derive(Compact, Clone)]
+pub(crate) struct ChunkHashesReply {
+    pub machine: MachineID,
+    pub n_turns: u64,
+    pub kept: bool,
+    pub hashes: CVec<ChunkHash>,
+}
+
+crate::compact_bounds!(ChunkHashesReply { hashes });
+
+/// Sent to the system services of a desynced peer to compare the instances
+/// of diverged chunks after a turn whose instance hashes are kept, or that it didn't finish yet
+#[derive(Compact, Clone)]
+pub(crate) struct InstanceHashesRequest {
+    pub requester: MachineID,
+    pub n_turns: u64,
+    pub chunks: CVec<ChunkRef>,
+}
+
+crate::compact_bounds!(InstanceHashesRequest { chunks });
+
+/// Sent back by the peer right away or after finishing the turn,
+/// `in_time` is false if it was already past that turn and didn't keep its instance hashes
+#[derive(Compact, Clone)]
+pub(crate) struct InstanceHashesReply {
+    pub machine: MachineID,
+    pub n_turns: u64,
+    pub in_time: bool,
+    pub hashes: CVec<InstanceHash>,
+}
+
+crate::compact_bounds!(InstanceHashesReply { hashes });
+
+/// Instances of one actor class with consecutive IDs, whose state differed between machines
+#[derive(Compact, Clone)]
+pub struct DivergedChunk {
+    /// The name of the actor class
+    pub class: CString,
+    /// The first instance ID of the chunk
+    pub first_instance_id: u32,
+    /// The last instance ID of the chunk
+    pub last_instance_id: u32,
+}
+
+crate::compact_bounds!(DivergedChunk { class });
+
+/// Sent to the recipient given to `ActorSystem::networking_diagnose_desync`
+#[derive(Compact, Clone)]
+pub struct DesyncDiagnosis {
+    /// The peer whose state differed
+    pub machine: MachineID,
+    /// The first turn after which the states differed
+    pub turn: u64,
+    /// The chunks of instances that first differed, after `turn`
+    pub chunks: CVec<DivergedChunk>,
+    /// The turn after which the instances of these chunks were compared, which is `turn`
+    /// unless the desync was diagnosed too late, when it is a later turn
+    pub instances_turn: u64,
+    /// The instances of these chunks that differed after `instances_turn`,
+    /// or that only existed on one of the machines
+    pub instances: CVec<RawID>,
+    /// False if `turn` was too long ago to compare chunks,
+    /// or the peer was already past `instances_turn`
+    pub complete: bool,
+}
+
+crate::compact_bounds!(DesyncDiagnosis { chunks, instances });
+
+/// A diagnosis that is done, to be sent as a `DesyncDiagnosis`
+/// by the `ActorSystem`, which knows the names of classes
+pub(crate) struct FinishedDiagnosis {
+    pub recipient: RawID,
+    pub machine: MachineID,
+    pub turn: usize,
+    pub chunks: Vec<ChunkRef>,
+    pub instances_turn: usize,
+    pub instances: Vec<RawID>,
+    pub complete: bool,
+}
+
+/// What the `ActorSystem` does next after a peer sent its chunk hashes
+pub(crate) enum DiagnosisStep {
+    /// Ask the peer for the hashes of the instances in these chunks after a turn
+    CompareInstances(usize, Vec<ChunkRef>),
+    Finished(FinishedDiagnosis),
+}
+
+struct PendingDiagnosis {
+    recipient: RawID,
+    machine: MachineID,
+    turn: usize,
+    chunks: Vec<ChunkRef>,
+    /// 0 until the peer sent its chunk hashes
+    instances_turn: usize,
+    own: Option<Vec<InstanceHash>>,
+    peer: Option<(bool, Vec<InstanceHash>)>,
+}
+
+impl PendingDiagnosis {
+    fn finish(self, instances: Vec<RawID>, complete: bool) -> FinishedDiagnosis {
+        FinishedDiagnosis {
+            recipient: self.recipient,
+            machine: self.machine,
+            turn: self.turn,
+            chunks: self.chunks,
+            instances_turn: self.instances_turn,
+            instances,
+            complete,
+        }
+    }
+
+    fn try_finish(self) -> Result<FinishedDiagnosis, PendingDiagnosis> {
+        match (self.own.as_ref(), self.peer.as_ref()) {
+            (Some(own), Some((true, peer))) => {
+                let instances = diverged_instances(own, peer);
+                Ok(self.finish(instances, true))
+            }
+            (_, Some((false, _))) => Ok(self.finish(Vec::new(), false)),
+            _ => Err(self),
+        }
+    }
+}
+
+/// Hierarchical hashes of the actor state to narrow down desyncs: the hash of the world
+/// combines the hashes of classes, which combine the hashes of chunks of instances.
+/// Chunk hashes are kept for recent turns and compared with a desynced peer, the hashes
+/// of single instances are kept for fewer turns and compared for the chunks that differed.
+pub(crate) struct Diagnosis {
+    kept: VecDeque<(usize, Vec<ChunkHash>)>,
+    kept_instances: VecDeque<(usize, Vec<InstanceHash>)>,
+    pending: Vec<PendingDiagnosis>,
+    /// Instance hashes to send to peers after a turn: `(n_turns, chunks, requester)`
+    replies: Vec<(usize, Vec<ChunkRef>, MachineID)>,
+}
+
+impl Diagnosis {
+    pub fn new() -> Diagnosis {
+        Diagnosis {
+            kept: VecDeque::with_capacity(KEPT_TURNS),
+            kept_instances: VecDeque::with_capacity(KEPT_INSTANCE_TURNS),
+            pending: Vec::new(),
+            replies: Vec::new(),
+        }
+    }
+
+    /// Keep the chunk hashes of all classes and the hashes of all instances after a turn,
+    /// returning the combined hash of the world
+    pub fn record(&mut self, n_turns: usize, classes: Vec<(ShortTypeId, Vec<u64>)>, instances: Vec<InstanceHash>) -> u64 {
+        let mut world_hash = 0;
+        let mut hashes = Vec::new();
+        for (class, chunk_hashes) in classes {
+            let mut bytes = vec![0; chunk_hashes.len() * 8];
+            LittleEndian::write_u64_into(&chunk_hashes, &mut bytes);
+            world_hash = xxh64(&bytes, world_hash ^ u64::from(class.as_u16()));
+            hashes.extend(
+                chunk_hashes
+                    .into_iter()
+                    .enumerate()
+                    .filter(|&(_, hash)| hash != 0)
+                    .map(|(chunk, hash)| ChunkHash {
+                        chunk: ChunkRef {
+                            class,
+                            chunk: chunk as u32,
+                        },
+                        hash,
+                    }),
+            );
+        }
+
+        keep(&mut self.kept, KEPT_TURNS, n_turns, hashes);
+        keep(&mut self.kept_instances, KEPT_INSTANCE_TURNS, n_turns, instances);
+        world_hash
+    }
+
+    /// The chunk hashes kept for a turn
+    pub fn chunk_hashes(&self, n_turns: usize) -> Option<&[ChunkHash]> {
+        self.kept
+            .iter()
+            .find(|&&(kept_turn, _)| kept_turn == n_turns)
+            .map(|(_, hashes)| &hashes[..])
+    }
+
+    /// Start diagnosing a desync with `machine` after `turn`,
+    /// the chunk hashes of the peer are requested by the `ActorSystem`
+    pub fn start(&mut self, machine: MachineID, turn: usize, recipient: RawID) {
+        self.pending.push(PendingDiagnosis {
+            recipient,
+            machine,
+            turn,
+            chunks: Vec::new(),
+            instances_turn: 0,
+            own: None,
+            peer: None,
+        });
+    }
+
+    /// Compare the chunk hashes a peer sent with our own, choosing to compare the instances
+    /// of diverged chunks after the same turn if their hashes are kept, or after `later_turn`
+    pub fn chunk_hashes_received(&mut self, reply: &ChunkHashesReply, later_turn: usize) -> Option<DiagnosisStep> {
+        let turn = reply.n_turns as usize;
+        let index = self.pending.iter().position(|pending| {
+            pending.machine == reply.machine && pending.turn == turn && pending.instances_turn == 0
+        })?;
+        let own_hashes = match self.chunk_hashes(turn) {
+            Some(own_hashes) if reply.kept => own_hashes,
+            _ => {
+                let pending = self.pending.swap_remove(index);
+                return Some(DiagnosisStep::Finished(pending.finish(Vec::new(), false)));
+            }
+        };
+        let chunks = diverged_chunks(own_hashes, &reply.hashes);
+        if chunks.is_empty() {
+            // the hashes only differed in how chunks were combined, which can't happen
+            let pending = self.pending.swap_remove(index);
+            return Some(DiagnosisStep::Finished(pending.finish(Vec::new(), false)));
+        }
+        let own_instances = kept_instance_hashes(&self.kept_instances, turn, &chunks);
+        let instances_turn = if own_instances.is_some() { turn } else { later_turn };
+        let pending = &mut self.pending[index];
+        pending.chunks = chunks.clone();
+        pending.instances_turn = instances_turn;
+        pending.own = own_instances;
+        Some(DiagnosisStep::CompareInstances(instances_turn, chunks))
+    }
+
+    /// Our hashes of the instances in `chunks` after a turn we already finished, if still kept
+    pub fn instance_hashes(&self, n_turns: usize, chunks: &[ChunkRef]) -> Option<Vec<InstanceHash>> {
+        kept_instance_hashes(&self.kept_instances, n_turns, chunks)
+    }
+
+    /// Send our hashes of the instances in `chunks` to `requester` after `n_turns`
+    pub fn reply_after(&mut self, n_turns: usize, chunks: Vec<ChunkRef>, requester: MachineID) {
+        self.replies.push((n_turns, chunks, requester));
+    }
+
+    /// Use the instance hashes recorded after a turn, returning the replies
+    /// to send to peers and the diagnoses that are done
+    pub fn instances_hashed(&mut self, n_turns: usize) -> (Vec<(MachineID, Vec<InstanceHash>)>, Vec<FinishedDiagnosis>) {
+        let mut replies = Vec::new();
+        for (_, chunks, requester) in self.replies.iter().filter(|&&(reply_turn, _, _)| reply_turn == n_turns) {
+            replies.push((*requester, kept_instance_hashes(&self.kept_instances, n_turns, chunks).unwrap_or_default()));
+        }
+        self.replies.retain(|&(reply_turn, _, _)| reply_turn != n_turns);
+
+        for pending in &mut self.pending {
+            if pending.instances_turn == n_turns && pending.own.is_none() {
+                pending.own = kept_instance_hashes(&self.kept_instances, n_turns, &pending.chunks);
+            }
+        }
+        (replies, self.take_finished())
+    }
+
+    /// Use the instance hashes a peer sent, returning the diagnoses that are done
+    pub fn peer_instances_hashed(&mut self, reply: &InstanceHashesReply) -> Vec<FinishedDiagnosis> {
+        for pending in &mut self.pending {
+            if pending.machine == reply.machine && pending.instances_turn == reply.n_turns as usize {
+                pending.peer = Some((reply.in_time, reply.hashes.to_vec()));
+            }
+        }
+        self.take_finished()
+    }
+
+    /// Give up on diagnoses with a peer that disconnected
+    pub fn disconnected(&mut self, machine: MachineID) -> Vec<FinishedDiagnosis> {
+        self.replies.retain(|&(_, _, requester)| requester != machine);
+        let (disconnected, pending) = self.pending.drain(..).partition(|pending| pending.machine == machine);
+        self.pending = pending;
+        disconnected
+            .into_iter()
+            .map(|pending: PendingDiagnosis| pending.finish(Vec::new(), false))
+            .collect()
+    }
+
+    fn take_finished(&mut self) -> Vec<FinishedDiagnosis> {
+        let mut finished = Vec::new();
+        let mut still_pending = Vec::new();
+        for pending in self.pending.drain(..) {
+            match pending.try_finish() {
+                Ok(done) => finished.push(done),
+                Err(pending) => still_pending.push(pending),
+            }
+        }
+        self.pending = still_pending;
+        finished
+    }
+}
+
+/// Keep the hashes after a turn, replacing those of the same turn
+/// (since a turn can be hashed again if it couldn't be finished yet)
+fn keep<T>(kept: &mut VecDeque<(usize, Vec<T>)>, max_turns: usize, n_turns: usize, hashes: Vec<T>) {
+    if kept.back().map_or(false, |&(kept_turn, _)| kept_turn == n_turns) {
+        kept.pop_back();
+    }
+    if kept.len() == max_turns {
+        kept.pop_front();
+    }
+    kept.push_back((n_turns, hashes));
+}
+
+/// The hashes kept for the instances in `chunks` after a turn
+fn kept_instance_hashes(
+    kept_instances: &VecDeque<(usize, Vec<InstanceHash>)>,
+    n_turns: usize,
+    chunks: &[ChunkRef],
+) -> Option<Vec<InstanceHash>> {
+    kept_instances
+        .iter()
+        .find(|&&(kept_turn, _)| kept_turn == n_turns)
+        .map(|(_, hashes)| {
+            hashes
+                .iter()
+                .filter(|hash| chunks.contains(&ChunkRef::of(hash.id)))
+                .cloned()
+                .collect()
+        })
+}
+
+/// The chunks whose hashes differ or that only have instances on one machine
+fn diverged_chunks(own: &[ChunkHash], peer: &[ChunkHash]) -> Vec<ChunkRef> {
+    let key = |chunk: ChunkRef| (chunk.class.as_u16(), chunk.chunk);
+    let mut hashes = HashMap::<(u16, u32), (u64, u64)>::new();
+    for own_hash in own {
+        hashes.entry(key(own_hash.chunk)).or_default().0 = own_hash.hash;
+    }
+    for peer_hash in peer {
+        hashes.entry(key(peer_hash.chunk)).or_default().1 = peer_hash.hash;
+    }
+    let mut diverged = hashes
+        .into_iter()
+        .filter(|&(_, (own_hash, peer_hash))| own_hash != peer_hash)
+        .map(|(key, _)| key)
+        .collect::<Vec<_>>();
+    diverged.sort();
+    diverged
+        .into_iter()
+        .filter_map(|(class, chunk)| ShortTypeId::new(class).map(|class| ChunkRef { class, chunk }))
+        .collect()
+}
+
+/// The instances whose hashes differ or that only exist on one machine.
+/// Instances are matched by class, instance ID and version, since
+/// lockstep instances on different machines can have their own machine IDs.
+fn diverged_instances(own: &[InstanceHash], peer: &[InstanceHash]) -> Vec<RawID> {
+    let key = |id: RawID| (id.type_id.as_u16(), id.instance_id, id.version);
+    let mut hashes = HashMap::<(u16, u32, u8), (RawID, Option<u64>, Option<u64>)>::new();
+    for own_hash in own {
+        hashes.entry(key(own_hash.id)).or_insert((own_hash.id, None, None)).1 = Some(own_hash.hash);
+    }
+    for peer_hash in peer {
+        hashes.entry(key(peer_hash.id)).or_insert((peer_hash.id, None, None)).2 = Some(peer_hash.hash);
+    }
+    let mut diverged = hashes
+        .into_iter()
+        .filter(|&(_, (_, own_hash, peer_hash))| own_hash != peer_hash)
+        .map(|(key, (id, _, _))| (key, id))
+        .collect::<Vec<_>>();
+    diverged.sort_by_key(|&(key, _)| key);
+    diverged.into_iter().map(|(_, id)| id).collect()
+}
+
+#[test]
+fn test_diagnosis_narrows_down_diverged_instances() {
+    let class = ShortTypeId::new(5).unwrap();
+    let id = |instance_id: u32, machine: u16| RawID::new(class, instance_id, MachineID(machine), 0);
+    let instance_hashes = |machine: u16, hashes: &[(u32, u64)]| {
+        hashes
+            .iter()
+            .map(|&(instance_id, hash)| InstanceHash { id: id(instance_id, machine), hash })
+            .collect::<Vec<_>>()
+    };
+    let own = instance_hashes(0, &[(512, 1), (513, 2), (3, 3)]);
+    let peer = InstanceHashesReply {
+        machine: MachineID(1),
+        n_turns: 7,
+        in_time: true,
+        hashes: instance_hashes(1, &[(512, 1), (513, 4), (514, 5)]).into(),
+    };
+    let chunk_hashes = ChunkHashesReply {
+        machine: MachineID(1),
+        n_turns: 7,
+        kept: true,
+        hashes: vec![
+            ChunkHash {
+                chunk: ChunkRef { class, chunk: 0 },
+                hash: 11,
+            },
+            ChunkHash {
+                chunk: ChunkRef { class, chunk: 2 },
+                hash: 99,
+            },
+        ]
+        .into(),
+    };
+
+    // diagnosed quickly, the instances are compared after the turn that first differed
+    let mut diagnosis = Diagnosis::new();
+    diagnosis.record(7, vec![(class, vec![11, 0, 13])], own.clone());
+    diagnosis.start(MachineID(1), 7, id(1, 0));
+    let chunks = match diagnosis.chunk_hashes_received(&chunk_hashes, 30) {
+        Some(DiagnosisStep::CompareInstances(7, chunks)) => chunks,
+        _ => panic!("Expected to compare instances after the same turn"),
+    };
+    assert!(chunks == vec![ChunkRef { class, chunk: 2 }]);
+    assert_eq!(diagnosis.instance_hashes(7, &chunks).unwrap().len(), 2);
+    let finished = diagnosis.peer_instances_hashed(&peer);
+    assert_eq!(finished.len(), 1);
+    assert!(finished[0].complete);
+    assert_eq!((finished[0].turn, finished[0].instances_turn), (7, 7));
+    let diverged = finished[0].instances.iter().map(|id| id.instance_id).collect::<Vec<_>>();
+    assert_eq!(diverged, vec![513, 514]);
+
+    // diagnosed late, they are compared after a later turn
+    let mut diagnosis = Diagnosis::new();
+    for n_turns in 7..(7 + KEPT_INSTANCE_TURNS + 1) {
+        diagnosis.record(n_turns, vec![(class, vec![11, 0, 13])], own.clone());
+    }
+    diagnosis.start(MachineID(1), 7, id(1, 0));
+    match diagnosis.chunk_hashes_received(&chunk_hashes, 30) {
+        Some(DiagnosisStep::CompareInstances(30, _)) => {}
+        _ => panic!("Expected to compare instances after a later turn"),
+    }
+    diagnosis.record(30, vec![(class, vec![11, 0, 13])], own);
+    let (replies, finished) = diagnosis.instances_hashed(30);
+    assert!(replies.is_empty() && finished.is_empty());
+    let finished = diagnosis.peer_instances_hashed(&InstanceHashesReply { n_turns: 30, ..peer });
+    assert_eq!((finished[0].turn, finished[0].instances_turn), (7, 30));
+    assert_eq!(finished[0].instances.len(), 2);
+}
@@ -4,7 +4,8 @@ use std::cell::Cell;
 // TODO: make this much more simple and just like a Box once we can move out of messages!
 
 /// A Marker for state of an actor instance that is not managed by the actor system.
-/// As such it will not be compacted, **nor persisted**.
+/// As such it will not be compacted, **nor persisted**: register actors holding externals
+/// with `ActorSystem::register_external` or `register_external_synced`, so snapshots stay valid.
 /// External implements clone, so it can be used in actor state, but **you have to ensure
 /// at runtime** that only one actor or message ever holds onto an external.
 /// Any attempt to access the external from two different places will throw (see `steal`).
@@ -0,0 +1,333 @@
+use crate::actor_system::World;
+use crate::id::{MachineID, RawID};
+use crate::messaging::{Message, Packet};
+use crate::type_registry::ShortTypeId;
+use compact::CVec;
+use std::any::Any;
+use std::collections::HashMap;
+
+/// Refers to a scatter-gather request started with `World::gather`
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct GatherID(u32);
+
+/// Where instances that received a `Gather` request send their reply to
+#[derive(Copy, Clone, Debug)]
+pub struct Gatherer {
+    services: RawID,
+    gather: GatherID,
+}
+
+impl Gatherer {
+    pub(crate) fn services(&self) -> RawID {
+        self.services
+    }
+
+    pub(crate) fn gather(&self) -> GatherID {
+        self.gather
+    }
+
+    /// Send the reply of this instance to the machine that gathers all replies.
+    /// The request and reply types need to be registered with `ActorSystem::register_gather`.
+    pub fn reply<R: Message>(&self, reply: R, world: &mut World) {
+        let from = world.local_machine_id();
+        world.send(
+            self.services,
+            GatherReply {
+                gather: self.gather,
+                from,
+                reply,
+            },
+        );
+    }
+}
+
+/// A request broadcast to all instances of a class with `World::gather`,
+/// to be handled like any other message. Each instance answers it using `gatherer.reply`.
+#[derive(Compact, Clone)]
+pub struct Gather<Req: Message> {
+    /// The actual request
+    pub request: Req,
+    /// Where to send the reply to
+    pub gatherer: Gatherer,
+}
+
+crate::compact_bounds!(Gather<Req> { request });
+
+/// Sent to the requester of `World::gather` once all instances on all machines replied,
+/// or the timeout passed (the requester needs to handle it)
+#[derive(Compact, Clone)]
+pub struct Gathered<R: Message> {
+    /// The request that is answered
+    pub gather: GatherID,
+    /// The replies of all instances, in the order they arrived in
+    pub replies: CVec<R>,
+    /// Did some replies not arrive in time?
+    pub timed_out: bool,
+}
+
+crate::compact_bounds!(Gathered<R> { replies });
+
+/// Broadcast to the system services of all machines, which pass the request on
+/// to all local instances and tell the gathering machine how many classes received it
+#[derive(Compact, Clone)]
+pub(crate) struct GatherRequest<Req: Message> {
+    pub class: ShortTypeId,
+    pub request: Req,
+    pub gatherer: Gatherer,
+}
+
+crate::compact_bounds!(GatherRequest<Req> { request });
+
+/// How many classes on a machine received a gather request (one, or the implementors of a trait)
+#[derive(Compact, Clone)]
+pub(crate) struct GatherExpect {
+    pub gather: GatherID,
+    pub from: MachineID,
+    pub n_classes: u32,
+}
+
+/// How many instances of a class received a gather request, sent once the class
+/// handles the broadcast, so instances spawned or dead in the meantime are accounted for
+#[derive(Compact, Clone)]
+pub(crate) struct GatherResolved {
+    pub gather: GatherID,
+    pub from: MachineID,
+    pub n_replies: u32,
+}
+
+#[derive(Compact, Clone)]
+pub(crate) struct GatherReply<R: Message> {
+    pub gather: GatherID,
+    pub from: MachineID,
+    pub reply: R,
+}
+
+crate::compact_bounds!(GatherReply<R> { reply });
+
+type FinishFn = Box<dyn Fn(RawID, GatherID, Box<dyn Any>, bool, &mut World)>;
+
+/// Tells the gathering machine how many instances a `Gather` broadcast (the packet) reached
+pub(crate) type ResolvedFn = fn(*const (), usize, &mut World);
+
+fn resolved<Req: Message>(packet_ptr: *const (), n_recipients: usize, world: &mut World) {
+    let gatherer = unsafe { (*(packet_ptr as *const Packet<Gather<Req>>)).message.gatherer };
+    let from = world.local_machine_id();
+    world.send(
+        gatherer.services,
+        GatherResolved {
+            gather: gatherer.gather,
+            from,
+            n_replies: n_recipients as u32,
+        },
+    );
+}
+
+pub(crate) struct PendingGather {
+    gather: GatherID,
+    requester: RawID,
+    /// Machines that didn't tell yet how many classes received the request
+    waiting_for: Vec<MachineID>,
+    n_classes: HashMap<MachineID, usize>,
+    /// How many classes told how many replies to expect from them
+    n_resolved: HashMap<MachineID, usize>,
+    expected: HashMap<MachineID, usize>,
+    received: HashMap<MachineID, usize>,
+    /// A `Vec` of the reply type
+    replies: Box<dyn Any>,
+    deadline_ms: f64,
+    finish: FinishFn,
+}
+
+impl PendingGather {
+    fn is_complete(&self) -> bool {
+        let count = |counts: &HashMap<MachineID, usize>, machine| counts.get(machine).cloned().unwrap_or(0);
+        self.waiting_for.is_empty()
+            && self
+                .n_classes
+                .iter()
+                .all(|(machine, n_classes)| count(&self.n_resolved, machine) >= *n_classes)
+            && self
+                .expected
+                .iter()
+                .all(|(machine, n_expected)| count(&self.received, machine) >= *n_expected)
+    }
+
+    /// Send all replies to the requester
+    pub fn finish(self, timed_out: bool, world: &mut World) {
+        (self.finish)(self.requester, self.gather, self.replies, timed_out, world)
+    }
+}
+
+/// Scatter-gather requests this machine started and is still collecting replies for
+pub(crate) struct Gathers {
+    pending: HashMap<GatherID, PendingGather>,
+    next_gather: u32,
+    /// By the message type of the `Gather` request
+    resolved_fns: HashMap<ShortTypeId, ResolvedFn>,
+}
+
+impl Gathers {
+    pub fn new() -> Gathers {
+        Gathers {
+            pending: HashMap::new(),
+            next_gather: 0,
+            resolved_fns: HashMap::new(),
+        }
+    }
+
+    /// Remember that `message_type` is a `Gather<Req>`, whose recipients need to be counted
+    pub fn register<Req: Message>(&mut self, message_type: ShortTypeId) {
+        self.resolved_fns.insert(message_type, resolved::<Req>);
+    }
+
+    /// What to call once a broadcast of `message_type` reached its recipients, if it is a `Gather`
+    pub fn resolved_fn(&self, message_type: ShortTypeId) -> Option<ResolvedFn> {
+        if self.resolved_fns.is_empty() {
+            None
+        } else {
+            self.resolved_fns.get(&message_type).cloned()
+        }
+    }
+
+    pub fn start<R: Message>(
+        &mut self,
+        services: RawID,
+        requester: RawID,
+        machines: Vec<MachineID>,
+        deadline_ms: f64,
+    ) -> Gatherer {
+        let gather = GatherID(self.next_gather);
+        self.next_gather = self.next_gather.wrapping_add(1);
+        self.pending.insert(
+            gather,
+            PendingGather {
+                gather,
+                requester,
+                waiting_for: machines,
+                n_classes: HashMap::new(),
+                n_resolved: HashMap::new(),
+                expected: HashMap::new(),
+                received: HashMap::new(),
+                replies: Box::new(Vec::<R>::new()),
+                deadline_ms,
+                finish: Box::new(|requester, gather, replies, timed_out, world| {
+                    let replies = *replies.downcast::<Vec<R>>().expect("Replies should have the gathered type");
+                    world.send(
+                        requester,
+                        Gathered {
+                            gather,
+                            replies: replies.into(),
+                            timed_out,
+                        },
+                    );
+                }),
+            },
+        );
+        Gatherer { services, gather }
+    }
+
+    fn take_if_complete(&mut self, gather: GatherID) -> Option<PendingGather> {
+        if self.pending.get(&gather).map_or(false, PendingGather::is_complete) {
+            self.pending.remove(&gather)
+        } else {
+            None
+        }
+    }
+
+    pub fn expect(&mut self, expect: &GatherExpect) -> Option<PendingGather> {
+        if let Some(pending) = self.pending.get_mut(&expect.gather) {
+            pending.waiting_for.retain(|machine| *machine != expect.from);
+            pending.n_classes.insert(expect.from, expect.n_classes as usize);
+        }
+        self.take_if_complete(expect.gather)
+    }
+
+    pub fn resolved(&mut self, resolved: &GatherResolved) -> Option<PendingGather> {
+        if let Some(pending) = self.pending.get_mut(&resolved.gather) {
+            *pending.n_resolved.entry(resolved.from).or_insert(0) += 1;
+            *pending.expected.entry(resolved.from).or_insert(0) += resolved.n_replies as usize;
+        }
+        self.take_if_complete(resolved.gather)
+    }
+
+    pub fn reply<R: Message>(&mut self, reply: &GatherReply<R>) -> Option<PendingGather> {
+        if let Some(pending) = self.pending.get_mut(&reply.gather) {
+            *pending.received.entry(reply.from).or_insert(0) += 1;
+            pending
+                .replies
+                .downcast_mut::<Vec<R>>()
+                .expect("Replies should have the gathered type")
+                .push(reply.reply.clone());
+        }
+        self.take_if_complete(reply.gather)
+    }
+
+    /// A machine disconnected, stop waiting for its replies
+    pub fn disconnected(&mut self, machine: MachineID) -> Vec<PendingGather> {
+        for pending in self.pending.values_mut() {
+            pending.waiting_for.retain(|waiting_for| *waiting_for != machine);
+            pending.n_classes.remove(&machine);
+            pending.expected.remove(&machine);
+        }
+        let mut gathers = self.pending.keys().cloned().collect::<Vec<_>>();
+        gathers.sort_by_key(|gather| gather.0);
+        gathers
+            .into_iter()
+            .filter_map(|gather| self.take_if_complete(gather))
+            .collect()
+    }
+
+    /// Take all requests whose deadline passed, in the order they were started
+    pub fn expire(&mut self, now_ms: f64) -> Vec<PendingGather> {
+        let mut expired = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| pending.deadline_ms <= now_ms)
+            .map(|(gather, _)| *gather)
+            .collect::<Vec<_>>();
+        expired.sort_by_key(|gather| gather.0);
+        expired
+            .into_iter()
+            .filter_map(|gather| self.pending.remove(&gather))
+            .collect()
+    }
+}
+
+#[test]
+fn test_gathers_count_recipients_when_they_are_reached() {
+    use crate::id::TypedID;
+    use crate::messaging::Fate;
+    use crate::test_support::{local_system, Add, Counter, OtherCounter};
+
+    let mut system = local_system();
+    system.register::<Counter>();
+    system.register::<OtherCounter>();
+    system.register_gather::<Add, u32>();
+    system.add_handler::<Counter, _, _>(|_: &Add, _, _| Fate::Die, false);
+    system.add_handler::<Counter, _, _>(
+        |gather: &Gather<Add>, counter, world| {
+            gather.gatherer.reply(counter.count + gather.request.0, world);
+            Fate::Live
+        },
+        false,
+    );
+    system.add_handler::<OtherCounter, _, _>(
+        |gathered: &Gathered<u32>, other, _| {
+            assert!(!gathered.timed_out);
+            other.count = gathered.replies.iter().sum();
+            Fate::Live
+        },
+        false,
+    );
+
+    let counters = system.spawn_many((1..4).map(Counter::new));
+    let requester = system.spawn_many(vec![OtherCounter::new(0)])[0];
+    // the first counter dies after the request was passed on, but before it reaches the counters
+    system.send(counters[0].as_raw(), Add(0));
+    system
+        .world()
+        .gather::<Counter, Add, u32>(Add(10), requester.as_raw(), ::std::time::Duration::from_secs(60));
+    system.process_all_messages();
+
+    assert_eq!(system.instance::<OtherCounter>(requester).unwrap().count, 25);
+}
@@ -0,0 +1,165 @@
+use crate::actor_system::ActorSystem;
+use crate::debugger::PacketHeader;
+use crate::id::RawID;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::rc::Rc;
+
+/// A message received by an instance whose history is recorded
+#[derive(Clone, Debug)]
+pub struct ReceivedMessage {
+    /// The name of the message type
+    pub message_type: String,
+    /// The message rendered as text, if its type opted in with `ActorSystem::decode_packets`
+    pub message: Option<String>,
+    /// Whether the message was broadcast to all instances of the class
+    pub broadcast: bool,
+}
+
+/// The state of an instance at the start of a turn and the messages it received during the turn
+#[derive(Clone, Debug)]
+pub struct HistoryTurn {
+    /// The networking turn
+    pub n_turns: usize,
+    /// The compact bytes of the state, `None` if the instance didn't exist
+    pub state: Option<Vec<u8>>,
+    /// The state as described by the function given to `ActorSystem::record_history`
+    pub description: Option<String>,
+    /// The messages in the order they were handled
+    pub messages: Vec<ReceivedMessage>,
+}
+
+/// The recorded last turns of one instance, see `ActorSystem::record_history`
+#[derive(Clone, Debug)]
+pub struct InstanceHistory {
+    /// The instance
+    pub id: RawID,
+    /// The recorded turns, oldest first
+    pub turns: VecDeque<HistoryTurn>,
+    max_turns: usize,
+}
+
+impl InstanceHistory {
+    fn new(id: RawID, max_turns: usize) -> InstanceHistory {
+        InstanceHistory {
+            id,
+            turns: VecDeque::with_capacity(max_turns),
+            max_turns,
+        }
+    }
+
+    fn start_turn(&mut self, n_turns: usize, snapshot: Option<(Vec<u8>, String)>) {
+        if self.turns.len() == self.max_turns {
+            self.turns.pop_front();
+        }
+        let (state, description) = match snapshot {
+            Some((state, description)) => (Some(state), Some(description)),
+            None => (None, None),
+        };
+        self.turns.push_back(HistoryTurn {
+            n_turns,
+            state,
+            description,
+            messages: Vec::new(),
+        });
+    }
+
+    fn received(&mut self, header: &PacketHeader) {
+        if let Some(turn) = self.turns.back_mut() {
+            turn.messages.push(ReceivedMessage {
+                message_type: header.message_type.clone(),
+                message: header.message.clone(),
+                broadcast: header.recipient.is_broadcast(),
+            });
+        }
+    }
+
+    /// Render the recorded turns as text, showing how the instance got into its current state
+    pub fn dump(&self) -> String {
+        let mut dump = String::new();
+        for turn in &self.turns {
+            let description = match (&turn.description, &turn.state) {
+                (Some(description), Some(state)) => format!("{} ({} bytes)", description, state.len()),
+                _ => "(doesn't exist)".to_owned(),
+            };
+            writeln!(dump, "Turn {}: {}", turn.n_turns, description).expect("Writing to a string");
+            for message in &turn.messages {
+                writeln!(
+                    dump,
+                    "  {} {}{}",
+                    if message.broadcast { "<<" } else { "<-" },
+                    message.message_type,
+                    message.message.as_ref().map(|text| format!(" {}", text)).unwrap_or_default()
+                )
+                .expect("Writing to a string");
+            }
+        }
+        dump
+    }
+}
+
+type SnapshotFn = Box<dyn Fn(&ActorSystem) -> Option<(Vec<u8>, String)>>;
+
+/// The history of an instance being recorded, filled with received messages by an interceptor
+pub(crate) struct RecordedHistory {
+    history: Rc<RefCell<InstanceHistory>>,
+    snapshot: SnapshotFn,
+}
+
+impl RecordedHistory {
+    pub fn new(id: RawID, max_turns: usize, snapshot: SnapshotFn) -> RecordedHistory {
+        RecordedHistory {
+            history: Rc::new(RefCell::new(InstanceHistory::new(id, max_turns))),
+            snapshot,
+        }
+    }
+
+    /// A function to be called with the header of each packet before it is handled,
+    /// recording the packets to the instance (or broadcast to its class `class_name`)
+    pub fn receiver(&self, class_name: &'static str) -> impl Fn(&PacketHeader) {
+        let history = Rc::clone(&self.history);
+        move |header: &PacketHeader| {
+            let id = history.borrow().id;
+            if header.recipient == id || (header.recipient.is_broadcast() && header.recipient_class == class_name) {
+                history.borrow_mut().received(header);
+            }
+        }
+    }
+
+    /// Take a snapshot of the instance at the start of a turn
+    pub fn start_turn(&self, n_turns: usize, system: &ActorSystem) {
+        let snapshot = (self.snapshot)(system);
+        self.history.borrow_mut().start_turn(n_turns, snapshot);
+    }
+
+    pub fn history(&self) -> InstanceHistory {
+        self.history.borrow().clone()
+    }
+}
+
+#[test]
+fn test_instance_history() {
+    use crate::id::MachineID;
+    use crate::type_registry::ShortTypeId;
+
+    let id = RawID::new(ShortTypeId::new(3).unwrap(), 7, MachineID(0), 0);
+    let mut history = InstanceHistory::new(id, 2);
+    let header = |recipient: RawID, message: &str| PacketHeader {
+        recipient,
+        recipient_class: "Car".to_owned(),
+        message_type_id: 12,
+        message_type: "Honk".to_owned(),
+        message: Some(message.to_owned()),
+    };
+    history.start_turn(1, None);
+    history.start_turn(2, Some((vec![0; 4], "speed 0".to_owned())));
+    history.received(&header(id, "loud"));
+    history.start_turn(3, Some((vec![0; 4], "speed 5".to_owned())));
+    history.received(&header(id.local_broadcast(), "quiet"));
+    assert_eq!(history.turns.len(), 2);
+    assert_eq!(
+        history.dump(),
+        "Turn 2: speed 0 (4 bytes)\n  <- Honk loud\nTurn 3: speed 5 (4 bytes)\n  << Honk quiet\n"
+    );
+}
@@ -1,4 +1,4 @@
-use crate::type_registry::ShortTypeId;
+use crate::type_registry::{ShortTypeId, TypeRegistry};
 use crate::actor_system::World;
 use crate::actor::ActorOrActorTrait;
 
@@ -8,10 +8,54 @@ use crate::actor::ActorOrActorTrait;
     derive(Serialize, Deserialize)
 )]
 #[derive(Copy, Clone, Eq, PartialEq, PartialOrd, Ord, Hash, Debug)]
-pub struct MachineID(pub u8);
+pub struct MachineID(pub u16);
 
-/// A raw (untyped) ID referring to an actor class instance
+/// Machine IDs starting from this one address multicast groups instead of single machines
+const FIRST_MULTICAST_GROUP_MACHINE_ID: u16 = 0xFE00;
+
+/// The largest number of machines in a network, since larger machine IDs
+/// are reserved for multicast groups and broadcasts
+pub const MAX_MACHINES: usize = FIRST_MULTICAST_GROUP_MACHINE_ID as usize;
+
+impl MachineID {
+    /// The machine ID of the machine at `index` in a network,
+    /// unless that would be in the reserved range
+    pub(crate) fn of_machine(index: usize) -> Option<MachineID> {
+        if index < MAX_MACHINES {
+            Some(MachineID(index as u16))
+        } else {
+            None
+        }
+    }
+
+    /// Check whether this machine ID addresses a single machine
+    /// (instead of a multicast group or a broadcast)
+    pub fn is_machine(&self) -> bool {
+        self.0 < FIRST_MULTICAST_GROUP_MACHINE_ID
+    }
+
+    /// The machine ID addressing all members of multicast group `group`,
+    /// as defined with `ActorSystem::networking_define_multicast_group`
+    pub fn multicast_group(group: u8) -> MachineID {
+        MachineID(FIRST_MULTICAST_GROUP_MACHINE_ID + u16::from(group))
+    }
+
+    /// Check whether this machine ID addresses a multicast group
+    pub fn is_multicast_group(&self) -> bool {
+        self.0 >= FIRST_MULTICAST_GROUP_MACHINE_ID
+            && self.0 < FIRST_MULTICAST_GROUP_MACHINE_ID + 0x100
+    }
+}
+
+/// A raw (untyped) ID referring to an actor class instance.
+///
+/// Laid out with `repr(C)`, since it is read directly out of packets received from peers.
+/// It takes 12 bytes in packets, including 3 explicitly zeroed padding bytes, so identical IDs
+/// are always identical bytes (for state hashes, delta frames and compression).
+/// Note that this differs from the 8 bytes of versions with single-byte machine IDs:
+/// only their handshakes stay readable, their messages can't be exchanged with this version.
 #[derive(Copy, Clone, PartialEq, Eq, Hash)]
+#[repr(C)]
 pub struct RawID {
     /// instance ID within the class
     pub instance_id: u32,
@@ -24,6 +68,7 @@ pub struct RawID {
     /// A version of the ID to be able to  safelyreuse instance IDs
     /// after an actor dies.
     pub version: u8,
+    _padding: [u8; 3],
 }
 
 pub fn broadcast_instance_id() -> u32 {
@@ -31,7 +76,7 @@ pub fn broadcast_instance_id() -> u32 {
 }
 
 pub fn broadcast_machine_id() -> MachineID {
-    MachineID(u8::max_value())
+    MachineID(u16::max_value())
 }
 
 impl RawID {
@@ -42,6 +87,7 @@ impl RawID {
             machine,
             version,
             instance_id,
+            _padding: [0; 3],
         }
     }
 
@@ -61,6 +107,15 @@ impl RawID {
         }
     }
 
+    /// Convert a given RawID into one that represents a broadcast
+    /// to the members of a multicast group
+    pub fn multicast(&self, group: u8) -> RawID {
+        RawID {
+            machine: MachineID::multicast_group(group),
+            ..self.local_broadcast()
+        }
+    }
+
     /// Check whether this RawID represents a (local || global) broadcast
     pub fn is_broadcast(&self) -> bool {
         self.instance_id == broadcast_instance_id()
@@ -71,18 +126,99 @@ impl RawID {
         self.machine == broadcast_machine_id()
     }
 
-    /// Get the canonical string format of a RawID
+    /// Get the human-readable string format of a RawID, see `NamedRawID`
     pub fn format(&self, world: &mut World) -> String {
-        format!(
-            "{}_{:X}.{:X}@{:X}",
-            world.get_actor_name(self.type_id),
-            self.instance_id,
-            self.version,
-            self.machine.0
-        )
+        world.display_id(*self).to_string()
+    }
+}
+
+/// Formats a `RawID` using the name of its actor class, for logs and debug consoles:
+///
+/// - `Car.local#42@machine3` is instance 42 of `Car` on machine 3
+/// - `Car.local#42v2@machine3` is the same with ID version 2
+/// - `Car.broadcast@machine3` is a broadcast to all `Car`s on machine 3
+/// - `Car.broadcast@group1` is a broadcast to all `Car`s in multicast group 1
+/// - `Car.broadcast@all` is a broadcast to all `Car`s on all machines
+///
+/// Classes are named without their module path, unless that is ambiguous.
+/// Created with `ActorSystem::display_id`, parsed back with `ActorSystem::parse_id`.
+pub struct NamedRawID<'a> {
+    id: RawID,
+    actor_registry: &'a TypeRegistry,
+}
+
+impl<'a> NamedRawID<'a> {
+    pub(crate) fn new(id: RawID, actor_registry: &'a TypeRegistry) -> NamedRawID<'a> {
+        NamedRawID { id, actor_registry }
     }
 }
 
+impl<'a> ::std::fmt::Display for NamedRawID<'a> {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        let id = self.id;
+        write!(f, "{}", self.actor_registry.display_name(id.type_id))?;
+        if id.is_broadcast() {
+            write!(f, ".broadcast")?;
+        } else if id.version == 0 {
+            write!(f, ".local#{}", id.instance_id)?;
+        } else {
+            write!(f, ".local#{}v{}", id.instance_id, id.version)?;
+        }
+        if id.is_global_broadcast() {
+            write!(f, "@all")
+        } else if id.machine.is_multicast_group() {
+            write!(f, "@group{}", id.machine.0 - FIRST_MULTICAST_GROUP_MACHINE_ID)
+        } else {
+            write!(f, "@machine{}", id.machine.0)
+        }
+    }
+}
+
+/// Parse the format of `NamedRawID`, also accepting full class names and plain machine numbers
+pub(crate) fn parse_named(s: &str, actor_registry: &TypeRegistry) -> Result<RawID, ParseRawIDError> {
+    let at = s.rfind('@').ok_or(ParseRawIDError::Format)?;
+    let (name_part, machine_part) = (&s[..at], &s[at + 1..]);
+    let dot = name_part.rfind('.').ok_or(ParseRawIDError::Format)?;
+    let (class_part, instance_part) = (&name_part[..dot], &name_part[dot + 1..]);
+
+    let type_id = actor_registry
+        .get_by_display_name(class_part)
+        .ok_or_else(|| ParseRawIDError::UnknownClass(class_part.to_owned()))?;
+
+    let (instance_id, version) = if instance_part == "broadcast" {
+        (broadcast_instance_id(), 0)
+    } else if instance_part.starts_with("local#") {
+        let mut instance_parts = instance_part["local#".len()..].splitn(2, 'v');
+        let instance_id = instance_parts
+            .next()
+            .ok_or(ParseRawIDError::Format)?
+            .parse()
+            .map_err(ParseRawIDError::ParseIntError)?;
+        let version = match instance_parts.next() {
+            Some(version) => version.parse().map_err(ParseRawIDError::ParseIntError)?,
+            None => 0,
+        };
+        (instance_id, version)
+    } else {
+        return Err(ParseRawIDError::Format);
+    };
+
+    let machine = if machine_part == "all" {
+        broadcast_machine_id()
+    } else if machine_part.starts_with("group") {
+        MachineID::multicast_group(machine_part["group".len()..].parse().map_err(ParseRawIDError::ParseIntError)?)
+    } else {
+        let machine_number = if machine_part.starts_with("machine") {
+            &machine_part["machine".len()..]
+        } else {
+            machine_part
+        };
+        MachineID(machine_number.parse().map_err(ParseRawIDError::ParseIntError)?)
+    };
+
+    Ok(RawID::new(type_id, instance_id, machine, version))
+}
+
 impl ::std::fmt::Debug for RawID {
     fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
         write!(
@@ -102,11 +238,17 @@ impl ::std::fmt::Display for RawID {
     }
 }
 
+/// Why a string couldn't be parsed as a `RawID`
 #[derive(Debug)]
 pub enum ParseRawIDError {
+    /// The string doesn't have the parts of a `RawID`
     Format,
+    /// The type ID is 0
     InvalidTypeId,
+    /// A part isn't a valid number
     ParseIntError(::std::num::ParseIntError),
+    /// No actor class (or ambiguously many) has this name
+    UnknownClass(String),
 }
 
 impl ::std::fmt::Display for ParseRawIDError {
@@ -131,14 +273,9 @@ impl ::std::str::FromStr for RawID {
                 let version =
                     u8::from_str_radix(version_part, 16).map_err(ParseRawIDError::ParseIntError)?;
                 let machine = MachineID(
-                    u8::from_str_radix(machine_part, 16).map_err(ParseRawIDError::ParseIntError)?,
+                    u16::from_str_radix(machine_part, 16).map_err(ParseRawIDError::ParseIntError)?,
                 );
-                Ok(RawID {
-                    type_id,
-                    machine,
-                    version,
-                    instance_id,
-                })
+                Ok(RawID::new(type_id, instance_id, machine, version))
             }
             _ => Err(ParseRawIDError::Format),
         }
@@ -242,3 +379,54 @@ pub trait TypedID: Copy + Clone + Sized + ::std::fmt::Debug + ::std::hash::Hash
         Self::from_raw(world.global_broadcast::<Self::Target>())
     }
 }
+
+#[test]
+fn test_named_raw_ids() {
+    mod cars {
+        pub struct Car;
+    }
+    struct Truck;
+
+    let mut registry = TypeRegistry::new();
+    let car_id = registry.register_new::<cars::Car>();
+    registry.register_new::<Truck>();
+
+    let ids = vec![
+        (RawID::new(car_id, 42, MachineID(3), 0), "Car.local#42@machine3"),
+        (RawID::new(car_id, 42, MachineID(3), 2), "Car.local#42v2@machine3"),
+        (RawID::new(car_id, 0, MachineID(3), 0).local_broadcast(), "Car.broadcast@machine3"),
+        (RawID::new(car_id, 0, MachineID(3), 0).multicast(1), "Car.broadcast@group1"),
+        (RawID::new(car_id, 0, MachineID(3), 0).global_broadcast(), "Car.broadcast@all"),
+    ];
+    for (id, formatted) in ids {
+        assert_eq!(NamedRawID::new(id, &registry).to_string(), formatted);
+        assert_eq!(parse_named(formatted, &registry).unwrap(), id);
+    }
+
+    assert_eq!(parse_named("Car.local#42@3", &registry).unwrap(), RawID::new(car_id, 42, MachineID(3), 0));
+    let full_name = format!("{}.local#1@machine0", registry.get_name(car_id));
+    assert_eq!(parse_named(&full_name, &registry).unwrap(), RawID::new(car_id, 1, MachineID(0), 0));
+    assert!(parse_named("Bus.local#1@machine0", &registry).is_err());
+    assert!(parse_named("Car#1@machine0", &registry).is_err());
+}
+
+#[test]
+fn test_raw_id_bytes() {
+    let id = RawID::new(ShortTypeId::new(0x0203).unwrap(), 0x0A0B_0C0D, MachineID(0x0405), 6);
+    let bytes: [u8; 12] = unsafe { ::std::mem::transmute(id.global_broadcast().local_broadcast()) };
+    assert_eq!(&bytes[4..], &[0x03, 0x02, 0xFF, 0xFF, 6, 0, 0, 0]);
+    let bytes: [u8; 12] = unsafe { ::std::mem::transmute(id) };
+    assert_eq!(bytes, [0x0D, 0x0C, 0x0B, 0x0A, 0x03, 0x02, 0x05, 0x04, 6, 0, 0, 0]);
+}
+
+#[test]
+fn test_machine_ids_are_not_multicast_groups() {
+    let last_machine = MachineID::of_machine(MAX_MACHINES - 1).unwrap();
+    assert!(last_machine.is_machine() && !last_machine.is_multicast_group());
+    assert!(MachineID::of_machine(MAX_MACHINES).is_none());
+    for group in 0..=255u8 {
+        let group = MachineID::multicast_group(group);
+        assert!(group.is_multicast_group() && !group.is_machine());
+    }
+    assert!(!broadcast_machine_id().is_machine() && !broadcast_machine_id().is_multicast_group());
+}
@@ -0,0 +1,145 @@
+use crate::id::{MachineID, RawID};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::HashMap;
+
+/// Decides the instance IDs of new instances of an actor class, set per class with
+/// `ActorSystem::set_id_allocator`.
+///
+/// Without an allocator, each machine allocates the IDs of its own instances from its
+/// instance store, reusing the IDs of dead instances. Instances then have to be spawned with
+/// IDs allocated on the machine they live on, since two machines could otherwise give out the
+/// same ID. An allocator instead splits the IDs of each machine's instances between all machines
+/// allocating them, so any machine can allocate IDs of instances on any machine without asking,
+/// in the same way on all machines for lockstep simulations.
+///
+/// IDs given out by allocators are never reused. The state of allocators is saved in snapshots,
+/// set them up again after loading one (before spawning) to continue behind the restored IDs.
+pub trait IdAllocator {
+    /// Allocate `n` consecutive instance IDs of instances on `owner`, for the machine `local`
+    /// in its networking turn `n_turns`. Returns the first one, or `None` if there's no room left.
+    fn allocate(&mut self, local: MachineID, owner: MachineID, n_turns: usize, n: u32) -> Option<u32>;
+
+    /// How many more IDs of instances on `owner` the machine `local` can allocate in `n_turns`
+    fn remaining(&self, local: MachineID, owner: MachineID, n_turns: usize) -> usize;
+
+    /// The state of the allocator, to be written to snapshots
+    fn save(&self) -> Vec<u8>;
+
+    /// Continue from a state written by `save`, when set up again after loading a snapshot
+    fn restore(&mut self, saved: &[u8]);
+}
+
+/// Gives each allocating machine its own range of `ids_per_machine` instance IDs
+/// on every owning machine: machine `m` allocates from `m * ids_per_machine` on.
+/// The owning machine keeps lookup entries for all IDs below the highest one used,
+/// so `ids_per_machine` should be as small as the expected instances allow.
+pub struct MachineRanges {
+    ids_per_machine: u32,
+    /// The number of IDs used per owning machine
+    used: HashMap<MachineID, u32>,
+}
+
+impl MachineRanges {
+    /// Ranges of `ids_per_machine` IDs each
+    pub fn new(ids_per_machine: u32) -> MachineRanges {
+        MachineRanges {
+            ids_per_machine,
+            used: HashMap::new(),
+        }
+    }
+}
+
+impl IdAllocator for MachineRanges {
+    fn allocate(&mut self, local: MachineID, owner: MachineID, _n_turns: usize, n: u32) -> Option<u32> {
+        let used = self.used.get(&owner).cloned().unwrap_or(0);
+        if self.ids_per_machine - used < n {
+            return None;
+        }
+        let first = u64::from(local.0) * u64::from(self.ids_per_machine) + u64::from(used);
+        if first + u64::from(n) > u64::from(u32::max_value()) {
+            return None;
+        }
+        self.used.insert(owner, used + n);
+        Some(first as u32)
+    }
+
+    fn remaining(&self, _local: MachineID, owner: MachineID, _n_turns: usize) -> usize {
+        (self.ids_per_machine - self.used.get(&owner).cloned().unwrap_or(0)) as usize
+    }
+
+    /// Layout: `[n_owners: u32]` followed by `[owner: u16][used: u32]` for each owning machine
+    fn save(&self) -> Vec<u8> {
+        let mut used = self.used.iter().collect::<Vec<_>>();
+        used.sort_by_key(|&(owner, _)| *owner);
+        let mut saved = Vec::new();
+        saved.write_u32::<LittleEndian>(used.len() as u32).unwrap();
+        for (owner, &n_used) in used {
+            saved.write_u16::<LittleEndian>(owner.0).unwrap();
+            saved.write_u32::<LittleEndian>(n_used).unwrap();
+        }
+        saved
+    }
+
+    fn restore(&mut self, mut saved: &[u8]) {
+        let n_owners = saved.read_u32::<LittleEndian>().expect("Saved ID ranges should be complete");
+        for _ in 0..n_owners {
+            let owner = MachineID(saved.read_u16::<LittleEndian>().expect("Saved ID ranges should be complete"));
+            let n_used = saved.read_u32::<LittleEndian>().expect("Saved ID ranges should be complete");
+            self.used.insert(owner, n_used);
+        }
+    }
+}
+
+/// Consecutive instance IDs reserved for a bulk spawn, see `World::reserve_instance_ids`
+pub struct IdBlock {
+    next: RawID,
+    remaining: u32,
+}
+
+impl IdBlock {
+    pub(crate) fn new(first: RawID, n: u32) -> IdBlock {
+        IdBlock {
+            next: first,
+            remaining: n,
+        }
+    }
+}
+
+impl Iterator for IdBlock {
+    type Item = RawID;
+
+    fn next(&mut self) -> Option<RawID> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let id = self.next;
+        self.next.instance_id += 1;
+        self.remaining -= 1;
+        Some(id)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining as usize, Some(self.remaining as usize))
+    }
+}
+
+impl ExactSizeIterator for IdBlock {}
+
+#[test]
+fn test_id_allocators() {
+    let mut ranges = MachineRanges::new(100);
+    assert_eq!(ranges.allocate(MachineID(2), MachineID(0), 0, 10), Some(200));
+    assert_eq!(ranges.allocate(MachineID(2), MachineID(0), 0, 1), Some(210));
+    assert_eq!(ranges.allocate(MachineID(2), MachineID(1), 0, 1), Some(200));
+    assert_eq!(ranges.remaining(MachineID(2), MachineID(0), 0), 89);
+    assert_eq!(ranges.allocate(MachineID(2), MachineID(0), 0, 90), None);
+
+    let mut restored = MachineRanges::new(100);
+    restored.restore(&ranges.save());
+    assert_eq!(restored.allocate(MachineID(2), MachineID(0), 0, 1), Some(211));
+    assert_eq!(restored.allocate(MachineID(2), MachineID(1), 0, 1), Some(201));
+    assert_eq!(restored.allocate(MachineID(2), MachineID(3), 0, 1), Some(200));
+
+    let block = IdBlock::new(RawID::new(crate::type_registry::ShortTypeId::new(1).unwrap(), 7, MachineID(0), 0), 3);
+    assert_eq!(block.map(|id| id.instance_id).collect::<Vec<_>>(), vec![7, 8, 9]);
+}
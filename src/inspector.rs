@@ -0,0 +1,141 @@
+use crate::debugger::PacketHeader;
+use crate::id::MachineID;
+use std::collections::HashMap;
+
+/// A snapshot of the state of an `ActorSystem`, returned by `ActorSystem::inspect`,
+/// for example to show in a debug overlay
+#[derive(Clone, Debug)]
+pub struct SystemInspection {
+    /// All registered actor classes, ordered by type ID
+    pub classes: Vec<ClassInspection>,
+    /// The networking turn state
+    pub networking: NetworkingInspection,
+    /// Did a panic happen inside a message handler?
+    pub panic_happened: bool,
+    /// The message the debugger stopped at, if it is paused
+    pub held_packet: Option<PacketHeader>,
+}
+
+/// The state of one actor class
+#[derive(Clone, Debug)]
+pub struct ClassInspection {
+    /// The type ID of the class
+    pub type_id: u16,
+    /// The full type name of the class
+    pub type_name: String,
+    /// Number of local instances
+    pub instances: usize,
+    /// Bytes taken by the state of all local instances
+    pub state_bytes: usize,
+    /// Number of messages queued in the inbox of the class
+    pub queued_messages: usize,
+    /// Bytes of all messages queued in the inbox of the class
+    pub queued_bytes: usize,
+}
+
+/// How well the storage of the instances of a class is used, see `ActorSystem::occupancy`
+#[derive(Clone, Debug, Default)]
+pub struct ClassOccupancy {
+    /// Number of local instances
+    pub instances: usize,
+    /// Instance IDs ever allocated, including those of dead instances
+    pub ids: usize,
+    /// IDs of dead instances that will be reused (in a newer version)
+    pub free_ids: usize,
+    /// Bytes taken by the state of all local instances
+    pub state_bytes: usize,
+    /// Bytes of the storage slots the instances are in
+    pub slot_bytes: usize,
+    /// Instances in a slot bigger than they need, because they shrunk
+    pub oversized_instances: usize,
+}
+
+impl ClassOccupancy {
+    /// The share of the storage slots that is unused, between 0 and 1
+    pub fn fragmentation(&self) -> f64 {
+        if self.slot_bytes == 0 {
+            0.0
+        } else {
+            1.0 - self.state_bytes as f64 / self.slot_bytes as f64
+        }
+    }
+}
+
+/// The memory taken by all actor classes, returned by `ActorSystem::memory_report`
+#[derive(Clone, Debug)]
+pub struct MemoryReport {
+    /// All registered actor classes, ordered by type ID
+    pub classes: Vec<ClassMemory>,
+}
+
+impl MemoryReport {
+    /// Bytes of all chunks allocated for instances, their IDs and inboxes
+    pub fn allocated_bytes(&self) -> usize {
+        self.classes.iter().map(ClassMemory::allocated_bytes).sum()
+    }
+
+    /// Bytes actually used by the state of instances and queued messages
+    pub fn used_bytes(&self) -> usize {
+        self.classes.iter().map(ClassMemory::used_bytes).sum()
+    }
+
+    /// All classes, the ones with the most allocated memory first
+    pub fn largest_first(&self) -> Vec<&ClassMemory> {
+        let mut classes = self.classes.iter().collect::<Vec<_>>();
+        classes.sort_by_key(|class| ::std::cmp::Reverse(class.allocated_bytes()));
+        classes
+    }
+}
+
+/// The memory taken by one actor class
+#[derive(Clone, Debug)]
+pub struct ClassMemory {
+    /// The type ID of the class
+    pub type_id: u16,
+    /// The full type name of the class
+    pub type_name: String,
+    /// Number of local instances
+    pub instances: usize,
+    /// Bytes of all chunks allocated to store instances
+    pub instance_bytes_allocated: usize,
+    /// Bytes taken by the state of all local instances
+    pub instance_bytes_used: usize,
+    /// Bytes of all chunks allocated to look up instances by ID
+    pub id_bytes_allocated: usize,
+    /// Number of messages queued in the inbox of the class
+    pub inbox_messages: usize,
+    /// Bytes of the chunks needed for the queued messages (approximated, since
+    /// the queue doesn't tell how many chunks it holds on to)
+    pub inbox_bytes_allocated: usize,
+    /// Bytes of all messages queued in the inbox of the class
+    pub inbox_bytes_used: usize,
+    /// Bytes of queued messages that were spilled to disk, see `Tuning::inbox_spill_bytes`
+    pub inbox_bytes_spilled: usize,
+}
+
+impl ClassMemory {
+    /// Bytes of all chunks allocated for the class
+    pub fn allocated_bytes(&self) -> usize {
+        self.instance_bytes_allocated + self.id_bytes_allocated + self.inbox_bytes_allocated
+    }
+
+    /// Bytes actually used by the state of instances and queued messages
+    pub fn used_bytes(&self) -> usize {
+        self.instance_bytes_used + self.inbox_bytes_used
+    }
+}
+
+/// The networking turn state, as seen locally
+#[derive(Clone, Debug)]
+pub struct NetworkingInspection {
+    /// The machine ID of the local actor system
+    pub machine_id: MachineID,
+    /// The local number of networking turns
+    pub n_turns: usize,
+    /// The last turn each connected peer finished
+    pub peer_n_turns: HashMap<MachineID, usize>,
+    /// The machine currently acting as host
+    pub host: MachineID,
+    /// Did all machines stop advancing turns because of a requested pause?
+    pub paused: bool,
+}
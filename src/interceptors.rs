@@ -0,0 +1,147 @@
+use crate::actor_system::World;
+use crate::class::inbox::DispatchablePacket;
+use crate::debugger::PacketHeader;
+use crate::messaging::Fate;
+use crate::type_registry::ShortTypeId;
+
+/// Refers to an interceptor added with `ActorSystem::intercept` or `ActorSystem::observe_handled`
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct InterceptorID(u32);
+
+/// What should happen with a packet after an interceptor looked at it
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Intercept {
+    /// Pass it on to the next interceptor and finally the handler
+    Deliver,
+    /// Drop it without handling it
+    Drop,
+}
+
+/// How handling a packet went, passed to observers added with `ActorSystem::observe_handled`
+#[derive(Copy, Clone, Debug)]
+pub struct Handled {
+    /// How long handling took in milliseconds
+    pub duration_ms: f64,
+    /// The fate the recipient chose, `None` for broadcasts, batched handlers
+    /// and packets that no instance handled (like ones to dead or migrated instances).
+    /// Instances that die handling a broadcast are reported as `SystemEvent::InstanceDied`.
+    pub fate: Option<Fate>,
+}
+
+type BeforeFn = Box<dyn Fn(*mut (), &PacketHeader, &mut World) -> Intercept>;
+type AfterFn = Box<dyn Fn(&PacketHeader, &Handled, &mut World)>;
+
+enum Interceptor {
+    /// Called before handling packets of one message type (or all, with `None`)
+    Before(Option<ShortTypeId>, BeforeFn),
+    /// Called after handling any packet, with how that went
+    After(AfterFn),
+}
+
+/// The middleware chain all packets pass through before and after dispatch, in the order it was added in
+pub(crate) struct Interceptors {
+    chain: Vec<(InterceptorID, Interceptor)>,
+    next_id: u32,
+}
+
+impl Interceptors {
+    pub fn new() -> Interceptors {
+        Interceptors {
+            chain: Vec::new(),
+            next_id: 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chain.is_empty()
+    }
+
+    fn push(&mut self, interceptor: Interceptor) -> InterceptorID {
+        let id = InterceptorID(self.next_id);
+        self.next_id += 1;
+        self.chain.push((id, interceptor));
+        id
+    }
+
+    pub fn add_before(&mut self, message_type: Option<ShortTypeId>, before: BeforeFn) -> InterceptorID {
+        self.push(Interceptor::Before(message_type, before))
+    }
+
+    pub fn add_after(&mut self, after: AfterFn) -> InterceptorID {
+        self.push(Interceptor::After(after))
+    }
+
+    pub fn remove(&mut self, id: InterceptorID) -> bool {
+        let n_interceptors = self.chain.len();
+        self.chain.retain(|(existing, _)| *existing != id);
+        self.chain.len() < n_interceptors
+    }
+
+    pub fn wants_after(&self) -> bool {
+        self.chain.iter().any(|(_, interceptor)| match interceptor {
+            Interceptor::After(_) => true,
+            _ => false,
+        })
+    }
+
+    /// Run all interceptors for the packet, stops at the first that drops it
+    pub fn before(&self, packet: &DispatchablePacket, header: &PacketHeader, world: &mut World) -> Intercept {
+        for (_, interceptor) in &self.chain {
+            if let Interceptor::Before(message_type, before) = interceptor {
+                if message_type.map_or(true, |message_type| message_type == packet.message_type)
+                    && before(packet.packet_ptr as *mut (), header, world) == Intercept::Drop
+                {
+                    return Intercept::Drop;
+                }
+            }
+        }
+        Intercept::Deliver
+    }
+
+    pub fn after(&self, header: &PacketHeader, handled: &Handled, world: &mut World) {
+        for (_, interceptor) in &self.chain {
+            if let Interceptor::After(after) = interceptor {
+                after(header, handled, world);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_observers_get_fates() {
+    use crate::id::TypedID;
+    use crate::test_support::{local_system, Add, Counter};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let mut system = local_system();
+    system.register::<Counter>();
+    system.add_handler::<Counter, _, _>(
+        |&Add(n), counter, _| {
+            counter.count += n;
+            if counter.count > 1 {
+                Fate::Die
+            } else {
+                Fate::Live
+            }
+        },
+        false,
+    );
+    let fates = Rc::new(RefCell::new(Vec::new()));
+    let observed = fates.clone();
+    system.observe_handled(move |_header, handled, _world| {
+        observed.borrow_mut().push(match handled.fate {
+            Some(Fate::Live) => "live",
+            Some(Fate::Die) => "die",
+            None => "none",
+        });
+    });
+
+    let counter = system.spawn_many(vec![Counter::new(0)])[0];
+    for _ in 0..3 {
+        system.send(counter.as_raw(), Add(1));
+    }
+    system.process_all_messages();
+
+    assert_eq!(*fates.borrow(), vec!["live", "die", "none"]);
+}
@@ -0,0 +1,121 @@
+use crate::id::MachineID;
+use crate::spatial::SpatialArea;
+use crate::type_registry::ShortTypeId;
+use compact::CVec;
+use std::collections::HashMap;
+
+/// Broadcast to the system services of all machines to replicate
+/// which broadcasts a machine wants to receive
+#[derive(Compact, Clone)]
+pub(crate) struct InterestDeclaration {
+    pub machine: MachineID,
+    /// Broadcasts of positioned message types are only sent to the machine
+    /// if their position is in one of `areas`, unless it is interested everywhere
+    pub everywhere: bool,
+    pub areas: CVec<SpatialArea>,
+    /// Broadcasts to these classes or traits aren't sent to the machine
+    pub ignored_classes: CVec<ShortTypeId>,
+}
+
+crate::compact_bounds!(InterestDeclaration { areas, ignored_classes });
+
+impl InterestDeclaration {
+    fn everything(machine: MachineID) -> InterestDeclaration {
+        InterestDeclaration {
+            machine,
+            everywhere: true,
+            areas: CVec::new(),
+            ignored_classes: CVec::new(),
+        }
+    }
+
+    fn wants(&self, recipient_type: ShortTypeId, position: Option<(f32, f32)>) -> bool {
+        let in_areas = match position {
+            Some(position) if !self.everywhere => self.areas.iter().any(|area| area.contains(position)),
+            _ => true,
+        };
+        in_areas && !self.ignored_classes.contains(&recipient_type)
+    }
+}
+
+/// The interests of this machine and its peers, which decide which peers
+/// broadcasts are sent to, see `ActorSystem::set_interest_areas`
+pub(crate) struct Interests {
+    pub local: InterestDeclaration,
+    peers: HashMap<MachineID, InterestDeclaration>,
+    /// Where messages of positioned types are, given a pointer to one
+    positions: HashMap<ShortTypeId, Box<dyn Fn(*const ()) -> (f32, f32)>>,
+}
+
+impl Interests {
+    pub fn new(machine: MachineID) -> Interests {
+        Interests {
+            local: InterestDeclaration::everything(machine),
+            peers: HashMap::new(),
+            positions: HashMap::new(),
+        }
+    }
+
+    pub fn set_position(&mut self, message_type: ShortTypeId, position: Box<dyn Fn(*const ()) -> (f32, f32)>) {
+        self.positions.insert(message_type, position);
+    }
+
+    pub fn update(&mut self, declaration: &InterestDeclaration) {
+        if declaration.machine != self.local.machine {
+            self.peers.insert(declaration.machine, declaration.clone());
+        }
+    }
+
+    /// Forget the interest of a machine that disconnected, it gets everything when it reconnects
+    /// until it declares its interest again
+    pub fn forget(&mut self, machine: MachineID) {
+        self.peers.remove(&machine);
+    }
+
+    /// Are there any interests that could filter broadcasts?
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    /// The position of a message, if its type is positioned
+    pub fn position(&self, message_type: ShortTypeId, message: *const ()) -> Option<(f32, f32)> {
+        self.positions.get(&message_type).map(|position| position(message))
+    }
+
+    /// Should a broadcast to `recipient_type` at `position` be sent to `machine`?
+    pub fn wants(&self, machine: MachineID, recipient_type: ShortTypeId, position: Option<(f32, f32)>) -> bool {
+        self.peers
+            .get(&machine)
+            .map_or(true, |declaration| declaration.wants(recipient_type, position))
+    }
+}
+
+#[test]
+fn test_interests() {
+    let class = ShortTypeId::new(3).unwrap();
+    let other_class = ShortTypeId::new(4).unwrap();
+    let message_type = ShortTypeId::new(5).unwrap();
+    let mut interests = Interests::new(MachineID(0));
+    interests.set_position(message_type, Box::new(|message: *const ()| unsafe { *(message as *const (f32, f32)) }));
+    assert!(interests.wants(MachineID(1), class, Some((100.0, 100.0))));
+
+    let mut declaration = InterestDeclaration::everything(MachineID(1));
+    declaration.everywhere = false;
+    declaration.areas.push(SpatialArea::Box {
+        min: (0.0, 0.0),
+        max: (10.0, 10.0),
+    });
+    declaration.ignored_classes.push(other_class);
+    interests.update(&declaration);
+
+    let position = interests.position(message_type, &(5.0f32, 5.0f32) as *const (f32, f32) as *const ());
+    assert_eq!(position, Some((5.0, 5.0)));
+    assert!(interests.wants(MachineID(1), class, position));
+    assert!(!interests.wants(MachineID(1), class, Some((50.0, 5.0))));
+    assert!(interests.wants(MachineID(1), class, None));
+    assert!(!interests.wants(MachineID(1), other_class, None));
+    assert!(interests.wants(MachineID(2), other_class, Some((50.0, 5.0))));
+
+    interests.forget(MachineID(1));
+    assert!(interests.is_empty());
+}
@@ -0,0 +1,198 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use compact::Compact;
+use crate::messaging::{Message, Packet};
+use crate::type_registry::{ShortTypeId, TypeRegistry};
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+
+const JOURNAL_MAGIC: &[u8; 8] = b"KAYJRN01";
+
+/// Journals every message that is injected into an `ActorSystem` from the outside
+/// (not sent from inside a message handler), together with the turn it was sent in
+/// and its position among all injected messages. Each message is stored exactly
+/// like in an inbox, so it can be put into inboxes again as it is.
+///
+/// Layout: the magic bytes, then for each message
+/// `[turn: u32][sequence number: u32][length: u32][message type and packet bytes]`
+pub(crate) struct MessageJournal {
+    writer: Box<dyn Write>,
+    next_sequence: u32,
+}
+
+impl MessageJournal {
+    pub fn new(mut writer: Box<dyn Write>) -> ::std::io::Result<MessageJournal> {
+        writer.write_all(JOURNAL_MAGIC)?;
+        writer.flush()?;
+        Ok(MessageJournal {
+            writer,
+            next_sequence: 0,
+        })
+    }
+
+    pub fn record<M: Message>(&mut self, n_turns: usize, packet: &Packet<M>, message_registry: &TypeRegistry) {
+        let data = encode(packet.clone(), message_registry);
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+
+        // flushed right away, so the journal is complete even if the process dies
+        let result = self
+            .writer
+            .write_u32::<LittleEndian>(n_turns as u32)
+            .and_then(|_| self.writer.write_u32::<LittleEndian>(sequence))
+            .and_then(|_| self.writer.write_u32::<LittleEndian>(data.len() as u32))
+            .and_then(|_| self.writer.write_all(&data))
+            .and_then(|_| self.writer.flush());
+
+        if let Err(e) = result {
+            println!("Error while journaling message: {}", e);
+        }
+    }
+}
+
+/// Write a packet the same way `Inbox::put` does
+fn encode<M: Message>(mut packet: Packet<M>, message_registry: &TypeRegistry) -> Vec<u8> {
+    let type_size = ::std::mem::size_of::<ShortTypeId>();
+    let mut data = vec![0u8; type_size + packet.total_size_bytes()];
+
+    #[allow(clippy::cast_ptr_alignment)]
+    unsafe {
+        *(data.as_mut_ptr() as *mut ShortTypeId) = message_registry.get::<M>();
+        let payload_ptr = data.as_mut_ptr().add(type_size);
+        Compact::compact_behind(&mut packet, payload_ptr as *mut Packet<M>);
+        ::std::mem::forget(packet);
+    }
+
+    data
+}
+
+/// The messages of a journal written with `ActorSystem::start_journal`,
+/// fed into a fresh system by `ActorSystem::replay_journal` in the turns they were sent in
+pub(crate) struct JournalReplay {
+    messages: VecDeque<(usize, Vec<u8>)>,
+}
+
+impl JournalReplay {
+    pub fn new<R: Read>(mut reader: R) -> ::std::io::Result<JournalReplay> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != JOURNAL_MAGIC {
+            return Err(::std::io::Error::new(
+                ::std::io::ErrorKind::InvalidData,
+                "Not a kay message journal",
+            ));
+        }
+
+        let mut messages = VecDeque::new();
+        let mut expected_sequence = 0;
+
+        loop {
+            let n_turns = match reader.read_u32::<LittleEndian>() {
+                Ok(n_turns) => n_turns as usize,
+                Err(ref e) if e.kind() == ::std::io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            };
+            let sequence = reader.read_u32::<LittleEndian>()?;
+            if sequence != expected_sequence {
+                return Err(::std::io::Error::new(
+                    ::std::io::ErrorKind::InvalidData,
+                    "Message journal is missing messages",
+                ));
+            }
+            expected_sequence += 1;
+            let len = reader.read_u32::<LittleEndian>()? as usize;
+            let mut data = vec![0; len];
+            reader.read_exact(&mut data)?;
+            messages.push_back((n_turns, data));
+        }
+
+        Ok(JournalReplay { messages })
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.messages.len()
+    }
+
+    /// Take all messages that were sent up to turn `n_turns`, in their original order
+    pub fn take_until(&mut self, n_turns: usize) -> Vec<Vec<u8>> {
+        let n_due = self
+            .messages
+            .iter()
+            .take_while(|&&(message_turns, _)| message_turns <= n_turns)
+            .count();
+        self.messages.drain(..n_due).map(|(_, data)| data).collect()
+    }
+}
+
+#[test]
+fn test_replay_reproduces_journaled_state() {
+    use crate::actor_system::ActorSystem;
+    use crate::id::TypedID;
+    use crate::messaging::Fate;
+    use crate::test_support::{local_system, Add, Counter};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    struct SharedWriter(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedWriter {
+        fn write(&mut self, data: &[u8]) -> ::std::io::Result<usize> {
+            self.0.borrow_mut().write(data)
+        }
+        fn flush(&mut self) -> ::std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn setup(system: &mut ActorSystem) -> Vec<crate::test_support::CounterID> {
+        system.register::<Counter>();
+        system.add_handler::<Counter, _, _>(
+            |&Add(n), counter, world| {
+                counter.count = counter.count * 2 + n;
+                // sent from inside a handler, so not journaled
+                if n >= 10 {
+                    world.send(counter.id.as_raw(), Add(n - 10));
+                }
+                Fate::Live
+            },
+            false,
+        );
+        system.spawn_many(vec![Counter::new(0), Counter::new(1)])
+    }
+
+    fn run_turn(system: &mut ActorSystem) {
+        system.process_all_messages();
+        system.networking_finish_turn();
+    }
+
+    let journal = Rc::new(RefCell::new(Vec::new()));
+    let mut system = local_system();
+    let counters = setup(&mut system);
+    system.start_journal(SharedWriter(Rc::clone(&journal))).unwrap();
+    for turn in 0..6 {
+        system.send(counters[turn % 2].as_raw(), Add(turn as u32 + 9));
+        if turn == 3 {
+            system.send(counters[0].as_raw(), Add(1));
+        }
+        run_turn(&mut system);
+    }
+
+    let mut replayed = local_system();
+    setup(&mut replayed);
+    replayed.replay_journal(&journal.borrow()[..]).unwrap();
+    // ignored while replaying
+    replayed.send(counters[0].as_raw(), Add(100));
+    for _ in 0..6 {
+        run_turn(&mut replayed);
+    }
+
+    let mut fresh = local_system();
+    setup(&mut fresh);
+    assert_ne!(replayed.state_hash(), fresh.state_hash());
+    assert_eq!(replayed.state_hash(), system.state_hash());
+    for &counter in &counters {
+        assert_eq!(
+            replayed.instance::<Counter>(counter).unwrap().count,
+            system.instance::<Counter>(counter).unwrap().count
+        );
+    }
+}
@@ -0,0 +1,59 @@
+use crate::actor_system::World;
+use crate::id::RawID;
+use crate::messaging::Message;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+
+type SendJsonFn = Box<dyn Fn(RawID, &str, &mut World) -> Result<(), String>>;
+
+/// Message types that can be sent as JSON by external tools, by their registered type name
+/// (see `ActorSystem::register_json`)
+pub(crate) struct JsonMessages {
+    senders: HashMap<String, SendJsonFn>,
+}
+
+impl JsonMessages {
+    pub fn new() -> JsonMessages {
+        JsonMessages {
+            senders: HashMap::new(),
+        }
+    }
+
+    pub fn register<M: Message + DeserializeOwned>(&mut self, type_name: String) {
+        self.senders.insert(
+            type_name,
+            Box::new(|recipient, json, world| {
+                let message = from_json::<M>(json)?;
+                world.send(recipient, message);
+                Ok(())
+            }),
+        );
+    }
+
+    pub fn send(&self, recipient: RawID, type_name: &str, json: &str, world: &mut World) -> Result<(), String> {
+        let send = self
+            .senders
+            .get(type_name)
+            .ok_or_else(|| format!("{} isn't registered to be sent as JSON", type_name))?;
+        send(recipient, json, world)
+    }
+
+    /// The type names of all message types that can be sent as JSON, sorted
+    pub fn type_names(&self) -> Vec<&str> {
+        let mut type_names = self.senders.keys().map(String::as_str).collect::<Vec<_>>();
+        type_names.sort();
+        type_names
+    }
+}
+
+/// Convert a message or the state of an instance to JSON, for external tools.
+/// Compact collections like `CVec` convert like their standard counterparts.
+pub fn to_json<T: Serialize>(value: &T) -> String {
+    ::serde_json::to_string(value).expect("Couldn't convert to JSON")
+}
+
+/// Read a message or the state of an instance from JSON written by external tools
+pub fn from_json<T: DeserializeOwned>(json: &str) -> Result<T, String> {
+    ::serde_json::from_str(json).map_err(|e| e.to_string())
+}
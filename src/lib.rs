@@ -18,6 +18,19 @@
 //! serialisation-free linear memory layouts for plain old data and nested datastructures.
 //! This does, in turn, impose the constraint that actor state and messages need to implement
 //! [Compact](https://TODO)
+//!
+//! # Determinism
+//!
+//! Machines running the same turns with the same messages end up in the same state,
+//! because kay handles messages in a deterministic order:
+//! each class handles its inbox in the order messages arrived, batches from other machines
+//! are handled in the order of their machine IDs, broadcasts reach instances in the order
+//! they are stored, and deferred actions, timeouts and disconnect notifications follow the
+//! order in which they were caused. Handlers have to keep this up: iterate
+//! `DeterministicHashMap`s instead of `std` hash maps and use `World::rng` for randomness.
+//! Messages sent after a duration, query and gather timeouts and background tasks depend on
+//! the local clock and are not deterministic. `ActorSystem::enable_determinism_audit` records
+//! their uses, and those reported with `World::audit_nondeterminism`, during test runs.
 
 #![warn(missing_docs)]
 #![feature(core_intrinsics)]
@@ -35,38 +48,135 @@ extern crate core;
 extern crate stdweb;
 #[cfg(feature = "server")]
 extern crate tungstenite;
+#[cfg(feature = "server")]
+extern crate rand;
+#[cfg(feature = "encryption")]
+extern crate snow;
+#[cfg(feature = "compression")]
+extern crate zstd;
 extern crate url;
 #[cfg(feature = "serde-serialization")]
 #[macro_use]
 extern crate serde_derive;
 #[cfg(feature = "serde-serialization")]
 extern crate serde;
-
-macro_rules! make_array {
-    ($n:expr, $constructor:expr) => {{
-        let mut items: [_; $n] = ::std::mem::uninitialized();
-        for (i, place) in items.iter_mut().enumerate() {
-            ::std::ptr::write(place, $constructor(i));
-        }
-        items
-    }};
-}
+#[cfg(feature = "serde-serialization")]
+extern crate serde_json;
 
 mod tuning;
 mod actor;
+#[cfg(feature = "admin")]
+mod admin;
 mod actor_system;
 mod external;
+mod gather;
+mod history;
 mod id;
+mod id_allocation;
+mod inspector;
+mod interceptors;
+mod interest;
+mod journal;
+mod metrics;
+#[cfg(feature = "serde-serialization")]
+mod json;
 mod class;
+mod client_protocol;
+mod columns;
+#[cfg(feature = "serde-serialization")]
+mod console;
+mod dead_letters;
+mod debugger;
+mod determinism;
+mod diagnosis;
 mod messaging;
+mod migration;
+mod names;
 mod networking;
+mod parallel;
+mod phases;
+mod placement;
+mod plugin;
+mod profiling;
+mod query;
+mod random;
+mod rate_limits;
+mod scheduler;
+mod scheduling;
+mod scratch;
+mod shutdown;
+mod snapshot;
+mod spatial;
+mod state_hash;
 mod storage_aware;
+mod supervision;
+mod system_events;
+mod system_group;
+mod tasks;
+#[cfg(test)]
+#[macro_use]
+mod test_support;
+mod time;
+mod topics;
+mod topology;
+mod trace;
+mod turn_driver;
+mod type_manifest;
 mod type_registry;
+mod watches;
 
 pub use self::actor::{Actor, ActorOrActorTrait, TraitIDFrom};
 pub use self::actor_system::{ActorSystem, World};
+pub use self::dead_letters::DeadLetter;
+pub use self::debugger::{BreakpointID, DebugStop, PacketHeader};
+pub use self::determinism::{
+    DeterministicHashMap, DeterministicHashSet, DeterministicHasher, NondeterminismReport, NondeterminismSource,
+};
+pub use self::diagnosis::{DesyncDiagnosis, DivergedChunk};
+pub use self::client_protocol::{ClientMessageType, ClientProtocol};
+pub use self::columns::{Column, ColumnLayout, Columns};
 pub use self::external::External;
-pub use self::id::{MachineID, RawID, TypedID};
-pub use self::messaging::{Fate, Message, Packet};
-pub use self::networking::Networking;
-pub use self::tuning::Tuning;
\ No newline at end of file
+pub use self::gather::{Gather, GatherID, Gathered, Gatherer};
+pub use self::phases::{FlowEnforcement, FlowProblem, FlowViolation};
+pub use self::placement::PlacementPolicy;
+pub use self::plugin::Plugin;
+pub use self::profiling::{ClassProfile, ProfilingReport};
+pub use self::rate_limits::{RateLimitAction, RateLimitCounters};
+pub use self::random::DeterministicRng;
+pub use self::query::{Asker, QueryHandle, QueryStatus, QueryTimedOut};
+pub use self::scheduler::ScheduledMessage;
+pub use self::scheduling::SchedulingPolicy;
+pub use self::scratch::{Scratch, ScratchScope};
+pub use self::shutdown::ShutdownReport;
+pub use self::spatial::{FoundInstances, Positioned, SpatialArea, SpatialQueryID};
+pub use self::topics::Topic;
+pub use self::turn_driver::{SimulationSpeed, TurnDriver};
+pub use self::system_events::SystemEvent;
+pub use self::system_group::{HostedSystemID, SystemGroup};
+pub use self::topology::{MessageTopology, TopologyEdge};
+pub use self::supervision::{HandlerPanicked, SupervisionPolicy};
+pub use self::history::{HistoryTurn, InstanceHistory, ReceivedMessage};
+pub use self::id_allocation::{IdAllocator, IdBlock, MachineRanges};
+pub use self::id::{MachineID, NamedRawID, ParseRawIDError, RawID, TypedID};
+pub use self::interceptors::{Handled, Intercept, InterceptorID};
+#[cfg(feature = "serde-serialization")]
+pub use self::json::{from_json, to_json};
+pub use self::inspector::{
+    ClassInspection, ClassMemory, ClassOccupancy, MemoryReport, NetworkingInspection, SystemInspection,
+};
+pub use self::messaging::{dynamic_parts_within, CompactBounds, Correlation, Fate, Message, Packet};
+pub use self::networking::{
+    BufferPoolStats, ClockStats, DesyncDetected, InvalidPeerAddress, LinkConditions, LockstepTurns, LockstepWait, MachineRole,
+    MessageTraffic, NetworkPaused, NetworkResumed, NetworkTraffic, Networking, NetworkingBuilder,
+    PeerAddress, PeerConnected, PeerDisconnected, PeerLagging, PlaybackNetworking, RemoteMessageRejected,
+    TurnProtocol,
+};
+#[cfg(feature = "server")]
+pub use self::networking::{DiscoveredPeer, Discovery, KeepAlive, Proxy, SocketOptions};
+pub use self::tuning::Tuning;
+pub use self::type_manifest::{TypeKind, TypeManifest, TypeManifestEntry, TypeSchema};
+pub use self::type_registry::TypeIdUsage;
+pub use chunky::{Chunk, ChunkStorage, HeapStorage, Ident};
+#[cfg(feature = "server")]
+pub use chunky::MmapStorage;
+pub use self::watches::Terminated;
@@ -1,8 +1,9 @@
-use super::compact::Compact;
-use super::id::RawID;
+use super::compact::{COption, CString, CVec, Compact};
+use super::id::{MachineID, RawID};
 use super::World;
 
 /// The self-chosen fate of an actor instance it returns after handling a message
+#[derive(Copy, Clone, Debug)]
 pub enum Fate {
     /// The actor should continue to live after handling this message
     Live,
@@ -14,6 +15,93 @@ pub enum Fate {
 pub trait Message: Compact + 'static {}
 impl<T: Compact + 'static> Message for T {}
 
+/// Needs to be implemented by message types with dynamic parts (like `CVec` or `CString` fields)
+/// to receive them from peers: checks that the relative pointers of all dynamic parts stay
+/// within the received message, so a malformed message can't make handlers read beyond it.
+/// Messages with dynamic parts that don't implement it are rejected from peers.
+/// Implement it with `compact_bounds!` for structs.
+pub trait CompactBounds {
+    /// Do all dynamic parts lie within `bounds`, the bytes of the received message?
+    fn dynamic_parts_within(&self, bounds: &[u8]) -> bool;
+}
+
+/// Implement `CompactBounds` for a struct by checking the listed fields, which should be
+/// all fields with dynamic parts: `compact_bounds!(Chat { sender_name, text });`
+/// (or `compact_bounds!(Reply<R> { reply });` for a struct generic over messages)
+#[macro_export]
+macro_rules! compact_bounds {
+    ($message:ident<$($param:ident),*> { $($field:ident),* }) => {
+        impl<$($param: $crate::Message),*> $crate::CompactBounds for $message<$($param),*> {
+            fn dynamic_parts_within(&self, bounds: &[u8]) -> bool {
+                true $(&& $crate::dynamic_parts_within(&self.$field, bounds))*
+            }
+        }
+    };
+    ($message:ty { $($field:ident),* }) => {
+        impl $crate::CompactBounds for $message {
+            fn dynamic_parts_within(&self, bounds: &[u8]) -> bool {
+                true $(&& $crate::dynamic_parts_within(&self.$field, bounds))*
+            }
+        }
+    };
+}
+
+/// Check the dynamic parts of a value received from a peer, see `CompactBounds`.
+/// Values without drop glue can't own dynamic parts, so they are always fine.
+pub fn dynamic_parts_within<T>(value: &T, bounds: &[u8]) -> bool {
+    CheckBounds::within(value, bounds)
+}
+
+trait CheckBounds {
+    fn within(&self, bounds: &[u8]) -> bool;
+}
+
+impl<T> CheckBounds for T {
+    default fn within(&self, _bounds: &[u8]) -> bool {
+        !::std::mem::needs_drop::<T>()
+    }
+}
+
+impl<T: CompactBounds> CheckBounds for T {
+    fn within(&self, bounds: &[u8]) -> bool {
+        self.dynamic_parts_within(bounds)
+    }
+}
+
+/// Does the memory of `len` bytes at `ptr` lie within `bounds`?
+fn range_within(ptr: *const u8, len: usize, bounds: &[u8]) -> bool {
+    let (start, ptr) = (bounds.as_ptr() as usize, ptr as usize);
+    len == 0 || (ptr >= start && ptr.checked_add(len).map_or(false, |end| end <= start + bounds.len()))
+}
+
+impl<T: Compact + Clone> CompactBounds for CVec<T> {
+    fn dynamic_parts_within(&self, bounds: &[u8]) -> bool {
+        let capacity_bytes = self.capacity().checked_mul(::std::mem::size_of::<T>());
+        self.len() <= self.capacity()
+            && capacity_bytes.map_or(false, |bytes| range_within(self.as_ptr() as *const u8, bytes, bounds))
+            && self.iter().all(|element| dynamic_parts_within(element, bounds))
+            // pointers to the heap of the sender are never compact
+            && self.is_still_compact()
+    }
+}
+
+impl CompactBounds for CString {
+    fn dynamic_parts_within(&self, bounds: &[u8]) -> bool {
+        // the dynamic part of a string is its capacity in bytes
+        let capacity_bytes = self.dynamic_size_bytes();
+        self.len() <= capacity_bytes
+            && range_within(self.as_ptr(), capacity_bytes, bounds)
+            && self.is_still_compact()
+            && ::std::str::from_utf8(self.as_bytes()).is_ok()
+    }
+}
+
+impl<T: Compact + Clone> CompactBounds for COption<T> {
+    fn dynamic_parts_within(&self, bounds: &[u8]) -> bool {
+        self.0.as_ref().map_or(true, |value| dynamic_parts_within(value, bounds))
+    }
+}
+
 pub type HandlerFnRef = dyn Fn(*mut(), *const (), &mut World) -> Fate;
 
 #[derive(Compact, Clone)]
@@ -22,6 +110,41 @@ pub type HandlerFnRef = dyn Fn(*mut(), *const (), &mut World) -> Fate;
 pub struct Packet<M: Message> {
     /// The recipient
     pub recipient_id: RawID,
+    /// The query this packet is the request of or the reply to, if any
+    pub correlation: Correlation,
     /// The message
     pub message: M,
 }
+
+/// Ties the request of a query and its reply together (see `World::query`),
+/// carried in the header of both packets
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+#[repr(C)]
+pub struct Correlation {
+    /// Counts the queries asked by `machine`, zero in packets that aren't part of a query
+    pub id: u32,
+    /// The machine that asked the query
+    pub machine: MachineID,
+    _padding: [u8; 2],
+}
+
+impl Correlation {
+    /// The query with `id` asked by `machine` (with a nonzero `id`)
+    pub fn new(id: u32, machine: MachineID) -> Correlation {
+        Correlation {
+            id,
+            machine,
+            _padding: [0; 2],
+        }
+    }
+
+    /// The correlation of packets that aren't part of a query
+    pub fn none() -> Correlation {
+        Correlation::new(0, MachineID(0))
+    }
+
+    /// Is the packet the request of or the reply to a query?
+    pub fn is_query(&self) -> bool {
+        self.id != 0
+    }
+}
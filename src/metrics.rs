@@ -0,0 +1,79 @@
+use std::fmt::Write;
+
+/// Renders metrics in the Prometheus text exposition format, see `ActorSystem::metrics`
+pub(crate) struct MetricsWriter {
+    out: String,
+}
+
+/// The kind of a metric, as declared in its `# TYPE` line
+#[derive(Copy, Clone)]
+pub(crate) enum MetricKind {
+    Counter,
+    Gauge,
+}
+
+impl MetricsWriter {
+    pub fn new() -> MetricsWriter {
+        MetricsWriter { out: String::new() }
+    }
+
+    /// Write a metric with one sample per set of labels, sorted by labels so the output is stable.
+    /// Metrics without samples are left out.
+    pub fn metric(&mut self, name: &str, kind: MetricKind, help: &str, mut samples: Vec<(Vec<(&str, String)>, f64)>) {
+        if samples.is_empty() {
+            return;
+        }
+        samples.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let kind = match kind {
+            MetricKind::Counter => "counter",
+            MetricKind::Gauge => "gauge",
+        };
+        writeln!(self.out, "# HELP {} {}", name, help).expect("Writing to a string");
+        writeln!(self.out, "# TYPE {} {}", name, kind).expect("Writing to a string");
+        for (labels, value) in samples {
+            self.out.push_str(name);
+            if !labels.is_empty() {
+                let labels = labels
+                    .iter()
+                    .map(|(label, value)| format!("{}=\"{}\"", label, escape_label_value(value)))
+                    .collect::<Vec<_>>();
+                write!(self.out, "{{{}}}", labels.join(",")).expect("Writing to a string");
+            }
+            writeln!(self.out, " {}", value).expect("Writing to a string");
+        }
+    }
+
+    pub fn finish(self) -> String {
+        self.out
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[test]
+fn test_metrics_format() {
+    let mut metrics = MetricsWriter::new();
+    metrics.metric("kay_turn", MetricKind::Gauge, "The current turn", vec![(vec![], 12.0)]);
+    metrics.metric("kay_unused", MetricKind::Gauge, "Has no samples", vec![]);
+    metrics.metric(
+        "kay_messages_total",
+        MetricKind::Counter,
+        "Messages handled",
+        vec![
+            (vec![("message_type", "b::Move".to_owned())], 3.0),
+            (vec![("message_type", "a::Say<\"hi\">".to_owned())], 0.5),
+        ],
+    );
+    assert_eq!(
+        metrics.finish(),
+        "# HELP kay_turn The current turn\n\
+         # TYPE kay_turn gauge\n\
+         kay_turn 12\n\
+         # HELP kay_messages_total Messages handled\n\
+         # TYPE kay_messages_total counter\n\
+         kay_messages_total{message_type=\"a::Say<\\\"hi\\\">\"} 0.5\n\
+         kay_messages_total{message_type=\"b::Move\"} 3\n"
+    );
+}
@@ -0,0 +1,192 @@
+use crate::actor_system::World;
+use crate::id::RawID;
+use compact::CVec;
+use std::collections::HashMap;
+
+/// For how many turns after a migration messages to the old ID of an instance
+/// are still forwarded to its new ID
+pub(crate) const MIGRATION_GRACE_TURNS: usize = 100;
+
+/// For how many turns messages to a migrating instance are held back, before its migration
+/// counts as failed (its state got lost on the way, or its new machine disconnected)
+pub(crate) const MIGRATION_TIMEOUT_TURNS: usize = 1000;
+
+/// Sends a message that was addressed to a migrated instance to its new ID
+pub(crate) type Forwarding = Box<dyn FnOnce(RawID, &mut World)>;
+
+/// Creates the `Forwarding` of a message of a type-erased packet
+pub(crate) type ForwardFn = dyn Fn(*const ()) -> Forwarding;
+
+/// Sent to the system services of the machine an instance migrates to,
+/// carrying the compact state of the instance
+#[derive(Compact, Clone)]
+pub(crate) struct MigrateInstance {
+    pub old_id: RawID,
+    pub state: CVec<u8>,
+}
+
+crate::compact_bounds!(MigrateInstance { state });
+
+/// Broadcast to the system services of all machines once a migrated instance
+/// was added on its new machine, so messages are sent to its new ID
+#[derive(Compact, Clone)]
+pub(crate) struct InstanceMigrated {
+    pub old_id: RawID,
+    pub new_id: RawID,
+}
+
+enum Route {
+    /// The instance is on its way to another machine,
+    /// messages to it are held back until it arrived, or until turn `until_turn`
+    Pending { held_back: Vec<Forwarding>, until_turn: usize },
+    /// The instance moved to a new ID, until the grace period ends after turn `until_turn`
+    Moved { new_id: RawID, until_turn: usize },
+}
+
+/// Where instances that migrated away from their machine are now, as known on this machine
+pub(crate) struct Migrations {
+    routes: HashMap<RawID, Route>,
+}
+
+impl Migrations {
+    pub fn new() -> Migrations {
+        Migrations {
+            routes: HashMap::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    /// Note that an instance of this machine started migrating to another machine in turn `n_turns`
+    pub fn start(&mut self, old_id: RawID, n_turns: usize) {
+        self.routes.insert(
+            old_id,
+            Route::Pending {
+                held_back: Vec::new(),
+                until_turn: n_turns + MIGRATION_TIMEOUT_TURNS,
+            },
+        );
+    }
+
+    /// Note the new ID of a migrated instance, returning the messages held back for it
+    pub fn moved(&mut self, old_id: RawID, new_id: RawID, n_turns: usize) -> Vec<Forwarding> {
+        let previous = self.routes.insert(
+            old_id,
+            Route::Moved {
+                new_id,
+                until_turn: n_turns + MIGRATION_GRACE_TURNS,
+            },
+        );
+        match previous {
+            Some(Route::Pending { held_back, .. }) => held_back,
+            _ => Vec::new(),
+        }
+    }
+
+    /// The current ID of an instance, following all its migrations
+    pub fn resolve(&self, mut id: RawID) -> RawID {
+        while let Some(&Route::Moved { new_id, .. }) = self.routes.get(&id) {
+            id = new_id;
+        }
+        id
+    }
+
+    /// Is the instance with this ID migrating or did it migrate?
+    pub fn has_route(&self, id: RawID) -> bool {
+        self.routes.contains_key(&id)
+    }
+
+    /// Hold back a message to an instance that is still migrating, or return
+    /// the new ID of the instance to forward the message to
+    pub fn forward(&mut self, id: RawID, forwarding: Forwarding) -> Option<(RawID, Forwarding)> {
+        match self.routes.get_mut(&id) {
+            Some(Route::Pending { held_back, .. }) => {
+                held_back.push(forwarding);
+                None
+            }
+            Some(&mut Route::Moved { new_id, .. }) => Some((self.resolve(new_id), forwarding)),
+            None => None,
+        }
+    }
+
+    /// Forget the routes of instances whose grace period ended, and of migrations that timed out,
+    /// returning the old IDs of the latter with the messages held back for them
+    pub fn expire(&mut self, n_turns: usize) -> Vec<(RawID, Vec<Forwarding>)> {
+        let timed_out = self
+            .routes
+            .iter()
+            .filter_map(|(&id, route)| match *route {
+                Route::Pending { until_turn, .. } if until_turn < n_turns => Some(id),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let timed_out = timed_out
+            .into_iter()
+            .map(|id| match self.routes.remove(&id) {
+                Some(Route::Pending { held_back, .. }) => (id, held_back),
+                _ => (id, Vec::new()),
+            })
+            .collect();
+        self.routes.retain(|_, route| match *route {
+            Route::Pending { .. } => true,
+            Route::Moved { until_turn, .. } => until_turn >= n_turns,
+        });
+        timed_out
+    }
+}
+
+#[test]
+fn test_migration_arrival_and_timeout() {
+    use crate::actor_system::ActorSystem;
+    use crate::id::{MachineID, TypedID};
+    use crate::messaging::Fate;
+    use crate::test_support::{local_system, Add, Counter};
+
+    let mut system = local_system();
+    system.register::<Counter>();
+    system.add_handler::<Counter, _, _>(
+        |&Add(n), counter, _| {
+            counter.count += n;
+            Fate::Live
+        },
+        false,
+    );
+    let counts = |system: &mut ActorSystem| {
+        let mut counts = Vec::new();
+        system.world().for_each_instance::<Counter, _>(|counter| counts.push((counter.id.as_raw(), counter.count)));
+        counts
+    };
+
+    // an instance arrives from machine 1, and messages to its old ID follow it
+    let counter_type = system.world().local_broadcast::<Counter>().type_id;
+    let old_id = RawID::new(counter_type, 3, MachineID(1), 0);
+    let state = Counter::new(7);
+    let state_bytes = unsafe { ::std::slice::from_raw_parts(&state as *const Counter as *const u8, ::std::mem::size_of::<Counter>()) };
+    let services = system.services_id(MachineID(0));
+    system.send(services, MigrateInstance { old_id, state: state_bytes.to_vec().into() });
+    system.process_all_messages();
+    system.send(old_id, Add(1));
+    system.process_all_messages();
+    let arrived = counts(&mut system);
+    assert_eq!(arrived.len(), 1);
+    assert!(arrived[0].0.machine == MachineID(0));
+    assert_eq!(arrived[0].1, 8);
+
+    // its new machine isn't in the network, so the instance never arrives there
+    let migrating = arrived[0].0;
+    assert!(system.migrate(migrating, MachineID(1)));
+    system.send(migrating, Add(1));
+    system.process_all_messages();
+    assert_eq!(system.instance_count::<Counter>(), 0);
+    assert_eq!(system.dead_letter_count(), 0);
+    for _ in 0..MIGRATION_TIMEOUT_TURNS + 2 {
+        system.process_all_messages();
+        system.networking_finish_turn();
+    }
+    system.process_all_messages();
+    assert!(!system.world().panic_happened());
+    assert_eq!(system.dead_letter_count(), 1);
+    assert_eq!(system.take_dead_letters()[0].recipient, migrating);
+}
@@ -0,0 +1,105 @@
+use crate::id::{MachineID, RawID};
+use compact::CString;
+use std::collections::HashMap;
+
+/// Broadcast to the system services of all machines to replicate a name
+#[derive(Compact, Clone)]
+pub(crate) struct NameRegistration {
+    pub name: CString,
+    pub id: RawID,
+    pub registered: bool,
+    /// The turn the name was (un)registered in, so all machines agree
+    /// on the latest registration of a name
+    pub n_turns: u32,
+    /// The machine that (un)registered the name, to order registrations from the same turn
+    pub from: MachineID,
+}
+
+crate::compact_bounds!(NameRegistration { name });
+
+/// The actors registered under a name, as known on this machine
+pub(crate) struct Names {
+    ids: HashMap<String, (RawID, u32, MachineID)>,
+}
+
+impl Names {
+    pub fn new() -> Names {
+        Names { ids: HashMap::new() }
+    }
+
+    /// Apply a registration, unless a newer one for the same name was applied already.
+    /// Registrations from the same turn are ordered by the machine they come from.
+    pub fn update(&mut self, registration: &NameRegistration) {
+        let name: &str = &registration.name;
+        let is_newer = match self.ids.get(name) {
+            Some(&(_, n_turns, from)) => {
+                registration.n_turns > n_turns || (registration.n_turns == n_turns && registration.from >= from)
+            }
+            None => true,
+        };
+        if registration.registered {
+            if is_newer {
+                self.ids
+                    .insert(name.to_owned(), (registration.id, registration.n_turns, registration.from));
+            }
+        } else if self.resolve(name) == Some(registration.id) {
+            self.ids.remove(name);
+        }
+    }
+
+    pub fn resolve(&self, name: &str) -> Option<RawID> {
+        self.ids.get(name).map(|&(id, _, _)| id)
+    }
+
+    /// All names of actors on `machine`, to tell a newly connected machine about them
+    pub fn registered_on(&self, machine: MachineID) -> Vec<NameRegistration> {
+        self.ids
+            .iter()
+            .filter(|(_, (id, _, _))| id.machine == machine)
+            .map(|(name, &(id, n_turns, from))| NameRegistration {
+                name: name.clone().into(),
+                id,
+                registered: true,
+                n_turns,
+                from,
+            })
+            .collect()
+    }
+
+    /// Forget the names of all actors on a machine that disconnected
+    pub fn forget(&mut self, machine: MachineID) {
+        self.ids.retain(|_, (id, _, _)| id.machine != machine);
+    }
+}
+
+#[test]
+fn test_names() {
+    use crate::test_support::test_id as id;
+
+    let registration = |registered, instance_id, machine, n_turns, from| NameRegistration {
+        name: "simulation".to_owned().into(),
+        id: id(instance_id, machine),
+        registered,
+        n_turns,
+        from: MachineID(from),
+    };
+    let mut names = Names::new();
+
+    names.update(&registration(true, 1, 0, 5, 0));
+    assert_eq!(names.resolve("simulation"), Some(id(1, 0)));
+    names.update(&registration(true, 2, 1, 4, 1));
+    assert_eq!(names.resolve("simulation"), Some(id(1, 0)));
+    names.update(&registration(true, 2, 1, 5, 1));
+    assert_eq!(names.resolve("simulation"), Some(id(2, 1)));
+    names.update(&registration(false, 1, 0, 6, 0));
+    assert_eq!(names.resolve("simulation"), Some(id(2, 1)));
+
+    // in the same turn, the registering machine decides, not the one of the actor
+    names.update(&registration(true, 3, 0, 7, 2));
+    names.update(&registration(true, 4, 3, 7, 1));
+    assert_eq!(names.resolve("simulation"), Some(id(3, 0)));
+
+    assert_eq!(names.registered_on(MachineID(0)).len(), 1);
+    names.forget(MachineID(0));
+    assert_eq!(names.resolve("simulation"), None);
+}
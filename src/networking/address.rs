@@ -0,0 +1,122 @@
+use std::net::SocketAddr;
+
+/// The address a peer accepts networking connections on
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub enum PeerAddress {
+    /// An IPv4 or IPv6 address with a port
+    Ip(SocketAddr),
+    /// A DNS hostname with a port, resolved when connecting
+    Host {
+        /// The hostname
+        name: String,
+        /// The port
+        port: u16,
+    },
+    /// A full websocket URL, like `wss://example.com/kay`, only dialed by browser clients
+    Url(String),
+    /// A machine that doesn't accept connections, but only connects to others,
+    /// like a browser client
+    ClientOnly,
+}
+
+/// Reasons for a string not being a valid `PeerAddress`
+#[derive(Debug)]
+pub enum InvalidPeerAddress {
+    /// There is no `:port` at the end
+    MissingPort,
+    /// The port isn't a valid number
+    InvalidPort(::std::num::ParseIntError),
+    /// The host is neither an IP address nor a valid hostname
+    InvalidHost,
+}
+
+impl ::std::fmt::Display for InvalidPeerAddress {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        ::std::fmt::Debug::fmt(self, f)
+    }
+}
+
+impl PeerAddress {
+    /// Parse `"1.2.3.4:9999"`, `"[::1]:9999"`, `"example.com:9999"` or a websocket URL
+    pub fn parse(address: &str) -> Result<PeerAddress, InvalidPeerAddress> {
+        address.parse()
+    }
+}
+
+impl ::std::str::FromStr for PeerAddress {
+    type Err = InvalidPeerAddress;
+
+    fn from_str(address: &str) -> Result<Self, Self::Err> {
+        if address.contains("://") {
+            return Ok(PeerAddress::Url(address.to_owned()));
+        }
+
+        if let Ok(socket_address) = address.parse::<SocketAddr>() {
+            return Ok(PeerAddress::Ip(socket_address));
+        }
+
+        let colon = address.rfind(':').ok_or(InvalidPeerAddress::MissingPort)?;
+        let (name, port) = (&address[..colon], &address[colon + 1..]);
+        let port = port.parse().map_err(InvalidPeerAddress::InvalidPort)?;
+
+        let valid_hostname = !name.is_empty()
+            && name
+                .split('.')
+                .all(|label| {
+                    !label.is_empty()
+                        && !label.starts_with('-')
+                        && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+                });
+
+        if valid_hostname {
+            Ok(PeerAddress::Host {
+                name: name.to_owned(),
+                port,
+            })
+        } else {
+            Err(InvalidPeerAddress::InvalidHost)
+        }
+    }
+}
+
+impl ::std::fmt::Display for PeerAddress {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match *self {
+            // brackets IPv6 addresses as needed for URLs
+            PeerAddress::Ip(ref socket_address) => write!(f, "{}", socket_address),
+            PeerAddress::Host { ref name, port } => write!(f, "{}:{}", name, port),
+            PeerAddress::Url(ref url) => write!(f, "{}", url),
+            // the same as an unknown address, so nobody tries to connect to it
+            PeerAddress::ClientOnly => Ok(()),
+        }
+    }
+}
+
+impl From<SocketAddr> for PeerAddress {
+    fn from(socket_address: SocketAddr) -> PeerAddress {
+        PeerAddress::Ip(socket_address)
+    }
+}
+
+#[test]
+fn test_peer_address() {
+    let v4 = PeerAddress::parse("127.0.0.1:9999").unwrap();
+    assert_eq!(v4.to_string(), "127.0.0.1:9999");
+
+    let v6 = PeerAddress::parse("[::1]:9999").unwrap();
+    assert_eq!(v6.to_string(), "[::1]:9999");
+
+    let host = PeerAddress::parse("sim-3.example.com:80").unwrap();
+    assert_eq!(
+        host,
+        PeerAddress::Host {
+            name: "sim-3.example.com".to_owned(),
+            port: 80
+        }
+    );
+
+    assert!(PeerAddress::parse("https://example.com").is_ok());
+    assert!(PeerAddress::parse("example.com").is_err());
+    assert!(PeerAddress::parse("::1:9999").is_err());
+    assert!(PeerAddress::parse("exa mple.com:80").is_err());
+}
@@ -0,0 +1,88 @@
+/// How quickly the estimated bytes per turn follow the bytes enqueued in the last turn
+const SMOOTHING: f64 = 0.25;
+/// How much the largest recently enqueued message is forgotten per turn
+const LARGEST_MESSAGE_DECAY: f64 = 0.9;
+/// Batches should fit at least this many of the largest recent messages,
+/// so large messages are still sent together with others
+const MIN_LARGEST_MESSAGES_PER_BATCH: f64 = 2.0;
+
+/// The bounds and goal of adaptive batch sizes, see `Networking::enable_adaptive_batching`
+#[derive(Clone, Debug)]
+pub(crate) struct BatchingTarget {
+    pub min_batch_bytes: usize,
+    pub max_batch_bytes: usize,
+    pub frames_per_turn: usize,
+}
+
+/// Tracks the sizes of messages enqueued for one peer, to choose a batch size
+/// that sends the bytes of a typical turn in the target number of frames.
+///
+/// The bytes per turn are smoothed exponentially, so a single busy turn doesn't
+/// make the batches of the following turns huge, and the largest recent message
+/// only decays slowly, so batches stay big enough for messages that are sent regularly.
+pub(crate) struct BatchSizer {
+    bytes_this_turn: usize,
+    smoothed_bytes_per_turn: f64,
+    largest_message: f64,
+}
+
+impl BatchSizer {
+    pub fn new() -> BatchSizer {
+        BatchSizer {
+            bytes_this_turn: 0,
+            smoothed_bytes_per_turn: 0.0,
+            largest_message: 0.0,
+        }
+    }
+
+    /// Count a message of `size` bytes (including its length) enqueued in this turn
+    pub fn enqueued(&mut self, size: usize) {
+        self.bytes_this_turn += size;
+        self.largest_message = self.largest_message.max(size as f64);
+    }
+
+    /// Update the estimates once per finished turn, returns the batch size to use next
+    pub fn finish_turn(&mut self, target: &BatchingTarget) -> usize {
+        self.smoothed_bytes_per_turn += SMOOTHING * (self.bytes_this_turn as f64 - self.smoothed_bytes_per_turn);
+        self.bytes_this_turn = 0;
+        let for_frames = self.smoothed_bytes_per_turn / target.frames_per_turn as f64;
+        let for_largest = self.largest_message * MIN_LARGEST_MESSAGES_PER_BATCH;
+        self.largest_message *= LARGEST_MESSAGE_DECAY;
+        (for_frames.max(for_largest).ceil() as usize)
+            .max(target.min_batch_bytes)
+            .min(target.max_batch_bytes)
+    }
+}
+
+#[test]
+fn test_batch_size_follows_traffic() {
+    let target = BatchingTarget {
+        min_batch_bytes: 1000,
+        max_batch_bytes: 50_000,
+        frames_per_turn: 2,
+    };
+    let mut sizer = BatchSizer::new();
+    assert_eq!(sizer.finish_turn(&target), 1000);
+
+    let mut batch_bytes = 0;
+    for _turn in 0..50 {
+        for _message in 0..100 {
+            sizer.enqueued(200);
+        }
+        batch_bytes = sizer.finish_turn(&target);
+    }
+    assert!(batch_bytes > 9_900 && batch_bytes <= 10_000);
+
+    for _turn in 0..5 {
+        for _message in 0..1000 {
+            sizer.enqueued(200);
+        }
+        batch_bytes = sizer.finish_turn(&target);
+    }
+    assert_eq!(batch_bytes, 50_000);
+
+    for _turn in 0..100 {
+        batch_bytes = sizer.finish_turn(&target);
+    }
+    assert_eq!(batch_bytes, 1000);
+}
@@ -0,0 +1,125 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// How many emptied buffers are kept around for reuse, across all outboxes
+const MAX_SPARE_BUFFERS: usize = 64;
+
+/// Buffers that grew beyond this many times the batch size (by coalescing)
+/// are dropped instead of pooled, so the pool doesn't hold on to huge allocations
+const MAX_BUFFER_GROWTH: usize = 4;
+
+/// Statistics of the buffer pool batches to peers are written into, see `Networking::buffer_pool_stats`
+#[derive(Clone, Default, Debug)]
+pub struct BufferPoolStats {
+    /// Buffers that had to be freshly allocated
+    pub allocated: usize,
+    /// Buffers that were taken from the pool instead
+    pub reused: usize,
+    /// Buffers that were given back, after being coalesced into others or sent
+    pub recycled: usize,
+    /// Buffers currently in the pool
+    pub spare: usize,
+    /// The most buffers that were in the pool at once
+    pub peak_spare: usize,
+    /// Buffers freshly allocated during the last finished turn. Stays low in a steady state,
+    /// except for one per frame sent on connections that keep the buffers they send
+    pub allocated_last_turn: usize,
+    /// The most buffers freshly allocated during one turn
+    pub peak_allocated_per_turn: usize,
+}
+
+/// Recycles the batch buffers of all outboxes of a `Networking`,
+/// to avoid allocating a full-sized batch for every send at high message rates
+pub(crate) struct BufferPool {
+    buffer_bytes: usize,
+    /// Smaller buffers aren't pooled, lowered for adaptive batch sizes
+    min_buffer_bytes: usize,
+    spare: Vec<Vec<u8>>,
+    allocated_this_turn: usize,
+    stats: BufferPoolStats,
+}
+
+pub(crate) type SharedBufferPool = Rc<RefCell<BufferPool>>;
+
+impl BufferPool {
+    pub fn new(buffer_bytes: usize) -> BufferPool {
+        BufferPool {
+            buffer_bytes,
+            min_buffer_bytes: buffer_bytes,
+            spare: Vec::new(),
+            allocated_this_turn: 0,
+            stats: BufferPoolStats::default(),
+        }
+    }
+
+    pub fn shared(buffer_bytes: usize) -> SharedBufferPool {
+        Rc::new(RefCell::new(BufferPool::new(buffer_bytes)))
+    }
+
+    /// Also pool buffers of at least `min_buffer_bytes`, for batches smaller than the maximum
+    pub fn set_min_buffer_bytes(&mut self, min_buffer_bytes: usize) {
+        self.min_buffer_bytes = min_buffer_bytes.min(self.buffer_bytes);
+    }
+
+    /// An empty buffer with room for a batch of `batch_bytes`
+    pub fn take(&mut self, batch_bytes: usize) -> Vec<u8> {
+        match self.spare.pop() {
+            Some(mut buffer) => {
+                self.stats.reused += 1;
+                buffer.reserve(batch_bytes);
+                buffer
+            }
+            None => {
+                self.stats.allocated += 1;
+                self.allocated_this_turn += 1;
+                Vec::with_capacity(batch_bytes)
+            }
+        }
+    }
+
+    pub fn recycle(&mut self, mut buffer: Vec<u8>) {
+        if self.spare.len() < MAX_SPARE_BUFFERS
+            && buffer.capacity() >= self.min_buffer_bytes
+            && buffer.capacity() <= MAX_BUFFER_GROWTH * self.buffer_bytes
+        {
+            buffer.clear();
+            self.spare.push(buffer);
+            self.stats.recycled += 1;
+            self.stats.peak_spare = self.stats.peak_spare.max(self.spare.len());
+        }
+    }
+
+    pub fn finish_turn(&mut self) {
+        self.stats.allocated_last_turn = self.allocated_this_turn;
+        self.stats.peak_allocated_per_turn = self.stats.peak_allocated_per_turn.max(self.allocated_this_turn);
+        self.allocated_this_turn = 0;
+    }
+
+    pub fn stats(&self) -> BufferPoolStats {
+        BufferPoolStats {
+            spare: self.spare.len(),
+            ..self.stats.clone()
+        }
+    }
+}
+
+#[test]
+fn test_buffer_pool_reuse() {
+    let mut pool = BufferPool::new(16);
+    let a = pool.take(16);
+    let mut b = pool.take(16);
+    b.extend_from_slice(&[1, 2, 3]);
+    pool.recycle(b);
+    pool.recycle(Vec::with_capacity(1000));
+    pool.finish_turn();
+    let b = pool.take(16);
+    assert!(b.is_empty());
+    pool.recycle(a);
+    pool.recycle(b);
+    pool.finish_turn();
+
+    let stats = pool.stats();
+    assert_eq!((stats.allocated, stats.reused, stats.recycled), (2, 1, 3));
+    assert_eq!((stats.spare, stats.peak_spare), (2, 2));
+    assert_eq!((stats.allocated_last_turn, stats.peak_allocated_per_turn), (0, 2));
+}
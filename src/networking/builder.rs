@@ -0,0 +1,177 @@
+use super::address::PeerAddress;
+use super::handshake::MachineRole;
+#[cfg(feature = "server")]
+use super::proxy::Proxy;
+#[cfg(feature = "server")]
+use super::socket_options::SocketOptions;
+use super::Networking;
+use std::time::Duration;
+
+/// Configures a `Networking` with named parameters, all of them defaulted
+/// except for the local machine ID and the addresses of all machines
+pub struct NetworkingBuilder {
+    machine_id: u16,
+    network: Vec<PeerAddress>,
+    batch_message_bytes: usize,
+    acceptable_turn_distance: usize,
+    skip_turns_per_turn_head: usize,
+    auth_token: Option<Vec<u8>>,
+    host_migration: bool,
+    strict_lockstep_timeout: Option<Duration>,
+    adaptive_pacing_base_tick: Option<Duration>,
+    adaptive_batching: Option<(usize, usize)>,
+    reliable_delivery: bool,
+    role: MachineRole,
+    thin_clients: bool,
+    #[cfg(feature = "server")]
+    proxy: Option<Proxy>,
+    #[cfg(feature = "server")]
+    socket_options: SocketOptions,
+}
+
+impl NetworkingBuilder {
+    pub(crate) fn new(machine_id: u16, network: Vec<PeerAddress>) -> NetworkingBuilder {
+        NetworkingBuilder {
+            machine_id,
+            network,
+            batch_message_bytes: 50_000,
+            acceptable_turn_distance: 30,
+            skip_turns_per_turn_head: 10,
+            auth_token: None,
+            host_migration: false,
+            strict_lockstep_timeout: None,
+            adaptive_pacing_base_tick: None,
+            adaptive_batching: None,
+            reliable_delivery: false,
+            role: MachineRole::Participant,
+            thin_clients: false,
+            #[cfg(feature = "server")]
+            proxy: None,
+            #[cfg(feature = "server")]
+            socket_options: SocketOptions::default(),
+        }
+    }
+
+    /// The maximum size of a batch of messages sent at once (default 50000 bytes)
+    pub fn batch_message_bytes(mut self, batch_message_bytes: usize) -> Self {
+        self.batch_message_bytes = batch_message_bytes;
+        self
+    }
+
+    /// How many turns a peer can be behind before we skip turns (default 30)
+    pub fn acceptable_turn_distance(mut self, acceptable_turn_distance: usize) -> Self {
+        self.acceptable_turn_distance = acceptable_turn_distance;
+        self
+    }
+
+    /// How many turns to skip per turn that a peer is too far behind (default 10)
+    pub fn skip_turns_per_turn_head(mut self, skip_turns_per_turn_head: usize) -> Self {
+        self.skip_turns_per_turn_head = skip_turns_per_turn_head;
+        self
+    }
+
+    /// See `Networking::set_auth_token`
+    pub fn auth_token(mut self, token: Vec<u8>) -> Self {
+        self.auth_token = Some(token);
+        self
+    }
+
+    /// See `Networking::enable_host_migration`
+    pub fn host_migration(mut self) -> Self {
+        self.host_migration = true;
+        self
+    }
+
+    /// See `Networking::enable_strict_lockstep`
+    pub fn strict_lockstep(mut self, timeout: Duration) -> Self {
+        self.strict_lockstep_timeout = Some(timeout);
+        self
+    }
+
+    /// See `Networking::enable_adaptive_pacing`
+    pub fn adaptive_pacing(mut self, base_tick: Duration) -> Self {
+        self.adaptive_pacing_base_tick = Some(base_tick);
+        self
+    }
+
+    /// See `Networking::enable_adaptive_batching`
+    pub fn adaptive_batching(mut self, min_batch_bytes: usize, frames_per_turn: usize) -> Self {
+        self.adaptive_batching = Some((min_batch_bytes, frames_per_turn));
+        self
+    }
+
+    /// See `Networking::enable_reliable_delivery`
+    pub fn reliable_delivery(mut self) -> Self {
+        self.reliable_delivery = true;
+        self
+    }
+
+    /// See `Networking::set_role`
+    pub fn role(mut self, role: MachineRole) -> Self {
+        self.role = role;
+        self
+    }
+
+    /// See `Networking::accept_thin_clients`
+    pub fn thin_clients(mut self) -> Self {
+        self.thin_clients = true;
+        self
+    }
+
+    /// See `Networking::set_proxy`
+    #[cfg(feature = "server")]
+    pub fn proxy(mut self, proxy: Proxy) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// See `Networking::set_socket_options`
+    #[cfg(feature = "server")]
+    pub fn socket_options(mut self, options: SocketOptions) -> Self {
+        self.socket_options = options;
+        self
+    }
+
+    /// Create the configured `Networking`, see `Networking::new`
+    pub fn build(self) -> ::std::io::Result<Networking> {
+        let mut networking = Networking::new(
+            self.machine_id,
+            self.network,
+            self.batch_message_bytes,
+            self.acceptable_turn_distance,
+            self.skip_turns_per_turn_head,
+        )?;
+
+        if let Some(token) = self.auth_token {
+            networking.set_auth_token(token);
+        }
+        if self.host_migration {
+            networking.enable_host_migration();
+        }
+        if let Some(timeout) = self.strict_lockstep_timeout {
+            networking.enable_strict_lockstep(timeout);
+        }
+        if let Some(base_tick) = self.adaptive_pacing_base_tick {
+            networking.enable_adaptive_pacing(base_tick);
+        }
+        if let Some((min_batch_bytes, frames_per_turn)) = self.adaptive_batching {
+            networking.enable_adaptive_batching(min_batch_bytes, frames_per_turn);
+        }
+        if self.reliable_delivery {
+            networking.enable_reliable_delivery();
+        }
+        networking.set_role(self.role);
+        if self.thin_clients {
+            networking.accept_thin_clients();
+        }
+        #[cfg(feature = "server")]
+        {
+            if let Some(proxy) = self.proxy {
+                networking.set_proxy(proxy);
+            }
+            networking.set_socket_options(self.socket_options);
+        }
+
+        Ok(networking)
+    }
+}
@@ -0,0 +1,116 @@
+use std::collections::VecDeque;
+
+/// How strongly a new offset sample affects the smoothed offset
+const OFFSET_SMOOTHING: f64 = 0.2;
+/// How many offset samples are kept to estimate drift
+const MAX_SAMPLES: usize = 32;
+
+/// Clock synchronization statistics for one peer
+#[derive(Clone, Debug)]
+pub struct ClockStats {
+    /// Smoothed estimate of how far the peer's clock is ahead of ours, in milliseconds
+    pub offset_ms: f64,
+    /// How fast that offset changes, in milliseconds per second
+    pub drift_ms_per_s: f64,
+    /// The number of samples the drift estimate is based on
+    pub n_samples: usize,
+}
+
+/// Estimates the offset of a peer's clock from ping exchanges, like NTP does:
+/// assuming symmetric latency, the peer read its clock halfway through the round trip.
+pub(crate) struct ClockSync {
+    offset_ms: Option<f64>,
+    /// (local time, offset sample) pairs
+    samples: VecDeque<(f64, f64)>,
+}
+
+impl ClockSync {
+    pub fn new() -> ClockSync {
+        ClockSync {
+            offset_ms: None,
+            samples: VecDeque::with_capacity(MAX_SAMPLES),
+        }
+    }
+
+    /// Add a sample from a ping sent at local time `sent_at_ms`, answered with
+    /// the peer's time `peer_time_ms` and received back at local time `received_at_ms`
+    pub fn add_sample(&mut self, sent_at_ms: f64, peer_time_ms: f64, received_at_ms: f64) {
+        let midpoint = (sent_at_ms + received_at_ms) / 2.0;
+        let sample = peer_time_ms - midpoint;
+
+        self.offset_ms = Some(match self.offset_ms {
+            Some(offset) => offset + OFFSET_SMOOTHING * (sample - offset),
+            None => sample,
+        });
+
+        if self.samples.len() == MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((midpoint, sample));
+    }
+
+    pub fn offset_ms(&self) -> Option<f64> {
+        self.offset_ms
+    }
+
+    pub fn stats(&self) -> Option<ClockStats> {
+        self.offset_ms.map(|offset_ms| ClockStats {
+            offset_ms,
+            drift_ms_per_s: self.drift_ms_per_s(),
+            n_samples: self.samples.len(),
+        })
+    }
+
+    /// Least squares slope of the offset samples over time
+    fn drift_ms_per_s(&self) -> f64 {
+        let n = self.samples.len() as f64;
+        if n < 2.0 {
+            return 0.0;
+        }
+        let mean_t = self.samples.iter().map(|&(t, _)| t).sum::<f64>() / n;
+        let mean_o = self.samples.iter().map(|&(_, o)| o).sum::<f64>() / n;
+        let (covariance, variance) =
+            self.samples
+                .iter()
+                .fold((0.0, 0.0), |(covariance, variance), &(t, o)| {
+                    (
+                        covariance + (t - mean_t) * (o - mean_o),
+                        variance + (t - mean_t) * (t - mean_t),
+                    )
+                });
+        if variance > 0.0 {
+            covariance / variance * 1000.0
+        } else {
+            0.0
+        }
+    }
+}
+
+#[test]
+fn test_clock_offset_and_drift() {
+    let mut clock = ClockSync::new();
+    assert!(clock.offset_ms().is_none() && clock.stats().is_none());
+
+    // with symmetric latency, the peer read its clock halfway through the round trip
+    clock.add_sample(1000.0, 1510.0, 1020.0);
+    assert_eq!(clock.offset_ms(), Some(500.0));
+    assert_eq!(clock.stats().unwrap().drift_ms_per_s, 0.0);
+
+    // a single outlier only moves the smoothed offset a bit
+    clock.add_sample(2000.0, 2610.0, 2020.0);
+    assert!((clock.offset_ms().unwrap() - 520.0).abs() < 1e-9);
+
+    // a peer clock running 2 ms per second fast
+    let mut clock = ClockSync::new();
+    for i in 0..(MAX_SAMPLES + 8) {
+        let sent_at_ms = i as f64 * 1000.0;
+        let offset_ms = 300.0 + 2.0 * i as f64;
+        clock.add_sample(sent_at_ms, sent_at_ms + 15.0 + offset_ms, sent_at_ms + 30.0);
+    }
+    let stats = clock.stats().unwrap();
+    assert_eq!(stats.n_samples, MAX_SAMPLES);
+    assert!((stats.drift_ms_per_s - 2.0).abs() < 1e-6);
+    // the smoothed offset lags a bit behind the drifting clock
+    let latest_offset_ms = 300.0 + 2.0 * (MAX_SAMPLES + 7) as f64;
+    assert!(stats.offset_ms < latest_offset_ms && stats.offset_ms > latest_offset_ms - 20.0);
+}
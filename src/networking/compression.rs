@@ -0,0 +1,254 @@
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use std::collections::VecDeque;
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use zstd::block::{Compressor, Decompressor};
+
+const KIND_RAW: u8 = 0;
+const KIND_COMPRESSED: u8 = 1;
+const KIND_DICTIONARY: u8 = 2;
+
+const LEVEL: i32 = 3;
+/// The latest messages sent are kept as samples to train the next dictionary from
+const MAX_SAMPLES: usize = 2000;
+/// Fewer samples don't make a useful dictionary
+const MIN_SAMPLES: usize = 200;
+const MAX_DICTIONARY_BYTES: usize = 16 * 1024;
+/// zstd needs samples of many times the size of the dictionary to train it
+const SAMPLE_BYTES_PER_DICTIONARY_BYTE: usize = 10;
+/// A new dictionary is trained after sending this many frames with the current one,
+/// so it follows changes in the traffic
+const FRAMES_PER_DICTIONARY: usize = 5000;
+/// Smaller frames are sent uncompressed
+const MIN_COMPRESSED_FRAME_BYTES: usize = 32;
+/// Dictionaries of the peer kept to decompress frames, in case it sent
+/// frames compressed with an older one after a newer one
+const MAX_PEER_DICTIONARIES: usize = 2;
+/// Decompressed frames larger than this are rejected
+const MAX_FRAME_BYTES: usize = 64 * 1024 * 1024;
+
+/// Compresses the frames sent to one peer with zstd dictionaries trained online
+/// on the messages sent to it, and decompresses the frames it sends with its dictionaries.
+/// Simulation traffic repeats the same message types with similar contents,
+/// so a trained dictionary compresses even small frames well. Dictionaries are trained
+/// on a background thread from a rolling window of the latest messages sent, and frames
+/// are compressed with the current dictionary meanwhile.
+///
+/// Frames start with a kind byte: raw frames are followed by the batch,
+/// compressed ones by `[dictionary ID: u32][batch length: u32]` and the compressed batch.
+/// New dictionaries are sent in frames of their own, `[dictionary ID: u32][dictionary]`,
+/// before the first frame compressed with them.
+pub(crate) struct CompressionChannel {
+    samples: VecDeque<Vec<u8>>,
+    frames_since_training: usize,
+    /// The dictionary being trained on a background thread, `None` if there was too little variety
+    training: Option<Receiver<Option<Vec<u8>>>>,
+    compressor: Option<(u32, Compressor)>,
+    next_dictionary_id: u32,
+    peer_dictionaries: Vec<(u32, Decompressor)>,
+    raw_bytes: usize,
+    compressed_bytes: usize,
+}
+
+impl CompressionChannel {
+    pub fn new() -> CompressionChannel {
+        CompressionChannel {
+            samples: VecDeque::new(),
+            frames_since_training: 0,
+            training: None,
+            compressor: None,
+            next_dictionary_id: 0,
+            peer_dictionaries: Vec::new(),
+            raw_bytes: 0,
+            compressed_bytes: 0,
+        }
+    }
+
+    fn collect_samples(&mut self, batch: &[u8]) {
+        let mut pos = 0;
+        while pos + 4 <= batch.len() {
+            let message_size = LittleEndian::read_u32(&batch[pos..]) as usize;
+            pos += 4;
+            if let Some(message) = batch.get(pos..pos + message_size) {
+                if self.samples.len() == MAX_SAMPLES {
+                    self.samples.pop_front();
+                }
+                self.samples.push_back(message.to_vec());
+            }
+            pos += message_size;
+        }
+    }
+
+    /// Start training a new dictionary on a background thread if it's time
+    fn maybe_train(&mut self) {
+        let due = self.compressor.is_none() || self.frames_since_training >= FRAMES_PER_DICTIONARY;
+        if !due || self.training.is_some() || self.samples.len() < MIN_SAMPLES {
+            return;
+        }
+        let samples = self.samples.iter().cloned().collect::<Vec<_>>();
+        let sample_bytes = samples.iter().map(Vec::len).sum::<usize>();
+        let dictionary_bytes = MAX_DICTIONARY_BYTES.min(sample_bytes / SAMPLE_BYTES_PER_DICTIONARY_BYTE);
+        let (trained, receiver) = channel();
+        ::std::thread::spawn(move || {
+            let _ = trained.send(::zstd::dict::from_samples(&samples, dictionary_bytes).ok());
+        });
+        self.training = Some(receiver);
+        self.frames_since_training = 0;
+    }
+
+    /// Use a dictionary that finished training, returns the frame announcing it
+    fn take_trained(&mut self) -> Option<Vec<u8>> {
+        let dictionary = match self.training.as_ref()?.try_recv() {
+            Ok(dictionary) => dictionary,
+            Err(TryRecvError::Empty) => return None,
+            Err(TryRecvError::Disconnected) => None,
+        };
+        self.training = None;
+        // too little variety to train on, keep using the current dictionary
+        let dictionary = dictionary?;
+
+        let id = self.next_dictionary_id;
+        self.next_dictionary_id += 1;
+        let mut frame = Vec::with_capacity(5 + dictionary.len());
+        frame.push(KIND_DICTIONARY);
+        frame.write_u32::<LittleEndian>(id).unwrap();
+        frame.extend_from_slice(&dictionary);
+        self.compressor = Some((id, Compressor::with_dict(dictionary)));
+        Some(frame)
+    }
+
+    /// Is a dictionary being trained?
+    #[cfg(test)]
+    fn is_training(&self) -> bool {
+        self.training.is_some()
+    }
+
+    /// Turn a batch into the frames to send, a new dictionary first if one finished training
+    pub fn compress(&mut self, batch: &[u8]) -> Vec<Vec<u8>> {
+        self.collect_samples(batch);
+        self.frames_since_training += 1;
+        self.maybe_train();
+        let mut frames: Vec<Vec<u8>> = self.take_trained().into_iter().collect();
+
+        let compressed = match self.compressor {
+            Some((id, ref mut compressor)) if batch.len() >= MIN_COMPRESSED_FRAME_BYTES => compressor
+                .compress(batch, LEVEL)
+                .ok()
+                .filter(|compressed| compressed.len() + 8 < batch.len())
+                .map(|compressed| (id, compressed)),
+            _ => None,
+        };
+
+        let frame = match compressed {
+            Some((id, compressed)) => {
+                let mut frame = Vec::with_capacity(9 + compressed.len());
+                frame.push(KIND_COMPRESSED);
+                frame.write_u32::<LittleEndian>(id).unwrap();
+                frame.write_u32::<LittleEndian>(batch.len() as u32).unwrap();
+                frame.extend_from_slice(&compressed);
+                frame
+            }
+            None => {
+                let mut frame = Vec::with_capacity(1 + batch.len());
+                frame.push(KIND_RAW);
+                frame.extend_from_slice(batch);
+                frame
+            }
+        };
+        self.raw_bytes += batch.len();
+        self.compressed_bytes += frames.iter().map(Vec::len).sum::<usize>() + frame.len();
+        frames.push(frame);
+        frames
+    }
+
+    /// Get the batch of a received frame, `None` if it only announced a dictionary
+    pub fn decompress(&mut self, frame: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        match frame.first() {
+            Some(&KIND_RAW) => Ok(Some(frame[1..].to_vec())),
+            Some(&KIND_DICTIONARY) if frame.len() >= 5 => {
+                let id = LittleEndian::read_u32(&frame[1..]);
+                if self.peer_dictionaries.len() == MAX_PEER_DICTIONARIES {
+                    self.peer_dictionaries.remove(0);
+                }
+                self.peer_dictionaries
+                    .push((id, Decompressor::with_dict(frame[5..].to_vec())));
+                Ok(None)
+            }
+            Some(&KIND_COMPRESSED) if frame.len() >= 9 => {
+                let id = LittleEndian::read_u32(&frame[1..]);
+                let batch_len = LittleEndian::read_u32(&frame[5..]) as usize;
+                if batch_len > MAX_FRAME_BYTES {
+                    return Err(format!("Compressed frame too large ({} bytes)", batch_len));
+                }
+                let decompressor = self
+                    .peer_dictionaries
+                    .iter_mut()
+                    .find(|(dictionary_id, _)| *dictionary_id == id)
+                    .map(|(_, decompressor)| decompressor)
+                    .ok_or_else(|| format!("Unknown compression dictionary {}", id))?;
+                decompressor
+                    .decompress(&frame[9..], batch_len)
+                    .map(Some)
+                    .map_err(|e| format!("Couldn't decompress frame: {}", e))
+            }
+            _ => Err("Malformed compressed frame".to_owned()),
+        }
+    }
+
+    /// The bytes of all frames sent per byte of their batches
+    pub fn ratio(&self) -> f64 {
+        if self.raw_bytes == 0 {
+            1.0
+        } else {
+            self.compressed_bytes as f64 / self.raw_bytes as f64
+        }
+    }
+}
+
+#[test]
+fn test_compression_roundtrip() {
+    let mut sender = CompressionChannel::new();
+    let mut receiver = CompressionChannel::new();
+    let mut n_dictionaries = 0;
+    for i in 0..400u32 {
+        let mut batch = Vec::new();
+        for j in 0..4u32 {
+            let message = format!("MoveTo {{ car: {}, lane: {}, position: {}.5 }}", i % 17, j, i * 3);
+            batch.write_u32::<LittleEndian>(message.len() as u32).unwrap();
+            batch.extend_from_slice(message.as_bytes());
+        }
+        let frames = sender.compress(&batch);
+        let mut received = Vec::new();
+        for frame in frames {
+            match receiver.decompress(&frame).unwrap() {
+                Some(batch) => received.push(batch),
+                None => n_dictionaries += 1,
+            }
+        }
+        assert_eq!(received, vec![batch]);
+        // let training finish before the next batch, so the test doesn't depend on timing
+        while sender.is_training() {
+            ::std::thread::sleep(::std::time::Duration::from_millis(1));
+            if let Some(frame) = sender.take_trained() {
+                assert_eq!(receiver.decompress(&frame).unwrap(), None);
+                n_dictionaries += 1;
+            }
+        }
+    }
+    assert!(n_dictionaries > 0);
+    assert!(sender.ratio() < 1.0);
+    assert!(receiver.decompress(&[KIND_COMPRESSED, 9, 0, 0, 0, 1, 0, 0, 0, 0]).is_err());
+}
+
+#[test]
+fn test_samples_are_a_rolling_window() {
+    let mut channel = CompressionChannel::new();
+    for i in 0..(MAX_SAMPLES + 10) as u32 {
+        let mut batch = Vec::new();
+        batch.write_u32::<LittleEndian>(4).unwrap();
+        batch.write_u32::<LittleEndian>(i).unwrap();
+        channel.collect_samples(&batch);
+    }
+    assert_eq!(channel.samples.len(), MAX_SAMPLES);
+    assert_eq!(LittleEndian::read_u32(&channel.samples[0]), 10);
+    assert_eq!(LittleEndian::read_u32(channel.samples.back().unwrap()), (MAX_SAMPLES + 9) as u32);
+}
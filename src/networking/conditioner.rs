@@ -0,0 +1,143 @@
+use crate::time::duration_ms;
+use std::time::Duration;
+
+/// Adverse network conditions to simulate on outgoing batches,
+/// to test turn backpressure and reliability without a real bad network
+#[derive(Clone, Debug)]
+pub struct LinkConditions {
+    /// Delay added to every batch
+    pub latency: Duration,
+    /// Up to this much additional random delay per batch
+    pub jitter: Duration,
+    /// Probability (0 to 1) that a batch is held back long enough to be overtaken by later ones
+    pub reorder_probability: f64,
+    /// Probability (0 to 1) that a batch is dropped entirely
+    pub loss_probability: f64,
+    /// Seed for the random decisions, so simulated runs are repeatable
+    pub seed: u64,
+}
+
+impl Default for LinkConditions {
+    fn default() -> LinkConditions {
+        LinkConditions {
+            latency: Duration::from_millis(0),
+            jitter: Duration::from_millis(0),
+            reorder_probability: 0.0,
+            loss_probability: 0.0,
+            seed: 0x2545_F491_4F6C_DD1D,
+        }
+    }
+}
+
+struct InFlightBatch {
+    due_ms: f64,
+    seq: usize,
+    data: Vec<u8>,
+}
+
+/// Holds back outgoing batches of one connection according to `LinkConditions`
+pub(crate) struct LinkConditioner {
+    conditions: LinkConditions,
+    rng_state: u64,
+    in_flight: Vec<InFlightBatch>,
+    next_seq: usize,
+    /// Batches that aren't reordered are never due before this
+    last_ordered_due_ms: f64,
+}
+
+impl LinkConditioner {
+    pub fn new(conditions: LinkConditions, stream: u64) -> LinkConditioner {
+        // xorshift must never have an all-zero state
+        let rng_state = (conditions.seed ^ stream.wrapping_mul(0x9E37_79B9_7F4A_7C15)).max(1);
+        LinkConditioner {
+            conditions,
+            rng_state,
+            in_flight: Vec::new(),
+            next_seq: 0,
+            last_ordered_due_ms: 0.0,
+        }
+    }
+
+    /// A uniformly distributed number in `[0, 1)` (xorshift64*)
+    fn random(&mut self) -> f64 {
+        self.rng_state ^= self.rng_state >> 12;
+        self.rng_state ^= self.rng_state << 25;
+        self.rng_state ^= self.rng_state >> 27;
+        let value = self.rng_state.wrapping_mul(0x2545_F491_4F6C_DD1D);
+        (value >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    pub fn submit(&mut self, data: Vec<u8>, now_ms: f64) {
+        if self.random() < self.conditions.loss_probability {
+            return;
+        }
+
+        let latency_ms = duration_ms(self.conditions.latency);
+        let jitter_ms = duration_ms(self.conditions.jitter);
+        let mut due_ms = now_ms + latency_ms + jitter_ms * self.random();
+
+        if self.random() < self.conditions.reorder_probability {
+            due_ms += latency_ms + jitter_ms;
+        } else {
+            due_ms = due_ms.max(self.last_ordered_due_ms);
+            self.last_ordered_due_ms = due_ms;
+        }
+
+        self.in_flight.push(InFlightBatch {
+            due_ms,
+            seq: self.next_seq,
+            data,
+        });
+        self.next_seq += 1;
+    }
+
+    /// Take all batches that are due to be actually sent, in their simulated arrival order
+    pub fn take_due(&mut self, now_ms: f64) -> Vec<Vec<u8>> {
+        let (mut due, in_flight): (Vec<_>, Vec<_>) = self
+            .in_flight
+            .drain(..)
+            .partition(|batch| batch.due_ms <= now_ms);
+        self.in_flight = in_flight;
+
+        due.sort_by(|a, b| {
+            a.due_ms
+                .partial_cmp(&b.due_ms)
+                .unwrap()
+                .then(a.seq.cmp(&b.seq))
+        });
+        due.into_iter().map(|batch| batch.data).collect()
+    }
+}
+
+#[test]
+fn test_link_conditioner() {
+    let mut perfect = LinkConditioner::new(LinkConditions::default(), 0);
+    for i in 0..10 {
+        perfect.submit(vec![i], 0.0);
+    }
+    assert_eq!(perfect.take_due(0.0), (0..10).map(|i| vec![i]).collect::<Vec<_>>());
+
+    let mut delayed = LinkConditioner::new(
+        LinkConditions {
+            latency: Duration::from_millis(50),
+            jitter: Duration::from_millis(20),
+            ..LinkConditions::default()
+        },
+        1,
+    );
+    for i in 0..10 {
+        delayed.submit(vec![i], 0.0);
+    }
+    assert!(delayed.take_due(49.0).is_empty());
+    assert_eq!(delayed.take_due(100.0), (0..10).map(|i| vec![i]).collect::<Vec<_>>());
+
+    let mut lossy = LinkConditioner::new(
+        LinkConditions {
+            loss_probability: 1.0,
+            ..LinkConditions::default()
+        },
+        2,
+    );
+    lossy.submit(vec![0], 0.0);
+    assert!(lossy.take_due(1000.0).is_empty());
+}
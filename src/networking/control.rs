@@ -0,0 +1,405 @@
+use super::clock::ClockSync;
+use super::delta::DeltaChannel;
+use super::handshake::MachineRole;
+use super::traffic::TrafficCounters;
+use super::turn_protocol::TurnProtocol;
+use crate::id::RawID;
+use crate::time::now_ms;
+use crate::type_registry::ShortTypeId;
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use std::collections::HashSet;
+use std::rc::Rc;
+
+/// Size of the "message type" that marks a control frame (always 0)
+const CONTROL_MARKER_BYTES: usize = 2;
+
+const KIND_TURN: u8 = 0;
+const KIND_PING: u8 = 1;
+const KIND_PONG: u8 = 2;
+const KIND_SEQUENCE: u8 = 3;
+const KIND_ACK: u8 = 4;
+const KIND_PAUSE: u8 = 5;
+const KIND_RESUME: u8 = 6;
+const KIND_TURN_WITH_STATE_HASH: u8 = 7;
+const KIND_LEAVE: u8 = 8;
+/// Delta encoded messages, see `DeltaChannel`
+pub(crate) const KIND_DELTA_BASE: u8 = 9;
+pub(crate) const KIND_DELTA: u8 = 10;
+const KIND_STRIPED: u8 = 11;
+const KIND_PAUSE_REQUEST: u8 = 12;
+
+/// How strongly a new round trip time sample affects the smoothed estimate
+const RTT_SMOOTHING: f64 = 0.125;
+
+/// Frames exchanged between peers in the same batches as messages,
+/// distinguished from actual packets by using 0 as their "message type".
+///
+/// Layout: `[0: u16][kind: u8][payload]`
+#[derive(Clone, Debug)]
+pub(crate) enum ControlFrame {
+    /// The sender finished a turn, described by a marker of its `TurnProtocol`,
+    /// optionally with the hash of its actor state at the end of the turn
+    Turn {
+        marker: Vec<u8>,
+        state_hash: Option<u64>,
+    },
+    /// Sent regularly to measure the round trip time, echoed back as a `Pong`
+    Ping { sent_at_ms: f64 },
+    /// The echo of a `Ping`, including the time of the responder's clock
+    Pong {
+        ping_sent_at_ms: f64,
+        responder_time_ms: f64,
+    },
+    /// Starts a batch with reliable delivery, numbering it
+    Sequence { seq: u32 },
+    /// The sender received all reliable batches up to and including `seq`
+    Ack { seq: u32 },
+    /// Sent to the host, which decides the turn all machines pause at
+    PauseRequest,
+    /// All machines should stop advancing once they finished turn `at_turn`,
+    /// as decided by the host
+    Pause { at_turn: u32 },
+    /// All machines should continue advancing turns
+    Resume,
+    /// The sender shuts down and will close the connection after this batch,
+    /// so it departed for good instead of being lost
+    Leave,
+    /// The frames the sender sends after this one are striped across `n_lanes` connections,
+    /// see `Striping`
+    Striped { n_lanes: u8 },
+}
+
+impl ControlFrame {
+    pub fn encoded_len(&self) -> usize {
+        CONTROL_MARKER_BYTES
+            + 1
+            + match *self {
+                ControlFrame::Turn {
+                    ref marker,
+                    state_hash,
+                } => marker.len() + state_hash.map_or(0, |_| ::std::mem::size_of::<u64>()),
+                ControlFrame::Ping { .. } => ::std::mem::size_of::<f64>(),
+                ControlFrame::Pong { .. } => 2 * ::std::mem::size_of::<f64>(),
+                ControlFrame::Sequence { .. }
+                | ControlFrame::Ack { .. }
+                | ControlFrame::Pause { .. } => ::std::mem::size_of::<u32>(),
+                ControlFrame::Striped { .. } => 1,
+                ControlFrame::PauseRequest | ControlFrame::Resume | ControlFrame::Leave => 0,
+            }
+    }
+
+    pub fn encode_into(&self, data: &mut Vec<u8>) {
+        data.write_u16::<LittleEndian>(0).unwrap();
+        match *self {
+            ControlFrame::Turn {
+                ref marker,
+                state_hash,
+            } => {
+                if let Some(state_hash) = state_hash {
+                    data.push(KIND_TURN_WITH_STATE_HASH);
+                    data.write_u64::<LittleEndian>(state_hash).unwrap();
+                } else {
+                    data.push(KIND_TURN);
+                }
+                data.extend_from_slice(marker);
+            }
+            ControlFrame::Ping { sent_at_ms } => {
+                data.push(KIND_PING);
+                data.write_f64::<LittleEndian>(sent_at_ms).unwrap();
+            }
+            ControlFrame::Pong {
+                ping_sent_at_ms,
+                responder_time_ms,
+            } => {
+                data.push(KIND_PONG);
+                data.write_f64::<LittleEndian>(ping_sent_at_ms).unwrap();
+                data.write_f64::<LittleEndian>(responder_time_ms).unwrap();
+            }
+            ControlFrame::Sequence { seq } => {
+                data.push(KIND_SEQUENCE);
+                data.write_u32::<LittleEndian>(seq).unwrap();
+            }
+            ControlFrame::Ack { seq } => {
+                data.push(KIND_ACK);
+                data.write_u32::<LittleEndian>(seq).unwrap();
+            }
+            ControlFrame::Pause { at_turn } => {
+                data.push(KIND_PAUSE);
+                data.write_u32::<LittleEndian>(at_turn).unwrap();
+            }
+            ControlFrame::PauseRequest => data.push(KIND_PAUSE_REQUEST),
+            ControlFrame::Resume => data.push(KIND_RESUME),
+            ControlFrame::Leave => data.push(KIND_LEAVE),
+            ControlFrame::Striped { n_lanes } => {
+                data.push(KIND_STRIPED);
+                data.push(n_lanes);
+            }
+        }
+    }
+
+    pub fn decode(data: &[u8]) -> Option<ControlFrame> {
+        let payload = data.get(CONTROL_MARKER_BYTES + 1..)?;
+        match data[CONTROL_MARKER_BYTES] {
+            KIND_TURN => Some(ControlFrame::Turn {
+                marker: payload.to_vec(),
+                state_hash: None,
+            }),
+            KIND_TURN_WITH_STATE_HASH if payload.len() >= 8 => Some(ControlFrame::Turn {
+                marker: payload[8..].to_vec(),
+                state_hash: Some(LittleEndian::read_u64(payload)),
+            }),
+            KIND_PING if payload.len() >= 8 => Some(ControlFrame::Ping {
+                sent_at_ms: LittleEndian::read_f64(payload),
+            }),
+            KIND_PONG if payload.len() >= 16 => Some(ControlFrame::Pong {
+                ping_sent_at_ms: LittleEndian::read_f64(payload),
+                responder_time_ms: LittleEndian::read_f64(&payload[8..]),
+            }),
+            KIND_SEQUENCE if payload.len() >= 4 => Some(ControlFrame::Sequence {
+                seq: LittleEndian::read_u32(payload),
+            }),
+            KIND_ACK if payload.len() >= 4 => Some(ControlFrame::Ack {
+                seq: LittleEndian::read_u32(payload),
+            }),
+            KIND_PAUSE if payload.len() >= 4 => Some(ControlFrame::Pause {
+                at_turn: LittleEndian::read_u32(payload),
+            }),
+            KIND_PAUSE_REQUEST => Some(ControlFrame::PauseRequest),
+            KIND_RESUME => Some(ControlFrame::Resume),
+            KIND_LEAVE => Some(ControlFrame::Leave),
+            KIND_STRIPED if !payload.is_empty() => Some(ControlFrame::Striped { n_lanes: payload[0] }),
+            _ => None,
+        }
+    }
+}
+
+/// Is this message actually a control frame?
+pub(crate) fn is_control_frame(data: &[u8]) -> bool {
+    data[0] == 0 && data[1] == 0
+}
+
+/// Is this control frame a delta encoded message?
+pub(crate) fn is_delta_frame(data: &[u8]) -> bool {
+    match data.get(CONTROL_MARKER_BYTES) {
+        Some(&KIND_DELTA_BASE) | Some(&KIND_DELTA) => true,
+        _ => false,
+    }
+}
+
+/// Is this control frame a turn marker?
+pub(crate) fn is_turn_frame(data: &[u8]) -> bool {
+    is_control_frame(data)
+        && match data.get(CONTROL_MARKER_BYTES) {
+            Some(&KIND_TURN) | Some(&KIND_TURN_WITH_STATE_HASH) => true,
+            _ => false,
+        }
+}
+
+/// The `(message type, recipient type)` pairs accepted from peers,
+/// see `ActorSystem::networking_allow_remote`
+#[derive(Clone)]
+pub(crate) struct RemoteAllowlist {
+    /// Messages to the system services of a machine are always accepted,
+    /// they implement name registrations, queries and similar
+    system_services: ShortTypeId,
+    pairs: HashSet<(ShortTypeId, ShortTypeId)>,
+}
+
+impl RemoteAllowlist {
+    pub fn new(system_services: ShortTypeId) -> RemoteAllowlist {
+        RemoteAllowlist {
+            system_services,
+            pairs: HashSet::new(),
+        }
+    }
+
+    pub fn allow(&mut self, message_type_id: ShortTypeId, recipient_type_id: ShortTypeId) {
+        self.pairs.insert((message_type_id, recipient_type_id));
+    }
+
+    pub fn allows(&self, message_type_id: ShortTypeId, recipient_type_id: ShortTypeId) -> bool {
+        recipient_type_id == self.system_services || self.pairs.contains(&(message_type_id, recipient_type_id))
+    }
+}
+
+/// What we know about a connected peer from the control frames it sent us
+pub(crate) struct PeerState {
+    /// The last turn the peer finished
+    pub n_turns: usize,
+    pub n_turns_since_own_turn: usize,
+    /// Smoothed round trip time, once measured
+    pub rtt_ms: Option<f64>,
+    pub clock: ClockSync,
+    /// Control frames to send back to the peer with the next batch
+    pub replies: Vec<ControlFrame>,
+    /// Message traffic exchanged with the peer (not counting control frames)
+    pub traffic: TrafficCounters,
+    /// The latest acknowledgement of reliable batches the peer sent, not yet processed
+    pub acked_up_to: Option<u32>,
+    /// The role the peer announced in its handshake
+    pub role: MachineRole,
+    /// Did the peer connect as a thin client (see `Networking::accept_thin_clients`)?
+    pub thin_client: bool,
+    /// If set, messages of other types from this peer are dropped
+    pub accepted_messages: Option<HashSet<ShortTypeId>>,
+    /// If set, only messages it allows are accepted from this peer
+    pub allowed_remote: Option<Rc<RemoteAllowlist>>,
+    /// Messages rejected by `allowed_remote`, not yet reported
+    pub rejected: Vec<(ShortTypeId, RawID)>,
+    /// Why batches or messages from the peer were ignored as invalid, not yet reported
+    pub invalid: Vec<String>,
+    /// Was the peer reported as lagging behind already?
+    pub lagging: bool,
+    /// The latest pause request, pause or resume the peer sent, not yet processed
+    pub pause_request: Option<ControlFrame>,
+    /// State hashes of turns the peer finished, not yet compared to our own
+    pub state_hashes: Vec<(usize, u64)>,
+    /// Was a desync with the peer detected already?
+    pub desynced: bool,
+    /// Did the peer announce that it shuts down?
+    pub left: bool,
+    /// The number of lanes the peer announced to stripe its frames across, not yet processed
+    pub striped: Option<u8>,
+    /// The last delta encoded messages exchanged with the peer
+    pub delta: DeltaChannel,
+}
+
+impl PeerState {
+    pub fn new() -> PeerState {
+        PeerState {
+            n_turns: 0,
+            n_turns_since_own_turn: 0,
+            rtt_ms: None,
+            clock: ClockSync::new(),
+            replies: Vec::new(),
+            traffic: TrafficCounters::default(),
+            acked_up_to: None,
+            role: MachineRole::Participant,
+            thin_client: false,
+            accepted_messages: None,
+            allowed_remote: None,
+            rejected: Vec::new(),
+            invalid: Vec::new(),
+            lagging: false,
+            pause_request: None,
+            state_hashes: Vec::new(),
+            desynced: false,
+            left: false,
+            striped: None,
+            delta: DeltaChannel::new(),
+        }
+    }
+
+    /// Does this peer take part in lockstep?
+    pub fn is_participant(&self) -> bool {
+        self.role == MachineRole::Participant
+    }
+
+    /// Update the peer state from a control frame,
+    /// returns true if we should stop reading from this peer for now
+    pub fn handle(&mut self, frame: ControlFrame, turn_protocol: &mut dyn TurnProtocol) -> bool {
+        match frame {
+            ControlFrame::Turn { marker, state_hash } => match turn_protocol.finished_turn(&marker) {
+                Some(n_turns) => {
+                    self.n_turns = n_turns;
+                    if let Some(state_hash) = state_hash {
+                        self.state_hashes.push((n_turns, state_hash));
+                    }
+                    self.n_turns_since_own_turn += 1;
+
+                    // pretend that we're blocked so we only process the
+                    // messages of a limited number of incoming turns
+                    // within one of our own turns, applying backpressure
+                    turn_protocol.apply_backpressure(self.n_turns_since_own_turn)
+                }
+                None => {
+                    println!("Ignoring invalid turn marker {:?}", marker);
+                    false
+                }
+            },
+            ControlFrame::Ping { sent_at_ms } => {
+                self.replies.push(ControlFrame::Pong {
+                    ping_sent_at_ms: sent_at_ms,
+                    responder_time_ms: now_ms(),
+                });
+                false
+            }
+            ControlFrame::Pong {
+                ping_sent_at_ms,
+                responder_time_ms,
+            } => {
+                let received_at_ms = now_ms();
+                self.clock
+                    .add_sample(ping_sent_at_ms, responder_time_ms, received_at_ms);
+                let sample = (received_at_ms - ping_sent_at_ms).max(0.0);
+                self.rtt_ms = Some(match self.rtt_ms {
+                    Some(rtt) => rtt + RTT_SMOOTHING * (sample - rtt),
+                    None => sample,
+                });
+                false
+            }
+            // handled when dispatching the batch
+            ControlFrame::Sequence { .. } => false,
+            ControlFrame::Ack { seq } => {
+                self.acked_up_to = Some(seq);
+                false
+            }
+            request @ ControlFrame::PauseRequest
+            | request @ ControlFrame::Pause { .. }
+            | request @ ControlFrame::Resume => {
+                self.pause_request = Some(request);
+                false
+            }
+            ControlFrame::Leave => {
+                self.left = true;
+                false
+            }
+            ControlFrame::Striped { n_lanes } => {
+                self.striped = Some(n_lanes);
+                false
+            }
+        }
+    }
+}
+
+#[test]
+fn test_remote_allowlist() {
+    let id = |id| ShortTypeId::new(id).unwrap();
+    let system_services = id(1);
+    let mut allowlist = RemoteAllowlist::new(system_services);
+    allowlist.allow(id(10), id(2));
+
+    assert!(allowlist.allows(id(10), id(2)));
+    // neither other recipients of the message type nor other message types to the recipient
+    assert!(!allowlist.allows(id(10), id(3)));
+    assert!(!allowlist.allows(id(11), id(2)));
+    // the system services always accept messages
+    assert!(allowlist.allows(id(11), system_services));
+}
+
+#[test]
+fn test_round_trip_time_is_smoothed() {
+    use super::turn_protocol::LockstepTurns;
+
+    let mut peer = PeerState::new();
+    let pong = |rtt_ms: f64| ControlFrame::Pong {
+        ping_sent_at_ms: now_ms() - rtt_ms,
+        responder_time_ms: now_ms(),
+    };
+    assert!(peer.rtt_ms.is_none());
+    peer.handle(pong(40.0), &mut LockstepTurns);
+    let first_rtt_ms = peer.rtt_ms.unwrap();
+    assert!(first_rtt_ms >= 40.0 && first_rtt_ms < 45.0);
+    // a spike only moves the estimate by an eighth of the difference
+    peer.handle(pong(120.0), &mut LockstepTurns);
+    let rtt_ms = peer.rtt_ms.unwrap();
+    assert!(rtt_ms >= first_rtt_ms + 10.0 - 1.0 && rtt_ms < first_rtt_ms + 10.0 + 1.0);
+    assert_eq!(peer.clock.stats().unwrap().n_samples, 2);
+
+    // pings are answered with our own time
+    peer.handle(ControlFrame::Ping { sent_at_ms: 1.0 }, &mut LockstepTurns);
+    match peer.replies[..] {
+        [ControlFrame::Pong { ping_sent_at_ms, .. }] => assert_eq!(ping_sent_at_ms, 1.0),
+        _ => panic!("Ping should have been answered"),
+    }
+}
@@ -0,0 +1,248 @@
+use super::control::{is_control_frame, KIND_DELTA, KIND_DELTA_BASE};
+use crate::determinism::DeterministicHasher;
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use std::collections::HashMap;
+use std::hash::Hasher;
+
+/// `[0: u16][kind: u8][sender class: u16]`
+pub(crate) const FRAME_HEADER_BYTES: usize = 5;
+/// The message type and recipient, which identify a payload together with the sender class
+const MESSAGE_HEADER_BYTES: usize = 2 + ::std::mem::size_of::<crate::id::RawID>();
+/// `[unchanged bytes: u16][changed bytes: u16]`
+const RUN_HEADER_BYTES: usize = 4;
+const MAX_RUN_BYTES: usize = 0xFFFF;
+/// Unchanged stretches shorter than a run header are sent along with the changes around them
+const MIN_UNCHANGED_BYTES: usize = RUN_HEADER_BYTES;
+/// After this many deltas against the same payload history, the full payload is sent again,
+/// so a receiver that missed one (like after a reconnect) catches up
+const DELTAS_PER_BASE: usize = 32;
+/// Payloads remembered per direction and peer, further keys aren't delta encoded
+const MAX_PAYLOADS: usize = 4096;
+
+/// Identifies a payload history: the sender class, message type and the recipient's
+/// instance ID, type ID, machine and version, read field by field so padding doesn't matter
+type PayloadKey = (u16, u16, u32, u16, u16, u8);
+
+fn payload_key(sender_class: u16, message: &[u8]) -> PayloadKey {
+    let recipient = &message[2..];
+    (
+        sender_class,
+        LittleEndian::read_u16(message),
+        LittleEndian::read_u32(recipient),
+        LittleEndian::read_u16(&recipient[4..]),
+        LittleEndian::read_u16(&recipient[6..]),
+        recipient[8],
+    )
+}
+
+fn payload_hash(message: &[u8]) -> u64 {
+    let mut hasher = DeterministicHasher::default();
+    hasher.write(message);
+    hasher.finish()
+}
+
+/// Delta encodes messages of the types set with `Networking::enable_delta_encoding`
+/// on one connection: it remembers the last message sent to and received from the peer
+/// per sender class, message type and recipient, and sends only the bytes of a message
+/// that changed compared to the last one, when that is smaller.
+///
+/// Messages are wrapped in control frames, so receivers need no setup:
+/// `[0: u16][KIND_DELTA_BASE][sender class: u16][message]` for full messages and
+/// `[0: u16][KIND_DELTA][sender class: u16][message type and recipient][hash of the last message: u64]`
+/// followed by runs of `[unchanged bytes: u16][changed bytes: u16][changed bytes]` for deltas.
+/// Deltas that don't match the last message received are dropped.
+pub(crate) struct DeltaChannel {
+    sent: HashMap<PayloadKey, (Vec<u8>, usize)>,
+    received: HashMap<PayloadKey, Vec<u8>>,
+}
+
+impl DeltaChannel {
+    pub fn new() -> DeltaChannel {
+        DeltaChannel {
+            sent: HashMap::new(),
+            received: HashMap::new(),
+        }
+    }
+
+    /// The frame to send instead of `message` (`[message type][packet]`), at most
+    /// `max_frame_bytes` long: a message whose full frame would be longer is sent as it is
+    pub fn encode(&mut self, sender_class: u16, message: &[u8], max_frame_bytes: usize) -> Vec<u8> {
+        let key = payload_key(sender_class, message);
+        let mut frame = Vec::with_capacity(FRAME_HEADER_BYTES + message.len());
+        frame.write_u16::<LittleEndian>(0).unwrap();
+
+        let delta = match self.sent.get(&key) {
+            Some((last, n_deltas)) if last.len() == message.len() && *n_deltas < DELTAS_PER_BASE => {
+                let mut runs = Vec::new();
+                encode_runs(&last[MESSAGE_HEADER_BYTES..], &message[MESSAGE_HEADER_BYTES..], &mut runs);
+                if MESSAGE_HEADER_BYTES + 8 + runs.len() < message.len() {
+                    Some((payload_hash(last), runs, n_deltas + 1))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+
+        let n_deltas = match delta {
+            Some((last_hash, runs, n_deltas)) => {
+                frame.push(KIND_DELTA);
+                frame.write_u16::<LittleEndian>(sender_class).unwrap();
+                frame.extend_from_slice(&message[..MESSAGE_HEADER_BYTES]);
+                frame.write_u64::<LittleEndian>(last_hash).unwrap();
+                frame.extend_from_slice(&runs);
+                n_deltas
+            }
+            None if FRAME_HEADER_BYTES + message.len() > max_frame_bytes => {
+                // the peer doesn't remember plain messages, so the next one can't be a delta either
+                self.sent.remove(&key);
+                return message.to_vec();
+            }
+            None => {
+                frame.push(KIND_DELTA_BASE);
+                frame.write_u16::<LittleEndian>(sender_class).unwrap();
+                frame.extend_from_slice(message);
+                0
+            }
+        };
+
+        if self.sent.len() < MAX_PAYLOADS || self.sent.contains_key(&key) {
+            self.sent.insert(key, (message.to_vec(), n_deltas));
+        }
+        frame
+    }
+
+    /// The message a received delta frame stands for
+    pub fn decode(&mut self, frame: &[u8]) -> Result<Vec<u8>, String> {
+        if frame.len() < FRAME_HEADER_BYTES + MESSAGE_HEADER_BYTES {
+            return Err(format!("Delta frame of {} bytes is truncated", frame.len()));
+        }
+        let sender_class = LittleEndian::read_u16(&frame[3..]);
+        let payload = &frame[FRAME_HEADER_BYTES..];
+        let key = payload_key(sender_class, payload);
+
+        let message = if frame[2] == KIND_DELTA_BASE {
+            payload.to_vec()
+        } else {
+            let runs = payload
+                .get(MESSAGE_HEADER_BYTES + 8..)
+                .ok_or_else(|| "Delta frame has no hash".to_owned())?;
+            let last = self
+                .received
+                .get(&key)
+                .filter(|last| payload_hash(last) == LittleEndian::read_u64(&payload[MESSAGE_HEADER_BYTES..]))
+                .ok_or_else(|| "Delta against a message that wasn't received".to_owned())?;
+            let mut message = last.clone();
+            apply_runs(&mut message[MESSAGE_HEADER_BYTES..], runs)?;
+            message
+        };
+
+        if is_control_frame(&message) {
+            return Err("Delta frame wraps a control frame".to_owned());
+        }
+        if self.received.len() < MAX_PAYLOADS || self.received.contains_key(&key) {
+            self.received.insert(key, message.clone());
+        }
+        Ok(message)
+    }
+}
+
+fn encode_runs(last: &[u8], message: &[u8], runs: &mut Vec<u8>) {
+    let changed = |i: usize| last[i] != message[i];
+    let mut pos = 0;
+    while let Some(offset) = (pos..message.len()).position(changed) {
+        if offset > MAX_RUN_BYTES {
+            runs.write_u16::<LittleEndian>(MAX_RUN_BYTES as u16).unwrap();
+            runs.write_u16::<LittleEndian>(0).unwrap();
+            pos += MAX_RUN_BYTES;
+            continue;
+        }
+        let start = pos + offset;
+        let limit = message.len().min(start + MAX_RUN_BYTES);
+        let mut end = start + 1;
+        while end < limit {
+            if changed(end) {
+                end += 1;
+            } else {
+                match (end..limit.min(end + MIN_UNCHANGED_BYTES)).find(|&i| changed(i)) {
+                    Some(next_change) => end = next_change + 1,
+                    None => break,
+                }
+            }
+        }
+        runs.write_u16::<LittleEndian>(offset as u16).unwrap();
+        runs.write_u16::<LittleEndian>((end - start) as u16).unwrap();
+        runs.extend_from_slice(&message[start..end]);
+        pos = end;
+    }
+}
+
+fn apply_runs(message: &mut [u8], runs: &[u8]) -> Result<(), String> {
+    let mut pos = 0;
+    let mut run_pos = 0;
+    while run_pos < runs.len() {
+        let run_header = runs
+            .get(run_pos..run_pos + RUN_HEADER_BYTES)
+            .ok_or_else(|| "Truncated delta run".to_owned())?;
+        pos += LittleEndian::read_u16(run_header) as usize;
+        let n_changed = LittleEndian::read_u16(&run_header[2..]) as usize;
+        run_pos += RUN_HEADER_BYTES;
+        let changed = runs
+            .get(run_pos..run_pos + n_changed)
+            .ok_or_else(|| "Truncated delta run".to_owned())?;
+        message
+            .get_mut(pos..pos + n_changed)
+            .ok_or_else(|| "Delta run beyond the message".to_owned())?
+            .copy_from_slice(changed);
+        pos += n_changed;
+        run_pos += n_changed;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_delta_roundtrip() {
+    let mut sender = DeltaChannel::new();
+    let mut receiver = DeltaChannel::new();
+    let mut message = vec![7, 0, 1, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0];
+    message.extend((0..200u32).map(|i| i as u8));
+
+    let mut frame_sizes = Vec::new();
+    for turn in 0..40u8 {
+        message[20] = turn;
+        message[21] = turn / 2;
+        message[150] = turn;
+        let frame = sender.encode(3, &message, 1024);
+        frame_sizes.push(frame.len());
+        assert_eq!(receiver.decode(&frame).unwrap(), message);
+    }
+    assert_eq!(frame_sizes[0], FRAME_HEADER_BYTES + message.len());
+    assert!(frame_sizes[1] < 50);
+    // full messages again after a number of deltas
+    assert_eq!(frame_sizes[DELTAS_PER_BASE + 1], FRAME_HEADER_BYTES + message.len());
+
+    message[30] = 99;
+    let missed = sender.encode(3, &message, 1024);
+    message[31] = 99;
+    let delta = sender.encode(3, &message, 1024);
+    assert!(missed.len() < 50);
+    assert!(receiver.decode(&delta).is_err());
+    assert!(receiver.decode(&delta[..10]).is_err());
+}
+
+#[test]
+fn test_delta_frames_fit_into_batches() {
+    let mut sender = DeltaChannel::new();
+    let mut message = vec![7, 0, 1, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0];
+    message.extend((0..200u32).map(|i| i as u8));
+
+    // a full frame wouldn't fit, so the message is sent plainly, every time
+    for turn in 0..3u8 {
+        message[20] = turn;
+        assert_eq!(sender.encode(3, &message, message.len()), message);
+    }
+    let base = sender.encode(3, &message, message.len() + FRAME_HEADER_BYTES);
+    assert_eq!(base.len(), message.len() + FRAME_HEADER_BYTES);
+    message[20] = 99;
+    assert!(sender.encode(3, &message, message.len()).len() < 50);
+}
@@ -0,0 +1,77 @@
+use std::collections::VecDeque;
+
+/// How many of our own state hashes we keep to compare with peers that are behind us
+const KEPT_OWN_HASHES: usize = 256;
+
+/// Compares the hashes of the actor state at the end of each turn between machines.
+/// All machines in lockstep should have identical state after the same turn,
+/// so differing hashes mean that their simulations diverged (desynced).
+pub(crate) struct DesyncDetection {
+    /// The hash of the turn that is about to be finished, set by the `ActorSystem`
+    pending: Option<u64>,
+    own_hashes: VecDeque<(usize, u64)>,
+}
+
+impl DesyncDetection {
+    pub fn new() -> DesyncDetection {
+        DesyncDetection {
+            pending: None,
+            own_hashes: VecDeque::with_capacity(KEPT_OWN_HASHES),
+        }
+    }
+
+    pub fn set_state_hash(&mut self, state_hash: u64) {
+        self.pending = Some(state_hash);
+    }
+
+    /// Remember the hash of the turn that was just finished, to be sent to peers
+    pub fn finish_turn(&mut self, n_turns: usize) -> Option<u64> {
+        let state_hash = self.pending.take()?;
+        if self.own_hashes.len() == KEPT_OWN_HASHES {
+            self.own_hashes.pop_front();
+        }
+        self.own_hashes.push_back((n_turns, state_hash));
+        Some(state_hash)
+    }
+
+    /// Compare the hashes a peer sent with our own, keeping those of turns we didn't finish yet.
+    /// Returns the first turn with differing hashes.
+    pub fn compare(&self, peer_hashes: &mut Vec<(usize, u64)>) -> Option<usize> {
+        let own_n_turns = self.own_hashes.back().map(|&(n_turns, _)| n_turns).unwrap_or(0);
+        let mut desynced_turn = None;
+
+        peer_hashes.retain(|&(n_turns, peer_hash)| {
+            if n_turns > own_n_turns {
+                return true;
+            }
+            let own_hash = self
+                .own_hashes
+                .iter()
+                .find(|&&(own_turn, _)| own_turn == n_turns)
+                .map(|&(_, own_hash)| own_hash);
+            if own_hash.map_or(false, |own_hash| own_hash != peer_hash) && desynced_turn.is_none() {
+                desynced_turn = Some(n_turns);
+            }
+            false
+        });
+
+        desynced_turn
+    }
+}
+
+#[test]
+fn test_desync_detected_once_both_finished_turn() {
+    let mut detection = DesyncDetection::new();
+    detection.set_state_hash(1);
+    assert_eq!(detection.finish_turn(1), Some(1));
+    assert_eq!(detection.finish_turn(2), None);
+
+    let mut peer_hashes = vec![(1, 1), (3, 5)];
+    assert_eq!(detection.compare(&mut peer_hashes), None);
+    assert_eq!(peer_hashes, vec![(3, 5)]);
+
+    detection.set_state_hash(4);
+    detection.finish_turn(3);
+    assert_eq!(detection.compare(&mut peer_hashes), Some(3));
+    assert!(peer_hashes.is_empty());
+}
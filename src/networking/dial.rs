@@ -0,0 +1,219 @@
+use super::handshake::{Handshake, InvalidHandshake};
+use super::proxy::Proxy;
+use crate::time::now_ms;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::time::Duration;
+use tungstenite::handshake::client::{ClientHandshake, Response};
+use tungstenite::handshake::MidHandshake;
+use tungstenite::util::NonBlockingError;
+use tungstenite::{
+    client as websocket_client, HandshakeError, Message as WebSocketMessage, WebSocket,
+};
+use url::Url;
+
+/// How long to try to open a TCP connection to one resolved address
+pub(crate) const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long to wait before dialing a peer again after a failed attempt
+const RETRY_DELAY_MS: f64 = 1000.0;
+
+/// Updates from the background thread that resolves and connects
+enum DialProgress {
+    Resolved,
+    Connected(TcpStream),
+    Failed(String),
+}
+
+enum DialState {
+    Resolving(Receiver<DialProgress>),
+    Connecting(Receiver<DialProgress>),
+    WebSocketHandshake(MidHandshake<ClientHandshake<TcpStream>>),
+    /// Waiting for the peer's reply to our handshake
+    Handshaking(WebSocket<TcpStream>),
+    WaitingToRetry { retry_at_ms: f64 },
+}
+
+/// The result of polling a `PendingConnection`
+pub(crate) enum DialOutcome {
+    Pending,
+    Established(WebSocket<TcpStream>, Handshake),
+}
+
+/// A connection to a peer we are dialing, progressing through
+/// resolving, connecting and handshaking without ever blocking.
+/// Failed attempts are retried, so peers may come up in any order.
+pub(crate) struct PendingConnection {
+    address: String,
+    proxy: Option<Proxy>,
+    state: Option<DialState>,
+    failures: usize,
+}
+
+impl PendingConnection {
+    /// Dial `address`, through `proxy` if given
+    pub fn start(address: &str, proxy: Option<&Proxy>) -> PendingConnection {
+        PendingConnection {
+            address: address.to_owned(),
+            proxy: proxy.cloned(),
+            state: Some(DialState::Resolving(spawn_dial(address.to_owned(), proxy.cloned()))),
+            failures: 0,
+        }
+    }
+
+    /// Advance as far as possible without blocking
+    pub fn poll(&mut self, handshake: &Handshake, auth_token: &Option<Vec<u8>>) -> DialOutcome {
+        loop {
+            let (next_state, progressed) = match self.state.take().unwrap() {
+                DialState::Resolving(progress) => match progress.try_recv() {
+                    Ok(DialProgress::Resolved) => (DialState::Connecting(progress), true),
+                    Ok(other) => (self.dial_progress(other, handshake), true),
+                    Err(TryRecvError::Empty) => (DialState::Resolving(progress), false),
+                    Err(TryRecvError::Disconnected) => (self.failed("dialing thread died"), false),
+                },
+                DialState::Connecting(progress) => match progress.try_recv() {
+                    Ok(other) => (self.dial_progress(other, handshake), true),
+                    Err(TryRecvError::Empty) => (DialState::Connecting(progress), false),
+                    Err(TryRecvError::Disconnected) => (self.failed("dialing thread died"), false),
+                },
+                DialState::WebSocketHandshake(mid_handshake) => {
+                    match self.websocket_progress(mid_handshake.handshake(), handshake) {
+                        state @ DialState::WebSocketHandshake(_) => (state, false),
+                        state => (state, true),
+                    }
+                }
+                DialState::Handshaking(mut websocket) => {
+                    let written = websocket.write_pending().or_else(|e| match e.into_non_blocking() {
+                        Some(real_err) => Err(real_err),
+                        None => Ok(()),
+                    });
+                    match written.and_then(|_| websocket.read_message()) {
+                        Ok(WebSocketMessage::Binary(data)) => match verified_reply(&data, auth_token) {
+                            Ok(reply) => return DialOutcome::Established(websocket, reply),
+                            Err(reason) => {
+                                println!("Rejected {}: {}", self.address, reason);
+                                let _ = websocket.close(None);
+                                (self.failed(&reason.to_string()), false)
+                            }
+                        },
+                        Ok(_) => (DialState::Handshaking(websocket), false),
+                        Err(e) => match e.into_non_blocking() {
+                            Some(real_err) => (self.failed(&real_err.to_string()), false),
+                            None => (DialState::Handshaking(websocket), false),
+                        },
+                    }
+                }
+                DialState::WaitingToRetry { retry_at_ms } => {
+                    if now_ms() >= retry_at_ms {
+                        (DialState::Resolving(spawn_dial(self.address.clone(), self.proxy.clone())), false)
+                    } else {
+                        (DialState::WaitingToRetry { retry_at_ms }, false)
+                    }
+                }
+            };
+
+            self.state = Some(next_state);
+            if !progressed {
+                return DialOutcome::Pending;
+            }
+        }
+    }
+
+    fn dial_progress(&mut self, progress: DialProgress, handshake: &Handshake) -> DialState {
+        match progress {
+            DialProgress::Resolved => unreachable!("Resolved twice"),
+            DialProgress::Connected(stream) => {
+                let url = Url::parse(&format!("ws://{}", self.address));
+                match (stream.set_nonblocking(true), url) {
+                    (Ok(()), Ok(url)) => {
+                        self.websocket_progress(websocket_client(url, stream), handshake)
+                    }
+                    (Err(e), _) => self.failed(&e.to_string()),
+                    (_, Err(e)) => self.failed(&e.to_string()),
+                }
+            }
+            DialProgress::Failed(reason) => self.failed(&reason),
+        }
+    }
+
+    fn websocket_progress(
+        &mut self,
+        result: Result<(WebSocket<TcpStream>, Response), HandshakeError<ClientHandshake<TcpStream>>>,
+        handshake: &Handshake,
+    ) -> DialState {
+        match result {
+            Ok((mut websocket, _)) => {
+                // queued even if the socket would block, sent by `write_pending` later
+                match websocket.write_message(WebSocketMessage::binary(handshake.encode())) {
+                    Ok(_) => DialState::Handshaking(websocket),
+                    Err(e) => match e.into_non_blocking() {
+                        Some(real_err) => self.failed(&real_err.to_string()),
+                        None => DialState::Handshaking(websocket),
+                    },
+                }
+            }
+            Err(HandshakeError::Interrupted(mid_handshake)) => {
+                DialState::WebSocketHandshake(mid_handshake)
+            }
+            Err(HandshakeError::Failure(e)) => self.failed(&e.to_string()),
+        }
+    }
+
+    fn failed(&mut self, reason: &str) -> DialState {
+        self.failures += 1;
+        if self.failures == 1 {
+            println!("Couldn't connect to {} yet ({}), retrying", self.address, reason);
+        }
+        DialState::WaitingToRetry {
+            retry_at_ms: now_ms() + RETRY_DELAY_MS,
+        }
+    }
+}
+
+fn verified_reply(
+    data: &[u8],
+    auth_token: &Option<Vec<u8>>,
+) -> Result<Handshake, InvalidHandshake> {
+    let reply = Handshake::decode(data)?;
+    reply.verify(auth_token)?;
+    Ok(reply)
+}
+
+/// Resolve and connect on a background thread, since neither can be done without blocking.
+/// Through a proxy, only the proxy is resolved here, the peer's address by the proxy
+fn spawn_dial(address: String, proxy: Option<Proxy>) -> Receiver<DialProgress> {
+    let (progress, receiver) = channel();
+
+    ::std::thread::spawn(move || {
+        if let Some(proxy) = proxy {
+            let _ = progress.send(DialProgress::Resolved);
+            let _ = progress.send(match proxy.connect(&address, CONNECT_TIMEOUT) {
+                Ok(stream) => DialProgress::Connected(stream),
+                Err(reason) => DialProgress::Failed(reason),
+            });
+            return;
+        }
+
+        let addresses: Vec<SocketAddr> = match address.to_socket_addrs() {
+            Ok(addresses) => addresses.collect(),
+            Err(e) => {
+                let _ = progress.send(DialProgress::Failed(e.to_string()));
+                return;
+            }
+        };
+        let _ = progress.send(DialProgress::Resolved);
+
+        let mut last_error = "no addresses resolved".to_owned();
+        for socket_address in addresses {
+            match TcpStream::connect_timeout(&socket_address, CONNECT_TIMEOUT) {
+                Ok(stream) => {
+                    let _ = progress.send(DialProgress::Connected(stream));
+                    return;
+                }
+                Err(e) => last_error = e.to_string(),
+            }
+        }
+        let _ = progress.send(DialProgress::Failed(last_error));
+    });
+
+    receiver
+}
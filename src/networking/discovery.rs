@@ -0,0 +1,285 @@
+use super::address::PeerAddress;
+use crate::id::MachineID;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+
+const RECORD_PREFIX: &str = "kay-discovery/1";
+const MAX_RECORD_BYTES: usize = 1024;
+
+/// A peer that announced itself on the local network
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct DiscoveredPeer {
+    /// The machine ID the peer announced
+    pub machine_id: MachineID,
+    /// The address the peer accepts networking connections on
+    pub address: String,
+}
+
+/// Announces an `ActorSystem` on the local network using UDP broadcasts
+/// and browses for peers announcing the same service, to produce
+/// the peer list for `Networking::new` without typing in addresses.
+///
+/// All peers of one service need to use the same discovery port.
+/// The port is bound with `SO_REUSEADDR`, so several processes on one host
+/// can browse it (on Unix, elsewhere only one `Discovery` per host can).
+pub struct Discovery {
+    service: String,
+    port: u16,
+    socket: UdpSocket,
+    own_record: Option<DiscoveredPeer>,
+    peers: HashMap<MachineID, DiscoveredPeer>,
+    on_discovered: Option<Box<dyn FnMut(&DiscoveredPeer)>>,
+}
+
+impl Discovery {
+    /// Start browsing for peers of `service` on the given UDP port
+    pub fn new(service: &str, port: u16) -> ::std::io::Result<Discovery> {
+        let socket = bind_shared(port)?;
+        socket.set_broadcast(true)?;
+        socket.set_nonblocking(true)?;
+
+        Ok(Discovery {
+            service: service.to_owned(),
+            port,
+            socket,
+            own_record: None,
+            peers: HashMap::new(),
+            on_discovered: None,
+        })
+    }
+
+    /// Announce the local actor system with each `poll`.
+    /// If the host part of `address` is empty, `0.0.0.0` or `[::]`
+    /// (like in `":9999"`), peers substitute the IP they received the announcement from.
+    pub fn announce_as(&mut self, machine_id: MachineID, address: &str) {
+        self.own_record = Some(DiscoveredPeer {
+            machine_id,
+            address: address.to_owned(),
+        });
+    }
+
+    /// Set a callback that is called whenever a new machine is discovered,
+    /// or a known machine announces a different address
+    pub fn on_discovered<F: FnMut(&DiscoveredPeer) + 'static>(&mut self, callback: F) {
+        self.on_discovered = Some(Box::new(callback));
+    }
+
+    /// Send the own announcement (if any) and process all received announcements.
+    /// Meant to be called regularly, for example once per turn until all peers are known.
+    pub fn poll(&mut self) {
+        if let Some(record) = self.encode_record() {
+            if let Err(e) = self
+                .socket
+                .send_to(record.as_bytes(), (Ipv4Addr::BROADCAST, self.port))
+            {
+                println!("Error while announcing for discovery: {}", e);
+            }
+        }
+
+        let mut buf = [0u8; MAX_RECORD_BYTES];
+
+        loop {
+            match self.socket.recv_from(&mut buf) {
+                Ok((len, source)) => {
+                    if let Some(peer) = self.parse_record(&buf[..len], source) {
+                        self.learn(peer);
+                    }
+                }
+                Err(ref e) if e.kind() == ::std::io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    println!("Error while browsing for discovery: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+
+    fn encode_record(&self) -> Option<String> {
+        self.own_record.as_ref().map(|own_record| {
+            format!(
+                "{} {} {} {}",
+                RECORD_PREFIX, self.service, own_record.machine_id.0, own_record.address
+            )
+        })
+    }
+
+    fn parse_record(&self, data: &[u8], source: SocketAddr) -> Option<DiscoveredPeer> {
+        let record = ::std::str::from_utf8(data).ok()?;
+        let mut parts = record.split(' ');
+
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(RECORD_PREFIX), Some(service), Some(machine_part), Some(address))
+                if service == self.service =>
+            {
+                let machine_id = MachineID(machine_part.parse().ok()?);
+
+                if self
+                    .own_record
+                    .as_ref()
+                    .map(|own| own.machine_id == machine_id)
+                    .unwrap_or(false)
+                {
+                    return None;
+                }
+
+                Some(DiscoveredPeer {
+                    machine_id,
+                    address: substitute_host(address, source.ip()),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    fn learn(&mut self, peer: DiscoveredPeer) {
+        if self.peers.get(&peer.machine_id) != Some(&peer) {
+            if let Some(ref mut callback) = self.on_discovered {
+                callback(&peer);
+            }
+            self.peers.insert(peer.machine_id, peer);
+        }
+    }
+
+    /// All peers discovered so far (not including the own announcement)
+    pub fn peers(&self) -> impl Iterator<Item = &DiscoveredPeer> {
+        self.peers.values()
+    }
+
+    /// Get a candidate peer list to be used as the `network` of `Networking::new`,
+    /// ordered by machine ID and including the own announcement.
+    /// Returns `None` as long as not all of `n_machines` machine IDs are known
+    /// (with valid addresses).
+    pub fn peer_list(&self, n_machines: usize) -> Option<Vec<PeerAddress>> {
+        (0..n_machines)
+            .map(|i| {
+                let machine_id = MachineID(i as u16);
+                let address = match self.own_record {
+                    Some(ref own) if own.machine_id == machine_id => Some(&own.address),
+                    _ => self.peers.get(&machine_id).map(|peer| &peer.address),
+                };
+                address.and_then(|address| PeerAddress::parse(address).ok())
+            })
+            .collect()
+    }
+}
+
+/// Bind the discovery port on all interfaces, sharing it with other sockets that do the same
+#[cfg(unix)]
+fn bind_shared(port: u16) -> ::std::io::Result<UdpSocket> {
+    use std::os::unix::io::FromRawFd;
+
+    let last_error = || Err(::std::io::Error::last_os_error());
+    unsafe {
+        let fd = libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0);
+        if fd < 0 {
+            return last_error();
+        }
+        // closes the socket again if anything fails
+        let socket = UdpSocket::from_raw_fd(fd);
+        let enabled: libc::c_int = 1;
+        if libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_REUSEADDR,
+            &enabled as *const libc::c_int as *const libc::c_void,
+            ::std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        ) != 0
+        {
+            return last_error();
+        }
+        let mut address: libc::sockaddr_in = ::std::mem::zeroed();
+        address.sin_family = libc::AF_INET as libc::sa_family_t;
+        address.sin_port = port.to_be();
+        address.sin_addr.s_addr = u32::from(Ipv4Addr::UNSPECIFIED).to_be();
+        if libc::bind(
+            fd,
+            &address as *const libc::sockaddr_in as *const libc::sockaddr,
+            ::std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+        ) != 0
+        {
+            return last_error();
+        }
+        Ok(socket)
+    }
+}
+
+#[cfg(not(unix))]
+fn bind_shared(port: u16) -> ::std::io::Result<UdpSocket> {
+    UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))
+}
+
+fn substitute_host(address: &str, source: IpAddr) -> String {
+    match address.rfind(':') {
+        Some(colon) if ["", "0.0.0.0", "[::]"].contains(&&address[..colon]) => {
+            match address[colon + 1..].parse() {
+                // formatted as a socket address to bracket IPv6 sources
+                Ok(port) => SocketAddr::new(source, port).to_string(),
+                Err(_) => address.to_owned(),
+            }
+        }
+        _ => address.to_owned(),
+    }
+}
+
+#[test]
+fn test_announcement_roundtrip() {
+    let source: SocketAddr = "192.168.1.7:40000".parse().unwrap();
+    let free_port = || UdpSocket::bind("0.0.0.0:0").unwrap().local_addr().unwrap().port();
+    let mut announcing = Discovery::new("game", free_port()).unwrap();
+    let browsing = Discovery::new("game", free_port()).unwrap();
+    assert_eq!(announcing.encode_record(), None);
+
+    announcing.announce_as(MachineID(3), "example.com:9999");
+    let record = announcing.encode_record().unwrap();
+    assert_eq!(record, "kay-discovery/1 game 3 example.com:9999");
+    assert_eq!(
+        browsing.parse_record(record.as_bytes(), source),
+        Some(DiscoveredPeer {
+            machine_id: MachineID(3),
+            address: "example.com:9999".to_owned()
+        })
+    );
+    // own announcements are ignored
+    assert_eq!(announcing.parse_record(record.as_bytes(), source), None);
+
+    // unspecified hosts are substituted by the source of the announcement
+    announcing.announce_as(MachineID(3), ":9999");
+    let record = announcing.encode_record().unwrap();
+    let peer = browsing.parse_record(record.as_bytes(), source).unwrap();
+    assert_eq!(peer.address, "192.168.1.7:9999");
+    let ipv6_source: SocketAddr = "[fe80::1]:40000".parse().unwrap();
+    let peer = browsing.parse_record(b"kay-discovery/1 game 4 [::]:9999", ipv6_source).unwrap();
+    assert_eq!(peer.address, "[fe80::1]:9999");
+
+    // announcements of other services, versions or garbage are ignored
+    for &record in &[
+        &b"kay-discovery/1 other 3 example.com:9999"[..],
+        b"kay-discovery/2 game 3 example.com:9999",
+        b"kay-discovery/1 game x example.com:9999",
+        b"kay-discovery/1 game 3",
+        b"\xff\xfe",
+    ] {
+        assert_eq!(browsing.parse_record(record, source), None);
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_discovery_port_is_shared() {
+    use std::time::{Duration, Instant};
+
+    let port = UdpSocket::bind("0.0.0.0:0").unwrap().local_addr().unwrap().port();
+    let mut first = Discovery::new("game", port).unwrap();
+    let mut second = Discovery::new("game", port).unwrap();
+    first.announce_as(MachineID(0), "127.0.0.1:9000");
+    second.announce_as(MachineID(1), "127.0.0.1:9001");
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while first.peer_list(2).is_none() || second.peer_list(2).is_none() {
+        assert!(Instant::now() < deadline, "Discoveries sharing a port didn't find each other");
+        first.poll();
+        second.poll();
+        ::std::thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(first.peer_list(2), second.peer_list(2));
+}
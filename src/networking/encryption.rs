@@ -0,0 +1,200 @@
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use snow::{Builder, HandshakeState, TransportState};
+
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+/// Maximum size of one Noise message, including its authentication tag
+const MAX_NOISE_MESSAGE: usize = 65535;
+const TAG_BYTES: usize = 16;
+const MAX_CHUNK_PLAINTEXT: usize = MAX_NOISE_MESSAGE - TAG_BYTES;
+
+enum NoiseState {
+    Handshake(HandshakeState),
+    Transport(TransportState),
+    /// Only while switching from handshake to transport mode
+    Switching,
+}
+
+/// An encrypted channel to one peer, negotiated with the Noise XX pattern
+/// right after the connection handshake.
+///
+/// Frames are split into chunks that fit into Noise messages,
+/// each encrypted chunk is prefixed with its length as a `u16`.
+pub(crate) struct NoiseChannel {
+    state: NoiseState,
+    outgoing_handshake: Vec<Vec<u8>>,
+    /// The static public key the peer has to prove it holds, if it is pinned
+    peer_key: Option<Vec<u8>>,
+}
+
+impl NoiseChannel {
+    /// The dialing side (`initiator`) sends the first handshake message.
+    /// If `peer_key` is given, the handshake fails unless the peer's static key is that one
+    pub fn new(private_key: &[u8], initiator: bool, peer_key: Option<Vec<u8>>) -> Result<NoiseChannel, snow::Error> {
+        let builder = Builder::new(NOISE_PARAMS.parse().unwrap()).local_private_key(private_key);
+        let mut handshake = if initiator {
+            builder.build_initiator()?
+        } else {
+            builder.build_responder()?
+        };
+
+        let mut outgoing_handshake = Vec::new();
+        if initiator {
+            let mut message = vec![0u8; MAX_NOISE_MESSAGE];
+            let len = handshake.write_message(&[], &mut message)?;
+            message.truncate(len);
+            outgoing_handshake.push(message);
+        }
+
+        Ok(NoiseChannel {
+            state: NoiseState::Handshake(handshake),
+            outgoing_handshake,
+            peer_key,
+        })
+    }
+
+    /// A new static key pair, `(private key, public key)`
+    pub fn generate_keypair() -> (Vec<u8>, Vec<u8>) {
+        let keypair = Builder::new(NOISE_PARAMS.parse().unwrap())
+            .generate_keypair()
+            .unwrap();
+        (keypair.private, keypair.public)
+    }
+
+    pub fn is_established(&self) -> bool {
+        match self.state {
+            NoiseState::Transport(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Handshake messages that need to be sent to the peer before anything else
+    pub fn take_handshake_messages(&mut self) -> Vec<Vec<u8>> {
+        self.outgoing_handshake.drain(..).collect()
+    }
+
+    /// Process a handshake message received from the peer,
+    /// checking its static key against the pinned one once the handshake finished
+    pub fn read_handshake(&mut self, data: &[u8]) -> Result<(), String> {
+        let mut handshake = match ::std::mem::replace(&mut self.state, NoiseState::Switching) {
+            NoiseState::Handshake(handshake) => handshake,
+            other => {
+                self.state = other;
+                return Ok(());
+            }
+        };
+
+        let mut payload = vec![0u8; MAX_NOISE_MESSAGE];
+        handshake.read_message(data, &mut payload).map_err(|e| e.to_string())?;
+
+        if !handshake.is_handshake_finished() && handshake.is_my_turn() {
+            let mut message = vec![0u8; MAX_NOISE_MESSAGE];
+            let len = handshake.write_message(&[], &mut message).map_err(|e| e.to_string())?;
+            message.truncate(len);
+            self.outgoing_handshake.push(message);
+        }
+
+        if handshake.is_handshake_finished() {
+            if let Some(ref peer_key) = self.peer_key {
+                if handshake.get_remote_static() != Some(&peer_key[..]) {
+                    return Err("Peer doesn't have the pinned static key".to_owned());
+                }
+            }
+            self.state = NoiseState::Transport(handshake.into_transport_mode().map_err(|e| e.to_string())?);
+        } else {
+            self.state = NoiseState::Handshake(handshake);
+        }
+        Ok(())
+    }
+
+    pub fn encrypt(&mut self, frame: &[u8]) -> Result<Vec<u8>, snow::Error> {
+        let transport = match self.state {
+            NoiseState::Transport(ref mut transport) => transport,
+            _ => panic!("Encrypting before the encryption handshake finished"),
+        };
+
+        let n_chunks = frame.len() / MAX_CHUNK_PLAINTEXT + 1;
+        let mut encrypted = Vec::with_capacity(frame.len() + n_chunks * (2 + TAG_BYTES));
+        let mut chunk_buffer = vec![0u8; MAX_NOISE_MESSAGE];
+        for chunk in frame.chunks(MAX_CHUNK_PLAINTEXT) {
+            let len = transport.write_message(chunk, &mut chunk_buffer)?;
+            encrypted.write_u16::<LittleEndian>(len as u16).unwrap();
+            encrypted.extend_from_slice(&chunk_buffer[..len]);
+        }
+        Ok(encrypted)
+    }
+
+    pub fn decrypt(&mut self, data: &[u8]) -> Result<Vec<u8>, snow::Error> {
+        let transport = match self.state {
+            NoiseState::Transport(ref mut transport) => transport,
+            _ => panic!("Decrypting before the encryption handshake finished"),
+        };
+
+        let mut frame = Vec::with_capacity(data.len());
+        let mut chunk_buffer = vec![0u8; MAX_NOISE_MESSAGE];
+        let mut pos = 0;
+        while pos + 2 <= data.len() {
+            let len = LittleEndian::read_u16(&data[pos..]) as usize;
+            pos += 2;
+            let chunk = data
+                .get(pos..pos + len)
+                .ok_or(snow::Error::Decrypt)?;
+            let plain_len = transport.read_message(chunk, &mut chunk_buffer)?;
+            frame.extend_from_slice(&chunk_buffer[..plain_len]);
+            pos += len;
+        }
+        Ok(frame)
+    }
+}
+
+#[cfg(test)]
+fn handshake(initiator: &mut NoiseChannel, responder: &mut NoiseChannel) -> Result<(), String> {
+    while !initiator.is_established() || !responder.is_established() {
+        let to_responder = initiator.take_handshake_messages();
+        let to_initiator = responder.take_handshake_messages();
+        if to_responder.is_empty() && to_initiator.is_empty() {
+            return Err("Handshake stalled".to_owned());
+        }
+        for message in to_responder {
+            responder.read_handshake(&message)?;
+        }
+        for message in to_initiator {
+            initiator.read_handshake(&message)?;
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_encrypted_roundtrip() {
+    let (initiator_private, _) = NoiseChannel::generate_keypair();
+    let (responder_private, responder_public) = NoiseChannel::generate_keypair();
+    let mut initiator = NoiseChannel::new(&initiator_private, true, Some(responder_public)).unwrap();
+    let mut responder = NoiseChannel::new(&responder_private, false, None).unwrap();
+    handshake(&mut initiator, &mut responder).unwrap();
+
+    // spans several Noise messages
+    let frame: Vec<u8> = (0..3 * MAX_CHUNK_PLAINTEXT + 7).map(|i| i as u8).collect();
+    let encrypted = initiator.encrypt(&frame).unwrap();
+    assert_ne!(&encrypted[..64], &frame[..64]);
+    assert_eq!(responder.decrypt(&encrypted).unwrap(), frame);
+
+    let reply = b"reply".to_vec();
+    let encrypted = responder.encrypt(&reply).unwrap();
+    assert_eq!(initiator.decrypt(&encrypted).unwrap(), reply);
+
+    let mut tampered = initiator.encrypt(&reply).unwrap();
+    let last = tampered.len() - 1;
+    tampered[last] ^= 1;
+    assert!(responder.decrypt(&tampered).is_err());
+}
+
+#[test]
+fn test_pinned_key_mismatch() {
+    let (initiator_private, _) = NoiseChannel::generate_keypair();
+    let (responder_private, _) = NoiseChannel::generate_keypair();
+    let (_, other_public) = NoiseChannel::generate_keypair();
+    let mut initiator = NoiseChannel::new(&initiator_private, true, Some(other_public)).unwrap();
+    let mut responder = NoiseChannel::new(&responder_private, false, None).unwrap();
+    assert!(handshake(&mut initiator, &mut responder).is_err());
+    assert!(!initiator.is_established());
+}
@@ -0,0 +1,90 @@
+use crate::id::{MachineID, RawID};
+use crate::type_registry::ShortTypeId;
+use compact::CString;
+
+/// Sent to the recipient set with `ActorSystem::networking_notify_connection_events`
+/// when a connection to a peer was established
+#[derive(Copy, Clone, Debug)]
+pub struct PeerConnected {
+    /// The connected peer
+    pub machine_id: MachineID,
+}
+
+/// Sent to the recipient set with `ActorSystem::networking_notify_connection_events`
+/// when the connection to a peer closed
+#[derive(Copy, Clone, Debug)]
+pub struct PeerDisconnected {
+    /// The disconnected peer
+    pub machine_id: MachineID,
+}
+
+/// Sent to the recipient set with `ActorSystem::networking_notify_connection_events`
+/// when a peer starts to fall behind by more than the acceptable turn distance
+#[derive(Copy, Clone, Debug)]
+pub struct PeerLagging {
+    /// The lagging peer
+    pub machine_id: MachineID,
+    /// How many turns the peer is behind the local turn
+    pub turns_behind: usize,
+}
+
+/// Sent to the recipient set with `ActorSystem::networking_notify_pause_events`
+/// when all machines stopped advancing turns after a pause was requested
+#[derive(Copy, Clone, Debug)]
+pub struct NetworkPaused {
+    /// The turn the network paused at
+    pub n_turns: usize,
+}
+
+/// Sent to the recipient set with `ActorSystem::networking_notify_pause_events`
+/// when turns continue to advance after a pause
+#[derive(Copy, Clone, Debug)]
+pub struct NetworkResumed {
+    /// The turn the network resumed at
+    pub n_turns: usize,
+}
+
+/// Sent to the recipient set with `ActorSystem::networking_notify_desyncs`
+/// when the actor state of a peer differed from ours after the same turn
+#[derive(Copy, Clone, Debug)]
+pub struct DesyncDetected {
+    /// The first turn after which the states differed
+    pub turn: usize,
+    /// The peer whose state differed
+    pub machine: MachineID,
+}
+
+/// Sent to the recipient set with `ActorSystem::networking_notify_rejected_messages`
+/// when a peer sent a message that wasn't allowed with `ActorSystem::networking_allow_remote`
+#[derive(Compact, Clone)]
+pub struct RemoteMessageRejected {
+    /// The peer that sent the message
+    pub from: MachineID,
+    /// The name of the message type
+    pub message_type: CString,
+    /// The recipient the message was addressed to
+    pub recipient: RawID,
+}
+
+crate::compact_bounds!(RemoteMessageRejected { message_type });
+
+/// Connection, pause and desync state changes and rejected messages,
+/// collected until the `ActorSystem` delivers them as messages
+pub(crate) enum NetworkingEvent {
+    Connected(MachineID),
+    /// A peer shut down and told us it leaves, followed by `Disconnected`
+    Left(MachineID),
+    /// The connection to a peer closed unexpectedly: `(peer, why)`, followed by `Disconnected`
+    ConnectionLost(MachineID, String),
+    Disconnected(MachineID),
+    Lagging(MachineID, usize),
+    Paused(usize),
+    Resumed(usize),
+    Desync(usize, MachineID),
+    /// Strict lockstep waited for these peers for longer than the timeout
+    LockstepTimedOut(Vec<MachineID>),
+    /// A message from a peer was rejected by the allowlist: `(from, message type, recipient)`
+    Rejected(MachineID, ShortTypeId, RawID),
+    /// A batch or message from a peer was ignored as invalid: `(from, what was wrong with it)`
+    Invalid(MachineID, String),
+}
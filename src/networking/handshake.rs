@@ -0,0 +1,326 @@
+use crate::id::MachineID;
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+
+const FIELD_AUTH_TOKEN: u8 = 1;
+const FIELD_LISTEN_ADDRESS: u8 = 2;
+const FIELD_ASSIGNED_MACHINE_ID: u8 = 3;
+const FIELD_PEER_TABLE: u8 = 4;
+const FIELD_ROLE: u8 = 5;
+const FIELD_SESSION_TOKEN: u8 = 6;
+const FIELD_THIN_CLIENT: u8 = 7;
+const FIELD_COMPRESSION: u8 = 8;
+const FIELD_STRIPES: u8 = 9;
+const FIELD_STRIPE_LANE: u8 = 10;
+
+const ROLE_SPECTATOR: u8 = 1;
+
+/// Marks a handshake that starts with a two-byte machine ID.
+/// Never a valid legacy machine ID byte, since it is the legacy broadcast machine ID.
+const WIDE_MACHINE_ID_FLAG: u8 = 0xFF;
+
+/// How a machine takes part in the network
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum MachineRole {
+    /// A full member of the simulation (the default)
+    Participant,
+    /// Receives messages and turn markers, but may only send message types allowed with
+    /// `ActorSystem::allow_from_spectators` and never holds back lockstep.
+    /// Can join and leave freely, for example a web dashboard.
+    Spectator,
+}
+
+/// The first message sent in both directions on a new connection.
+///
+/// Layout: the machine ID, followed by any number of optional fields,
+/// each encoded as `[tag: u8][length: u16][bytes]`. Unknown fields are skipped,
+/// so a bare machine ID byte (as sent by older peers) is still a valid handshake.
+///
+/// Machine IDs below 255 are sent as a single byte for compatibility,
+/// larger ones as `[WIDE_MACHINE_ID_FLAG][machine ID: u16]`. Only the handshake is compatible:
+/// packets carry 12 byte `RawID`s, which older peers can't read.
+///
+/// A peer joining a negotiated network sends the broadcast machine ID
+/// plus its listening address, and gets its assigned machine ID and the peer table
+/// (listening addresses indexed by machine ID) in the reply.
+/// A peer table that doesn't fit into one field is split across several consecutive
+/// peer table fields, each holding whole addresses.
+///
+/// The accepting side issues a session token in its reply,
+/// which the connecting side presents again when it reconnects.
+///
+/// Thin clients (see `Networking::accept_thin_clients`) send the broadcast machine ID
+/// and an empty thin client field, and get their assigned machine ID in the reply.
+///
+/// Peers that can compress frames (see `Networking::enable_compression`) send an empty
+/// compression field, frames are compressed in both directions if both sides did.
+///
+/// Peers that can stripe frames across several connections (see `Networking::enable_striping`)
+/// send the number of lanes they want, `[lanes: u8]`, and stripe across the smaller number.
+/// The extra connections send handshakes with their lane, `[lane: u8]`, and the session token
+/// the peer issued on the main connection.
+#[derive(Clone)]
+pub(crate) struct Handshake {
+    pub machine_id: MachineID,
+    pub auth_token: Option<Vec<u8>>,
+    pub listen_address: Option<String>,
+    pub assigned_machine_id: Option<MachineID>,
+    pub peer_table: Option<Vec<String>>,
+    pub role: MachineRole,
+    pub session_token: Option<Vec<u8>>,
+    pub thin_client: bool,
+    pub compression: bool,
+    pub stripes: u8,
+    pub stripe_lane: Option<u8>,
+}
+
+/// Reasons for rejecting a handshake
+#[derive(Debug)]
+pub(crate) enum InvalidHandshake {
+    Empty,
+    Truncated,
+    Malformed,
+    WrongCredentials,
+}
+
+impl ::std::fmt::Display for InvalidHandshake {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        ::std::fmt::Debug::fmt(self, f)
+    }
+}
+
+impl Handshake {
+    pub fn new(machine_id: MachineID, auth_token: &Option<Vec<u8>>) -> Handshake {
+        Handshake {
+            machine_id,
+            auth_token: auth_token.clone(),
+            listen_address: None,
+            assigned_machine_id: None,
+            peer_table: None,
+            role: MachineRole::Participant,
+            session_token: None,
+            thin_client: false,
+            compression: false,
+            stripes: 1,
+            stripe_lane: None,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+
+        if self.machine_id.0 < u16::from(WIDE_MACHINE_ID_FLAG) {
+            data.push(self.machine_id.0 as u8);
+        } else {
+            data.push(WIDE_MACHINE_ID_FLAG);
+            data.write_u16::<LittleEndian>(self.machine_id.0).unwrap();
+        }
+
+        if let Some(ref token) = self.auth_token {
+            write_field(&mut data, FIELD_AUTH_TOKEN, token);
+        }
+
+        if let Some(ref address) = self.listen_address {
+            write_field(&mut data, FIELD_LISTEN_ADDRESS, address.as_bytes());
+        }
+
+        if let Some(assigned) = self.assigned_machine_id {
+            let mut field = Vec::new();
+            field.write_u16::<LittleEndian>(assigned.0).unwrap();
+            write_field(&mut data, FIELD_ASSIGNED_MACHINE_ID, &field);
+        }
+
+        if let Some(ref peer_table) = self.peer_table {
+            for chunk in peer_table_chunks(peer_table) {
+                write_field(&mut data, FIELD_PEER_TABLE, chunk.as_bytes());
+            }
+        }
+
+        if self.role == MachineRole::Spectator {
+            write_field(&mut data, FIELD_ROLE, &[ROLE_SPECTATOR]);
+        }
+
+        if let Some(ref token) = self.session_token {
+            write_field(&mut data, FIELD_SESSION_TOKEN, token);
+        }
+
+        if self.thin_client {
+            write_field(&mut data, FIELD_THIN_CLIENT, &[]);
+        }
+
+        if self.compression {
+            write_field(&mut data, FIELD_COMPRESSION, &[]);
+        }
+
+        if self.stripes > 1 {
+            write_field(&mut data, FIELD_STRIPES, &[self.stripes]);
+        }
+
+        if let Some(lane) = self.stripe_lane {
+            write_field(&mut data, FIELD_STRIPE_LANE, &[lane]);
+        }
+
+        data
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Handshake, InvalidHandshake> {
+        let (machine_id, mut pos) = match data.first() {
+            None => return Err(InvalidHandshake::Empty),
+            Some(&WIDE_MACHINE_ID_FLAG) => {
+                if data.len() < 3 {
+                    return Err(InvalidHandshake::Truncated);
+                }
+                (MachineID(LittleEndian::read_u16(&data[1..])), 3)
+            }
+            Some(&legacy_machine_id) => (MachineID(u16::from(legacy_machine_id)), 1),
+        };
+        let mut handshake = Handshake::new(machine_id, &None);
+
+        while pos < data.len() {
+            if pos + 3 > data.len() {
+                return Err(InvalidHandshake::Truncated);
+            }
+            let tag = data[pos];
+            let len = LittleEndian::read_u16(&data[pos + 1..]) as usize;
+            pos += 3;
+            if pos + len > data.len() {
+                return Err(InvalidHandshake::Truncated);
+            }
+            let field = &data[pos..pos + len];
+
+            match tag {
+                FIELD_AUTH_TOKEN => handshake.auth_token = Some(field.to_vec()),
+                FIELD_LISTEN_ADDRESS => handshake.listen_address = Some(decode_str(field)?),
+                FIELD_ASSIGNED_MACHINE_ID => {
+                    if field.len() != 2 {
+                        return Err(InvalidHandshake::Malformed);
+                    }
+                    handshake.assigned_machine_id = Some(MachineID(LittleEndian::read_u16(field)))
+                }
+                FIELD_PEER_TABLE => handshake
+                    .peer_table
+                    .get_or_insert_with(Vec::new)
+                    .extend(decode_str(field)?.split('\n').map(str::to_owned)),
+                FIELD_ROLE => {
+                    handshake.role = match field {
+                        [0] => MachineRole::Participant,
+                        [ROLE_SPECTATOR] => MachineRole::Spectator,
+                        _ => return Err(InvalidHandshake::Malformed),
+                    }
+                }
+                FIELD_SESSION_TOKEN => handshake.session_token = Some(field.to_vec()),
+                FIELD_THIN_CLIENT => handshake.thin_client = true,
+                FIELD_COMPRESSION => handshake.compression = true,
+                FIELD_STRIPES => match field {
+                    [stripes] if *stripes > 0 => handshake.stripes = *stripes,
+                    _ => return Err(InvalidHandshake::Malformed),
+                },
+                FIELD_STRIPE_LANE => match field {
+                    [lane] if *lane > 0 => handshake.stripe_lane = Some(*lane),
+                    _ => return Err(InvalidHandshake::Malformed),
+                },
+                _ => {}
+            }
+
+            pos += len;
+        }
+
+        Ok(handshake)
+    }
+
+    /// Check the credentials presented by the peer against our own pre-shared token.
+    /// If we don't have a token, every peer is accepted.
+    pub fn verify(&self, expected_token: &Option<Vec<u8>>) -> Result<(), InvalidHandshake> {
+        match (expected_token, &self.auth_token) {
+            (None, _) => Ok(()),
+            (Some(expected), Some(presented)) if constant_time_eq(expected, presented) => Ok(()),
+            _ => Err(InvalidHandshake::WrongCredentials),
+        }
+    }
+}
+
+fn write_field(data: &mut Vec<u8>, tag: u8, field: &[u8]) {
+    assert!(field.len() <= u16::max_value() as usize, "Handshake field too long");
+    data.push(tag);
+    data.write_u16::<LittleEndian>(field.len() as u16).unwrap();
+    data.extend_from_slice(field);
+}
+
+/// Join the addresses of `peer_table` into as few "\n"-separated chunks as fit into fields.
+/// Each address was itself received in a handshake field, so it always fits into one.
+fn peer_table_chunks(peer_table: &[String]) -> Vec<String> {
+    let mut chunks = vec![String::new()];
+    for (i, address) in peer_table.iter().enumerate() {
+        let chunk = chunks.last_mut().unwrap();
+        if i == 0 {
+            chunk.push_str(address);
+        } else if chunk.len() + 1 + address.len() <= u16::max_value() as usize {
+            chunk.push('\n');
+            chunk.push_str(address);
+        } else {
+            chunks.push(address.clone());
+        }
+    }
+    chunks
+}
+
+fn decode_str(field: &[u8]) -> Result<String, InvalidHandshake> {
+    ::std::str::from_utf8(field)
+        .map(str::to_owned)
+        .map_err(|_| InvalidHandshake::Malformed)
+}
+
+/// Compare two tokens without leaking the position of the first difference through timing
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[test]
+fn test_handshake_roundtrip() {
+    let token = Some(b"secret".to_vec());
+    let data = Handshake::new(MachineID(3), &token).encode();
+    let decoded = Handshake::decode(&data).unwrap();
+    assert_eq!(decoded.machine_id, MachineID(3));
+    assert!(decoded.verify(&token).is_ok());
+    assert!(decoded.verify(&Some(b"secreT".to_vec())).is_err());
+
+    let mut wide = Handshake::new(MachineID(300), &None);
+    wide.compression = true;
+    wide.stripes = 4;
+    let wide = Handshake::decode(&wide.encode()).unwrap();
+    assert_eq!(wide.machine_id, MachineID(300));
+    assert!(wide.compression && !decoded.compression);
+    assert_eq!((wide.stripes, decoded.stripes), (4, 1));
+    assert!(Handshake::decode(&[3, FIELD_STRIPE_LANE, 1, 0, 0]).is_err());
+
+    // as sent by the generated TypeScript client (see `ClientProtocol::typescript`)
+    let thin = Handshake::decode(&[0xFF, 0xFF, 0xFF, FIELD_THIN_CLIENT, 0, 0]).unwrap();
+    assert_eq!(thin.machine_id, MachineID(u16::max_value()));
+    assert!(thin.thin_client);
+
+    let legacy = Handshake::decode(&[7]).unwrap();
+    assert_eq!(legacy.machine_id, MachineID(7));
+    assert!(legacy.verify(&None).is_ok());
+    assert!(legacy.verify(&token).is_err());
+}
+
+#[test]
+fn test_large_peer_table() {
+    let peer_table = (0..5000)
+        .map(|i| format!("machine-{:05}.example.com:9999", i))
+        .collect::<Vec<_>>();
+    assert!(peer_table.join("\n").len() > u16::max_value() as usize);
+    let mut reply = Handshake::new(MachineID(0), &None);
+    reply.assigned_machine_id = Some(MachineID(4999));
+    reply.peer_table = Some(peer_table.clone());
+    let decoded = Handshake::decode(&reply.encode()).unwrap();
+    assert_eq!(decoded.assigned_machine_id, Some(MachineID(4999)));
+    assert_eq!(decoded.peer_table, Some(peer_table));
+
+    let mut small = Handshake::new(MachineID(0), &None);
+    small.peer_table = Some(vec!["a:1".to_owned(), "b:2".to_owned()]);
+    let decoded = Handshake::decode(&small.encode()).unwrap();
+    assert_eq!(decoded.peer_table, small.peer_table);
+}
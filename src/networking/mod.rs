@@ -0,0 +1,3150 @@
+use crate::class::Class;
+use crate::id::{broadcast_machine_id, MachineID, RawID, MAX_MACHINES};
+use crate::interest::Interests;
+use crate::messaging::{Message, Packet};
+use crate::type_registry::ShortTypeId;
+use crate::time::{duration_ms, now_ms};
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use compact::Compact;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use std::time::Duration;
+#[cfg(feature = "server")]
+use std::net::{TcpListener, TcpStream};
+#[cfg(feature = "browser")]
+use stdweb::traits::{IEventTarget, IMessageEvent};
+#[cfg(feature = "browser")]
+use stdweb::web::{SocketBinaryType, SocketReadyState, TypedArray, WebSocket};
+#[cfg(feature = "server")]
+use tungstenite::util::NonBlockingError;
+#[cfg(feature = "server")]
+use tungstenite::{
+    accept as websocket_accept, client as websocket_client, HandshakeError,
+    Message as WebSocketMessage, WebSocket,
+};
+#[cfg(feature = "server")]
+use url::Url;
+
+#[cfg(feature = "server")]
+mod discovery;
+#[cfg(feature = "server")]
+pub use self::discovery::{DiscoveredPeer, Discovery};
+
+mod address;
+pub use self::address::{InvalidPeerAddress, PeerAddress};
+mod batching;
+use self::batching::BatchingTarget;
+mod buffer_pool;
+use self::buffer_pool::{BufferPool, SharedBufferPool};
+pub use self::buffer_pool::BufferPoolStats;
+mod builder;
+pub use self::builder::NetworkingBuilder;
+mod clock;
+pub use self::clock::ClockStats;
+mod desync;
+use self::desync::DesyncDetection;
+mod delta;
+#[cfg(feature = "server")]
+mod dial;
+#[cfg(feature = "server")]
+use self::dial::{DialOutcome, PendingConnection};
+#[cfg(feature = "server")]
+mod proxy;
+#[cfg(feature = "server")]
+pub use self::proxy::Proxy;
+#[cfg(feature = "server")]
+mod socket_options;
+#[cfg(feature = "server")]
+pub use self::socket_options::{KeepAlive, SocketOptions};
+mod conditioner;
+use self::conditioner::LinkConditioner;
+pub use self::conditioner::LinkConditions;
+mod control;
+use self::control::{is_control_frame, is_delta_frame, ControlFrame, PeerState, RemoteAllowlist};
+#[cfg(any(feature = "webtransport", test))]
+mod webtransport;
+#[cfg(feature = "webtransport")]
+use self::webtransport::{webtransport_url, FrameReader, WebTransportSession, MAX_DATAGRAM_BYTES};
+#[cfg(feature = "compression")]
+mod compression;
+#[cfg(feature = "compression")]
+use self::compression::CompressionChannel;
+#[cfg(feature = "encryption")]
+mod encryption;
+#[cfg(feature = "encryption")]
+use self::encryption::NoiseChannel;
+mod events;
+pub(crate) use self::events::NetworkingEvent;
+pub use self::events::{
+    DesyncDetected, NetworkPaused, NetworkResumed, PeerConnected, PeerDisconnected, PeerLagging,
+    RemoteMessageRejected,
+};
+mod handshake;
+use self::handshake::Handshake;
+pub use self::handshake::MachineRole;
+mod outbox;
+use self::outbox::Outbox;
+mod pacing;
+use self::pacing::PacingController;
+mod recording;
+use self::recording::BatchRecorder;
+mod reliability;
+use self::reliability::ReliableLink;
+pub use self::recording::PlaybackNetworking;
+mod session;
+use self::session::Sessions;
+#[cfg(feature = "server")]
+mod striping;
+#[cfg(feature = "server")]
+use self::striping::{Lane, Striping};
+mod traffic;
+pub use self::traffic::{MessageTraffic, NetworkTraffic};
+mod turn_protocol;
+pub use self::turn_protocol::{LockstepTurns, TurnProtocol};
+mod validate;
+use self::validate::{validate_batch, validate_message};
+
+/// A requested pause takes effect this many turns after the furthest machine's turn
+/// known to the host, so its decision reaches all machines before any of them passes the pause turn
+const PAUSE_MARGIN_TURNS: usize = 10;
+
+/// How many machine IDs beyond the ones we know peers of a negotiated network can claim,
+/// since up to this many peers could have joined since we got our peer table
+#[cfg(feature = "server")]
+const MAX_MACHINE_IDS_JOINED_SINCE: usize = 64;
+
+/// Every this many turns, a ping is sent to each peer to measure round trip times
+const PING_INTERVAL_TURNS: usize = 10;
+
+/// Represents a networking configuration, topology and state of an `ActorSystem`
+pub struct Networking {
+    /// The machine ID of the local actor system
+    pub machine_id: MachineID,
+    batch_message_bytes: usize,
+    /// The progress of networking turns of the local actor system
+    pub n_turns: usize,
+    acceptable_turn_distance: usize,
+    skip_turns_per_turn_head: usize,
+    network: Vec<String>,
+    network_connections: Vec<Option<Connection>>,
+    /// Outgoing batches per peer, kept across dropped connections
+    outboxes: HashMap<MachineID, Outbox>,
+    /// Recycles the batch buffers of all outboxes
+    buffer_pool: SharedBufferPool,
+    auth_token: Option<Vec<u8>>,
+    negotiated: bool,
+    host: MachineID,
+    host_migration: bool,
+    departed: HashSet<MachineID>,
+    strict_lockstep_timeout: Option<Duration>,
+    lockstep_waiting_since_ms: Option<f64>,
+    lockstep_timeout_reported: bool,
+    /// The turn after which all machines stop advancing, if a pause was requested
+    pause_at: Option<usize>,
+    paused: bool,
+    pacing: Option<PacingController>,
+    batching: Option<BatchingTarget>,
+    desync_detection: Option<DesyncDetection>,
+    recorder: Option<BatchRecorder>,
+    playback: Option<PlaybackNetworking>,
+    link_conditions: Option<LinkConditions>,
+    multicast_groups: HashMap<MachineID, Vec<MachineID>>,
+    reliable_delivery: bool,
+    turn_protocol: Box<dyn TurnProtocol>,
+    /// Never connect to peers or send them messages, see `ActorSystem::fork`
+    isolated: bool,
+    sessions: Sessions,
+    role: MachineRole,
+    /// Message types that spectators may send
+    spectator_messages: HashSet<ShortTypeId>,
+    /// Message types that are delta encoded, see `enable_delta_encoding`
+    delta_messages: HashSet<ShortTypeId>,
+    #[cfg(feature = "webtransport")]
+    datagram_messages: HashSet<ShortTypeId>,
+    /// Which broadcasts peers want to receive
+    interests: Interests,
+    /// Accept connections from thin clients, see `accept_thin_clients`
+    thin_clients: bool,
+    /// Which messages are accepted from peers, if restricted
+    remote_allowlist: Option<Rc<RemoteAllowlist>>,
+    /// Connection state changes, until the `ActorSystem` takes them
+    events: Vec<NetworkingEvent>,
+    /// Reliability state of peers that are currently disconnected
+    detached_links: HashMap<MachineID, ReliableLink>,
+    #[cfg(feature = "server")]
+    listener: Option<TcpListener>,
+    /// Connections to smaller machine IDs that are still being established
+    #[cfg(feature = "server")]
+    dials: HashMap<MachineID, PendingConnection>,
+    /// How many connections to stripe frames to each peer across, see `enable_striping`
+    #[cfg(feature = "server")]
+    stripes: u8,
+    /// The proxy to dial peers through, see `set_proxy`
+    #[cfg(feature = "server")]
+    proxy: Option<Proxy>,
+    #[cfg(feature = "server")]
+    socket_options: SocketOptions,
+    /// Our static private key for encrypted connections, if enabled
+    #[cfg(feature = "encryption")]
+    encryption_key: Option<Vec<u8>>,
+    /// The static public keys peers have to present, see `pin_peer_key`
+    #[cfg(feature = "encryption")]
+    pinned_peer_keys: HashMap<MachineID, Vec<u8>>,
+    #[cfg(feature = "compression")]
+    compression: bool,
+}
+
+/// Reported instead of finishing a turn in strict lockstep mode,
+/// while some peers haven't confirmed the previous turn yet
+#[derive(Clone, Debug)]
+pub struct LockstepWait {
+    /// Connected peers that are still behind the local turn
+    pub stragglers: Vec<MachineID>,
+    /// Have we already been waiting for longer than the configured timeout?
+    pub timed_out: bool,
+}
+
+impl Networking {
+    /// A `Networking` for a system forked with `ActorSystem::fork`, which keeps the machine ID
+    /// and turn of the original, but never connects to peers and drops all messages to them
+    pub(crate) fn isolated(original: &Networking) -> Networking {
+        let mut networking = Self::unbound(
+            original.machine_id.0,
+            vec![String::new(); original.network.len()],
+            original.batch_message_bytes,
+            original.acceptable_turn_distance,
+            original.skip_turns_per_turn_head,
+        );
+        networking.n_turns = original.n_turns;
+        networking.host = original.host;
+        networking.isolated = true;
+        networking
+    }
+
+    /// Start configuring a `Networking` with named, defaulted parameters,
+    /// with `network` containing the addresses of all machines, indexed by machine ID
+    pub fn builder(machine_id: u16, network: Vec<PeerAddress>) -> NetworkingBuilder {
+        NetworkingBuilder::new(machine_id, network)
+    }
+
+    /// Configure a new `Networking`, with `network` containing the addresses
+    /// of all machines (including the local one), indexed by machine ID.
+    /// Returns an error if we can't listen on the local machine's address.
+    pub fn new(
+        machine_id: u16,
+        network: Vec<PeerAddress>,
+        batch_message_bytes: usize,
+        acceptable_turn_distance: usize,
+        skip_turns_per_turn_head: usize,
+    ) -> ::std::io::Result<Networking> {
+        Self::bound(
+            machine_id,
+            network.iter().map(ToString::to_string).collect(),
+            batch_message_bytes,
+            acceptable_turn_distance,
+            skip_turns_per_turn_head,
+        )
+    }
+
+    /// Like `new`, but with addresses as they are sent in handshakes
+    /// (where unknown addresses are empty)
+    fn bound(
+        machine_id: u16,
+        network: Vec<String>,
+        batch_message_bytes: usize,
+        acceptable_turn_distance: usize,
+        skip_turns_per_turn_head: usize,
+    ) -> ::std::io::Result<Networking> {
+        #[allow(unused_mut)]
+        let mut networking = Self::unbound(
+            machine_id,
+            network,
+            batch_message_bytes,
+            acceptable_turn_distance,
+            skip_turns_per_turn_head,
+        );
+
+        #[cfg(feature = "server")]
+        {
+            let listener = TcpListener::bind(&networking.network[machine_id as usize])?;
+            listener.set_nonblocking(true)?;
+            networking.listener = Some(listener);
+        }
+
+        Ok(networking)
+    }
+
+    fn unbound(
+        machine_id: u16,
+        network: Vec<String>,
+        batch_message_bytes: usize,
+        acceptable_turn_distance: usize,
+        skip_turns_per_turn_head: usize,
+    ) -> Networking {
+        assert!(
+            network.len() <= MAX_MACHINES && MachineID(machine_id).is_machine(),
+            "A network can have at most {} machines",
+            MAX_MACHINES
+        );
+        Networking {
+            machine_id: MachineID(machine_id),
+            batch_message_bytes,
+            n_turns: 0,
+            acceptable_turn_distance,
+            skip_turns_per_turn_head,
+            network_connections: (0..network.len()).into_iter().map(|_| None).collect(),
+            outboxes: HashMap::new(),
+            buffer_pool: BufferPool::shared(batch_message_bytes),
+            network,
+            auth_token: None,
+            negotiated: false,
+            host: MachineID(0),
+            host_migration: false,
+            departed: HashSet::new(),
+            strict_lockstep_timeout: None,
+            lockstep_waiting_since_ms: None,
+            lockstep_timeout_reported: false,
+            pause_at: None,
+            paused: false,
+            pacing: None,
+            batching: None,
+            desync_detection: None,
+            recorder: None,
+            playback: None,
+            link_conditions: None,
+            multicast_groups: HashMap::new(),
+            reliable_delivery: false,
+            turn_protocol: Box::new(LockstepTurns),
+            isolated: false,
+            sessions: Sessions::new(),
+            role: MachineRole::Participant,
+            spectator_messages: HashSet::new(),
+            delta_messages: HashSet::new(),
+            #[cfg(feature = "webtransport")]
+            datagram_messages: HashSet::new(),
+            interests: Interests::new(MachineID(machine_id)),
+            thin_clients: false,
+            remote_allowlist: None,
+            events: Vec::new(),
+            detached_links: HashMap::new(),
+            #[cfg(feature = "server")]
+            listener: None,
+            #[cfg(feature = "server")]
+            dials: HashMap::new(),
+            #[cfg(feature = "server")]
+            stripes: 1,
+            #[cfg(feature = "server")]
+            proxy: None,
+            #[cfg(feature = "server")]
+            socket_options: SocketOptions::default(),
+            #[cfg(feature = "encryption")]
+            encryption_key: None,
+            #[cfg(feature = "encryption")]
+            pinned_peer_keys: HashMap::new(),
+            #[cfg(feature = "compression")]
+            compression: false,
+        }
+    }
+
+    /// Number batches and retransmit the ones a peer didn't acknowledge yet
+    /// when it reconnects after a dropped connection, so no enqueued messages are lost.
+    /// Needs to be enabled on all machines of the network.
+    pub fn enable_reliable_delivery(&mut self) {
+        self.reliable_delivery = true;
+    }
+
+    /// Replace the default `LockstepTurns` with a custom turn protocol
+    pub fn set_turn_protocol<P: TurnProtocol + 'static>(&mut self, turn_protocol: P) {
+        self.turn_protocol = Box::new(turn_protocol);
+    }
+
+    /// Encrypt all traffic to peers with a channel negotiated using the Noise protocol
+    /// (XX pattern), for deployments where TLS termination isn't possible.
+    /// Needs to be enabled on all machines. The connection handshake itself
+    /// (including the auth token) still happens before encryption starts.
+    ///
+    /// This uses a random static key, so it doesn't authenticate peers: use
+    /// `enable_encryption_with_key` and `pin_peer_key` to make sure peers are who they claim.
+    #[cfg(feature = "encryption")]
+    pub fn enable_encryption(&mut self) {
+        self.encryption_key = Some(NoiseChannel::generate_keypair().0);
+    }
+
+    /// Like `enable_encryption`, but with a configured static private key,
+    /// whose public key can be pinned by peers
+    #[cfg(feature = "encryption")]
+    pub fn enable_encryption_with_key(&mut self, private_key: Vec<u8>) {
+        self.encryption_key = Some(private_key);
+    }
+
+    /// Only accept encrypted connections from and to the machine with `machine_id`
+    /// if it proves it holds the private key of `public_key`. Once any key is pinned,
+    /// connections to machines without a pinned key are refused.
+    #[cfg(feature = "encryption")]
+    pub fn pin_peer_key(&mut self, machine_id: MachineID, public_key: Vec<u8>) {
+        self.pinned_peer_keys.insert(machine_id, public_key);
+    }
+
+    /// A new static key pair for `enable_encryption_with_key`, `(private key, public key)`
+    #[cfg(feature = "encryption")]
+    pub fn generate_encryption_keypair() -> (Vec<u8>, Vec<u8>) {
+        NoiseChannel::generate_keypair()
+    }
+
+    /// Start the encryption handshake on a new connection to `peer`, if encryption is enabled.
+    /// Returns an error if keys are pinned, but not the one of `peer`
+    #[cfg(feature = "server")]
+    #[allow(unused_variables)]
+    fn start_encryption(&self, connection: &mut Connection, initiator: bool, peer: MachineID) -> Result<(), String> {
+        #[cfg(feature = "encryption")]
+        {
+            if let Some(ref key) = self.encryption_key {
+                let peer_key = self.pinned_peer_keys.get(&peer).cloned();
+                if peer_key.is_none() && !self.pinned_peer_keys.is_empty() {
+                    return Err(format!("No static key is pinned for machine ID {}", peer.0));
+                }
+                connection.noise = Some(
+                    NoiseChannel::new(key, initiator, peer_key).expect("Couldn't set up encryption"),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Open `n_lanes` connections to each peer instead of one and spread the messages
+    /// sent to it across them by recipient actor type, so a single websocket doesn't cap
+    /// the throughput. Messages to one actor type stay in order, and a peer's turn only
+    /// finishes once all of its lanes delivered the turn's messages. Only used with peers
+    /// that enabled it as well, across the smaller number of lanes of both, and not on
+    /// encrypted, compressed or reliable connections. Needs to be set before connecting.
+    #[cfg(feature = "server")]
+    pub fn enable_striping(&mut self, n_lanes: u8) {
+        assert!(n_lanes > 0, "Striping needs at least one lane");
+        self.stripes = n_lanes;
+    }
+
+    /// Dial all peers through an HTTP `CONNECT` or SOCKS5 proxy, for networks that only
+    /// allow outbound connections through one. Connections from peers are still accepted
+    /// directly. Needs to be set before connecting.
+    #[cfg(feature = "server")]
+    pub fn set_proxy(&mut self, proxy: Proxy) {
+        self.proxy = Some(proxy);
+    }
+
+    /// Tune the TCP sockets of connections to peers, like enabling keepalive probes and
+    /// larger buffers across a WAN. Applies to connections established afterwards.
+    #[cfg(feature = "server")]
+    pub fn set_socket_options(&mut self, options: SocketOptions) {
+        self.socket_options = options;
+    }
+
+    /// The number of lanes we offer to stripe across, only one with encryption
+    /// or reliable delivery, which need the frames of a connection in order
+    #[cfg(feature = "server")]
+    fn lane_stripes(&self) -> u8 {
+        #[cfg(feature = "encryption")]
+        {
+            if self.encryption_key.is_some() {
+                return 1;
+            }
+        }
+        if self.reliable_delivery {
+            1
+        } else {
+            self.stripes
+        }
+    }
+
+    /// Stripe the frames of a new connection, if both sides enabled striping
+    /// and the connection isn't compressed.
+    /// The side that dialed the connection dials the extra lanes at `address`.
+    #[cfg(feature = "server")]
+    fn start_striping(&self, connection: &mut Connection, peer_stripes: u8, address: Option<&str>) {
+        let n_lanes = self.lane_stripes().min(peer_stripes);
+        #[cfg(feature = "compression")]
+        let n_lanes = if connection.compression.is_some() { 1 } else { n_lanes };
+        if n_lanes > 1 {
+            connection.striping = Some(Striping::new(n_lanes as usize, address, self.proxy.as_ref()));
+        }
+    }
+
+    /// Compress all frames sent to peers with zstd dictionaries, trained on the messages
+    /// sent to each peer and renewed regularly. Only used on connections to peers that
+    /// enabled it as well (negotiated in the connection handshake), so it can be
+    /// rolled out gradually.
+    #[cfg(feature = "compression")]
+    pub fn enable_compression(&mut self) {
+        self.compression = true;
+    }
+
+    /// The bytes sent per byte of batches so far on each compressed connection
+    #[cfg(feature = "compression")]
+    pub fn compression_ratios(&self) -> HashMap<MachineID, f64> {
+        self.network_connections
+            .iter()
+            .enumerate()
+            .filter_map(|(machine_id, maybe_connection)| {
+                let connection = maybe_connection.as_ref()?;
+                let compression = connection.compression.as_ref()?;
+                Some((MachineID(machine_id as u16), compression.ratio()))
+            })
+            .collect()
+    }
+
+    /// Compress the frames of a new connection, if both sides enabled compression
+    #[cfg(feature = "server")]
+    #[allow(unused_variables)]
+    fn start_compression(&self, connection: &mut Connection, peer_compresses: bool) {
+        #[cfg(feature = "compression")]
+        {
+            if self.compression && peer_compresses {
+                connection.compression = Some(CompressionChannel::new());
+            }
+        }
+    }
+
+    /// Simulate adverse network conditions for all batches sent to peers from now on.
+    /// Only meant for testing, for example how turn backpressure copes with a bad network.
+    pub fn simulate_link_conditions(&mut self, conditions: LinkConditions) {
+        self.link_conditions = Some(conditions);
+    }
+
+    /// Record every batch received from peers to `writer`,
+    /// to be replayed later using `PlaybackNetworking`
+    pub fn start_recording<W: ::std::io::Write + 'static>(
+        &mut self,
+        writer: W,
+    ) -> ::std::io::Result<()> {
+        self.recorder = Some(BatchRecorder::new(Box::new(writer))?);
+        Ok(())
+    }
+
+    /// Adapt the duration of a local turn to the round trip time to and the progress of
+    /// the slowest peer, never going below `base_tick`. The host should use
+    /// `target_tick_duration` to pace its turns.
+    pub fn enable_adaptive_pacing(&mut self, base_tick: Duration) {
+        self.pacing = Some(PacingController::new(base_tick));
+    }
+
+    /// Adapt the size of the batches sent to each peer to the bytes of messages recently
+    /// sent to it per turn, so they are sent in about `frames_per_turn` frames.
+    /// Batches stay between `min_batch_bytes` and `batch_message_bytes`, which still limits
+    /// the size of single messages.
+    pub fn enable_adaptive_batching(&mut self, min_batch_bytes: usize, frames_per_turn: usize) {
+        assert!(frames_per_turn > 0, "Need to send at least one frame per turn");
+        let min_batch_bytes = min_batch_bytes.min(self.batch_message_bytes);
+        self.buffer_pool.borrow_mut().set_min_buffer_bytes(min_batch_bytes);
+        self.batching = Some(BatchingTarget {
+            min_batch_bytes,
+            max_batch_bytes: self.batch_message_bytes,
+            frames_per_turn,
+        });
+    }
+
+    /// The size batches to each peer are currently filled up to, see `enable_adaptive_batching`
+    pub fn batch_sizes(&self) -> HashMap<MachineID, usize> {
+        self.outboxes
+            .iter()
+            .map(|(machine_id, outbox)| (*machine_id, outbox.batch_bytes()))
+            .collect()
+    }
+
+    /// Send a hash of the actor state with every finished turn and compare it with
+    /// the hashes of peers, to detect that their simulations diverged.
+    /// All machines of a network need to enable it.
+    pub fn enable_desync_detection(&mut self) {
+        self.desync_detection.get_or_insert_with(DesyncDetection::new);
+    }
+
+    /// Does the `ActorSystem` need to hash its state before finishing a turn?
+    pub(crate) fn wants_state_hash(&self) -> bool {
+        self.desync_detection.is_some()
+    }
+
+    pub(crate) fn set_state_hash(&mut self, state_hash: u64) {
+        if let Some(ref mut detection) = self.desync_detection {
+            detection.set_state_hash(state_hash);
+        }
+    }
+
+    fn detect_desyncs(&mut self) {
+        let detection = match self.desync_detection {
+            Some(ref detection) => detection,
+            None => return,
+        };
+
+        let mut desyncs = Vec::new();
+        for (machine_id, maybe_connection) in self.network_connections.iter_mut().enumerate() {
+            if let Some(ref mut connection) = *maybe_connection {
+                let peer = &mut connection.peer;
+                if let Some(n_turns) = detection.compare(&mut peer.state_hashes) {
+                    if !peer.desynced {
+                        peer.desynced = true;
+                        desyncs.push(NetworkingEvent::Desync(n_turns, MachineID(machine_id as u16)));
+                    }
+                }
+            }
+        }
+
+        for desync in desyncs {
+            self.emit(desync);
+        }
+    }
+
+    /// The duration a local turn should take, if adaptive pacing is enabled
+    pub fn target_tick_duration(&self) -> Option<Duration> {
+        self.pacing.as_ref().map(PacingController::target_tick)
+    }
+
+    /// Estimate the current time in milliseconds since the Unix epoch,
+    /// according to the clock of the host, which all peers use as the shared time.
+    /// Falls back to the local clock until the offset to the host has been measured.
+    pub fn estimated_shared_time(&self) -> f64 {
+        let offset_ms = self
+            .network_connections
+            .get(self.host.0 as usize)
+            .and_then(Option::as_ref)
+            .and_then(|connection| connection.peer.clock.offset_ms())
+            .unwrap_or(0.0);
+        now_ms() + offset_ms
+    }
+
+    /// How well batch buffers of outgoing messages are recycled
+    pub fn buffer_pool_stats(&self) -> BufferPoolStats {
+        self.buffer_pool.borrow().stats()
+    }
+
+    /// Clock offset and drift statistics for each connected peer that was measured so far
+    pub fn clock_stats(&self) -> HashMap<MachineID, ClockStats> {
+        self.network_connections
+            .iter()
+            .enumerate()
+            .filter_map(|(i, maybe_connection)| {
+                maybe_connection
+                    .as_ref()
+                    .and_then(|connection| connection.peer.clock.stats())
+                    .map(|stats| (MachineID(i as u16), stats))
+            })
+            .collect()
+    }
+
+    /// The smoothed round trip time to each connected peer that was measured so far
+    pub fn round_trip_times(&self) -> HashMap<MachineID, Duration> {
+        self.network_connections
+            .iter()
+            .enumerate()
+            .filter_map(|(i, maybe_connection)| {
+                maybe_connection
+                    .as_ref()
+                    .and_then(|connection| connection.peer.rtt_ms)
+                    .map(|rtt_ms| {
+                        (
+                            MachineID(i as u16),
+                            Duration::from_micros((rtt_ms * 1000.0) as u64),
+                        )
+                    })
+            })
+            .collect()
+    }
+
+    /// Only finish a turn once all connected peers have confirmed the previous turn,
+    /// instead of letting them drift apart up to `acceptable_turn_distance`.
+    /// Waiting for longer than `timeout` is reported by `ActorSystem::networking_try_finish_turn`
+    /// and as a `SystemEvent::LockstepTimedOut`, so stragglers can be dealt with.
+    pub fn enable_strict_lockstep(&mut self, timeout: Duration) {
+        self.strict_lockstep_timeout = Some(timeout);
+    }
+
+    /// When a peer disconnects, consider it departed instead of trying to reconnect to it.
+    /// If the departed peer was the host, the lowest surviving machine ID becomes the new host,
+    /// taking over assigning machine IDs to joining peers in a negotiated network.
+    pub fn enable_host_migration(&mut self) {
+        self.host_migration = true;
+    }
+
+    pub(crate) fn define_multicast_group(&mut self, group: u8, members: Vec<MachineID>) {
+        self.multicast_groups
+            .insert(MachineID::multicast_group(group), members);
+    }
+
+    pub(crate) fn is_multicast_group_member(&self, group_machine_id: MachineID) -> bool {
+        self.multicast_groups
+            .get(&group_machine_id)
+            .map(|members| members.contains(&self.machine_id))
+            .unwrap_or(false)
+    }
+
+    /// Take part in the network as a spectator (or participant again).
+    /// Needs to be set before connecting, since the role is announced in the handshake.
+    pub fn set_role(&mut self, role: MachineRole) {
+        self.role = role;
+    }
+
+    /// The role of the local machine in the network
+    pub fn role(&self) -> MachineRole {
+        self.role
+    }
+
+    /// Accept connections from thin clients, like bots written in other languages using
+    /// the protocol described by `ActorSystem::client_protocol`. Thin clients send a relaxed
+    /// handshake without a listening address, get the next free machine ID assigned and are
+    /// treated like spectators: they never hold back lockstep and may only send message
+    /// types allowed with `ActorSystem::allow_from_spectators`. Their connections are never
+    /// encrypted and don't use reliable delivery, even if enabled for the network.
+    pub fn accept_thin_clients(&mut self) {
+        self.thin_clients = true;
+    }
+
+    /// The connection state changes since this was last called
+    pub(crate) fn take_events(&mut self) -> Vec<NetworkingEvent> {
+        self.events.drain(..).collect()
+    }
+
+    fn emit(&mut self, event: NetworkingEvent) {
+        self.events.push(event);
+    }
+
+    /// Allow spectators to send messages of this type (needs to be done before connecting)
+    pub(crate) fn allow_from_spectators(&mut self, message_type_id: ShortTypeId) {
+        self.spectator_messages.insert(message_type_id);
+    }
+
+    /// Send messages of this type to peers as the bytes that changed compared to the last one
+    /// from the same sender class to the same recipient, if that is smaller.
+    /// Meant for actors that send their whole state every turn, of which little changes.
+    /// Peers reconstruct the messages before dispatching them, so handlers don't notice.
+    /// A message whose predecessor was lost (on reconnects) is dropped, until the full message
+    /// is sent again after a number of turns. Messages of these types can't be cancelled
+    /// once they are enqueued for peers (see `ActorSystem::send_cancellable`).
+    pub(crate) fn enable_delta_encoding(&mut self, message_type_id: ShortTypeId) {
+        self.delta_messages.insert(message_type_id);
+    }
+
+    /// Send messages of this type to peers connected over WebTransport (see `Networking::connect`)
+    /// as unreliable datagrams instead of on the stream, for lower latency. Meant for messages
+    /// that are sent again every turn and don't affect the simulation, like cursor positions:
+    /// they can get lost, arrive out of order and aren't tied to the turn they were sent in.
+    /// Messages too large for a datagram, and messages to websocket peers, are sent as usual.
+    #[cfg(feature = "webtransport")]
+    pub(crate) fn send_as_datagrams(&mut self, message_type_id: ShortTypeId) {
+        self.datagram_messages.insert(message_type_id);
+    }
+
+    /// The interests of this machine and its peers, which filter the peers
+    /// broadcasts and multicasts are sent to
+    pub(crate) fn interests(&mut self) -> &mut Interests {
+        &mut self.interests
+    }
+
+    /// Accept messages of this type to this recipient type from peers,
+    /// rejecting all pairs that weren't allowed from then on
+    pub(crate) fn allow_remote(
+        &mut self,
+        system_services: ShortTypeId,
+        message_type_id: ShortTypeId,
+        recipient_type_id: ShortTypeId,
+    ) {
+        Rc::make_mut(
+            self.remote_allowlist
+                .get_or_insert_with(|| Rc::new(RemoteAllowlist::new(system_services))),
+        )
+        .allow(message_type_id, recipient_type_id);
+    }
+
+    /// All peers we are currently connected to
+    pub(crate) fn connected_machines(&self) -> Vec<MachineID> {
+        self.network_connections
+            .iter()
+            .enumerate()
+            .filter(|(_, maybe_connection)| maybe_connection.is_some())
+            .map(|(machine_id, _)| MachineID(machine_id as u16))
+            .filter(|machine_id| *machine_id != self.machine_id)
+            .collect()
+    }
+
+    /// The machine currently acting as host (initially machine ID 0)
+    pub fn host(&self) -> MachineID {
+        self.host
+    }
+
+    /// Ask all machines to stop advancing turns, all at the same turn number,
+    /// which the host decides on and announces to all machines.
+    /// `ActorSystem::networking_finish_turn` then doesn't finish turns anymore
+    /// until `request_resume` is called on any machine.
+    pub fn request_pause(&mut self) {
+        if self.host == self.machine_id {
+            self.decide_pause();
+        } else {
+            let host = self.host;
+            let batch_message_bytes = self.batch_message_bytes;
+            let buffer_pool = self.buffer_pool.clone();
+            self.outboxes
+                .entry(host)
+                .or_insert_with(|| Outbox::new(batch_message_bytes, buffer_pool.clone()))
+                .write_control(&ControlFrame::PauseRequest);
+        }
+    }
+
+    /// As the host, decide the turn all machines pause at and announce it
+    fn decide_pause(&mut self) {
+        let furthest_n_turns = self
+            .network_connections
+            .iter()
+            .filter_map(Option::as_ref)
+            .map(|connection| connection.peer.n_turns)
+            .fold(self.n_turns, usize::max);
+        let at_turn = furthest_n_turns + PAUSE_MARGIN_TURNS;
+        self.broadcast_control(&ControlFrame::Pause {
+            at_turn: at_turn as u32,
+        });
+        self.pause_requested(at_turn);
+    }
+
+    /// Ask all machines to continue advancing turns after a pause
+    pub fn request_resume(&mut self) {
+        self.broadcast_control(&ControlFrame::Resume);
+        self.resume_requested();
+    }
+
+    /// Did all machines stop advancing turns because of a requested pause?
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    fn broadcast_control(&mut self, frame: &ControlFrame) {
+        let batch_message_bytes = self.batch_message_bytes;
+        let buffer_pool = self.buffer_pool.clone();
+        for machine_id in 0..self.network.len() {
+            if machine_id != self.machine_id.0 as usize {
+                self.outboxes
+                    .entry(MachineID(machine_id as u16))
+                    .or_insert_with(|| Outbox::new(batch_message_bytes, buffer_pool.clone()))
+                    .write_control(frame);
+            }
+        }
+    }
+
+    fn pause_requested(&mut self, at_turn: usize) {
+        if at_turn < self.n_turns {
+            println!(
+                "Pause for turn {} requested too late, pausing at turn {}",
+                at_turn, self.n_turns
+            );
+        }
+        // concurrent requests agree on the earliest turn
+        self.pause_at = Some(self.pause_at.map_or(at_turn, |earlier| earlier.min(at_turn)));
+    }
+
+    fn resume_requested(&mut self) {
+        self.pause_at = None;
+        if self.paused {
+            self.paused = false;
+            let n_turns = self.n_turns;
+            self.emit(NetworkingEvent::Resumed(n_turns));
+        }
+    }
+
+    fn peer_lost(&mut self, machine_id: MachineID, role: MachineRole) {
+        self.emit(NetworkingEvent::Disconnected(machine_id));
+
+        if role == MachineRole::Spectator {
+            // spectators come and go, nothing to deliver later
+            self.outboxes.remove(&machine_id);
+            self.detached_links.remove(&machine_id);
+            return;
+        }
+
+        if !self.host_migration {
+            return;
+        }
+
+        self.departed.insert(machine_id);
+        self.outboxes.remove(&machine_id);
+        self.detached_links.remove(&machine_id);
+        self.sessions.forget(machine_id);
+
+        if machine_id == self.host {
+            // every surviving peer is connected to all others,
+            // so they all deterministically elect the same new host
+            let own_machine = self.machine_id.0 as usize;
+            let own_role = self.role;
+            let connections = &self.network_connections;
+            self.host = (0..connections.len())
+                .filter(|&i| match connections[i] {
+                    _ if i == own_machine => own_role == MachineRole::Participant,
+                    Some(ref connection) => connection.peer.is_participant(),
+                    None => false,
+                })
+                .map(|i| MachineID(i as u16))
+                .min()
+                .unwrap_or(self.machine_id);
+            println!(
+                "Host Machine ID {} departed, Machine ID {} is the new host",
+                machine_id.0, self.host.0
+            );
+        }
+    }
+
+    /// Tell all connected peers that we leave, send them everything still in their outboxes
+    /// (waiting at most `timeout` for that) and close the connections.
+    /// Returns the peers that not everything could be sent to.
+    pub(crate) fn shutdown(
+        &mut self,
+        classes: &mut [Option<Class>],
+        implementors: &mut [Option<Vec<ShortTypeId>>],
+        timeout: Duration,
+    ) -> Vec<MachineID> {
+        let batch_message_bytes = self.batch_message_bytes;
+        let buffer_pool = self.buffer_pool.clone();
+        for machine_id in self.connected_machines() {
+            self.outboxes
+                .entry(machine_id)
+                .or_insert_with(|| Outbox::new(batch_message_bytes, buffer_pool.clone()))
+                .write_control(&ControlFrame::Leave);
+        }
+
+        let deadline_ms = now_ms() + duration_ms(timeout);
+        let mut unsent = loop {
+            self.send_and_receive(classes, implementors);
+            let outboxes = &self.outboxes;
+            let unsent = self
+                .connected_machines()
+                .into_iter()
+                .filter(|machine_id| outboxes.get(machine_id).map_or(false, |outbox| !outbox.is_empty()))
+                .collect::<Vec<_>>();
+            if unsent.is_empty() || now_ms() >= deadline_ms {
+                break unsent;
+            }
+            #[cfg(feature = "server")]
+            ::std::thread::sleep(Duration::from_millis(1));
+            // browser websockets only send once we return to the event loop
+            #[cfg(not(feature = "server"))]
+            break unsent;
+        };
+
+        for maybe_connection in self.network_connections.iter_mut() {
+            if let Some(mut connection) = maybe_connection.take() {
+                connection.close();
+            }
+        }
+        for (machine_id, outbox) in &self.outboxes {
+            if !outbox.is_empty() && !unsent.contains(machine_id) {
+                unsent.push(*machine_id);
+            }
+        }
+        unsent.sort();
+        unsent
+    }
+
+    /// Require every peer to present this pre-shared token in the connection handshake.
+    /// Connections with a missing or wrong token are rejected. All peers
+    /// need to be configured with the same token, since it is also presented to them.
+    pub fn set_auth_token(&mut self, token: Vec<u8>) {
+        self.auth_token = Some(token);
+    }
+
+    /// Configure a new `Networking` that coordinates a negotiated network as machine ID 0.
+    /// Peers don't need to know the full network, they can join it
+    /// with `Networking::join`, only knowing the coordinator's `address`.
+    /// Returns an error if we can't listen on `address`.
+    #[cfg(feature = "server")]
+    pub fn coordinate(
+        address: PeerAddress,
+        batch_message_bytes: usize,
+        acceptable_turn_distance: usize,
+        skip_turns_per_turn_head: usize,
+    ) -> ::std::io::Result<Networking> {
+        let mut networking = Networking::new(
+            0,
+            vec![address],
+            batch_message_bytes,
+            acceptable_turn_distance,
+            skip_turns_per_turn_head,
+        )?;
+        networking.negotiated = true;
+        Ok(networking)
+    }
+
+    /// Join a negotiated network by contacting its coordinator, which assigns
+    /// a machine ID and sends the table of all peers known so far.
+    /// `own_address` is where this machine will accept connections from later peers.
+    ///
+    /// Blocks until the coordinator replied. If `proxy` is given, the coordinator
+    /// and all peers are dialed through it (see `set_proxy`).
+    /// Returns an error if the coordinator can't be reached or rejects us,
+    /// or if we can't listen on `own_address`.
+    #[cfg(feature = "server")]
+    pub fn join(
+        coordinator_address: &PeerAddress,
+        own_address: PeerAddress,
+        auth_token: Option<Vec<u8>>,
+        proxy: Option<Proxy>,
+        batch_message_bytes: usize,
+        acceptable_turn_distance: usize,
+        skip_turns_per_turn_head: usize,
+    ) -> Result<Networking, String> {
+        let mut websocket = open_websocket(&coordinator_address.to_string(), proxy.as_ref())
+            .map_err(|e| format!("Couldn't reach coordinator: {}", e))?;
+        let mut request = Handshake::new(broadcast_machine_id(), &auth_token);
+        request.listen_address = Some(own_address.to_string());
+
+        let reply = client_handshake(&mut websocket, &request, &auth_token)
+            .map_err(|reason| format!("Rejected by coordinator: {}", reason))?;
+
+        let (machine_id, network) = match (reply.assigned_machine_id, reply.peer_table) {
+            (Some(machine_id), Some(network)) => (machine_id, network),
+            _ => return Err("Coordinator didn't assign a machine ID".to_owned()),
+        };
+        if !machine_id.is_machine() || network.len() > MAX_MACHINES {
+            return Err(format!("Coordinator assigned reserved Machine ID {}", machine_id.0));
+        }
+        println!("Coordinator assigned Machine ID {}", machine_id.0);
+
+        let mut networking = Networking::bound(
+            machine_id.0,
+            network,
+            batch_message_bytes,
+            acceptable_turn_distance,
+            skip_turns_per_turn_head,
+        )
+        .map_err(|e| format!("Couldn't listen on {}: {}", own_address, e))?;
+        networking.negotiated = true;
+        networking.auth_token = auth_token;
+        networking.proxy = proxy;
+        let mut connection = Connection::new(websocket, &networking.socket_options);
+        connection.peer.role = reply.role;
+        connection.session_token = reply.session_token;
+        networking
+            .start_encryption(&mut connection, true, reply.machine_id)
+            .map_err(|e| format!("Refused connection to coordinator: {}", e))?;
+        networking.start_compression(&mut connection, reply.compression);
+        networking.attach_connection(reply.machine_id, connection);
+        Ok(networking)
+    }
+
+    /// Are we responsible for assigning machine IDs to joining peers?
+    #[cfg(feature = "server")]
+    fn assigns_machine_ids(&self) -> bool {
+        self.negotiated && self.machine_id == self.host
+    }
+
+    /// Could a peer of a negotiated network that claims `machine_id` have joined after
+    /// we got our peer table? The coordinator assigns machine IDs in order, so it can't be
+    /// far beyond the ones we know, and it can't be one that is connected already
+    #[cfg(feature = "server")]
+    fn may_be_new_peer(&self, machine_id: MachineID) -> bool {
+        let index = machine_id.0 as usize;
+        machine_id != self.machine_id
+            && machine_id.is_machine()
+            && index < self.network.len() + MAX_MACHINE_IDS_JOINED_SINCE
+            && self.network_connections.get(index).map_or(true, Option::is_none)
+    }
+
+    /// Make room for a peer that wasn't known in our network yet
+    #[cfg(feature = "server")]
+    fn learn_peer(&mut self, machine_id: MachineID, address: Option<String>) {
+        let index = machine_id.0 as usize;
+        if index >= self.network.len() {
+            self.network.resize(index + 1, String::new());
+            self.network_connections.resize_with(index + 1, || None);
+        }
+        if let Some(address) = address {
+            self.network[index] = address;
+        }
+    }
+
+    fn own_handshake(&self) -> Handshake {
+        let mut handshake = Handshake::new(self.machine_id, &self.auth_token);
+        handshake.role = self.role;
+        #[cfg(feature = "compression")]
+        {
+            handshake.compression = self.compression;
+        }
+        #[cfg(feature = "server")]
+        {
+            handshake.stripes = self.lane_stripes();
+        }
+        if self.negotiated {
+            handshake.listen_address = Some(self.network[self.machine_id.0 as usize].clone());
+        }
+        handshake
+    }
+
+    /// Our handshake for connecting to `peer`, presenting the session token
+    /// it issued to us earlier, if any
+    fn own_handshake_for(&self, peer: MachineID) -> Handshake {
+        let mut handshake = self.own_handshake();
+        handshake.session_token = self.sessions.presented_to(peer);
+        handshake
+    }
+
+    /// Restore the lockstep state of a peer that resumed its session after a transient
+    /// disconnect, or forget it if the peer connected as a new session
+    fn resume_session(&mut self, machine_id: MachineID, resumed: bool, connection: &mut Connection) {
+        if let Some(n_turns) = self.sessions.take_lost_turns(machine_id) {
+            if resumed {
+                connection.peer.n_turns = n_turns;
+                println!("Machine ID {} resumed its session", machine_id.0);
+            }
+        }
+    }
+
+    #[cfg(feature = "server")]
+    pub(crate) fn connect(&mut self) {
+        // first wait for a larger machine_id to connect
+        // (in a negotiated network, new peers can always join)
+        if self.negotiated
+            || self.thin_clients
+            || self
+                .network_connections
+                .iter()
+                .filter_map(Option::as_ref)
+                .any(Connection::expects_lanes)
+            || self
+                .network_connections
+                .iter()
+                .enumerate()
+                .any(|(machine_id, connection)| {
+                    machine_id > self.machine_id.0 as usize && connection.is_none()
+                })
+        {
+            match self.listener.as_ref().map(TcpListener::accept) {
+                Some(Ok((stream, addr))) => {
+                    let mut handshake_state = Some(websocket_accept(stream));
+                    loop {
+                        handshake_state = match handshake_state {
+                            Some(Ok(mut websocket)) => {
+                                loop {
+                                    match websocket.read_message() {
+                                        Ok(WebSocketMessage::Binary(data)) => {
+                                            self.accept_peer(websocket, &data, addr);
+                                            break;
+                                        }
+                                        Ok(_) => {}
+                                        Err(e) => {
+                                            if let Some(real_err) = e.into_non_blocking() {
+                                                println!(
+                                                    "Error while expecting first message: {}",
+                                                    real_err
+                                                );
+                                                break;
+                                            }
+                                        }
+                                    }
+                                }
+                                break;
+                            }
+                            Some(Err(HandshakeError::Interrupted(s))) => Some(s.handshake()),
+                            Some(Err(HandshakeError::Failure(e))) => {
+                                println!("Error while accepting connection: {}", e);
+                                break;
+                            }
+                            None => break,
+                        }
+                    }
+                }
+                Some(Err(ref e)) if e.kind() == ::std::io::ErrorKind::WouldBlock => {}
+                Some(Err(e)) => println!("Error while accepting connection: {}", e),
+                None => {}
+            }
+        }
+
+        // then keep dialing all smaller machine_ids, without blocking
+        for machine_id in 0..(self.machine_id.0 as usize).min(self.network.len()) {
+            let peer = MachineID(machine_id as u16);
+            if self.network_connections[machine_id].is_none()
+                && !self.network[machine_id].is_empty()
+                && !self.departed.contains(&peer)
+            {
+                let handshake = self.own_handshake_for(peer);
+                let address = &self.network[machine_id];
+                let proxy = self.proxy.as_ref();
+                let outcome = self
+                    .dials
+                    .entry(peer)
+                    .or_insert_with(|| PendingConnection::start(address, proxy))
+                    .poll(&handshake, &self.auth_token);
+
+                if let DialOutcome::Established(websocket, reply) = outcome {
+                    self.dials.remove(&peer);
+                    let mut connection = Connection::new(websocket, &self.socket_options);
+                    connection.peer.role = reply.role;
+                    let resumed = Sessions::was_resumed(&handshake.session_token, &reply.session_token);
+                    connection.session_token = reply.session_token;
+                    if let Err(e) = self.start_encryption(&mut connection, true, peer) {
+                        println!("Refused connection to Machine ID {}: {}", machine_id, e);
+                        connection.close();
+                        continue;
+                    }
+                    self.resume_session(peer, resumed, &mut connection);
+                    self.start_compression(&mut connection, reply.compression);
+                    self.start_striping(&mut connection, reply.stripes, Some(&self.network[machine_id]));
+                    self.attach_connection(peer, connection);
+                }
+            } else {
+                self.dials.remove(&peer);
+            }
+        }
+
+        // and the extra lanes of striped connections we dialed
+        if self.stripes > 1 {
+            let handshake = self.own_handshake();
+            for connection in self.network_connections.iter_mut().filter_map(Option::as_mut) {
+                connection.dial_lanes(&handshake, &self.auth_token, &self.socket_options);
+            }
+        }
+    }
+
+    /// Verify the handshake of a peer that connected to us, reply with our own
+    /// and remember the connection (assigning a machine ID first, if requested)
+    #[cfg(feature = "server")]
+    fn accept_peer(
+        &mut self,
+        mut websocket: WebSocket<TcpStream>,
+        data: &[u8],
+        addr: ::std::net::SocketAddr,
+    ) {
+        let peer_handshake = match Handshake::decode(data).and_then(|handshake| {
+            handshake.verify(&self.auth_token)?;
+            Ok(handshake)
+        }) {
+            Ok(handshake) => handshake,
+            Err(reason) => {
+                println!("Rejected connection from {}: {}", addr, reason);
+                let _ = websocket.close(None);
+                return;
+            }
+        };
+
+        if let Some(lane) = peer_handshake.stripe_lane {
+            self.accept_lane(websocket, &peer_handshake, lane as usize, addr);
+            return;
+        }
+
+        let mut reply = self.own_handshake();
+        let resumed_machine_id = peer_handshake
+            .session_token
+            .as_ref()
+            .and_then(|token| self.sessions.resume(token));
+
+        let peer_machine_id = if peer_handshake.thin_client {
+            if !self.thin_clients {
+                println!("Rejected connection from {}: thin clients aren't accepted", addr);
+                let _ = websocket.close(None);
+                return;
+            }
+            let assigned = match MachineID::of_machine(self.network.len()) {
+                Some(assigned) => assigned,
+                None => {
+                    println!("Rejected connection from {}: the network is full", addr);
+                    let _ = websocket.close(None);
+                    return;
+                }
+            };
+            self.learn_peer(assigned, None);
+            reply.assigned_machine_id = Some(assigned);
+            assigned
+        } else if peer_handshake.machine_id == broadcast_machine_id() {
+            match peer_handshake.listen_address {
+                Some(address) if resumed_machine_id.is_some() && self.negotiated => {
+                    // a joining peer that lost its connection gets its old machine ID back
+                    let resumed = resumed_machine_id.unwrap();
+                    self.learn_peer(resumed, Some(address));
+                    reply.assigned_machine_id = Some(resumed);
+                    reply.peer_table = Some(self.network.clone());
+                    resumed
+                }
+                Some(address) if self.assigns_machine_ids() && self.network.len() < MAX_MACHINES => {
+                    let assigned = MachineID(self.network.len() as u16);
+                    self.learn_peer(assigned, Some(address));
+                    reply.assigned_machine_id = Some(assigned);
+                    reply.peer_table = Some(self.network.clone());
+                    assigned
+                }
+                _ => {
+                    println!(
+                        "Rejected connection from {}: can't assign a machine ID",
+                        addr
+                    );
+                    let _ = websocket.close(None);
+                    return;
+                }
+            }
+        } else if self.negotiated {
+            if !self.may_be_new_peer(peer_handshake.machine_id) {
+                println!(
+                    "Rejected connection from {}: implausible machine ID {}",
+                    addr, peer_handshake.machine_id.0
+                );
+                let _ = websocket.close(None);
+                return;
+            }
+            self.learn_peer(peer_handshake.machine_id, peer_handshake.listen_address);
+            peer_handshake.machine_id
+        } else if (peer_handshake.machine_id.0 as usize) < self.network.len() {
+            peer_handshake.machine_id
+        } else {
+            println!(
+                "Rejected connection from {}: unknown machine ID {}",
+                addr, peer_handshake.machine_id.0
+            );
+            let _ = websocket.close(None);
+            return;
+        };
+
+        let resumed = resumed_machine_id == Some(peer_machine_id);
+        reply.session_token = Some(self.sessions.token_for_peer(peer_machine_id, resumed));
+
+        if let Err(e) = websocket
+            .write_message(WebSocketMessage::binary(reply.encode()))
+            .and_then(|_| websocket.write_pending())
+        {
+            println!("Error while replying to handshake: {}", e);
+            return;
+        }
+
+        self.departed.remove(&peer_machine_id);
+        let mut connection = Connection::new(websocket, &self.socket_options);
+        if peer_handshake.thin_client {
+            connection.peer.role = MachineRole::Spectator;
+            connection.peer.thin_client = true;
+        } else {
+            connection.peer.role = peer_handshake.role;
+            if let Err(e) = self.start_encryption(&mut connection, false, peer_machine_id) {
+                println!("Refused connection from {}: {}", addr, e);
+                connection.close();
+                return;
+            }
+            self.start_compression(&mut connection, peer_handshake.compression);
+            self.start_striping(&mut connection, peer_handshake.stripes, None);
+        }
+        self.resume_session(peer_machine_id, resumed, &mut connection);
+        self.attach_connection(peer_machine_id, connection);
+    }
+
+    /// Use a connection a peer opened as an extra lane of its striped connection,
+    /// if it presents the session token we issued on the main connection
+    #[cfg(feature = "server")]
+    fn accept_lane(
+        &mut self,
+        mut websocket: WebSocket<TcpStream>,
+        peer_handshake: &Handshake,
+        lane: usize,
+        addr: ::std::net::SocketAddr,
+    ) {
+        let machine_id = peer_handshake.machine_id;
+        let issued_to = peer_handshake
+            .session_token
+            .as_ref()
+            .and_then(|token| self.sessions.resume(token));
+        let reply = self.own_handshake();
+        let striping = self
+            .network_connections
+            .get_mut(machine_id.0 as usize)
+            .and_then(Option::as_mut)
+            .and_then(|connection| connection.striping.as_mut())
+            .filter(|striping| issued_to == Some(machine_id) && striping.expects_lane(lane));
+
+        match striping {
+            Some(striping) => {
+                if let Err(e) = websocket
+                    .write_message(WebSocketMessage::binary(reply.encode()))
+                    .and_then(|_| websocket.write_pending())
+                {
+                    println!("Error while replying to handshake: {}", e);
+                    return;
+                }
+                set_up_socket(&mut websocket, &self.socket_options);
+                striping.open(lane, websocket);
+                println!("...lane {} of machine ID {} connected!", lane, machine_id.0);
+            }
+            None => {
+                println!(
+                    "Rejected connection from {}: unexpected lane {} of machine ID {}",
+                    addr, lane, machine_id.0
+                );
+                let _ = websocket.close(None);
+            }
+        }
+    }
+
+    /// Connect to the peers we aren't connected to yet, over WebTransport for peer addresses like
+    /// `webtransport://host:port/path` (with the `webtransport` feature) and over websockets otherwise.
+    /// WebTransport peers need to serve an HTTP/3 endpoint there, which accepts a bidirectional stream
+    /// carrying the websocket frames, each prefixed with `[length: u32]` (kay's `server` side doesn't
+    /// provide one yet, so this needs a gateway), plus datagrams of single message batches.
+    #[cfg(feature = "browser")]
+    pub fn connect(&mut self) {
+        for machine_id in 0..self.network.len() {
+            if machine_id != self.machine_id.0 as usize
+                && !self.network[machine_id].is_empty()
+                && !self.departed.contains(&MachineID(machine_id as u16))
+            {
+                if self.network_connections[machine_id].is_none() {
+                    let peer = MachineID(machine_id as u16);
+                    let handshake = self.own_handshake_for(peer);
+                    let mut connection = self.open_connection(machine_id, handshake.encode());
+                    // resumed once the peer's reply confirms the token we presented
+                    if let Some(presented) = handshake.session_token {
+                        if let Some(n_turns) = self.sessions.take_lost_turns(peer) {
+                            connection.resumable = Some((presented, n_turns));
+                        }
+                    }
+                    self.attach_connection(peer, connection);
+                }
+            }
+        }
+    }
+
+    /// Open a connection to the peer at `network[machine_id]`, over WebTransport
+    /// for `webtransport://` addresses and over a websocket otherwise
+    #[cfg(feature = "browser")]
+    fn open_connection(&self, machine_id: usize, handshake: Vec<u8>) -> Connection {
+        #[cfg(feature = "webtransport")]
+        {
+            if let Some(url) = webtransport_url(&self.network[machine_id]) {
+                return Connection::over_webtransport(&url, handshake, self.auth_token.clone());
+            }
+        }
+        let wsAddress = websocket_address(&self.network[machine_id]);
+        let websocket = WebSocket::new(&wsAddress).unwrap();
+        Connection::new(websocket, handshake, self.auth_token.clone())
+    }
+
+    /// Use a new connection to a peer, which will first send
+    /// everything that was enqueued for the peer in the meantime
+    fn attach_connection(&mut self, machine_id: MachineID, mut connection: Connection) {
+        if !connection.peer.is_participant() {
+            connection.peer.accepted_messages = Some(self.spectator_messages.clone());
+        }
+        let batch_message_bytes = self.batch_message_bytes;
+        let buffer_pool = self.buffer_pool.clone();
+        self.outboxes
+            .entry(machine_id)
+            .or_insert_with(|| Outbox::new(batch_message_bytes, buffer_pool.clone()));
+        self.network_connections[machine_id.0 as usize] = Some(connection);
+        self.emit(NetworkingEvent::Connected(machine_id));
+    }
+
+    /// Finish the turn, unless we are paused or, in strict lockstep mode,
+    /// waiting for the peers reported in the `LockstepWait`.
+    /// Waiting for longer than the timeout is also emitted as `NetworkingEvent::LockstepTimedOut`
+    pub(crate) fn try_finish_turn(&mut self) -> Result<Option<usize>, LockstepWait> {
+        self.detect_desyncs();
+
+        if let Some(pause_at) = self.pause_at {
+            if self.n_turns >= pause_at {
+                if !self.paused {
+                    self.paused = true;
+                    let n_turns = self.n_turns;
+                    self.emit(NetworkingEvent::Paused(n_turns));
+                }
+                return Ok(None);
+            }
+        }
+
+        if let Some(timeout) = self.strict_lockstep_timeout {
+            let stragglers = self
+                .network_connections
+                .iter()
+                .enumerate()
+                .filter_map(|(machine_id, maybe_connection)| match *maybe_connection {
+                    Some(ref connection)
+                        if connection.peer.is_participant()
+                            && connection.peer.n_turns < self.n_turns =>
+                    {
+                        Some(MachineID(machine_id as u16))
+                    }
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+
+            if stragglers.is_empty() {
+                self.lockstep_waiting_since_ms = None;
+                self.lockstep_timeout_reported = false;
+            } else {
+                let now = now_ms();
+                let waiting_since = *self.lockstep_waiting_since_ms.get_or_insert(now);
+                let timed_out = now - waiting_since > duration_ms(timeout);
+                if timed_out && !self.lockstep_timeout_reported {
+                    self.lockstep_timeout_reported = true;
+                    self.emit(NetworkingEvent::LockstepTimedOut(stragglers.clone()));
+                }
+                return Err(LockstepWait {
+                    stragglers,
+                    timed_out,
+                });
+            }
+        }
+
+        Ok(self.advance_turn())
+    }
+
+    fn advance_turn(&mut self) -> Option<usize> {
+        let mut maybe_skip_turns = None;
+
+        for (machine_id, maybe_connection) in self.network_connections.iter_mut().enumerate() {
+            if let Some(ref mut connection) = *maybe_connection {
+                if !connection.peer.is_participant() {
+                    continue;
+                }
+                let n_turns = connection.peer.n_turns;
+                let lagging = n_turns + self.acceptable_turn_distance < self.n_turns;
+                if lagging {
+                    maybe_skip_turns = Some(
+                        (self.n_turns - self.acceptable_turn_distance - n_turns)
+                            * self.skip_turns_per_turn_head,
+                    );
+                    if !connection.peer.lagging {
+                        self.events.push(NetworkingEvent::Lagging(
+                            MachineID(machine_id as u16),
+                            self.n_turns - n_turns,
+                        ));
+                    }
+                }
+                connection.peer.lagging = lagging;
+            }
+        }
+
+        if let Some(ref mut pacing) = self.pacing {
+            let own_n_turns = self.n_turns;
+            let connections = self
+                .network_connections
+                .iter()
+                .filter_map(Option::as_ref)
+                .filter(|connection| connection.peer.is_participant());
+            let slowest_rtt_ms = connections
+                .clone()
+                .filter_map(|connection| connection.peer.rtt_ms)
+                .fold(None, |max: Option<f64>, rtt| Some(max.map_or(rtt, |max| max.max(rtt))));
+            let turns_ahead = connections
+                .map(|connection| own_n_turns.saturating_sub(connection.peer.n_turns))
+                .max()
+                .unwrap_or(0);
+            pacing.update(slowest_rtt_ms, turns_ahead);
+        }
+
+        if let Some(ref batching) = self.batching {
+            for outbox in self.outboxes.values_mut() {
+                outbox.adapt_batch_size(batching);
+            }
+        }
+
+        self.n_turns += 1;
+        self.buffer_pool.borrow_mut().finish_turn();
+
+        let send_ping = self.n_turns % PING_INTERVAL_TURNS == 0;
+        let turn_marker = self.turn_protocol.turn_marker(self.n_turns);
+        let n_turns = self.n_turns;
+        let state_hash = self
+            .desync_detection
+            .as_mut()
+            .and_then(|detection| detection.finish_turn(n_turns));
+
+        for (machine_id, maybe_connection) in self.network_connections.iter_mut().enumerate() {
+            if let Some(ref mut connection) = *maybe_connection {
+                let batch_message_bytes = self.batch_message_bytes;
+                let buffer_pool = self.buffer_pool.clone();
+                let outbox = self
+                    .outboxes
+                    .entry(MachineID(machine_id as u16))
+                    .or_insert_with(|| Outbox::new(batch_message_bytes, buffer_pool.clone()));
+                outbox.write_control(&ControlFrame::Turn {
+                    marker: turn_marker.clone(),
+                    state_hash,
+                });
+                if send_ping {
+                    outbox.write_control(&ControlFrame::Ping {
+                        sent_at_ms: now_ms(),
+                    });
+                }
+                connection.peer.n_turns_since_own_turn = 0;
+            }
+        }
+
+        maybe_skip_turns
+    }
+
+    pub(crate) fn send_and_receive(
+        &mut self,
+        classes: &mut [Option<Class>],
+        implementors: &mut [Option<Vec<ShortTypeId>>],
+    ) {
+        if let Some(ref mut playback) = self.playback {
+            playback.play_until(self.n_turns, classes, implementors, &mut *self.turn_protocol);
+            return;
+        }
+
+        self.connect();
+
+        let mut lost_peers = Vec::new();
+        let mut rejected = Vec::new();
+        let mut invalid = Vec::new();
+        let n_turns = self.n_turns;
+        let batch_message_bytes = self.batch_message_bytes;
+        let buffer_pool = self.buffer_pool.clone();
+
+        for (machine_id, maybe_connection) in self.network_connections.iter_mut().enumerate() {
+            let recording = self
+                .recorder
+                .as_mut()
+                .map(|recorder| (recorder, MachineID(machine_id as u16), n_turns));
+            let role = maybe_connection
+                .as_ref()
+                .map(|connection| connection.peer.role)
+                .unwrap_or(MachineRole::Participant);
+            let closed_reason = if let Some(ref mut connection) = *maybe_connection {
+                if let Some(ref conditions) = self.link_conditions {
+                    if connection.conditioner.is_none() {
+                        connection.conditioner =
+                            Some(LinkConditioner::new(conditions.clone(), machine_id as u64));
+                    }
+                }
+                if self.reliable_delivery && connection.link.is_none() && !connection.peer.thin_client {
+                    let mut link = self
+                        .detached_links
+                        .remove(&MachineID(machine_id as u16))
+                        .unwrap_or_else(ReliableLink::new);
+                    link.reconnected();
+                    connection.link = Some(link);
+                }
+                let outbox = self
+                    .outboxes
+                    .entry(MachineID(machine_id as u16))
+                    .or_insert_with(|| Outbox::new(batch_message_bytes, buffer_pool.clone()));
+                connection.peer.allowed_remote = self.remote_allowlist.clone();
+                let turn_protocol = &mut *self.turn_protocol;
+                let result = connection.try_send_pending(outbox).and_then(|_| {
+                    connection.try_receive(classes, implementors, recording, turn_protocol)
+                });
+                for frame in connection.peer.replies.drain(..) {
+                    outbox.write_control(&frame);
+                }
+                for (message_type_id, recipient) in connection.peer.rejected.drain(..) {
+                    rejected.push((MachineID(machine_id as u16), message_type_id, recipient));
+                }
+                for problem in connection.peer.invalid.drain(..) {
+                    invalid.push((MachineID(machine_id as u16), problem));
+                }
+                match result {
+                    Ok(()) => None,
+                    Err(err) => Some(err),
+                }
+            } else {
+                None
+            };
+
+            if let Some(closed_reason) = closed_reason {
+                let left = maybe_connection.as_ref().map_or(false, |connection| connection.peer.left);
+                if let Some(connection) = maybe_connection.take().filter(|_| !left) {
+                    let machine_id = MachineID(machine_id as u16);
+                    self.sessions.lost(machine_id, connection.peer.n_turns);
+                    if let Some(token) = connection.session_token() {
+                        self.sessions.remember_received(machine_id, token);
+                    }
+                    if let Some(link) = connection.link {
+                        self.detached_links.insert(machine_id, link);
+                    }
+                }
+                let reason = if left { None } else { Some(closed_reason.to_string()) };
+                lost_peers.push((MachineID(machine_id as u16), role, reason));
+            }
+        }
+
+        for (machine_id, role, reason) in lost_peers {
+            let left = reason.is_none();
+            self.emit(match reason {
+                None => NetworkingEvent::Left(machine_id),
+                Some(reason) => NetworkingEvent::ConnectionLost(machine_id, reason),
+            });
+            self.peer_lost(machine_id, role);
+            if left {
+                // it won't reconnect, so nothing needs to be kept for it
+                self.outboxes.remove(&machine_id);
+                self.detached_links.remove(&machine_id);
+                self.sessions.forget(machine_id);
+            }
+        }
+
+        for (from, message_type_id, recipient) in rejected {
+            self.emit(NetworkingEvent::Rejected(from, message_type_id, recipient));
+        }
+        for (from, problem) in invalid {
+            self.emit(NetworkingEvent::Invalid(from, problem));
+        }
+
+        let pause_requests = self
+            .network_connections
+            .iter_mut()
+            .filter_map(Option::as_mut)
+            .filter_map(|connection| connection.peer.pause_request.take())
+            .collect::<Vec<_>>();
+        for request in pause_requests {
+            match request {
+                ControlFrame::PauseRequest if self.host == self.machine_id => self.decide_pause(),
+                ControlFrame::PauseRequest => println!("Ignoring pause request meant for the host"),
+                ControlFrame::Pause { at_turn } => self.pause_requested(at_turn as usize),
+                _ => self.resume_requested(),
+            }
+        }
+
+        #[cfg(feature = "browser")]
+        {
+            let max_n_turns = self
+                .network_connections
+                .iter()
+                .map(|maybe_connection| {
+                    if let Some(connection) = maybe_connection {
+                        connection.peer.n_turns
+                    } else {
+                        0
+                    }
+                })
+                .max()
+                .unwrap_or(self.n_turns);
+
+            if max_n_turns > 1000 + self.n_turns {
+                self.n_turns = max_n_turns;
+            }
+        }
+    }
+
+    pub(crate) fn enqueue<M: Message>(
+        &mut self,
+        message_type_id: ShortTypeId,
+        mut packet: Packet<M>,
+        cancellation_key: Option<u64>,
+        sender_class: Option<ShortTypeId>,
+    ) {
+        if self.network.len() == 1 || self.isolated {
+            return;
+        }
+
+        if self.role == MachineRole::Spectator
+            && !self.spectator_messages.contains(&message_type_id)
+        {
+            // spectators may not affect the simulation of others
+            return;
+        }
+
+        let packet_size = Compact::total_size_bytes(&packet);
+        let total_size = ::std::mem::size_of::<ShortTypeId>() + packet_size;
+        let machine_id = packet.recipient_id.machine;
+
+        let recipients = if machine_id == broadcast_machine_id() {
+            (0..self.network.len()).into_iter().collect()
+        } else if machine_id.is_multicast_group() {
+            self.multicast_groups
+                .get(&machine_id)
+                .map(|members| {
+                    members
+                        .iter()
+                        .filter(|member| **member != self.machine_id)
+                        .map(|member| member.0 as usize)
+                        .filter(|&member| member < self.network_connections.len())
+                        .collect()
+                })
+                .unwrap_or_else(Vec::new)
+        } else {
+            vec![machine_id.0 as usize]
+        };
+
+        let fanned_out = machine_id == broadcast_machine_id() || machine_id.is_multicast_group();
+        let recipients = if fanned_out && !self.interests.is_empty() {
+            let interests = &self.interests;
+            let position = interests.position(message_type_id, &packet.message as *const M as *const ());
+            let recipient_type = packet.recipient_id.type_id;
+            recipients
+                .into_iter()
+                .filter(|&member| interests.wants(MachineID(member as u16), recipient_type, position))
+                .collect()
+        } else {
+            recipients
+        };
+
+        let delta_message = if self.delta_messages.contains(&message_type_id) {
+            let mut message = Vec::with_capacity(total_size);
+            message
+                .write_u16::<LittleEndian>(message_type_id.into())
+                .unwrap();
+            message.resize(total_size, 0);
+            unsafe {
+                Compact::compact_behind(
+                    &mut packet,
+                    &mut message[::std::mem::size_of::<ShortTypeId>()] as *mut u8 as *mut Packet<M>,
+                );
+            }
+            Some(message)
+        } else {
+            None
+        };
+
+        for machine_id in recipients {
+            // also keep enqueueing for peers that are only temporarily disconnected
+            if let Some(outbox) = self.outboxes.get_mut(&MachineID(machine_id as u16)) {
+                let connection = self.network_connections[machine_id].as_mut();
+
+                #[cfg(feature = "webtransport")]
+                {
+                    let as_datagram = self.datagram_messages.contains(&message_type_id)
+                        && ::std::mem::size_of::<u32>() + total_size <= MAX_DATAGRAM_BYTES
+                        && connection.as_ref().map_or(false, |connection| connection.takes_datagrams());
+                    if as_datagram {
+                        let connection = connection.unwrap();
+                        // a batch of just this message
+                        let mut datagram = Vec::with_capacity(::std::mem::size_of::<u32>() + total_size);
+                        datagram.write_u32::<LittleEndian>(total_size as u32).unwrap();
+                        datagram.write_u16::<LittleEndian>(message_type_id.into()).unwrap();
+                        let packet_pos = datagram.len();
+                        datagram.resize(packet_pos + packet_size, 0);
+                        unsafe {
+                            Compact::compact_behind(
+                                &mut packet,
+                                &mut datagram[packet_pos] as *mut u8 as *mut Packet<M>,
+                            );
+                        }
+                        connection
+                            .peer
+                            .traffic
+                            .count_out(message_type_id, datagram.len());
+                        connection.out_datagrams.push(datagram);
+                        continue;
+                    }
+                }
+
+                if let Some(ref message) = delta_message {
+                    // without a connection, there's nothing to encode against
+                    let frame = match connection {
+                        Some(connection) => {
+                            let frame = connection
+                                .peer
+                                .delta
+                                .encode(sender_class.map_or(0, |class| class.into()), message, self.batch_message_bytes);
+                            connection
+                                .peer
+                                .traffic
+                                .count_out(message_type_id, ::std::mem::size_of::<u32>() + frame.len());
+                            frame
+                        }
+                        None => message.clone(),
+                    };
+                    // not cancellable, since the channel already counts it as sent to base later deltas on
+                    outbox.enqueue_in_batch(frame.len()).extend_from_slice(&frame);
+                    continue;
+                }
+
+                if let Some(connection) = connection {
+                    connection
+                        .peer
+                        .traffic
+                        .count_out(message_type_id, ::std::mem::size_of::<u32>() + total_size);
+                }
+                let data = outbox.enqueue_in_batch(total_size);
+                data.write_u16::<LittleEndian>(message_type_id.into())
+                    .unwrap();
+                let packet_pos = data.len();
+                data.resize(packet_pos + packet_size, 0);
+
+                unsafe {
+                    // store packet compactly in write queue
+                    Compact::compact_behind(
+                        &mut packet,
+                        &mut data[packet_pos] as *mut u8 as *mut Packet<M>,
+                    );
+                }
+                if let Some(key) = cancellation_key {
+                    outbox.key_last(key, total_size);
+                }
+            }
+        }
+
+        ::std::mem::forget(packet);
+    }
+
+    /// Cancel all messages enqueued with `key` that weren't sent yet, returns how many
+    pub(crate) fn cancel(&mut self, key: u64) -> usize {
+        self.outboxes.values_mut().map(|outbox| outbox.cancel(key)).sum()
+    }
+
+    /// Check a batch as if it was received from `from` and dispatch it if it is valid,
+    /// see `ActorSystem::dispatch_untrusted`
+    pub(crate) fn dispatch_untrusted(
+        &mut self,
+        from: MachineID,
+        data: &[u8],
+        classes: &mut [Option<Class>],
+        implementors: &mut [Option<Vec<ShortTypeId>>],
+    ) -> Result<(), String> {
+        let mut unconnected_peer = PeerState::new();
+        let peer = match self.network_connections.get_mut(from.0 as usize).and_then(Option::as_mut) {
+            Some(connection) => &mut connection.peer,
+            None => &mut unconnected_peer,
+        };
+        peer.allowed_remote = self.remote_allowlist.clone();
+        dispatch_batch(data, classes, implementors, peer, None, &mut *self.turn_protocol);
+        let rejected = peer.rejected.drain(..).collect::<Vec<_>>();
+        let invalid = peer.invalid.drain(..).collect::<Vec<_>>();
+        for (message_type_id, recipient) in rejected {
+            self.emit(NetworkingEvent::Rejected(from, message_type_id, recipient));
+        }
+        match invalid.into_iter().next() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Get the traffic per message type of all connected peers,
+    /// using `type_name` to name message types
+    pub(crate) fn traffic<F: Fn(ShortTypeId) -> String>(&self, type_name: F) -> NetworkTraffic {
+        let mut traffic = NetworkTraffic::default();
+        for (machine_id, maybe_connection) in self.network_connections.iter().enumerate() {
+            if let Some(ref connection) = *maybe_connection {
+                traffic.add_peer(MachineID(machine_id as u16), &connection.peer.traffic, &type_name);
+            }
+        }
+        traffic
+    }
+
+    pub(crate) fn reset_traffic(&mut self) {
+        for connection in self.network_connections.iter_mut().filter_map(Option::as_mut) {
+            connection.peer.traffic.reset();
+        }
+    }
+
+    /// The last turn each connected peer finished
+    pub(crate) fn peer_n_turns(&self) -> HashMap<MachineID, usize> {
+        self.network_connections
+            .iter()
+            .enumerate()
+            .filter_map(|(i, maybe_connection)| {
+                maybe_connection
+                    .as_ref()
+                    .map(|connection| (MachineID(i as u16), connection.peer.n_turns))
+            })
+            .collect()
+    }
+
+    pub(crate) fn debug_all_n_turns(&self) -> HashMap<MachineID, isize> {
+        self.network_connections
+            .iter()
+            .enumerate()
+            .map(|(i, maybe_connection)| {
+                (
+                    MachineID(i as u16),
+                    if i == usize::from(self.machine_id.0) {
+                        self.n_turns as isize
+                    } else {
+                        if let Some(connection) = maybe_connection.as_ref() {
+                            connection.peer.n_turns as isize
+                        } else {
+                            -1
+                        }
+                    },
+                )
+            })
+            .collect()
+    }
+
+    #[cfg(feature = "browser")]
+    pub fn main_out_connection(&self) -> Option<&Connection> {
+        self.network_connections[0].as_ref()
+    }
+}
+
+impl From<PlaybackNetworking> for Networking {
+    fn from(playback: PlaybackNetworking) -> Networking {
+        let machine_id = playback.machine_id.0;
+        let mut networking = Networking::unbound(
+            machine_id,
+            vec![String::new(); machine_id as usize + 1],
+            0,
+            0,
+            0,
+        );
+        networking.playback = Some(playback);
+        networking
+    }
+}
+
+#[cfg(feature = "server")]
+fn open_websocket(address: &str, proxy: Option<&Proxy>) -> Result<WebSocket<TcpStream>, String> {
+    let stream = match proxy {
+        Some(proxy) => proxy.connect(address, self::dial::CONNECT_TIMEOUT)?,
+        None => TcpStream::connect(address).map_err(|e| e.to_string())?,
+    };
+    stream.set_read_timeout(None).map_err(|e| e.to_string())?;
+    stream.set_write_timeout(None).map_err(|e| e.to_string())?;
+    let url = Url::parse(&format!("ws://{}", address)).map_err(|e| e.to_string())?;
+    websocket_client(url, stream)
+        .map(|(websocket, _)| websocket)
+        .map_err(|e| format!("Websocket handshake failed: {}", e))
+}
+
+/// Send our handshake on a freshly opened (still blocking) websocket
+/// and wait for the peer's verified reply
+#[cfg(feature = "server")]
+fn client_handshake(
+    websocket: &mut WebSocket<TcpStream>,
+    handshake: &Handshake,
+    auth_token: &Option<Vec<u8>>,
+) -> Result<Handshake, String> {
+    websocket
+        .write_message(WebSocketMessage::binary(handshake.encode()))
+        .and_then(|_| websocket.write_pending())
+        .map_err(|e| format!("Error while sending first message: {}", e))?;
+    loop {
+        match websocket.read_message() {
+            Ok(WebSocketMessage::Binary(data)) => {
+                let reply = Handshake::decode(&data).map_err(|e| e.to_string())?;
+                reply.verify(auth_token).map_err(|e| e.to_string())?;
+                return Ok(reply);
+            }
+            Ok(_) => {}
+            Err(e) => return Err(format!("Error while expecting handshake reply: {}", e)),
+        }
+    }
+}
+
+fn websocket_address(address: &str) -> String  {
+    let v: Vec<&str> = address.split("://").collect();
+    if v.len() == 1 {
+        format!("ws://{}", &v[0])
+    } else {
+        let rest = &v[1..].join("");
+        match v[0] {
+            "http" => format!("ws://{}", rest),
+            "https" => format!("wss://{}", rest),
+            "wss" => address.to_owned(),
+            "ws" => address.to_owned(),
+            _ => format!("ws://{}", rest)
+        }
+    }
+}
+
+#[test]
+fn test_websocket_address() {
+    assert_eq!(websocket_address("asd.as"), "ws://asd.as");
+    assert_eq!(websocket_address("://asd.as"), "ws://asd.as");
+    assert_eq!(websocket_address("://asd.as"), "ws://asd.as");
+    assert_eq!(websocket_address("ws://asd.as"), "ws://asd.as");
+    assert_eq!(websocket_address("wss://asd.as"), "wss://asd.as");
+    assert_eq!(websocket_address("http://asd.as"), "ws://asd.as");
+    assert_eq!(websocket_address("https://asd.as"), "wss://asd.as");
+}
+
+#[cfg(feature = "server")]
+#[test]
+fn test_claimed_machine_ids_are_bounded() {
+    let networking = crate::test_support::local_networking();
+    assert!(!networking.may_be_new_peer(MachineID(0)));
+    assert!(networking.may_be_new_peer(MachineID(1)));
+    assert!(networking.may_be_new_peer(MachineID(MAX_MACHINE_IDS_JOINED_SINCE as u16)));
+    assert!(!networking.may_be_new_peer(MachineID(MAX_MACHINE_IDS_JOINED_SINCE as u16 + 1)));
+    assert!(!networking.may_be_new_peer(MachineID(::std::u16::MAX)));
+}
+
+#[cfg(feature = "server")]
+pub struct Connection {
+    peer: PeerState,
+    websocket: WebSocket<TcpStream>,
+    conditioner: Option<LinkConditioner>,
+    link: Option<ReliableLink>,
+    /// The session token the peer issued to us, if we connected to it
+    session_token: Option<Vec<u8>>,
+    #[cfg(feature = "encryption")]
+    noise: Option<NoiseChannel>,
+    #[cfg(feature = "compression")]
+    compression: Option<CompressionChannel>,
+    striping: Option<Striping>,
+}
+
+#[cfg(feature = "server")]
+fn set_up_socket(websocket: &mut WebSocket<TcpStream>, options: &SocketOptions) {
+    let tcp_socket = websocket.get_mut();
+    tcp_socket.set_nonblocking(true).unwrap();
+    tcp_socket.set_read_timeout(None).unwrap();
+    tcp_socket.set_write_timeout(None).unwrap();
+    if let Err(e) = options.apply(tcp_socket) {
+        println!("Couldn't apply socket options: {}", e);
+    }
+}
+
+#[cfg(feature = "server")]
+impl Connection {
+    pub fn new(mut websocket: WebSocket<TcpStream>, options: &SocketOptions) -> Connection {
+        set_up_socket(&mut websocket, options);
+        Connection {
+            peer: PeerState::new(),
+            websocket,
+            conditioner: None,
+            link: None,
+            session_token: None,
+            #[cfg(feature = "encryption")]
+            noise: None,
+            #[cfg(feature = "compression")]
+            compression: None,
+            striping: None,
+        }
+    }
+
+    pub fn session_token(&self) -> Option<Vec<u8>> {
+        self.session_token.clone()
+    }
+
+    /// Close the websocket, flushing what it still has buffered as far as possible
+    pub fn close(&mut self) {
+        let _ = self.websocket.close(None);
+        let _ = self.websocket.write_pending();
+        if let Some(ref mut striping) = self.striping {
+            for lane in striping.lanes_mut() {
+                if let Lane::Open(ref mut websocket) = *lane {
+                    let _ = websocket.close(None);
+                    let _ = websocket.write_pending();
+                }
+            }
+        }
+    }
+
+    /// Is the peer still going to open lanes of this connection?
+    fn expects_lanes(&self) -> bool {
+        self.striping
+            .as_ref()
+            .map_or(false, |striping| (1..striping.n_lanes()).any(|lane| striping.expects_lane(lane)))
+    }
+
+    /// Advance dialing the extra lanes of a striped connection we dialed
+    fn dial_lanes(&mut self, own_handshake: &Handshake, auth_token: &Option<Vec<u8>>, options: &SocketOptions) {
+        let session_token = self.session_token.clone();
+        if let Some(ref mut striping) = self.striping {
+            for (index, lane) in striping.lanes_mut().iter_mut().enumerate() {
+                let established = match *lane {
+                    Lane::Dialing(ref mut dial) => {
+                        let mut handshake = own_handshake.clone();
+                        handshake.session_token = session_token.clone();
+                        handshake.stripe_lane = Some(index as u8 + 1);
+                        match dial.poll(&handshake, auth_token) {
+                            DialOutcome::Established(websocket, _) => Some(websocket),
+                            DialOutcome::Pending => None,
+                        }
+                    }
+                    _ => None,
+                };
+                if let Some(mut websocket) = established {
+                    set_up_socket(&mut websocket, options);
+                    *lane = Lane::Open(websocket);
+                }
+            }
+        }
+    }
+
+    /// Write a frame to the lanes it is striped across, or to the main websocket
+    fn write_frame(&mut self, frame: Vec<u8>) -> Result<(), ::tungstenite::Error> {
+        let frames = match self.striping {
+            Some(ref mut striping) => striping.stripe(frame),
+            None => vec![(0, frame)],
+        };
+        for (lane, frame) in frames {
+            let websocket = match lane {
+                0 => &mut self.websocket,
+                lane => self
+                    .striping
+                    .as_mut()
+                    .and_then(|striping| striping.open_lane(lane))
+                    .expect("Frames are only striped across open lanes"),
+            };
+            if let Err(e) = websocket.write_message(WebSocketMessage::binary(frame)) {
+                if let Some(real_err) = e.into_non_blocking() {
+                    return Err(real_err);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Once all lanes of a striped connection are open, tell the peer that
+    /// the frames after this one are striped. It is sent on its own instead of
+    /// through the outbox, so it isn't sent again on a later connection.
+    fn start_striping(&mut self, outbox: &mut Outbox) -> Result<(), ::tungstenite::Error> {
+        let n_lanes = match self.striping {
+            Some(ref striping) if striping.is_ready() => striping.n_lanes(),
+            _ => return Ok(()),
+        };
+        let announcement = ControlFrame::Striped {
+            n_lanes: n_lanes as u8,
+        };
+        let mut batch = Vec::with_capacity(::std::mem::size_of::<u32>() + announcement.encoded_len());
+        batch
+            .write_u32::<LittleEndian>(announcement.encoded_len() as u32)
+            .unwrap();
+        announcement.encode_into(&mut batch);
+        for frame in self.compress_frame(batch, outbox) {
+            let frame = self.seal_frame(frame, outbox);
+            self.write_frame(frame)?;
+        }
+        self.striping.as_mut().unwrap().start_sending();
+        Ok(())
+    }
+
+    /// Read the next frame from `lane`. Errors of any lane lose the whole connection,
+    /// since the peer's turns can't finish without it
+    fn read_frame(&mut self, lane: usize) -> Result<Option<WebSocketMessage>, ::tungstenite::Error> {
+        let read = match lane {
+            0 => self.websocket.read_message(),
+            lane => match self.striping.as_mut().and_then(|striping| striping.open_lane(lane)) {
+                Some(websocket) => websocket.read_message(),
+                // not opened by the peer yet
+                None => return Ok(None),
+            },
+        };
+        match read {
+            Ok(WebSocketMessage::Binary(data)) => match self.striping {
+                Some(ref mut striping) => striping
+                    .received(lane, data)
+                    .map(|data| Some(WebSocketMessage::Binary(data)))
+                    .map_err(|e| {
+                        ::tungstenite::Error::Io(::std::io::Error::new(::std::io::ErrorKind::InvalidData, e))
+                    }),
+                None => Ok(Some(WebSocketMessage::Binary(data))),
+            },
+            Ok(other_message) => Ok(Some(other_message)),
+            Err(e) => match e.into_non_blocking() {
+                Some(real_err) => Err(real_err),
+                None => Ok(None),
+            },
+        }
+    }
+
+    /// Read the frames the peer sends striped after announcing it
+    fn follow_striping(&mut self) -> Result<(), ::tungstenite::Error> {
+        if let Some(n_lanes) = self.peer.striped.take() {
+            let started = match self.striping {
+                Some(ref mut striping) => striping.start_receiving(n_lanes as usize),
+                None => Err("Peer stripes frames across lanes that weren't agreed on".to_owned()),
+            };
+            started.map_err(|e| {
+                ::tungstenite::Error::Io(::std::io::Error::new(::std::io::ErrorKind::InvalidData, e))
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Process the peer's acknowledgement of reliable batches and queue our own
+    fn write_ack(&mut self, outbox: &mut Outbox) {
+        if let Some(ref mut link) = self.link {
+            if let Some(seq) = self.peer.acked_up_to.take() {
+                link.acknowledged(seq);
+            }
+            if let Some(ack) = link.take_ack() {
+                outbox.write_control(&ack);
+            }
+        }
+    }
+
+    /// Take the batches to actually send now from the outbox,
+    /// numbered for reliable delivery and held back by the link conditioner if any
+    fn take_sendable_batches(&mut self, outbox: &mut Outbox) -> Vec<Vec<u8>> {
+        self.write_ack(outbox);
+        let mut batches = outbox.take_frames();
+
+        if let Some(ref mut link) = self.link {
+            link.seal(&mut batches);
+        }
+
+        match self.conditioner {
+            Some(ref mut conditioner) => {
+                let now = now_ms();
+                for batch in batches {
+                    conditioner.submit(batch, now);
+                }
+                conditioner.take_due(now)
+            }
+            None => batches,
+        }
+    }
+
+    /// Send pending encryption handshake messages,
+    /// returns whether batches can be sent yet
+    #[cfg(feature = "encryption")]
+    fn progress_encryption(&mut self) -> Result<bool, ::tungstenite::Error> {
+        if let Some(ref mut noise) = self.noise {
+            for message in noise.take_handshake_messages() {
+                if let Err(e) = self.websocket.write_message(WebSocketMessage::binary(message)) {
+                    if let Some(real_err) = e.into_non_blocking() {
+                        return Err(real_err);
+                    }
+                }
+            }
+            Ok(noise.is_established())
+        } else {
+            Ok(true)
+        }
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    fn progress_encryption(&mut self) -> Result<bool, ::tungstenite::Error> {
+        Ok(true)
+    }
+
+    /// Decrypt a received frame if the connection is encrypted,
+    /// returns `None` if it was part of the encryption handshake
+    #[cfg(feature = "encryption")]
+    fn open_frame(&mut self, data: Vec<u8>) -> Result<Option<Vec<u8>>, ::tungstenite::Error> {
+        let encryption_error = |e: String| {
+            ::tungstenite::Error::Io(::std::io::Error::new(
+                ::std::io::ErrorKind::InvalidData,
+                format!("Encryption error: {}", e),
+            ))
+        };
+        match self.noise {
+            Some(ref mut noise) if noise.is_established() => {
+                noise.decrypt(&data).map(Some).map_err(|e| encryption_error(e.to_string()))
+            }
+            Some(ref mut noise) => noise.read_handshake(&data).map(|_| None).map_err(encryption_error),
+            None => Ok(Some(data)),
+        }
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    fn open_frame(&mut self, data: Vec<u8>) -> Result<Option<Vec<u8>>, ::tungstenite::Error> {
+        Ok(Some(data))
+    }
+
+    /// Encrypt a frame to send if the connection is encrypted
+    #[cfg(feature = "encryption")]
+    fn seal_frame(&mut self, frame: Vec<u8>, outbox: &mut Outbox) -> Vec<u8> {
+        match self.noise {
+            Some(ref mut noise) => {
+                let sealed = noise.encrypt(&frame).expect("Couldn't encrypt frame");
+                outbox.recycle(frame);
+                sealed
+            }
+            None => frame,
+        }
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    fn seal_frame(&mut self, frame: Vec<u8>, _outbox: &mut Outbox) -> Vec<u8> {
+        frame
+    }
+
+    /// Compress a batch to send if the connection is compressed,
+    /// which can add a frame announcing a new dictionary before it
+    #[cfg(feature = "compression")]
+    fn compress_frame(&mut self, batch: Vec<u8>, outbox: &mut Outbox) -> Vec<Vec<u8>> {
+        match self.compression {
+            Some(ref mut compression) => {
+                let frames = compression.compress(&batch);
+                outbox.recycle(batch);
+                frames
+            }
+            None => vec![batch],
+        }
+    }
+
+    #[cfg(not(feature = "compression"))]
+    fn compress_frame(&mut self, batch: Vec<u8>, _outbox: &mut Outbox) -> Vec<Vec<u8>> {
+        vec![batch]
+    }
+
+    /// Decompress a received frame if the connection is compressed,
+    /// returns `None` if it only announced a new dictionary
+    #[cfg(feature = "compression")]
+    fn decompress_frame(&mut self, data: Vec<u8>) -> Result<Option<Vec<u8>>, ::tungstenite::Error> {
+        match self.compression {
+            Some(ref mut compression) => compression.decompress(&data).map_err(|e| {
+                ::tungstenite::Error::Io(::std::io::Error::new(::std::io::ErrorKind::InvalidData, e))
+            }),
+            None => Ok(Some(data)),
+        }
+    }
+
+    #[cfg(not(feature = "compression"))]
+    fn decompress_frame(&mut self, data: Vec<u8>) -> Result<Option<Vec<u8>>, ::tungstenite::Error> {
+        Ok(Some(data))
+    }
+
+    pub fn try_send_pending(&mut self, outbox: &mut Outbox) -> Result<(), ::tungstenite::Error> {
+        let encrypted_if_needed = self.progress_encryption()?;
+
+        let batches = if encrypted_if_needed {
+            self.start_striping(outbox)?;
+            self.take_sendable_batches(outbox)
+        } else {
+            Vec::new()
+        };
+
+        for batch in batches {
+            for frame in self.compress_frame(batch, outbox) {
+                // the websocket keeps unencrypted frames, so only encrypted ones give back their buffer
+                let frame = self.seal_frame(frame, outbox);
+                self.write_frame(frame)?;
+            }
+        }
+
+        if let Some(ref mut striping) = self.striping {
+            for lane in striping.lanes_mut() {
+                if let Lane::Open(ref mut websocket) = *lane {
+                    if let Err(e) = websocket.write_pending() {
+                        if let Some(real_err) = e.into_non_blocking() {
+                            return Err(real_err);
+                        }
+                    }
+                }
+            }
+        }
+
+        match self.websocket.write_pending() {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                if let Some(real_err) = e.into_non_blocking() {
+                    Err(real_err)
+                } else {
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    pub fn try_receive(
+        &mut self,
+        classes: &mut [Option<Class>],
+        implementors: &mut [Option<Vec<ShortTypeId>>],
+        mut recording: Option<(&mut BatchRecorder, MachineID, usize)>,
+        turn_protocol: &mut dyn TurnProtocol,
+    ) -> Result<(), ::tungstenite::Error> {
+        // read the lanes of a striped connection independently, round-robin
+        loop {
+            let mut read_any = false;
+            let n_lanes = self.striping.as_ref().map_or(1, Striping::receive_lanes);
+            for lane in 0..n_lanes {
+                let batches = match self.read_frame(lane)? {
+                    Some(WebSocketMessage::Binary(data)) => match self.open_frame(data)? {
+                        Some(data) => match self.decompress_frame(data)? {
+                            Some(data) => match self.striping {
+                                Some(ref mut striping) => {
+                                    let mut batches = vec![striping.hold_back_turns(lane, data)];
+                                    batches.extend(striping.take_finished_turns());
+                                    batches
+                                }
+                                None => vec![data],
+                            },
+                            None => Vec::new(),
+                        },
+                        None => Vec::new(),
+                    },
+                    Some(other_message) => panic!("Got a non binary message: {:?}", other_message),
+                    None => continue,
+                };
+                read_any = true;
+
+                let mut blocked = false;
+                for data in batches.into_iter().filter(|data| !data.is_empty()) {
+                    if let Some((ref mut recorder, machine_id, n_turns)) = recording {
+                        recorder.record(n_turns, machine_id, &data);
+                    }
+                    blocked = dispatch_batch(
+                        &data,
+                        classes,
+                        implementors,
+                        &mut self.peer,
+                        self.link.as_mut(),
+                        turn_protocol,
+                    ) || blocked;
+                }
+                self.follow_striping()?;
+
+                if blocked {
+                    return Ok(());
+                }
+            }
+
+            if !read_any {
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn dispatch_batch(
+    data: &[u8],
+    classes: &mut [Option<Class>],
+    implementors: &mut [Option<Vec<ShortTypeId>>],
+    peer: &mut PeerState,
+    link: Option<&mut ReliableLink>,
+    turn_protocol: &mut dyn TurnProtocol,
+) -> bool {
+    // peers could send anything, only dispatch batches that can't break the system
+    if let Err(e) = validate_batch(data, classes, implementors) {
+        peer.invalid.push(format!("Ignored invalid batch: {}", e));
+        return false;
+    }
+
+    let following = match (link, batch_sequence(data)) {
+        (Some(link), Some(seq)) => match link.receive(seq, data) {
+            Some(following) => following,
+            // already dispatched before a reconnect, or held back until earlier batches arrived
+            None => return false,
+        },
+        _ => Vec::new(),
+    };
+
+    // let msg = format!("Got batch of len {}, {:?}", data.len(), data);
+    // #[cfg(feature = "server")]
+    // println!("{}", msg);
+    // #[cfg(feature = "browser")]
+    // console!(log, msg);
+
+    let mut one_wants_to_wait = dispatch_messages(data, classes, implementors, peer, turn_protocol);
+    for batch in following {
+        let wants_to_wait = dispatch_messages(&batch, classes, implementors, peer, turn_protocol);
+        one_wants_to_wait = one_wants_to_wait || wants_to_wait;
+    }
+    one_wants_to_wait
+}
+
+fn dispatch_messages(
+    data: &[u8],
+    classes: &mut [Option<Class>],
+    implementors: &mut [Option<Vec<ShortTypeId>>],
+    peer: &mut PeerState,
+    turn_protocol: &mut dyn TurnProtocol,
+) -> bool {
+    let mut pos = 0;
+    let mut one_wants_to_wait = false;
+
+    while pos < data.len() {
+        let message_size = LittleEndian::read_u32(&data[pos..]);
+        pos += ::std::mem::size_of::<u32>();
+        let wants_to_wait = dispatch_message(
+            &data[pos..(pos + message_size as usize)],
+            classes,
+            implementors,
+            peer,
+            turn_protocol,
+        );
+        one_wants_to_wait = one_wants_to_wait || wants_to_wait;
+
+        pos += message_size as usize;
+    }
+
+    one_wants_to_wait
+}
+
+/// The sequence number of a batch sent with reliable delivery
+fn batch_sequence(data: &[u8]) -> Option<u32> {
+    let message_size = LittleEndian::read_u32(data.get(..4)?) as usize;
+    let first_message = data.get(4..4 + message_size)?;
+    if message_size > 2 && is_control_frame(first_message) {
+        match ControlFrame::decode(first_message) {
+            Some(ControlFrame::Sequence { seq }) => Some(seq),
+            _ => None,
+        }
+    } else {
+        None
+    }
+}
+
+fn dispatch_message(
+    data: &[u8],
+    classes: &mut [Option<Class>],
+    implementors: &mut [Option<Vec<ShortTypeId>>],
+    peer: &mut PeerState,
+    turn_protocol: &mut dyn TurnProtocol,
+) -> bool {
+    if is_delta_frame(data) {
+        let decoded = peer.delta.decode(data).and_then(|message| {
+            validate_message(&message, classes, implementors).map(|()| message)
+        });
+        match decoded {
+            Ok(message) => dispatch_message(&message, classes, implementors, peer, turn_protocol),
+            Err(e) => {
+                peer.invalid.push(format!("Ignored delta encoded message: {}", e));
+                false
+            }
+        }
+    } else if is_control_frame(data) {
+        match ControlFrame::decode(data) {
+            Some(frame) => peer.handle(frame, turn_protocol),
+            None => {
+                println!("Ignoring unknown control frame {:?}", &data[..3.min(data.len())]);
+                false
+            }
+        }
+    } else {
+        let message_type_id = ShortTypeId::new(LittleEndian::read_u16(data)).unwrap();
+        peer.traffic
+            .count_in(message_type_id, ::std::mem::size_of::<u32>() + data.len());
+
+        if let Some(ref accepted) = peer.accepted_messages {
+            if !accepted.contains(&message_type_id) {
+                println!(
+                    "Ignoring message type {} sent by a spectator",
+                    message_type_id.as_u16()
+                );
+                return false;
+            }
+        }
+
+        let recipient_id =
+            (&data[::std::mem::size_of::<ShortTypeId>()] as *const u8) as *const RawID;
+
+        if let Some(ref allowed) = peer.allowed_remote {
+            let recipient = unsafe { ::std::ptr::read_unaligned(recipient_id) };
+            if !allowed.allows(message_type_id, recipient.type_id) {
+                peer.rejected.push((message_type_id, recipient));
+                return false;
+            }
+        }
+
+        unsafe {
+            if let Some(ref mut class) = classes[(*recipient_id).type_id.as_usize()] {
+                class.inbox.put_raw(&data);
+            } else {
+                if let Some(implementors) =
+                    implementors[(*recipient_id).type_id.as_usize()].as_ref()
+                {
+                    for implementor_type_id in implementors {
+                        if let Some(class) = classes[implementor_type_id.as_usize()].as_mut() {
+                            class.inbox.put_raw(&data);
+                        } else {
+                            panic!(
+                                "No inbox for actor type {}, trait type {} (coming from network)",
+                                implementor_type_id.as_usize(),
+                                (*recipient_id).type_id.as_usize()
+                            );
+                        }
+                    }
+                } else {
+                    panic!(
+                        "No inbox for actor type {} - or no implementors (coming from network)",
+                        (*recipient_id).type_id.as_usize()
+                    )
+                }
+            }
+        }
+
+        false
+    }
+}
+
+#[cfg(feature = "browser")]
+use std::cell::RefCell;
+#[cfg(feature = "browser")]
+use std::collections::VecDeque;
+
+/// What a browser connection is carried over
+#[cfg(feature = "browser")]
+enum Transport {
+    WebSocket(WebSocket),
+    #[cfg(feature = "webtransport")]
+    WebTransport(WebTransportSession),
+}
+
+#[cfg(feature = "browser")]
+impl Transport {
+    fn is_open(&self) -> bool {
+        match *self {
+            Transport::WebSocket(ref websocket) => websocket.ready_state() == SocketReadyState::Open,
+            #[cfg(feature = "webtransport")]
+            Transport::WebTransport(ref session) => session.is_open(),
+        }
+    }
+
+    fn send(&self, frame: &[u8]) {
+        match *self {
+            Transport::WebSocket(ref websocket) => websocket.send_bytes(frame).unwrap(),
+            #[cfg(feature = "webtransport")]
+            Transport::WebTransport(ref session) => session.send(frame),
+        }
+    }
+
+    fn close(&self) {
+        match *self {
+            Transport::WebSocket(ref websocket) => websocket.close(),
+            #[cfg(feature = "webtransport")]
+            Transport::WebTransport(ref session) => session.close(),
+        }
+    }
+}
+
+#[cfg(feature = "browser")]
+pub struct Connection {
+    peer: PeerState,
+    transport: Transport,
+    in_queue: Rc<RefCell<VecDeque<Vec<u8>>>>,
+    /// Datagrams received over WebTransport, dispatched as soon as they arrive
+    #[cfg(feature = "webtransport")]
+    in_datagrams: Rc<RefCell<VecDeque<Vec<u8>>>>,
+    /// Datagrams waiting for the WebTransport session to open, see `Networking::send_as_datagrams`
+    #[cfg(feature = "webtransport")]
+    out_datagrams: Vec<Vec<u8>>,
+    got_machine_id: Rc<RefCell<bool>>,
+    rejected: Rc<RefCell<bool>>,
+    /// The session token the peer issued in its handshake reply
+    session_token: Rc<RefCell<Option<Vec<u8>>>>,
+    /// Our handshake, sent before any batches once the websocket is open
+    pending_handshake: Option<Vec<u8>>,
+    /// The session token we presented and the peer's last turn before it disconnected,
+    /// restored if its reply shows that it resumed our session
+    resumable: Option<(Vec<u8>, usize)>,
+    conditioner: Option<LinkConditioner>,
+    link: Option<ReliableLink>,
+}
+
+#[cfg(feature = "browser")]
+use stdweb::web::event::SocketMessageEvent;
+
+#[cfg(feature = "browser")]
+impl Connection {
+    pub fn new(websocket: WebSocket, handshake: Vec<u8>, auth_token: Option<Vec<u8>>) -> Connection {
+        Self::with_transport(handshake, auth_token, move |mut receive_frame| {
+            websocket.set_binary_type(SocketBinaryType::ArrayBuffer);
+            websocket.add_event_listener(move |event: SocketMessageEvent| {
+                let typed_array: TypedArray<u8> = event.data().into_array_buffer().unwrap().into();
+                receive_frame(typed_array.to_vec());
+            });
+            Transport::WebSocket(websocket)
+        })
+    }
+
+    /// Connect over a WebTransport session to `url` instead of a websocket
+    #[cfg(feature = "webtransport")]
+    pub fn over_webtransport(url: &str, handshake: Vec<u8>, auth_token: Option<Vec<u8>>) -> Connection {
+        let in_datagrams = Rc::new(RefCell::new(VecDeque::new()));
+        let in_datagrams_for_listener = in_datagrams.clone();
+        let mut connection = Self::with_transport(handshake, auth_token, move |mut receive_frame| {
+            let mut reader = FrameReader::default();
+            let session = WebTransportSession::open(
+                url,
+                move |chunk: TypedArray<u8>| {
+                    for frame in reader.read(&chunk.to_vec()) {
+                        receive_frame(frame);
+                    }
+                },
+                move |datagram: TypedArray<u8>| {
+                    in_datagrams_for_listener.borrow_mut().push_back(datagram.to_vec());
+                },
+            );
+            Transport::WebTransport(session)
+        });
+        connection.in_datagrams = in_datagrams;
+        connection
+    }
+
+    /// Set up the connection state shared with the listener that `open_transport` registers,
+    /// which receives frames, the first of which is the peer's handshake reply
+    fn with_transport<F: FnOnce(Box<dyn FnMut(Vec<u8>)>) -> Transport>(
+        handshake: Vec<u8>,
+        auth_token: Option<Vec<u8>>,
+        open_transport: F,
+    ) -> Connection {
+        let in_queue = Rc::new(RefCell::new(VecDeque::new()));
+        let in_queue_for_listener = in_queue.clone();
+        let got_machine_id = Rc::new(RefCell::new(false));
+        let got_machine_id_for_listener = got_machine_id.clone();
+        let rejected = Rc::new(RefCell::new(false));
+        let rejected_for_listener = rejected.clone();
+        let session_token = Rc::new(RefCell::new(None));
+        let session_token_for_listener = session_token.clone();
+
+        let transport = open_transport(Box::new(move |frame: Vec<u8>| {
+            let mut got_machine_id = got_machine_id_for_listener.borrow_mut();
+            if *got_machine_id {
+                in_queue_for_listener.borrow_mut().push_back(frame)
+            } else {
+                // first packet is the handshake reply
+                *got_machine_id = true;
+                match Handshake::decode(&frame).and_then(|reply| {
+                    reply.verify(&auth_token)?;
+                    Ok(reply)
+                }) {
+                    Ok(reply) => *session_token_for_listener.borrow_mut() = reply.session_token,
+                    Err(_) => *rejected_for_listener.borrow_mut() = true,
+                }
+            }
+        }));
+
+        Connection {
+            peer: PeerState::new(),
+            transport,
+            in_queue,
+            #[cfg(feature = "webtransport")]
+            in_datagrams: Rc::new(RefCell::new(VecDeque::new())),
+            #[cfg(feature = "webtransport")]
+            out_datagrams: Vec::new(),
+            got_machine_id,
+            rejected,
+            session_token,
+            pending_handshake: Some(handshake),
+            resumable: None,
+            conditioner: None,
+            link: None,
+        }
+    }
+
+    /// Can messages be sent to the peer as datagrams?
+    #[cfg(feature = "webtransport")]
+    fn takes_datagrams(&self) -> bool {
+        match self.transport {
+            Transport::WebTransport(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Process the peer's acknowledgement of reliable batches and queue our own
+    fn write_ack(&mut self, outbox: &mut Outbox) {
+        if let Some(ref mut link) = self.link {
+            if let Some(seq) = self.peer.acked_up_to.take() {
+                link.acknowledged(seq);
+            }
+            if let Some(ack) = link.take_ack() {
+                outbox.write_control(&ack);
+            }
+        }
+    }
+
+    /// Take the batches to actually send now from the outbox,
+    /// numbered for reliable delivery and held back by the link conditioner if any
+    fn take_sendable_batches(&mut self, outbox: &mut Outbox) -> Vec<Vec<u8>> {
+        self.write_ack(outbox);
+        let mut batches = outbox.take_frames();
+
+        if let Some(ref mut link) = self.link {
+            link.seal(&mut batches);
+        }
+
+        match self.conditioner {
+            Some(ref mut conditioner) => {
+                let now = now_ms();
+                for batch in batches {
+                    conditioner.submit(batch, now);
+                }
+                conditioner.take_due(now)
+            }
+            None => batches,
+        }
+    }
+
+    pub fn try_send_pending(&mut self, outbox: &mut Outbox) -> Result<(), ::std::io::Error> {
+        if self.transport.is_open() {
+            if let Some(handshake) = self.pending_handshake.take() {
+                self.transport.send(&handshake);
+            }
+
+            for batch in self.take_sendable_batches(outbox) {
+                self.transport.send(&batch);
+                outbox.recycle(batch);
+            }
+
+            #[cfg(feature = "webtransport")]
+            {
+                if let Transport::WebTransport(ref session) = self.transport {
+                    for datagram in self.out_datagrams.drain(..) {
+                        session.send_datagram(&datagram);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn try_receive(
+        &mut self,
+        classes: &mut [Option<Class>],
+        implementors: &mut [Option<Vec<ShortTypeId>>],
+        mut recording: Option<(&mut BatchRecorder, MachineID, usize)>,
+        turn_protocol: &mut dyn TurnProtocol,
+    ) -> Result<(), ::std::io::Error> {
+        if *self.rejected.borrow() {
+            return Err(::std::io::Error::new(
+                ::std::io::ErrorKind::PermissionDenied,
+                "Peer presented invalid credentials",
+            ));
+        }
+        #[cfg(feature = "webtransport")]
+        {
+            if let Transport::WebTransport(ref session) = self.transport {
+                if session.is_closed() {
+                    return Err(::std::io::Error::new(
+                        ::std::io::ErrorKind::ConnectionAborted,
+                        "WebTransport session closed",
+                    ));
+                }
+            }
+        }
+        if *self.got_machine_id.borrow() {
+            if let Some((presented, n_turns)) = self.resumable.take() {
+                if Sessions::was_resumed(&Some(presented), &self.session_token()) {
+                    self.peer.n_turns = n_turns;
+                }
+            }
+        }
+        if let Ok(mut in_queue) = self.in_queue.try_borrow_mut() {
+            //console!(log, "Before drain!");
+            for batch in in_queue.drain(..) {
+                //console!(log, "Before dispatch!");
+                if let Some((ref mut recorder, machine_id, n_turns)) = recording {
+                    recorder.record(n_turns, machine_id, &batch);
+                }
+                dispatch_batch(
+                    &batch,
+                    classes,
+                    implementors,
+                    &mut self.peer,
+                    self.link.as_mut(),
+                    turn_protocol,
+                );
+                //console!(log, "After dispatch!")
+            }
+        } else {
+            //console!(log, "Cannot borrow inqueue mutably!")
+        }
+        #[cfg(feature = "webtransport")]
+        {
+            if let Ok(mut in_datagrams) = self.in_datagrams.try_borrow_mut() {
+                // datagrams aren't sequenced, so they bypass reliable delivery
+                for datagram in in_datagrams.drain(..) {
+                    if let Some((ref mut recorder, machine_id, n_turns)) = recording {
+                        recorder.record(n_turns, machine_id, &datagram);
+                    }
+                    dispatch_batch(&datagram, classes, implementors, &mut self.peer, None, turn_protocol);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn session_token(&self) -> Option<Vec<u8>> {
+        self.session_token.borrow().clone()
+    }
+
+    /// Close the websocket, which the browser does after sending everything buffered
+    /// (a WebTransport session drops what wasn't sent yet)
+    pub fn close(&mut self) {
+        self.transport.close();
+    }
+
+    pub fn in_queue_len(&self) -> usize {
+        self.in_queue.borrow().len()
+    }
+}
+
+#[cfg(feature = "server")]
+#[test]
+fn test_new_host_is_elected_when_host_is_lost() {
+    use crate::actor_system::ActorSystem;
+    use crate::system_events::SystemEvent;
+    use crate::tuning::Tuning;
+    use std::sync::{Arc, Barrier};
+    use std::time::{Duration, Instant};
+
+    let free_address = || {
+        let listener = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string().parse().unwrap()
+    };
+    let network = vec![free_address(), free_address(), free_address()];
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let all_connected = Arc::new(Barrier::new(3));
+    // survivors stay connected to each other until both elected a new host
+    let all_elected = Arc::new(Barrier::new(2));
+
+    let machines = (0..3)
+        .map(|machine_id| {
+            let network = network.clone();
+            let all_connected = Arc::clone(&all_connected);
+            let all_elected = Arc::clone(&all_elected);
+            ::std::thread::spawn(move || {
+                let mut networking = Networking::new(machine_id, network, 50_000, 30, 10).unwrap();
+                networking.enable_host_migration();
+                let mut system = ActorSystem::new(networking, Tuning::default());
+                let events = system.subscribe_system_events();
+                let mut n_connected = 0;
+                while n_connected < 2 {
+                    assert!(Instant::now() < deadline, "Peers didn't connect");
+                    system.networking_connect();
+                    system.networking_send_and_receive();
+                    n_connected += events
+                        .try_iter()
+                        .filter(|event| if let SystemEvent::PeerConnected(_) = *event { true } else { false })
+                        .count();
+                }
+                let host_before = system.networking_host();
+                all_connected.wait();
+                if machine_id == 0 {
+                    // the host goes away without leaving
+                    return (host_before, host_before);
+                }
+                while system.networking_host() == host_before {
+                    assert!(Instant::now() < deadline, "No new host was elected");
+                    system.networking_connect();
+                    system.networking_send_and_receive();
+                }
+                all_elected.wait();
+                (host_before, system.networking_host())
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let hosts = machines.into_iter().map(|machine| machine.join().unwrap()).collect::<Vec<_>>();
+    assert!(hosts.iter().all(|&(before, _)| before == MachineID(0)));
+    // the lowest surviving machine ID takes over
+    assert_eq!(hosts[1].1, MachineID(1));
+    assert_eq!(hosts[2].1, MachineID(1));
+}
+
+#[cfg(feature = "server")]
+#[test]
+fn test_strict_lockstep_waits_for_all_peers() {
+    use crate::actor_system::ActorSystem;
+    use crate::system_events::SystemEvent;
+    use crate::tuning::Tuning;
+    use std::sync::mpsc::channel;
+    use std::time::{Duration, Instant};
+
+    fn connect(system: &mut ActorSystem, deadline: Instant) {
+        let events = system.subscribe_system_events();
+        while !events.try_iter().any(|event| if let SystemEvent::PeerConnected(_) = event { true } else { false }) {
+            assert!(Instant::now() < deadline, "Peers didn't connect");
+            system.networking_connect();
+            system.networking_send_and_receive();
+        }
+    }
+
+    let free_address = || {
+        let listener = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string().parse().unwrap()
+    };
+    let network = vec![free_address(), free_address()];
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let timeout = Duration::from_millis(50);
+
+    let (finish_turn, may_finish_turn) = channel::<()>();
+    let (stop, should_stop) = channel::<()>();
+    let peer_network = network.clone();
+    let peer = ::std::thread::spawn(move || {
+        let mut networking = Networking::new(1, peer_network, 50_000, 30, 10).unwrap();
+        networking.enable_strict_lockstep(timeout);
+        let mut system = ActorSystem::new(networking, Tuning::default());
+        connect(&mut system, deadline);
+        may_finish_turn.recv().unwrap();
+        assert!(system.networking_try_finish_turn().is_ok());
+        while should_stop.try_recv().is_err() {
+            assert!(Instant::now() < deadline, "Peer wasn't stopped");
+            system.networking_send_and_receive();
+        }
+    });
+
+    let mut networking = Networking::new(0, network, 50_000, 30, 10).unwrap();
+    networking.enable_strict_lockstep(timeout);
+    let mut system = ActorSystem::new(networking, Tuning::default());
+    connect(&mut system, deadline);
+
+    // both are at the first turn, so it can be finished
+    assert!(system.networking_try_finish_turn().is_ok());
+    system.networking_send_and_receive();
+    // but not the next one before the peer confirmed the first
+    let waiting = system.networking_try_finish_turn().unwrap_err();
+    assert_eq!(waiting.stragglers, vec![MachineID(1)]);
+    assert!(!waiting.timed_out);
+    ::std::thread::sleep(timeout * 2);
+    system.networking_send_and_receive();
+    assert!(system.networking_try_finish_turn().unwrap_err().timed_out);
+
+    finish_turn.send(()).unwrap();
+    loop {
+        assert!(Instant::now() < deadline, "Turn wasn't finished after the peer confirmed it");
+        system.networking_send_and_receive();
+        if system.networking_try_finish_turn().is_ok() {
+            break;
+        }
+    }
+    stop.send(()).unwrap();
+    peer.join().unwrap();
+}
+
+#[cfg(feature = "server")]
+#[test]
+fn test_only_allowed_messages_are_accepted_from_spectators() {
+    use crate::actor_system::ActorSystem;
+    use crate::id::TypedID;
+    use crate::messaging::Fate;
+    use crate::system_events::SystemEvent;
+    use crate::test_support::{Add, Counter};
+    use crate::tuning::Tuning;
+    use std::sync::mpsc::channel;
+    use std::time::{Duration, Instant};
+
+    #[derive(Compact, Clone)]
+    struct Multiply(u32);
+
+    fn setup(system: &mut ActorSystem) {
+        system.register::<Counter>();
+        system.add_handler::<Counter, _, _>(
+            |&Add(n), counter, _| {
+                counter.count += n;
+                Fate::Live
+            },
+            false,
+        );
+        system.add_handler::<Counter, _, _>(
+            |&Multiply(n), counter, _| {
+                counter.count *= n;
+                Fate::Live
+            },
+            false,
+        );
+    }
+
+    fn run_turn(system: &mut ActorSystem) {
+        system.networking_send_and_receive();
+        system.process_all_messages();
+        system.networking_finish_turn();
+    }
+
+    let free_address = || {
+        let listener = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string().parse().unwrap()
+    };
+    let network = vec![free_address(), free_address()];
+    let deadline = Instant::now() + Duration::from_secs(10);
+
+    let mut system = ActorSystem::new(Networking::new(0, network.clone(), 50_000, 30, 10).unwrap(), Tuning::default());
+    setup(&mut system);
+    system.allow_from_spectators::<Add>().unwrap();
+    let counter = system.spawn_many(vec![Counter::new(1)])[0];
+
+    let (stop, should_stop) = channel::<()>();
+    let counter_id = counter.as_raw();
+    let spectator = ::std::thread::spawn(move || {
+        let mut networking = Networking::new(1, network, 50_000, 30, 10).unwrap();
+        networking.set_role(MachineRole::Spectator);
+        let mut spectator = ActorSystem::new(networking, Tuning::default());
+        setup(&mut spectator);
+        // the spectator would send both, but its peer only accepts one of them
+        spectator.allow_from_spectators::<Add>().unwrap();
+        spectator.allow_from_spectators::<Multiply>().unwrap();
+        let events = spectator.subscribe_system_events();
+        while !events.try_iter().any(|event| if let SystemEvent::PeerConnected(_) = event { true } else { false }) {
+            assert!(Instant::now() < deadline, "Spectator didn't connect");
+            spectator.networking_connect();
+            spectator.networking_send_and_receive();
+        }
+        spectator.send(counter_id, Multiply(10));
+        spectator.send(counter_id, Add(3));
+        while should_stop.try_recv().is_err() {
+            assert!(Instant::now() < deadline, "Spectator wasn't stopped");
+            run_turn(&mut spectator);
+        }
+    });
+
+    while system.instance::<Counter>(counter).unwrap().count == 1 {
+        assert!(Instant::now() < deadline, "Messages from the spectator didn't arrive");
+        run_turn(&mut system);
+    }
+    for _ in 0..10 {
+        run_turn(&mut system);
+    }
+    stop.send(()).unwrap();
+    spectator.join().unwrap();
+    assert_eq!(system.instance::<Counter>(counter).unwrap().count, 4);
+}
@@ -0,0 +1,179 @@
+use super::batching::{BatchSizer, BatchingTarget};
+use super::buffer_pool::SharedBufferPool;
+#[cfg(test)]
+use super::buffer_pool::BufferPool;
+use super::control::ControlFrame;
+use byteorder::{LittleEndian, WriteBytesExt};
+
+/// The batches waiting to be sent to one peer.
+///
+/// Kept by `Networking` independently of the connection to the peer,
+/// so messages enqueued before or while a connection is dropped
+/// are sent once the peer reconnected.
+///
+/// Batch buffers that were coalesced into others or sent are recycled
+/// through the `BufferPool` shared by all outboxes.
+pub(crate) struct Outbox {
+    batches: Vec<Vec<u8>>,
+    /// The largest message and batch size
+    batch_message_bytes: usize,
+    /// The size batches are filled up to, below `batch_message_bytes` with adaptive batching
+    batch_bytes: usize,
+    sizer: Option<BatchSizer>,
+    pool: SharedBufferPool,
+    /// Messages that can still be cancelled before they are sent:
+    /// `(key, batch index, byte range including the length)`
+    cancellable: Vec<(u64, usize, ::std::ops::Range<usize>)>,
+    /// Byte ranges of cancelled messages per batch index, removed when taking frames
+    cancelled: Vec<(usize, ::std::ops::Range<usize>)>,
+}
+
+impl Outbox {
+    pub fn new(batch_message_bytes: usize, pool: SharedBufferPool) -> Outbox {
+        let first_batch = pool.borrow_mut().take(batch_message_bytes);
+        Outbox {
+            batches: vec![first_batch],
+            batch_message_bytes,
+            batch_bytes: batch_message_bytes,
+            sizer: None,
+            pool,
+            cancellable: Vec::new(),
+            cancelled: Vec::new(),
+        }
+    }
+
+    pub fn write_control(&mut self, frame: &ControlFrame) {
+        let data = self.enqueue_in_batch(frame.encoded_len());
+        frame.encode_into(data);
+    }
+
+    pub fn enqueue_in_batch(&mut self, message_size: usize) -> &mut Vec<u8> {
+        // let recipient_id =
+        //     (&message[::std::mem::size_of::<ShortTypeId>()] as *const u8) as *const RawID;
+        // println!(
+        //     "Enqueueing message recipient: {:?}, data: {:?}",
+        //     unsafe{(*recipient_id)}, message
+        // );
+
+        if message_size > self.batch_message_bytes {
+            panic!("Message size exceeds message batch size");
+        }
+
+        if let Some(ref mut sizer) = self.sizer {
+            sizer.enqueued(message_size + 4);
+        }
+
+        // messages larger than adapted batches get a batch of their own
+        let batch =
+            if self.batches.last().unwrap().len() + message_size < self.batch_bytes {
+                self.batches.last_mut().unwrap()
+            } else {
+                let fresh = self.fresh_buffer();
+                self.batches.push(fresh);
+                self.batches.last_mut().unwrap()
+            };
+
+        batch
+            .write_u32::<LittleEndian>(message_size as u32)
+            .unwrap();
+
+        batch
+    }
+
+    /// Make the message of `message_size` bytes that was just enqueued cancellable with `cancel`
+    pub fn key_last(&mut self, key: u64, message_size: usize) {
+        let batch_index = self.batches.len() - 1;
+        let end = self.batches[batch_index].len();
+        self.cancellable.push((key, batch_index, end - message_size - 4..end));
+    }
+
+    /// Cancel all messages enqueued with `key` that weren't sent yet, returns how many
+    pub fn cancel(&mut self, key: u64) -> usize {
+        let n_before = self.cancelled.len();
+        let cancelled = &mut self.cancelled;
+        self.cancellable.retain(|(message_key, batch_index, range)| {
+            if *message_key == key {
+                cancelled.push((*batch_index, range.clone()));
+                false
+            } else {
+                true
+            }
+        });
+        self.cancelled.len() - n_before
+    }
+
+    /// Is nothing waiting to be sent?
+    pub fn is_empty(&self) -> bool {
+        self.batches.iter().all(Vec::is_empty)
+    }
+
+    /// Take all non-empty batches to send them, each as one websocket frame.
+    /// Consecutive batches that fit into one frame together are coalesced,
+    /// so sending them needs fewer frames and syscalls.
+    pub fn take_frames(&mut self) -> Vec<Vec<u8>> {
+        let fresh = self.fresh_buffer();
+        let mut batches = ::std::mem::replace(&mut self.batches, vec![fresh]);
+        self.cancellable.clear();
+        // remove cancelled messages from the back, so earlier ranges stay valid
+        self.cancelled.sort_by_key(|(batch_index, range)| (*batch_index, range.start));
+        for (batch_index, range) in self.cancelled.drain(..).rev() {
+            batches[batch_index].drain(range);
+        }
+        let mut frames: Vec<Vec<u8>> = Vec::with_capacity(batches.len());
+
+        for batch in batches {
+            if batch.is_empty() {
+                self.recycle(batch);
+                continue;
+            }
+
+            let coalesce = frames
+                .last()
+                .map(|frame| frame.len() + batch.len() <= self.batch_bytes)
+                .unwrap_or(false);
+
+            if coalesce {
+                frames.last_mut().unwrap().extend_from_slice(&batch);
+                self.recycle(batch);
+            } else {
+                frames.push(batch);
+            }
+        }
+
+        frames
+    }
+
+    fn fresh_buffer(&mut self) -> Vec<u8> {
+        self.pool.borrow_mut().take(self.batch_bytes)
+    }
+
+    /// Choose the batch size for the next turn from the messages enqueued in the last one
+    pub fn adapt_batch_size(&mut self, target: &BatchingTarget) {
+        let batch_bytes = self.sizer.get_or_insert_with(BatchSizer::new).finish_turn(target);
+        self.batch_bytes = batch_bytes.min(self.batch_message_bytes);
+    }
+
+    /// The size batches are currently filled up to
+    pub fn batch_bytes(&self) -> usize {
+        self.batch_bytes
+    }
+
+    /// Give back a buffer that isn't needed anymore, like a frame that was sent
+    pub fn recycle(&mut self, buffer: Vec<u8>) {
+        self.pool.borrow_mut().recycle(buffer);
+    }
+}
+
+#[test]
+fn test_cancel_messages() {
+    let mut outbox = Outbox::new(64, BufferPool::shared(64));
+    outbox.enqueue_in_batch(2).extend_from_slice(&[1, 1]);
+    outbox.enqueue_in_batch(2).extend_from_slice(&[2, 2]);
+    outbox.key_last(7, 2);
+    outbox.enqueue_in_batch(2).extend_from_slice(&[3, 3]);
+    outbox.key_last(8, 2);
+    assert_eq!(outbox.cancel(7), 1);
+    assert_eq!(outbox.cancel(7), 0);
+    assert_eq!(outbox.take_frames(), vec![vec![2, 0, 0, 0, 1, 1, 2, 0, 0, 0, 3, 3]]);
+    assert_eq!(outbox.cancel(8), 0);
+}
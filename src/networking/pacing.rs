@@ -0,0 +1,110 @@
+use crate::time::duration_ms;
+use std::time::Duration;
+
+/// How quickly the target tick duration follows the desired one
+const SMOOTHING: f64 = 0.1;
+/// The target tick duration never changes by more than this fraction per turn
+const MAX_CHANGE_PER_TURN: f64 = 0.05;
+/// Being this many turns ahead of the slowest peer is not corrected for
+const TOLERATED_TURNS_AHEAD: usize = 1;
+/// How much longer a turn gets for each turn we are ahead of the slowest peer
+const SLOWDOWN_PER_TURN_AHEAD: f64 = 0.1;
+
+/// Computes how long a local turn should take, so the turn rate smoothly
+/// follows the slowest peer instead of racing ahead and then skipping turns.
+///
+/// The desired tick duration is long enough for half a round trip to the slowest peer
+/// and is stretched while we are ahead of it. The target only follows the desired
+/// duration through exponential smoothing with a limited rate of change,
+/// so short latency spikes don't make it oscillate.
+pub(crate) struct PacingController {
+    base_tick_ms: f64,
+    target_tick_ms: f64,
+}
+
+impl PacingController {
+    pub fn new(base_tick: Duration) -> PacingController {
+        let base_tick_ms = duration_ms(base_tick);
+        PacingController {
+            base_tick_ms,
+            target_tick_ms: base_tick_ms,
+        }
+    }
+
+    /// Update the target once per finished turn
+    pub fn update(&mut self, slowest_rtt_ms: Option<f64>, turns_ahead_of_slowest: usize) {
+        let latency_bound = slowest_rtt_ms.map(|rtt| rtt / 2.0).unwrap_or(0.0);
+        let lag_factor = 1.0
+            + SLOWDOWN_PER_TURN_AHEAD
+                * turns_ahead_of_slowest.saturating_sub(TOLERATED_TURNS_AHEAD) as f64;
+        let desired = self.base_tick_ms.max(latency_bound) * lag_factor;
+
+        let smoothed = self.target_tick_ms + SMOOTHING * (desired - self.target_tick_ms);
+        let max_change = self.target_tick_ms * MAX_CHANGE_PER_TURN;
+        self.target_tick_ms = smoothed
+            .max(self.target_tick_ms - max_change)
+            .min(self.target_tick_ms + max_change)
+            .max(self.base_tick_ms);
+    }
+
+    pub fn target_tick(&self) -> Duration {
+        let micros = (self.target_tick_ms * 1000.0) as u64;
+        Duration::from_micros(micros)
+    }
+}
+
+#[test]
+fn test_pacing_follows_turns_ahead() {
+    let base = Duration::from_millis(20);
+    let mut pacing = PacingController::new(base);
+    let target_ms = |pacing: &PacingController| duration_ms(pacing.target_tick());
+
+    // behind or just one turn ahead of the slowest peer, turns keep their base duration
+    for &turns_ahead in &[0, 1, 0] {
+        pacing.update(None, turns_ahead);
+        assert_eq!(pacing.target_tick(), base);
+    }
+
+    // further ahead, turns get longer, but only gradually
+    let mut previous_ms = target_ms(&pacing);
+    for _ in 0..10 {
+        pacing.update(None, 3);
+        let now_ms = target_ms(&pacing);
+        assert!(now_ms > previous_ms);
+        assert!(now_ms <= previous_ms * (1.0 + MAX_CHANGE_PER_TURN) + 0.001);
+        previous_ms = now_ms;
+    }
+    // approaching 10% longer for each turn ahead beyond the tolerated one, without overshooting
+    for _ in 0..200 {
+        pacing.update(None, 3);
+    }
+    assert!((target_ms(&pacing) - 24.0).abs() < 0.01);
+
+    // and they speed up again once caught up, but never beyond the base duration
+    pacing.update(None, 0);
+    assert!(target_ms(&pacing) < 24.0);
+    for _ in 0..200 {
+        pacing.update(None, 0);
+    }
+    assert_eq!(pacing.target_tick(), base);
+}
+
+#[test]
+fn test_pacing_follows_slowest_round_trip() {
+    let base = Duration::from_millis(20);
+    let mut pacing = PacingController::new(base);
+
+    // a round trip shorter than two base ticks doesn't slow turns down
+    pacing.update(Some(30.0), 0);
+    assert_eq!(pacing.target_tick(), base);
+
+    // a latency spike only changes the target a little
+    pacing.update(Some(200.0), 0);
+    assert!(duration_ms(pacing.target_tick()) <= 21.0 + 0.001);
+
+    // a long lasting higher latency makes turns last half a round trip
+    for _ in 0..300 {
+        pacing.update(Some(100.0), 0);
+    }
+    assert!((duration_ms(pacing.target_tick()) - 50.0).abs() < 0.01);
+}
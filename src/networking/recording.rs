@@ -0,0 +1,225 @@
+use super::control::PeerState;
+use super::dispatch_batch;
+use super::turn_protocol::TurnProtocol;
+use crate::class::Class;
+use crate::id::MachineID;
+use crate::type_registry::ShortTypeId;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::{HashMap, VecDeque};
+use std::io::{Read, Write};
+
+const RECORDING_MAGIC: &[u8; 8] = b"KAYREC01";
+
+/// Records every batch received from peers, together with the sending machine
+/// and the local turn it was received in.
+///
+/// Layout: the magic bytes, then for each batch
+/// `[turn: u32][machine ID: u16][length: u32][batch bytes]`
+pub(crate) struct BatchRecorder {
+    writer: Box<dyn Write>,
+}
+
+impl BatchRecorder {
+    pub fn new(mut writer: Box<dyn Write>) -> ::std::io::Result<BatchRecorder> {
+        writer.write_all(RECORDING_MAGIC)?;
+        Ok(BatchRecorder { writer })
+    }
+
+    pub fn record(&mut self, n_turns: usize, machine_id: MachineID, batch: &[u8]) {
+        let result = self
+            .writer
+            .write_u32::<LittleEndian>(n_turns as u32)
+            .and_then(|_| self.writer.write_u16::<LittleEndian>(machine_id.0))
+            .and_then(|_| self.writer.write_u32::<LittleEndian>(batch.len() as u32))
+            .and_then(|_| self.writer.write_all(batch));
+
+        if let Err(e) = result {
+            println!("Error while recording batch: {}", e);
+        }
+    }
+}
+
+struct RecordedBatch {
+    n_turns: usize,
+    machine_id: MachineID,
+    data: Vec<u8>,
+}
+
+/// Replays a recording made with `Networking::start_recording` into a local `ActorSystem`
+/// without opening any sockets. Convert it into a `Networking` to use it in place of one:
+/// each recorded batch is dispatched in the same local turn it was originally received in.
+pub struct PlaybackNetworking {
+    pub(crate) machine_id: MachineID,
+    batches: VecDeque<RecordedBatch>,
+    peers: HashMap<MachineID, PeerState>,
+}
+
+impl PlaybackNetworking {
+    /// Read a whole recording, to be replayed as machine `machine_id`
+    pub fn new<R: Read>(mut reader: R, machine_id: u16) -> ::std::io::Result<PlaybackNetworking> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != RECORDING_MAGIC {
+            return Err(::std::io::Error::new(
+                ::std::io::ErrorKind::InvalidData,
+                "Not a kay networking recording",
+            ));
+        }
+
+        let mut batches = VecDeque::new();
+
+        loop {
+            let n_turns = match reader.read_u32::<LittleEndian>() {
+                Ok(n_turns) => n_turns as usize,
+                Err(ref e) if e.kind() == ::std::io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            };
+            let recorded_machine_id = MachineID(reader.read_u16::<LittleEndian>()?);
+            let len = reader.read_u32::<LittleEndian>()? as usize;
+            let mut data = vec![0; len];
+            reader.read_exact(&mut data)?;
+            batches.push_back(RecordedBatch {
+                n_turns,
+                machine_id: recorded_machine_id,
+                data,
+            });
+        }
+
+        Ok(PlaybackNetworking {
+            machine_id: MachineID(machine_id),
+            batches,
+            peers: HashMap::new(),
+        })
+    }
+
+    /// The number of recorded batches that weren't replayed yet
+    pub fn remaining(&self) -> usize {
+        self.batches.len()
+    }
+
+    /// Dispatch all batches that were received up to local turn `n_turns`
+    pub(crate) fn play_until(
+        &mut self,
+        n_turns: usize,
+        classes: &mut [Option<Class>],
+        implementors: &mut [Option<Vec<ShortTypeId>>],
+        turn_protocol: &mut dyn TurnProtocol,
+    ) {
+        while self
+            .batches
+            .front()
+            .map(|batch| batch.n_turns <= n_turns)
+            .unwrap_or(false)
+        {
+            let batch = self.batches.pop_front().unwrap();
+            let peer = self
+                .peers
+                .entry(batch.machine_id)
+                .or_insert_with(PeerState::new);
+            dispatch_batch(&batch.data, classes, implementors, peer, None, turn_protocol);
+            // nobody to reply to during playback, and recorded batches were checked before
+            peer.replies.clear();
+            peer.invalid.clear();
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+#[test]
+fn test_playback_reproduces_recorded_state() {
+    use crate::actor_system::ActorSystem;
+    use crate::id::TypedID;
+    use crate::messaging::Fate;
+    use crate::networking::Networking;
+    use crate::system_events::SystemEvent;
+    use crate::test_support::{Add, Counter};
+    use crate::tuning::Tuning;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::sync::mpsc::channel;
+    use std::time::{Duration, Instant};
+
+    struct SharedWriter(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedWriter {
+        fn write(&mut self, data: &[u8]) -> ::std::io::Result<usize> {
+            self.0.borrow_mut().write(data)
+        }
+        fn flush(&mut self) -> ::std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn setup(system: &mut ActorSystem) {
+        system.register::<Counter>();
+        system.add_handler::<Counter, _, _>(
+            |&Add(n), counter, _| {
+                counter.count = counter.count * 2 + n;
+                Fate::Live
+            },
+            false,
+        );
+    }
+
+    fn run_turn(system: &mut ActorSystem) {
+        system.networking_send_and_receive();
+        system.process_all_messages();
+        system.networking_finish_turn();
+    }
+
+    let free_address = || {
+        let listener = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string().parse().unwrap()
+    };
+    let network = vec![free_address(), free_address()];
+    let deadline = Instant::now() + Duration::from_secs(10);
+
+    let recording = Rc::new(RefCell::new(Vec::new()));
+    let mut networking = Networking::new(0, network.clone(), 50_000, 30, 10).unwrap();
+    networking.start_recording(SharedWriter(Rc::clone(&recording))).unwrap();
+    let mut system = ActorSystem::new(networking, Tuning::default());
+    setup(&mut system);
+    let counter = system.spawn_many(vec![Counter::new(0)])[0];
+
+    let (stop, should_stop) = channel::<()>();
+    let counter_id = counter.as_raw();
+    let peer = ::std::thread::spawn(move || {
+        let mut peer = ActorSystem::new(Networking::new(1, network, 50_000, 30, 10).unwrap(), Tuning::default());
+        setup(&mut peer);
+        let events = peer.subscribe_system_events();
+        while !events.try_iter().any(|event| if let SystemEvent::PeerConnected(_) = event { true } else { false }) {
+            assert!(Instant::now() < deadline, "Peer didn't connect");
+            peer.networking_connect();
+            peer.networking_send_and_receive();
+        }
+        // in several turns, and in an order that matters
+        for n in 1..6 {
+            peer.send(counter_id, Add(n));
+            run_turn(&mut peer);
+        }
+        while should_stop.try_recv().is_err() {
+            assert!(Instant::now() < deadline, "Peer wasn't stopped");
+            peer.networking_send_and_receive();
+        }
+    });
+
+    let expected_count = (1..6).fold(0, |count, n| count * 2 + n);
+    while system.instance::<Counter>(counter).unwrap().count != expected_count {
+        assert!(Instant::now() < deadline, "Messages from the peer didn't arrive");
+        run_turn(&mut system);
+    }
+    stop.send(()).unwrap();
+    peer.join().unwrap();
+    let n_turns = system.networking_n_turns();
+
+    let playback = PlaybackNetworking::new(&recording.borrow()[..], 0).unwrap();
+    assert!(playback.remaining() > 0);
+    let mut replayed = ActorSystem::new(playback.into(), Tuning::default());
+    setup(&mut replayed);
+    replayed.spawn_many(vec![Counter::new(0)]);
+    while replayed.networking_n_turns() < n_turns {
+        run_turn(&mut replayed);
+    }
+    assert_eq!(replayed.instance::<Counter>(counter).unwrap().count, expected_count);
+    assert_eq!(replayed.state_hash(), system.state_hash());
+}
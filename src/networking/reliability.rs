@@ -0,0 +1,152 @@
+use super::control::ControlFrame;
+use byteorder::{LittleEndian, WriteBytesExt};
+use std::collections::{BTreeMap, VecDeque};
+
+/// Batches received ahead of a missing one that are kept at most,
+/// later ones are dropped and only arrive again when the peer resends them after a reconnect
+const MAX_EARLY_BATCHES: usize = 1024;
+
+/// Sequence numbers and retransmission state of the batches exchanged with one peer.
+///
+/// Every non-empty batch sent is prefixed with a `Sequence` control frame and kept
+/// until the peer acknowledges it. A link outlives its connection, so when the peer
+/// reconnects, all unacknowledged batches are sent again and the peer discards
+/// the ones it already received. Batches are dispatched strictly in sequence:
+/// ones that arrive ahead of a missing batch are held back until it arrived.
+pub(crate) struct ReliableLink {
+    next_seq: u32,
+    unacked: VecDeque<(u32, Vec<u8>)>,
+    last_received: Option<u32>,
+    /// Batches received ahead of the next expected one, by sequence number
+    early: BTreeMap<u32, Vec<u8>>,
+    ack_due: bool,
+    resend: bool,
+}
+
+impl ReliableLink {
+    pub fn new() -> ReliableLink {
+        ReliableLink {
+            next_seq: 0,
+            unacked: VecDeque::new(),
+            last_received: None,
+            early: BTreeMap::new(),
+            ack_due: false,
+            resend: false,
+        }
+    }
+
+    /// Called when the link is attached to a new connection to the peer
+    pub fn reconnected(&mut self) {
+        self.resend = true;
+        self.ack_due = self.last_received.is_some();
+    }
+
+    /// The acknowledgement to send to the peer, if it didn't get an up-to-date one yet
+    pub fn take_ack(&mut self) -> Option<ControlFrame> {
+        if self.ack_due {
+            self.ack_due = false;
+            self.last_received.map(|seq| ControlFrame::Ack { seq })
+        } else {
+            None
+        }
+    }
+
+    /// The peer received all batches up to and including `seq`
+    pub fn acknowledged(&mut self, seq: u32) {
+        while self
+            .unacked
+            .front()
+            .map(|&(unacked_seq, _)| unacked_seq <= seq)
+            .unwrap_or(false)
+        {
+            self.unacked.pop_front();
+        }
+    }
+
+    /// Prefix each non-empty batch with its sequence number and keep it until it is acknowledged.
+    /// After a reconnect, all unacknowledged batches are put in front again.
+    pub fn seal(&mut self, batches: &mut Vec<Vec<u8>>) {
+        let mut sealed = if self.resend {
+            self.unacked.iter().map(|&(_, ref data)| data.clone()).collect()
+        } else {
+            Vec::new()
+        };
+        self.resend = false;
+
+        for batch in batches.drain(..) {
+            if batch.is_empty() {
+                sealed.push(batch);
+                continue;
+            }
+
+            let frame = ControlFrame::Sequence { seq: self.next_seq };
+            let mut data = Vec::with_capacity(
+                ::std::mem::size_of::<u32>() + frame.encoded_len() + batch.len(),
+            );
+            data.write_u32::<LittleEndian>(frame.encoded_len() as u32)
+                .unwrap();
+            frame.encode_into(&mut data);
+            data.extend_from_slice(&batch);
+
+            self.unacked.push_back((self.next_seq, data.clone()));
+            sealed.push(data);
+            self.next_seq += 1;
+        }
+
+        *batches = sealed;
+    }
+
+    /// Register a received batch with sequence number `seq`. Returns `None` if it
+    /// can't be dispatched: it is a duplicate that was dispatched already, or it is held back
+    /// until the batches before it arrived. Otherwise, it is the next one in sequence and
+    /// the held back batches that follow it are returned, to be dispatched after it.
+    pub fn receive(&mut self, seq: u32, batch: &[u8]) -> Option<Vec<Vec<u8>>> {
+        let expected = self.last_received.map_or(0, |last| last.wrapping_add(1));
+        if seq == expected {
+            let mut following = Vec::new();
+            let mut last = seq;
+            while let Some(batch) = self.early.remove(&last.wrapping_add(1)) {
+                following.push(batch);
+                last = last.wrapping_add(1);
+            }
+            self.last_received = Some(last);
+            self.ack_due = true;
+            Some(following)
+        } else {
+            let is_early = self.last_received.map_or(true, |last| seq > last);
+            if is_early && self.early.len() < MAX_EARLY_BATCHES {
+                self.early.entry(seq).or_insert_with(|| batch.to_vec());
+            }
+            None
+        }
+    }
+}
+
+#[test]
+fn test_batches_are_dispatched_in_sequence() {
+    let mut sender = ReliableLink::new();
+    let mut receiver = ReliableLink::new();
+    let mut batches = vec![vec![1], vec![2], vec![3]];
+    sender.seal(&mut batches);
+    assert_eq!(batches.len(), 3);
+
+    assert_eq!(receiver.receive(0, &batches[0]), Some(vec![]));
+    // the third batch overtook the second one, so it waits for it
+    assert_eq!(receiver.receive(2, &batches[2]), None);
+    let acked = |receiver: &mut ReliableLink| match receiver.take_ack() {
+        Some(ControlFrame::Ack { seq }) => Some(seq),
+        _ => None,
+    };
+    assert_eq!(acked(&mut receiver), Some(0));
+    assert_eq!(receiver.receive(1, &batches[1]), Some(vec![batches[2].clone()]));
+    // duplicates are dropped
+    assert_eq!(receiver.receive(0, &batches[0]), None);
+    assert_eq!(receiver.receive(2, &batches[2]), None);
+    assert_eq!(acked(&mut receiver), Some(2));
+
+    sender.acknowledged(1);
+    sender.reconnected();
+    let mut resent = Vec::new();
+    sender.seal(&mut resent);
+    assert_eq!(resent, vec![batches[2].clone()]);
+}
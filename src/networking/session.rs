@@ -0,0 +1,113 @@
+use super::handshake::constant_time_eq;
+use crate::id::MachineID;
+#[cfg(feature = "server")]
+use rand::{OsRng, Rng};
+use std::collections::HashMap;
+
+/// Length of session tokens, long enough that they can't be guessed
+#[cfg(feature = "server")]
+const TOKEN_BYTES: usize = 16;
+
+/// Session tokens let a peer that briefly lost its connection resume
+/// its machine ID and lockstep state, instead of being treated as a new peer.
+///
+/// The accepting side of a connection issues a token in its handshake reply,
+/// the connecting side presents it again in the handshake when reconnecting.
+pub(crate) struct Sessions {
+    /// Tokens we issued to peers that connected to us
+    issued: HashMap<MachineID, Vec<u8>>,
+    /// Tokens peers issued to us, to present when reconnecting to them
+    received: HashMap<MachineID, Vec<u8>>,
+    /// The last finished turn of disconnected peers, restored when they resume
+    lost_turns: HashMap<MachineID, usize>,
+}
+
+impl Sessions {
+    pub fn new() -> Sessions {
+        Sessions {
+            issued: HashMap::new(),
+            received: HashMap::new(),
+            lost_turns: HashMap::new(),
+        }
+    }
+
+    /// Issue a new token to a peer, replacing any earlier one. Tokens come from the
+    /// operating system's secure random number generator, but they are no replacement
+    /// for an auth token
+    #[cfg(feature = "server")]
+    pub fn issue(&mut self, machine_id: MachineID) -> Vec<u8> {
+        let mut token = vec![0; TOKEN_BYTES];
+        OsRng::new()
+            .expect("Couldn't open the secure random number generator")
+            .fill_bytes(&mut token);
+        self.issued.insert(machine_id, token.clone());
+        token
+    }
+
+    /// Find the machine ID a presented token was issued to
+    pub fn resume(&self, presented: &[u8]) -> Option<MachineID> {
+        self.issued
+            .iter()
+            .find(|&(_, token)| constant_time_eq(token, presented))
+            .map(|(&machine_id, _)| machine_id)
+    }
+
+    /// The token issued to a peer that resumed its session, or a new one
+    #[cfg(feature = "server")]
+    pub fn token_for_peer(&mut self, machine_id: MachineID, resumed: bool) -> Vec<u8> {
+        match self.issued.get(&machine_id) {
+            Some(token) if resumed => token.clone(),
+            _ => self.issue(machine_id),
+        }
+    }
+
+    /// Did the peer we reconnected to resume our session, by replying with the same token
+    /// we presented from it instead of issuing a new one?
+    pub fn was_resumed(presented: &Option<Vec<u8>>, replied: &Option<Vec<u8>>) -> bool {
+        match (presented, replied) {
+            (Some(presented), Some(replied)) => constant_time_eq(presented, replied),
+            _ => false,
+        }
+    }
+
+    /// The token to present when reconnecting to `machine_id`, if it issued one
+    pub fn presented_to(&self, machine_id: MachineID) -> Option<Vec<u8>> {
+        self.received.get(&machine_id).cloned()
+    }
+
+    pub fn remember_received(&mut self, machine_id: MachineID, token: Vec<u8>) {
+        self.received.insert(machine_id, token);
+    }
+
+    pub fn lost(&mut self, machine_id: MachineID, n_turns: usize) {
+        self.lost_turns.insert(machine_id, n_turns);
+    }
+
+    /// The last turn a resuming peer finished before disconnecting
+    pub fn take_lost_turns(&mut self, machine_id: MachineID) -> Option<usize> {
+        self.lost_turns.remove(&machine_id)
+    }
+
+    /// Forget everything about a peer that left for good
+    pub fn forget(&mut self, machine_id: MachineID) {
+        self.issued.remove(&machine_id);
+        self.received.remove(&machine_id);
+        self.lost_turns.remove(&machine_id);
+    }
+}
+
+#[test]
+fn test_session_tokens() {
+    let mut sessions = Sessions::new();
+    let token = sessions.issue(MachineID(1));
+    assert_eq!(token.len(), TOKEN_BYTES);
+    assert_ne!(sessions.issue(MachineID(2)), token);
+    assert_eq!(sessions.resume(&token), Some(MachineID(1)));
+    assert_eq!(sessions.resume(&[0; TOKEN_BYTES]), None);
+    assert_eq!(sessions.token_for_peer(MachineID(1), true), token);
+    assert_ne!(sessions.token_for_peer(MachineID(1), false), token);
+
+    assert!(Sessions::was_resumed(&Some(token.clone()), &Some(token.clone())));
+    assert!(!Sessions::was_resumed(&Some(token.clone()), &Some(vec![0; TOKEN_BYTES])));
+    assert!(!Sessions::was_resumed(&None, &Some(token)));
+}
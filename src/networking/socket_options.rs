@@ -0,0 +1,142 @@
+use std::net::TcpStream;
+use std::time::Duration;
+
+/// TCP options of the connections to peers, see `Networking::set_socket_options`.
+/// The defaults keep the operating system's settings, apart from disabling Nagle's algorithm.
+#[derive(Clone, Debug)]
+pub struct SocketOptions {
+    /// Send small frames right away instead of coalescing them (default true),
+    /// which keeps turn latency low
+    pub nodelay: bool,
+    /// Probe idle connections, so dead peers are noticed behind NATs and firewalls
+    /// that silently drop idle connections (default: the operating system's setting)
+    pub keepalive: Option<KeepAlive>,
+    /// Size of the kernel send buffer, larger buffers help on links with a high
+    /// bandwidth-delay product (default: the operating system's setting)
+    pub send_buffer_bytes: Option<usize>,
+    /// Size of the kernel receive buffer (default: the operating system's setting)
+    pub receive_buffer_bytes: Option<usize>,
+    /// The IP type of service / traffic class byte, whose upper six bits are the DSCP,
+    /// like `46 << 2` to mark turns as expedited forwarding (default: unmarked)
+    pub tos: Option<u8>,
+}
+
+/// How to probe idle connections, see `SocketOptions::keepalive`
+#[derive(Clone, Debug)]
+pub struct KeepAlive {
+    /// How long a connection is idle before the first probe
+    pub idle: Duration,
+    /// How long to wait between unanswered probes
+    pub interval: Duration,
+    /// How many unanswered probes drop the connection
+    pub retries: u32,
+}
+
+impl Default for SocketOptions {
+    fn default() -> SocketOptions {
+        SocketOptions {
+            nodelay: true,
+            keepalive: None,
+            send_buffer_bytes: None,
+            receive_buffer_bytes: None,
+            tos: None,
+        }
+    }
+}
+
+impl SocketOptions {
+    pub(crate) fn apply(&self, stream: &TcpStream) -> Result<(), String> {
+        stream
+            .set_nodelay(self.nodelay)
+            .map_err(|e| format!("Couldn't set nodelay: {}", e))?;
+        self.apply_to_socket(stream)
+    }
+
+    #[cfg(unix)]
+    fn apply_to_socket(&self, stream: &TcpStream) -> Result<(), String> {
+        use libc::{IPPROTO_IP, IPPROTO_IPV6, IPPROTO_TCP, SOL_SOCKET};
+
+        if let Some(ref keepalive) = self.keepalive {
+            set_option(stream, SOL_SOCKET, libc::SO_KEEPALIVE, 1, "keepalive")?;
+            #[cfg(any(target_os = "macos", target_os = "ios"))]
+            let idle_option = libc::TCP_KEEPALIVE;
+            #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+            let idle_option = libc::TCP_KEEPIDLE;
+            let seconds = |duration: Duration| duration.as_secs().max(1).min(::std::i32::MAX as u64) as i32;
+            set_option(stream, IPPROTO_TCP, idle_option, seconds(keepalive.idle), "keepalive idle time")?;
+            set_option(stream, IPPROTO_TCP, libc::TCP_KEEPINTVL, seconds(keepalive.interval), "keepalive interval")?;
+            set_option(stream, IPPROTO_TCP, libc::TCP_KEEPCNT, keepalive.retries.max(1) as i32, "keepalive retries")?;
+        }
+        if let Some(bytes) = self.send_buffer_bytes {
+            set_option(stream, SOL_SOCKET, libc::SO_SNDBUF, buffer_size(bytes), "send buffer size")?;
+        }
+        if let Some(bytes) = self.receive_buffer_bytes {
+            set_option(stream, SOL_SOCKET, libc::SO_RCVBUF, buffer_size(bytes), "receive buffer size")?;
+        }
+        if let Some(tos) = self.tos {
+            let is_ipv6 = stream.local_addr().map(|addr| addr.is_ipv6()).unwrap_or(false);
+            if is_ipv6 {
+                set_option(stream, IPPROTO_IPV6, libc::IPV6_TCLASS, tos as i32, "traffic class")?;
+            } else {
+                set_option(stream, IPPROTO_IP, libc::IP_TOS, tos as i32, "type of service")?;
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn apply_to_socket(&self, _stream: &TcpStream) -> Result<(), String> {
+        if self.keepalive.is_some()
+            || self.send_buffer_bytes.is_some()
+            || self.receive_buffer_bytes.is_some()
+            || self.tos.is_some()
+        {
+            Err("Only nodelay is supported on this platform".to_owned())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(unix)]
+fn buffer_size(bytes: usize) -> i32 {
+    bytes.min(::std::i32::MAX as usize) as i32
+}
+
+#[cfg(unix)]
+fn set_option(stream: &TcpStream, level: i32, name: i32, value: i32, description: &str) -> Result<(), String> {
+    use std::os::unix::io::AsRawFd;
+    let result = unsafe {
+        libc::setsockopt(
+            stream.as_raw_fd(),
+            level,
+            name,
+            &value as *const i32 as *const libc::c_void,
+            ::std::mem::size_of::<i32>() as libc::socklen_t,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(format!("Couldn't set {}: {}", description, ::std::io::Error::last_os_error()))
+    }
+}
+
+#[test]
+fn test_socket_options_apply() {
+    let listener = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let options = SocketOptions {
+        keepalive: Some(KeepAlive {
+            idle: Duration::from_secs(30),
+            interval: Duration::from_secs(5),
+            retries: 4,
+        }),
+        send_buffer_bytes: Some(256 * 1024),
+        receive_buffer_bytes: Some(256 * 1024),
+        tos: Some(46 << 2),
+        ..SocketOptions::default()
+    };
+    options.apply(&stream).unwrap();
+    assert!(stream.nodelay().unwrap());
+}
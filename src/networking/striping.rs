@@ -0,0 +1,298 @@
+use super::control::{is_control_frame, is_delta_frame, is_turn_frame};
+use super::delta::FRAME_HEADER_BYTES as DELTA_FRAME_HEADER_BYTES;
+use super::dial::PendingConnection;
+use super::proxy::Proxy;
+use crate::id::RawID;
+use byteorder::{ByteOrder, LittleEndian};
+use std::collections::VecDeque;
+use std::net::TcpStream;
+use tungstenite::WebSocket;
+
+/// One of the extra websockets to a peer that frames are striped across
+pub(crate) enum Lane {
+    /// We dial it, since we dialed the main connection
+    Dialing(PendingConnection),
+    /// The peer dials it
+    Expected,
+    Open(WebSocket<TcpStream>),
+}
+
+/// Spreads the messages sent to a peer across the main connection (lane 0) and extra lanes,
+/// so one websocket doesn't cap the throughput to the peer. Each lane is its own ordering
+/// domain: messages go to the lane of their recipient's actor type, so messages to one class
+/// stay in order, while a stalled lane doesn't hold up the messages on the others.
+/// Turn markers are sent on all lanes and a peer's turn only counts as finished once
+/// its marker arrived on all of them, after all messages sent during the turn.
+/// Other control frames go to the main connection.
+///
+/// Once all lanes are open, each side announces with a `ControlFrame::Striped`
+/// on the main connection that the frames it sends after it are striped.
+/// Striped frames start with their sequence number within their lane, `[seq: u32]`.
+/// Since frames of different lanes arrive in any order, connections are only striped
+/// without encryption, compression and reliable delivery, which number or chain
+/// all frames of a connection.
+pub(crate) struct Striping {
+    /// Lanes 1 and up
+    lanes: Vec<Lane>,
+    sending: bool,
+    send_seqs: Vec<u32>,
+    receiving: bool,
+    receive_seqs: Vec<u32>,
+    /// How many turn markers arrived on each lane since the peer started striping
+    turns_received: Vec<usize>,
+    /// Turn markers received on the main connection that didn't arrive on all lanes yet
+    held_back_turns: VecDeque<Vec<u8>>,
+}
+
+impl Striping {
+    /// Stripe across `n_lanes`, dialing the extra ones at `address` (through `proxy`, if given)
+    /// if we dialed the main connection
+    pub fn new(n_lanes: usize, address: Option<&str>, proxy: Option<&Proxy>) -> Striping {
+        Striping {
+            lanes: (1..n_lanes)
+                .map(|_| match address {
+                    Some(address) => Lane::Dialing(PendingConnection::start(address, proxy)),
+                    None => Lane::Expected,
+                })
+                .collect(),
+            sending: false,
+            send_seqs: vec![0; n_lanes],
+            receiving: false,
+            receive_seqs: vec![0; n_lanes],
+            turns_received: vec![0; n_lanes],
+            held_back_turns: VecDeque::new(),
+        }
+    }
+
+    pub fn n_lanes(&self) -> usize {
+        self.lanes.len() + 1
+    }
+
+    pub fn lanes_mut(&mut self) -> &mut [Lane] {
+        &mut self.lanes
+    }
+
+    /// Are we waiting for the peer to open `lane`?
+    pub fn expects_lane(&self, lane: usize) -> bool {
+        match self.lanes.get(lane.wrapping_sub(1)) {
+            Some(Lane::Expected) => true,
+            _ => false,
+        }
+    }
+
+    pub fn open(&mut self, lane: usize, websocket: WebSocket<TcpStream>) {
+        self.lanes[lane - 1] = Lane::Open(websocket);
+    }
+
+    /// The websocket of an extra lane, if it is open already
+    pub fn open_lane(&mut self, lane: usize) -> Option<&mut WebSocket<TcpStream>> {
+        match self.lanes.get_mut(lane.wrapping_sub(1)) {
+            Some(Lane::Open(websocket)) => Some(websocket),
+            _ => None,
+        }
+    }
+
+    /// Can we stripe the frames we send from now on?
+    pub fn is_ready(&self) -> bool {
+        !self.sending
+            && self.lanes.iter().all(|lane| match lane {
+                Lane::Open(_) => true,
+                _ => false,
+            })
+    }
+
+    pub fn start_sending(&mut self) {
+        self.sending = true;
+    }
+
+    /// The peer stripes the frames it sends after its announcement across `n_lanes`
+    pub fn start_receiving(&mut self, n_lanes: usize) -> Result<(), String> {
+        if n_lanes != self.n_lanes() {
+            return Err(format!("Peer stripes across {} lanes instead of {}", n_lanes, self.n_lanes()));
+        }
+        self.receiving = true;
+        Ok(())
+    }
+
+    /// The lanes frames are currently received on: only the main connection
+    /// until the peer starts striping
+    pub fn receive_lanes(&self) -> usize {
+        if self.receiving {
+            self.n_lanes()
+        } else {
+            1
+        }
+    }
+
+    /// Split a batch into the frames to send on each lane, with their sequence numbers
+    pub fn stripe(&mut self, batch: Vec<u8>) -> Vec<(usize, Vec<u8>)> {
+        if !self.sending {
+            return vec![(0, batch)];
+        }
+        let n_lanes = self.n_lanes();
+        let mut frames = vec![vec![0; 4]; n_lanes];
+        let mut pos = 0;
+        while pos < batch.len() {
+            let end = pos + 4 + LittleEndian::read_u32(&batch[pos..]) as usize;
+            let message = &batch[pos..end];
+            match message_lane(&message[4..], n_lanes) {
+                Some(lane) => frames[lane].extend_from_slice(message),
+                None => {
+                    for frame in &mut frames {
+                        frame.extend_from_slice(message);
+                    }
+                }
+            }
+            pos = end;
+        }
+
+        let send_seqs = &mut self.send_seqs;
+        frames
+            .into_iter()
+            .enumerate()
+            .filter(|(_, frame)| frame.len() > 4)
+            .map(|(lane, mut frame)| {
+                LittleEndian::write_u32(&mut frame, send_seqs[lane]);
+                send_seqs[lane] = send_seqs[lane].wrapping_add(1);
+                (lane, frame)
+            })
+            .collect()
+    }
+
+    /// Check the sequence number of a frame received on `lane` and strip it
+    pub fn received(&mut self, lane: usize, mut frame: Vec<u8>) -> Result<Vec<u8>, String> {
+        if !self.receiving {
+            return Ok(frame);
+        }
+        if frame.len() < 4 {
+            return Err(format!("Striped frame on lane {} is truncated", lane));
+        }
+        let seq = LittleEndian::read_u32(&frame);
+        if seq != self.receive_seqs[lane] {
+            return Err(format!(
+                "Got frame {} on lane {}, expected {}",
+                seq, lane, self.receive_seqs[lane]
+            ));
+        }
+        self.receive_seqs[lane] = seq.wrapping_add(1);
+        frame.drain(..4);
+        Ok(frame)
+    }
+
+    /// Take the turn markers out of a batch received on `lane`, holding back the ones
+    /// of the main connection until they arrived on all lanes (see `take_finished_turns`)
+    pub fn hold_back_turns(&mut self, lane: usize, batch: Vec<u8>) -> Vec<u8> {
+        if !self.receiving {
+            return batch;
+        }
+        let mut rest = Vec::with_capacity(batch.len());
+        let mut pos = 0;
+        while pos + 4 <= batch.len() {
+            let end = (pos + 4 + LittleEndian::read_u32(&batch[pos..]) as usize).min(batch.len());
+            let message = &batch[pos..end];
+            if message.len() > 4 + 2 && is_turn_frame(&message[4..]) {
+                self.turns_received[lane] += 1;
+                if lane == 0 {
+                    self.held_back_turns.push_back(message.to_vec());
+                }
+            } else {
+                rest.extend_from_slice(message);
+            }
+            pos = end;
+        }
+        // leave what can't be split for validation to reject
+        rest.extend_from_slice(&batch[pos..]);
+        rest
+    }
+
+    /// Batches of the turn markers that arrived on all lanes now,
+    /// after all messages the peer sent during their turns
+    pub fn take_finished_turns(&mut self) -> Vec<Vec<u8>> {
+        let on_all_lanes = self.turns_received.iter().cloned().min().unwrap_or(0);
+        let n_released = self.turns_received[0] - self.held_back_turns.len();
+        let n_finished = on_all_lanes.saturating_sub(n_released);
+        self.held_back_turns.drain(..n_finished).collect()
+    }
+}
+
+/// The lane a message or control frame (`[message type][packet]`) is sent on,
+/// `None` for turn markers, which are sent on all lanes
+fn message_lane(message: &[u8], n_lanes: usize) -> Option<usize> {
+    let recipient_at = if !is_control_frame(message) {
+        2
+    } else if is_delta_frame(message) {
+        DELTA_FRAME_HEADER_BYTES + 2
+    } else if is_turn_frame(message) {
+        return None;
+    } else {
+        return Some(0);
+    };
+    let recipient = unsafe { ::std::ptr::read_unaligned(message[recipient_at..].as_ptr() as *const RawID) };
+    Some(recipient.type_id.as_usize() % n_lanes)
+}
+
+#[cfg(test)]
+fn message_to(recipient_type: u16, payload: u8) -> Vec<u8> {
+    use byteorder::WriteBytesExt;
+    // field by field, so the padding of the recipient ID is zeroed
+    let mut recipient = vec![0; ::std::mem::size_of::<RawID>()];
+    LittleEndian::write_u16(&mut recipient[4..], recipient_type);
+    let mut message = Vec::new();
+    message.write_u32::<LittleEndian>((2 + recipient.len() + 1) as u32).unwrap();
+    message.write_u16::<LittleEndian>(5).unwrap();
+    message.extend_from_slice(&recipient);
+    message.push(payload);
+    message
+}
+
+#[test]
+fn test_striping_by_recipient_type() {
+    use super::control::ControlFrame;
+    use byteorder::WriteBytesExt;
+
+    let mut sender = Striping::new(3, None, None);
+    let mut receiver = Striping::new(3, None, None);
+    assert!(!sender.is_ready());
+    assert_eq!(sender.stripe(vec![0]), vec![(0, vec![0])]);
+
+    sender.start_sending();
+    receiver.start_receiving(3).unwrap();
+    assert_eq!(receiver.receive_lanes(), 3);
+
+    let turn = ControlFrame::Turn {
+        marker: vec![1, 0, 0, 0],
+        state_hash: None,
+    };
+    let mut turn_marker = Vec::new();
+    turn_marker.write_u32::<LittleEndian>(turn.encoded_len() as u32).unwrap();
+    turn.encode_into(&mut turn_marker);
+
+    let mut batch = Vec::new();
+    for (recipient_type, payload) in &[(3, 1), (4, 2), (7, 3)] {
+        batch.extend(message_to(*recipient_type, *payload));
+    }
+    batch.extend_from_slice(&turn_marker);
+    batch.extend(message_to(5, 4));
+
+    let frames = sender.stripe(batch);
+    assert_eq!(frames.iter().map(|(lane, _)| *lane).collect::<Vec<_>>(), vec![0, 1, 2]);
+    let mut frames = frames.into_iter().map(|(_, frame)| frame).collect::<Vec<_>>();
+
+    // the lanes arrive in any order, but the turn only finishes once all of them arrived
+    let lane_2 = receiver.received(2, frames.remove(2)).unwrap();
+    assert_eq!(receiver.hold_back_turns(2, lane_2), message_to(5, 4));
+    let lane_0 = receiver.received(0, frames.remove(0)).unwrap();
+    assert_eq!(receiver.hold_back_turns(0, lane_0), message_to(3, 1));
+    assert!(receiver.take_finished_turns().is_empty());
+
+    let lane_1 = receiver.received(1, frames.remove(0)).unwrap();
+    let mut in_order = message_to(4, 2);
+    in_order.extend(message_to(7, 3));
+    assert_eq!(receiver.hold_back_turns(1, lane_1), in_order);
+    assert_eq!(receiver.take_finished_turns(), vec![turn_marker]);
+    assert!(receiver.take_finished_turns().is_empty());
+
+    let stale = vec![0, 0, 0, 0, 9];
+    assert!(receiver.received(1, stale).is_err());
+    assert!(Striping::new(2, None, None).start_receiving(3).is_err());
+}
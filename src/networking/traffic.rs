@@ -0,0 +1,79 @@
+use crate::id::MachineID;
+use crate::type_registry::ShortTypeId;
+use std::collections::HashMap;
+
+/// Bytes and message counts of one message type, sent to and received from peers.
+/// Byte counts include the per-message framing within batches.
+#[derive(Copy, Clone, Default, Debug, PartialEq)]
+pub struct MessageTraffic {
+    /// Bytes received
+    pub bytes_in: usize,
+    /// Bytes sent
+    pub bytes_out: usize,
+    /// Number of messages received
+    pub messages_in: usize,
+    /// Number of messages sent
+    pub messages_out: usize,
+}
+
+impl MessageTraffic {
+    fn add(&mut self, other: &MessageTraffic) {
+        self.bytes_in += other.bytes_in;
+        self.bytes_out += other.bytes_out;
+        self.messages_in += other.messages_in;
+        self.messages_out += other.messages_out;
+    }
+}
+
+/// Network traffic by message type name, per connected peer and aggregated over all peers
+#[derive(Clone, Default, Debug)]
+pub struct NetworkTraffic {
+    /// Traffic of each currently connected peer
+    pub per_peer: HashMap<MachineID, HashMap<String, MessageTraffic>>,
+    /// Traffic of all currently connected peers combined
+    pub total: HashMap<String, MessageTraffic>,
+}
+
+impl NetworkTraffic {
+    pub(crate) fn add_peer<F: Fn(ShortTypeId) -> String>(
+        &mut self,
+        machine_id: MachineID,
+        counters: &TrafficCounters,
+        type_name: F,
+    ) {
+        let mut peer_traffic = HashMap::new();
+        for (&message_type_id, traffic) in &counters.by_type {
+            let name = type_name(message_type_id);
+            self.total
+                .entry(name.clone())
+                .or_insert_with(MessageTraffic::default)
+                .add(traffic);
+            peer_traffic.insert(name, *traffic);
+        }
+        self.per_peer.insert(machine_id, peer_traffic);
+    }
+}
+
+/// Traffic per message type of one connection
+#[derive(Default)]
+pub(crate) struct TrafficCounters {
+    by_type: HashMap<ShortTypeId, MessageTraffic>,
+}
+
+impl TrafficCounters {
+    pub fn count_in(&mut self, message_type_id: ShortTypeId, bytes: usize) {
+        let traffic = self.by_type.entry(message_type_id).or_insert_with(MessageTraffic::default);
+        traffic.bytes_in += bytes;
+        traffic.messages_in += 1;
+    }
+
+    pub fn count_out(&mut self, message_type_id: ShortTypeId, bytes: usize) {
+        let traffic = self.by_type.entry(message_type_id).or_insert_with(MessageTraffic::default);
+        traffic.bytes_out += bytes;
+        traffic.messages_out += 1;
+    }
+
+    pub fn reset(&mut self) {
+        self.by_type.clear();
+    }
+}
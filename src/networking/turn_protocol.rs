@@ -0,0 +1,134 @@
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+
+/// How many turns of a peer we process at most during one of our own turns
+/// with `LockstepTurns`, before we stop reading from it to apply backpressure
+const MAX_PEER_TURNS_PER_OWN_TURN: usize = 10;
+
+/// Defines the turn markers machines announce finished turns with, and how
+/// we react to them. Replace the default `LockstepTurns` using
+/// `Networking::set_turn_protocol` to build custom lockstep schemes,
+/// such as sub-turns or voting on the simulation speed.
+///
+/// All machines of a network need to use compatible turn protocols.
+pub trait TurnProtocol {
+    /// Create the marker we send to all peers after finishing turn `n_turns`
+    fn turn_marker(&mut self, n_turns: usize) -> Vec<u8>;
+
+    /// Interpret a turn marker received from a peer, returning the turn it finished.
+    /// Returning `None` ignores the marker.
+    fn finished_turn(&mut self, marker: &[u8]) -> Option<usize>;
+
+    /// Should we stop processing messages from a peer for now, after it finished
+    /// `n_turns_since_own_turn` turns during our current turn?
+    fn apply_backpressure(&self, n_turns_since_own_turn: usize) -> bool;
+}
+
+/// The default turn protocol: markers contain just the finished turn,
+/// and we process the messages of at most 10 turns of a peer within one of our turns
+#[derive(Copy, Clone, Default, Debug)]
+pub struct LockstepTurns;
+
+impl TurnProtocol for LockstepTurns {
+    fn turn_marker(&mut self, n_turns: usize) -> Vec<u8> {
+        let mut marker = Vec::with_capacity(::std::mem::size_of::<u32>());
+        marker.write_u32::<LittleEndian>(n_turns as u32).unwrap();
+        marker
+    }
+
+    fn finished_turn(&mut self, marker: &[u8]) -> Option<usize> {
+        if marker.len() >= ::std::mem::size_of::<u32>() {
+            Some(LittleEndian::read_u32(marker) as usize)
+        } else {
+            None
+        }
+    }
+
+    fn apply_backpressure(&self, n_turns_since_own_turn: usize) -> bool {
+        n_turns_since_own_turn >= MAX_PEER_TURNS_PER_OWN_TURN
+    }
+}
+
+#[test]
+fn test_lockstep_turn_markers() {
+    let mut turns = LockstepTurns;
+    let marker = turns.turn_marker(70_000);
+    assert_eq!(marker, vec![0x70, 0x11, 0x01, 0x00]);
+    assert_eq!(turns.finished_turn(&marker), Some(70_000));
+
+    // newer protocols may append to the marker, the turn is still understood
+    let mut extended = marker.clone();
+    extended.push(3);
+    assert_eq!(turns.finished_turn(&extended), Some(70_000));
+    // truncated markers are ignored
+    assert_eq!(turns.finished_turn(&marker[..3]), None);
+
+    assert!(!turns.apply_backpressure(MAX_PEER_TURNS_PER_OWN_TURN - 1));
+    assert!(turns.apply_backpressure(MAX_PEER_TURNS_PER_OWN_TURN));
+}
+
+#[test]
+fn test_custom_turn_protocol_frames() {
+    use super::control::{ControlFrame, PeerState};
+
+    /// Splits each turn into sub-turns, only the last one finishes the turn
+    struct SubTurns {
+        n_sub_turns: u8,
+    }
+
+    impl TurnProtocol for SubTurns {
+        fn turn_marker(&mut self, n_turns: usize) -> Vec<u8> {
+            let mut marker = LockstepTurns.turn_marker(n_turns);
+            marker.push(self.n_sub_turns - 1);
+            marker
+        }
+
+        fn finished_turn(&mut self, marker: &[u8]) -> Option<usize> {
+            match marker.get(4) {
+                Some(&sub_turn) if sub_turn + 1 == self.n_sub_turns => LockstepTurns.finished_turn(marker),
+                _ => None,
+            }
+        }
+
+        fn apply_backpressure(&self, n_turns_since_own_turn: usize) -> bool {
+            n_turns_since_own_turn >= 2
+        }
+    }
+
+    let mut protocol = SubTurns { n_sub_turns: 4 };
+    let roundtrip = |frame: ControlFrame| {
+        let mut data = Vec::new();
+        frame.encode_into(&mut data);
+        assert_eq!(data.len(), frame.encoded_len());
+        ControlFrame::decode(&data).unwrap()
+    };
+
+    let mut peer = PeerState::new();
+    let frame = roundtrip(ControlFrame::Turn {
+        marker: protocol.turn_marker(5),
+        state_hash: Some(0xdead_beef),
+    });
+    assert!(!peer.handle(frame, &mut protocol));
+    assert_eq!(peer.n_turns, 5);
+    assert_eq!(peer.state_hashes, vec![(5, 0xdead_beef)]);
+
+    // a marker of a peer using plain lockstep isn't understood and ignored
+    let frame = roundtrip(ControlFrame::Turn {
+        marker: LockstepTurns.turn_marker(6),
+        state_hash: None,
+    });
+    assert!(!peer.handle(frame, &mut protocol));
+    assert_eq!(peer.n_turns, 5);
+
+    // but plain lockstep understands the custom marker
+    let frame = roundtrip(ControlFrame::Turn {
+        marker: protocol.turn_marker(7),
+        state_hash: None,
+    });
+    let mut lockstep_peer = PeerState::new();
+    assert!(!lockstep_peer.handle(frame.clone(), &mut LockstepTurns));
+    assert_eq!(lockstep_peer.n_turns, 7);
+
+    // the custom protocol applies backpressure after two turns
+    assert!(peer.handle(frame, &mut protocol));
+    assert_eq!(peer.n_turns, 7);
+}
@@ -0,0 +1,259 @@
+use super::control::is_control_frame;
+use crate::class::Class;
+use crate::type_registry::ShortTypeId;
+use byteorder::{ByteOrder, LittleEndian};
+
+const MESSAGE_TYPE_BYTES: usize = ::std::mem::size_of::<ShortTypeId>();
+/// `RawID` is `repr(C)`, its type ID follows the `u32` instance ID
+const RECIPIENT_TYPE_OFFSET: usize = MESSAGE_TYPE_BYTES + ::std::mem::size_of::<u32>();
+const HEADER_BYTES: usize = MESSAGE_TYPE_BYTES + ::std::mem::size_of::<crate::id::RawID>();
+
+/// Check that a batch received from a peer can be dispatched safely: all message lengths
+/// stay within the batch, and each message is either a control frame or has a known
+/// message type and recipient, where all receiving classes handle the message type
+/// and the message has at least the fixed size of its packets, with all dynamic parts
+/// (like the contents of a `CVec`) within the message (see `CompactBounds`).
+pub(crate) fn validate_batch(
+    data: &[u8],
+    classes: &[Option<Class>],
+    implementors: &[Option<Vec<ShortTypeId>>],
+) -> Result<(), String> {
+    let mut pos = 0;
+    while pos < data.len() {
+        let message_size = data
+            .get(pos..pos + 4)
+            .map(LittleEndian::read_u32)
+            .ok_or_else(|| format!("Truncated message length at byte {}", pos))? as usize;
+        let message = data
+            .get(pos + 4..pos + 4 + message_size)
+            .ok_or_else(|| format!("Message at byte {} is longer than the batch", pos))?;
+        validate_message(message, classes, implementors).map_err(|e| format!("{} (message at byte {})", e, pos))?;
+        pos += 4 + message_size;
+    }
+    Ok(())
+}
+
+/// Check one message of a batch, see `validate_batch`
+pub(crate) fn validate_message(
+    message: &[u8],
+    classes: &[Option<Class>],
+    implementors: &[Option<Vec<ShortTypeId>>],
+) -> Result<(), String> {
+    if message.len() < MESSAGE_TYPE_BYTES {
+        return Err(format!("Message of {} bytes has no message type", message.len()));
+    }
+    if is_control_frame(message) {
+        // decoding control frames is bounds checked and ignores unknown ones
+        return Ok(());
+    }
+    if message.len() < HEADER_BYTES {
+        return Err(format!("Message of {} bytes has no recipient", message.len()));
+    }
+
+    let message_type = LittleEndian::read_u16(message);
+    // message types beyond what the system has room for aren't handled by any class
+    let message_type =
+        ShortTypeId::new(message_type).ok_or_else(|| format!("Invalid message type {}", message_type))?;
+    let recipient_type = LittleEndian::read_u16(&message[RECIPIENT_TYPE_OFFSET..]);
+    let recipient_type = ShortTypeId::new(recipient_type)
+        .filter(|recipient_type| recipient_type.as_usize() < classes.len())
+        .ok_or_else(|| format!("Invalid recipient type {}", recipient_type))?;
+
+    let packet = &message[MESSAGE_TYPE_BYTES..];
+    let check_class = |class_type: ShortTypeId| match classes[class_type.as_usize()]
+        .as_ref()
+        .and_then(|class| class.packet_check(message_type))
+    {
+        Some(check) if packet.len() < check.size => Err(format!(
+            "Message of type {} has {} bytes, needs at least {}",
+            message_type.as_u16(),
+            message.len(),
+            MESSAGE_TYPE_BYTES + check.size
+        )),
+        Some(check) if !(check.dynamic_parts_within)(packet.as_ptr() as *const (), packet) => Err(format!(
+            "Message of type {} has dynamic parts outside of it, or can't be checked for them",
+            message_type.as_u16()
+        )),
+        Some(_) => Ok(()),
+        None => Err(format!(
+            "Actor type {} doesn't handle message type {}",
+            class_type.as_u16(),
+            message_type.as_u16()
+        )),
+    };
+
+    if classes[recipient_type.as_usize()].is_some() {
+        check_class(recipient_type)
+    } else {
+        match implementors.get(recipient_type.as_usize()).and_then(Option::as_ref) {
+            Some(implementors) => implementors.iter().cloned().map(check_class).collect(),
+            None => Err(format!("No actor type or implementors for type {}", recipient_type.as_u16())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::actor_system::ActorSystem;
+    use crate::compact::{CVec, Compact};
+    use crate::id::{MachineID, RawID, TypedID};
+    use crate::messaging::{Correlation, Fate, Message, Packet};
+    use crate::test_support::{local_system, Counter, CounterID};
+    use byteorder::{LittleEndian, WriteBytesExt};
+
+    #[derive(Compact, Clone)]
+    struct AddAll {
+        amounts: CVec<u32>,
+    }
+
+    crate::compact_bounds!(AddAll { amounts });
+
+    /// Has dynamic parts, but no `CompactBounds`
+    #[derive(Compact, Clone)]
+    struct AddUnchecked {
+        amounts: CVec<u32>,
+    }
+
+    const ADD_ALL: u16 = 100;
+    const ADD_UNCHECKED: u16 = 101;
+
+    fn system_with_counter() -> (ActorSystem, CounterID) {
+        let mut system = local_system();
+        system.pin_message_type_id::<AddAll>(ADD_ALL).unwrap();
+        system.pin_message_type_id::<AddUnchecked>(ADD_UNCHECKED).unwrap();
+        system.register::<Counter>();
+        system.add_handler::<Counter, _, _>(
+            |add: &AddAll, counter, _| {
+                counter.count += add.amounts.iter().sum::<u32>();
+                Fate::Live
+            },
+            false,
+        );
+        system.add_handler::<Counter, _, _>(|_: &AddUnchecked, _, _| Fate::Live, false);
+        let counter = system.spawn_many(vec![Counter::new(0)])[0];
+        (system, counter)
+    }
+
+    /// A message like it is sent to peers: its type ID followed by the compacted packet
+    fn wire_message<M: Message>(message_type: u16, recipient: RawID, message: M) -> Vec<u8> {
+        let mut packet = Packet {
+            recipient_id: recipient,
+            correlation: Correlation::none(),
+            message,
+        };
+        let size = Compact::total_size_bytes(&packet);
+        let mut compacted = vec![0u64; (size + 7) / 8];
+        unsafe {
+            Compact::compact_behind(&mut packet, compacted.as_mut_ptr() as *mut Packet<M>);
+        }
+        ::std::mem::forget(packet);
+        let mut wire = Vec::new();
+        wire.write_u16::<LittleEndian>(message_type).unwrap();
+        wire.extend_from_slice(unsafe { ::std::slice::from_raw_parts(compacted.as_ptr() as *const u8, size) });
+        wire
+    }
+
+    fn batch(messages: &[&[u8]]) -> Vec<u8> {
+        let mut batch = Vec::new();
+        for message in messages {
+            batch.write_u32::<LittleEndian>(message.len() as u32).unwrap();
+            batch.extend_from_slice(message);
+        }
+        batch
+    }
+
+    fn add_all(amounts: Vec<u32>) -> AddAll {
+        AddAll { amounts: amounts.into() }
+    }
+
+    #[test]
+    fn test_malformed_batches_are_rejected() {
+        let (mut system, counter) = system_with_counter();
+        let message = wire_message(ADD_ALL, counter.as_raw(), add_all(vec![1, 2, 3]));
+
+        let mut truncated_length = batch(&[&message]);
+        truncated_length.extend_from_slice(&[1, 0]);
+        assert!(system.dispatch_untrusted(MachineID(1), &truncated_length).is_err());
+
+        let mut too_long = batch(&[&message]);
+        too_long.truncate(too_long.len() - 1);
+        assert!(system.dispatch_untrusted(MachineID(1), &too_long).is_err());
+
+        let mut unknown_type = message.clone();
+        unknown_type[..2].copy_from_slice(&999u16.to_le_bytes());
+        assert!(system.dispatch_untrusted(MachineID(1), &batch(&[&unknown_type])).is_err());
+
+        // the message without its dynamic part, so the contents of its `CVec` would be beyond it
+        let static_size = 2 + ::std::mem::size_of::<Packet<AddAll>>();
+        let cut_off = &message[..static_size];
+        assert!(system.dispatch_untrusted(MachineID(1), &batch(&[cut_off])).is_err());
+
+        // cut off in the middle of the dynamic part
+        let partly_cut_off = &message[..static_size + 4];
+        assert!(system.dispatch_untrusted(MachineID(1), &batch(&[partly_cut_off])).is_err());
+
+        let unchecked = wire_message(ADD_UNCHECKED, counter.as_raw(), AddUnchecked { amounts: vec![1].into() });
+        assert!(system.dispatch_untrusted(MachineID(1), &batch(&[&unchecked])).is_err());
+
+        // nothing of the invalid batches was dispatched
+        system.process_all_messages();
+        assert_eq!(system.instance::<Counter>(counter).unwrap().count, 0);
+    }
+
+    #[test]
+    fn test_valid_batches_are_dispatched() {
+        let (mut system, counter) = system_with_counter();
+        let first = wire_message(ADD_ALL, counter.as_raw(), add_all(vec![1, 2, 3]));
+        let second = wire_message(ADD_ALL, counter.as_raw(), add_all(vec![]));
+        system.dispatch_untrusted(MachineID(1), &batch(&[&first, &second])).unwrap();
+        system.process_all_messages();
+        assert!(!system.world().panic_happened());
+        assert_eq!(system.instance::<Counter>(counter).unwrap().count, 6);
+    }
+
+    #[test]
+    fn test_rejected_pairs_are_not_dispatched() {
+        use crate::networking::RemoteMessageRejected;
+        use crate::test_support::{OtherCounter, ThirdCounter};
+
+        let (mut system, counter) = system_with_counter();
+        system.register::<OtherCounter>();
+        system.register::<ThirdCounter>();
+        system.add_handler::<OtherCounter, _, _>(
+            |add: &AddAll, other, _| {
+                other.count += add.amounts.iter().sum::<u32>();
+                Fate::Live
+            },
+            false,
+        );
+        system.add_handler::<ThirdCounter, _, _>(
+            |rejected: &RemoteMessageRejected, third, _| {
+                assert!(rejected.from == MachineID(1));
+                assert!(rejected.message_type.ends_with("AddAll"));
+                third.count += 1;
+                Fate::Live
+            },
+            false,
+        );
+        let other = system.spawn_many(vec![OtherCounter::new(0)])[0];
+        let security = system.spawn_many(vec![ThirdCounter::new(0)])[0];
+        system.networking_notify_rejected_messages(security.as_raw());
+        system.networking_allow_remote::<AddAll, Counter>().unwrap();
+
+        let allowed = wire_message(ADD_ALL, counter.as_raw(), add_all(vec![1, 2, 3]));
+        let rejected = wire_message(ADD_ALL, other.as_raw(), add_all(vec![4]));
+        system.dispatch_untrusted(MachineID(1), &batch(&[&allowed, &rejected, &rejected])).unwrap();
+        // rejections are delivered along with other networking events
+        system.networking_send_and_receive();
+        system.process_all_messages();
+
+        assert_eq!(system.instance::<Counter>(counter).unwrap().count, 6);
+        assert_eq!(system.instance::<OtherCounter>(other).unwrap().count, 0);
+        assert_eq!(system.instance::<ThirdCounter>(security).unwrap().count, 2);
+
+        // local messages aren't affected by the allowlist
+        system.send(other.as_raw(), add_all(vec![4]));
+        system.process_all_messages();
+        assert_eq!(system.instance::<OtherCounter>(other).unwrap().count, 4);
+    }
+}
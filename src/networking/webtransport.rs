@@ -0,0 +1,168 @@
+//! Browser connections over WebTransport (HTTP/3) instead of WebSockets,
+//! see `Networking::send_as_datagrams`
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+#[cfg(feature = "webtransport")]
+use stdweb::unstable::TryInto;
+#[cfg(feature = "webtransport")]
+use stdweb::web::TypedArray;
+#[cfg(feature = "webtransport")]
+use stdweb::Reference;
+
+/// Messages larger than this are sent on the stream instead of as datagrams,
+/// since they might not fit into a single QUIC packet
+#[cfg(feature = "webtransport")]
+pub(crate) const MAX_DATAGRAM_BYTES: usize = 1200;
+
+const SCHEME: &str = "webtransport://";
+
+/// The URL to open a WebTransport session to, if the peer address is one like
+/// `webtransport://host:port/path` (which needs to be served over HTTP/3, at `https://host:port/path`)
+pub(crate) fn webtransport_url(address: &str) -> Option<String> {
+    if address.starts_with(SCHEME) {
+        Some(format!("https://{}", &address[SCHEME.len()..]))
+    } else {
+        None
+    }
+}
+
+/// Prefix a frame with its length, since WebTransport streams don't keep frame boundaries
+pub(crate) fn length_prefixed(frame: &[u8]) -> Vec<u8> {
+    let mut prefixed = Vec::with_capacity(::std::mem::size_of::<u32>() + frame.len());
+    prefixed.write_u32::<LittleEndian>(frame.len() as u32).unwrap();
+    prefixed.extend_from_slice(frame);
+    prefixed
+}
+
+/// Splits the chunks read from a WebTransport stream back into the frames they were written as,
+/// each prefixed with `[length: u32]`
+#[derive(Default)]
+pub(crate) struct FrameReader {
+    buffer: Vec<u8>,
+}
+
+impl FrameReader {
+    /// Add a chunk read from the stream, returning the frames it completed
+    pub fn read(&mut self, chunk: &[u8]) -> Vec<Vec<u8>> {
+        self.buffer.extend_from_slice(chunk);
+        let mut frames = Vec::new();
+        let mut pos = 0;
+        let prefix_bytes = ::std::mem::size_of::<u32>();
+
+        while self.buffer.len() - pos >= prefix_bytes {
+            let length = LittleEndian::read_u32(&self.buffer[pos..]) as usize;
+            if self.buffer.len() - pos - prefix_bytes < length {
+                break;
+            }
+            frames.push(self.buffer[pos + prefix_bytes..pos + prefix_bytes + length].to_vec());
+            pos += prefix_bytes + length;
+        }
+
+        self.buffer.drain(..pos);
+        frames
+    }
+}
+
+/// A WebTransport session to a peer, with one bidirectional stream carrying
+/// the same frames as a websocket connection, and unreliable datagrams
+/// for messages of the types given to `Networking::send_as_datagrams`
+#[cfg(feature = "webtransport")]
+pub(crate) struct WebTransportSession {
+    transport: Reference,
+}
+
+#[cfg(feature = "webtransport")]
+impl WebTransportSession {
+    /// Open a session to `url`, calling `on_chunk` with the bytes read from the stream
+    /// and `on_datagram` with each received datagram
+    pub fn open<C, D>(url: &str, on_chunk: C, on_datagram: D) -> WebTransportSession
+    where
+        C: FnMut(TypedArray<u8>) + 'static,
+        D: FnMut(TypedArray<u8>) + 'static,
+    {
+        let transport = js! {
+            const transport = new WebTransport(@{url});
+            const onChunk = @{on_chunk};
+            const onDatagram = @{on_datagram};
+            const readAll = (reader, callback) => reader.read().then(result => {
+                if (!result.done) {
+                    callback(result.value);
+                    readAll(reader, callback);
+                }
+            });
+            transport.kayOpen = false;
+            transport.kayClosed = false;
+            transport.ready
+                .then(() => transport.createBidirectionalStream())
+                .then(stream => {
+                    transport.kayWriter = stream.writable.getWriter();
+                    transport.kayDatagramWriter = transport.datagrams.writable.getWriter();
+                    transport.kayOpen = true;
+                    readAll(stream.readable.getReader(), onChunk);
+                    readAll(transport.datagrams.readable.getReader(), onDatagram);
+                })
+                .catch(() => transport.close());
+            transport.closed.catch(() => {}).then(() => {
+                transport.kayOpen = false;
+                transport.kayClosed = true;
+            });
+            return transport;
+        };
+
+        WebTransportSession {
+            transport: transport.into_reference().unwrap(),
+        }
+    }
+
+    /// Are the stream and datagrams ready to send?
+    pub fn is_open(&self) -> bool {
+        js!(return @{&self.transport}.kayOpen;).try_into().unwrap_or(false)
+    }
+
+    /// Did the session fail to open or close?
+    pub fn is_closed(&self) -> bool {
+        js!(return @{&self.transport}.kayClosed;).try_into().unwrap_or(false)
+    }
+
+    /// Write a frame to the stream, once the session is open
+    pub fn send(&self, frame: &[u8]) {
+        let bytes: TypedArray<u8> = length_prefixed(frame).as_slice().into();
+        js! { @(no_return) @{&self.transport}.kayWriter.write(@{bytes}); }
+    }
+
+    /// Send a datagram, which might get lost, once the session is open
+    pub fn send_datagram(&self, datagram: &[u8]) {
+        let bytes: TypedArray<u8> = datagram.into();
+        js! { @(no_return) @{&self.transport}.kayDatagramWriter.write(@{bytes}); }
+    }
+
+    /// Close the session, which drops what wasn't sent yet
+    pub fn close(&self) {
+        js! { @(no_return) @{&self.transport}.close(); }
+    }
+}
+
+#[test]
+fn test_frames_are_split_across_chunks() {
+    let mut stream = length_prefixed(&[1, 2, 3]);
+    stream.extend(length_prefixed(&[]));
+    stream.extend(length_prefixed(&[4; 300]));
+
+    let mut reader = FrameReader::default();
+    let mut frames = Vec::new();
+    for chunk in stream.chunks(5) {
+        frames.extend(reader.read(chunk));
+    }
+
+    assert_eq!(frames, vec![vec![1, 2, 3], vec![], vec![4; 300]]);
+    assert!(reader.read(&[]).is_empty());
+}
+
+#[test]
+fn test_webtransport_urls() {
+    assert_eq!(
+        webtransport_url("webtransport://example.com:4433/kay"),
+        Some("https://example.com:4433/kay".to_owned())
+    );
+    assert_eq!(webtransport_url("wss://example.com:4433"), None);
+    assert_eq!(webtransport_url("example.com:4433"), None);
+}
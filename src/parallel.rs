@@ -0,0 +1,488 @@
+use crate::actor_system::ActorSystem;
+#[cfg(feature = "server")]
+use crate::class::Class;
+#[cfg(feature = "server")]
+use crate::messaging::Fate;
+use crate::type_registry::ShortTypeId;
+use std::cell::RefCell;
+#[cfg(feature = "server")]
+use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
+#[cfg(feature = "server")]
+use std::sync::mpsc::{channel, Sender};
+#[cfg(feature = "server")]
+use std::thread::JoinHandle;
+
+/// Something a handler running on a worker thread wanted to do to the `ActorSystem`,
+/// done on the main thread once all workers are finished
+pub(crate) type Deferred = Box<dyn FnOnce(&mut ActorSystem)>;
+
+/// Broadcasts to classes with parallel broadcasts enabled are only split up
+/// between workers if the class has at least this many instances
+#[cfg(feature = "server")]
+const MIN_PARALLEL_BROADCAST_INSTANCES: usize = 1024;
+
+/// What the current thread is doing while working for `ParallelProcessing`
+struct WorkerContext {
+    /// The system whose classes are handled, since several systems
+    /// can be hosted in one process (see `SystemGroup`)
+    system: *const ActorSystem,
+    /// The class whose messages are being handled
+    class: ShortTypeId,
+    /// All classes of the group the worker is handling, which only it touches
+    /// while it is running (empty if it handles a chunk of the instances
+    /// of a class for a broadcast)
+    #[cfg(feature = "server")]
+    group_classes: Vec<(ShortTypeId, *mut Class)>,
+    deferred: Vec<(ShortTypeId, Deferred)>,
+}
+
+thread_local! {
+    static WORKER_CONTEXT: RefCell<Option<WorkerContext>> = RefCell::new(None);
+}
+
+/// If called from a worker of `system`, defer `f` until all workers are finished
+/// and return `None`. Otherwise give `f` back, to be called right away.
+pub(crate) fn defer<F: FnOnce(&mut ActorSystem) + 'static>(system: *const ActorSystem, f: F) -> Option<F> {
+    let mut f = Some(f);
+    WORKER_CONTEXT.with(|context| {
+        if let Some(context) = context.borrow_mut().as_mut().filter(|context| context.system == system) {
+            let class = context.class;
+            context
+                .deferred
+                .push((class, Box::new(f.take().expect("Should only be taken once"))));
+        }
+    });
+    f
+}
+
+/// Is the current thread a worker of `ParallelProcessing`?
+pub(crate) fn in_worker() -> bool {
+    WORKER_CONTEXT.with(|context| context.borrow().is_some())
+}
+
+/// The class `class`, if the current thread is a worker handling the group it belongs to.
+/// Workers reach the classes of their group only through this, never through the system.
+#[cfg(feature = "server")]
+pub(crate) fn group_class(class: ShortTypeId) -> Option<*mut Class> {
+    WORKER_CONTEXT.with(|context| {
+        context.borrow().as_ref().and_then(|context| {
+            context
+                .group_classes
+                .iter()
+                .find(|&&(class_id, _)| class_id == class)
+                .map(|&(_, class_ptr)| class_ptr)
+        })
+    })
+}
+
+#[cfg(feature = "server")]
+fn set_current_class(class: ShortTypeId) {
+    WORKER_CONTEXT.with(|context| {
+        if let Some(ref mut context) = *context.borrow_mut() {
+            context.class = class;
+        }
+    });
+}
+
+/// Wraps things that are only moved to a worker and back while the main thread waits,
+/// and never touched by two threads at the same time
+#[cfg(feature = "server")]
+struct AssertSend<T>(T);
+#[cfg(feature = "server")]
+unsafe impl<T> Send for AssertSend<T> {}
+
+#[cfg(feature = "server")]
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Work for one worker, with what it is doing
+#[cfg(feature = "server")]
+struct Task<T> {
+    class: ShortTypeId,
+    group_classes: Vec<(ShortTypeId, *mut Class)>,
+    work: Box<dyn FnOnce() -> T>,
+}
+
+#[cfg(feature = "server")]
+type TaskOutcome<T> = (usize, ::std::thread::Result<T>, Vec<(ShortTypeId, Deferred)>);
+
+/// Calls a closure borrowed by `ParallelProcessing::cycle` or `broadcast` with its type erased,
+/// so it can be handed to workers while the calling thread waits for them
+#[cfg(feature = "server")]
+type HandleFn = unsafe fn(*const (), *mut Class, &mut [usize]);
+
+#[cfg(feature = "server")]
+unsafe fn call_handle<F: Fn(&mut Class, &mut [usize])>(handle: *const (), class: *mut Class, message_statistics: &mut [usize]) {
+    (*(handle as *const F))(&mut *class, message_statistics)
+}
+
+#[cfg(feature = "server")]
+type ReceiveFn = unsafe fn(*const (), *mut ()) -> Fate;
+
+#[cfg(feature = "server")]
+unsafe fn call_receive<F: Fn(*mut ()) -> Fate>(receive: *const (), actor: *mut ()) -> Fate {
+    (*(receive as *const F))(actor)
+}
+
+/// Handles the inboxes of independent groups of actor classes on a pool of threads.
+/// Each class always belongs to exactly one group, which is handled by one thread at a time,
+/// so messages to an instance are still handled one after the other.
+/// Classes that depend on each other beyond messages (for example because one allocates
+/// instance IDs of the other) need to be put into the same group.
+///
+/// Broadcasts to classes with many instances can also be split up between the threads,
+/// if enabled for the class.
+#[cfg(feature = "server")]
+pub(crate) struct ParallelProcessing {
+    /// The group of each class, by type ID
+    group_of: Vec<usize>,
+    /// Which classes handle broadcasts on several threads, by type ID
+    parallel_broadcasts: Vec<bool>,
+    jobs: Vec<Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+#[cfg(feature = "server")]
+impl ParallelProcessing {
+    pub fn new(n_threads: usize, n_classes: usize) -> ParallelProcessing {
+        let mut jobs = Vec::new();
+        let mut workers = Vec::new();
+        for i in 0..n_threads.max(1) {
+            let (job_sender, job_receiver) = channel::<Job>();
+            jobs.push(job_sender);
+            workers.push(
+                ::std::thread::Builder::new()
+                    .name(format!("kay-worker-{}", i))
+                    .spawn(move || {
+                        for job in job_receiver {
+                            job();
+                        }
+                    })
+                    .expect("Should be able to spawn worker thread"),
+            );
+        }
+        ParallelProcessing {
+            group_of: (0..n_classes).collect(),
+            parallel_broadcasts: vec![false; n_classes],
+            jobs,
+            workers,
+        }
+    }
+
+    /// Put the classes `a` and `b` (and everything already grouped with them) into the same group
+    pub fn declare_dependency(&mut self, a: ShortTypeId, b: ShortTypeId) {
+        let (from, to) = (self.group_of[b.as_usize()], self.group_of[a.as_usize()]);
+        for group in &mut self.group_of {
+            if *group == from {
+                *group = to;
+            }
+        }
+    }
+
+    pub fn enable_parallel_broadcasts(&mut self, class: ShortTypeId) {
+        self.parallel_broadcasts[class.as_usize()] = true;
+    }
+
+    /// Should a broadcast to `class` be split up between the workers right now?
+    pub fn should_broadcast_in_parallel(&self, class: ShortTypeId, n_instances: usize) -> bool {
+        self.parallel_broadcasts[class.as_usize()]
+            && n_instances >= MIN_PARALLEL_BROADCAST_INSTANCES
+            && !in_worker()
+    }
+
+    /// Run all tasks on the workers and wait for them. Returns their results
+    /// and what they deferred in the order of the tasks, and panics on this thread
+    /// if any of them panicked.
+    fn run<T: 'static>(&self, system: *const ActorSystem, tasks: Vec<Task<T>>) -> Vec<(T, Vec<(ShortTypeId, Deferred)>)> {
+        let (results_sender, results) = channel::<AssertSend<TaskOutcome<T>>>();
+        let n_tasks = tasks.len();
+
+        for (i, task) in tasks.into_iter().enumerate() {
+            let task = AssertSend((task, results_sender.clone(), system));
+            self.jobs[i % self.jobs.len()]
+                .send(Box::new(move || {
+                    let AssertSend((Task { class, group_classes, work }, results, system)) = task;
+                    WORKER_CONTEXT.with(|context| {
+                        *context.borrow_mut() = Some(WorkerContext {
+                            system,
+                            class,
+                            group_classes,
+                            deferred: Vec::new(),
+                        })
+                    });
+                    let outcome = catch_unwind(AssertUnwindSafe(work));
+                    let context = WORKER_CONTEXT
+                        .with(|context| context.borrow_mut().take())
+                        .expect("Worker context should still be set");
+                    results
+                        .send(AssertSend((i, outcome, context.deferred)))
+                        .expect("Main thread should wait for results");
+                }))
+                .expect("Worker thread should be running");
+        }
+
+        let mut outcomes = (0..n_tasks)
+            .map(|_| results.recv().expect("Workers should send results").0)
+            .collect::<Vec<_>>();
+        outcomes.sort_by_key(|&(i, _, _)| i);
+
+        let mut finished = Vec::with_capacity(n_tasks);
+        let mut first_panic = None;
+        for (_, outcome, deferred) in outcomes {
+            match outcome {
+                Ok(result) => finished.push((result, deferred)),
+                Err(panic) => {
+                    first_panic.get_or_insert(panic);
+                }
+            }
+        }
+        if let Some(panic) = first_panic {
+            resume_unwind(panic);
+        }
+        finished
+    }
+
+    /// Handle the inboxes of all classes once, each group of classes on one worker
+    /// (a group that is alone in having messages is handled right here).
+    /// Classes are handled in `order` (by scheduling priority) within their group, and groups
+    /// are ordered by their first class in `order`. Returns what the handlers deferred, in that order.
+    ///
+    /// Each worker only gets the classes of its group (including those without messages,
+    /// whose instance IDs it might allocate). Every class has its own storage handle
+    /// (see `ClassStorage`), so workers share nothing but the locked bookkeeping of snapshots
+    /// and read-only parts of the system.
+    pub fn cycle<F: Fn(&mut Class, &mut [usize])>(
+        &self,
+        system: *const ActorSystem,
+        classes: &mut [Option<Class>],
+        order: &[usize],
+        message_statistics: &mut [usize],
+        handle: F,
+    ) -> Vec<(ShortTypeId, Deferred)> {
+        // (group, classes of the group, classes with messages)
+        type Group = (usize, Vec<(ShortTypeId, *mut Class)>, Vec<(ShortTypeId, *mut Class)>);
+        let mut groups: Vec<Group> = Vec::new();
+        for &type_id in order {
+            if let Some(class) = classes[type_id].as_mut() {
+                let class_id = ShortTypeId::new(type_id as u16).expect("Class should have a type ID");
+                let group = self.group_of[type_id];
+                let has_messages = class.inbox.len() > 0;
+                let class_ptr = class as *mut Class;
+                let index = match groups.iter().position(|(existing, _, _)| *existing == group) {
+                    Some(index) => index,
+                    None => {
+                        groups.push((group, Vec::new(), Vec::new()));
+                        groups.len() - 1
+                    }
+                };
+                groups[index].1.push((class_id, class_ptr));
+                if has_messages {
+                    groups[index].2.push((class_id, class_ptr));
+                }
+            }
+        }
+        groups.retain(|(_, _, with_messages)| !with_messages.is_empty());
+
+        if groups.len() <= 1 {
+            for (_, _, with_messages) in groups {
+                for (_, class_ptr) in with_messages {
+                    handle(unsafe { &mut *class_ptr }, message_statistics);
+                }
+            }
+            return Vec::new();
+        }
+
+        let handle_ptr = &handle as *const F as *const ();
+        let handle_fn: HandleFn = call_handle::<F>;
+        let n_message_types = message_statistics.len();
+        let tasks = groups
+            .into_iter()
+            .map(|(_, group_classes, with_messages)| Task {
+                class: with_messages[0].0,
+                group_classes,
+                work: Box::new(move || {
+                    let mut message_statistics = vec![0; n_message_types];
+                    for &(class_id, class_ptr) in &with_messages {
+                        set_current_class(class_id);
+                        unsafe { handle_fn(handle_ptr, class_ptr, &mut message_statistics) };
+                    }
+                    message_statistics
+                }) as Box<dyn FnOnce() -> Vec<usize>>,
+            })
+            .collect();
+
+        let mut deferred = Vec::new();
+        for (group_statistics, group_deferred) in self.run(system, tasks) {
+            for (statistic, count) in message_statistics.iter_mut().zip(group_statistics) {
+                *statistic += count;
+            }
+            deferred.extend(group_deferred);
+        }
+        deferred
+    }
+
+    /// Let `receive` handle a broadcast for each of the `actors` of `class`, in one chunk of
+    /// actors per worker. Returns the fate of each actor and what the handlers deferred,
+    /// in the order of the actors.
+    ///
+    /// The actors are split into disjoint chunks before any worker starts, and workers
+    /// get neither the class nor its instance store, only their chunk of actors.
+    pub fn broadcast<F: Fn(*mut ()) -> Fate>(
+        &self,
+        system: *const ActorSystem,
+        class: ShortTypeId,
+        actors: &[*mut ()],
+        receive: F,
+    ) -> (Vec<Fate>, Vec<(ShortTypeId, Deferred)>) {
+        let chunk_size = ((actors.len() + self.jobs.len() - 1) / self.jobs.len()).max(1);
+        let chunks = actors.chunks(chunk_size).map(<[*mut ()]>::to_vec).collect::<Vec<_>>();
+        let receive_ptr = &receive as *const F as *const ();
+        let receive_fn: ReceiveFn = call_receive::<F>;
+        let tasks = chunks
+            .into_iter()
+            .map(|chunk| Task {
+                class,
+                group_classes: Vec::new(),
+                work: Box::new(move || {
+                    chunk
+                        .into_iter()
+                        .map(|actor| unsafe { receive_fn(receive_ptr, actor) })
+                        .collect::<Vec<_>>()
+                }) as Box<dyn FnOnce() -> Vec<Fate>>,
+            })
+            .collect();
+
+        let mut fates = Vec::with_capacity(actors.len());
+        let mut deferred = Vec::new();
+        for (chunk_fates, chunk_deferred) in self.run(system, tasks) {
+            fates.extend(chunk_fates);
+            deferred.extend(chunk_deferred);
+        }
+        (fates, deferred)
+    }
+}
+
+#[cfg(feature = "server")]
+impl Drop for ParallelProcessing {
+    fn drop(&mut self) {
+        self.jobs.clear();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+#[test]
+fn test_parallel_processing_of_several_classes() {
+    use crate::id::TypedID;
+    use crate::test_support::{local_system, Add, Counter, OtherCounter, ThirdCounter};
+
+    let mut system = local_system();
+    system.register::<Counter>();
+    system.register::<OtherCounter>();
+    system.register::<ThirdCounter>();
+    system.add_handler::<Counter, _, _>(
+        |&Add(n), counter, _| {
+            counter.count += n;
+            Fate::Live
+        },
+        false,
+    );
+    // sends to another class, which is deferred until all groups were handled
+    system.add_handler::<OtherCounter, _, _>(
+        |&Add(n), counter, world| {
+            counter.count += n;
+            let third = world.allocate_instance_id::<ThirdCounter>();
+            assert!(third.type_id == world.local_first::<ThirdCounter>().type_id);
+            let counters = world.local_broadcast::<Counter>();
+            world.send(counters, Add(n));
+            Fate::Live
+        },
+        false,
+    );
+    system.add_handler::<ThirdCounter, _, _>(
+        |&Add(n), counter, _| {
+            counter.count += n;
+            Fate::Live
+        },
+        false,
+    );
+    system.enable_parallel_processing(3);
+    system.declare_dependency::<OtherCounter, ThirdCounter>();
+
+    let counters = system.spawn_many((0..50).map(|_| Counter::new(0)));
+    let others = system.spawn_many((0..2).map(|_| OtherCounter::new(0)));
+    let thirds = system.spawn_many((0..50).map(|_| ThirdCounter::new(0)));
+    for id in &counters {
+        system.send(id.as_raw(), Add(1));
+    }
+    for id in &others {
+        system.send(id.as_raw(), Add(10));
+    }
+    for id in &thirds {
+        system.send(id.as_raw(), Add(100));
+    }
+    system.process_all_messages();
+
+    // each counter got its own message and the broadcasts of both others
+    for id in counters {
+        assert_eq!(system.instance::<Counter>(id).unwrap().count, 21);
+    }
+    for id in others {
+        assert_eq!(system.instance::<OtherCounter>(id).unwrap().count, 10);
+    }
+    for id in thirds {
+        assert_eq!(system.instance::<ThirdCounter>(id).unwrap().count, 100);
+    }
+}
+
+#[cfg(feature = "server")]
+#[test]
+fn test_parallel_processing_follows_priorities() {
+    use crate::id::TypedID;
+    use crate::scheduling::SchedulingPolicy;
+    use crate::test_support::{local_system, Add, Counter, OtherCounter, ThirdCounter};
+
+    let mut system = local_system();
+    system.register::<Counter>();
+    system.register::<OtherCounter>();
+    system.register::<ThirdCounter>();
+    // both send to the third counter from their workers, which is delivered in priority order
+    system.add_handler::<Counter, _, _>(
+        |&Add(n), _, world| {
+            let thirds = world.local_broadcast::<ThirdCounter>();
+            world.send(thirds, Add(n));
+            Fate::Live
+        },
+        false,
+    );
+    system.add_handler::<OtherCounter, _, _>(
+        |&Add(n), _, world| {
+            let thirds = world.local_broadcast::<ThirdCounter>();
+            world.send(thirds, Add(n));
+            Fate::Live
+        },
+        false,
+    );
+    system.add_handler::<ThirdCounter, _, _>(
+        |&Add(n), counter, _| {
+            counter.count = counter.count * 10 + n;
+            Fate::Live
+        },
+        false,
+    );
+    system.enable_parallel_processing(2);
+    system.set_scheduling_policy::<OtherCounter>(SchedulingPolicy {
+        priority: 1,
+        ..SchedulingPolicy::default()
+    });
+
+    let counter = system.spawn_many(vec![Counter::new(0)])[0];
+    let other = system.spawn_many(vec![OtherCounter::new(0)])[0];
+    let third = system.spawn_many(vec![ThirdCounter::new(0)])[0];
+    system.send(counter.as_raw(), Add(1));
+    system.send(other.as_raw(), Add(2));
+    system.process_all_messages();
+
+    assert_eq!(system.instance::<ThirdCounter>(third).unwrap().count, 21);
+}
@@ -0,0 +1,214 @@
+use crate::type_registry::ShortTypeId;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// What happens when a message is sent against the declared message flow,
+/// see `ActorSystem::enforce_flow`
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum FlowEnforcement {
+    /// Remember the violation, to be taken with `ActorSystem::take_flow_violations`
+    Report,
+    /// Panic in the handler that sent the message
+    Panic,
+}
+
+/// A message that was sent against the declared message flow
+#[derive(Clone, Debug)]
+pub struct FlowViolation {
+    /// The actor class whose handler sent the message
+    pub sender: String,
+    /// The name of the message type
+    pub message_type: String,
+    /// The receiving actor class (or actor trait)
+    pub recipient: String,
+    /// The networking turn the message was sent in
+    pub n_turns: usize,
+    /// Why the send isn't allowed
+    pub reason: String,
+}
+
+/// A problem of the declared message flow itself, found by `ActorSystem::check_flow`
+#[derive(Clone, Debug, PartialEq)]
+pub enum FlowProblem {
+    /// Classes that (indirectly) send messages to themselves within a turn,
+    /// so the order of their handling depends on message cycles
+    Cycle(Vec<String>),
+    /// A declared send to a class in an earlier phase
+    Backward {
+        /// The sending actor class
+        sender: String,
+        /// The name of the message type
+        message_type: String,
+        /// The receiving actor class (or actor trait)
+        recipient: String,
+    },
+}
+
+/// The declared phases of classes and which classes send which messages to which classes
+pub(crate) struct MessageFlow {
+    phases: HashMap<ShortTypeId, u32>,
+    /// `(sender, message type, recipient)`
+    sends: BTreeSet<(u16, u16, u16)>,
+    enforcement: Option<FlowEnforcement>,
+    violations: Vec<FlowViolation>,
+}
+
+impl MessageFlow {
+    pub fn new() -> MessageFlow {
+        MessageFlow {
+            phases: HashMap::new(),
+            sends: BTreeSet::new(),
+            enforcement: None,
+            violations: Vec::new(),
+        }
+    }
+
+    pub fn set_phase(&mut self, class: ShortTypeId, phase: u32) {
+        self.phases.insert(class, phase);
+    }
+
+    pub fn declare(&mut self, sender: ShortTypeId, message_type: ShortTypeId, recipient: ShortTypeId) {
+        self.sends.insert((sender.as_u16(), message_type.as_u16(), recipient.as_u16()));
+    }
+
+    pub fn enforce(&mut self, enforcement: Option<FlowEnforcement>) {
+        self.enforcement = enforcement;
+    }
+
+    pub fn enforcement(&self) -> Option<FlowEnforcement> {
+        self.enforcement
+    }
+
+    fn is_backward(&self, sender: ShortTypeId, recipient: ShortTypeId) -> bool {
+        match (self.phases.get(&sender), self.phases.get(&recipient)) {
+            (Some(sender_phase), Some(recipient_phase)) => recipient_phase < sender_phase,
+            _ => false,
+        }
+    }
+
+    /// Why a send from a handler of `sender` isn't allowed, if it isn't
+    pub fn check(&self, sender: ShortTypeId, message_type: ShortTypeId, recipient: ShortTypeId) -> Option<String> {
+        self.enforcement?;
+        if self.is_backward(sender, recipient) {
+            Some(format!(
+                "Phase {} sends to phase {}",
+                self.phases[&sender], self.phases[&recipient]
+            ))
+        } else if !self.sends.is_empty()
+            && !self.sends.contains(&(sender.as_u16(), message_type.as_u16(), recipient.as_u16()))
+        {
+            Some("Send wasn't declared".to_owned())
+        } else {
+            None
+        }
+    }
+
+    pub fn report(&mut self, violation: FlowViolation) {
+        self.violations.push(violation);
+    }
+
+    pub fn take_violations(&mut self) -> Vec<FlowViolation> {
+        self.violations.drain(..).collect()
+    }
+
+    /// All declared sends that go to an earlier phase, as `(sender, message type, recipient)`
+    pub fn backward_sends(&self) -> Vec<(ShortTypeId, ShortTypeId, ShortTypeId)> {
+        self.sends
+            .iter()
+            .map(|&(sender, message_type, recipient)| (id(sender), id(message_type), id(recipient)))
+            .filter(|&(sender, _, recipient)| self.is_backward(sender, recipient))
+            .collect()
+    }
+
+    /// All cycles of classes sending to each other, each as its classes in sending order
+    pub fn cycles(&self) -> Vec<Vec<ShortTypeId>> {
+        let mut graph = BTreeMap::<u16, BTreeSet<u16>>::new();
+        for &(sender, _, recipient) in &self.sends {
+            graph.entry(sender).or_insert_with(BTreeSet::new).insert(recipient);
+        }
+        // Tarjan's strongly connected components, every component with more than one class
+        // (or a class sending to itself) is a cycle
+        let mut search = CycleSearch {
+            graph: &graph,
+            index: HashMap::new(),
+            low_link: HashMap::new(),
+            stack: Vec::new(),
+            next_index: 0,
+            components: Vec::new(),
+        };
+        for &class in graph.keys() {
+            if !search.index.contains_key(&class) {
+                search.visit(class);
+            }
+        }
+        search
+            .components
+            .into_iter()
+            .filter(|component| {
+                component.len() > 1 || graph.get(&component[0]).map_or(false, |recipients| recipients.contains(&component[0]))
+            })
+            .map(|component| component.into_iter().map(id).collect())
+            .collect()
+    }
+}
+
+fn id(id: u16) -> ShortTypeId {
+    ShortTypeId::new(id).expect("Type IDs are never 0")
+}
+
+struct CycleSearch<'a> {
+    graph: &'a BTreeMap<u16, BTreeSet<u16>>,
+    index: HashMap<u16, usize>,
+    low_link: HashMap<u16, usize>,
+    stack: Vec<u16>,
+    next_index: usize,
+    components: Vec<Vec<u16>>,
+}
+
+impl<'a> CycleSearch<'a> {
+    fn visit(&mut self, class: u16) {
+        self.index.insert(class, self.next_index);
+        self.low_link.insert(class, self.next_index);
+        self.next_index += 1;
+        self.stack.push(class);
+
+        let graph = self.graph;
+        for &recipient in graph.get(&class).into_iter().flatten() {
+            if !self.index.contains_key(&recipient) {
+                self.visit(recipient);
+                let low_link = self.low_link[&class].min(self.low_link[&recipient]);
+                self.low_link.insert(class, low_link);
+            } else if self.stack.contains(&recipient) {
+                let low_link = self.low_link[&class].min(self.index[&recipient]);
+                self.low_link.insert(class, low_link);
+            }
+        }
+
+        if self.low_link[&class] == self.index[&class] {
+            let start = self.stack.iter().rposition(|&on_stack| on_stack == class).expect("Should be on stack");
+            let mut component = self.stack.split_off(start);
+            component.sort();
+            self.components.push(component);
+        }
+    }
+}
+
+#[test]
+fn test_message_flow() {
+    let mut flow = MessageFlow::new();
+    flow.declare(id(1), id(10), id(2));
+    flow.declare(id(2), id(11), id(3));
+    flow.declare(id(3), id(12), id(2));
+    flow.declare(id(4), id(13), id(4));
+    assert!(flow.cycles() == vec![vec![id(2), id(3)], vec![id(4)]]);
+
+    flow.set_phase(id(1), 0);
+    flow.set_phase(id(2), 1);
+    flow.set_phase(id(3), 0);
+    assert!(flow.backward_sends() == vec![(id(2), id(11), id(3))]);
+
+    assert_eq!(flow.check(id(2), id(11), id(3)), None);
+    flow.enforce(Some(FlowEnforcement::Report));
+    assert!(flow.check(id(2), id(11), id(3)).is_some());
+    assert!(flow.check(id(1), id(12), id(2)).is_some());
+    assert_eq!(flow.check(id(1), id(10), id(2)), None);
+}
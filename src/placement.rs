@@ -0,0 +1,329 @@
+use crate::id::{MachineID, RawID};
+use crate::type_registry::ShortTypeId;
+use compact::CVec;
+use std::collections::{BTreeMap, HashMap};
+
+/// Every this many turns, each machine broadcasts its load to all others
+pub(crate) const LOAD_REPORT_INTERVAL_TURNS: usize = 10;
+
+/// How the machine to spawn new instances of an actor class on is chosen,
+/// set per class with `ActorSystem::set_placement`
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum PlacementPolicy {
+    /// Always spawn on the local machine (the default)
+    Local,
+    /// Take turns between all machines that report their load
+    RoundRobin,
+    /// Spawn on the machine with the fewest instances of the class
+    LeastInstances,
+    /// Spawn on the machine that spent the least time in message handlers recently
+    LeastBusy,
+}
+
+impl Default for PlacementPolicy {
+    fn default() -> PlacementPolicy {
+        PlacementPolicy::Local
+    }
+}
+
+/// Broadcast regularly to the system services of all machines
+#[derive(Compact, Clone)]
+pub(crate) struct LoadReport {
+    pub machine: MachineID,
+    /// The number of instances of each actor class, indexed by type ID
+    pub instances: CVec<u32>,
+    /// Time spent in message handlers since the last report
+    pub busy_ms: f64,
+}
+
+crate::compact_bounds!(LoadReport { instances });
+
+/// Maps a shard key of an actor class (like a spatial cell) to the machine owning it
+pub(crate) type ShardFn = Box<dyn Fn(u64) -> MachineID>;
+
+/// The shard key of a type-erased instance state or message
+pub(crate) type ShardKeyFn = Box<dyn Fn(*const ()) -> u64>;
+
+struct Sharding {
+    key_of: ShardKeyFn,
+    shard_of: ShardFn,
+}
+
+/// The load of all machines, as last reported, and the placement policies
+/// and sharding key functions of classes
+pub(crate) struct Placement {
+    policies: HashMap<ShortTypeId, PlacementPolicy>,
+    sharding: HashMap<ShortTypeId, Sharding>,
+    /// The shard keys of broadcasts, by actor class and message type
+    broadcast_keys: HashMap<(ShortTypeId, ShortTypeId), ShardKeyFn>,
+    loads: BTreeMap<MachineID, LoadReport>,
+    next_round_robin: usize,
+}
+
+impl Placement {
+    pub fn new() -> Placement {
+        Placement {
+            policies: HashMap::new(),
+            sharding: HashMap::new(),
+            broadcast_keys: HashMap::new(),
+            loads: BTreeMap::new(),
+            next_round_robin: 0,
+        }
+    }
+
+    pub fn set_policy(&mut self, class: ShortTypeId, policy: PlacementPolicy) {
+        self.policies.insert(class, policy);
+    }
+
+    pub fn set_sharding(&mut self, class: ShortTypeId, key_of: ShardKeyFn, shard_of: ShardFn) {
+        self.sharding.insert(class, Sharding { key_of, shard_of });
+    }
+
+    pub fn set_broadcast_key(&mut self, class: ShortTypeId, message: ShortTypeId, key_of: ShardKeyFn) {
+        self.broadcast_keys.insert((class, message), key_of);
+    }
+
+    /// The machine owning the shard `key` of `class`, if the class is sharded
+    pub fn shard_owner(&self, class: ShortTypeId, key: u64) -> Option<MachineID> {
+        self.sharding.get(&class).map(|sharding| (sharding.shard_of)(key))
+    }
+
+    /// The machine owning the shard of an instance of `class`, if the class is sharded
+    pub fn owner_of_instance(&self, class: ShortTypeId, state: *const ()) -> Option<MachineID> {
+        self.sharding
+            .get(&class)
+            .map(|sharding| (sharding.shard_of)((sharding.key_of)(state)))
+    }
+
+    /// The machine owning the shard a broadcast of `message` to `class` is meant for,
+    /// if the class is sharded and the message has a shard key
+    pub fn owner_of_broadcast(&self, class: ShortTypeId, message_type: ShortTypeId, message: *const ()) -> Option<MachineID> {
+        if self.broadcast_keys.is_empty() {
+            return None;
+        }
+        let key_of = self.broadcast_keys.get(&(class, message_type))?;
+        self.shard_owner(class, key_of(message))
+    }
+
+    /// Does `class` use a policy that might place instances on other machines?
+    pub fn has_policy(&self, class: ShortTypeId) -> bool {
+        self.policies.get(&class).map_or(false, |&policy| policy != PlacementPolicy::Local)
+    }
+
+    /// Do any classes use a policy that depends on the load of other machines?
+    pub fn needs_load_reports(&self) -> bool {
+        self.policies.values().any(|&policy| policy != PlacementPolicy::Local)
+    }
+
+    pub fn update(&mut self, report: &LoadReport) {
+        self.loads.insert(report.machine, report.clone());
+    }
+
+    pub fn forget(&mut self, machine: MachineID) {
+        self.loads.remove(&machine);
+    }
+
+    /// Choose the machine to spawn an instance of `class` on.
+    /// An instance to spawn near of, if given, always decides the machine.
+    pub fn place(&mut self, class: ShortTypeId, near: Option<RawID>, local: MachineID) -> MachineID {
+        if let Some(near) = near {
+            if !near.is_global_broadcast() && !near.machine.is_multicast_group() {
+                return near.machine;
+            }
+        }
+
+        let policy = self.policies.get(&class).cloned().unwrap_or_default();
+        let chosen = match policy {
+            PlacementPolicy::Local => None,
+            PlacementPolicy::RoundRobin => {
+                let n_machines = self.loads.len();
+                if n_machines == 0 {
+                    None
+                } else {
+                    self.next_round_robin = (self.next_round_robin + 1) % n_machines;
+                    self.loads.keys().nth(self.next_round_robin).cloned()
+                }
+            }
+            PlacementPolicy::LeastInstances => {
+                let class_index = class.as_usize();
+                self.loads
+                    .values()
+                    .min_by_key(|report| report.instances.get(class_index).cloned().unwrap_or(0))
+                    .map(|report| report.machine)
+            }
+            PlacementPolicy::LeastBusy => self
+                .loads
+                .values()
+                .min_by(|a, b| {
+                    a.busy_ms
+                        .partial_cmp(&b.busy_ms)
+                        .unwrap_or(::std::cmp::Ordering::Equal)
+                })
+                .map(|report| report.machine),
+        };
+
+        chosen.unwrap_or(local)
+    }
+}
+
+#[test]
+fn test_placement_policies() {
+    let class = ShortTypeId::new(2).unwrap();
+    let mut placement = Placement::new();
+    assert_eq!(placement.place(class, None, MachineID(0)), MachineID(0));
+
+    for &(machine, n_instances, busy_ms) in &[(0, 5, 1.0), (1, 2, 3.0), (2, 9, 0.5)] {
+        placement.update(&LoadReport {
+            machine: MachineID(machine),
+            instances: vec![0, 0, n_instances].into(),
+            busy_ms,
+        });
+    }
+
+    placement.set_policy(class, PlacementPolicy::LeastInstances);
+    assert_eq!(placement.place(class, None, MachineID(0)), MachineID(1));
+    placement.set_policy(class, PlacementPolicy::LeastBusy);
+    assert_eq!(placement.place(class, None, MachineID(0)), MachineID(2));
+
+    placement.set_policy(class, PlacementPolicy::RoundRobin);
+    let chosen = (0..3)
+        .map(|_| placement.place(class, None, MachineID(0)))
+        .collect::<Vec<_>>();
+    assert_eq!(chosen, vec![MachineID(1), MachineID(2), MachineID(0)]);
+
+    let near = RawID::new(class, 3, MachineID(2), 0);
+    assert_eq!(placement.place(class, Some(near), MachineID(0)), MachineID(2));
+}
+
+#[test]
+fn test_sharding() {
+    let class = ShortTypeId::new(2).unwrap();
+    let other_class = ShortTypeId::new(3).unwrap();
+    let mut placement = Placement::new();
+    assert_eq!(placement.shard_owner(class, 5), None);
+
+    let key_of = |state: *const ()| unsafe { *(state as *const u64) };
+    placement.set_sharding(class, Box::new(key_of), Box::new(|cell| MachineID((cell % 3) as u16)));
+    assert_eq!(placement.shard_owner(class, 5), Some(MachineID(2)));
+    assert_eq!(placement.shard_owner(class, 6), Some(MachineID(0)));
+    assert_eq!(placement.shard_owner(other_class, 5), None);
+
+    let cell = 4u64;
+    let cell_ptr = &cell as *const u64 as *const ();
+    assert_eq!(placement.owner_of_instance(class, cell_ptr), Some(MachineID(1)));
+    assert_eq!(placement.owner_of_instance(other_class, cell_ptr), None);
+
+    let message_type = ShortTypeId::new(7).unwrap();
+    assert_eq!(placement.owner_of_broadcast(class, message_type, cell_ptr), None);
+    placement.set_broadcast_key(class, message_type, Box::new(key_of));
+    assert_eq!(placement.owner_of_broadcast(class, message_type, cell_ptr), Some(MachineID(1)));
+    assert_eq!(placement.owner_of_broadcast(other_class, message_type, cell_ptr), None);
+}
+
+#[test]
+fn test_sharding_on_spawn_and_broadcast() {
+    use crate::id::TypedID;
+    use crate::messaging::Fate;
+    use crate::test_support::{local_system, Add, Counter, CounterID, OtherCounter};
+
+    let mut system = local_system();
+    system.register::<Counter>();
+    system.register::<OtherCounter>();
+    system.add_spawner::<Counter, _, _>(
+        |&Add(n), world| {
+            let mut counter = Counter::new(n);
+            counter.id = CounterID::from_raw(world.allocate_instance_id::<Counter>());
+            counter
+        },
+        false,
+    );
+    system.add_handler::<OtherCounter, _, _>(
+        |&Add(n), counter, _| {
+            counter.count += n;
+            Fate::Live
+        },
+        false,
+    );
+    let in_shard = |key: u64| MachineID((key % 2) as u16);
+    system.set_sharding::<Counter, _, _>(|counter| u64::from(counter.count), in_shard);
+    system.set_sharding::<OtherCounter, _, _>(|_| 0, in_shard);
+    system.shard_broadcasts_by::<OtherCounter, Add, _>(|&Add(n)| u64::from(n));
+
+    // the instance in the shard of machine 1 moves there once spawned
+    let counters = system.world().local_broadcast::<Counter>();
+    system.send(counters, Add(2));
+    system.send(counters, Add(3));
+    system.process_all_messages();
+    assert_eq!(system.instance_count::<Counter>(), 1);
+    system.world().for_each_instance::<Counter, _>(|counter| assert_eq!(counter.count, 2));
+
+    // broadcasts only reach the machine owning their shard
+    system.spawn_many(vec![OtherCounter::new(0)]);
+    let all_other_counters = system.world().global_broadcast::<OtherCounter>();
+    system.send(all_other_counters, Add(4));
+    system.send(all_other_counters, Add(5));
+    system.process_all_messages();
+    system.world().for_each_instance::<OtherCounter, _>(|counter| assert_eq!(counter.count, 4));
+}
+
+#[test]
+fn test_placement_on_spawn() {
+    use crate::id::TypedID;
+    use crate::test_support::{local_system, Add, Counter, CounterID};
+
+    let mut system = local_system();
+    system.register::<Counter>();
+    system.add_spawner::<Counter, _, _>(
+        |&Add(n), world| {
+            let mut counter = Counter::new(n);
+            counter.id = CounterID::from_raw(world.allocate_instance_id::<Counter>());
+            counter
+        },
+        false,
+    );
+    system.set_placement::<Counter>(PlacementPolicy::LeastBusy);
+    let services = system.services_id(MachineID(0));
+    for &(machine, busy_ms) in &[(0, 5.0), (1, 1.0)] {
+        let instances = CVec::new();
+        system.send(services, LoadReport { machine: MachineID(machine), instances, busy_ms });
+    }
+    system.process_all_messages();
+
+    // spawned by a local broadcast, the instance moves to the least busy machine
+    let counters = system.world().local_broadcast::<Counter>();
+    system.send(counters, Add(1));
+    system.process_all_messages();
+    assert_eq!(system.instance_count::<Counter>(), 0);
+
+    // spawned by a message to the class on this machine, it stays
+    let counter_class = system.world().local_first::<Counter>();
+    system.send(counter_class, Add(2));
+    system.process_all_messages();
+    assert_eq!(system.instance_count::<Counter>(), 1);
+}
+
+#[test]
+fn test_placement_of_spawn_many() {
+    use crate::test_support::{local_system, Counter, OtherCounter};
+
+    let mut system = local_system();
+    system.register::<Counter>();
+    system.register::<OtherCounter>();
+    system.set_sharding::<Counter, _, _>(|counter| u64::from(counter.count), |key| MachineID((key % 2) as u16));
+    system.set_placement::<OtherCounter>(PlacementPolicy::LeastBusy);
+    let services = system.services_id(MachineID(0));
+    for &(machine, busy_ms) in &[(0, 5.0), (1, 1.0)] {
+        let instances = CVec::new();
+        system.send(services, LoadReport { machine: MachineID(machine), instances, busy_ms });
+    }
+    system.process_all_messages();
+
+    // the instances in the shard of machine 1 move there
+    system.spawn_many((0..6).map(Counter::new));
+    // and those of a class with a placement policy to the least busy machine
+    system.spawn_many(vec![OtherCounter::new(0), OtherCounter::new(1)]);
+    system.process_all_messages();
+    assert_eq!(system.instance_count::<Counter>(), 3);
+    system.world().for_each_instance::<Counter, _>(|counter| assert_eq!(counter.count % 2, 0));
+    assert_eq!(system.instance_count::<OtherCounter>(), 0);
+}
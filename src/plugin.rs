@@ -0,0 +1,81 @@
+use crate::actor_system::ActorSystem;
+
+/// A module (a mod of a game, for example) that adds actor classes, message types and handlers
+/// to an `ActorSystem` that is already running, see `ActorSystem::load_plugin`.
+///
+/// All types a plugin registers for the first time are namespaced with its name
+/// in the type registry, so same-named types of different plugins don't get mixed up,
+/// and their type IDs are replicated to peers like all types registered after setup.
+/// Peers need to load the same plugin before they receive messages for its classes.
+pub trait Plugin {
+    /// A name that is unique among all plugins
+    fn name(&self) -> &str;
+
+    /// Register classes, traits, handlers and spawners, like during setup of the system
+    fn setup(&self, system: &mut ActorSystem);
+}
+
+/// Export `$constructor` (a function returning a `Plugin`) from a dynamic library,
+/// so it can be loaded with `ActorSystem::load_plugin_library`.
+///
+/// The library needs to be built with the same compiler and version of kay as the host.
+/// Plugins compiled to WebAssembly need to be instantiated by the embedder,
+/// which can then load a `Plugin` forwarding to them with `ActorSystem::load_plugin`.
+#[macro_export]
+macro_rules! declare_plugin {
+    ($constructor:path) => {
+        #[no_mangle]
+        pub extern "C" fn kay_plugin() -> *mut Box<dyn $crate::Plugin> {
+            let plugin: Box<dyn $crate::Plugin> = Box::new($constructor());
+            Box::into_raw(Box::new(plugin))
+        }
+    };
+}
+
+#[cfg(all(feature = "server", unix))]
+mod dynamic {
+    use super::Plugin;
+    use std::ffi::{CStr, CString};
+    use std::os::raw::{c_char, c_int, c_void};
+
+    const RTLD_NOW: c_int = 2;
+
+    #[link(name = "dl")]
+    extern "C" {
+        fn dlopen(filename: *const c_char, flags: c_int) -> *mut c_void;
+        fn dlsym(handle: *mut c_void, symbol: *const c_char) -> *mut c_void;
+        fn dlerror() -> *mut c_char;
+    }
+
+    fn last_error() -> String {
+        unsafe {
+            let error = dlerror();
+            if error.is_null() {
+                "unknown error".to_owned()
+            } else {
+                CStr::from_ptr(error).to_string_lossy().into_owned()
+            }
+        }
+    }
+
+    /// Load the plugin exported with `declare_plugin!` from a dynamic library.
+    /// The library is never unloaded, since handlers of its classes point into it.
+    pub fn load_library(path: &str) -> Result<Box<dyn Plugin>, String> {
+        let c_path = CString::new(path).map_err(|_| format!("Invalid plugin path {}", path))?;
+        unsafe {
+            let handle = dlopen(c_path.as_ptr(), RTLD_NOW);
+            if handle.is_null() {
+                return Err(format!("Couldn't load plugin {}: {}", path, last_error()));
+            }
+            let symbol = dlsym(handle, b"kay_plugin\0".as_ptr() as *const c_char);
+            if symbol.is_null() {
+                return Err(format!("{} doesn't declare a plugin: {}", path, last_error()));
+            }
+            let constructor: extern "C" fn() -> *mut Box<dyn Plugin> = ::std::mem::transmute(symbol);
+            Ok(*Box::from_raw(constructor()))
+        }
+    }
+}
+
+#[cfg(all(feature = "server", unix))]
+pub(crate) use self::dynamic::load_library;
@@ -0,0 +1,73 @@
+use std::collections::HashMap;
+
+/// How much work the instances of one actor class did,
+/// since profiling was enabled or last reset
+#[derive(Copy, Clone, Default, Debug, PartialEq)]
+pub struct ClassProfile {
+    /// Number of messages handled (a broadcast counts once)
+    pub messages: usize,
+    /// Wall time spent in message handlers, in milliseconds
+    pub handler_ms: f64,
+    /// The longest time handling a single message took, in milliseconds
+    pub max_handler_ms: f64,
+    /// Number of instances at the end of the last turn
+    pub instances: usize,
+    /// Number of instances, averaged over all profiled turns
+    pub mean_instances: f64,
+    instance_turns: usize,
+}
+
+impl ClassProfile {
+    pub(crate) fn count_message(&mut self, duration_ms: f64) {
+        self.messages += 1;
+        self.handler_ms += duration_ms;
+        self.max_handler_ms = self.max_handler_ms.max(duration_ms);
+    }
+
+    pub(crate) fn count_instances(&mut self, instances: usize) {
+        self.instances = instances;
+        self.mean_instances += (instances as f64 - self.mean_instances) / (self.instance_turns + 1) as f64;
+        self.instance_turns += 1;
+    }
+}
+
+/// Per actor class statistics, returned by `ActorSystem::profiling_report`
+#[derive(Clone, Default, Debug)]
+pub struct ProfilingReport {
+    /// Statistics by actor class name
+    pub per_class: HashMap<String, ClassProfile>,
+    /// Number of turns profiled
+    pub turns: usize,
+}
+
+impl ProfilingReport {
+    /// Actor class names with their statistics, those that spent the most time in handlers first
+    pub fn by_handler_time(&self) -> Vec<(&str, &ClassProfile)> {
+        let mut classes = self
+            .per_class
+            .iter()
+            .map(|(name, profile)| (name.as_str(), profile))
+            .collect::<Vec<_>>();
+        classes.sort_by(|(_, a), (_, b)| {
+            b.handler_ms
+                .partial_cmp(&a.handler_ms)
+                .unwrap_or(::std::cmp::Ordering::Equal)
+        });
+        classes
+    }
+}
+
+#[test]
+fn test_class_profile() {
+    let mut profile = ClassProfile::default();
+    profile.count_message(2.0);
+    profile.count_message(0.5);
+    assert_eq!(profile.messages, 2);
+    assert_eq!(profile.handler_ms, 2.5);
+    assert_eq!(profile.max_handler_ms, 2.0);
+
+    profile.count_instances(10);
+    profile.count_instances(20);
+    assert_eq!(profile.instances, 20);
+    assert_eq!(profile.mean_instances, 15.0);
+}
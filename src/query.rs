@@ -0,0 +1,234 @@
+use crate::actor_system::World;
+use crate::id::MachineID;
+use crate::messaging::{Correlation, Message};
+use crate::time::now_ms;
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+
+thread_local! {
+    /// The correlation of the packet whose handler is running on this thread
+    static HANDLED_CORRELATION: Cell<Correlation> = Cell::new(Correlation::none());
+}
+
+/// Restores the correlation of the outer handler (or none) once a handler is done, even if it panics
+struct HandledCorrelation(Correlation);
+
+impl Drop for HandledCorrelation {
+    fn drop(&mut self) {
+        let outer = self.0;
+        HANDLED_CORRELATION.with(|handled| handled.set(outer));
+    }
+}
+
+/// Run a handler of a packet with `correlation`, which it can answer with `World::asker`
+pub(crate) fn handling<T, F: FnOnce() -> T>(correlation: Correlation, handle: F) -> T {
+    let _outer = HandledCorrelation(HANDLED_CORRELATION.with(|handled| handled.replace(correlation)));
+    handle()
+}
+
+/// The correlation of the packet whose handler is running on this thread
+pub(crate) fn handled_correlation() -> Correlation {
+    HANDLED_CORRELATION.with(Cell::get)
+}
+
+/// Who asked a query, to send the reply to, see `World::asker`
+#[derive(Copy, Clone, Debug)]
+pub struct Asker {
+    correlation: Correlation,
+}
+
+impl Asker {
+    pub(crate) fn new(correlation: Correlation) -> Asker {
+        Asker { correlation }
+    }
+
+    /// The machine that asked the query
+    pub fn machine(&self) -> MachineID {
+        self.correlation.machine
+    }
+
+    /// Send the reply to the asking machine, completing its `QueryHandle`.
+    /// The reply type needs to be registered with `ActorSystem::register_query_reply`.
+    pub fn reply<R: Message>(&self, reply: R, world: &mut World) {
+        world.send_reply(self.correlation, reply);
+    }
+}
+
+/// The outcome of a query, so far
+pub enum QueryStatus<R> {
+    /// Neither a reply arrived nor did the query time out yet
+    Pending,
+    /// The reply arrived (only returned once)
+    Replied(R),
+    /// No reply arrived in time
+    TimedOut,
+}
+
+/// Returned by the `QueryHandle` future when no reply arrived in time
+#[derive(Copy, Clone, Debug)]
+pub struct QueryTimedOut;
+
+struct QuerySlot<R> {
+    reply: Option<R>,
+    timed_out: bool,
+    waker: Option<Waker>,
+}
+
+/// The completion handle of a query, which can either be polled
+/// directly with `status` or awaited as a future
+pub struct QueryHandle<R> {
+    slot: Rc<RefCell<QuerySlot<R>>>,
+}
+
+impl<R> QueryHandle<R> {
+    /// Check whether the reply arrived, taking it if so
+    pub fn status(&self) -> QueryStatus<R> {
+        let mut slot = self.slot.borrow_mut();
+        match slot.reply.take() {
+            Some(reply) => QueryStatus::Replied(reply),
+            None if slot.timed_out => QueryStatus::TimedOut,
+            None => QueryStatus::Pending,
+        }
+    }
+}
+
+impl<R> Future for QueryHandle<R> {
+    type Output = Result<R, QueryTimedOut>;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        match self.status() {
+            QueryStatus::Replied(reply) => Poll::Ready(Ok(reply)),
+            QueryStatus::TimedOut => Poll::Ready(Err(QueryTimedOut)),
+            QueryStatus::Pending => {
+                self.slot.borrow_mut().waker = Some(context.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// A query waiting for its reply, with the reply type erased
+trait PendingReply {
+    fn deliver(&self, reply: &dyn Any);
+    fn expire(&self);
+}
+
+impl<R: Message> PendingReply for Rc<RefCell<QuerySlot<R>>> {
+    fn deliver(&self, reply: &dyn Any) {
+        let mut slot = self.borrow_mut();
+        slot.reply = reply.downcast_ref::<R>().cloned();
+        if let Some(waker) = slot.waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn expire(&self) {
+        let mut slot = self.borrow_mut();
+        slot.timed_out = true;
+        if let Some(waker) = slot.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// The queries asked from this machine that are still waiting for replies
+pub(crate) struct Queries {
+    next_correlation: u32,
+    pending: HashMap<u32, (f64, Box<dyn PendingReply>)>,
+}
+
+impl Queries {
+    pub fn new() -> Queries {
+        Queries {
+            next_correlation: 1,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Start waiting for a reply to a query asked by `machine`, which needs to arrive
+    /// within `timeout_ms`. Returns the correlation to send the request with.
+    pub fn ask<R: Message>(&mut self, machine: MachineID, timeout_ms: f64) -> (Correlation, QueryHandle<R>) {
+        let correlation = self.next_correlation;
+        // zero is left for packets that aren't part of a query
+        self.next_correlation = self.next_correlation.checked_add(1).unwrap_or(1);
+
+        let slot = Rc::new(RefCell::new(QuerySlot {
+            reply: None,
+            timed_out: false,
+            waker: None,
+        }));
+        self.pending.insert(
+            correlation,
+            (now_ms() + timeout_ms, Box::new(slot.clone()) as Box<dyn PendingReply>),
+        );
+
+        (Correlation::new(correlation, machine), QueryHandle { slot })
+    }
+
+    /// Complete a pending query, ignoring replies that come too late
+    pub fn reply(&mut self, correlation: u32, reply: &dyn Any) {
+        if let Some((_, pending)) = self.pending.remove(&correlation) {
+            pending.deliver(reply);
+        }
+    }
+
+    /// Time out all queries whose deadline passed (in the order they were asked),
+    /// returns how many timed out
+    pub fn expire(&mut self, now_ms: f64) -> usize {
+        let mut expired = self
+            .pending
+            .iter()
+            .filter(|&(_, &(deadline_ms, _))| deadline_ms < now_ms)
+            .map(|(&correlation, _)| correlation)
+            .collect::<Vec<_>>();
+        expired.sort();
+        let n_expired = expired.len();
+        for correlation in expired {
+            if let Some((_, pending)) = self.pending.remove(&correlation) {
+                pending.expire();
+            }
+        }
+        n_expired
+    }
+}
+
+#[test]
+fn test_queries_are_answered_by_correlation() {
+    use crate::id::TypedID;
+    use crate::messaging::Fate;
+    use crate::test_support::{local_system, Add, Counter};
+    use std::time::Duration;
+
+    let mut system = local_system();
+    system.register::<Counter>();
+    system.add_handler::<Counter, _, _>(
+        |add: &Add, counter, world| {
+            counter.count += add.0;
+            if let Some(asker) = world.asker() {
+                asker.reply(counter.count, world);
+            }
+            Fate::Live
+        },
+        false,
+    );
+    system.register_query_reply::<u32>();
+    let counter = system.spawn_many(vec![Counter::new(0)])[0].as_raw();
+
+    let mut world = system.world();
+    world.send(counter, Add(1));
+    let first = world.query::<u32>(counter, Add(2), Duration::from_secs(10));
+    let second = world.query::<u32>(counter, Add(3), Duration::from_secs(10));
+    system.process_all_messages();
+
+    for (handle, expected) in vec![(first, 3), (second, 6)] {
+        match handle.status() {
+            QueryStatus::Replied(count) => assert_eq!(count, expected),
+            _ => panic!("Query should have been answered"),
+        }
+    }
+}
@@ -0,0 +1,100 @@
+use crate::id::RawID;
+use crate::state_hash::xxh64;
+use byteorder::{ByteOrder, LittleEndian};
+
+/// A small, fast random number generator (SplitMix64) whose streams are derived
+/// from the random seed of the actor system, the current networking turn and an actor ID
+/// (see `World::rng`), so all machines in lockstep draw exactly the same numbers.
+/// Not suitable for cryptography.
+#[derive(Clone, Debug)]
+pub struct DeterministicRng {
+    state: u64,
+}
+
+impl DeterministicRng {
+    /// Create a generator from an explicit seed
+    pub fn from_seed(seed: u64) -> DeterministicRng {
+        DeterministicRng { state: seed }
+    }
+
+    pub(crate) fn for_actor(world_seed: u64, n_turns: usize, id: RawID, stream: u64) -> DeterministicRng {
+        let mut key = [0u8; 25];
+        LittleEndian::write_u64(&mut key[0..8], n_turns as u64);
+        LittleEndian::write_u32(&mut key[8..12], id.instance_id);
+        LittleEndian::write_u16(&mut key[12..14], id.type_id.as_u16());
+        LittleEndian::write_u16(&mut key[14..16], id.machine.0);
+        key[16] = id.version;
+        LittleEndian::write_u64(&mut key[17..25], stream);
+        DeterministicRng::from_seed(xxh64(&key, world_seed))
+    }
+
+    /// The next random `u64`, uniformly distributed
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// The next random `u32`, uniformly distributed
+    pub fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    /// A random float in `[0, 1)`
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// A random float in `[0, 1)`
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u32() >> 8) as f32 / (1u32 << 24) as f32
+    }
+
+    /// A random integer in `[low, high)`, panics if the range is empty
+    pub fn range(&mut self, low: u64, high: u64) -> u64 {
+        assert!(low < high, "Random range {}..{} is empty", low, high);
+        low + ((u128::from(self.next_u64()) * u128::from(high - low)) >> 64) as u64
+    }
+
+    /// `true` with probability `p`
+    pub fn chance(&mut self, p: f64) -> bool {
+        self.next_f64() < p
+    }
+
+    /// A random element of `items`, `None` if it is empty
+    pub fn choose<'a, T>(&mut self, items: &'a [T]) -> Option<&'a T> {
+        if items.is_empty() {
+            None
+        } else {
+            Some(&items[self.range(0, items.len() as u64) as usize])
+        }
+    }
+}
+
+#[test]
+fn test_deterministic_rng() {
+    use crate::id::MachineID;
+    use crate::type_registry::ShortTypeId;
+
+    let id = RawID::new(ShortTypeId::new(3).unwrap(), 7, MachineID(1), 0);
+    let other_id = RawID::new(ShortTypeId::new(3).unwrap(), 8, MachineID(1), 0);
+
+    let draw = |n_turns, id, stream| {
+        let mut rng = DeterministicRng::for_actor(42, n_turns, id, stream);
+        (0..4).map(|_| rng.next_u64()).collect::<Vec<_>>()
+    };
+    assert_eq!(draw(10, id, 0), draw(10, id, 0));
+    assert_ne!(draw(10, id, 0), draw(11, id, 0));
+    assert_ne!(draw(10, id, 0), draw(10, other_id, 0));
+    assert_ne!(draw(10, id, 0), draw(10, id, 1));
+
+    let mut rng = DeterministicRng::from_seed(1);
+    for _ in 0..1000 {
+        let value = rng.range(5, 8);
+        assert!(value >= 5 && value < 8);
+        let float = rng.next_f64();
+        assert!(float >= 0.0 && float < 1.0);
+    }
+}
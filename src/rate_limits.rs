@@ -0,0 +1,236 @@
+use crate::actor_system::World;
+use crate::id::RawID;
+use crate::migration::{ForwardFn, Forwarding};
+use crate::type_registry::ShortTypeId;
+use std::collections::HashMap;
+
+/// What happens to messages to an instance beyond its rate limit,
+/// see `ActorSystem::rate_limit`
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum RateLimitAction {
+    /// Drop the message silently
+    Drop,
+    /// Handle the message in the next turn (where it counts against the limit again)
+    Defer,
+    /// Record the message as a dead letter, see `ActorSystem::take_dead_letters`
+    DeadLetter,
+}
+
+#[derive(Copy, Clone)]
+struct RateLimit {
+    max_messages: usize,
+    window_turns: usize,
+    action: RateLimitAction,
+}
+
+/// How often a rate limit of an actor class was exceeded, by what happened to the messages
+#[derive(Clone, Debug, Default)]
+pub struct RateLimitCounters {
+    /// The actor class whose instances are limited
+    pub class: String,
+    /// The name of the limited message type
+    pub message_type: String,
+    /// Messages that were dropped
+    pub dropped: usize,
+    /// Messages that were deferred to the next turn (counted each time)
+    pub deferred: usize,
+    /// Messages that were recorded as dead letters
+    pub dead_lettered: usize,
+}
+
+/// The rate limits of the message types of one actor class and the messages each
+/// instance received in the current window. Kept per class, so classes can be
+/// handled on different threads.
+pub(crate) struct RateLimits {
+    limits: HashMap<ShortTypeId, RateLimit>,
+    /// Messages received in the current window: `(window, count)`
+    received: HashMap<(RawID, ShortTypeId), (usize, usize)>,
+    /// `(dropped, deferred, dead lettered)`
+    counters: HashMap<ShortTypeId, (usize, usize, usize)>,
+    deferred: Vec<(RawID, Forwarding)>,
+    n_turns: usize,
+}
+
+impl RateLimits {
+    pub fn new() -> RateLimits {
+        RateLimits {
+            limits: HashMap::new(),
+            received: HashMap::new(),
+            counters: HashMap::new(),
+            deferred: Vec::new(),
+            n_turns: 0,
+        }
+    }
+
+    pub fn set(&mut self, message_type: ShortTypeId, max_messages: usize, window_turns: usize, action: RateLimitAction) {
+        assert!(window_turns > 0, "Rate limit windows need to be at least one turn");
+        self.limits.insert(
+            message_type,
+            RateLimit {
+                max_messages,
+                window_turns,
+                action,
+            },
+        );
+    }
+
+    /// Called before a turn, forgets the counts of past windows
+    /// and takes the messages deferred to this turn
+    pub fn start_turn(&mut self, n_turns: usize) -> Vec<(RawID, Forwarding)> {
+        self.n_turns = n_turns;
+        let limits = &self.limits;
+        self.received.retain(|&(_, message_type), &mut (window, _)| {
+            limits
+                .get(&message_type)
+                .map_or(false, |limit| window == n_turns / limit.window_turns)
+        });
+        self.deferred.drain(..).collect()
+    }
+
+    /// Count a message to `recipient_id` and return whether it can be handled.
+    /// Otherwise the action of the rate limit is taken.
+    pub fn admit(
+        &mut self,
+        recipient_id: RawID,
+        message_type: ShortTypeId,
+        packet_ptr: *const (),
+        forward: &ForwardFn,
+        world: &mut World,
+    ) -> bool {
+        let limit = match self.limits.get(&message_type) {
+            Some(limit) => *limit,
+            None => return true,
+        };
+        let window = self.n_turns / limit.window_turns;
+        let received = self.received.entry((recipient_id, message_type)).or_insert((window, 0));
+        if received.0 != window {
+            *received = (window, 0);
+        }
+        if received.1 < limit.max_messages {
+            received.1 += 1;
+            return true;
+        }
+
+        let counters = self.counters.entry(message_type).or_insert((0, 0, 0));
+        match limit.action {
+            RateLimitAction::Drop => counters.0 += 1,
+            RateLimitAction::Defer => {
+                counters.1 += 1;
+                self.deferred.push((recipient_id, forward(packet_ptr)));
+            }
+            RateLimitAction::DeadLetter => {
+                counters.2 += 1;
+                world.dead_letter(recipient_id, message_type, packet_ptr);
+            }
+        }
+        false
+    }
+
+    /// The counters of all limited message types that were exceeded so far,
+    /// using `message_name` to name message types
+    pub fn counters<F: Fn(ShortTypeId) -> String>(&self, class: &str, message_name: F) -> Vec<RateLimitCounters> {
+        self.counters
+            .iter()
+            .map(|(&message_type, &(dropped, deferred, dead_lettered))| RateLimitCounters {
+                class: class.to_owned(),
+                message_type: message_name(message_type),
+                dropped,
+                deferred,
+                dead_lettered,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+fn limited_counter(action: RateLimitAction) -> (crate::actor_system::ActorSystem, crate::test_support::CounterID) {
+    use crate::messaging::Fate;
+    use crate::test_support::{local_system, Add, Counter};
+
+    let mut system = local_system();
+    system.register::<Counter>();
+    system.add_handler::<Counter, _, _>(
+        |&Add(n), counter, _| {
+            counter.count += n;
+            Fate::Live
+        },
+        false,
+    );
+    system.rate_limit::<Counter, Add>(2, 2, action);
+    let counter = system.spawn_many(vec![Counter::new(0)])[0];
+    (system, counter)
+}
+
+#[test]
+fn test_rate_limited_messages_are_dropped() {
+    use crate::id::TypedID;
+    use crate::test_support::{Add, Counter};
+
+    let (mut system, counter) = limited_counter(RateLimitAction::Drop);
+    let send_five = |system: &mut crate::actor_system::ActorSystem| {
+        for _ in 0..5 {
+            system.send(counter.as_raw(), Add(1));
+        }
+        system.process_all_messages();
+        system.networking_finish_turn();
+    };
+
+    send_five(&mut system);
+    assert_eq!(system.instance::<Counter>(counter).unwrap().count, 2);
+    // the window of two turns isn't over yet
+    send_five(&mut system);
+    assert_eq!(system.instance::<Counter>(counter).unwrap().count, 2);
+    send_five(&mut system);
+    assert_eq!(system.instance::<Counter>(counter).unwrap().count, 4);
+
+    let counters = system.rate_limit_counters();
+    assert_eq!(counters.len(), 1);
+    assert_eq!((counters[0].dropped, counters[0].deferred, counters[0].dead_lettered), (11, 0, 0));
+    // broadcasts aren't limited
+    let broadcast = system.world().local_broadcast::<Counter>();
+    for _ in 0..5 {
+        system.send(broadcast, Add(1));
+    }
+    system.process_all_messages();
+    assert_eq!(system.instance::<Counter>(counter).unwrap().count, 9);
+}
+
+#[test]
+fn test_rate_limited_messages_are_deferred() {
+    use crate::id::TypedID;
+    use crate::test_support::{Add, Counter};
+
+    let (mut system, counter) = limited_counter(RateLimitAction::Defer);
+    for _ in 0..5 {
+        system.send(counter.as_raw(), Add(1));
+    }
+    let mut counts = Vec::new();
+    for _ in 0..5 {
+        system.process_all_messages();
+        system.networking_finish_turn();
+        counts.push(system.instance::<Counter>(counter).unwrap().count);
+    }
+
+    // deferred messages count against the limit of the next turn's window again
+    assert_eq!(counts, vec![2, 2, 4, 4, 5]);
+    assert_eq!(system.rate_limit_counters()[0].deferred, 3 + 3 + 1 + 1);
+    assert_eq!(system.dead_letter_count(), 0);
+}
+
+#[test]
+fn test_rate_limited_messages_become_dead_letters() {
+    use crate::id::TypedID;
+    use crate::test_support::{Add, Counter};
+
+    let (mut system, counter) = limited_counter(RateLimitAction::DeadLetter);
+    for _ in 0..5 {
+        system.send(counter.as_raw(), Add(1));
+    }
+    system.process_all_messages();
+
+    assert_eq!(system.instance::<Counter>(counter).unwrap().count, 2);
+    let dead_letters = system.take_dead_letters();
+    assert_eq!(dead_letters.len(), 3);
+    assert!(dead_letters.iter().all(|letter| letter.recipient == counter.as_raw()));
+    assert_eq!(system.rate_limit_counters()[0].dead_lettered, 3);
+}
@@ -0,0 +1,198 @@
+use crate::actor_system::ActorSystem;
+use std::collections::{BTreeMap, HashMap};
+
+/// Returned when scheduling a message, can be used to cancel it before it is sent
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct ScheduledMessage(u64);
+
+/// When a scheduled message is due
+#[derive(Copy, Clone)]
+enum Due {
+    /// After the networking turn with this number started
+    Turn(usize),
+    /// At this wall clock time, in microseconds since the Unix epoch
+    Time(u64),
+}
+
+type Sending = Box<dyn FnOnce(&mut ActorSystem)>;
+
+/// Messages that are sent later, either in a number of turns or after a delay.
+/// Kept ordered by when they are due, with the order of scheduling as a tie breaker.
+pub(crate) struct Scheduler {
+    next_id: u64,
+    by_turn: BTreeMap<(usize, u64), Sending>,
+    by_time: BTreeMap<(u64, u64), Sending>,
+    due: HashMap<u64, Due>,
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Scheduler {
+            next_id: 0,
+            by_turn: BTreeMap::new(),
+            by_time: BTreeMap::new(),
+            due: HashMap::new(),
+        }
+    }
+
+    pub fn in_turn(&mut self, n_turns: usize, sending: Sending) -> ScheduledMessage {
+        self.schedule(Due::Turn(n_turns), sending)
+    }
+
+    pub fn at_time(&mut self, time_ms: f64, sending: Sending) -> ScheduledMessage {
+        self.schedule(Due::Time((time_ms * 1000.0) as u64), sending)
+    }
+
+    fn schedule(&mut self, due: Due, sending: Sending) -> ScheduledMessage {
+        let id = self.next_id;
+        self.next_id += 1;
+        match due {
+            Due::Turn(n_turns) => self.by_turn.insert((n_turns, id), sending),
+            Due::Time(time) => self.by_time.insert((time, id), sending),
+        };
+        self.due.insert(id, due);
+        ScheduledMessage(id)
+    }
+
+    /// Cancel a scheduled message, returns false if it was already sent or cancelled
+    pub fn cancel(&mut self, scheduled: ScheduledMessage) -> bool {
+        let id = scheduled.0;
+        match self.due.remove(&id) {
+            Some(Due::Turn(n_turns)) => self.by_turn.remove(&(n_turns, id)).is_some(),
+            Some(Due::Time(time)) => self.by_time.remove(&(time, id)).is_some(),
+            None => false,
+        }
+    }
+
+    /// Take all messages that are due at turn `n_turns` or time `now_ms`,
+    /// messages due by turn first
+    pub fn take_due(&mut self, n_turns: usize, now_ms: f64) -> Vec<Sending> {
+        let now = (now_ms * 1000.0) as u64;
+        let still_to_come_by_turn = self.by_turn.split_off(&(n_turns + 1, 0));
+        let due_by_turn = ::std::mem::replace(&mut self.by_turn, still_to_come_by_turn);
+        let still_to_come_by_time = self.by_time.split_off(&(now + 1, 0));
+        let due_by_time = ::std::mem::replace(&mut self.by_time, still_to_come_by_time);
+
+        let mut sendings = Vec::with_capacity(due_by_turn.len() + due_by_time.len());
+        for ((_, id), sending) in due_by_turn {
+            self.due.remove(&id);
+            sendings.push(sending);
+        }
+        for ((_, id), sending) in due_by_time {
+            self.due.remove(&id);
+            sendings.push(sending);
+        }
+        sendings
+    }
+}
+
+#[cfg(test)]
+fn run_turn(system: &mut ActorSystem) {
+    system.process_all_messages();
+    system.networking_finish_turn();
+}
+
+#[test]
+fn test_due_turns_and_cancellation() {
+    use crate::id::TypedID;
+    use crate::messaging::Fate;
+    use crate::test_support::{local_system, Add, Counter};
+
+    let mut system = local_system();
+    system.register::<Counter>();
+    system.add_handler::<Counter, _, _>(
+        |&Add(n), counter, _| {
+            // keeps the order messages arrive in
+            counter.count = counter.count * 10 + n;
+            Fate::Live
+        },
+        false,
+    );
+    let counter = system.spawn_many(vec![Counter::new(0)])[0];
+    let id = counter.as_raw();
+    let count = |system: &ActorSystem| system.instance::<Counter>(counter).unwrap().count;
+
+    system.send_in_turns(id, Add(3), 2);
+    system.send_in_turns(id, Add(4), 2);
+    let cancelled = system.send_in_turns(id, Add(9), 1);
+    system.send_in_turns(id, Add(1), 1);
+    assert!(system.cancel_scheduled(cancelled));
+    assert!(!system.cancel_scheduled(cancelled));
+
+    run_turn(&mut system);
+    assert_eq!(count(&system), 0);
+    run_turn(&mut system);
+    assert_eq!(count(&system), 1);
+    run_turn(&mut system);
+    assert_eq!(count(&system), 134);
+    run_turn(&mut system);
+    assert_eq!(count(&system), 134);
+}
+
+#[test]
+fn test_send_after_delay() {
+    use crate::id::TypedID;
+    use crate::messaging::Fate;
+    use crate::test_support::{local_system, Add, Counter};
+    use std::time::Duration;
+
+    let mut system = local_system();
+    system.register::<Counter>();
+    system.add_handler::<Counter, _, _>(
+        |&Add(n), counter, _| {
+            counter.count += n;
+            Fate::Live
+        },
+        false,
+    );
+    let counter = system.spawn_many(vec![Counter::new(0)])[0];
+
+    let sent = system.send_after(counter.as_raw(), Add(1), Duration::from_millis(50));
+    let cancelled = system.send_after(counter.as_raw(), Add(10), Duration::from_millis(50));
+    assert!(system.cancel_scheduled(cancelled));
+    system.process_all_messages();
+    assert_eq!(system.instance::<Counter>(counter).unwrap().count, 0);
+
+    ::std::thread::sleep(Duration::from_millis(60));
+    system.process_all_messages();
+    assert_eq!(system.instance::<Counter>(counter).unwrap().count, 1);
+    assert!(!system.cancel_scheduled(sent));
+}
+
+#[test]
+fn test_scheduled_messages_are_not_forked_or_saved() {
+    use crate::id::TypedID;
+    use crate::messaging::Fate;
+    use crate::test_support::{local_networking, local_system, Add, Counter};
+    use crate::tuning::Tuning;
+
+    let setup = |system: &mut ActorSystem| {
+        system.register::<Counter>();
+        system.add_handler::<Counter, _, _>(
+            |&Add(n), counter, _| {
+                counter.count += n;
+                Fate::Live
+            },
+            false,
+        );
+    };
+    let mut system = local_system();
+    setup(&mut system);
+    let counter = system.spawn_many(vec![Counter::new(0)])[0];
+    system.send_in_turns(counter.as_raw(), Add(1), 1);
+
+    let mut fork = system.fork(setup).unwrap();
+    let mut snapshot = Vec::new();
+    system.save(&mut snapshot).unwrap();
+    let mut loaded = ActorSystem::load(local_networking(), &snapshot[..], Tuning::default()).unwrap();
+    setup(&mut loaded);
+
+    for _ in 0..3 {
+        run_turn(&mut system);
+        run_turn(&mut fork);
+        run_turn(&mut loaded);
+    }
+    assert_eq!(system.instance::<Counter>(counter).unwrap().count, 1);
+    assert_eq!(fork.instance::<Counter>(counter).unwrap().count, 0);
+    assert_eq!(loaded.instance::<Counter>(counter).unwrap().count, 0);
+}
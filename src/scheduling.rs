@@ -0,0 +1,154 @@
+use std::cmp::Reverse;
+
+/// How the inbox of a class is scheduled within a turn, see `ActorSystem::set_scheduling_policy`
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SchedulingPolicy {
+    /// Classes with a higher priority handle their inboxes first in each message cycle,
+    /// classes with the same priority in the order they were registered in (default: 0)
+    pub priority: i32,
+    /// Handle at most this many messages per turn, the rest stays queued for the next turn
+    pub max_messages_per_turn: Option<usize>,
+    /// Stop handling messages once this many microseconds were spent on them in a turn.
+    /// Depends on the speed of the machine, so it breaks determinism in lockstep.
+    pub max_micros_per_turn: Option<u64>,
+    /// After this many turns in a row that ended with messages left over because of the budget,
+    /// handle all queued messages in the next turn, so bulk work doesn't fall behind forever
+    pub starvation_turns: Option<usize>,
+}
+
+impl Default for SchedulingPolicy {
+    fn default() -> Self {
+        SchedulingPolicy {
+            priority: 0,
+            max_messages_per_turn: None,
+            max_micros_per_turn: None,
+            starvation_turns: None,
+        }
+    }
+}
+
+impl SchedulingPolicy {
+    fn has_budget(&self) -> bool {
+        self.max_messages_per_turn.is_some() || self.max_micros_per_turn.is_some()
+    }
+}
+
+#[derive(Copy, Clone, Default)]
+struct Usage {
+    messages: usize,
+    ms: f64,
+    /// Turns in a row that ended with the budget used up and messages left over
+    starved_turns: usize,
+    /// The budget is lifted for this turn because of starvation
+    unlimited: bool,
+}
+
+/// The order classes are handled in and the budgets they used in the current turn
+pub(crate) struct Scheduling {
+    policies: Vec<SchedulingPolicy>,
+    usage: Vec<Usage>,
+    order: Vec<usize>,
+}
+
+impl Scheduling {
+    pub fn new(n_classes: usize) -> Scheduling {
+        Scheduling {
+            policies: vec![SchedulingPolicy::default(); n_classes],
+            usage: vec![Usage::default(); n_classes],
+            order: (0..n_classes).collect(),
+        }
+    }
+
+    pub fn set_policy(&mut self, class: usize, policy: SchedulingPolicy) {
+        self.policies[class] = policy;
+        let policies = &self.policies;
+        self.order = (0..policies.len()).collect();
+        self.order.sort_by_key(|&class| Reverse(policies[class].priority));
+    }
+
+    pub fn has_budgets(&self) -> bool {
+        self.policies.iter().any(SchedulingPolicy::has_budget)
+    }
+
+    /// Class type IDs in the order their inboxes should be handled in
+    pub fn order(&self) -> &[usize] {
+        &self.order
+    }
+
+    fn used_up(&self, class: usize) -> bool {
+        let (policy, usage) = (&self.policies[class], &self.usage[class]);
+        policy.max_messages_per_turn.map_or(false, |max| usage.messages >= max)
+            || policy
+                .max_micros_per_turn
+                .map_or(false, |max| usage.ms * 1000.0 >= max as f64)
+    }
+
+    /// How many messages a class may still handle in this turn and for how many milliseconds,
+    /// `None` if it has no budget
+    pub fn remaining(&self, class: usize) -> Option<(usize, f64)> {
+        let (policy, usage) = (&self.policies[class], &self.usage[class]);
+        if !policy.has_budget() || usage.unlimited {
+            return None;
+        }
+        let messages = policy
+            .max_messages_per_turn
+            .map_or(usize::max_value(), |max| max.saturating_sub(usage.messages));
+        let ms = policy
+            .max_micros_per_turn
+            .map_or(::std::f64::INFINITY, |max| (max as f64 / 1000.0 - usage.ms).max(0.0));
+        Some((messages, ms))
+    }
+
+    pub fn count(&mut self, class: usize, messages: usize, ms: f64) {
+        let usage = &mut self.usage[class];
+        usage.messages += messages;
+        usage.ms += ms;
+    }
+
+    /// Reset budgets for a new turn, `has_queued` tells if a class has messages left over
+    pub fn start_turn<F: Fn(usize) -> bool>(&mut self, has_queued: F) {
+        for class in 0..self.policies.len() {
+            if !self.policies[class].has_budget() {
+                continue;
+            }
+            let starved = self.used_up(class) && has_queued(class);
+            let usage = &mut self.usage[class];
+            usage.starved_turns = if starved { usage.starved_turns + 1 } else { 0 };
+            usage.unlimited = self.policies[class]
+                .starvation_turns
+                .map_or(false, |max| usage.starved_turns >= max);
+            if usage.unlimited {
+                usage.starved_turns = 0;
+            }
+            usage.messages = 0;
+            usage.ms = 0.0;
+        }
+    }
+}
+
+#[test]
+fn test_scheduling() {
+    let mut scheduling = Scheduling::new(4);
+    scheduling.set_policy(2, SchedulingPolicy { priority: 1, ..SchedulingPolicy::default() });
+    scheduling.set_policy(
+        3,
+        SchedulingPolicy {
+            max_messages_per_turn: Some(10),
+            starvation_turns: Some(2),
+            ..SchedulingPolicy::default()
+        },
+    );
+    assert_eq!(scheduling.order(), &[2, 0, 1, 3]);
+    assert_eq!(scheduling.remaining(0), None);
+
+    scheduling.count(3, 10, 1.0);
+    assert_eq!(scheduling.remaining(3), Some((0, ::std::f64::INFINITY)));
+    scheduling.start_turn(|_| true);
+    assert_eq!(scheduling.remaining(3).map(|(messages, _)| messages), Some(10));
+
+    scheduling.count(3, 10, 1.0);
+    scheduling.start_turn(|_| true);
+    assert_eq!(scheduling.remaining(3), None);
+    scheduling.start_turn(|_| true);
+    assert_eq!(scheduling.remaining(3).map(|(messages, _)| messages), Some(10));
+}
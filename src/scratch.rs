@@ -0,0 +1,197 @@
+use std::cell::{Cell, UnsafeCell};
+
+/// The size of the chunks a `Scratch` arena allocates from, in 8-byte words
+const SCRATCH_CHUNK_WORDS: usize = 8 * 1024;
+
+/// A bump arena for temporaries that only live until the end of the current turn,
+/// see `World::scratch`. Allocating only moves a cursor through reused chunks,
+/// all of it is freed at once at the end of the turn.
+///
+/// Only `Copy` values with an alignment of at most 8 bytes can be allocated,
+/// since nothing is dropped.
+///
+/// It is only reached through a `ScratchScope`, which allocations borrow
+/// and which keeps the arena from being reset.
+pub struct Scratch {
+    chunks: UnsafeCell<Vec<Vec<u64>>>,
+    /// The chunk currently allocated from
+    current: Cell<usize>,
+    /// The words of the current chunk already allocated
+    offset: Cell<usize>,
+    bytes_used: Cell<usize>,
+    /// The system and turn the allocations belong to, see `for_turn`
+    turn: Cell<(usize, usize)>,
+    /// The number of live `ScratchScope`s, the arena isn't reset while there are any
+    scopes: Cell<usize>,
+}
+
+/// A handle to the scratch arena of the current thread, see `World::scratch`.
+/// What is allocated from it can't outlive the handle, and the arena is only reset
+/// for a new turn once all handles of earlier turns were dropped.
+/// (It can't be sent to other threads, since each has its own arena.)
+pub struct ScratchScope {
+    scratch: *const Scratch,
+}
+
+impl ::std::ops::Deref for ScratchScope {
+    type Target = Scratch;
+
+    fn deref(&self) -> &Scratch {
+        unsafe { &*self.scratch }
+    }
+}
+
+impl Drop for ScratchScope {
+    fn drop(&mut self) {
+        let scopes = &unsafe { &*self.scratch }.scopes;
+        scopes.set(scopes.get() - 1);
+    }
+}
+
+thread_local! {
+    static SCRATCH: Scratch = Scratch::new();
+}
+
+impl Scratch {
+    fn new() -> Scratch {
+        Scratch {
+            chunks: UnsafeCell::new(Vec::new()),
+            current: Cell::new(0),
+            offset: Cell::new(0),
+            bytes_used: Cell::new(0),
+            turn: Cell::new((0, 0)),
+            scopes: Cell::new(0),
+        }
+    }
+
+    /// The arena of the current thread (so workers of `ParallelProcessing` each have their own),
+    /// reset first if it was last used in a different turn of `system` or for another system
+    /// and no scope of that turn is still alive (otherwise it keeps growing until they are dropped)
+    pub(crate) fn for_turn(system: usize, n_turn: usize) -> ScratchScope {
+        let scratch_ptr = SCRATCH.with(|scratch| scratch as *const Scratch);
+        let scratch = unsafe { &*scratch_ptr };
+        if scratch.turn.get() != (system, n_turn) && scratch.scopes.get() == 0 {
+            scratch.reset();
+            scratch.turn.set((system, n_turn));
+        }
+        scratch.scopes.set(scratch.scopes.get() + 1);
+        ScratchScope { scratch: scratch_ptr }
+    }
+
+    fn reset(&self) {
+        self.current.set(0);
+        self.offset.set(0);
+        self.bytes_used.set(0);
+    }
+
+    /// The bytes allocated in this turn
+    pub fn bytes_used(&self) -> usize {
+        self.bytes_used.get()
+    }
+
+    fn alloc_raw<T: Copy>(&self, len: usize) -> *mut T {
+        assert!(
+            ::std::mem::align_of::<T>() <= 8,
+            "Scratch can only allocate types aligned to at most 8 bytes"
+        );
+        let bytes = len * ::std::mem::size_of::<T>();
+        let words = (bytes + 7) / 8;
+        let chunks = unsafe { &mut *self.chunks.get() };
+
+        let mut current = self.current.get();
+        let mut offset = self.offset.get();
+        while chunks.get(current).map_or(true, |chunk| chunk.len() - offset < words) {
+            if current < chunks.len() {
+                current += 1;
+                offset = 0;
+            }
+            if current == chunks.len() || chunks[current].len() < words {
+                chunks.insert(current, vec![0; SCRATCH_CHUNK_WORDS.max(words)]);
+            }
+        }
+
+        self.current.set(current);
+        self.offset.set(offset + words);
+        self.bytes_used.set(self.bytes_used.get() + bytes);
+        unsafe { chunks[current].as_mut_ptr().add(offset) as *mut T }
+    }
+
+    /// Allocate `value`
+    #[allow(clippy::mut_from_ref)]
+    pub fn alloc<T: Copy>(&self, value: T) -> &mut T {
+        let ptr = self.alloc_raw::<T>(1);
+        unsafe {
+            ptr.write(value);
+            &mut *ptr
+        }
+    }
+
+    /// Allocate a slice of `len` copies of `value`
+    #[allow(clippy::mut_from_ref)]
+    pub fn alloc_slice_fill<T: Copy>(&self, len: usize, value: T) -> &mut [T] {
+        let ptr = self.alloc_raw::<T>(len);
+        unsafe {
+            for i in 0..len {
+                ptr.add(i).write(value);
+            }
+            ::std::slice::from_raw_parts_mut(ptr, len)
+        }
+    }
+
+    /// Allocate a copy of `items`
+    #[allow(clippy::mut_from_ref)]
+    pub fn alloc_slice<T: Copy>(&self, items: &[T]) -> &mut [T] {
+        let ptr = self.alloc_raw::<T>(items.len());
+        unsafe {
+            ::std::ptr::copy_nonoverlapping(items.as_ptr(), ptr, items.len());
+            ::std::slice::from_raw_parts_mut(ptr, items.len())
+        }
+    }
+
+    /// Allocate a slice of all items of `items`, without a temporary `Vec`
+    /// if the iterator knows its exact length
+    #[allow(clippy::mut_from_ref)]
+    pub fn collect<T: Copy, I: IntoIterator<Item = T>>(&self, items: I) -> &mut [T] {
+        let mut items = items.into_iter();
+        match items.size_hint() {
+            (lower, Some(upper)) if lower == upper => {
+                let ptr = self.alloc_raw::<T>(lower);
+                let mut len = 0;
+                while let Some(item) = if len < lower { items.next() } else { None } {
+                    unsafe { ptr.add(len).write(item) };
+                    len += 1;
+                }
+                unsafe { ::std::slice::from_raw_parts_mut(ptr, len) }
+            }
+            _ => self.alloc_slice(&items.collect::<Vec<_>>()),
+        }
+    }
+}
+
+#[test]
+fn test_scratch_reuses_chunks_per_turn() {
+    let scratch = Scratch::for_turn(1, 0);
+    let a = scratch.alloc(7u8);
+    let b = scratch.alloc_slice_fill(3, 1.5f64);
+    let big = scratch.collect(0..(SCRATCH_CHUNK_WORDS as u64 * 2));
+    let c = scratch.alloc_slice(&[1u32, 2, 3]);
+    assert_eq!((*a, b[2], big[SCRATCH_CHUNK_WORDS], c[1]), (7, 1.5, SCRATCH_CHUNK_WORDS as u64, 2));
+    assert_eq!(b.as_ptr() as usize % 8, 0);
+    let n_chunks = unsafe { (*scratch.chunks.get()).len() };
+
+    let same_turn = Scratch::for_turn(1, 0);
+    assert!(same_turn.bytes_used() > 0);
+    drop(same_turn);
+    // the allocations of the first scope stay valid in the next turn, while it is alive
+    let next_turn = Scratch::for_turn(1, 1);
+    assert!(next_turn.bytes_used() > 0);
+    assert_eq!((*a, c[2]), (7, 3));
+    drop(next_turn);
+    drop(scratch);
+
+    let scratch = Scratch::for_turn(1, 1);
+    assert_eq!(scratch.bytes_used(), 0);
+    scratch.collect(0..(SCRATCH_CHUNK_WORDS as u64 * 2));
+    scratch.collect((0..10).filter(|i| i % 2 == 0));
+    assert_eq!(unsafe { (*scratch.chunks.get()).len() }, n_chunks);
+}
@@ -0,0 +1,110 @@
+use crate::id::MachineID;
+
+/// What `ActorSystem::shutdown` did to tear down the system
+#[derive(Clone, Debug, Default)]
+pub struct ShutdownReport {
+    /// Turns spent handling the messages that were still queued
+    pub drained_turns: usize,
+    /// Messages still queued after the final turn (like those sent by `on_destroy` hooks
+    /// of instances spawned in that turn), which were dropped
+    pub undelivered_messages: usize,
+    /// Instances that were destroyed, running their `on_destroy` hooks
+    pub destroyed_instances: usize,
+    /// Peers that not all messages could be sent to before the connections were closed
+    pub unflushed_peers: Vec<MachineID>,
+}
+
+#[test]
+fn test_shutdown_drains_destroys_and_leaves() {
+    use crate::actor_system::ActorSystem;
+    use crate::id::TypedID;
+    use crate::messaging::Fate;
+    use crate::networking::Networking;
+    use crate::system_events::SystemEvent;
+    use crate::test_support::{Add, Counter};
+    use crate::tuning::Tuning;
+    use std::sync::mpsc::channel;
+    use std::time::{Duration, Instant};
+
+    fn setup(system: &mut ActorSystem) {
+        system.register::<Counter>();
+        system.add_handler::<Counter, _, _>(
+            |&Add(n), counter, _| {
+                counter.count += n;
+                Fate::Live
+            },
+            false,
+        );
+    }
+
+    fn is_connected(event: &SystemEvent) -> bool {
+        if let SystemEvent::PeerConnected(_) = *event {
+            true
+        } else {
+            false
+        }
+    }
+
+    fn is_left(event: &SystemEvent) -> bool {
+        if let SystemEvent::PeerLeft(_) = *event {
+            true
+        } else {
+            false
+        }
+    }
+
+    let free_address = || {
+        let listener = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string().parse().unwrap()
+    };
+    let network = vec![free_address(), free_address()];
+    let deadline = Instant::now() + Duration::from_secs(10);
+
+    let host_networking = Networking::new(0, network.clone(), 50_000, 30, 10).unwrap();
+    let mut host = ActorSystem::new(host_networking, Tuning::default());
+    setup(&mut host);
+    let host_counter = host.spawn_many(vec![Counter::new(0)])[0];
+    let host_events = host.subscribe_system_events();
+
+    let (report_sender, report_receiver) = channel();
+    let leaving_peer = ::std::thread::spawn(move || {
+        let peer_networking = Networking::new(1, network, 50_000, 30, 10).unwrap();
+        let mut peer = ActorSystem::new(peer_networking, Tuning::default());
+        setup(&mut peer);
+        peer.on_destroy::<Counter, _>(move |counter, world| {
+            // to the host, which is still there, and to itself, which isn't
+            world.send(host_counter.as_raw(), Add(counter.count));
+            world.send(counter.id.as_raw(), Add(1));
+        });
+        let peer_events = peer.subscribe_system_events();
+        while !peer_events.try_iter().any(|event| is_connected(&event)) {
+            assert!(Instant::now() < deadline, "Peer didn't connect");
+            peer.networking_connect();
+            peer.networking_send_and_receive();
+        }
+        let counters = peer.spawn_many(vec![Counter::new(10)]);
+        // still queued when shutting down
+        peer.send(counters[0].as_raw(), Add(5));
+        let report = peer.shutdown(3, Duration::from_secs(1));
+        report_sender.send((report, peer.dead_letter_count())).unwrap();
+    });
+
+    let mut peer_left = false;
+    while !peer_left || host.instance::<Counter>(host_counter).unwrap().count == 0 {
+        assert!(Instant::now() < deadline, "Peer didn't leave");
+        host.networking_connect();
+        host.networking_send_and_receive();
+        host.process_all_messages();
+        peer_left |= host_events.try_iter().any(|event| is_left(&event));
+    }
+    leaving_peer.join().unwrap();
+
+    let (report, n_dead_letters) = report_receiver.recv().unwrap();
+    assert_eq!(report.drained_turns, 1);
+    assert_eq!(report.destroyed_instances, 1);
+    assert_eq!(report.undelivered_messages, 0);
+    assert!(report.unflushed_peers.is_empty());
+    // the message the hook sent to its own instance was handled in the final turn
+    assert_eq!(n_dead_letters, 1);
+    assert_eq!(host.instance::<Counter>(host_counter).unwrap().count, 15);
+}
@@ -0,0 +1,737 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use chunky::{Chunk, ChunkStorage, Ident};
+use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hasher;
+use std::io::{Read, Write};
+use std::rc::Rc;
+use std::sync::{Mutex, MutexGuard};
+
+const SNAPSHOT_MAGIC: &[u8; 8] = b"KAYSNAP\0";
+const SNAPSHOT_VERSION: u32 = 1;
+
+const KIND_FULL: u8 = 0;
+const KIND_INCREMENTAL: u8 = 1;
+
+/// Where the memory of a live chunk is, to copy it into a snapshot.
+/// Chunks are only freed by forgetting them or by dropping the collections of a class,
+/// which also drops its `ClassStorage` and with it all its live chunks, so `ptr` stays valid.
+#[derive(Copy, Clone)]
+struct LiveChunk {
+    ptr: *const u8,
+    len: usize,
+    /// The `ClassStorage` the chunk was created through (0 for the system storage itself)
+    owner: usize,
+    /// Is the chunk memory-mapped from a file that `sync_mapped` should write to?
+    #[cfg_attr(not(all(feature = "server", unix)), allow(dead_code))]
+    mapped: bool,
+}
+
+impl LiveChunk {
+    unsafe fn bytes(&self) -> &[u8] {
+        ::std::slice::from_raw_parts(self.ptr, self.len)
+    }
+}
+
+/// The contents a chunk gets once it is loaded
+enum Restorable {
+    /// Read from a snapshot
+    Saved(Vec<u8>),
+    /// A live chunk of the system this one was forked from, which doesn't change
+    /// until the fork is detached from it (see `SnapshotStorage::forked`)
+    Forked(LiveChunk),
+}
+
+impl Restorable {
+    fn bytes(&self) -> &[u8] {
+        match *self {
+            Restorable::Saved(ref data) => data,
+            Restorable::Forked(ref live) => unsafe { live.bytes() },
+        }
+    }
+
+    fn into_vec(self) -> Vec<u8> {
+        match self {
+            Restorable::Saved(data) => data,
+            Restorable::Forked(live) => unsafe { live.bytes() }.to_vec(),
+        }
+    }
+}
+
+/// Wraps the `ChunkStorage` of an `ActorSystem`, keeping track of all live chunks
+/// so the whole system state (instances, inboxes and ID counters all live in chunks)
+/// can be written to a snapshot, and serving chunks from a loaded snapshot
+/// when the collections of a fresh system load them.
+///
+/// Incremental snapshots only contain the chunks whose contents changed since
+/// the last snapshot (compared by hash) and the idents of chunks that were forgotten,
+/// to be applied on top of a full snapshot.
+///
+/// Layout: the magic bytes, the version, the kind (full or incremental),
+/// the actor and message type names (ordered by type ID),
+/// then chunks, each as `[ident][length: u32][bytes]` and finally the forgotten idents.
+/// Lists are prefixed with their length as `u32`,
+/// strings are encoded as `[length: u16][UTF-8 bytes]`.
+///
+/// What chunks are created, loaded and forgotten through is locked, since the classes
+/// handled on the workers of parallel processing do that at the same time
+/// (each through its own `ClassStorage`).
+pub(crate) struct SnapshotStorage {
+    inner: Rc<dyn ChunkStorage>,
+    /// Is `inner` a `chunky::MmapStorage`?
+    inner_mapped: bool,
+    live: Mutex<BTreeMap<String, LiveChunk>>,
+    to_restore: Mutex<HashMap<String, Restorable>>,
+    /// Content hashes of all chunks as of the last snapshot
+    saved_hashes: Mutex<HashMap<String, u64>>,
+    /// Ident prefixes of chunks that are left out of snapshots
+    excluded: RefCell<Vec<String>>,
+    /// Data that isn't in a live chunk, written to snapshots as chunks
+    extra: RefCell<BTreeMap<String, Vec<u8>>>,
+}
+
+/// The type names of actors and messages in a loaded snapshot, ordered by type ID
+pub(crate) struct RestoredNames {
+    pub actors: Vec<String>,
+    pub messages: Vec<String>,
+}
+
+impl SnapshotStorage {
+    pub fn new(inner: Rc<dyn ChunkStorage>) -> SnapshotStorage {
+        SnapshotStorage {
+            inner,
+            inner_mapped: false,
+            live: Mutex::new(BTreeMap::new()),
+            to_restore: Mutex::new(HashMap::new()),
+            saved_hashes: Mutex::new(HashMap::new()),
+            excluded: RefCell::new(Vec::new()),
+            extra: RefCell::new(BTreeMap::new()),
+        }
+    }
+
+    /// Leave all chunks whose ident starts with `prefix` out of snapshots
+    pub fn exclude(&self, prefix: String) {
+        self.excluded.borrow_mut().push(prefix);
+    }
+
+    /// Write `data` to all following snapshots as the chunk `ident`
+    pub fn set_extra(&self, ident: String, data: Vec<u8>) {
+        self.extra.borrow_mut().insert(ident, data);
+    }
+
+    /// Take the data of a chunk from a loaded snapshot that isn't loaded as a live chunk
+    /// (see `set_extra`)
+    pub fn take_restored(&self, ident: &str) -> Option<Vec<u8>> {
+        lock(&self.to_restore).remove(ident).map(Restorable::into_vec)
+    }
+
+    /// Wrap a `chunky::MmapStorage`, whose chunks can be synced to their files
+    #[cfg(feature = "server")]
+    pub fn mapped(inner: Rc<chunky::MmapStorage>) -> SnapshotStorage {
+        SnapshotStorage {
+            inner_mapped: true,
+            ..SnapshotStorage::new(inner)
+        }
+    }
+
+    /// Write all changes to memory-mapped chunks to their files, returns once they are on disk
+    #[cfg(all(feature = "server", unix))]
+    pub fn sync_mapped(&self) -> ::std::io::Result<()> {
+        for live in lock(&self.live).values().filter(|live| live.mapped) {
+            // memory-mapped chunks always start at a page boundary
+            if unsafe { libc::msync(live.ptr as *mut libc::c_void, live.len, libc::MS_SYNC) } != 0 {
+                return Err(::std::io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    /// Read a whole snapshot, whose chunks are restored as they are loaded
+    pub fn from_snapshot<R: Read>(
+        inner: Rc<dyn ChunkStorage>,
+        reader: R,
+    ) -> ::std::io::Result<(SnapshotStorage, RestoredNames)> {
+        let storage = SnapshotStorage::new(inner);
+        let names = storage.read_snapshot(reader, KIND_FULL)?;
+        Ok((storage, names))
+    }
+
+    /// A storage for a fork of the system that `original` belongs to. Instead of going through
+    /// a snapshot, each live chunk of the original is copied straight from its memory once
+    /// the fork loads it, and chunks the fork never loads (like those of classes it doesn't
+    /// register) aren't copied at all. The original must not change until `detach` is called.
+    pub fn forked(original: &SnapshotStorage, inner: Rc<dyn ChunkStorage>) -> SnapshotStorage {
+        let storage = SnapshotStorage::new(inner);
+        {
+            let excluded = original.excluded.borrow();
+            let mut to_restore = lock(&storage.to_restore);
+            for (ident, live) in lock(&original.live).iter() {
+                if !excluded.iter().any(|prefix| ident.starts_with(prefix.as_str())) {
+                    to_restore.insert(ident.clone(), Restorable::Forked(*live));
+                }
+            }
+            for (ident, data) in original.extra.borrow().iter() {
+                to_restore.insert(ident.clone(), Restorable::Saved(data.clone()));
+            }
+        }
+        storage
+    }
+
+    /// Copy the chunks of the original that weren't loaded yet, after which the original can change
+    pub fn detach(&self) {
+        for restorable in lock(&self.to_restore).values_mut() {
+            if let Restorable::Forked(live) = *restorable {
+                *restorable = Restorable::Saved(unsafe { live.bytes() }.to_vec());
+            }
+        }
+    }
+
+    /// Apply an incremental snapshot on top of the snapshot loaded before,
+    /// returns the type names as of the incremental snapshot
+    pub fn apply_increment<R: Read>(&self, reader: R) -> ::std::io::Result<RestoredNames> {
+        self.read_snapshot(reader, KIND_INCREMENTAL)
+    }
+
+    fn read_snapshot<R: Read>(&self, mut reader: R, expected_kind: u8) -> ::std::io::Result<RestoredNames> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        let version = reader.read_u32::<LittleEndian>()?;
+        if &magic != SNAPSHOT_MAGIC || version != SNAPSHOT_VERSION {
+            return Err(::std::io::Error::new(
+                ::std::io::ErrorKind::InvalidData,
+                "Not a snapshot of a compatible actor system",
+            ));
+        }
+        if reader.read_u8()? != expected_kind {
+            return Err(::std::io::Error::new(
+                ::std::io::ErrorKind::InvalidData,
+                "Expected a full snapshot, followed by incremental ones",
+            ));
+        }
+
+        let names = RestoredNames {
+            actors: read_names(&mut reader)?,
+            messages: read_names(&mut reader)?,
+        };
+
+        let mut to_restore = lock(&self.to_restore);
+        let mut saved_hashes = lock(&self.saved_hashes);
+
+        let n_chunks = reader.read_u32::<LittleEndian>()?;
+        for _ in 0..n_chunks {
+            let ident = read_string(&mut reader)?;
+            let len = reader.read_u32::<LittleEndian>()? as usize;
+            let mut data = vec![0; len];
+            reader.read_exact(&mut data)?;
+            saved_hashes.insert(ident.clone(), content_hash(&data));
+            to_restore.insert(ident, Restorable::Saved(data));
+        }
+
+        for ident in read_names(&mut reader)? {
+            saved_hashes.remove(&ident);
+            to_restore.remove(&ident);
+        }
+
+        Ok(names)
+    }
+
+    /// Write all live chunks, together with the type names of the
+    /// actors and messages (ordered by type ID) that the chunks refer to
+    pub fn save<W: Write>(
+        &self,
+        writer: W,
+        actor_names: &[&str],
+        message_names: &[&str],
+    ) -> ::std::io::Result<()> {
+        self.write_snapshot(writer, KIND_FULL, actor_names, message_names)
+    }
+
+    /// Write only the chunks that changed since the last snapshot
+    pub fn save_incremental<W: Write>(
+        &self,
+        writer: W,
+        actor_names: &[&str],
+        message_names: &[&str],
+    ) -> ::std::io::Result<()> {
+        self.write_snapshot(writer, KIND_INCREMENTAL, actor_names, message_names)
+    }
+
+    fn write_snapshot<W: Write>(
+        &self,
+        mut writer: W,
+        kind: u8,
+        actor_names: &[&str],
+        message_names: &[&str],
+    ) -> ::std::io::Result<()> {
+        writer.write_all(SNAPSHOT_MAGIC)?;
+        writer.write_u32::<LittleEndian>(SNAPSHOT_VERSION)?;
+        writer.write_u8(kind)?;
+        write_names(&mut writer, actor_names)?;
+        write_names(&mut writer, message_names)?;
+
+        let live = lock(&self.live);
+        let excluded = self.excluded.borrow();
+        let extra = self.extra.borrow();
+        let mut saved_hashes = lock(&self.saved_hashes);
+        let mut new_hashes = HashMap::with_capacity(live.len());
+
+        let changed = live
+            .iter()
+            .filter(|(ident, _)| !excluded.iter().any(|prefix| ident.starts_with(prefix.as_str())))
+            .map(|(ident, chunk)| (ident, unsafe { chunk.bytes() }))
+            .chain(extra.iter().map(|(ident, data)| (ident, &data[..])))
+            .filter_map(|(ident, data)| {
+                let hash = content_hash(data);
+                new_hashes.insert(ident.clone(), hash);
+                if kind == KIND_FULL || saved_hashes.get(ident) != Some(&hash) {
+                    Some((ident, data))
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+
+        writer.write_u32::<LittleEndian>(changed.len() as u32)?;
+        for (ident, data) in changed {
+            write_string(&mut writer, ident)?;
+            writer.write_u32::<LittleEndian>(data.len() as u32)?;
+            writer.write_all(data)?;
+        }
+
+        let forgotten = if kind == KIND_FULL {
+            Vec::new()
+        } else {
+            saved_hashes
+                .keys()
+                .filter(|ident| !new_hashes.contains_key(*ident))
+                .map(String::as_str)
+                .collect::<Vec<_>>()
+        };
+        write_names(&mut writer, &forgotten)?;
+        writer.flush()?;
+
+        *saved_hashes = new_hashes;
+        Ok(())
+    }
+
+    fn track(&self, ident: Ident, chunk: Chunk, mapped: bool, owner: usize) -> Chunk {
+        lock(&self.live).insert(
+            ident.0,
+            LiveChunk {
+                ptr: chunk.as_ptr(),
+                len: chunk.len(),
+                owner,
+                mapped,
+            },
+        );
+        chunk
+    }
+
+    /// Stop tracking the chunks of a `ClassStorage` that is being dropped
+    fn untrack_owner(&self, owner: usize) {
+        lock(&self.live).retain(|_, live| live.owner != owner);
+    }
+
+    fn restore(&self, inner: &dyn ChunkStorage, ident: &Ident) -> Option<Chunk> {
+        let restorable = lock(&self.to_restore).remove(&ident.0)?;
+        let data = restorable.bytes();
+        let (mut chunk, _) = inner.load_or_create_chunk(ident.clone(), data.len());
+        chunk[..data.len()].copy_from_slice(data);
+        // the chunk might be larger than saved, so it counts as unchanged only with its full contents
+        lock(&self.saved_hashes)
+            .insert(ident.0.clone(), content_hash(&chunk));
+        Some(chunk)
+    }
+
+    fn create_chunk_in(&self, inner: &dyn ChunkStorage, mapped: bool, owner: usize, ident: Ident, size: usize) -> Chunk {
+        let chunk = inner.create_chunk(ident.clone(), size);
+        self.track(ident, chunk, mapped, owner)
+    }
+
+    fn load_or_create_chunk_in(&self, inner: &dyn ChunkStorage, mapped: bool, owner: usize, ident: Ident, size: usize) -> (Chunk, bool) {
+        let (chunk, created_new) = match self.restore(inner, &ident) {
+            Some(chunk) => (chunk, false),
+            None => inner.load_or_create_chunk(ident.clone(), size),
+        };
+        (self.track(ident, chunk, mapped, owner), created_new)
+    }
+
+    fn load_chunk_in(&self, inner: &dyn ChunkStorage, mapped: bool, owner: usize, ident: Ident) -> Chunk {
+        let chunk = match self.restore(inner, &ident) {
+            Some(chunk) => chunk,
+            None => inner.load_chunk(ident.clone()),
+        };
+        self.track(ident, chunk, mapped, owner)
+    }
+
+    fn forget_chunk_in(&self, inner: &dyn ChunkStorage, chunk: Chunk) {
+        let ptr = chunk.as_ptr();
+        lock(&self.live).retain(|_, live| live.ptr != ptr);
+        inner.forget_chunk(chunk);
+    }
+}
+
+impl ChunkStorage for SnapshotStorage {
+    fn create_chunk(&self, ident: Ident, size: usize) -> Chunk {
+        self.create_chunk_in(&*self.inner, self.inner_mapped, 0, ident, size)
+    }
+
+    fn load_or_create_chunk(&self, ident: Ident, size: usize) -> (Chunk, bool) {
+        self.load_or_create_chunk_in(&*self.inner, self.inner_mapped, 0, ident, size)
+    }
+
+    fn load_chunk(&self, ident: Ident) -> Chunk {
+        self.load_chunk_in(&*self.inner, self.inner_mapped, 0, ident)
+    }
+
+    fn forget_chunk(&self, chunk: Chunk) {
+        self.forget_chunk_in(&*self.inner, chunk)
+    }
+}
+
+/// The storage handle of one actor class, whose chunks are part of snapshots.
+/// Every class has its own, so classes handled on different workers of parallel processing
+/// never share an `Rc`. Its chunks are in the storage of the system, or in the storage
+/// the class was given (see `ActorSystem::use_storage_for` and `ActorSystem::use_mmap_storage_for`).
+pub(crate) struct ClassStorage {
+    snapshots: Rc<SnapshotStorage>,
+    inner: Rc<dyn ChunkStorage>,
+    /// Is `inner` memory-mapped, so `SnapshotStorage::sync_mapped` writes its chunks?
+    mapped: bool,
+}
+
+impl ClassStorage {
+    /// Identifies the chunks created through this storage, which doesn't move since it is in an `Rc`
+    fn owner(&self) -> usize {
+        self as *const ClassStorage as usize
+    }
+
+    /// A handle to the storage of the system itself
+    pub fn of_system(snapshots: &Rc<SnapshotStorage>) -> ClassStorage {
+        ClassStorage {
+            snapshots: Rc::clone(snapshots),
+            inner: Rc::clone(&snapshots.inner),
+            mapped: snapshots.inner_mapped,
+        }
+    }
+
+    /// A handle to the storage given to one class, which is `mapped` if it is a `chunky::MmapStorage`
+    pub fn new(snapshots: Rc<SnapshotStorage>, inner: Rc<dyn ChunkStorage>, mapped: bool) -> ClassStorage {
+        ClassStorage {
+            snapshots,
+            inner,
+            mapped,
+        }
+    }
+}
+
+impl ChunkStorage for ClassStorage {
+    fn create_chunk(&self, ident: Ident, size: usize) -> Chunk {
+        self.snapshots.create_chunk_in(&*self.inner, self.mapped, self.owner(), ident, size)
+    }
+
+    fn load_or_create_chunk(&self, ident: Ident, size: usize) -> (Chunk, bool) {
+        self.snapshots.load_or_create_chunk_in(&*self.inner, self.mapped, self.owner(), ident, size)
+    }
+
+    fn load_chunk(&self, ident: Ident) -> Chunk {
+        self.snapshots.load_chunk_in(&*self.inner, self.mapped, self.owner(), ident)
+    }
+
+    fn forget_chunk(&self, chunk: Chunk) {
+        self.snapshots.forget_chunk_in(&*self.inner, chunk)
+    }
+}
+
+impl Drop for ClassStorage {
+    fn drop(&mut self) {
+        // all collections holding chunks of this storage were dropped, and their chunks with them
+        self.snapshots.untrack_owner(self.owner());
+    }
+}
+
+/// Lock some bookkeeping, which stays usable if a handler panicked while it was locked
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn content_hash(data: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(data);
+    hasher.finish()
+}
+
+fn write_string<W: Write>(writer: &mut W, string: &str) -> ::std::io::Result<()> {
+    assert!(string.len() <= u16::max_value() as usize, "Name too long for snapshot");
+    writer.write_u16::<LittleEndian>(string.len() as u16)?;
+    writer.write_all(string.as_bytes())
+}
+
+fn read_string<R: Read>(reader: &mut R) -> ::std::io::Result<String> {
+    let len = reader.read_u16::<LittleEndian>()? as usize;
+    let mut bytes = vec![0; len];
+    reader.read_exact(&mut bytes)?;
+    String::from_utf8(bytes)
+        .map_err(|e| ::std::io::Error::new(::std::io::ErrorKind::InvalidData, e))
+}
+
+fn write_names<W: Write>(writer: &mut W, names: &[&str]) -> ::std::io::Result<()> {
+    writer.write_u32::<LittleEndian>(names.len() as u32)?;
+    for name in names {
+        write_string(writer, name)?;
+    }
+    Ok(())
+}
+
+fn read_names<R: Read>(reader: &mut R) -> ::std::io::Result<Vec<String>> {
+    let n_names = reader.read_u32::<LittleEndian>()?;
+    (0..n_names).map(|_| read_string(reader)).collect()
+}
+
+#[test]
+fn test_dropped_class_storage_leaves_snapshots() {
+    let snapshots = Rc::new(SnapshotStorage::new(Rc::new(chunky::HeapStorage)));
+    let storage: Rc<dyn ChunkStorage> = Rc::new(ClassStorage::of_system(&snapshots));
+    let mut numbers = chunky::Vector::<u32>::new(Ident::from("numbers"), 1024, Rc::clone(&storage));
+    numbers.push(42);
+    assert!(lock(&snapshots.live).keys().any(|ident| ident.starts_with("numbers")));
+
+    // like the collections of a class, dropping the vector frees its chunks and drops the storage
+    drop(numbers);
+    drop(storage);
+    assert!(lock(&snapshots.live).is_empty());
+    let mut snapshot = Vec::new();
+    snapshots.save(&mut snapshot, &[], &[]).unwrap();
+}
+
+#[test]
+fn test_snapshot_roundtrip() {
+    use crate::actor_system::ActorSystem;
+    use crate::id::TypedID;
+    use crate::messaging::Fate;
+    use crate::test_support::{local_networking, local_system, Add, Counter};
+    use crate::tuning::Tuning;
+
+    fn register(system: &mut ActorSystem) {
+        system.register::<Counter>();
+        system.add_handler::<Counter, _, _>(
+            |&Add(n), counter, _| {
+                counter.count += n;
+                Fate::Live
+            },
+            false,
+        );
+    }
+
+    let mut system = local_system();
+    register(&mut system);
+    let counters = system.spawn_many(vec![Counter::new(0), Counter::new(10)]);
+    system.send(counters[0].as_raw(), Add(1));
+    system.process_all_messages();
+    // still in the inbox when saving
+    system.send(counters[1].as_raw(), Add(2));
+    let mut snapshot = Vec::new();
+    system.save(&mut snapshot).unwrap();
+
+    let mut restored = ActorSystem::load(local_networking(), &snapshot[..], Tuning::default()).unwrap();
+    register(&mut restored);
+    assert_eq!(restored.instance_count::<Counter>(), 2);
+    assert_eq!(restored.instance::<Counter>(counters[0]).unwrap().count, 1);
+    restored.process_all_messages();
+    assert_eq!(restored.instance::<Counter>(counters[1]).unwrap().count, 12);
+}
+
+#[test]
+fn test_incremental_snapshot_roundtrip() {
+    use crate::actor_system::ActorSystem;
+    use crate::id::TypedID;
+    use crate::messaging::Fate;
+    use crate::test_support::{local_networking, local_system, Add, Counter};
+    use crate::tuning::Tuning;
+
+    fn register(system: &mut ActorSystem) {
+        system.register::<Counter>();
+        system.add_handler::<Counter, _, _>(
+            |&Add(n), counter, _| {
+                if n == 0 {
+                    return Fate::Die;
+                }
+                counter.count += n;
+                Fate::Live
+            },
+            false,
+        );
+    }
+
+    let mut system = local_system();
+    register(&mut system);
+    let counters = system.spawn_many(vec![Counter::new(0), Counter::new(10), Counter::new(20)]);
+    let mut base = Vec::new();
+    system.save(&mut base).unwrap();
+
+    system.send(counters[0].as_raw(), Add(1));
+    system.send(counters[1].as_raw(), Add(0));
+    system.process_all_messages();
+    let mut first_increment = Vec::new();
+    system.save_incremental(&mut first_increment).unwrap();
+
+    let spawned = system.spawn_many(vec![Counter::new(30)])[0];
+    // still in the inbox when saving
+    system.send(counters[2].as_raw(), Add(2));
+    let mut second_increment = Vec::new();
+    system.save_incremental(&mut second_increment).unwrap();
+
+    let increments = vec![&first_increment[..], &second_increment[..]];
+    let mut restored = ActorSystem::load_incremental(local_networking(), &base[..], increments, Tuning::default()).unwrap();
+    register(&mut restored);
+    assert_eq!(restored.instance_count::<Counter>(), 3);
+    assert_eq!(restored.instance::<Counter>(counters[0]).unwrap().count, 1);
+    assert!(restored.instance::<Counter>(counters[1]).is_none());
+    assert_eq!(restored.instance::<Counter>(spawned).unwrap().count, 30);
+    restored.process_all_messages();
+    assert_eq!(restored.instance::<Counter>(counters[2]).unwrap().count, 22);
+
+    // only the first increment
+    let mut restored = ActorSystem::load_incremental(local_networking(), &base[..], vec![&first_increment[..]], Tuning::default()).unwrap();
+    register(&mut restored);
+    assert_eq!(restored.instance_count::<Counter>(), 2);
+    assert!(restored.instance::<Counter>(counters[1]).is_none());
+    assert_eq!(restored.instance::<Counter>(counters[2]).unwrap().count, 20);
+}
+
+#[test]
+fn test_id_allocators_continue_after_restore() {
+    use crate::actor_system::ActorSystem;
+    use crate::id::MachineID;
+    use crate::id_allocation::MachineRanges;
+    use crate::test_support::{local_networking, local_system, Counter};
+    use crate::tuning::Tuning;
+
+    let mut system = local_system();
+    system.register::<Counter>();
+    system.set_id_allocator::<Counter, _>(MachineRanges::new(100));
+    let allocated = system.world().allocate_instance_id_on::<Counter>(MachineID(1));
+    let mut snapshot = Vec::new();
+    system.save(&mut snapshot).unwrap();
+
+    let mut restored = ActorSystem::load(local_networking(), &snapshot[..], Tuning::default()).unwrap();
+    restored.register::<Counter>();
+    restored.set_id_allocator::<Counter, _>(MachineRanges::new(100));
+    let next = restored.world().allocate_instance_id_on::<Counter>(MachineID(1));
+    assert_eq!(next.instance_id, allocated.instance_id + 1);
+}
+
+#[test]
+#[cfg(all(feature = "server", unix))]
+fn test_mmap_class_storage_is_synced() {
+    use crate::test_support::{local_system, Counter};
+
+    let directory = ::std::env::temp_dir().join(format!("kay_test_mmap_class_{}", ::std::process::id()));
+    ::std::fs::create_dir_all(&directory).unwrap();
+    let mut system = local_system();
+    system.use_mmap_storage_for::<Counter>(Rc::new(chunky::MmapStorage::new(directory.clone())));
+    system.register::<Counter>();
+    system.spawn_many(vec![Counter::new(3)]);
+    system.process_all_messages();
+
+    assert!(lock(&system.snapshots.live).values().any(|live| live.mapped));
+    system.sync().unwrap();
+    drop(system);
+    ::std::fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn test_fork_is_isolated() {
+    use crate::actor_system::ActorSystem;
+    use crate::id::TypedID;
+    use crate::messaging::Fate;
+    use crate::test_support::{local_system, Add, Counter, OtherCounter};
+
+    fn register(system: &mut ActorSystem) {
+        system.register::<Counter>();
+        system.add_handler::<Counter, _, _>(
+            |&Add(n), counter, _| {
+                counter.count += n;
+                Fate::Live
+            },
+            false,
+        );
+        system.register::<OtherCounter>();
+    }
+
+    let mut system = local_system();
+    register(&mut system);
+    let counters = system.spawn_many(vec![Counter::new(0), Counter::new(10)]);
+    system.spawn_many(vec![OtherCounter::new(5)]);
+    // still in the inbox when forking
+    system.send(counters[0].as_raw(), Add(1));
+
+    let mut fork = system.fork(register).unwrap();
+    assert_eq!(fork.instance_count::<Counter>(), 2);
+    assert_eq!(fork.instance_count::<OtherCounter>(), 1);
+    fork.send(counters[1].as_raw(), Add(100));
+    fork.process_all_messages();
+    system.send(counters[1].as_raw(), Add(2));
+    system.process_all_messages();
+
+    assert_eq!(fork.instance::<Counter>(counters[0]).unwrap().count, 1);
+    assert_eq!(fork.instance::<Counter>(counters[1]).unwrap().count, 110);
+    assert_eq!(system.instance::<Counter>(counters[0]).unwrap().count, 1);
+    assert_eq!(system.instance::<Counter>(counters[1]).unwrap().count, 12);
+}
+
+#[cfg(test)]
+fn register_counter_adding(system: &mut crate::actor_system::ActorSystem) {
+    use crate::messaging::Fate;
+    use crate::test_support::{Add, Counter};
+    system.register::<Counter>();
+    system.add_handler::<Counter, _, _>(
+        |&Add(n), counter, _| {
+            counter.count += n;
+            Fate::Live
+        },
+        false,
+    );
+}
+
+#[test]
+fn test_cancellation_after_load() {
+    use crate::actor_system::ActorSystem;
+    use crate::id::TypedID;
+    use crate::test_support::{local_networking, local_system, Add, Counter};
+    use crate::tuning::Tuning;
+
+    let mut system = local_system();
+    register_counter_adding(&mut system);
+    let counter = system.spawn_many(vec![Counter::new(0)])[0];
+    // still in the inbox when saving
+    system.send(counter.as_raw(), Add(1));
+    let mut snapshot = Vec::new();
+    system.save(&mut snapshot).unwrap();
+
+    let mut restored = ActorSystem::load(local_networking(), &snapshot[..], Tuning::default()).unwrap();
+    register_counter_adding(&mut restored);
+    restored.send_cancellable(counter.as_raw(), Add(100), 7);
+    assert_eq!(restored.cancel_messages(7), 1);
+    restored.process_all_messages();
+    assert_eq!(restored.instance::<Counter>(counter).unwrap().count, 1);
+}
+
+#[test]
+fn test_cancellation_after_fork() {
+    use crate::id::TypedID;
+    use crate::test_support::{local_system, Add, Counter};
+
+    let mut system = local_system();
+    register_counter_adding(&mut system);
+    let counter = system.spawn_many(vec![Counter::new(0)])[0];
+    // still in the inbox when forking
+    system.send(counter.as_raw(), Add(1));
+
+    let mut fork = system.fork(register_counter_adding).unwrap();
+    fork.send_cancellable(counter.as_raw(), Add(100), 7);
+    assert_eq!(fork.cancel_messages(7), 1);
+    fork.process_all_messages();
+    assert_eq!(fork.instance::<Counter>(counter).unwrap().count, 1);
+}
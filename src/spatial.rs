@@ -0,0 +1,302 @@
+use crate::id::{MachineID, RawID};
+use crate::type_registry::ShortTypeId;
+use compact::CVec;
+use std::collections::HashMap;
+
+/// Implemented by the state of actors that can be found by where they are,
+/// see `ActorSystem::enable_spatial_index`
+pub trait Positioned {
+    /// The current position of the instance in the plane
+    fn position(&self) -> (f32, f32);
+}
+
+/// An area to find instances in
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SpatialArea {
+    /// All positions at most `radius` away from `center`
+    Circle {
+        /// The center of the circle
+        center: (f32, f32),
+        /// The radius of the circle
+        radius: f32,
+    },
+    /// All positions in an axis-aligned box, including its edges
+    Box {
+        /// The corner with the smallest coordinates
+        min: (f32, f32),
+        /// The corner with the largest coordinates
+        max: (f32, f32),
+    },
+}
+
+impl SpatialArea {
+    pub(crate) fn contains(&self, (x, y): (f32, f32)) -> bool {
+        match *self {
+            SpatialArea::Circle { center, radius } => {
+                let (dx, dy) = (x - center.0, y - center.1);
+                dx * dx + dy * dy <= radius * radius
+            }
+            SpatialArea::Box { min, max } => x >= min.0 && x <= max.0 && y >= min.1 && y <= max.1,
+        }
+    }
+
+    fn bounds(&self) -> ((f32, f32), (f32, f32)) {
+        match *self {
+            SpatialArea::Circle { center, radius } => (
+                (center.0 - radius, center.1 - radius),
+                (center.0 + radius, center.1 + radius),
+            ),
+            SpatialArea::Box { min, max } => (min, max),
+        }
+    }
+}
+
+/// Refers to a query started with `World::find_in`
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct SpatialQueryID(u32);
+
+/// Sent to the requester of `World::find_in` once all machines answered or the timeout passed,
+/// with all instances in the area (the requester needs to handle it)
+#[derive(Compact, Clone, Debug)]
+pub struct FoundInstances {
+    /// The query that is answered
+    pub query: SpatialQueryID,
+    /// The instances in the area, on all machines that answered
+    pub ids: CVec<RawID>,
+    /// Did some machines not answer in time?
+    pub timed_out: bool,
+}
+
+crate::compact_bounds!(FoundInstances { ids });
+
+/// Broadcast to the system services of all machines to find instances there
+#[derive(Compact, Clone)]
+pub(crate) struct SpatialQuery {
+    pub class: ShortTypeId,
+    pub area: SpatialArea,
+    pub query: SpatialQueryID,
+    pub origin: MachineID,
+}
+
+/// Sent back to the system services of the machine that asked a `SpatialQuery`
+#[derive(Compact, Clone)]
+pub(crate) struct SpatialQueryResults {
+    pub query: SpatialQueryID,
+    pub from: MachineID,
+    pub ids: CVec<RawID>,
+}
+
+crate::compact_bounds!(SpatialQueryResults { ids });
+
+/// A uniform grid of the positions of all local instances of a class,
+/// rebuilt after each call of `process_all_messages`
+pub(crate) struct SpatialGrid {
+    cell_size: f32,
+    cells: HashMap<(i32, i32), Vec<(RawID, (f32, f32))>>,
+    pub position: Box<dyn Fn(*const ()) -> (f32, f32)>,
+}
+
+impl SpatialGrid {
+    pub fn new(cell_size: f32, position: Box<dyn Fn(*const ()) -> (f32, f32)>) -> SpatialGrid {
+        assert!(cell_size > 0.0, "Cells of a spatial index need a size");
+        SpatialGrid {
+            cell_size,
+            cells: HashMap::new(),
+            position,
+        }
+    }
+
+    fn cell_of(&self, (x, y): (f32, f32)) -> (i32, i32) {
+        ((x / self.cell_size).floor() as i32, (y / self.cell_size).floor() as i32)
+    }
+
+    pub fn clear(&mut self) {
+        self.cells.clear();
+    }
+
+    pub fn insert(&mut self, id: RawID, position: (f32, f32)) {
+        let cell = self.cell_of(position);
+        self.cells.entry(cell).or_insert_with(Vec::new).push((id, position));
+    }
+
+    /// All instances in `area`, ordered by ID so all machines agree on the order
+    pub fn query(&self, area: &SpatialArea) -> Vec<RawID> {
+        let (min, max) = area.bounds();
+        let (min_cell, max_cell) = (self.cell_of(min), self.cell_of(max));
+        let n_cells_in_bounds = (i64::from(max_cell.0) - i64::from(min_cell.0) + 1)
+            * (i64::from(max_cell.1) - i64::from(min_cell.1) + 1);
+        let mut ids = Vec::new();
+        let mut add_matching = |entries: &Vec<(RawID, (f32, f32))>| {
+            for &(id, position) in entries {
+                if area.contains(position) {
+                    ids.push(id);
+                }
+            }
+        };
+        if n_cells_in_bounds > self.cells.len() as i64 {
+            for entries in self.cells.values() {
+                add_matching(entries);
+            }
+        } else {
+            for x in min_cell.0..=max_cell.0 {
+                for y in min_cell.1..=max_cell.1 {
+                    if let Some(entries) = self.cells.get(&(x, y)) {
+                        add_matching(entries);
+                    }
+                }
+            }
+        }
+        ids.sort_by_key(|id| (id.machine.0, id.type_id.as_u16(), id.instance_id, id.version));
+        ids
+    }
+}
+
+struct PendingQuery {
+    requester: RawID,
+    waiting_for: Vec<MachineID>,
+    ids: Vec<RawID>,
+    deadline_ms: f64,
+}
+
+/// The spatial indices of all classes that have one, and queries spanning all machines
+pub(crate) struct SpatialIndices {
+    pub grids: HashMap<ShortTypeId, SpatialGrid>,
+    pending: HashMap<SpatialQueryID, PendingQuery>,
+    next_query: u32,
+}
+
+impl SpatialIndices {
+    pub fn new() -> SpatialIndices {
+        SpatialIndices {
+            grids: HashMap::new(),
+            pending: HashMap::new(),
+            next_query: 0,
+        }
+    }
+
+    /// Instances of `class` in `area` on this machine
+    pub fn query(&self, class: ShortTypeId, area: &SpatialArea) -> Vec<RawID> {
+        self.grids.get(&class).map_or_else(Vec::new, |grid| grid.query(area))
+    }
+
+    /// Start a query that waits for the results of all `machines`, until `deadline_ms`
+    pub fn start(&mut self, requester: RawID, machines: Vec<MachineID>, deadline_ms: f64) -> SpatialQueryID {
+        let query = SpatialQueryID(self.next_query);
+        self.next_query = self.next_query.wrapping_add(1);
+        self.pending.insert(
+            query,
+            PendingQuery {
+                requester,
+                waiting_for: machines,
+                ids: Vec::new(),
+                deadline_ms,
+            },
+        );
+        query
+    }
+
+    /// Add the results of a machine, returns the requester and all results once the query is complete
+    pub fn add_results(&mut self, results: &SpatialQueryResults) -> Option<(RawID, FoundInstances)> {
+        if let Some(pending) = self.pending.get_mut(&results.query) {
+            pending.waiting_for.retain(|machine| *machine != results.from);
+            pending.ids.extend(results.ids.iter().cloned());
+        }
+        self.take_if_complete(results.query)
+    }
+
+    /// A machine disconnected, returns all queries that were only waiting for it
+    pub fn disconnected(&mut self, machine: MachineID) -> Vec<(RawID, FoundInstances)> {
+        let mut queries = self.pending.keys().cloned().collect::<Vec<_>>();
+        queries.sort_by_key(|query| query.0);
+        queries
+            .into_iter()
+            .filter_map(|query| {
+                self.pending
+                    .get_mut(&query)
+                    .expect("Should be pending")
+                    .waiting_for
+                    .retain(|waiting_for| *waiting_for != machine);
+                self.take_if_complete(query)
+            })
+            .collect()
+    }
+
+    /// Take all queries whose deadline passed, in the order they were started
+    pub fn expire(&mut self, now_ms: f64) -> Vec<(RawID, FoundInstances)> {
+        let mut expired = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| pending.deadline_ms <= now_ms)
+            .map(|(query, _)| *query)
+            .collect::<Vec<_>>();
+        expired.sort_by_key(|query| query.0);
+        expired
+            .into_iter()
+            .map(|query| self.finish(query, true))
+            .collect()
+    }
+
+    fn take_if_complete(&mut self, query: SpatialQueryID) -> Option<(RawID, FoundInstances)> {
+        if self.pending.get(&query).map_or(false, |pending| pending.waiting_for.is_empty()) {
+            Some(self.finish(query, false))
+        } else {
+            None
+        }
+    }
+
+    fn finish(&mut self, query: SpatialQueryID, timed_out: bool) -> (RawID, FoundInstances) {
+        let mut pending = self.pending.remove(&query).expect("Should be pending");
+        pending
+            .ids
+            .sort_by_key(|id| (id.machine.0, id.type_id.as_u16(), id.instance_id, id.version));
+        (
+            pending.requester,
+            FoundInstances {
+                query,
+                ids: pending.ids.into(),
+                timed_out,
+            },
+        )
+    }
+}
+
+#[test]
+fn test_spatial_grid() {
+    use crate::test_support::test_id;
+
+    let id = |instance_id| test_id(instance_id, 0);
+    let mut grid = SpatialGrid::new(10.0, Box::new(|_| (0.0, 0.0)));
+    grid.insert(id(1), (1.0, 1.0));
+    grid.insert(id(2), (12.0, 1.0));
+    grid.insert(id(3), (-25.0, 40.0));
+
+    let near_origin = SpatialArea::Circle { center: (0.0, 0.0), radius: 5.0 };
+    assert_eq!(grid.query(&near_origin), vec![id(1)]);
+    let wide = SpatialArea::Box { min: (-30.0, -30.0), max: (15.0, 50.0) };
+    assert_eq!(grid.query(&wide), vec![id(1), id(2), id(3)]);
+    let huge = SpatialArea::Circle { center: (0.0, 0.0), radius: 1.0e6 };
+    assert_eq!(grid.query(&huge).len(), 3);
+}
+
+#[test]
+fn test_spatial_queries_time_out() {
+    use crate::test_support::test_id;
+
+    let mut indices = SpatialIndices::new();
+    let query = indices.start(test_id(1, 0), vec![MachineID(0), MachineID(1)], 100.0);
+    let results = SpatialQueryResults {
+        query,
+        from: MachineID(0),
+        ids: vec![test_id(2, 0)].into(),
+    };
+    assert!(indices.add_results(&results).is_none());
+    assert!(indices.expire(50.0).is_empty());
+
+    let expired = indices.expire(100.0);
+    assert_eq!(expired.len(), 1);
+    let (requester, ref found) = expired[0];
+    assert_eq!(requester, test_id(1, 0));
+    assert!(found.timed_out);
+    assert_eq!(found.ids.to_vec(), vec![test_id(2, 0)]);
+    assert!(indices.expire(200.0).is_empty());
+}
@@ -0,0 +1,162 @@
+use byteorder::{ByteOrder, LittleEndian};
+use compact::Compact;
+
+const PRIME_1: u64 = 0x9E37_79B1_85EB_CA87;
+const PRIME_2: u64 = 0xC2B2_AE3D_27D4_EB4F;
+const PRIME_3: u64 = 0x1656_67B1_9E37_79F9;
+const PRIME_4: u64 = 0x85EB_CA77_C2B2_AE63;
+const PRIME_5: u64 = 0x27D4_EB2F_1656_67C5;
+
+/// XXH64, which is fast enough to hash the whole actor state every turn
+/// and (unlike the standard hasher) gives the same hash on all machines
+pub(crate) fn xxh64(data: &[u8], seed: u64) -> u64 {
+    let mut rest = data;
+
+    let mut hash = if data.len() >= 32 {
+        let mut lanes = [
+            seed.wrapping_add(PRIME_1).wrapping_add(PRIME_2),
+            seed.wrapping_add(PRIME_2),
+            seed,
+            seed.wrapping_sub(PRIME_1),
+        ];
+        while rest.len() >= 32 {
+            for (i, lane) in lanes.iter_mut().enumerate() {
+                *lane = round(*lane, LittleEndian::read_u64(&rest[i * 8..]));
+            }
+            rest = &rest[32..];
+        }
+        let mut hash = lanes[0]
+            .rotate_left(1)
+            .wrapping_add(lanes[1].rotate_left(7))
+            .wrapping_add(lanes[2].rotate_left(12))
+            .wrapping_add(lanes[3].rotate_left(18));
+        for lane in &lanes {
+            hash = (hash ^ round(0, *lane))
+                .wrapping_mul(PRIME_1)
+                .wrapping_add(PRIME_4);
+        }
+        hash
+    } else {
+        seed.wrapping_add(PRIME_5)
+    };
+
+    hash = hash.wrapping_add(data.len() as u64);
+
+    while rest.len() >= 8 {
+        hash ^= round(0, LittleEndian::read_u64(rest));
+        hash = hash.rotate_left(27).wrapping_mul(PRIME_1).wrapping_add(PRIME_4);
+        rest = &rest[8..];
+    }
+
+    if rest.len() >= 4 {
+        hash ^= u64::from(LittleEndian::read_u32(rest)).wrapping_mul(PRIME_1);
+        hash = hash.rotate_left(23).wrapping_mul(PRIME_2).wrapping_add(PRIME_3);
+        rest = &rest[4..];
+    }
+
+    for &byte in rest {
+        hash ^= u64::from(byte).wrapping_mul(PRIME_5);
+        hash = hash.rotate_left(11).wrapping_mul(PRIME_1);
+    }
+
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(PRIME_2);
+    hash ^= hash >> 29;
+    hash = hash.wrapping_mul(PRIME_3);
+    hash ^ (hash >> 32)
+}
+
+/// A `Hasher` for actor states, which hashes everything written to it with XXH64
+/// and writes `usize`s as 64 bit, so browser clients and servers agree on hashes
+pub(crate) struct StateHasher {
+    seed: u64,
+    bytes: Vec<u8>,
+}
+
+impl StateHasher {
+    pub fn new(seed: u64) -> StateHasher {
+        StateHasher {
+            seed,
+            bytes: Vec::new(),
+        }
+    }
+}
+
+impl ::std::hash::Hasher for StateHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    fn write_usize(&mut self, value: usize) {
+        let mut bytes = [0; 8];
+        LittleEndian::write_u64(&mut bytes, value as u64);
+        self.write(&bytes);
+    }
+
+    fn finish(&self) -> u64 {
+        xxh64(&self.bytes, self.seed)
+    }
+}
+
+/// Hash the state of an actor, through its `Hash` implementation if it has one.
+/// Other actors are hashed as their compact bytes, which only hash the same on all machines
+/// if their state has no padding, since padding bytes aren't initialized.
+pub(crate) fn hash_state<A: Compact>(actor: &A, hasher: &mut StateHasher) {
+    HashState::hash_state(actor, hasher)
+}
+
+trait HashState {
+    fn hash_state(&self, hasher: &mut StateHasher);
+}
+
+impl<T: Compact> HashState for T {
+    default fn hash_state(&self, hasher: &mut StateHasher) {
+        let bytes = unsafe {
+            ::std::slice::from_raw_parts(self as *const T as *const u8, self.total_size_bytes())
+        };
+        ::std::hash::Hasher::write(hasher, bytes);
+    }
+}
+
+impl<T: Compact + ::std::hash::Hash> HashState for T {
+    fn hash_state(&self, hasher: &mut StateHasher) {
+        ::std::hash::Hash::hash(self, hasher);
+    }
+}
+
+fn round(accumulator: u64, input: u64) -> u64 {
+    accumulator
+        .wrapping_add(input.wrapping_mul(PRIME_2))
+        .rotate_left(31)
+        .wrapping_mul(PRIME_1)
+}
+
+#[test]
+fn test_xxh64_reference_values() {
+    assert_eq!(xxh64(b"", 0), 0xEF46_DB37_51D8_E999);
+    assert_eq!(xxh64(b"abc", 0), 0x44BC_2CF5_AD77_0999);
+    assert_eq!(
+        xxh64(b"Nobody inspects the spammish repetition", 0),
+        0xFBCE_A83C_8A37_8BF1
+    );
+}
+
+#[test]
+fn test_states_hash_without_padding() {
+    #[derive(Compact, Clone, Hash)]
+    struct Padded {
+        flag: u8,
+        count: u32,
+    }
+
+    let hash = |padding: u8| {
+        let mut padded: Padded = unsafe { ::std::mem::zeroed() };
+        unsafe { ::std::ptr::write_bytes(&mut padded as *mut Padded, padding, 1) };
+        padded.flag = 1;
+        padded.count = 2;
+        let mut hasher = StateHasher::new(0);
+        hash_state(&padded, &mut hasher);
+        ::std::hash::Hasher::finish(&hasher)
+    };
+    assert_eq!(hash(0), hash(0xFF));
+}
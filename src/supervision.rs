@@ -0,0 +1,186 @@
+use crate::actor_system::World;
+use crate::class::ActorStateVTable;
+use crate::id::RawID;
+use crate::messaging::{Fate, HandlerFnRef};
+use crate::type_registry::ShortTypeId;
+use compact::CString;
+use std::any::Any;
+use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
+
+/// What happens when a message handler of an actor class panics,
+/// set per class with `ActorSystem::supervise`
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SupervisionPolicy {
+    /// The whole actor system enters the panicked state,
+    /// after which only critical messages are handled (the default)
+    Escalate,
+    /// The panicking instance keeps living (with whatever state the handler left),
+    /// the message is dropped
+    Ignore,
+    /// The panicking instance is removed, so the supervisor can spawn a replacement
+    Restart,
+}
+
+impl Default for SupervisionPolicy {
+    fn default() -> SupervisionPolicy {
+        SupervisionPolicy::Escalate
+    }
+}
+
+/// Sent to the supervisor set with `ActorSystem::set_supervisor`
+/// whenever a message handler panicked. With `SupervisionPolicy::Escalate`,
+/// the supervisor only receives it if it handles it as a critical message.
+#[derive(Compact, Clone)]
+pub struct HandlerPanicked {
+    /// The instance whose handler panicked
+    pub actor: RawID,
+    /// The name of the message type that was handled
+    pub message_type: CString,
+    /// The panic message, if it was a string
+    pub reason: CString,
+    /// How the panic was dealt with
+    pub policy: SupervisionPolicy,
+}
+
+crate::compact_bounds!(HandlerPanicked { message_type, reason });
+
+/// Call a message handler, catching a panic inside it and dealing with it
+/// according to the policy of the actor class
+pub(crate) fn call_supervised(
+    handler: &HandlerFnRef,
+    actor: *mut (),
+    packet_ptr: *const (),
+    world: &mut World,
+    policy: SupervisionPolicy,
+    message_type: ShortTypeId,
+    state_v_table: &ActorStateVTable,
+) -> Fate {
+    match catch_unwind(AssertUnwindSafe(|| handler(actor, packet_ptr, world))) {
+        Ok(fate) => fate,
+        Err(payload) => {
+            let actor_id = (state_v_table.get_raw_id)(actor);
+            world.handler_panicked(actor_id, message_type, panic_reason(&*payload), policy);
+            match policy {
+                SupervisionPolicy::Escalate => resume_unwind(payload),
+                SupervisionPolicy::Ignore => Fate::Live,
+                SupervisionPolicy::Restart => Fate::Die,
+            }
+        }
+    }
+}
+
+fn panic_reason(payload: &(dyn Any + Send)) -> String {
+    if let Some(reason) = payload.downcast_ref::<&str>() {
+        (*reason).to_owned()
+    } else if let Some(reason) = payload.downcast_ref::<String>() {
+        reason.clone()
+    } else {
+        "(no panic message)".to_owned()
+    }
+}
+
+/// A system with a `Counter` whose handler panics when adding 0, supervised with `policy`
+/// by an `OtherCounter` that records every `HandlerPanicked` it receives
+#[cfg(test)]
+fn supervised_counter(
+    policy: SupervisionPolicy,
+) -> (
+    crate::actor_system::ActorSystem,
+    crate::test_support::CounterID,
+    ::std::rc::Rc<::std::cell::RefCell<Vec<(RawID, String, String, SupervisionPolicy)>>>,
+) {
+    use crate::id::TypedID;
+    use crate::test_support::{local_system, Add, Counter, OtherCounter};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let reports = Rc::new(RefCell::new(Vec::new()));
+    let received = Rc::clone(&reports);
+    let mut system = local_system();
+    system.register::<Counter>();
+    system.add_handler::<Counter, _, _>(
+        |&Add(n), counter, _| {
+            if n == 0 {
+                panic!("Adding nothing");
+            }
+            counter.count += n;
+            Fate::Live
+        },
+        false,
+    );
+    system.supervise::<Counter>(policy);
+    system.register::<OtherCounter>();
+    system.add_handler::<OtherCounter, _, _>(
+        move |panicked: &HandlerPanicked, _, _| {
+            received.borrow_mut().push((
+                panicked.actor,
+                panicked.message_type.to_string(),
+                panicked.reason.to_string(),
+                panicked.policy,
+            ));
+            Fate::Live
+        },
+        false,
+    );
+    let supervisor = system.spawn_many(vec![OtherCounter::new(0)])[0];
+    system.set_supervisor(supervisor.as_raw());
+    let counter = system.spawn_many(vec![Counter::new(0)])[0];
+    (system, counter, reports)
+}
+
+#[test]
+fn test_restart_after_panic() {
+    use crate::id::TypedID;
+    use crate::test_support::{Add, Counter};
+
+    let (mut system, counter, reports) = supervised_counter(SupervisionPolicy::Restart);
+    system.send(counter.as_raw(), Add(2));
+    system.send(counter.as_raw(), Add(0));
+    system.process_all_messages();
+
+    assert!(!system.world().panic_happened());
+    assert!(system.instance::<Counter>(counter).is_none());
+    assert_eq!(system.instance_count::<Counter>(), 0);
+    let reports = reports.borrow();
+    assert_eq!(reports.len(), 1);
+    let (actor, ref message_type, ref reason, policy) = reports[0];
+    assert_eq!(actor, counter.as_raw());
+    assert!(message_type.ends_with("Add"));
+    assert_eq!(reason, "Adding nothing");
+    assert_eq!(policy, SupervisionPolicy::Restart);
+}
+
+#[test]
+fn test_ignore_panic() {
+    use crate::id::TypedID;
+    use crate::test_support::{Add, Counter};
+
+    let (mut system, counter, reports) = supervised_counter(SupervisionPolicy::Ignore);
+    system.send(counter.as_raw(), Add(2));
+    system.send(counter.as_raw(), Add(0));
+    system.send(counter.as_raw(), Add(3));
+    system.process_all_messages();
+
+    assert!(!system.world().panic_happened());
+    // the instance keeps living and handling messages
+    assert_eq!(system.instance::<Counter>(counter).unwrap().count, 5);
+    let reports = reports.borrow();
+    assert_eq!(reports.len(), 1);
+    assert_eq!(reports[0].0, counter.as_raw());
+    assert_eq!(reports[0].3, SupervisionPolicy::Ignore);
+}
+
+#[test]
+fn test_escalate_panic() {
+    use crate::id::TypedID;
+    use crate::test_support::Add;
+
+    let (mut system, counter, reports) = supervised_counter(SupervisionPolicy::Escalate);
+    system.send(counter.as_raw(), Add(0));
+    system.process_all_messages();
+
+    assert!(system.world().panic_happened());
+    // the supervisor doesn't handle `HandlerPanicked` as a critical message
+    system.process_all_messages();
+    assert!(reports.borrow().is_empty());
+}
@@ -0,0 +1,250 @@
+use crate::id::{MachineID, RawID};
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+/// Something that happened inside kay itself, see `ActorSystem::subscribe_system_events`
+#[derive(Clone, Debug)]
+pub enum SystemEvent {
+    /// A local actor class was registered
+    ClassRegistered {
+        /// The type ID of the class
+        type_id: u16,
+        /// The name of the class
+        name: String,
+    },
+    /// A local instance was spawned
+    InstanceSpawned(RawID),
+    /// A local instance died (or was migrated away)
+    InstanceDied(RawID),
+    /// A connection to a peer was established
+    PeerConnected(MachineID),
+    /// A peer shut down and told us it leaves for good, followed by `PeerDisconnected`
+    PeerLeft(MachineID),
+    /// The connection to a peer closed unexpectedly, followed by `PeerDisconnected`
+    PeerConnectionLost {
+        /// The peer
+        peer: MachineID,
+        /// Why the connection closed
+        reason: String,
+    },
+    /// The connection to a peer closed
+    PeerDisconnected(MachineID),
+    /// The messages queued in the inbox of a class grew beyond `Tuning::inbox_overflow_bytes`,
+    /// or beyond `Tuning::inbox_spill_bytes`, so further messages are spilled to disk until it is handled
+    InboxOverflow {
+        /// The name of the class
+        class: String,
+        /// The bytes of messages in the inbox in memory
+        queued_bytes: usize,
+    },
+    /// In strict lockstep mode, the local turn waited for these peers
+    /// for longer than the timeout given to `Networking::enable_strict_lockstep`
+    LockstepTimedOut {
+        /// The connected peers that are still behind the local turn
+        stragglers: Vec<MachineID>,
+    },
+    /// A batch or message from a peer was ignored, since it was malformed
+    InvalidFromPeer {
+        /// The peer that sent it
+        from: MachineID,
+        /// What was wrong with it
+        problem: String,
+    },
+    /// A type registered at runtime on a peer got a different type ID there than here,
+    /// which happens when machines register new types concurrently. Messages of the type
+    /// can't be exchanged with that peer.
+    TypeConflict {
+        /// The peer that registered the type
+        from: MachineID,
+        /// Which type and IDs conflict
+        problem: String,
+    },
+    /// A message handler panicked
+    HandlerPanicked {
+        /// The instance that handled the message
+        actor: RawID,
+        /// The name of the message type
+        message_type: String,
+        /// The panic message
+        reason: String,
+    },
+}
+
+impl ::std::fmt::Display for SystemEvent {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match *self {
+            SystemEvent::ClassRegistered { type_id, ref name } => {
+                write!(f, "Registered class {} (type ID {})", name, type_id)
+            }
+            SystemEvent::InstanceSpawned(id) => write!(f, "Spawned {:?}", id),
+            SystemEvent::InstanceDied(id) => write!(f, "{:?} died", id),
+            SystemEvent::PeerConnected(machine) => write!(f, "Machine ID {} connected", machine.0),
+            SystemEvent::PeerLeft(machine) => write!(f, "Machine ID {} shut down and left", machine.0),
+            SystemEvent::PeerConnectionLost { peer, ref reason } => {
+                write!(f, "Lost the connection to Machine ID {}: {}", peer.0, reason)
+            }
+            SystemEvent::PeerDisconnected(machine) => write!(f, "Machine ID {} disconnected", machine.0),
+            SystemEvent::InboxOverflow { ref class, queued_bytes } => write!(
+                f,
+                "Inbox of {} overflowed with {} bytes in memory",
+                class, queued_bytes
+            ),
+            SystemEvent::LockstepTimedOut { ref stragglers } => write!(
+                f,
+                "Timed out waiting for Machine IDs {:?} to finish the turn",
+                stragglers.iter().map(|machine| machine.0).collect::<Vec<_>>()
+            ),
+            SystemEvent::InvalidFromPeer { from, ref problem } => {
+                write!(f, "Machine ID {} sent something invalid: {}", from.0, problem)
+            }
+            SystemEvent::TypeConflict { from, ref problem } => write!(
+                f,
+                "Type registered on Machine ID {} conflicts with a local one: {} \
+                 (register new types at runtime on one machine at a time)",
+                from.0, problem
+            ),
+            SystemEvent::HandlerPanicked {
+                actor,
+                ref message_type,
+                ref reason,
+            } => write!(f, "Handler for {} in {:?} panicked: {}", message_type, actor, reason),
+        }
+    }
+}
+
+/// The subscribers to `SystemEvent`s, which are dropped once they stop receiving
+pub(crate) struct SystemEvents {
+    subscribers: Vec<Sender<SystemEvent>>,
+}
+
+impl SystemEvents {
+    pub fn new() -> SystemEvents {
+        SystemEvents {
+            subscribers: Vec::new(),
+        }
+    }
+
+    pub fn subscribe(&mut self) -> Receiver<SystemEvent> {
+        let (sender, receiver) = channel();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    /// Is anybody subscribed? Events don't need to be created otherwise.
+    pub fn is_empty(&self) -> bool {
+        self.subscribers.is_empty()
+    }
+
+    pub fn emit(&mut self, event: SystemEvent) {
+        if let Some((last, others)) = self.subscribers.split_last() {
+            let mut closed = Vec::new();
+            for (i, subscriber) in others.iter().enumerate() {
+                if subscriber.send(event.clone()).is_err() {
+                    closed.push(i);
+                }
+            }
+            if last.send(event).is_err() {
+                closed.push(others.len());
+            }
+            for i in closed.into_iter().rev() {
+                self.subscribers.remove(i);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_system_event_subscribers() {
+    let mut events = SystemEvents::new();
+    assert!(events.is_empty());
+    let first = events.subscribe();
+    let second = events.subscribe();
+    events.emit(SystemEvent::PeerConnected(MachineID(1)));
+    drop(second);
+    events.emit(SystemEvent::PeerDisconnected(MachineID(1)));
+    assert_eq!(
+        first.try_iter().map(|event| event.to_string()).collect::<Vec<_>>(),
+        vec!["Machine ID 1 connected", "Machine ID 1 disconnected"]
+    );
+    assert_eq!(events.subscribers.len(), 1);
+    drop(first);
+    events.emit(SystemEvent::PeerConnected(MachineID(2)));
+    assert!(events.is_empty());
+}
+
+#[test]
+fn test_inbox_overflow_is_reported_without_spilling() {
+    use crate::actor_system::ActorSystem;
+    use crate::id::TypedID;
+    use crate::messaging::Fate;
+    use crate::test_support::{local_networking, Add, Counter};
+    use crate::tuning::Tuning;
+
+    let tuning = Tuning {
+        inbox_overflow_bytes: Some(200),
+        ..Tuning::default()
+    };
+    let mut system = ActorSystem::new(local_networking(), tuning);
+    system.register::<Counter>();
+    system.add_handler::<Counter, _, _>(
+        |&Add(n), counter, _| {
+            counter.count += n;
+            Fate::Live
+        },
+        false,
+    );
+    let counter = system.spawn_many(vec![Counter::new(0)])[0];
+    let events = system.subscribe_system_events();
+    let overflows = || {
+        events
+            .try_iter()
+            .filter(|event| if let SystemEvent::InboxOverflow { .. } = *event { true } else { false })
+            .count()
+    };
+
+    system.send(counter.as_raw(), Add(1));
+    system.process_all_messages();
+    assert_eq!(overflows(), 0);
+
+    for _ in 0..20 {
+        system.send(counter.as_raw(), Add(1));
+    }
+    system.process_all_messages();
+    assert_eq!(overflows(), 1);
+    assert_eq!(system.instance::<Counter>(counter).unwrap().count, 21);
+}
+
+#[test]
+fn test_type_conflicts_are_reported() {
+    use crate::id::TypedID;
+    use crate::test_support::{local_system, Counter};
+    use crate::type_registry::TypeRegistration;
+
+    let mut system = local_system();
+    system.register::<Counter>();
+    let counter = system.spawn_many(vec![Counter::new(0)])[0];
+    let events = system.subscribe_system_events();
+
+    let services = system.services_id(MachineID(0));
+    system.send(
+        services,
+        TypeRegistration {
+            actor: true,
+            id: counter.as_raw().type_id.as_u16(),
+            name: "OtherPlugin::Counter".to_owned().into(),
+            from: MachineID(1),
+        },
+    );
+    system.process_all_messages();
+
+    let conflicts = events
+        .try_iter()
+        .filter_map(|event| {
+            if let SystemEvent::TypeConflict { from, .. } = event {
+                Some(from)
+            } else {
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(conflicts, vec![MachineID(1)]);
+}
@@ -0,0 +1,105 @@
+use crate::actor_system::ActorSystem;
+use crate::turn_driver::TurnDriver;
+use std::time::Duration;
+
+/// Identifies a system hosted by a `SystemGroup`
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct HostedSystemID(usize);
+
+/// Hosts several isolated actor systems in one process, like the sessions of a dedicated
+/// game server. All state of an `ActorSystem` (type registries, instance IDs, networking,
+/// worker threads) belongs to that system, so the hosted systems only share the thread
+/// driving them. Each system runs turns with its own `TurnDriver`, at its own tick rate and speed.
+///
+/// Systems are kept boxed, so `World`s of a system stay valid while it is hosted.
+pub struct SystemGroup {
+    systems: Vec<Option<(Box<ActorSystem>, TurnDriver)>>,
+}
+
+impl SystemGroup {
+    /// Create a group without systems
+    pub fn new() -> SystemGroup {
+        SystemGroup { systems: Vec::new() }
+    }
+
+    /// Host `system`, running its turns with `driver`
+    pub fn add(&mut self, system: ActorSystem, driver: TurnDriver) -> HostedSystemID {
+        let hosted = Some((Box::new(system), driver));
+        match self.systems.iter().position(Option::is_none) {
+            Some(free) => {
+                self.systems[free] = hosted;
+                HostedSystemID(free)
+            }
+            None => {
+                self.systems.push(hosted);
+                HostedSystemID(self.systems.len() - 1)
+            }
+        }
+    }
+
+    /// Stop hosting a system and give it back (for example to save it),
+    /// after which its `World`s can't be used anymore
+    pub fn remove(&mut self, id: HostedSystemID) -> Option<(ActorSystem, TurnDriver)> {
+        let (system, driver) = self.systems.get_mut(id.0)?.take()?;
+        Some((*system, driver))
+    }
+
+    /// A hosted system, to register types, send messages or inspect it between turns
+    pub fn get_mut(&mut self, id: HostedSystemID) -> Option<&mut ActorSystem> {
+        self.systems.get_mut(id.0)?.as_mut().map(|(system, _)| &mut **system)
+    }
+
+    /// The driver of a hosted system, to change its speed
+    pub fn driver_mut(&mut self, id: HostedSystemID) -> Option<&mut TurnDriver> {
+        self.systems.get_mut(id.0)?.as_mut().map(|(_, driver)| driver)
+    }
+
+    /// The IDs of all hosted systems
+    pub fn ids(&self) -> Vec<HostedSystemID> {
+        self.systems
+            .iter()
+            .enumerate()
+            .filter(|(_, hosted)| hosted.is_some())
+            .map(|(i, _)| HostedSystemID(i))
+            .collect()
+    }
+
+    /// Run all turns that are due in each hosted system, one system after the other
+    /// (see `TurnDriver::drive`). Returns how many turns each system finished.
+    pub fn drive(&mut self) -> Vec<(HostedSystemID, usize)> {
+        self.systems
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(i, hosted)| {
+                let (system, driver) = hosted.as_mut()?;
+                Some((HostedSystemID(i), driver.drive(system)))
+            })
+            .collect()
+    }
+
+    /// How long until the next turn of any hosted system is due
+    pub fn time_until_next_turn(&self) -> Duration {
+        self.systems
+            .iter()
+            .filter_map(Option::as_ref)
+            .map(|(system, driver)| driver.time_until_next_turn(system))
+            .min()
+            .unwrap_or_else(|| Duration::from_millis(10))
+    }
+
+    /// Drive all hosted systems until `stop` returns true, sleeping until the next turn is due.
+    /// `stop` is called before each `drive`, it can also be used to add and remove systems.
+    #[cfg(feature = "server")]
+    pub fn run_until<F: FnMut(&mut SystemGroup) -> bool>(&mut self, mut stop: F) {
+        while !stop(self) {
+            self.drive();
+            ::std::thread::sleep(self.time_until_next_turn());
+        }
+    }
+}
+
+impl Default for SystemGroup {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,198 @@
+use crate::actor_system::ActorSystem;
+use crate::id::RawID;
+use crate::messaging::Message;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+
+type DeliverFn = Box<dyn FnOnce(&mut ActorSystem)>;
+#[cfg_attr(not(feature = "server"), allow(dead_code))]
+type SendDeliverFn = Box<dyn FnOnce(&mut ActorSystem) + Send>;
+
+/// Set when a future can make progress, so it is polled again before the next turn
+struct WakeFlag(AtomicBool);
+
+impl Wake for WakeFlag {
+    fn wake(self: Arc<Self>) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+struct PendingFuture {
+    future: Pin<Box<dyn Future<Output = DeliverFn>>>,
+    woken: Arc<WakeFlag>,
+}
+
+/// Work started by handlers that runs outside of turns (see `World::spawn_task`
+/// and `World::spawn_future`), whose results are sent as messages before the next turn
+pub(crate) struct Tasks {
+    #[cfg_attr(not(feature = "server"), allow(dead_code))]
+    finished_sender: Sender<SendDeliverFn>,
+    finished: Receiver<SendDeliverFn>,
+    futures: Vec<PendingFuture>,
+}
+
+impl Tasks {
+    pub fn new() -> Tasks {
+        let (finished_sender, finished) = channel();
+        Tasks {
+            finished_sender,
+            finished,
+            futures: Vec::new(),
+        }
+    }
+
+    /// Run `task` on its own thread and send its result to `recipient` once it finished
+    #[cfg(feature = "server")]
+    pub fn spawn<M: Message + Send, F: FnOnce() -> M + Send + 'static>(&mut self, recipient: RawID, task: F) {
+        let finished_sender = self.finished_sender.clone();
+        ::std::thread::spawn(move || {
+            let result = task();
+            // the actor system might be gone already
+            let _ = finished_sender.send(Box::new(move |system: &mut ActorSystem| system.send(recipient, result)));
+        });
+    }
+
+    /// Without background threads (in the browser), run `task` on this thread
+    /// the next time futures are polled, and send its result to `recipient`
+    #[cfg(not(feature = "server"))]
+    pub fn spawn<M: Message + Send, F: FnOnce() -> M + Send + 'static>(&mut self, recipient: RawID, task: F) {
+        self.spawn_future(recipient, async move { task() });
+    }
+
+    /// Poll `future` between turns and send its output to `recipient` once it completed
+    pub fn spawn_future<M: Message, F: Future<Output = M> + 'static>(&mut self, recipient: RawID, future: F) {
+        self.futures.push(PendingFuture {
+            future: Box::pin(async move {
+                let result = future.await;
+                Box::new(move |system: &mut ActorSystem| system.send(recipient, result)) as DeliverFn
+            }),
+            woken: Arc::new(WakeFlag(AtomicBool::new(true))),
+        });
+    }
+
+    /// Poll all futures that were woken, and take the deliveries of all results
+    /// that are ready, in the order they became ready
+    pub fn take_finished(&mut self) -> Vec<DeliverFn> {
+        let mut finished = self.finished.try_iter().map(|deliver| deliver as DeliverFn).collect::<Vec<_>>();
+        let mut i = 0;
+        while i < self.futures.len() {
+            if self.futures[i].woken.0.swap(false, Ordering::SeqCst) {
+                let waker = Waker::from(Arc::clone(&self.futures[i].woken));
+                let mut context = Context::from_waker(&waker);
+                if let Poll::Ready(deliver) = self.futures[i].future.as_mut().poll(&mut context) {
+                    finished.push(deliver);
+                    self.futures.remove(i);
+                    continue;
+                }
+            }
+            i += 1;
+        }
+        finished
+    }
+}
+
+#[test]
+fn test_future_outputs_arrive_on_the_next_turn() {
+    use crate::id::TypedID;
+    use crate::messaging::Fate;
+    use crate::test_support::{local_system, Add, Counter};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Pending until opened
+    struct Gate(Rc<RefCell<(bool, Option<Waker>)>>);
+
+    impl Future for Gate {
+        type Output = Add;
+        fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<Add> {
+            let mut gate = self.0.borrow_mut();
+            if gate.0 {
+                Poll::Ready(Add(100))
+            } else {
+                gate.1 = Some(context.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    let gate = Rc::new(RefCell::new((false, None)));
+    let handler_gate = Rc::clone(&gate);
+    let mut system = local_system();
+    system.register::<Counter>();
+    system.add_handler::<Counter, _, _>(
+        move |&Add(n), counter, world| {
+            counter.count += n;
+            match n {
+                1 => world.spawn_future(counter.id.as_raw(), async { Add(10) }),
+                2 => world.spawn_future(counter.id.as_raw(), Gate(Rc::clone(&handler_gate))),
+                _ => {}
+            }
+            Fate::Live
+        },
+        false,
+    );
+    let counter = system.spawn_many(vec![Counter::new(0)])[0];
+    let count = |system: &crate::actor_system::ActorSystem| system.instance::<Counter>(counter).unwrap().count;
+
+    system.send(counter.as_raw(), Add(1));
+    system.process_all_messages();
+    assert_eq!(count(&system), 1);
+    system.process_all_messages();
+    assert_eq!(count(&system), 11);
+
+    system.send(counter.as_raw(), Add(2));
+    system.process_all_messages();
+    system.process_all_messages();
+    assert_eq!(count(&system), 13);
+    let mut opened = gate.borrow_mut();
+    opened.0 = true;
+    opened.1.take().expect("The future should wait to be woken").wake();
+    drop(opened);
+    system.process_all_messages();
+    assert_eq!(count(&system), 113);
+}
+
+#[test]
+fn test_task_results_arrive_on_a_later_turn() {
+    use crate::id::TypedID;
+    use crate::messaging::Fate;
+    use crate::test_support::{local_system, Add, Counter};
+    use std::time::{Duration, Instant};
+
+    let (finish, finished) = channel::<()>();
+    let finished = ::std::cell::RefCell::new(Some(finished));
+    let mut system = local_system();
+    system.register::<Counter>();
+    system.add_handler::<Counter, _, _>(
+        move |&Add(n), counter, world| {
+            counter.count += n;
+            if let Some(finished) = finished.borrow_mut().take() {
+                world.spawn_task(counter.id.as_raw(), move || {
+                    finished.recv().unwrap();
+                    Add(10)
+                });
+            }
+            Fate::Live
+        },
+        false,
+    );
+    let counter = system.spawn_many(vec![Counter::new(0)])[0];
+    let count = |system: &ActorSystem| system.instance::<Counter>(counter).unwrap().count;
+
+    system.send(counter.as_raw(), Add(1));
+    system.process_all_messages();
+    system.process_all_messages();
+    assert_eq!(count(&system), 1);
+
+    finish.send(()).unwrap();
+    let started = Instant::now();
+    while count(&system) == 1 && started.elapsed() < Duration::from_secs(10) {
+        ::std::thread::sleep(Duration::from_millis(1));
+        system.process_all_messages();
+    }
+    assert_eq!(count(&system), 11);
+}
@@ -0,0 +1,79 @@
+//! Fixtures shared by the tests of several modules
+
+use crate::actor::Actor;
+use crate::actor_system::ActorSystem;
+use crate::id::{MachineID, RawID, TypedID};
+use crate::networking::Networking;
+use crate::tuning::Tuning;
+use crate::type_registry::ShortTypeId;
+
+/// The ID of an instance of the made-up class with type ID 1, for tests that only look at IDs
+pub fn test_id(instance_id: u32, machine: u16) -> RawID {
+    RawID::new(ShortTypeId::new(1).unwrap(), instance_id, MachineID(machine), 0)
+}
+
+/// A network with only this machine, listening on a free local port
+pub fn local_networking() -> Networking {
+    let address = "127.0.0.1:0".parse().unwrap();
+    Networking::new(0, vec![address], 50_000, 30, 10).unwrap()
+}
+
+/// A system that is alone in its network
+pub fn local_system() -> ActorSystem {
+    ActorSystem::new(local_networking(), Tuning::default())
+}
+
+/// Define a test actor class counting up, with its ID type
+macro_rules! counting_actor {
+    ($actor:ident, $id:ident) => {
+        #[derive(Compact, Clone)]
+        pub struct $actor {
+            pub id: $id,
+            pub count: u32,
+        }
+
+        #[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+        pub struct $id {
+            _raw_id: RawID,
+        }
+
+        impl TypedID for $id {
+            type Target = $actor;
+
+            fn from_raw(id: RawID) -> Self {
+                $id { _raw_id: id }
+            }
+            fn as_raw(&self) -> RawID {
+                self._raw_id
+            }
+        }
+
+        impl Actor for $actor {
+            type ID = $id;
+            fn id(&self) -> Self::ID {
+                self.id
+            }
+            unsafe fn set_id(&mut self, id: RawID) {
+                self.id = Self::ID::from_raw(id);
+            }
+        }
+
+        impl $actor {
+            /// A new instance, whose ID is set once it is spawned
+            pub fn new(count: u32) -> $actor {
+                $actor {
+                    id: $id::from_raw(test_id(0, 0)),
+                    count,
+                }
+            }
+        }
+    };
+}
+
+counting_actor!(Counter, CounterID);
+counting_actor!(OtherCounter, OtherCounterID);
+counting_actor!(ThirdCounter, ThirdCounterID);
+
+/// Makes a counter count up by this much
+#[derive(Compact, Clone)]
+pub struct Add(pub u32);
@@ -0,0 +1,21 @@
+/// Wall clock time in milliseconds since the Unix epoch,
+/// available both natively and in the browser
+#[cfg(feature = "server")]
+pub fn now_ms() -> f64 {
+    let since_epoch = ::std::time::SystemTime::now()
+        .duration_since(::std::time::UNIX_EPOCH)
+        .expect("System clock is before the Unix epoch");
+    since_epoch.as_secs() as f64 * 1000.0 + f64::from(since_epoch.subsec_nanos()) / 1_000_000.0
+}
+
+/// Wall clock time in milliseconds since the Unix epoch,
+/// available both natively and in the browser
+#[cfg(feature = "browser")]
+pub fn now_ms() -> f64 {
+    ::stdweb::web::Date::now()
+}
+
+/// Convert a `Duration` to (fractional) milliseconds
+pub fn duration_ms(duration: ::std::time::Duration) -> f64 {
+    duration.as_secs() as f64 * 1000.0 + f64::from(duration.subsec_nanos()) / 1_000_000.0
+}
@@ -0,0 +1,115 @@
+use crate::id::RawID;
+use crate::messaging::Message;
+use crate::type_registry::ShortTypeId;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+
+/// A named topic that actors can subscribe to with `World::subscribe`,
+/// to receive all messages of type `M` published to it with `World::publish`,
+/// without the publisher knowing the subscribers.
+/// Topics with the same name but different message types are different topics.
+pub struct Topic<M: Message> {
+    key: u64,
+    message: PhantomData<M>,
+}
+
+impl<M: Message> Topic<M> {
+    /// Refer to the topic with this name, topics with the same name are the same
+    /// on all machines
+    pub fn new(name: &str) -> Topic<M> {
+        Topic {
+            key: topic_key(name),
+            message: PhantomData,
+        }
+    }
+
+    /// The key of the topic, given the type ID `M` is registered with
+    pub(crate) fn key(&self, message_type_id: ShortTypeId) -> u64 {
+        let message_type_id = u16::from(message_type_id);
+        fnv1a(self.key, &[message_type_id as u8, (message_type_id >> 8) as u8])
+    }
+}
+
+impl<M: Message> Clone for Topic<M> {
+    fn clone(&self) -> Topic<M> {
+        Topic {
+            key: self.key,
+            message: PhantomData,
+        }
+    }
+}
+
+impl<M: Message> Copy for Topic<M> {}
+
+/// FNV-1a, which (unlike the standard hasher) is guaranteed
+/// to give the same key for a name on all machines
+fn topic_key(name: &str) -> u64 {
+    fnv1a(0xcbf2_9ce4_8422_2325, name.as_bytes())
+}
+
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Broadcast to the system services of all machines to replicate subscriptions
+#[derive(Compact, Clone)]
+pub(crate) struct TopicSubscription {
+    pub topic: u64,
+    pub subscriber: RawID,
+    pub subscribed: bool,
+}
+
+/// The subscribers of all topics, as known on this machine
+pub(crate) struct Topics {
+    subscribers: HashMap<u64, Vec<RawID>>,
+}
+
+impl Topics {
+    pub fn new() -> Topics {
+        Topics {
+            subscribers: HashMap::new(),
+        }
+    }
+
+    pub fn update(&mut self, subscription: &TopicSubscription) {
+        let subscribers = self
+            .subscribers
+            .entry(subscription.topic)
+            .or_insert_with(Vec::new);
+        subscribers.retain(|&subscriber| subscriber != subscription.subscriber);
+        if subscription.subscribed {
+            subscribers.push(subscription.subscriber);
+        }
+    }
+
+    pub fn subscribers(&self, topic: u64) -> Vec<RawID> {
+        self.subscribers.get(&topic).cloned().unwrap_or_else(Vec::new)
+    }
+}
+
+#[test]
+fn test_topic_subscriptions() {
+    let mut topics = Topics::new();
+    let (u32_id, u64_id) = (ShortTypeId::new(1).unwrap(), ShortTypeId::new(2).unwrap());
+    let topic = Topic::<u32>::new("traffic");
+    let key = topic.key(u32_id);
+    assert_eq!(key, Topic::<u32>::new("traffic").key(u32_id));
+    assert!(key != Topic::<u32>::new("weather").key(u32_id));
+    assert!(key != Topic::<u64>::new("traffic").key(u64_id));
+
+    let subscriber = crate::test_support::test_id(7, 1);
+    let mut subscription = TopicSubscription {
+        topic: key,
+        subscriber,
+        subscribed: true,
+    };
+    topics.update(&subscription);
+    topics.update(&subscription);
+    assert_eq!(topics.subscribers(key), vec![subscriber]);
+
+    subscription.subscribed = false;
+    topics.update(&subscription);
+    assert!(topics.subscribers(key).is_empty());
+}
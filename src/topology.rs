@@ -0,0 +1,153 @@
+use crate::type_registry::ShortTypeId;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// Counts which actor classes send which message types to which classes,
+/// while a sampling window is open
+pub(crate) struct TopologySampler {
+    /// `(sender class, message type, recipient class)`, the sender is `None` for
+    /// messages sent from outside of message handlers
+    counts: BTreeMap<(Option<u16>, u16, u16), usize>,
+}
+
+impl TopologySampler {
+    pub fn new() -> TopologySampler {
+        TopologySampler {
+            counts: BTreeMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, sender: Option<ShortTypeId>, message_type: ShortTypeId, recipient: ShortTypeId) {
+        *self
+            .counts
+            .entry((sender.map(|id| id.as_u16()), message_type.as_u16(), recipient.as_u16()))
+            .or_insert(0) += 1;
+    }
+
+    /// Resolve the type IDs of all sampled sends to names
+    pub fn into_topology<F: Fn(u16) -> String, G: Fn(u16) -> String>(
+        self,
+        actor_name: F,
+        message_name: G,
+    ) -> MessageTopology {
+        MessageTopology {
+            edges: self
+                .counts
+                .into_iter()
+                .map(|((sender, message_type, recipient), count)| TopologyEdge {
+                    sender: sender.map(&actor_name),
+                    message_type: message_name(message_type),
+                    recipient: actor_name(recipient),
+                    count,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// One kind of message sent between two actor classes during the sampling window
+#[derive(Clone, Debug, PartialEq)]
+pub struct TopologyEdge {
+    /// The sending actor class, `None` for messages sent from outside of message handlers
+    pub sender: Option<String>,
+    /// The type of the sent messages
+    pub message_type: String,
+    /// The receiving actor class (or actor trait)
+    pub recipient: String,
+    /// How many messages were sent
+    pub count: usize,
+}
+
+/// The directed graph of which actor classes sent which messages to which classes,
+/// returned by `ActorSystem::stop_topology_sampling`
+#[derive(Clone, Debug, Default)]
+pub struct MessageTopology {
+    /// All sender, message type and recipient combinations that occurred
+    pub edges: Vec<TopologyEdge>,
+}
+
+/// The node name used for senders outside of message handlers
+const OUTSIDE: &str = "(outside)";
+
+impl MessageTopology {
+    /// Export as a Graphviz DOT digraph, with one node per actor class
+    /// and one labelled edge per message type
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph kay {\n");
+        for edge in &self.edges {
+            writeln!(
+                dot,
+                "    {} -> {} [label={}];",
+                dot_id(edge.sender.as_ref().map_or(OUTSIDE, String::as_str)),
+                dot_id(&edge.recipient),
+                dot_id(&format!("{} ({})", edge.message_type, edge.count)),
+            )
+            .unwrap();
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Export as JSON: `{"edges": [{"sender": ..., "message_type": ..., "recipient": ..., "count": ...}]}`,
+    /// with `null` as the sender of messages sent from outside of message handlers
+    pub fn to_json(&self) -> String {
+        let edges = self
+            .edges
+            .iter()
+            .map(|edge| {
+                format!(
+                    "{{\"sender\":{},\"message_type\":{},\"recipient\":{},\"count\":{}}}",
+                    edge.sender.as_ref().map_or("null".to_owned(), |sender| json_string(sender)),
+                    json_string(&edge.message_type),
+                    json_string(&edge.recipient),
+                    edge.count
+                )
+            })
+            .collect::<Vec<_>>();
+        format!("{{\"edges\":[{}]}}", edges.join(","))
+    }
+}
+
+fn dot_id(name: &str) -> String {
+    format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+pub(crate) fn json_string(string: &str) -> String {
+    let mut escaped = String::with_capacity(string.len() + 2);
+    escaped.push('"');
+    for character in string.chars() {
+        match character {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            character if (character as u32) < 0x20 => {
+                write!(escaped, "\\u{:04x}", character as u32).unwrap()
+            }
+            character => escaped.push(character),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+#[test]
+fn test_topology_export() {
+    let id = |id| ShortTypeId::new(id).unwrap();
+    let mut sampler = TopologySampler::new();
+    sampler.record(Some(id(1)), id(3), id(2));
+    sampler.record(Some(id(1)), id(3), id(2));
+    sampler.record(None, id(4), id(1));
+
+    let topology = sampler.into_topology(
+        |id| ["", "Car", "Lane"][id as usize].to_owned(),
+        |id| format!("Message{}", id),
+    );
+    assert_eq!(
+        topology.to_dot(),
+        "digraph kay {\n    \"(outside)\" -> \"Car\" [label=\"Message4 (1)\"];\n    \"Car\" -> \"Lane\" [label=\"Message3 (2)\"];\n}\n"
+    );
+    assert_eq!(
+        topology.to_json(),
+        "{\"edges\":[{\"sender\":null,\"message_type\":\"Message4\",\"recipient\":\"Car\",\"count\":1},\
+         {\"sender\":\"Car\",\"message_type\":\"Message3\",\"recipient\":\"Lane\",\"count\":2}]}"
+    );
+}
@@ -0,0 +1,84 @@
+use crate::topology::json_string;
+use std::io::Write;
+
+/// Records spans of what the actor system does during some turns as Chrome trace events
+/// (see `ActorSystem::record_trace`), to be viewed in chrome://tracing or Perfetto
+pub(crate) struct TraceRecorder {
+    writer: Box<dyn Write>,
+    /// Each event, already encoded as JSON
+    events: Vec<String>,
+    turns_left: usize,
+    /// Traces of several machines can be combined, each machine gets its own process
+    machine_id: u16,
+}
+
+impl TraceRecorder {
+    pub fn new(writer: Box<dyn Write>, n_turns: usize, machine_id: u16) -> TraceRecorder {
+        TraceRecorder {
+            writer,
+            events: Vec::new(),
+            turns_left: n_turns,
+            machine_id,
+        }
+    }
+
+    /// Record a span from `start_ms` to `end_ms` (as measured by `now_ms`)
+    pub fn span(&mut self, name: &str, category: &str, start_ms: f64, end_ms: f64, args: &[(&str, usize)]) {
+        let args = args
+            .iter()
+            .map(|(arg, value)| format!("{}:{}", json_string(arg), value))
+            .collect::<Vec<_>>();
+        self.events.push(format!(
+            "{{\"name\":{},\"cat\":{},\"ph\":\"X\",\"ts\":{:.3},\"dur\":{:.3},\"pid\":{},\"tid\":0,\"args\":{{{}}}}}",
+            json_string(name),
+            json_string(category),
+            start_ms * 1000.0,
+            (end_ms - start_ms) * 1000.0,
+            self.machine_id,
+            args.join(",")
+        ));
+    }
+
+    /// Record something that happened at a point in time
+    pub fn instant(&mut self, name: &str, category: &str, at_ms: f64) {
+        self.events.push(format!(
+            "{{\"name\":{},\"cat\":{},\"ph\":\"i\",\"s\":\"p\",\"ts\":{:.3},\"pid\":{},\"tid\":0}}",
+            json_string(name),
+            json_string(category),
+            at_ms * 1000.0,
+            self.machine_id
+        ));
+    }
+
+    /// Called when a turn starts, returns false once all turns to record are over
+    pub fn start_turn(&mut self) -> bool {
+        if self.turns_left == 0 {
+            false
+        } else {
+            self.turns_left -= 1;
+            true
+        }
+    }
+
+    /// Write all recorded events as a trace file
+    pub fn finish(mut self) -> ::std::io::Result<()> {
+        write!(self.writer, "{{\"traceEvents\":[{}],\"displayTimeUnit\":\"ms\"}}", self.events.join(",\n"))?;
+        self.writer.flush()
+    }
+}
+
+#[test]
+fn test_trace_events() {
+    let mut recorder = TraceRecorder::new(Box::new(Vec::new()), 1, 3);
+    assert!(recorder.start_turn());
+    recorder.span("Turn 7", "turn", 1.0, 2.5, &[("n_turns", 7)]);
+    recorder.instant("Waiting for peers", "barrier", 3.0);
+    assert!(!recorder.start_turn());
+    assert_eq!(
+        recorder.events,
+        vec![
+            "{\"name\":\"Turn 7\",\"cat\":\"turn\",\"ph\":\"X\",\"ts\":1000.000,\"dur\":1500.000,\"pid\":3,\"tid\":0,\"args\":{\"n_turns\":7}}",
+            "{\"name\":\"Waiting for peers\",\"cat\":\"barrier\",\"ph\":\"i\",\"s\":\"p\",\"ts\":3000.000,\"pid\":3,\"tid\":0}",
+        ]
+    );
+}
@@ -1,9 +1,21 @@
+#[derive(Clone)]
 pub struct Tuning {
     pub instance_chunk_size: usize,
     pub instance_entry_chunk_size: usize,
     pub instance_versions_chunk_size: usize,
     pub instance_free_chunk_size: usize,
-    pub inbox_queue_chunk_size: usize
+    pub inbox_queue_chunk_size: usize,
+    /// How many actor classes and traits a system has room for (type IDs are below this)
+    pub max_actor_types: usize,
+    /// How many message types a system has room for (type IDs are below this)
+    pub max_message_types: usize,
+    /// Once the inbox of a class holds this many bytes of messages in memory, write further
+    /// messages to a temporary file and read them back while handling them, to bound memory
+    /// during long backlogs like loading screens (default `None`, never spill)
+    pub inbox_spill_bytes: Option<usize>,
+    /// Report a `SystemEvent::InboxOverflow` once the inbox of a class grows beyond this many
+    /// bytes of messages in memory, whether or not it spills (default 64 MiB, `None` to never report)
+    pub inbox_overflow_bytes: Option<usize>,
 }
 
 impl ::std::default::Default for Tuning {
@@ -13,7 +25,11 @@ impl ::std::default::Default for Tuning {
             instance_entry_chunk_size: 1024 * 1024,
             instance_versions_chunk_size: 512 * 1024,
             instance_free_chunk_size: 8 * 1024,
-            inbox_queue_chunk_size: 1024 * 1024
+            inbox_queue_chunk_size: 1024 * 1024,
+            max_actor_types: 64,
+            max_message_types: 256,
+            inbox_spill_bytes: None,
+            inbox_overflow_bytes: Some(64 * 1024 * 1024),
         }
     }
 }
\ No newline at end of file
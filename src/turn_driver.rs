@@ -0,0 +1,204 @@
+use crate::actor_system::ActorSystem;
+use crate::time::{duration_ms, now_ms};
+use std::time::Duration;
+
+/// How fast a `TurnDriver` advances the simulation
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SimulationSpeed {
+    /// Don't advance turns, only keep networking connections alive
+    Paused,
+    /// One turn per tick
+    Normal,
+    /// Two turns per tick
+    Double,
+    /// As many turns as fit into the duration of one tick
+    FastForward,
+}
+
+/// Runs networking turns at a fixed tick rate, so embedders don't have to write their own turn loop.
+///
+/// Call `drive` as often as possible (from a loop on the server, see `run_until`,
+/// or from `requestAnimationFrame` in the browser): it runs as many turns as are due
+/// since the last call, at the current speed. If turns are late because a frame took
+/// too long, at most `max_catch_up_turns` are run at once and the rest of the backlog
+/// is dropped, so a slow machine doesn't fall further and further behind.
+///
+/// Uses the tick duration from adaptive pacing instead of the fixed one,
+/// if it is enabled (see `Networking::enable_adaptive_pacing`), and waits while
+/// networking asks to skip turns because we are ahead of our peers,
+/// or while the network is paused (see `Networking::request_pause`).
+pub struct TurnDriver {
+    tick_ms: f64,
+    speed: SimulationSpeed,
+    max_catch_up_turns: usize,
+    /// Time that is due to be simulated, already multiplied by the speed
+    backlog_ms: f64,
+    last_drive_ms: Option<f64>,
+    /// Turns networking asked us to wait for
+    skip_turns: usize,
+    /// A turn whose messages were processed, but that is waiting for peers in strict lockstep
+    finishing: bool,
+}
+
+impl TurnDriver {
+    /// Create a driver running one turn every `tick` (at normal speed)
+    pub fn new(tick: Duration) -> TurnDriver {
+        TurnDriver {
+            tick_ms: duration_ms(tick),
+            speed: SimulationSpeed::Normal,
+            max_catch_up_turns: 5,
+            backlog_ms: 0.0,
+            last_drive_ms: None,
+            skip_turns: 0,
+            finishing: false,
+        }
+    }
+
+    /// Change the simulation speed, takes effect with the next `drive`
+    pub fn set_speed(&mut self, speed: SimulationSpeed) {
+        if speed == SimulationSpeed::Paused {
+            self.backlog_ms = 0.0;
+        }
+        self.speed = speed;
+    }
+
+    /// The current simulation speed
+    pub fn speed(&self) -> SimulationSpeed {
+        self.speed
+    }
+
+    /// How many late turns one `drive` may run at most to catch up (at normal speed, default: 5)
+    pub fn set_max_catch_up_turns(&mut self, max_catch_up_turns: usize) {
+        self.max_catch_up_turns = max_catch_up_turns.max(1);
+    }
+
+    fn tick_ms(&self, system: &ActorSystem) -> f64 {
+        system
+            .networking_target_tick_duration()
+            .map_or(self.tick_ms, duration_ms)
+    }
+
+    /// Run all turns that are due, returns how many turns were finished
+    pub fn drive(&mut self, system: &mut ActorSystem) -> usize {
+        let now = now_ms();
+        let first_drive = self.last_drive_ms.is_none();
+        let elapsed_ms = self.last_drive_ms.map_or(0.0, |last| (now - last).max(0.0));
+        self.last_drive_ms = Some(now);
+
+        system.networking_send_and_receive();
+
+        let tick_ms = self.tick_ms(system);
+        let (max_turns, deadline_ms) = match self.speed {
+            SimulationSpeed::Paused => return 0,
+            SimulationSpeed::Normal | SimulationSpeed::Double => {
+                let multiplier = if self.speed == SimulationSpeed::Double { 2.0 } else { 1.0 };
+                self.backlog_ms += elapsed_ms * multiplier;
+                if first_drive {
+                    self.backlog_ms = self.backlog_ms.max(tick_ms);
+                }
+                let max_backlog_ms = tick_ms * self.max_catch_up_turns as f64 * multiplier;
+                self.backlog_ms = self.backlog_ms.min(max_backlog_ms);
+                ((self.backlog_ms / tick_ms) as usize, None)
+            }
+            SimulationSpeed::FastForward => {
+                self.backlog_ms = 0.0;
+                (usize::max_value(), Some(now + tick_ms))
+            }
+        };
+
+        let mut n_finished = 0;
+        let mut n_due = max_turns;
+        while n_due > 0 {
+            if let Some(deadline_ms) = deadline_ms {
+                if n_finished > 0 && now_ms() >= deadline_ms {
+                    break;
+                }
+            }
+            n_due -= 1;
+            if deadline_ms.is_none() {
+                self.backlog_ms -= tick_ms;
+            }
+
+            if self.skip_turns > 0 {
+                self.skip_turns -= 1;
+                continue;
+            }
+
+            if !self.finishing {
+                system.process_all_messages();
+                self.finishing = true;
+            }
+
+            match system.networking_try_finish_turn() {
+                Ok(_) if system.networking_is_paused() => {
+                    // the turn is only finished once the network resumes, tried again with the next drive
+                    self.backlog_ms = tick_ms;
+                    break;
+                }
+                Ok(maybe_skip_turns) => {
+                    self.finishing = false;
+                    self.skip_turns = maybe_skip_turns.unwrap_or(0);
+                    n_finished += 1;
+                    system.networking_send_and_receive();
+                }
+                Err(_waiting_for_peers) => {
+                    // try again with the next drive, without catching up on the time in between
+                    self.backlog_ms = tick_ms;
+                    break;
+                }
+            }
+        }
+
+        n_finished
+    }
+
+    /// How long until the next turn is due, to sleep or set a timeout for
+    pub fn time_until_next_turn(&self, system: &ActorSystem) -> Duration {
+        let wait_ms = match self.speed {
+            SimulationSpeed::Paused => self.tick_ms(system),
+            SimulationSpeed::Normal | SimulationSpeed::Double => {
+                let multiplier = if self.speed == SimulationSpeed::Double { 2.0 } else { 1.0 };
+                let since_last_drive_ms = self.last_drive_ms.map_or(0.0, |last| (now_ms() - last).max(0.0));
+                ((self.tick_ms(system) - self.backlog_ms) / multiplier - since_last_drive_ms).max(0.0)
+            }
+            SimulationSpeed::FastForward => 0.0,
+        };
+        Duration::from_micros((wait_ms * 1000.0) as u64)
+    }
+
+    /// Drive the system until `stop` returns true, sleeping between turns.
+    /// `stop` is called before each `drive`, it can also be used to send messages from outside.
+    #[cfg(feature = "server")]
+    pub fn run_until<F: FnMut(&mut ActorSystem) -> bool>(&mut self, system: &mut ActorSystem, mut stop: F) {
+        while !stop(system) {
+            self.drive(system);
+            ::std::thread::sleep(self.time_until_next_turn(system).min(Duration::from_millis(
+                self.tick_ms as u64,
+            )));
+        }
+    }
+}
+
+#[test]
+fn test_paused_network_finishes_no_turns() {
+    use crate::test_support::local_system;
+
+    let mut system = local_system();
+    let mut driver = TurnDriver::new(Duration::from_millis(1));
+    driver.set_speed(SimulationSpeed::FastForward);
+    system.networking_request_pause();
+
+    let mut n_finished = 0;
+    while !system.networking_is_paused() {
+        n_finished += driver.drive(&mut system);
+        assert!(n_finished < 100, "Network should have paused");
+    }
+    let paused_at = system.networking_n_turns();
+    assert_eq!(n_finished, paused_at);
+    assert_eq!(driver.drive(&mut system), 0);
+    assert_eq!(system.networking_n_turns(), paused_at);
+
+    system.networking_request_resume();
+    assert!(driver.drive(&mut system) > 0);
+    assert!(system.networking_n_turns() > paused_at);
+}
@@ -0,0 +1,313 @@
+use crate::state_hash::xxh64;
+use crate::topology::json_string;
+
+const HEADER: &str = "kay type manifest 1";
+
+/// Whether a type in a `TypeManifest` is an actor class (or trait) or a message type
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum TypeKind {
+    /// An actor class or actor trait, whose ID is the type ID in `RawID`s
+    Actor,
+    /// A message type, whose ID precedes each message on the wire
+    Message,
+}
+
+impl TypeKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            TypeKind::Actor => "actor",
+            TypeKind::Message => "message",
+        }
+    }
+}
+
+/// A registered type, with its type ID and a hash of its layout and fields
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct TypeManifestEntry {
+    /// Whether it is an actor or message type
+    pub kind: TypeKind,
+    /// The type ID
+    pub id: u16,
+    /// The full name of the type, including the namespace of its plugin
+    pub name: String,
+    /// The size of the fixed part of the type in bytes
+    pub size: usize,
+    /// The alignment of the type in bytes
+    pub align: usize,
+    /// A hash of the name, size, alignment and (for types implementing `TypeSchema`)
+    /// the names and types of all fields, which changes when the fixed layout or the fields
+    /// of the type change (but not when only the contents of dynamic parts change)
+    pub schema_hash: u64,
+}
+
+impl TypeManifestEntry {
+    pub(crate) fn new(
+        kind: TypeKind,
+        id: u16,
+        name: String,
+        size: usize,
+        align: usize,
+        fields: &[(&str, &str)],
+    ) -> TypeManifestEntry {
+        let mut schema = format!("{}:{}:{}", name, size, align);
+        for (field, field_type) in fields {
+            // how types are stringified differs in whitespace between compiler versions
+            schema.push_str(&format!(";{}:{}", field, field_type.split_whitespace().collect::<String>()));
+        }
+        let schema_hash = xxh64(schema.as_bytes(), 0);
+        TypeManifestEntry {
+            kind,
+            id,
+            name,
+            size,
+            align,
+            schema_hash,
+        }
+    }
+}
+
+/// Describes the fields of a type, so they are part of its schema hash in a `TypeManifest`.
+/// Implement it with `type_schema!` for structs. Types that don't implement it
+/// are only described by their name and layout.
+pub trait TypeSchema {
+    /// The names and types of all fields, in the order they are declared in
+    fn fields() -> Vec<(&'static str, &'static str)>;
+}
+
+/// Implement `TypeSchema` for a struct with named fields by listing all of them with their types,
+/// which fails to compile once the list doesn't match the struct anymore:
+/// `type_schema!(Car { position: f32, speed: f32 });`
+/// (or `type_schema!(Reply<R> { reply: R });` for a generic struct)
+#[macro_export]
+macro_rules! type_schema {
+    ($type:ident<$($param:ident),*> { $($field:ident: $field_type:ty),* }) => {
+        impl<$($param),*> $crate::TypeSchema for $type<$($param),*> {
+            fn fields() -> Vec<(&'static str, &'static str)> {
+                #[allow(dead_code)]
+                fn matches_declaration<$($param),*>(value: &$type<$($param),*>) {
+                    let &$type { $(ref $field),* } = value;
+                    $(let _: &$field_type = $field;)*
+                }
+                vec![$((stringify!($field), stringify!($field_type))),*]
+            }
+        }
+    };
+    ($type:ident { $($field:ident: $field_type:ty),* }) => {
+        impl $crate::TypeSchema for $type {
+            fn fields() -> Vec<(&'static str, &'static str)> {
+                #[allow(dead_code)]
+                fn matches_declaration(value: &$type) {
+                    let &$type { $(ref $field),* } = value;
+                    $(let _: &$field_type = $field;)*
+                }
+                vec![$((stringify!($field), stringify!($field_type))),*]
+            }
+        }
+    };
+}
+
+/// The fields of `T` if it implements `TypeSchema`, none otherwise
+pub(crate) fn schema_fields<T>() -> Vec<(&'static str, &'static str)> {
+    <T as SchemaFields>::schema_fields()
+}
+
+trait SchemaFields {
+    fn schema_fields() -> Vec<(&'static str, &'static str)>;
+}
+
+impl<T> SchemaFields for T {
+    default fn schema_fields() -> Vec<(&'static str, &'static str)> {
+        Vec::new()
+    }
+}
+
+impl<T: TypeSchema> SchemaFields for T {
+    fn schema_fields() -> Vec<(&'static str, &'static str)> {
+        T::fields()
+    }
+}
+
+/// The type IDs of all actor and message types of a system, ordered by kind and ID, so external
+/// tools (replay analyzers, save editors, bots written in other languages) can interpret
+/// messages, IDs and snapshots, see `ActorSystem::export_type_manifest`.
+///
+/// The text format is a header line followed by one line per type, with tab-separated fields:
+/// `actor|message`, ID, size, alignment, schema hash (16 hex digits) and the name.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct TypeManifest {
+    /// All types
+    pub entries: Vec<TypeManifestEntry>,
+}
+
+impl TypeManifest {
+    /// Find the entry of a type by its kind and name
+    pub fn get(&self, kind: TypeKind, name: &str) -> Option<&TypeManifestEntry> {
+        self.entries.iter().find(|entry| entry.kind == kind && entry.name == name)
+    }
+
+    /// Write the manifest in its text format
+    pub fn to_text(&self) -> String {
+        let mut text = format!("{}\n", HEADER);
+        for entry in &self.entries {
+            text.push_str(&format!(
+                "{}\t{}\t{}\t{}\t{:016x}\t{}\n",
+                entry.kind.as_str(),
+                entry.id,
+                entry.size,
+                entry.align,
+                entry.schema_hash,
+                entry.name
+            ));
+        }
+        text
+    }
+
+    /// Export as JSON: `{"types": [{"kind": ..., "id": ..., "name": ..., "size": ...,
+    /// "align": ..., "schema_hash": ...}]}`, with the schema hash as a hex string
+    pub fn to_json(&self) -> String {
+        let types = self
+            .entries
+            .iter()
+            .map(|entry| {
+                format!(
+                    "{{\"kind\":\"{}\",\"id\":{},\"name\":{},\"size\":{},\"align\":{},\"schema_hash\":\"{:016x}\"}}",
+                    entry.kind.as_str(),
+                    entry.id,
+                    json_string(&entry.name),
+                    entry.size,
+                    entry.align,
+                    entry.schema_hash
+                )
+            })
+            .collect::<Vec<_>>();
+        format!("{{\"types\":[{}]}}", types.join(","))
+    }
+
+    /// Read a manifest in the text format written by `to_text`
+    pub fn parse(text: &str) -> Result<TypeManifest, String> {
+        let mut lines = text.lines();
+        match lines.next() {
+            Some(header) if header.trim_end() == HEADER => {}
+            _ => return Err(format!("A type manifest needs to start with \"{}\"", HEADER)),
+        }
+        let mut entries = Vec::new();
+        for (i, line) in lines.enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+            let line_number = i + 2;
+            let fields = line.splitn(6, '\t').collect::<Vec<_>>();
+            if fields.len() != 6 {
+                return Err(format!("Line {} needs 6 tab-separated fields", line_number));
+            }
+            let kind = match fields[0] {
+                "actor" => TypeKind::Actor,
+                "message" => TypeKind::Message,
+                other => return Err(format!("Unknown kind of type {} in line {}", other, line_number)),
+            };
+            let number = |field: &str| {
+                field
+                    .parse::<usize>()
+                    .map_err(|_| format!("Invalid number {} in line {}", field, line_number))
+            };
+            let id = fields[1]
+                .parse::<u16>()
+                .map_err(|_| format!("Invalid type ID {} in line {}", fields[1], line_number))?;
+            let schema_hash = u64::from_str_radix(fields[4], 16)
+                .map_err(|_| format!("Invalid schema hash {} in line {}", fields[4], line_number))?;
+            entries.push(TypeManifestEntry {
+                kind,
+                id,
+                name: fields[5].to_owned(),
+                size: number(fields[2])?,
+                align: number(fields[3])?,
+                schema_hash,
+            });
+        }
+        Ok(TypeManifest { entries })
+    }
+
+    /// Describe how `other` (for example the manifest of this system) differs from this manifest:
+    /// types of this manifest that are missing in `other` or have another ID or schema hash there
+    pub fn differences(&self, other: &TypeManifest) -> Vec<String> {
+        self.entries
+            .iter()
+            .filter_map(|entry| match other.get(entry.kind, &entry.name) {
+                None => Some(format!("{} {} is not registered", entry.kind.as_str(), entry.name)),
+                Some(other_entry) if other_entry.id != entry.id => Some(format!(
+                    "{} {} has type ID {} instead of {}",
+                    entry.kind.as_str(),
+                    entry.name,
+                    other_entry.id,
+                    entry.id
+                )),
+                Some(other_entry) if other_entry.schema_hash != entry.schema_hash => Some(format!(
+                    "{} {} has a different schema ({} bytes, aligned to {}, instead of {} bytes, aligned to {}, or other fields)",
+                    entry.kind.as_str(),
+                    entry.name,
+                    other_entry.size,
+                    other_entry.align,
+                    entry.size,
+                    entry.align
+                )),
+                Some(_) => None,
+            })
+            .collect()
+    }
+}
+
+#[test]
+fn test_type_manifest_roundtrip() {
+    let manifest = TypeManifest {
+        entries: vec![
+            TypeManifestEntry::new(TypeKind::Actor, 1, "game::Car".to_owned(), 48, 8, &[]),
+            TypeManifestEntry::new(TypeKind::Message, 3, "game::Honk<u8, u16>".to_owned(), 4, 2, &[]),
+        ],
+    };
+    let text = manifest.to_text();
+    assert!(text.starts_with("kay type manifest 1\nactor\t1\t48\t8\t"));
+    assert_eq!(TypeManifest::parse(&text), Ok(manifest.clone()));
+    assert!(TypeManifest::parse("actor\t1\t48\t8\t0\tgame::Car").is_err());
+
+    let mut changed = manifest.clone();
+    changed.entries[0] = TypeManifestEntry::new(TypeKind::Actor, 2, "game::Car".to_owned(), 48, 8, &[]);
+    changed.entries[1] = TypeManifestEntry::new(TypeKind::Message, 3, "game::Honk<u8, u16>".to_owned(), 8, 4, &[]);
+    assert_eq!(
+        manifest.differences(&changed),
+        vec![
+            "actor game::Car has type ID 2 instead of 1",
+            "message game::Honk<u8, u16> has a different schema (8 bytes, aligned to 4, instead of 4 bytes, aligned to 2, or other fields)",
+        ]
+    );
+    assert_eq!(manifest.differences(&manifest), Vec::<String>::new());
+}
+
+#[test]
+fn test_schema_hash_covers_fields() {
+    use crate::type_registry::TypeRegistry;
+
+    #[allow(dead_code)]
+    struct Car {
+        position: f32,
+        speed: f32,
+    }
+    type_schema!(Car { position: f32, speed: f32 });
+
+    #[allow(dead_code)]
+    struct Reply<R> {
+        reply: R,
+    }
+    type_schema!(Reply<R> { reply: R });
+
+    let layout_only = TypeManifestEntry::new(TypeKind::Actor, 1, "game::Car".to_owned(), 8, 4, &[]);
+    let with_fields = TypeManifestEntry::new(TypeKind::Actor, 1, "game::Car".to_owned(), 8, 4, &Car::fields());
+    let renamed = TypeManifestEntry::new(TypeKind::Actor, 1, "game::Car".to_owned(), 8, 4, &[("x", "f32"), ("speed", "f32")]);
+    let retyped = TypeManifestEntry::new(TypeKind::Actor, 1, "game::Car".to_owned(), 8, 4, &[("position", "u32"), ("speed", "f32")]);
+    assert!(layout_only.schema_hash != with_fields.schema_hash);
+    assert!(with_fields.schema_hash != renamed.schema_hash);
+    assert!(with_fields.schema_hash != retyped.schema_hash);
+    assert_eq!(Reply::<u8>::fields(), vec![("reply", "R")]);
+
+    let mut registry = TypeRegistry::new();
+    registry.register_new::<Car>();
+    let registered = registry.export(TypeKind::Actor).remove(0);
+    let expected = TypeManifestEntry::new(TypeKind::Actor, registered.id, registered.name.clone(), 8, 4, &Car::fields());
+    assert_eq!(registered, expected);
+}
@@ -1,7 +1,11 @@
+use crate::id::MachineID;
+use crate::type_manifest::{schema_fields, TypeKind, TypeManifestEntry};
+use compact::CString;
 use std::collections::HashMap;
 use std::convert::From;
 use std::intrinsics::{type_id, type_name};
 use std::num::NonZeroU16;
+use std::ops::Range;
 
 #[cfg_attr(
     feature = "serde-serialization",
@@ -30,30 +34,327 @@ impl From<ShortTypeId> for u16 {
     }
 }
 
+/// Broadcast to the system services of all machines when a type gets registered
+/// after setup, so its type ID is the same everywhere
+#[derive(Compact, Clone)]
+pub(crate) struct TypeRegistration {
+    /// An actor (or actor trait) type, otherwise a message type
+    pub actor: bool,
+    pub id: u16,
+    pub name: CString,
+    /// The machine the type was registered on
+    pub from: MachineID,
+}
+
+crate::compact_bounds!(TypeRegistration { name });
+
+/// How many type IDs of a registry are used, see `ActorSystem::actor_type_id_usage`
+/// and `ActorSystem::message_type_id_usage`
+#[derive(Clone, Debug)]
+pub struct TypeIdUsage {
+    /// The highest type ID there is room for (see `Tuning`), IDs start at 1
+    pub max_id: u16,
+    /// How many type IDs are used, including IDs that peers assigned to types not registered here
+    pub used: usize,
+    /// How many type IDs are pinned to types that aren't registered yet
+    pub pinned: usize,
+    /// The ranges reserved for namespaces (plugins) and how many of their IDs are used:
+    /// `(namespace, IDs, used)`
+    pub ranges: Vec<(String, Range<u16>, usize)>,
+}
+
+impl ::std::fmt::Display for TypeIdUsage {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "{} of {} type IDs used, {} pinned", self.used, self.max_id, self.pinned)?;
+        for (namespace, ids, used) in &self.ranges {
+            write!(f, ", {}: {} of {} ({}-{})", namespace, used, ids.len(), ids.start, ids.end - 1)?;
+        }
+        Ok(())
+    }
+}
+
 pub struct TypeRegistry {
-    next_short_id: ShortTypeId,
+    /// One past the highest used type ID
+    next_short_id: u32,
+    /// The highest type ID there is room for (in the classes or message handlers of a system)
+    max_id: u16,
+    /// Types in the order they were registered here, to replicate types registered after setup
+    registration_order: Vec<ShortTypeId>,
     long_to_short_ids: HashMap<u64, ShortTypeId>,
     pub short_ids_to_names: HashMap<ShortTypeId, String>,
+    /// Type names in the order they were registered in when a snapshot was saved
+    expected_names: Vec<String>,
+    /// Type IDs that peers assigned to types not registered here yet
+    reserved: HashMap<String, ShortTypeId>,
+    /// Prefix for the names of newly registered types, to keep apart same-named types of different plugins
+    namespace: Option<String>,
+    /// Type IDs only used by the types of a namespace
+    ranges: Vec<(String, Range<u16>)>,
+    /// Type IDs chosen for types before they are registered, by their long type ID
+    pinned: HashMap<u64, ShortTypeId>,
+    /// Size, alignment and fields of the types registered here, for type manifests
+    layouts: HashMap<ShortTypeId, (usize, usize, Vec<(&'static str, &'static str)>)>,
 }
 
 impl TypeRegistry {
     pub fn new() -> TypeRegistry {
         TypeRegistry {
-            next_short_id: ShortTypeId::new(1).unwrap(), // Non nullable optimization
+            next_short_id: 1, // Non nullable optimization
+            max_id: u16::max_value(),
+            registration_order: Vec::new(),
             long_to_short_ids: HashMap::new(),
             short_ids_to_names: HashMap::new(),
+            expected_names: Vec::new(),
+            reserved: HashMap::new(),
+            namespace: None,
+            ranges: Vec::new(),
+            pinned: HashMap::new(),
+            layouts: HashMap::new(),
+        }
+    }
+
+    /// A registry that checks types are registered in the given order,
+    /// so type IDs match those of a restored snapshot
+    pub fn expecting(expected_names: Vec<String>) -> TypeRegistry {
+        TypeRegistry {
+            expected_names,
+            ..TypeRegistry::new()
+        }
+    }
+
+    /// The names of all registered types, ordered by type ID
+    pub fn names(&self) -> Vec<&str> {
+        (1..self.next_short_id)
+            // IDs assigned on peers to types that are unknown here leave gaps
+            .map(|id| self.short_ids_to_names.get(&ShortTypeId::new(id as u16).unwrap()).map_or("", String::as_str))
+            .collect()
+    }
+
+    /// Only use type IDs up to `max_id`, since a system has room for that many types
+    pub fn set_max_id(&mut self, max_id: u16) {
+        self.max_id = max_id;
+    }
+
+    /// How many types were registered here so far
+    pub fn n_registrations(&self) -> usize {
+        self.registration_order.len()
+    }
+
+    /// All types registered here after the first `n_registrations`,
+    /// without types that were only reserved by peers
+    pub fn registrations_since(&self, n_registrations: usize) -> Vec<(ShortTypeId, &str)> {
+        self.registration_order[n_registrations.min(self.registration_order.len())..]
+            .iter()
+            .map(|short_id| (*short_id, self.short_ids_to_names[short_id].as_str()))
+            .collect()
+    }
+
+    /// Use the type ID a peer assigned to a type, once it is registered here as well.
+    /// Fails if the name or the ID are already used differently here.
+    pub fn reserve(&mut self, name: &str, short_id: ShortTypeId) -> Result<(), String> {
+        if short_id.as_u16() > self.max_id {
+            return Err(format!(
+                "Type ID {} of {} is beyond the {} type IDs there is room for",
+                short_id.as_u16(),
+                name,
+                self.max_id
+            ));
+        }
+        if let Some(existing_name) = self.short_ids_to_names.get(&short_id) {
+            return if existing_name == name {
+                Ok(())
+            } else {
+                Err(format!(
+                    "Type ID {} of {} is already used by {}",
+                    short_id.as_u16(),
+                    name,
+                    existing_name
+                ))
+            };
+        }
+        if let Some((existing_id, _)) = self.short_ids_to_names.iter().find(|(_, existing_name)| existing_name.as_str() == name) {
+            return Err(format!(
+                "{} has type ID {} here, but {} elsewhere",
+                name,
+                existing_id.as_u16(),
+                short_id.as_u16()
+            ));
+        }
+        self.reserved.insert(name.to_owned(), short_id);
+        self.short_ids_to_names.insert(short_id, name.to_owned());
+        self.next_short_id = self.next_short_id.max(u32::from(short_id.as_u16()) + 1);
+        Ok(())
+    }
+
+    /// Register types under `namespace` until it is reset to `None`
+    pub fn set_namespace(&mut self, namespace: Option<String>) {
+        self.namespace = namespace;
+    }
+
+    fn is_free(&self, id: u16) -> bool {
+        let short_id = ShortTypeId::new(id).unwrap();
+        !self.short_ids_to_names.contains_key(&short_id) && !self.pinned.values().any(|pinned| *pinned == short_id)
+    }
+
+    fn check_usable(&self, ids: &Range<u16>) -> Result<(), String> {
+        if ids.start == 0 || ids.end == 0 || ids.end - 1 > self.max_id || ids.start >= ids.end {
+            Err(format!(
+                "Type IDs {}-{} are not within 1-{}",
+                ids.start,
+                ids.end.saturating_sub(1),
+                self.max_id
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Give the types registered under `namespace` IDs within `ids`, which no other types get
+    pub fn reserve_range(&mut self, namespace: &str, ids: Range<u16>) -> Result<(), String> {
+        self.check_usable(&ids)?;
+        if let Some((other, _)) = self
+            .ranges
+            .iter()
+            .find(|(_, other_ids)| other_ids.start < ids.end && ids.start < other_ids.end)
+        {
+            return Err(format!("Type IDs {}-{} overlap those reserved for {}", ids.start, ids.end - 1, other));
+        }
+        if let Some(used) = ids.clone().find(|&id| !self.is_free(id)) {
+            return Err(format!("Type ID {} is already used, so it can't be reserved for {}", used, namespace));
+        }
+        self.ranges.push((namespace.to_owned(), ids));
+        Ok(())
+    }
+
+    /// Give `T` the type ID `id` once it is registered
+    pub fn pin<T: 'static>(&mut self, id: u16) -> Result<(), String> {
+        let long_id = unsafe { type_id::<T>() };
+        let name = unsafe { type_name::<T>() };
+        self.check_usable(&(id..id.saturating_add(1)))?;
+        let short_id = ShortTypeId::new(id).unwrap();
+        if let Some(&registered_id) = self.long_to_short_ids.get(&long_id) {
+            return if registered_id == short_id {
+                Ok(())
+            } else {
+                Err(format!("{} is already registered with type ID {}", name, registered_id.as_u16()))
+            };
+        }
+        if self.pinned.get(&long_id) != Some(&short_id) && !self.is_free(id) {
+            return Err(format!("Type ID {} is already used, so it can't be pinned to {}", id, name));
+        }
+        if let Some((namespace, _)) = self.ranges.iter().find(|(_, ids)| ids.contains(&id)) {
+            return Err(format!("Type ID {} is reserved for {}, so it can't be pinned to {}", id, namespace, name));
+        }
+        self.pinned.insert(long_id, short_id);
+        Ok(())
+    }
+
+    /// How many type IDs are used, overall and in reserved ranges
+    pub fn usage(&self) -> TypeIdUsage {
+        TypeIdUsage {
+            max_id: self.max_id,
+            used: self.short_ids_to_names.len(),
+            pinned: self.pinned.len(),
+            ranges: self
+                .ranges
+                .iter()
+                .map(|(namespace, ids)| {
+                    let used = ids.clone().filter(|&id| !self.is_free(id)).count();
+                    (namespace.clone(), ids.clone(), used)
+                })
+                .collect(),
+        }
+    }
+
+    /// Choose the type ID of a newly registered type: the one it had in the restored snapshot,
+    /// the pinned one, or the next free one (in the range of its namespace, if it has one)
+    fn allocate_id(&mut self, long_id: u64, name: &str) -> Result<ShortTypeId, String> {
+        let pinned = self.pinned.remove(&long_id);
+        if let Some(index) = self.expected_names.iter().position(|expected_name| expected_name == name) {
+            return Ok(ShortTypeId::new(index as u16 + 1).unwrap());
+        }
+        if let Some(short_id) = pinned {
+            return Ok(short_id);
+        }
+        let range = self.namespace.as_ref().and_then(|namespace| {
+            self.ranges
+                .iter()
+                .find(|(range_namespace, _)| range_namespace == namespace)
+                .map(|(_, ids)| ids.clone())
+        });
+        match range {
+            Some(ids) => {
+                let ids_text = format!("{}-{}", ids.start, ids.end - 1);
+                ids.into_iter().find(|&id| self.is_free(id)).map(|id| ShortTypeId::new(id).unwrap()).ok_or_else(|| {
+                    format!(
+                        "No type IDs left for {} in the range {} reserved for {}",
+                        name,
+                        ids_text,
+                        self.namespace.as_ref().unwrap()
+                    )
+                })
+            }
+            None => {
+                // continue after the highest used ID, since lower unused IDs
+                // might have been assigned to types on peers that we don't know yet
+                let in_range = |id: u16| self.ranges.iter().any(|(_, ids)| ids.contains(&id));
+                let first_id = self
+                    .short_ids_to_names
+                    .keys()
+                    .map(ShortTypeId::as_u16)
+                    .filter(|&id| !in_range(id))
+                    .max()
+                    .map_or(1, |id| u32::from(id) + 1);
+                let id = (first_id..=u32::from(self.max_id))
+                    .map(|id| id as u16)
+                    .find(|&id| self.is_free(id) && !in_range(id))
+                    .ok_or_else(|| format!("No type IDs left for {}", name))?;
+                let short_id = ShortTypeId::new(id).unwrap();
+                if let Some(expected_name) = self.expected_names.get(short_id.as_usize() - 1) {
+                    assert_eq!(
+                        expected_name,
+                        name,
+                        "Types need to be registered in the same order as when the snapshot was saved"
+                    );
+                }
+                Ok(short_id)
+            }
+        }
+    }
+
+    fn name_of<T: 'static>(&self) -> String {
+        let type_name = unsafe { type_name::<T>() };
+        match self.namespace {
+            Some(ref namespace) => format!("{}/{}", namespace, type_name),
+            None => type_name.to_owned(),
         }
     }
 
     pub fn register_new<T: 'static>(&mut self) -> ShortTypeId {
-        let short_id = self.next_short_id;
+        self.try_register::<T>().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Register `T`, failing with a report of the usage of type IDs if none are left
+    pub fn try_register<T: 'static>(&mut self) -> Result<ShortTypeId, String> {
         let long_id = unsafe { type_id::<T>() };
         assert!(self.long_to_short_ids.get(&long_id).is_none());
+        let name = self.name_of::<T>();
+        let layout = (::std::mem::size_of::<T>(), ::std::mem::align_of::<T>(), schema_fields::<T>());
+        if let Some(short_id) = self.reserved.remove(&name) {
+            self.long_to_short_ids.insert(long_id, short_id);
+            self.layouts.insert(short_id, layout);
+            self.registration_order.push(short_id);
+            return Ok(short_id);
+        }
+        let short_id = self
+            .allocate_id(long_id, &name)
+            .map_err(|e| format!("{} ({})", e, self.usage()))?;
         self.long_to_short_ids.insert(long_id, short_id);
-        self.short_ids_to_names
-            .insert(short_id, unsafe { type_name::<T>() }.into());
-        self.next_short_id = ShortTypeId::new(u16::from(self.next_short_id) + 1).unwrap();
-        short_id
+        self.short_ids_to_names.insert(short_id, name);
+        self.layouts.insert(short_id, layout);
+        self.registration_order.push(short_id);
+        self.next_short_id = self.next_short_id.max(u32::from(short_id.as_u16()) + 1);
+        Ok(short_id)
     }
 
     pub fn get<T: 'static>(&self) -> ShortTypeId {
@@ -65,15 +366,76 @@ impl TypeRegistry {
     }
 
     pub fn get_or_register<T: 'static>(&mut self) -> ShortTypeId {
-        self.long_to_short_ids
-            .get(&unsafe { type_id::<T>() })
-            .cloned()
-            .unwrap_or_else(|| self.register_new::<T>())
+        self.try_get_or_register::<T>().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Like `get_or_register`, but failing if `T` is new and no type IDs are left
+    pub fn try_get_or_register<T: 'static>(&mut self) -> Result<ShortTypeId, String> {
+        match self.long_to_short_ids.get(&unsafe { type_id::<T>() }) {
+            Some(&short_id) => Ok(short_id),
+            None => self.try_register::<T>(),
+        }
     }
 
     pub fn get_name(&self, short_id: ShortTypeId) -> &String {
         &self.short_ids_to_names[&short_id]
     }
+
+    /// The name of a type without its module path and namespace,
+    /// or its full name if another type has the same short name
+    pub fn display_name(&self, short_id: ShortTypeId) -> &str {
+        let name = self.get_name(short_id);
+        let short = short_name(name);
+        let n_same = self.short_ids_to_names.values().filter(|other| short_name(other) == short).count();
+        if n_same == 1 {
+            short
+        } else {
+            name
+        }
+    }
+
+    /// Find a type by its full name or by its unambiguous short name (see `display_name`)
+    pub fn get_by_display_name(&self, name: &str) -> Option<ShortTypeId> {
+        self.get_by_name(name).or_else(|| {
+            let mut matching = self
+                .short_ids_to_names
+                .iter()
+                .filter(|(_, existing_name)| short_name(existing_name) == name);
+            match (matching.next(), matching.next()) {
+                (Some((short_id, _)), None) => Some(*short_id),
+                _ => None,
+            }
+        })
+    }
+
+    /// The types registered here, ordered by type ID (leaving out types only known by
+    /// the IDs peers assigned to them, since their layout isn't known here)
+    pub fn export(&self, kind: TypeKind) -> Vec<TypeManifestEntry> {
+        let mut entries = self
+            .layouts
+            .iter()
+            .map(|(short_id, &(size, align, ref fields))| {
+                TypeManifestEntry::new(kind, short_id.as_u16(), self.get_name(*short_id).clone(), size, align, fields)
+            })
+            .collect::<Vec<_>>();
+        entries.sort_by_key(|entry| entry.id);
+        entries
+    }
+
+    pub fn get_by_name(&self, name: &str) -> Option<ShortTypeId> {
+        self.short_ids_to_names
+            .iter()
+            .find(|(_, existing_name)| existing_name.as_str() == name)
+            .map(|(short_id, _)| *short_id)
+    }
+}
+
+/// A type name without its namespace and module path (keeping the paths of generic parameters)
+pub(crate) fn short_name(name: &str) -> &str {
+    let name = &name[name.find('/').map_or(0, |slash| slash + 1)..];
+    let path_end = name.find('<').unwrap_or_else(|| name.len());
+    let start = name[..path_end].rfind("::").map_or(0, |separator| separator + 2);
+    &name[start..]
 }
 
 impl Default for TypeRegistry {
@@ -81,3 +443,56 @@ impl Default for TypeRegistry {
         Self::new()
     }
 }
+
+#[test]
+fn test_reserved_type_ids() {
+    struct First;
+    struct Second;
+    struct Third;
+
+    let mut registry = TypeRegistry::new();
+    let first_id = registry.register_new::<First>();
+    let second_name = unsafe { type_name::<Second>() };
+    let reserved_id = ShortTypeId::new(3).unwrap();
+
+    assert!(registry.reserve(second_name, reserved_id).is_ok());
+    assert!(registry.reserve(second_name, reserved_id).is_ok());
+    assert!(registry.reserve(second_name, first_id).is_err());
+    assert!(registry.reserve("Other", reserved_id).is_err());
+    assert_eq!(registry.registrations_since(0).len(), 1);
+
+    assert_eq!(registry.register_new::<Third>().as_u16(), 4);
+    assert_eq!(registry.get_or_register::<Second>().as_u16(), 3);
+    assert_eq!(registry.registrations_since(1).len(), 2);
+}
+
+#[test]
+fn test_type_id_ranges_and_pins() {
+    struct Core;
+    struct Pinned;
+    struct FromPlugin;
+    struct AlsoFromPlugin;
+    struct Unranged;
+
+    let mut registry = TypeRegistry::new();
+    registry.set_max_id(6);
+    assert!(registry.reserve_range("plugin", 2..3).is_ok());
+    assert!(registry.reserve_range("other", 1..3).is_err());
+    assert!(registry.reserve_range("other", 6..8).is_err());
+    assert!(registry.pin::<Pinned>(2).is_err());
+    assert!(registry.pin::<Pinned>(4).is_ok());
+
+    assert_eq!(registry.register_new::<Core>().as_u16(), 1);
+    registry.set_namespace(Some("plugin".to_owned()));
+    assert_eq!(registry.register_new::<FromPlugin>().as_u16(), 2);
+    registry.set_namespace(None);
+    assert_eq!(registry.register_new::<Unranged>().as_u16(), 3);
+    assert_eq!(registry.register_new::<Pinned>().as_u16(), 4);
+    assert_eq!(registry.usage().to_string(), "4 of 6 type IDs used, 0 pinned, plugin: 1 of 1 (2-2)");
+
+    registry.set_namespace(Some("plugin".to_owned()));
+    let exhausted = registry.try_register::<AlsoFromPlugin>().err().unwrap();
+    assert!(exhausted.ends_with("(4 of 6 type IDs used, 0 pinned, plugin: 1 of 1 (2-2))"));
+    registry.set_namespace(None);
+    assert_eq!(registry.try_get_or_register::<Core>().ok().map(|id| id.as_u16()), Some(1));
+}
@@ -0,0 +1,132 @@
+use crate::id::{MachineID, RawID};
+use std::collections::HashMap;
+
+/// Sent to an observer registered with `World::watch` (which needs to handle it)
+/// once the watched instance died, or the machine it lives on disconnected
+#[derive(Compact, Clone, Debug)]
+pub struct Terminated(pub RawID);
+
+/// Sent to the system services of the machine of `target`, to start watching it
+#[derive(Compact, Clone)]
+pub(crate) struct Watch {
+    pub target: RawID,
+    pub observer: RawID,
+}
+
+/// Sent to the system services of the machine of `target`, to stop watching it
+#[derive(Compact, Clone)]
+pub(crate) struct Unwatch {
+    pub target: RawID,
+    pub observer: RawID,
+}
+
+/// Sent to the system services of the machine of `observer` once `target` died,
+/// to forget about the watch there and deliver `Terminated`
+#[derive(Compact, Clone)]
+pub(crate) struct NotifyTerminated {
+    pub target: RawID,
+    pub observer: RawID,
+}
+
+/// Who is watching which instances, as known on this machine
+pub(crate) struct Watches {
+    /// Observers (anywhere) of local instances
+    observers: HashMap<RawID, Vec<RawID>>,
+    /// Local observers of instances on other machines, by the machine of the target,
+    /// to notify them if that machine disconnects
+    remote: HashMap<MachineID, Vec<(RawID, RawID)>>,
+}
+
+impl Watches {
+    pub fn new() -> Watches {
+        Watches {
+            observers: HashMap::new(),
+            remote: HashMap::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.observers.is_empty() && self.remote.is_empty()
+    }
+
+    pub fn add(&mut self, target: RawID, observer: RawID) {
+        let observers = self.observers.entry(target).or_insert_with(Vec::new);
+        if !observers.contains(&observer) {
+            observers.push(observer);
+        }
+    }
+
+    pub fn remove(&mut self, target: RawID, observer: RawID) {
+        if let Some(observers) = self.observers.get_mut(&target) {
+            observers.retain(|existing| *existing != observer);
+            if observers.is_empty() {
+                self.observers.remove(&target);
+            }
+        }
+    }
+
+    /// The local instance `target` died or moved away, returns its observers
+    pub fn take_observers(&mut self, target: RawID) -> Vec<RawID> {
+        self.observers.remove(&target).unwrap_or_else(Vec::new)
+    }
+
+    pub fn add_remote(&mut self, target: RawID, observer: RawID) {
+        let watched = self.remote.entry(target.machine).or_insert_with(Vec::new);
+        if !watched.contains(&(target, observer)) {
+            watched.push((target, observer));
+        }
+    }
+
+    pub fn remove_remote(&mut self, target: RawID, observer: RawID) {
+        if let Some(watched) = self.remote.get_mut(&target.machine) {
+            watched.retain(|existing| *existing != (target, observer));
+            if watched.is_empty() {
+                self.remote.remove(&target.machine);
+            }
+        }
+    }
+
+    /// A remotely watched instance migrated to a new ID (and maybe machine)
+    pub fn moved_remote(&mut self, old_id: RawID, new_id: RawID) {
+        let observers = self
+            .remote
+            .get(&old_id.machine)
+            .map(|watched| {
+                watched
+                    .iter()
+                    .filter(|(target, _)| *target == old_id)
+                    .map(|&(_, observer)| observer)
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_else(Vec::new);
+        for observer in observers {
+            self.remove_remote(old_id, observer);
+            self.add_remote(new_id, observer);
+        }
+    }
+
+    /// A machine disconnected, returns all watched instances on it with their local observers
+    pub fn disconnected(&mut self, machine: MachineID) -> Vec<(RawID, RawID)> {
+        self.remote.remove(&machine).unwrap_or_else(Vec::new)
+    }
+}
+
+#[test]
+fn test_watches() {
+    use crate::test_support::test_id as id;
+
+    let mut watches = Watches::new();
+
+    watches.add(id(1, 0), id(2, 0));
+    watches.add(id(1, 0), id(2, 0));
+    watches.add(id(1, 0), id(3, 1));
+    watches.remove(id(1, 0), id(3, 1));
+    assert_eq!(watches.take_observers(id(1, 0)), vec![id(2, 0)]);
+    assert!(watches.is_empty());
+
+    watches.add_remote(id(4, 1), id(2, 0));
+    watches.moved_remote(id(4, 1), id(5, 2));
+    assert!(watches.disconnected(MachineID(1)).is_empty());
+    assert_eq!(watches.disconnected(MachineID(2)), vec![(id(5, 2), id(2, 0))]);
+    assert!(watches.is_empty());
+}